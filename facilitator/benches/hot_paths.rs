@@ -0,0 +1,238 @@
+use avro_rs::{Reader, Writer};
+use chrono::NaiveDateTime;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use facilitator::{
+    idl::{IngestionDataSharePacket, Packet},
+    intake::BatchIntaker,
+    sample::{SampleGenerator, SampleOutput},
+    test_utils::{
+        default_facilitator_packet_encryption_public_key, default_ingestor_private_key,
+        default_ingestor_public_key, default_pha_packet_encryption_public_key,
+        default_pha_signing_private_key, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+        DEFAULT_PHA_ECIES_PRIVATE_KEY,
+    },
+    transport::{
+        MemoryTransport, SignableTransport, VerifiableAndDecryptableTransport, VerifiableTransport,
+    },
+    DigestAlgorithm, DigestReader,
+};
+use prio::{
+    client::Client,
+    encrypt::PrivateKey,
+    field::Field32,
+    server::{is_valid_share, Server},
+};
+use slog::{o, Discard, Logger};
+use std::{collections::HashMap, io};
+use uuid::Uuid;
+
+/// Number of bins in the data shares used throughout these benchmarks
+const DIMENSION: usize = 128;
+/// Number of packets in the packet files and batches used in these benchmarks
+const PACKET_COUNT: usize = 1000;
+/// The r_pit value used by SampleGenerator
+const R_PIT: u32 = 998314904;
+
+/// Encodes and encrypts a single client's data, returning the PHA's and the
+/// facilitator's encrypted shares.
+fn encrypted_shares() -> (Vec<u8>, Vec<u8>) {
+    let mut client = Client::new(
+        DIMENSION,
+        default_pha_packet_encryption_public_key(),
+        default_facilitator_packet_encryption_public_key(),
+    )
+    .unwrap();
+    let data: Vec<Field32> = (0..DIMENSION)
+        .map(|bin| Field32::from((bin % 2) as u32))
+        .collect();
+    client.encode_simple(&data).unwrap()
+}
+
+fn packet_file(packets: &[IngestionDataSharePacket]) -> Vec<u8> {
+    let schema = IngestionDataSharePacket::schema();
+    let mut writer = Writer::new(&schema, Vec::new());
+    for packet in packets {
+        packet.write(&mut writer).unwrap();
+    }
+    writer.into_inner().unwrap()
+}
+
+fn packet_codec(c: &mut Criterion) {
+    let (pha_share, _) = encrypted_shares();
+    let packets: Vec<IngestionDataSharePacket> = (0..PACKET_COUNT)
+        .map(|_| IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: pha_share.clone(),
+            encryption_key_id: Some("pha-fake-key-1".to_owned()),
+            r_pit: R_PIT as i64,
+            version_configuration: Some("config-1".to_owned()),
+            device_nonce: None,
+            encryption_ciphersuite: None,
+        })
+        .collect();
+    let encoded = packet_file(&packets);
+
+    let mut group = c.benchmark_group("packet_codec");
+    group.throughput(Throughput::Elements(PACKET_COUNT as u64));
+    group.bench_function("encode", |b| b.iter(|| packet_file(&packets)));
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            let schema = IngestionDataSharePacket::schema();
+            let mut reader = Reader::with_schema(&schema, &encoded[..]).unwrap();
+            let mut count = 0;
+            while IngestionDataSharePacket::read(&mut reader).is_ok() {
+                count += 1;
+            }
+            assert_eq!(count, PACKET_COUNT);
+        })
+    });
+    group.finish();
+}
+
+fn share_verification(c: &mut Criterion) {
+    let (pha_share, facilitator_share) = encrypted_shares();
+    let mut pha_server = Server::new(
+        DIMENSION,
+        true,
+        PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+    );
+    let mut facilitator_server = Server::new(
+        DIMENSION,
+        false,
+        PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+    );
+    let r_pit = Field32::from(R_PIT);
+
+    let mut group = c.benchmark_group("share_verification");
+    // Intake decrypts each share and generates a verification message for it
+    group.bench_function("generate_verification_message", |b| {
+        b.iter(|| {
+            pha_server
+                .generate_verification_message(r_pit, &pha_share)
+                .unwrap()
+        })
+    });
+    // Aggregation checks the verification messages of both shares
+    let pha_message = pha_server
+        .generate_verification_message(r_pit, &pha_share)
+        .unwrap();
+    let facilitator_message = facilitator_server
+        .generate_verification_message(r_pit, &facilitator_share)
+        .unwrap();
+    group.bench_function("is_valid_share", |b| {
+        b.iter(|| assert!(is_valid_share(&pha_message, &facilitator_message)))
+    });
+    group.finish();
+}
+
+fn digest(c: &mut Criterion) {
+    let content = vec![0xa5u8; 1024 * 1024];
+
+    let mut group = c.benchmark_group("digest");
+    group.throughput(Throughput::Bytes(content.len() as u64));
+    for (name, algorithm) in &[
+        ("sha256", DigestAlgorithm::Sha256),
+        ("sha384", DigestAlgorithm::Sha384),
+    ] {
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut reader = DigestReader::with_algorithm(&content[..], *algorithm);
+                io::copy(&mut reader, &mut io::sink()).unwrap();
+                reader.finish()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn intake(c: &mut Criterion) {
+    let logger = Logger::root(Discard, o!());
+    let aggregation_name = "bench-aggregation";
+    let date = NaiveDateTime::from_timestamp(1234567890, 0);
+    let batch_uuid = Uuid::new_v4();
+
+    let pha_ingestion = MemoryTransport::new();
+    let mut pha_output = SampleOutput {
+        transport: SignableTransport {
+            transport: Box::new(pha_ingestion.clone()),
+            batch_signer: Box::new(default_ingestor_private_key()),
+        },
+        packet_encryption_public_key: default_pha_packet_encryption_public_key(),
+        drop_nth_packet: None,
+    };
+    let mut facilitator_output = SampleOutput {
+        transport: SignableTransport {
+            transport: Box::new(MemoryTransport::new()),
+            batch_signer: Box::new(default_ingestor_private_key()),
+        },
+        packet_encryption_public_key: default_facilitator_packet_encryption_public_key(),
+        drop_nth_packet: None,
+    };
+    SampleGenerator::new(
+        aggregation_name,
+        DIMENSION as i32,
+        0.11,
+        100,
+        100,
+        &mut pha_output,
+        &mut facilitator_output,
+        &logger,
+    )
+    .generate_ingestion_sample("None", &batch_uuid, &date, PACKET_COUNT)
+    .unwrap();
+
+    let mut ingestor_public_keys = HashMap::new();
+    ingestor_public_keys.insert(
+        default_ingestor_private_key().identifier,
+        default_ingestor_public_key(),
+    );
+    let mut ingestion_transport = VerifiableAndDecryptableTransport {
+        transport: VerifiableTransport {
+            transport: Box::new(pha_ingestion),
+            batch_signing_public_keys: ingestor_public_keys,
+        },
+        packet_decryption_keys: vec![
+            PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap()
+        ],
+        hpke_packet_decryption_keys: vec![],
+    };
+
+    let mut group = c.benchmark_group("intake");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(PACKET_COUNT as u64));
+    group.bench_function("batch", |b| {
+        b.iter_batched(
+            // Validation batches may only be written once, so each iteration
+            // gets fresh destinations.
+            || {
+                let validation_transport = || SignableTransport {
+                    transport: Box::new(MemoryTransport::new()),
+                    batch_signer: Box::new(default_pha_signing_private_key()),
+                };
+                (validation_transport(), validation_transport())
+            },
+            |(mut peer_validation_transport, mut own_validation_transport)| {
+                BatchIntaker::new(
+                    "None",
+                    aggregation_name,
+                    &batch_uuid,
+                    &date,
+                    &mut ingestion_transport,
+                    &mut peer_validation_transport,
+                    &mut own_validation_transport,
+                    true,
+                    false,
+                    &logger,
+                )
+                .unwrap()
+                .generate_validation_share(|_| Ok(()))
+                .unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, packet_codec, share_verification, digest, intake);
+criterion_main!(benches);
@@ -0,0 +1,259 @@
+//! Generates Rust structs from the Avro schemas in avro-schema, along with
+//! conversions between them and avro_rs::Value. The generated code is included
+//! into the idl::generated module.
+
+use serde_json::Value;
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn main() {
+    let schema_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("../avro-schema");
+    println!("cargo:rerun-if-changed={}", schema_dir.display());
+
+    let mut schema_paths: Vec<PathBuf> = fs::read_dir(&schema_dir)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", schema_dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "avsc"))
+        .collect();
+    schema_paths.sort();
+
+    let mut generated = String::new();
+    for path in &schema_paths {
+        println!("cargo:rerun-if-changed={}", path.display());
+        generate_record(&mut generated, path);
+    }
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("idl_generated.rs");
+    fs::write(&out_path, generated)
+        .unwrap_or_else(|e| panic!("failed to write {}: {}", out_path.display(), e));
+}
+
+/// A field of a record schema, reduced to what we need to generate code for it.
+struct Field {
+    name: String,
+    doc: Option<String>,
+    rust_type: String,
+    encode: String,
+    decode: String,
+    optional: bool,
+}
+
+fn generate_record(out: &mut String, path: &Path) {
+    let schema: Value = serde_json::from_str(
+        &fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e)),
+    )
+    .unwrap_or_else(|e| panic!("failed to parse {}: {}", path.display(), e));
+    assert_eq!(
+        schema["type"],
+        "record",
+        "{} is not a record schema",
+        path.display()
+    );
+    let name = schema["name"].as_str().unwrap();
+    let fields: Vec<Field> = schema["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|field| {
+            let field_name = field["name"].as_str().unwrap().to_owned();
+            // Logical types may be given on the field rather than its type
+            let logical_type = field["logicalType"].as_str();
+            let field_type = &field["type"];
+            let (rust_type, optional) = match optional_type(field_type) {
+                Some(inner) => (format!("Option<{}>", rust_type(inner, logical_type)), true),
+                None => (rust_type(field_type, logical_type), false),
+            };
+            let value_expr = format!("&self.{}", field_name);
+            Field {
+                encode: encode(field_type, logical_type, &value_expr),
+                decode: decode(field_type, &field_name),
+                doc: field["doc"].as_str().map(str::to_owned),
+                name: field_name,
+                rust_type,
+                optional,
+            }
+        })
+        .collect();
+
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    writeln!(out, "/// Generated from avro-schema/{}.", file_name).unwrap();
+    if let Some(doc) = schema["doc"].as_str() {
+        writeln!(out, "///\n/// {}", doc).unwrap();
+    }
+    writeln!(
+        out,
+        "#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]"
+    )
+    .unwrap();
+    writeln!(out, "pub struct {} {{", name).unwrap();
+    for field in &fields {
+        if let Some(doc) = &field.doc {
+            writeln!(out, "    /// {}", doc).unwrap();
+        }
+        writeln!(out, "    pub {}: {},", field.name, field.rust_type).unwrap();
+    }
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl {} {{", name).unwrap();
+    writeln!(
+        out,
+        "    /// The schema this struct was generated from.\n    \
+         pub const SCHEMA: &str = include_str!({:?});\n",
+        path.canonicalize().unwrap()
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    /// Parses SCHEMA, which was checked when this struct was generated.\n    \
+         pub fn schema() -> avro_rs::Schema {{\n        \
+         avro_rs::Schema::parse_str(Self::SCHEMA).unwrap()\n    }}\n"
+    )
+    .unwrap();
+
+    writeln!(
+        out,
+        "    /// Encodes this struct as an Avro record matching SCHEMA.\n    \
+         pub fn to_value(&self) -> avro_rs::types::Value {{\n        \
+         avro_rs::types::Value::Record(vec!["
+    )
+    .unwrap();
+    for field in &fields {
+        writeln!(
+            out,
+            "            ({:?}.to_owned(), {}),",
+            field.name, field.encode
+        )
+        .unwrap();
+    }
+    writeln!(out, "        ])\n    }}\n").unwrap();
+
+    writeln!(
+        out,
+        "    /// Decodes an Avro record that has been resolved against SCHEMA.\n    \
+         /// Fields not in SCHEMA are ignored.\n    \
+         pub fn from_value(value: avro_rs::types::Value) -> Result<Self, crate::Error> {{\n        \
+         let record = super::record_fields({:?}, value)?;",
+        name
+    )
+    .unwrap();
+    for field in &fields {
+        writeln!(out, "        let mut {} = None;", field.name).unwrap();
+    }
+    writeln!(out, "        for (field_name, field_value) in record {{\n            match field_name.as_str() {{").unwrap();
+    for field in &fields {
+        writeln!(
+            out,
+            "                {:?} => {} = Some({}?),",
+            field.name, field.name, field.decode
+        )
+        .unwrap();
+    }
+    writeln!(out, "                _ => {{}}\n            }}\n        }}").unwrap();
+    writeln!(out, "        Ok({} {{", name).unwrap();
+    for field in &fields {
+        if field.optional {
+            writeln!(out, "            {}: {}.flatten(),", field.name, field.name).unwrap();
+        } else {
+            writeln!(
+                out,
+                "            {}: {}.ok_or_else(|| super::missing_field({:?}, {:?}))?,",
+                field.name, field.name, name, field.name
+            )
+            .unwrap();
+        }
+    }
+    writeln!(out, "        }})\n    }}\n}}\n").unwrap();
+}
+
+/// If the type is a union of null and one other type, returns the other type.
+fn optional_type(field_type: &Value) -> Option<&Value> {
+    let variants = field_type.as_array()?;
+    match variants.as_slice() {
+        [Value::String(null), other] | [other, Value::String(null)] if null == "null" => {
+            Some(other)
+        }
+        _ => panic!("unsupported union type {}", field_type),
+    }
+}
+
+fn logical_type<'a>(field_type: &'a Value, field_logical_type: Option<&'a str>) -> Option<&'a str> {
+    field_type["logicalType"].as_str().or(field_logical_type)
+}
+
+fn primitive(field_type: &Value) -> &str {
+    match field_type {
+        Value::String(name) => name,
+        Value::Object(_) => field_type["type"].as_str().unwrap(),
+        _ => panic!("unsupported type {}", field_type),
+    }
+}
+
+fn rust_type(field_type: &Value, field_logical_type: Option<&str>) -> String {
+    match (
+        primitive(field_type),
+        logical_type(field_type, field_logical_type),
+    ) {
+        ("string", Some("uuid")) => "uuid::Uuid".to_owned(),
+        ("string", _) => "String".to_owned(),
+        ("bytes", _) => "Vec<u8>".to_owned(),
+        ("boolean", _) => "bool".to_owned(),
+        ("int", _) => "i32".to_owned(),
+        ("long", _) => "i64".to_owned(),
+        ("float", _) => "f32".to_owned(),
+        ("double", _) => "f64".to_owned(),
+        ("array", _) => format!("Vec<{}>", rust_type(&field_type["items"], None)),
+        (other, _) => panic!("unsupported type {}", other),
+    }
+}
+
+/// Returns an expression encoding the value referred to by `value` as an
+/// avro_rs::types::Value.
+fn encode(field_type: &Value, field_logical_type: Option<&str>, value: &str) -> String {
+    if let Some(inner) = optional_type(field_type) {
+        return format!(
+            "avro_rs::types::Value::Union(Box::new(match {} {{ \
+             Some(v) => {}, None => avro_rs::types::Value::Null }}))",
+            value,
+            encode(inner, field_logical_type, "v")
+        );
+    }
+    match (
+        primitive(field_type),
+        logical_type(field_type, field_logical_type),
+    ) {
+        ("string", Some("uuid")) => format!("avro_rs::types::Value::Uuid(*{})", value),
+        ("string", _) => format!("avro_rs::types::Value::String(Clone::clone({}))", value),
+        ("bytes", _) => format!("avro_rs::types::Value::Bytes(Clone::clone({}))", value),
+        ("boolean", _) => format!("avro_rs::types::Value::Boolean(*{})", value),
+        ("int", _) => format!("avro_rs::types::Value::Int(*{})", value),
+        ("long", Some("timestamp-millis")) => {
+            format!("avro_rs::types::Value::TimestampMillis(*{})", value)
+        }
+        ("long", _) => format!("avro_rs::types::Value::Long(*{})", value),
+        ("float", _) => format!("avro_rs::types::Value::Float(*{})", value),
+        ("double", _) => format!("avro_rs::types::Value::Double(*{})", value),
+        ("array", _) => format!(
+            "avro_rs::types::Value::Array(({}).iter().map(|v| {}).collect())",
+            value,
+            encode(&field_type["items"], None, "v")
+        ),
+        (other, _) => panic!("unsupported type {}", other),
+    }
+}
+
+/// Returns an expression decoding the avro_rs::types::Value `field_value` into a
+/// Result of the field's Rust type.
+fn decode(field_type: &Value, field_name: &str) -> String {
+    if optional_type(field_type).is_some() {
+        return format!("super::decode_optional({:?}, field_value)", field_name);
+    }
+    match primitive(field_type) {
+        "array" => format!("super::decode_array({:?}, field_value)", field_name),
+        _ => format!("super::decode({:?}, field_value)", field_name),
+    }
+}
@@ -1,31 +1,165 @@
 use crate::{
     batch::{Batch, BatchReader, BatchWriter},
+    dedup::PacketDeduplicator,
+    field::{EncodedField, PrioField},
     idl::{
-        IngestionDataSharePacket, IngestionHeader, InvalidPacket, Packet, SumPart,
+        Header, IngestionDataSharePacket, IngestionHeader, InvalidPacket, Packet, SumPart,
         ValidationHeader, ValidationPacket,
     },
-    logging::event,
+    logging::{event, set_traceparent},
     metrics::AggregateMetricsCollector,
-    transport::{SignableTransport, VerifiableAndDecryptableTransport, VerifiableTransport},
-    BatchSigningKey, Error,
+    noise::DifferentialPrivacy,
+    packet_encryption::HpkePacketDecryptor,
+    prio_crypto::{PrioBackend, PrioCrypto},
+    signing::BatchSigner,
+    transport::{
+        SignableTransport, Transport, VerifiableAndDecryptableTransport, VerifiableTransport,
+    },
+    DigestAlgorithm, Error, ValidationError,
 };
-use anyhow::{anyhow, Context, Result};
-use avro_rs::Reader;
-use chrono::NaiveDateTime;
+use anyhow::{anyhow, ensure, Context, Result};
+use avro_rs::Codec;
+use chrono::{Duration, NaiveDateTime};
 use prio::{
-    field::Field32,
-    server::{Server, VerificationMessage},
+    field::{Field32, Field64},
+    server::VerificationMessage,
 };
-use slog::{info, o, Logger};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
     convert::TryFrom,
-    io::Cursor,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
 };
+use tracing::{info_span, Span};
 use uuid::Uuid;
 
+/// Size of the window and maximum number of in-memory UUIDs of the
+/// deduplicator used to drop duplicate packets within a batch, if no
+/// deduplicator spanning the whole aggregation is provided. Beyond the in-memory
+/// limit, UUIDs are spilled to temporary files.
+const BATCH_DEDUP_WINDOW: usize = 10_000_000;
+const BATCH_DEDUP_MAX_IN_MEMORY: usize = 100_000;
+
+/// Lists the ingestion batches for the aggregation in the provided transport
+/// whose dates fall within [aggregation_start, aggregation_end) and returns the
+/// UUID and date of each one, ordered by date. Only the day prefixes spanned by
+/// the window are listed. If a glob is provided, only batches whose signature
+/// key begins with a match for it are returned. In the glob, `*` matches any
+/// run of characters and `?` any single character, except for `/`.
+pub fn discover_batches(
+    ingestion_transport: &mut dyn Transport,
+    aggregation_name: &str,
+    aggregation_start: &NaiveDateTime,
+    aggregation_end: &NaiveDateTime,
+    key_glob: Option<&str>,
+    trace_id: &str,
+) -> Result<Vec<(Uuid, NaiveDateTime)>> {
+    let mut batches = vec![];
+    let mut day = aggregation_start.date();
+    while day <= aggregation_end.date() {
+        let prefix = format!("{}/{}/", aggregation_name, day.format("%Y/%m/%d"));
+        for key in ingestion_transport.list(&prefix, trace_id)? {
+            let (key_aggregation_name, date, batch_id) =
+                match Batch::parse_ingestion_signature_key(&key) {
+                    Some(batch) => batch,
+                    None => continue,
+                };
+            if key_aggregation_name == aggregation_name
+                && date >= *aggregation_start
+                && date < *aggregation_end
+                && key_glob.map_or(true, |glob| {
+                    glob_matches_prefix(glob.as_bytes(), key.as_bytes())
+                })
+            {
+                batches.push((batch_id, date));
+            }
+        }
+        day += Duration::days(1);
+    }
+    batches.sort_by_key(|(batch_id, date)| (*date, *batch_id));
+    Ok(batches)
+}
+
+/// Returns true if some prefix of text matches the glob pattern.
+fn glob_matches_prefix(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => true,
+        Some((b'*', rest)) => {
+            for i in 0..=text.len() {
+                if glob_matches_prefix(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some((b'?', rest)) => match text.split_first() {
+            Some((c, text)) if *c != b'/' => glob_matches_prefix(rest, text),
+            _ => false,
+        },
+        Some((p, rest)) => match text.split_first() {
+            Some((c, text)) if c == p => glob_matches_prefix(rest, text),
+            _ => false,
+        },
+    }
+}
+
+/// Why a packet was rejected during aggregation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    /// The packet's proof was not valid
+    InvalidProof,
+    /// There was no packet in the peer's validation batch for the packet
+    MissingPeerValidationPacket,
+    /// There was no packet in our own validation batch for the packet
+    MissingOwnValidationPacket,
+}
+
+impl RejectionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::InvalidProof => "invalid_proof",
+            RejectionReason::MissingPeerValidationPacket => "missing_peer_validation_packet",
+            RejectionReason::MissingOwnValidationPacket => "missing_own_validation_packet",
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            RejectionReason::InvalidProof => 0,
+            RejectionReason::MissingPeerValidationPacket => 1,
+            RejectionReason::MissingOwnValidationPacket => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(RejectionReason::InvalidProof),
+            1 => Ok(RejectionReason::MissingPeerValidationPacket),
+            2 => Ok(RejectionReason::MissingOwnValidationPacket),
+            _ => Err(anyhow!("unknown rejection reason {}", byte)),
+        }
+    }
+}
+
+/// A packet rejected during aggregation, as listed in the rejected packets
+/// report, one JSON object per line.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RejectedPacket {
+    pub packet_uuid: Uuid,
+    pub batch_uuid: Uuid,
+    pub reason: RejectionReason,
+}
+
 pub struct BatchAggregator<'a> {
     trace_id: &'a str,
+    instance_name: String,
     is_first: bool,
     permit_malformed_batch: bool,
     aggregation_name: &'a str,
@@ -33,14 +167,70 @@ pub struct BatchAggregator<'a> {
     aggregation_end: &'a NaiveDateTime,
     own_validation_transport: &'a mut VerifiableTransport,
     peer_validation_transport: &'a mut VerifiableTransport,
+    own_validation_digest_algorithm: DigestAlgorithm,
+    peer_validation_digest_algorithm: DigestAlgorithm,
     ingestion_transport: &'a mut VerifiableAndDecryptableTransport,
     aggregation_batch: BatchWriter<'a, SumPart, InvalidPacket>,
-    share_processor_signing_key: &'a BatchSigningKey,
+    share_processor_signer: &'a dyn BatchSigner,
     total_individual_clients: i64,
     metrics_collector: Option<&'a AggregateMetricsCollector>,
+    packet_deduplicator: Option<PacketDeduplicator>,
+    batch_traceparents: HashMap<Uuid, String>,
+    prio_backend: PrioBackend,
+    checkpoint_transport: Option<&'a mut dyn Transport>,
+    checkpoint_key: String,
+    rejected_packets_transport: Option<&'a mut dyn Transport>,
+    rejected_packets_key: String,
+    checkpoint_interval: usize,
+    resume: bool,
+    min_complete_batches_percent: f64,
+    differential_privacy: Option<DifferentialPrivacy>,
+    sum_part_digest: Option<String>,
+    span: Span,
     logger: Logger,
 }
 
+/// The state of an aggregation after some of its batches have been aggregated,
+/// from which it can be resumed.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct AggregationCheckpoint {
+    /// UUIDs of the batches aggregated so far, in order
+    batch_uuids: Vec<Uuid>,
+    /// The accumulated shares of each server, encoded as in sum parts
+    total_shares: Vec<Vec<i64>>,
+    rejected_packets: Vec<RejectedPacket>,
+    total_individual_clients: i64,
+}
+
+/// An AggregationCheckpoint as it is stored, along with the hex encoded
+/// SHA-256 digest of its JSON encoding, so that a corrupt or truncated
+/// checkpoint is not resumed from.
+#[derive(Serialize, Deserialize)]
+struct StoredAggregationCheckpoint {
+    checkpoint: String,
+    digest: String,
+}
+
+impl AggregationCheckpoint {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let checkpoint = serde_json::to_string(self)?;
+        Ok(serde_json::to_vec(&StoredAggregationCheckpoint {
+            digest: hex::encode(digest(&SHA256, checkpoint.as_bytes())),
+            checkpoint,
+        })?)
+    }
+
+    fn decode(encoded: &[u8]) -> Result<Self> {
+        let stored: StoredAggregationCheckpoint =
+            serde_json::from_slice(encoded).context("failed to parse aggregation checkpoint")?;
+        ensure!(
+            hex::encode(digest(&SHA256, stored.checkpoint.as_bytes())) == stored.digest,
+            "aggregation checkpoint does not match its digest"
+        );
+        serde_json::from_str(&stored.checkpoint).context("failed to parse aggregation checkpoint")
+    }
+}
+
 impl<'a> BatchAggregator<'a> {
     #[allow(clippy::too_many_arguments)] // Grandfathered in
     pub fn new(
@@ -57,15 +247,24 @@ impl<'a> BatchAggregator<'a> {
         aggregation_transport: &'a mut SignableTransport,
         parent_logger: &Logger,
     ) -> Result<BatchAggregator<'a>> {
+        let sum_batch = Batch::new_sum(
+            instance_name,
+            aggregation_name,
+            aggregation_start,
+            aggregation_end,
+            is_first,
+        );
+        let checkpoint_key = format!("{}.checkpoint", sum_batch.header_key());
+        let rejected_packets_key = format!("{}.rejected_packets.jsonl", sum_batch.header_key());
+        let span = info_span!("aggregation", trace_id, aggregation_name);
         let logger = parent_logger.new(o!(
-            event::TRACE_ID => trace_id.to_owned(),
-            event::AGGREGATION_NAME => aggregation_name.to_owned(),
             event::INGESTION_PATH => ingestion_transport.transport.transport.path(),
             event::OWN_VALIDATION_PATH => own_validation_transport.transport.path(),
             event::PEER_VALIDATION_PATH => peer_validation_transport.transport.path(),
         ));
         Ok(BatchAggregator {
             trace_id,
+            instance_name: instance_name.to_owned(),
             is_first,
             permit_malformed_batch,
             aggregation_name,
@@ -73,21 +272,30 @@ impl<'a> BatchAggregator<'a> {
             aggregation_end,
             own_validation_transport,
             peer_validation_transport,
+            own_validation_digest_algorithm: DigestAlgorithm::default(),
+            peer_validation_digest_algorithm: DigestAlgorithm::default(),
             ingestion_transport,
             aggregation_batch: BatchWriter::new(
-                Batch::new_sum(
-                    instance_name,
-                    aggregation_name,
-                    aggregation_start,
-                    aggregation_end,
-                    is_first,
-                ),
+                sum_batch,
                 &mut *aggregation_transport.transport,
                 trace_id,
             ),
-            share_processor_signing_key: &aggregation_transport.batch_signing_key,
+            share_processor_signer: aggregation_transport.batch_signer.as_ref(),
             total_individual_clients: 0,
             metrics_collector: None,
+            packet_deduplicator: None,
+            batch_traceparents: HashMap::new(),
+            prio_backend: PrioBackend::default(),
+            checkpoint_transport: None,
+            checkpoint_key,
+            rejected_packets_transport: None,
+            rejected_packets_key,
+            checkpoint_interval: 1,
+            resume: false,
+            min_complete_batches_percent: 100.0,
+            differential_privacy: None,
+            sum_part_digest: None,
+            span,
             logger,
         })
     }
@@ -96,95 +304,534 @@ impl<'a> BatchAggregator<'a> {
         self.metrics_collector = Some(collector);
     }
 
+    /// Provide a deduplicator with which to drop ingestion packets that appear
+    /// in more than one of the batches being aggregated. Duplicate packets
+    /// within a single batch are always dropped.
+    pub fn set_packet_deduplicator(&mut self, deduplicator: PacketDeduplicator) {
+        self.packet_deduplicator = Some(deduplicator);
+    }
+
+    /// Provide the W3C Trace Context traceparents of the batches being
+    /// aggregated, keyed by batch UUID (see task::batch_traceparent).
+    /// Aggregating a batch whose traceparent is known is traced as part of the
+    /// batch's trace, alongside its intake, and linked to the aggregation.
+    pub fn set_batch_traceparents(&mut self, batch_traceparents: HashMap<Uuid, String>) {
+        self.batch_traceparents = batch_traceparents;
+    }
+
+    /// Sets the implementation of the Prio cryptography with which packets'
+    /// proofs are checked and their shares summed. Defaults to libprio-rs.
+    pub fn set_prio_backend(&mut self, backend: PrioBackend) {
+        self.prio_backend = backend;
+    }
+
+    /// Provide a transport in which the state of the aggregation is
+    /// checkpointed after every `interval` batches, and removed once the sum
+    /// part is written. If `resume` is true and a checkpoint from an earlier
+    /// attempt at the same aggregation exists, aggregation picks up after the
+    /// batches it covers. The state of any packet deduplicator is not
+    /// checkpointed, so duplicates of packets aggregated before the checkpoint
+    /// are only dropped if they are in the same batch.
+    pub fn set_checkpointing(
+        &mut self,
+        transport: &'a mut dyn Transport,
+        interval: usize,
+        resume: bool,
+    ) {
+        self.checkpoint_transport = Some(transport);
+        self.checkpoint_interval = interval.max(1);
+        self.resume = resume;
+    }
+
+    /// Provide a transport to which a report of the packets rejected during
+    /// aggregation is written alongside the sum part, listing each packet's
+    /// UUID, its batch's UUID and why it was rejected as line-delimited JSON.
+    pub fn set_rejected_packets_report(&mut self, transport: &'a mut dyn Transport) {
+        self.rejected_packets_transport = Some(transport);
+    }
+
+    /// Allows aggregation to proceed if a validation batch is missing for some
+    /// of the batches, as long as at least the provided percentage of them have
+    /// both validation batches. Batches missing a validation batch are left out
+    /// of the sum and listed in the sum part's excluded_batch_uuids. By
+    /// default, every batch must have both validation batches.
+    pub fn set_min_complete_batches_percent(&mut self, percent: f64) {
+        self.min_complete_batches_percent = percent;
+    }
+
+    /// Adds noise to our share of the sum before it is written, so that the
+    /// published sum is differentially private.
+    pub fn set_differential_privacy(&mut self, differential_privacy: DifferentialPrivacy) {
+        self.differential_privacy = Some(differential_privacy);
+    }
+
+    /// Sets the algorithms used to check the packet file digests of our own
+    /// and the peer's validation batches, which should be those advertised in
+    /// our own and the peer's specific manifests, respectively. Both default to
+    /// SHA-256.
+    pub fn set_validation_digest_algorithms(
+        &mut self,
+        own: DigestAlgorithm,
+        peer: DigestAlgorithm,
+    ) {
+        self.own_validation_digest_algorithm = own;
+        self.peer_validation_digest_algorithm = peer;
+    }
+
+    /// Sets the codec with which the blocks of the sum part's invalid packet
+    /// file are compressed. Defaults to no compression.
+    pub fn set_packet_file_codec(&mut self, codec: Codec) {
+        self.aggregation_batch.set_packet_file_codec(codec);
+    }
+
+    /// Returns the hex encoded SHA-256 digest of the Avro encoded sum part
+    /// header, once generate_sum_part has succeeded.
+    pub fn sum_part_digest(&self) -> Option<&str> {
+        self.sum_part_digest.as_deref()
+    }
+
     /// Compute the sum part for all the provided batch IDs and write it out to
     /// the aggregation transport. The provided callback is invoked after each
-    /// batch is aggregated.
+    /// batch is aggregated, and aggregation is abandoned if it returns an
+    /// error. Packets are folded into the running sum as they are read, and
+    /// rejected packets are kept in a temporary file, so memory use does not
+    /// grow with the number of packets. Failures are classified with
+    /// Error::classify.
     pub fn generate_sum_part<F>(
         &mut self,
         batch_ids: &[(Uuid, NaiveDateTime)],
-        mut callback: F,
+        callback: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&Logger) -> Result<()>,
+    {
+        let _span = self.span.clone().entered();
+        self.generate_sum_part_impl(batch_ids, callback)
+            .map_err(Error::classify)
+    }
+
+    fn generate_sum_part_impl<F>(
+        &mut self,
+        batch_ids: &[(Uuid, NaiveDateTime)],
+        callback: F,
     ) -> Result<()>
     where
-        F: FnMut(&Logger),
+        F: FnMut(&Logger) -> Result<()>,
     {
         info!(self.logger, "processing aggregation task");
-        let mut invalid_uuids = Vec::new();
-        let mut included_batch_uuids = Vec::new();
+        let excluded_batch_uuids = self.incomplete_batches(batch_ids)?;
+        let batch_ids: Vec<(Uuid, NaiveDateTime)> = batch_ids
+            .iter()
+            .filter(|(batch_id, _)| !excluded_batch_uuids.contains(batch_id))
+            .cloned()
+            .collect();
+        let batch_ids = &batch_ids[..];
 
+        // Every batch in the aggregation must be encoded with the parameters
+        // of the first one.
         let ingestion_header = self.ingestion_header(&batch_ids[0].0, &batch_ids[0].1)?;
+        let field = PrioField::from_prime(ingestion_header.prime)
+            .map_err(|e| ValidationError::Malformed(format!("{:#}", e)))?;
+        field
+            .check_bins(ingestion_header.bins)
+            .map_err(|e| ValidationError::Malformed(format!("{:#}", e)))?;
+
+        match field {
+            PrioField::Field32 => self.generate_sum_part_in_field::<Field32, _>(
+                batch_ids,
+                excluded_batch_uuids,
+                ingestion_header,
+                callback,
+            ),
+            PrioField::Field64 => self.generate_sum_part_in_field::<Field64, _>(
+                batch_ids,
+                excluded_batch_uuids,
+                ingestion_header,
+                callback,
+            ),
+        }
+    }
+
+    /// Aggregates the batches, whose packets are encoded in the field F with
+    /// the parameters in the provided ingestion header, and writes out the
+    /// sum part.
+    fn generate_sum_part_in_field<F, C>(
+        &mut self,
+        batch_ids: &[(Uuid, NaiveDateTime)],
+        excluded_batch_uuids: Vec<Uuid>,
+        ingestion_header: IngestionHeader,
+        mut callback: C,
+    ) -> Result<()>
+    where
+        F: EncodedField,
+        C: FnMut(&Logger) -> Result<()>,
+    {
+        let mut rejected_packets = RejectedPackets::new()?;
+        let mut included_batch_uuids = Vec::new();
 
         // Ideally, we would use the encryption_key_id in the ingestion packet
         // to figure out which private key to use for decryption, but that field
         // is optional. Instead we try all the keys we have available until one
         // works.
         // https://github.com/abetterinternet/prio-server/issues/73
-        let mut servers: Vec<Server<Field32>> = self
+        // Packets encrypted with HPKE are decrypted by an HpkePacketDecryptor
+        // and aggregated by its server, which follows those for the ECIES
+        // keys.
+        let hpke_decryptor = if self
+            .ingestion_transport
+            .hpke_packet_decryption_keys
+            .is_empty()
+        {
+            None
+        } else {
+            Some(HpkePacketDecryptor::new(
+                &self.ingestion_transport.hpke_packet_decryption_keys,
+            )?)
+        };
+        let prio_backend = self.prio_backend;
+        let mut servers: Vec<Box<dyn PrioCrypto<F>>> = self
             .ingestion_transport
             .packet_decryption_keys
             .iter()
-            .map(|k| Server::new(ingestion_header.bins as usize, self.is_first, k.clone()))
+            .map(|k| prio_backend.server(ingestion_header.bins as usize, self.is_first, k.clone()))
+            .chain(hpke_decryptor.iter().map(|decryptor| {
+                decryptor.server(prio_backend, ingestion_header.bins as usize, self.is_first)
+            }))
             .collect();
 
-        for batch_id in batch_ids {
-            self.aggregate_share(&batch_id.0, &batch_id.1, &mut servers, &mut invalid_uuids)?;
+        let resumed_batches = match self.load_checkpoint()? {
+            Some(checkpoint) => self.restore_checkpoint(
+                checkpoint,
+                batch_ids,
+                &mut servers,
+                &mut rejected_packets,
+                &mut included_batch_uuids,
+            )?,
+            None => 0,
+        };
+
+        for batch_id in &batch_ids[resumed_batches..] {
+            let batch_span = info_span!("aggregate_batch", batch_id = %batch_id.0);
+            if let Some(batch_traceparent) = self.batch_traceparents.get(&batch_id.0) {
+                set_traceparent(&batch_span, batch_traceparent);
+                batch_span.follows_from(&self.span);
+            }
+            batch_span.in_scope(|| {
+                self.aggregate_share(
+                    &batch_id.0,
+                    &batch_id.1,
+                    &ingestion_header,
+                    &mut servers,
+                    hpke_decryptor.as_ref(),
+                    &mut rejected_packets,
+                )
+            })?;
             included_batch_uuids.push(batch_id.0);
-            callback(&self.logger);
+            if included_batch_uuids.len() % self.checkpoint_interval == 0
+                && included_batch_uuids.len() < batch_ids.len()
+            {
+                self.store_checkpoint(&included_batch_uuids, &servers, &mut rejected_packets)?;
+            }
+            callback(&self.logger)?;
         }
 
+        let _write_sum_part_span = info_span!("write_sum_part").entered();
+
         // TODO(timg) what exactly do we write out when there are no invalid
         // packets? Right now we will write an empty file.
         let invalid_packets_digest =
             self.aggregation_batch
                 .packet_file_writer(|mut packet_file_writer| {
-                    for invalid_uuid in invalid_uuids {
-                        InvalidPacket { uuid: invalid_uuid }.write(&mut packet_file_writer)?
+                    for rejected_packet in rejected_packets.iter()? {
+                        InvalidPacket {
+                            uuid: rejected_packet?.packet_uuid,
+                        }
+                        .write(&mut packet_file_writer)?
                     }
                     Ok(())
                 })?;
+        self.write_rejected_packets_report(&mut rejected_packets)?;
 
         // We have one Server for each packet decryption key, and each of those
         // instances could contain some accumulated shares, depending on which
-        // key was used to encrypt an individual packet. We make a new Server
-        // instance into which we will aggregate them all together. It doesn't
-        // matter which private key we use here as we're not decrypting any
-        // packets with this Server instance, just accumulating data vectors.
-        let mut accumulator_server = Server::new(
-            ingestion_header.bins as usize,
-            self.is_first,
-            self.ingestion_transport.packet_decryption_keys[0].clone(),
-        );
+        // key was used to encrypt an individual packet, so we add them all
+        // together.
+        let mut sum = vec![F::zero(); ingestion_header.bins as usize];
         for server in servers.iter() {
-            accumulator_server
-                .merge_total_shares(server.total_shares())
-                .context("failed to accumulate shares")?;
+            for (total, share) in sum.iter_mut().zip(server.total_shares()) {
+                *total += *share;
+            }
         }
 
-        let sum = accumulator_server
-            .total_shares()
-            .iter()
-            .map(|f| u32::from(*f) as i64)
-            .collect();
+        // Each client contributes a vector of zeroes and ones, with at most
+        // hamming_weight ones if it is set.
+        let noise = match self.differential_privacy {
+            Some(differential_privacy) => {
+                let max_ones = u32::try_from(
+                    ingestion_header
+                        .hamming_weight
+                        .unwrap_or(ingestion_header.bins),
+                )
+                .context("invalid hamming weight or bin count in ingestion header")?;
+                let noise = differential_privacy.calibrate(max_ones);
+                noise.add_noise(&mut sum, &mut rand::thread_rng());
+                info!(
+                    self.logger, "added differential privacy noise to sum";
+                    "mechanism" => noise.mechanism.as_str(),
+                    "epsilon" => noise.epsilon,
+                    "delta" => noise.delta,
+                    "scale" => noise.scale,
+                );
+                Some(noise)
+            }
+            None => None,
+        };
+
+        let sum = sum.iter().map(|f| f.encode()).collect();
+
+        let sum_part = SumPart {
+            batch_uuids_digest: Some(SumPart::batch_uuids_digest(&included_batch_uuids)),
+            excluded_batch_uuids_digest: Some(SumPart::batch_uuids_digest(&excluded_batch_uuids)),
+            batch_uuids: included_batch_uuids,
+            name: ingestion_header.name,
+            bins: ingestion_header.bins,
+            epsilon: ingestion_header.epsilon,
+            prime: ingestion_header.prime,
+            number_of_servers: ingestion_header.number_of_servers,
+            hamming_weight: ingestion_header.hamming_weight,
+            sum,
+            aggregation_start_time: self.aggregation_start.timestamp_millis(),
+            aggregation_end_time: self.aggregation_end.timestamp_millis(),
+            packet_file_digest: invalid_packets_digest.as_ref().to_vec(),
+            total_individual_clients: self.total_individual_clients,
+            excluded_batch_uuids,
+            noise_mechanism: noise.as_ref().map(|n| n.mechanism.as_str().to_owned()),
+            noise_epsilon: noise.as_ref().map(|n| n.epsilon),
+            noise_delta: noise.as_ref().map(|n| n.delta),
+            noise_scale: noise.as_ref().map(|n| n.scale),
+            facilitator_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            instance_name: Some(self.instance_name.clone()),
+            is_first: Some(self.is_first),
+            min_complete_batches_percent: Some(self.min_complete_batches_percent),
+            rejected_packet_count: Some(
+                i64::try_from(rejected_packets.count).context("too many rejected packets")?,
+            ),
+        };
+        let mut encoded_sum_part = Vec::new();
+        sum_part
+            .write(&mut encoded_sum_part)
+            .context("failed to encode sum part")?;
+        let sum_part_digest = hex::encode(digest(&SHA256, &encoded_sum_part));
 
-        let sum_signature = self.aggregation_batch.put_header(
-            &SumPart {
-                batch_uuids: included_batch_uuids,
-                name: ingestion_header.name,
-                bins: ingestion_header.bins,
-                epsilon: ingestion_header.epsilon,
-                prime: ingestion_header.prime,
-                number_of_servers: ingestion_header.number_of_servers,
-                hamming_weight: ingestion_header.hamming_weight,
-                sum,
-                aggregation_start_time: self.aggregation_start.timestamp_millis(),
-                aggregation_end_time: self.aggregation_end.timestamp_millis(),
-                packet_file_digest: invalid_packets_digest.as_ref().to_vec(),
-                total_individual_clients: self.total_individual_clients,
-            },
-            &self.share_processor_signing_key.key,
-        )?;
+        let sum_signature = self
+            .aggregation_batch
+            .put_header(&sum_part, self.share_processor_signer)?;
 
         self.aggregation_batch
-            .put_signature(&sum_signature, &self.share_processor_signing_key.identifier)
+            .put_signature(&sum_signature, self.share_processor_signer.key_identifier())?;
+
+        if let Some(transport) = self.checkpoint_transport.as_deref_mut() {
+            transport.delete(&self.checkpoint_key, self.trace_id)?;
+        }
+        self.sum_part_digest = Some(sum_part_digest);
+        Ok(())
+    }
+
+    /// Returns the UUIDs of batches for which either our own or the peer's
+    /// validation batch is missing, or an error if too few batches are
+    /// complete. Unless partial aggregation was allowed, no batches are
+    /// checked and none are returned.
+    fn incomplete_batches(&mut self, batch_ids: &[(Uuid, NaiveDateTime)]) -> Result<Vec<Uuid>> {
+        if self.min_complete_batches_percent >= 100.0 {
+            return Ok(vec![]);
+        }
+
+        let mut complete_batches = 0;
+        let mut excluded_batch_uuids = Vec::new();
+        for (batch_id, batch_date) in batch_ids {
+            let own_validation_batch =
+                Batch::new_validation(self.aggregation_name, batch_id, batch_date, self.is_first);
+            let peer_validation_batch =
+                Batch::new_validation(self.aggregation_name, batch_id, batch_date, !self.is_first);
+            if self
+                .own_validation_transport
+                .transport
+                .exists(own_validation_batch.signature_key(), self.trace_id)?
+                && self
+                    .peer_validation_transport
+                    .transport
+                    .exists(peer_validation_batch.signature_key(), self.trace_id)?
+            {
+                complete_batches += 1;
+            } else {
+                excluded_batch_uuids.push(*batch_id);
+            }
+        }
+
+        let complete_percent = 100.0 * complete_batches as f64 / batch_ids.len() as f64;
+        ensure!(
+            complete_batches > 0 && complete_percent >= self.min_complete_batches_percent,
+            "only {} of {} batches have both validation batches, fewer than the required {}%",
+            complete_batches,
+            batch_ids.len(),
+            self.min_complete_batches_percent
+        );
+        if !excluded_batch_uuids.is_empty() {
+            warn!(
+                self.logger, "excluding batches missing a validation batch";
+                "excluded_batch_uuids" => format!("{:?}", excluded_batch_uuids),
+            );
+            if let Some(collector) = self.metrics_collector {
+                collector
+                    .batches_excluded
+                    .inc_by(excluded_batch_uuids.len() as u64);
+            }
+        }
+        Ok(excluded_batch_uuids)
+    }
+
+    /// Returns the checkpoint of an earlier attempt at this aggregation, if
+    /// resuming was requested and there is one.
+    fn load_checkpoint(&mut self) -> Result<Option<AggregationCheckpoint>> {
+        let transport = match self.checkpoint_transport.as_deref_mut() {
+            Some(transport) if self.resume => transport,
+            _ => return Ok(None),
+        };
+        if !transport.exists(&self.checkpoint_key, self.trace_id)? {
+            info!(self.logger, "no aggregation checkpoint to resume from");
+            return Ok(None);
+        }
+        let mut encoded = Vec::new();
+        transport
+            .get(&self.checkpoint_key, self.trace_id)?
+            .read_to_end(&mut encoded)
+            .context("failed to read aggregation checkpoint")?;
+        AggregationCheckpoint::decode(&encoded).map(Some)
+    }
+
+    /// Restores the state of the aggregation from the provided checkpoint,
+    /// returning the number of batches it covers.
+    fn restore_checkpoint<F: EncodedField>(
+        &mut self,
+        checkpoint: AggregationCheckpoint,
+        batch_ids: &[(Uuid, NaiveDateTime)],
+        servers: &mut [Box<dyn PrioCrypto<F>>],
+        rejected_packets: &mut RejectedPackets,
+        included_batch_uuids: &mut Vec<Uuid>,
+    ) -> Result<usize> {
+        ensure!(
+            checkpoint.batch_uuids.len() <= batch_ids.len()
+                && checkpoint
+                    .batch_uuids
+                    .iter()
+                    .zip(batch_ids)
+                    .all(|(checkpoint_uuid, (uuid, _))| checkpoint_uuid == uuid),
+            "aggregation checkpoint does not match the batches being aggregated"
+        );
+        ensure!(
+            checkpoint.total_shares.len() == servers.len(),
+            "aggregation checkpoint has shares for {} servers, but there are {}",
+            checkpoint.total_shares.len(),
+            servers.len()
+        );
+        for (server, total_shares) in servers.iter_mut().zip(&checkpoint.total_shares) {
+            let total_shares = total_shares
+                .iter()
+                .map(|f| F::decode(*f))
+                .collect::<Result<Vec<_>>>()
+                .context("invalid shares in aggregation checkpoint")?;
+            server
+                .merge_total_shares(&total_shares)
+                .context("failed to restore shares from aggregation checkpoint")?;
+        }
+        for rejected_packet in &checkpoint.rejected_packets {
+            rejected_packets.push(rejected_packet)?;
+        }
+        self.total_individual_clients = checkpoint.total_individual_clients;
+        included_batch_uuids.extend_from_slice(&checkpoint.batch_uuids);
+
+        if self.packet_deduplicator.is_some() {
+            warn!(
+                self.logger,
+                "packets aggregated before the checkpoint are not deduplicated"
+            );
+        }
+        info!(
+            self.logger, "resuming aggregation from checkpoint";
+            "batch_count" => checkpoint.batch_uuids.len(),
+        );
+        Ok(checkpoint.batch_uuids.len())
+    }
+
+    /// Writes a checkpoint of the aggregation state, if a checkpoint transport
+    /// was provided.
+    fn store_checkpoint<F: EncodedField>(
+        &mut self,
+        included_batch_uuids: &[Uuid],
+        servers: &[Box<dyn PrioCrypto<F>>],
+        rejected_packets: &mut RejectedPackets,
+    ) -> Result<()> {
+        let transport = match self.checkpoint_transport.as_deref_mut() {
+            Some(transport) => transport,
+            None => return Ok(()),
+        };
+        let encoded = AggregationCheckpoint {
+            batch_uuids: included_batch_uuids.to_vec(),
+            total_shares: servers
+                .iter()
+                .map(|server| server.total_shares().iter().map(|f| f.encode()).collect())
+                .collect(),
+            rejected_packets: rejected_packets.read_all()?,
+            total_individual_clients: self.total_individual_clients,
+        }
+        .encode()?;
+
+        let mut writer = transport.put(&self.checkpoint_key, self.trace_id)?;
+        let result = writer
+            .write_all(&encoded)
+            .context("failed to write aggregation checkpoint")
+            .and_then(|_| writer.complete_upload());
+        if result.is_err() {
+            // Don't mask the original error with any failure to cancel
+            let _ = writer.cancel_upload();
+        }
+        result?;
+        info!(
+            self.logger, "wrote aggregation checkpoint";
+            "batch_count" => included_batch_uuids.len(),
+        );
+        Ok(())
+    }
+
+    /// Writes the report of rejected packets, if a transport for it was
+    /// provided.
+    fn write_rejected_packets_report(
+        &mut self,
+        rejected_packets: &mut RejectedPackets,
+    ) -> Result<()> {
+        let transport = match self.rejected_packets_transport.as_deref_mut() {
+            Some(transport) => transport,
+            None => return Ok(()),
+        };
+        let mut writer = transport.put(&self.rejected_packets_key, self.trace_id)?;
+        let result = (|| {
+            for rejected_packet in rejected_packets.iter()? {
+                serde_json::to_writer(&mut writer, &rejected_packet?)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.complete_upload()
+        })()
+        .context("failed to write rejected packets report");
+        if result.is_err() {
+            // Don't mask the original error with any failure to cancel
+            let _ = writer.cancel_upload();
+        }
+        result?;
+        info!(
+            self.logger, "wrote rejected packets report";
+            "rejected_packet_count" => rejected_packets.count,
+        );
+        Ok(())
     }
 
     /// Fetch the ingestion header from one of the batches so various parameters
@@ -208,14 +855,17 @@ impl<'a> BatchAggregator<'a> {
     }
 
     /// Aggregate the batch for the provided batch_id into the provided server.
-    /// The UUIDs of packets for which aggregation fails are recorded in the
-    /// provided invalid_uuids vector.
-    fn aggregate_share(
+    /// The batch's parameters must match those in the provided ingestion
+    /// header of the aggregation's first batch. Packets for which aggregation
+    /// fails are recorded in the provided rejected_packets.
+    fn aggregate_share<F: EncodedField>(
         &mut self,
         batch_id: &Uuid,
         batch_date: &NaiveDateTime,
-        servers: &mut Vec<Server<Field32>>,
-        invalid_uuids: &mut Vec<Uuid>,
+        first_ingestion_header: &IngestionHeader,
+        servers: &mut [Box<dyn PrioCrypto<F>>],
+        hpke_decryptor: Option<&HpkePacketDecryptor>,
+        rejected_packets: &mut RejectedPackets,
     ) -> Result<()> {
         let mut ingestion_batch: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchReader::new(
@@ -241,6 +891,8 @@ impl<'a> BatchAggregator<'a> {
                 self.trace_id,
                 &self.logger,
             );
+        own_validation_batch.set_digest_algorithm(self.own_validation_digest_algorithm);
+        peer_validation_batch.set_digest_algorithm(self.peer_validation_digest_algorithm);
 
         if let Some(collector) = self.metrics_collector {
             own_validation_batch
@@ -259,65 +911,81 @@ impl<'a> BatchAggregator<'a> {
             .header(&self.ingestion_transport.transport.batch_signing_public_keys)?;
 
         // Make sure all the parameters in the headers line up
+        if ingestion_header.bins != first_ingestion_header.bins
+            || ingestion_header.prime != first_ingestion_header.prime
+        {
+            return Err(ValidationError::HeaderMismatch(format!(
+                "ingestion header parameters differ from those of the aggregation's first batch. \
+                Ingestion: {:?}\nFirst: {:?}",
+                ingestion_header, first_ingestion_header
+            ))
+            .into());
+        }
         if !peer_validation_header.check_parameters(&own_validation_header) {
-            return Err(anyhow!(
+            return Err(ValidationError::HeaderMismatch(format!(
                 "validation headers do not match. Peer: {:?}\nOwn: {:?}",
-                peer_validation_header,
-                own_validation_header
-            ));
+                peer_validation_header, own_validation_header
+            ))
+            .into());
         }
         if !ingestion_header.check_parameters(&peer_validation_header) {
-            return Err(anyhow!(
+            return Err(ValidationError::HeaderMismatch(format!(
                 "ingestion header does not match peer validation header. Ingestion: {:?}\nPeer:{:?}",
-                ingestion_header,
-                peer_validation_header
-            ));
+                ingestion_header, peer_validation_header
+            ))
+            .into());
         }
 
         // We can't be sure that the peer validation, own validation and
         // ingestion batches contain all the same packets or that they are in
-        // the same order. There could also be duplicate packets. Compared to
-        // the ingestion packets, the validation packets are small (they only
-        // contain a UUID and three i64s), so we construct a hashmap of the
-        // validation batches, mapping packet UUID to a ValidationPacket, then
-        // iterate over the ingestion packets. For each ingestion packet, if we
-        // have the corresponding validation packets and the proofs are good, we
+        // the same order. There could also be duplicate packets. Validation
+        // batches are normally written in the same order as the ingestion
+        // batch, so we read all three packet files in step, looking up the
+        // validation packets for each ingestion packet as we go. If we have
+        // the corresponding validation packets and the proofs are good, we
         // accumulate. Otherwise we drop the packet and move on.
-        let mut peer_validation_packet_file_reader =
-            peer_validation_batch.packet_file_reader(&peer_validation_header)?;
-        let peer_validation_packets: HashMap<Uuid, ValidationPacket> =
-            validation_packet_map(&mut peer_validation_packet_file_reader)?;
-
-        let mut own_validation_packet_file_reader =
-            own_validation_batch.packet_file_reader(&own_validation_header)?;
-
-        let own_validation_packets: HashMap<Uuid, ValidationPacket> =
-            validation_packet_map(&mut own_validation_packet_file_reader)?;
+        let mut peer_validation_packets = ValidationPacketStream::new(
+            peer_validation_batch.packet_file_reader(&peer_validation_header)?,
+        );
+        let mut own_validation_packets = ValidationPacketStream::new(
+            own_validation_batch.packet_file_reader(&own_validation_header)?,
+        );
 
         // Keep track of the ingestion packets we have seen so we can reject
-        // duplicates.
-        let mut processed_ingestion_packets = HashSet::new();
-        let mut ingestion_packet_reader = ingestion_batch.packet_file_reader(&ingestion_header)?;
+        // duplicates. A deduplicator spanning the whole aggregation also
+        // catches duplicates within this batch.
+        let mut batch_deduplicator = match self.packet_deduplicator {
+            Some(_) => None,
+            None => Some(PacketDeduplicator::new(
+                BATCH_DEDUP_WINDOW,
+                BATCH_DEDUP_MAX_IN_MEMORY,
+            )),
+        };
+        let ingestion_packet_reader = ingestion_batch.packet_file_reader(&ingestion_header)?;
 
         // Borrowing distinct parts of a struct works, but not under closures:
         // https://github.com/rust-lang/rust/issues/53488
         // The workaround is to borrow or copy fields outside the closure.
         let logger = &self.logger;
 
-        loop {
-            let ingestion_packet =
-                match IngestionDataSharePacket::read(&mut ingestion_packet_reader) {
-                    Ok(p) => p,
-                    Err(Error::EofError) => break,
-                    Err(e) => return Err(e.into()),
-                };
+        for ingestion_packet in ingestion_packet_reader {
+            let ingestion_packet = ingestion_packet?;
 
-            // Ignore duplicate packets
-            if processed_ingestion_packets.contains(&ingestion_packet.uuid) {
+            // Ignore duplicate packets, whether earlier in this batch or in
+            // another batch in the aggregation
+            let deduplicator = match self.packet_deduplicator.as_mut() {
+                Some(deduplicator) => deduplicator,
+                None => batch_deduplicator.as_mut().unwrap(),
+            };
+            let is_duplicate = deduplicator.contains(&ingestion_packet.uuid)?;
+            if is_duplicate {
                 info!(
                     logger, "ignoring duplicate packet";
                     event::PACKET_UUID => ingestion_packet.uuid.to_string()
                 );
+                if let Some(collector) = self.metrics_collector {
+                    collector.duplicate_packets_dropped.inc();
+                }
                 continue;
             }
 
@@ -325,37 +993,55 @@ impl<'a> BatchAggregator<'a> {
             // matching the ingestion packet.
             let peer_validation_packet = get_validation_packet(
                 &ingestion_packet.uuid,
-                &peer_validation_packets,
-                "peer",
-                invalid_uuids,
+                batch_id,
+                &mut peer_validation_packets,
+                RejectionReason::MissingPeerValidationPacket,
+                rejected_packets,
+                self.metrics_collector,
                 logger,
-            );
-            let peer_validation_packet: &ValidationPacket = match peer_validation_packet {
+            )?;
+            let peer_validation_packet = match peer_validation_packet {
                 Some(p) => p,
                 None => continue,
             };
 
             let own_validation_packet = get_validation_packet(
                 &ingestion_packet.uuid,
-                &own_validation_packets,
-                "own",
-                invalid_uuids,
+                batch_id,
+                &mut own_validation_packets,
+                RejectionReason::MissingOwnValidationPacket,
+                rejected_packets,
+                self.metrics_collector,
                 logger,
-            );
-            let own_validation_packet: &ValidationPacket = match own_validation_packet {
+            )?;
+            let own_validation_packet = match own_validation_packet {
                 Some(p) => p,
                 None => continue,
             };
 
-            processed_ingestion_packets.insert(ingestion_packet.uuid);
+            deduplicator.insert(&ingestion_packet.uuid)?;
+
+            // Shares decrypted with HPKE may only be aggregated by the HPKE
+            // decryptor's server, which is the last one, and ECIES payloads
+            // by the others.
+            let (payload, candidate_servers) = match hpke_decryptor {
+                Some(hpke_decryptor) => {
+                    let (hpke_server, ecies_servers) = servers.split_last_mut().unwrap();
+                    match hpke_decryptor.decrypt(&ingestion_packet)? {
+                        Some(share) => (share, std::slice::from_mut(hpke_server)),
+                        None => (ingestion_packet.encrypted_payload, ecies_servers),
+                    }
+                }
+                None => (ingestion_packet.encrypted_payload, &mut *servers),
+            };
 
             let mut did_aggregate_shares = false;
             let mut last_err = None;
-            for server in servers.iter_mut() {
+            for server in candidate_servers.iter_mut() {
                 match server.aggregate(
-                    &ingestion_packet.encrypted_payload,
-                    &VerificationMessage::try_from(peer_validation_packet)?,
-                    &VerificationMessage::try_from(own_validation_packet)?,
+                    &payload,
+                    &VerificationMessage::try_from(&peer_validation_packet)?,
+                    &VerificationMessage::try_from(&own_validation_packet)?,
                 ) {
                     Ok(valid) => {
                         if !valid {
@@ -363,7 +1049,20 @@ impl<'a> BatchAggregator<'a> {
                                 logger, "rejecting packet due to invalid proof";
                                 event::PACKET_UUID => peer_validation_packet.uuid.to_string(),
                             );
-                            invalid_uuids.push(peer_validation_packet.uuid);
+                            let reason = RejectionReason::InvalidProof;
+                            rejected_packets.push(&RejectedPacket {
+                                packet_uuid: peer_validation_packet.uuid,
+                                batch_uuid: *batch_id,
+                                reason,
+                            })?;
+                            if let Some(collector) = self.metrics_collector {
+                                collector
+                                    .packets_rejected
+                                    .with_label_values(&[reason.as_str()])
+                                    .inc();
+                            }
+                        } else if let Some(collector) = self.metrics_collector {
+                            collector.packets_aggregated.inc();
                         }
                         self.total_individual_clients += 1;
                         did_aggregate_shares = true;
@@ -392,37 +1091,343 @@ impl<'a> BatchAggregator<'a> {
     }
 }
 
-fn validation_packet_map(
-    reader: &mut Reader<Cursor<Vec<u8>>>,
-) -> Result<HashMap<Uuid, ValidationPacket>> {
-    let mut map = HashMap::new();
-    loop {
-        match ValidationPacket::read(reader) {
-            Ok(p) => {
-                map.insert(p.uuid, p);
+/// ValidationPacketStream looks up the validation packets matching a sequence
+/// of ingestion packets in a validation packet file. When the validation
+/// packets are in the same order as the ingestion packets, as they are when
+/// written by this facilitator, each lookup consumes the next packet and none
+/// are held in memory. Packets read past while looking for another are kept
+/// so that they can still be found, at the cost of memory.
+struct ValidationPacketStream<I> {
+    packets: I,
+    skipped: HashMap<Uuid, ValidationPacket>,
+}
+
+impl<I, E> ValidationPacketStream<I>
+where
+    I: Iterator<Item = Result<ValidationPacket, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    fn new(packets: I) -> Self {
+        ValidationPacketStream {
+            packets,
+            skipped: HashMap::new(),
+        }
+    }
+
+    /// Returns the validation packet with the provided UUID, if there is one
+    /// that has not already been returned.
+    fn take(&mut self, uuid: &Uuid) -> Result<Option<ValidationPacket>> {
+        if let Some(packet) = self.skipped.remove(uuid) {
+            return Ok(Some(packet));
+        }
+        for packet in self.packets.by_ref() {
+            let packet = packet?;
+            if packet.uuid == *uuid {
+                return Ok(Some(packet));
             }
-            Err(Error::EofError) => return Ok(map),
-            Err(e) => return Err(e.into()),
+            self.skipped.insert(packet.uuid, packet);
         }
+        Ok(None)
     }
 }
 
-fn get_validation_packet<'a>(
+/// Size of a rejected packet in a RejectedPackets file: the packet's UUID, its
+/// batch's UUID and the reason it was rejected.
+const REJECTED_PACKET_LEN: usize = 33;
+
+/// RejectedPackets holds the packets rejected during aggregation in a
+/// temporary file, until they are written to the sum part's packet file and
+/// the rejected packets report.
+struct RejectedPackets {
+    writer: BufWriter<File>,
+    count: u64,
+}
+
+impl RejectedPackets {
+    fn new() -> Result<Self> {
+        Ok(RejectedPackets {
+            writer: BufWriter::new(
+                tempfile::tempfile().context("failed to create rejected packets file")?,
+            ),
+            count: 0,
+        })
+    }
+
+    fn push(&mut self, rejected_packet: &RejectedPacket) -> Result<()> {
+        let mut encoded = [0u8; REJECTED_PACKET_LEN];
+        encoded[..16].copy_from_slice(rejected_packet.packet_uuid.as_bytes());
+        encoded[16..32].copy_from_slice(rejected_packet.batch_uuid.as_bytes());
+        encoded[32] = rejected_packet.reason.to_byte();
+        self.writer
+            .write_all(&encoded)
+            .context("failed to write rejected packets file")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the packets pushed so far, in the order they were pushed.
+    fn read_all(&mut self) -> Result<Vec<RejectedPacket>> {
+        let rejected_packets = self.iter()?.collect::<Result<Vec<_>>>()?;
+        self.writer
+            .get_mut()
+            .seek(SeekFrom::End(0))
+            .context("failed to seek rejected packets file")?;
+        Ok(rejected_packets)
+    }
+
+    /// Returns an iterator over the packets, in the order they were pushed.
+    /// Packets may not be pushed afterward, except after read_all.
+    fn iter(&mut self) -> Result<impl Iterator<Item = Result<RejectedPacket>> + '_> {
+        self.writer
+            .flush()
+            .context("failed to flush rejected packets file")?;
+        let file = self.writer.get_mut();
+        file.seek(SeekFrom::Start(0))
+            .context("failed to rewind rejected packets file")?;
+        let mut reader = BufReader::new(file);
+        Ok((0..self.count).map(move |_| {
+            let mut encoded = [0u8; REJECTED_PACKET_LEN];
+            reader
+                .read_exact(&mut encoded)
+                .context("failed to read rejected packets file")?;
+            Ok(RejectedPacket {
+                packet_uuid: Uuid::from_slice(&encoded[..16])?,
+                batch_uuid: Uuid::from_slice(&encoded[16..32])?,
+                reason: RejectionReason::from_byte(encoded[32])?,
+            })
+        }))
+    }
+}
+
+fn get_validation_packet<I, E>(
     uuid: &Uuid,
-    validation_packets: &'a HashMap<Uuid, ValidationPacket>,
-    kind: &str,
-    invalid_uuids: &mut Vec<Uuid>,
+    batch_id: &Uuid,
+    validation_packets: &mut ValidationPacketStream<I>,
+    reason: RejectionReason,
+    rejected_packets: &mut RejectedPackets,
+    metrics_collector: Option<&AggregateMetricsCollector>,
     logger: &Logger,
-) -> Option<&'a ValidationPacket> {
-    match validation_packets.get(uuid) {
+) -> Result<Option<ValidationPacket>>
+where
+    I: Iterator<Item = Result<ValidationPacket, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match validation_packets.take(uuid)? {
         None => {
             info!(
-                logger, "no {} validation packet", kind;
-                event::PACKET_UUID => uuid.to_string()
+                logger, "rejecting packet";
+                event::PACKET_UUID => uuid.to_string(),
+                "reason" => reason.as_str(),
             );
-            invalid_uuids.push(*uuid);
-            None
+            rejected_packets.push(&RejectedPacket {
+                packet_uuid: *uuid,
+                batch_uuid: *batch_id,
+                reason,
+            })?;
+            if let Some(collector) = metrics_collector {
+                collector
+                    .packets_rejected
+                    .with_label_values(&[reason.as_str()])
+                    .inc();
+            }
+            Ok(None)
+        }
+        result => Ok(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    fn validation_packet(uuid: Uuid) -> ValidationPacket {
+        ValidationPacket {
+            uuid,
+            f_r: 1,
+            g_r: 2,
+            h_r: 3,
+        }
+    }
+
+    #[test]
+    fn discover_batches_in_window() {
+        let mut transport = MemoryTransport::new();
+        let date = |s| NaiveDateTime::parse_from_str(s, crate::DATE_FORMAT).unwrap();
+        let batches: Vec<(Uuid, NaiveDateTime)> = [
+            "2021/06/30/23/59",
+            "2021/07/01/00/00",
+            "2021/07/01/12/30",
+            "2021/07/02/00/00",
+            "2021/07/02/23/59",
+            "2021/07/03/00/00",
+        ]
+        .iter()
+        .map(|d| (Uuid::new_v4(), date(d)))
+        .collect();
+        for (batch_id, batch_date) in &batches {
+            let batch = Batch::new_ingestion("kittens-seen", batch_id, batch_date);
+            for key in &[batch.header_key(), batch.signature_key()] {
+                transport.put(key, "").unwrap().complete_upload().unwrap();
+            }
+            let other = Batch::new_ingestion("puppies-seen", batch_id, batch_date);
+            transport
+                .put(other.signature_key(), "")
+                .unwrap()
+                .complete_upload()
+                .unwrap();
         }
-        result => result,
+        // Batches without signatures are still being written
+        let unsigned = Batch::new_ingestion("kittens-seen", &Uuid::new_v4(), &batches[2].1);
+        transport
+            .put(unsigned.header_key(), "")
+            .unwrap()
+            .complete_upload()
+            .unwrap();
+
+        let start = date("2021/07/01/00/00");
+        let end = date("2021/07/03/00/00");
+        assert_eq!(
+            discover_batches(&mut transport, "kittens-seen", &start, &end, None, "").unwrap(),
+            batches[1..5].to_vec()
+        );
+        assert_eq!(
+            discover_batches(
+                &mut transport,
+                "kittens-seen",
+                &start,
+                &end,
+                Some("kittens-seen/2021/07/0?/00/"),
+                ""
+            )
+            .unwrap(),
+            vec![batches[1], batches[3]]
+        );
+        assert!(discover_batches(
+            &mut transport,
+            "kittens-seen",
+            &start,
+            &end,
+            Some("kittens-seen/*/08/"),
+            ""
+        )
+        .unwrap()
+        .is_empty());
+    }
+
+    #[test]
+    fn glob_prefixes() {
+        for (pattern, text, matches) in &[
+            ("", "a/b", true),
+            ("a/", "a/b", true),
+            ("a/b/c", "a/b", false),
+            ("*/b", "a/b", true),
+            ("*/b", "aaa/b", true),
+            ("*b", "a/b", false),
+            ("a?c", "abc/d", true),
+            ("a?c", "a/c", false),
+            ("x*", "a/b", false),
+            ("a/*/c", "a/bb/c/d", true),
+        ] {
+            assert_eq!(
+                glob_matches_prefix(pattern.as_bytes(), text.as_bytes()),
+                *matches,
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn validation_packet_stream() {
+        let uuids: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+
+        // Packets in the same order as the lookups are never held in memory
+        let mut stream = ValidationPacketStream::new(
+            uuids
+                .iter()
+                .map(|uuid| Ok::<_, std::io::Error>(validation_packet(*uuid))),
+        );
+        for uuid in &uuids {
+            assert_eq!(stream.take(uuid).unwrap().unwrap().uuid, *uuid);
+            assert!(stream.skipped.is_empty());
+        }
+        assert_eq!(stream.take(&uuids[0]).unwrap(), None);
+
+        // Packets out of order or missing are still handled
+        let reordered = [uuids[2], uuids[0], uuids[3]];
+        let mut stream = ValidationPacketStream::new(
+            reordered
+                .iter()
+                .map(|uuid| Ok::<_, std::io::Error>(validation_packet(*uuid))),
+        );
+        assert_eq!(stream.take(&uuids[0]).unwrap().unwrap().uuid, uuids[0]);
+        assert_eq!(stream.skipped.len(), 1);
+        assert_eq!(stream.take(&uuids[1]).unwrap(), None);
+        assert_eq!(stream.take(&uuids[2]).unwrap().unwrap().uuid, uuids[2]);
+        assert_eq!(stream.take(&uuids[3]).unwrap().unwrap().uuid, uuids[3]);
+        assert!(stream.skipped.is_empty());
+    }
+
+    #[test]
+    fn aggregation_checkpoint_digest() {
+        let checkpoint = AggregationCheckpoint {
+            batch_uuids: vec![Uuid::new_v4(), Uuid::new_v4()],
+            total_shares: vec![vec![1, 2, 3], vec![4, 5, 6]],
+            rejected_packets: vec![RejectedPacket {
+                packet_uuid: Uuid::new_v4(),
+                batch_uuid: Uuid::new_v4(),
+                reason: RejectionReason::InvalidProof,
+            }],
+            total_individual_clients: 10,
+        };
+        let encoded = checkpoint.encode().unwrap();
+        assert_eq!(AggregationCheckpoint::decode(&encoded).unwrap(), checkpoint);
+
+        let mut stored: StoredAggregationCheckpoint = serde_json::from_slice(&encoded).unwrap();
+        stored.checkpoint = stored.checkpoint.replace(
+            "\"total_individual_clients\":10",
+            "\"total_individual_clients\":11",
+        );
+        let tampered = serde_json::to_vec(&stored).unwrap();
+        AggregationCheckpoint::decode(&tampered).unwrap_err();
+    }
+
+    #[test]
+    fn rejected_packets() {
+        let reasons = [
+            RejectionReason::InvalidProof,
+            RejectionReason::MissingPeerValidationPacket,
+            RejectionReason::MissingOwnValidationPacket,
+        ];
+        let packets: Vec<RejectedPacket> = (0..10)
+            .map(|i| RejectedPacket {
+                packet_uuid: Uuid::new_v4(),
+                batch_uuid: Uuid::new_v4(),
+                reason: reasons[i % reasons.len()],
+            })
+            .collect();
+        let mut rejected_packets = RejectedPackets::new().unwrap();
+        for packet in &packets[..5] {
+            rejected_packets.push(packet).unwrap();
+        }
+        assert_eq!(rejected_packets.read_all().unwrap(), &packets[..5]);
+        for packet in &packets[5..] {
+            rejected_packets.push(packet).unwrap();
+        }
+        let read_packets: Vec<RejectedPacket> = rejected_packets
+            .iter()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(read_packets, packets);
+
+        assert_eq!(RejectedPackets::new().unwrap().iter().unwrap().count(), 0);
+
+        assert_eq!(
+            serde_json::to_value(&packets[1]).unwrap()["reason"],
+            "missing_peer_validation_packet"
+        );
     }
 }
@@ -1,7 +1,7 @@
 use crate::{
     config::Identity,
-    http::{Method, RequestParameters, RetryingAgent},
-    retries,
+    gcp_oauth::GcpOauthTokenProvider,
+    retries::{self, RetryParameters},
 };
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
@@ -20,10 +20,10 @@ use rusoto_core::{
     Region, RusotoError, RusotoResult,
 };
 use rusoto_mock::MockCredentialsProvider;
-use rusoto_sts::WebIdentityProvider;
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient, WebIdentityProvider};
 //use s3::signing::{canonical_request, signed_header_string, signing_key};
 use sha2::{Digest, Sha256};
-use slog::{debug, o, Logger};
+use slog::{o, Logger};
 use std::str;
 use std::{
     boxed::Box,
@@ -87,6 +87,14 @@ pub enum Provider {
     /// AWS Elastic Kubernetes Service. Should only be used when running within
     /// EKS (+Fargate?).
     WebIdentityFromKubernetesEnvironment(AutoRefreshingProvider<WebIdentityProvider>),
+    /// A Rusoto StsAssumeRoleSessionCredentialsProvider that uses credentials
+    /// from another Provider to assume the IAM role identified by role_arn via
+    /// STS AssumeRole, e.g. to access resources in another AWS account. The
+    /// provider is wrapped in an Arc because it does not implement Clone.
+    AssumeRole {
+        role_arn: String,
+        provider: Arc<AutoRefreshingProvider<StsAssumeRoleSessionCredentialsProvider>>,
+    },
     /// Rusoto's mock credentials provider, wrapped in an Arc to provide
     /// Send + Sync. Should only be used in tests.
     Mock(Arc<MockCredentialsProvider>),
@@ -121,6 +129,37 @@ impl Provider {
         Self::Mock(Arc::new(MockCredentialsProvider))
     }
 
+    /// Instantiates a Provider that assumes the IAM role identified by
+    /// `role_arn` using the credentials obtained from `base_provider`. Requests
+    /// to the STS AssumeRole API are sent to the endpoint for `sts_region`.
+    /// Credentials for the assumed role are refreshed automatically before
+    /// they expire.
+    pub fn new_assume_role(
+        role_arn: &str,
+        base_provider: Provider,
+        sts_region: Region,
+    ) -> Result<Self> {
+        let http_client =
+            rusoto_core::HttpClient::new().context("failed to create HTTP client for STS")?;
+        let sts_client = StsClient::new_with(http_client, base_provider, sts_region);
+        let provider = AutoRefreshingProvider::new(StsAssumeRoleSessionCredentialsProvider::new(
+            sts_client,
+            role_arn.to_owned(),
+            // https://docs.aws.amazon.com/credref/latest/refdocs/setting-global-role_session_name.html
+            env::var("AWS_ROLE_SESSION_NAME").unwrap_or_else(|_| "prio-facilitator".to_owned()),
+            None,
+            None,
+            None,
+            None,
+        ))
+        .context("failed to create auto refreshing assume role credentials provider")?;
+
+        Ok(Self::AssumeRole {
+            role_arn: role_arn.to_owned(),
+            provider: Arc::new(provider),
+        })
+    }
+
     fn new_web_identity_from_kubernetes_environment() -> Result<Self> {
         Ok(Self::WebIdentityFromKubernetesEnvironment(
             AutoRefreshingProvider::new(WebIdentityProvider::from_k8s_env()).context(
@@ -129,17 +168,6 @@ impl Provider {
         ))
     }
 
-    // We use workload identity to map GCP service accounts to Kubernetes
-    // service accounts and make an auth token for the GCP account available to
-    // containers in the GKE metadata service. Sadly we can't use the Kubernetes
-    // feature to automount a service account token, because that would provide
-    // the token for the *Kubernetes* service account, not the GCP one.
-    // See terraform/modules/gke/gke.tf and terraform/modules/kuberenetes/kubernetes.tf
-    fn metadata_service_token_url() -> Url {
-        Url::parse("http://metadata.google.internal:80/computeMetadata/v1/instance/service-accounts/default/identity")
-            .expect("could not parse token metadata api url")
-    }
-
     fn new_web_identity_with_oidc(
         iam_role: &str,
         purpose: String,
@@ -156,10 +184,6 @@ impl Provider {
             "purpose" => purpose.clone(),
         ));
         let oidc_token_variable = Variable::dynamic(move || {
-            debug!(
-                token_logger,
-                "obtaining OIDC token from GKE metadata service"
-            );
             let aws_account_id = env::var("AWS_ACCOUNT_ID").map_err(|e| {
                 CredentialsError::new(format!(
                     "could not read AWS account ID from environment: {}",
@@ -167,40 +191,29 @@ impl Provider {
                 ))
             })?;
 
-            // We use ureq for this request because it is designed to use purely
-            // synchronous Rust. Ironically, we are in the context of an async
-            // runtime when this callback is invoked, but because the closure is
-            // not declared async, we cannot use .await to work with Futures.
-            let mut url = Self::metadata_service_token_url();
-
-            url.query_pairs_mut()
-                .append_pair("audience", &format!("sts.amazonaws.com/{}", aws_account_id))
-                .finish();
-
-            let agent = RetryingAgent::default();
-            let mut request = agent
-                .prepare_request(RequestParameters {
-                    url,
-                    method: Method::Get,
-                    ..Default::default()
-                })
-                .map_err(|e| CredentialsError::new(format!("failed to create request: {:?}", e)))?;
-
-            request = request.set("Metadata-Flavor", "Google");
-
-            let response = agent.call(&token_logger, &request).map_err(|e| {
+            // We use workload identity to map GCP service accounts to
+            // Kubernetes service accounts and make an auth token for the GCP
+            // account available to containers in the GKE metadata service.
+            // Sadly we can't use the Kubernetes feature to automount a service
+            // account token, because that would provide the token for the
+            // *Kubernetes* service account, not the GCP one.
+            // See terraform/modules/gke/gke.tf and terraform/modules/kuberenetes/kubernetes.tf
+            let token = GcpOauthTokenProvider::new(
+                "https://www.googleapis.com/auth/cloud-platform",
+                None,
+                None,
+                None,
+                &token_logger,
+            )
+            .and_then(|provider| {
+                provider.ensure_identity_token(&format!("sts.amazonaws.com/{}", aws_account_id))
+            })
+            .map_err(|e| {
                 CredentialsError::new(format!(
                     "failed to fetch {} auth token from metadata service: {:?}",
                     purpose, e
                 ))
             })?;
-
-            let token = response.into_string().map_err(|e| {
-                CredentialsError::new(format!(
-                    "failed to fetch {} auth token from metadata service: {}",
-                    purpose, e
-                ))
-            })?;
             Ok(Secret::from(token))
         });
 
@@ -242,6 +255,7 @@ impl Display for Provider {
                     role_arn
                 )
             }
+            Self::AssumeRole { role_arn, .. } => write!(f, "{} via STS AssumeRole", role_arn),
             Self::Mock(_) => write!(f, "mock credentials"),
         }
     }
@@ -254,6 +268,7 @@ impl ProvideAwsCredentials for Provider {
             Self::Default(p) => p.credentials().await,
             Self::WebIdentityWithOidc(p) => p.credentials().await,
             Self::WebIdentityFromKubernetesEnvironment(p) => p.credentials().await,
+            Self::AssumeRole { provider, .. } => provider.credentials().await,
             Self::Mock(p) => p.credentials().await,
         }
     }
@@ -270,9 +285,25 @@ where
     retries::retry_request(logger, f, |rusoto_error| retryable(rusoto_error))
 }
 
+/// Like retry_request, but retries according to the provided parameters rather
+/// than the defaults.
+pub(crate) fn retry_request_with_parameters<F, T, E>(
+    logger: &Logger,
+    parameters: &RetryParameters,
+    f: F,
+) -> RusotoResult<T, E>
+where
+    F: FnMut() -> RusotoResult<T, E>,
+    E: Debug,
+{
+    retries::retry_request_with_parameters(logger, parameters, f, |rusoto_error| {
+        retryable(rusoto_error)
+    })
+}
+
 /// Returns true if the error is transient and should be retried, false
 /// otherwise.
-fn retryable<T>(error: &RusotoError<T>) -> bool {
+pub(crate) fn retryable<T>(error: &RusotoError<T>) -> bool {
     match error {
         // RusotoError::HttpDispatch indicates a problem sending the request
         // such as a timeout or the connection getting closed under us. Rusoto
@@ -488,7 +519,7 @@ fn get_caller_identity_token_at_time(
     // Construct the GetCallerIdentity token expected by sts.googleapis.com.
     // Except for the Authorization header, these must match the headers signed
     // over earlier.
-    Ok(ureq::json!({
+    Ok(serde_json::json!({
         "url": sts_request_url.as_str(),
         "method": "POST",
         "headers": [
@@ -571,6 +602,7 @@ mod tests {
     use bytes::Bytes;
     use chrono::TimeZone;
     use http::{status::StatusCode, HeaderMap};
+    use mockito::{mock, Matcher};
     use rusoto_core::request::{BufferedHttpResponse, HttpDispatchError};
 
     #[test]
@@ -748,4 +780,65 @@ mod tests {
 
         assert_eq!(token, expected_token);
     }
+
+    #[test]
+    fn assume_role() {
+        let mocked_assume_role = mock("POST", "/")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::Regex("Action=AssumeRole".to_owned()),
+                Matcher::Regex(
+                    "RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fpeer".to_owned(),
+                ),
+            ]))
+            .with_status(200)
+            .with_body(
+                r#"<AssumeRoleResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleResult>
+    <Credentials>
+      <AccessKeyId>assumed-access-key</AccessKeyId>
+      <SecretAccessKey>assumed-secret-key</SecretAccessKey>
+      <SessionToken>assumed-session-token</SessionToken>
+      <Expiration>2100-01-01T00:00:00Z</Expiration>
+    </Credentials>
+    <AssumedRoleUser>
+      <Arn>arn:aws:sts::123456789012:assumed-role/peer/prio-facilitator</Arn>
+      <AssumedRoleId>ARO123EXAMPLE123:prio-facilitator</AssumedRoleId>
+    </AssumedRoleUser>
+  </AssumeRoleResult>
+  <ResponseMetadata>
+    <RequestId>c6104cbe-af31-11e0-8154-cbc7ccf896c7</RequestId>
+  </ResponseMetadata>
+</AssumeRoleResponse>"#,
+            )
+            .expect(1)
+            .create();
+
+        let provider = Provider::new_assume_role(
+            "arn:aws:iam::123456789012:role/peer",
+            Provider::new_mock(),
+            Region::Custom {
+                name: "us-west-2".to_owned(),
+                endpoint: mockito::server_url(),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            provider.to_string(),
+            "arn:aws:iam::123456789012:role/peer via STS AssumeRole"
+        );
+
+        let runtime = basic_runtime().unwrap();
+        // Credentials are cached, so the second call should not hit STS
+        for _ in 0..2 {
+            let credentials = runtime.block_on(provider.credentials()).unwrap();
+            assert_eq!(credentials.aws_access_key_id(), "assumed-access-key");
+            assert_eq!(credentials.aws_secret_access_key(), "assumed-secret-key");
+            assert_eq!(
+                credentials.token().as_deref(),
+                Some("assumed-session-token")
+            );
+        }
+
+        mocked_assume_role.assert();
+    }
 }
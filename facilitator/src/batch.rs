@@ -2,38 +2,80 @@ use crate::{
     hex_dump,
     idl::{BatchSignature, Header, Packet},
     metrics::BatchReaderMetricsCollector,
-    transport::{Transport, TransportWriter},
-    DigestWriter, SidecarWriter, DATE_FORMAT,
+    signing::BatchSigner,
+    transport::{is_not_found_error, Transport, TransportWriter},
+    CryptoError, DigestAlgorithm, DigestReader, DigestWriter, Error, SidecarWriter,
+    ValidationError,
 };
 use anyhow::{anyhow, Context, Result};
-use avro_rs::{Reader, Schema, Writer};
+use avro_rs::{Codec, Reader, Schema, Writer};
 use chrono::NaiveDateTime;
 use ring::{
-    digest::Digest,
-    rand::SystemRandom,
-    signature::{EcdsaKeyPair, Signature, UnparsedPublicKey},
+    digest::{self, Digest},
+    signature::UnparsedPublicKey,
 };
-use slog::{o, warn, Logger};
+use serde::Serialize;
+use slog::{debug, info, o, warn, Logger};
 use std::{
     collections::HashMap,
-    io::{Cursor, Read},
+    fs::File,
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write},
     marker::PhantomData,
+    sync::Arc,
 };
 use uuid::Uuid;
 
-pub const AGGREGATION_DATE_FORMAT: &str = "%Y%m%d%H%M";
+mod scheme;
+
+pub use scheme::{BatchFile, BatchPathScheme, EnpaPathScheme, AGGREGATION_DATE_FORMAT};
+
+/// Size in bytes of the blocks of encoded packets that BatchWriter buffers
+/// before passing them on to its transport writers. Together with the part
+/// size of the transport writers, this bounds the memory used to write a
+/// packet file, regardless of how many packets it contains.
+pub const PACKET_FILE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// What an ingestion or validation batch's path is made of, from which the
+/// keys of its variants are derived
+#[derive(Clone, Debug)]
+struct BatchLocation {
+    aggregation_name: String,
+    batch_id: Uuid,
+    date: NaiveDateTime,
+}
 
 /// Manages the paths to the different files in a batch
 pub struct Batch {
     header_path: String,
     signature_path: String,
     packet_file_path: String,
+    scheme: Arc<dyn BatchPathScheme>,
+    filename: String,
+    /// None for sum part batches, whose keys have no variants
+    location: Option<BatchLocation>,
 }
 
 impl Batch {
     /// Creates a Batch representing an ingestion batch
     pub fn new_ingestion(aggregation_name: &str, batch_id: &Uuid, date: &NaiveDateTime) -> Batch {
-        Batch::new(aggregation_name, batch_id, date, "batch")
+        Batch::new_ingestion_with_scheme(Arc::new(EnpaPathScheme), aggregation_name, batch_id, date)
+    }
+
+    /// Creates a Batch representing an ingestion batch laid out according to
+    /// the provided scheme
+    pub fn new_ingestion_with_scheme(
+        scheme: Arc<dyn BatchPathScheme>,
+        aggregation_name: &str,
+        batch_id: &Uuid,
+        date: &NaiveDateTime,
+    ) -> Batch {
+        Batch::new(
+            scheme,
+            aggregation_name,
+            batch_id,
+            date,
+            scheme::ingestion_filename(),
+        )
     }
 
     /// Creates a Batch representing a validation batch
@@ -42,12 +84,31 @@ impl Batch {
         batch_id: &Uuid,
         date: &NaiveDateTime,
         is_first: bool,
+    ) -> Batch {
+        Batch::new_validation_with_scheme(
+            Arc::new(EnpaPathScheme),
+            aggregation_name,
+            batch_id,
+            date,
+            is_first,
+        )
+    }
+
+    /// Creates a Batch representing a validation batch laid out according to
+    /// the provided scheme
+    pub fn new_validation_with_scheme(
+        scheme: Arc<dyn BatchPathScheme>,
+        aggregation_name: &str,
+        batch_id: &Uuid,
+        date: &NaiveDateTime,
+        is_first: bool,
     ) -> Batch {
         Batch::new(
+            scheme,
             aggregation_name,
             batch_id,
             date,
-            &format!("validity_{}", if is_first { 0 } else { 1 }),
+            scheme::validation_filename(is_first),
         )
     }
 
@@ -59,53 +120,199 @@ impl Batch {
         aggregation_end: &NaiveDateTime,
         is_first: bool,
     ) -> Batch {
-        let batch_path = format!(
-            "{}/{}/{}-{}",
+        Batch::new_sum_with_scheme(
+            Arc::new(EnpaPathScheme),
             instance_name,
             aggregation_name,
-            aggregation_start.format(AGGREGATION_DATE_FORMAT),
-            aggregation_end.format(AGGREGATION_DATE_FORMAT)
-        );
-        let filename = format!("sum_{}", if is_first { 0 } else { 1 });
+            aggregation_start,
+            aggregation_end,
+            is_first,
+        )
+    }
 
+    /// Creates a Batch representing a sum part batch laid out according to the
+    /// provided scheme
+    pub fn new_sum_with_scheme(
+        scheme: Arc<dyn BatchPathScheme>,
+        instance_name: &str,
+        aggregation_name: &str,
+        aggregation_start: &NaiveDateTime,
+        aggregation_end: &NaiveDateTime,
+        is_first: bool,
+    ) -> Batch {
+        let batch_path = scheme.sum_part_path(
+            instance_name,
+            aggregation_name,
+            aggregation_start,
+            aggregation_end,
+        );
+        let index = if is_first { 0 } else { 1 };
+        let filename = format!("sum_{}", index);
         Batch {
-            header_path: format!("{}.{}", batch_path, filename),
-            signature_path: format!("{}.{}.sig", batch_path, filename),
-            packet_file_path: format!(
-                "{}.invalid_uuid_{}.avro",
-                batch_path,
-                if is_first { 0 } else { 1 }
+            header_path: scheme.file_key(&batch_path, &filename, BatchFile::Header),
+            signature_path: scheme.file_key(&batch_path, &filename, BatchFile::Signature),
+            packet_file_path: scheme.file_key(
+                &batch_path,
+                &format!("invalid_uuid_{}", index),
+                BatchFile::PacketFile,
             ),
+            scheme,
+            filename,
+            location: None,
         }
     }
 
-    fn new(aggregation_name: &str, batch_id: &Uuid, date: &NaiveDateTime, filename: &str) -> Batch {
-        let batch_path = format!(
-            "{}/{}/{}",
-            aggregation_name,
-            date.format(DATE_FORMAT),
-            batch_id.to_hyphenated()
-        );
+    fn new(
+        scheme: Arc<dyn BatchPathScheme>,
+        aggregation_name: &str,
+        batch_id: &Uuid,
+        date: &NaiveDateTime,
+        filename: String,
+    ) -> Batch {
+        let batch_path = scheme.batch_path(aggregation_name, batch_id, date);
+        let mut batch = Batch::at_path(scheme, &batch_path, filename);
+        batch.location = Some(BatchLocation {
+            aggregation_name: aggregation_name.to_owned(),
+            batch_id: *batch_id,
+            date: *date,
+        });
+        batch
+    }
+
+    fn at_path(scheme: Arc<dyn BatchPathScheme>, batch_path: &str, filename: String) -> Batch {
         Batch {
-            header_path: format!("{}.{}", batch_path, filename),
-            signature_path: format!("{}.{}.sig", batch_path, filename),
-            packet_file_path: format!("{}.{}.avro", batch_path, filename),
+            header_path: scheme.file_key(batch_path, &filename, BatchFile::Header),
+            signature_path: scheme.file_key(batch_path, &filename, BatchFile::Signature),
+            packet_file_path: scheme.file_key(batch_path, &filename, BatchFile::PacketFile),
+            scheme,
+            filename,
+            location: None,
         }
     }
 
-    fn header_key(&self) -> &str {
+    /// Returns this batch as it would be laid out in each of the variants its
+    /// path scheme knows of, in the order they should be tried, along with
+    /// the variant's name. Sum part batches have no variants.
+    pub(crate) fn key_variants(&self) -> Vec<(&'static str, Batch)> {
+        let location = match &self.location {
+            Some(location) => location,
+            None => return Vec::new(),
+        };
+        self.scheme
+            .batch_path_variants(
+                &location.aggregation_name,
+                &location.batch_id,
+                &location.date,
+            )
+            .into_iter()
+            .map(|(variant, batch_path)| {
+                (
+                    variant,
+                    Batch::at_path(self.scheme.clone(), &batch_path, self.filename.clone()),
+                )
+            })
+            .collect()
+    }
+
+    /// Parses the key of an ingestion batch's signature, as written by an
+    /// ingestor, into the batch's aggregation name, date and UUID. Returns None
+    /// if the key does not name the signature of an ingestion batch. See
+    /// BatchPathScheme::parse_ingestion_signature_key for other layouts.
+    pub fn parse_ingestion_signature_key(key: &str) -> Option<(String, NaiveDateTime, Uuid)> {
+        EnpaPathScheme.parse_ingestion_signature_key(key)
+    }
+
+    /// Parses the key of a validation batch's signature, as written by the
+    /// first or second data share processor depending on is_first, into the
+    /// batch's aggregation name, date and UUID. Returns None if the key does
+    /// not name the signature of such a validation batch. See
+    /// BatchPathScheme::parse_validation_signature_key for other layouts.
+    pub fn parse_validation_signature_key(
+        key: &str,
+        is_first: bool,
+    ) -> Option<(String, NaiveDateTime, Uuid)> {
+        EnpaPathScheme.parse_validation_signature_key(key, is_first)
+    }
+
+    /// Parses the key of any of the files of an ingestion or validation batch
+    /// into the batch's aggregation name, date and UUID. Returns None if the
+    /// key does not name a file of such a batch. See
+    /// BatchPathScheme::parse_key for other layouts.
+    pub fn parse_key(key: &str) -> Option<(String, NaiveDateTime, Uuid)> {
+        EnpaPathScheme.parse_key(key)
+    }
+
+    pub(crate) fn header_key(&self) -> &str {
         self.header_path.as_ref()
     }
 
-    fn signature_key(&self) -> &str {
+    pub(crate) fn signature_key(&self) -> &str {
         self.signature_path.as_ref()
     }
 
-    fn packet_file_key(&self) -> &str {
+    pub(crate) fn packet_file_key(&self) -> &str {
         self.packet_file_path.as_ref()
     }
 }
 
+/// The outcome of checking the signature over a batch's header.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SignatureStatus {
+    /// The header was signed by the identified key.
+    Valid { key_identifier: String },
+    /// The identified key is unknown or does not match the signature, but the
+    /// header was signed by another of the keys the batch was checked against,
+    /// e.g. the peer's previous key while it rotates its batch signing keys.
+    ValidWithOtherKey {
+        key_identifier: String,
+        verifying_key_identifier: String,
+    },
+    /// The signature does not match the header and the identified key.
+    Invalid { key_identifier: String },
+    /// The identified key is not among the keys the batch was checked against.
+    UnknownKey { key_identifier: String },
+}
+
+impl SignatureStatus {
+    /// Returns true if the header was signed by any of the keys the batch was
+    /// checked against.
+    pub fn is_valid(&self) -> bool {
+        matches!(
+            self,
+            SignatureStatus::Valid { .. } | SignatureStatus::ValidWithOtherKey { .. }
+        )
+    }
+
+    /// Returns the identifier of the key that the header was signed by, if it
+    /// was signed by any of the keys the batch was checked against.
+    pub fn verifying_key_identifier(&self) -> Option<&str> {
+        match self {
+            SignatureStatus::Valid { key_identifier } => Some(key_identifier),
+            SignatureStatus::ValidWithOtherKey {
+                verifying_key_identifier,
+                ..
+            } => Some(verifying_key_identifier),
+            SignatureStatus::Invalid { .. } | SignatureStatus::UnknownKey { .. } => None,
+        }
+    }
+}
+
+/// The outcome of checking a batch's packet file against the digest in its
+/// header.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DigestStatus {
+    /// The packet file's digest matches the header.
+    Valid,
+    /// The packet file's digest does not match the header. Digests are
+    /// hexadecimal.
+    Mismatch {
+        header_digest: String,
+        actual_digest: String,
+    },
+}
+
 /// Allows reading files, including signature validation, from an ingestion or
 /// validation batch containing a header, a packet file and a signature.
 pub struct BatchReader<'a, H, P> {
@@ -114,6 +321,7 @@ pub struct BatchReader<'a, H, P> {
     transport: &'a mut dyn Transport,
     packet_schema: Schema,
     permit_malformed_batch: bool,
+    digest_algorithm: DigestAlgorithm,
     metrics_collector: Option<&'a BatchReaderMetricsCollector>,
     logger: Logger,
 
@@ -144,6 +352,7 @@ impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
             transport,
             packet_schema: P::schema(),
             permit_malformed_batch,
+            digest_algorithm: DigestAlgorithm::default(),
             metrics_collector: None,
             logger,
             phantom_header: PhantomData,
@@ -155,6 +364,13 @@ impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
         self.metrics_collector = Some(collector);
     }
 
+    /// Sets the algorithm used to compute the digest of the packet file that
+    /// is checked against the header's packet_file_digest field. Defaults to
+    /// SHA-256.
+    pub fn set_digest_algorithm(&mut self, algorithm: DigestAlgorithm) {
+        self.digest_algorithm = algorithm;
+    }
+
     pub fn path(&self) -> String {
         self.transport.path()
     }
@@ -163,15 +379,112 @@ impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
     /// valid. The signature is checked by getting the key_identifier value from
     /// the signature message, using that to obtain a public key from the
     /// provided public_keys map, and using that key to check the ECDSA P256
-    /// signature.
+    /// signature. If that key is absent or does not match, every other key in
+    /// the map is tried, so that batches signed with a peer's current or
+    /// previous key are accepted while manifests and deployments catch up with
+    /// a key rotation.
     pub fn header(
         &mut self,
         public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
     ) -> Result<H> {
-        let signature = BatchSignature::read(
-            self.transport
-                .get(self.batch.signature_key(), self.trace_id)?,
-        )?;
+        Ok(self.header_with_digest(public_keys)?.0)
+    }
+
+    /// Like BatchReader::header, but also returns the SHA-256 digest of the
+    /// header as it was fetched from the transport, e.g. to identify exactly
+    /// which header was processed, and the outcome of checking its signature,
+    /// e.g. to identify which key it was signed with.
+    pub fn header_with_digest(
+        &mut self,
+        public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+    ) -> Result<(H, Digest, SignatureStatus)> {
+        let (header_buf, signature_status) = self.verify_header(public_keys)?;
+        match &signature_status {
+            SignatureStatus::Valid { .. } | SignatureStatus::ValidWithOtherKey { .. } => {}
+            SignatureStatus::UnknownKey { key_identifier } => {
+                return Err(
+                    anyhow!("known key identifiers are {:?}", public_keys.keys()).context(
+                        CryptoError::UnknownSigningKey {
+                            key_identifier: key_identifier.clone(),
+                        },
+                    ),
+                );
+            }
+            SignatureStatus::Invalid { key_identifier } => {
+                let error = CryptoError::InvalidSignature {
+                    key_identifier: key_identifier.clone(),
+                };
+                if let Some(collector) = self.metrics_collector {
+                    collector
+                        .invalid_validation_batches
+                        .with_label_values(&["header"])
+                        .inc();
+                }
+                if self.permit_malformed_batch {
+                    warn!(self.logger, "{}", error);
+                } else {
+                    return Err(error.into());
+                }
+            }
+        }
+        let header_digest = digest::digest(&digest::SHA256, &header_buf);
+        Ok((
+            H::read(Cursor::new(header_buf))?,
+            header_digest,
+            signature_status,
+        ))
+    }
+
+    /// Return the parsed header from this batch along with the outcome of
+    /// checking its signature against the provided public_keys map. Unlike
+    /// BatchReader::header, the header is parsed whether or not its signature
+    /// is valid, so this is only suitable for inspecting batches, and never
+    /// for processing them.
+    pub fn header_with_signature_status(
+        &mut self,
+        public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+    ) -> Result<(H, SignatureStatus)> {
+        let (header_buf, signature_status) = self.verify_header(public_keys)?;
+        Ok((H::read(Cursor::new(header_buf))?, signature_status))
+    }
+
+    /// Fetches the batch's signature. If there is no signature under the
+    /// canonical key, the keys of each of the known variants of the batch's
+    /// layout are tried in turn, and if one of them is found, the batch's
+    /// other files are read from keys in the same variant.
+    fn get_signature(&mut self) -> Result<Box<dyn Read>> {
+        let not_found = match self
+            .transport
+            .get(self.batch.signature_key(), self.trace_id)
+        {
+            Err(error) if is_not_found_error(&error) => error,
+            result => return result,
+        };
+        for (variant, batch) in self.batch.key_variants() {
+            match self.transport.get(batch.signature_key(), self.trace_id) {
+                Ok(signature) => {
+                    info!(
+                        self.logger, "found batch under variant of canonical keys";
+                        "key_variant" => variant,
+                        "variant_signature_key" => batch.signature_key(),
+                    );
+                    self.batch = batch;
+                    return Ok(signature);
+                }
+                Err(error) if is_not_found_error(&error) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        Err(not_found)
+    }
+
+    /// Fetches the header and signature and checks the signature over the
+    /// header, returning the unparsed header.
+    fn verify_header(
+        &mut self,
+        public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+    ) -> Result<(Vec<u8>, SignatureStatus)> {
+        let signature = BatchSignature::read(self.get_signature()?)?;
 
         let mut header_buf = Vec::new();
         self.transport
@@ -179,38 +492,95 @@ impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
             .read_to_end(&mut header_buf)
             .context("failed to read header from transport")?;
 
-        let sig_valid = public_keys
-            .get(&signature.key_identifier)
-            .context(format!(
-                "key identifier {} not present in key map {:?}",
-                signature.key_identifier,
-                public_keys.keys(),
-            ))?
-            .verify(&header_buf, &signature.batch_header_signature);
-        if let Err(e) = sig_valid {
-            let message = format!(
-                "invalid signature on header with key {}: {:?}",
-                signature.key_identifier, e
+        let BatchSignature {
+            key_identifier,
+            batch_header_signature,
+        } = signature;
+        let verifies = |key: &UnparsedPublicKey<Vec<u8>>| {
+            key.verify(&header_buf, &batch_header_signature).is_ok()
+        };
+
+        let identified_key = public_keys.get(&key_identifier);
+        if identified_key.map_or(false, verifies) {
+            debug!(
+                self.logger, "verified header signature";
+                "verifying_key_identifier" => &key_identifier,
             );
+            return Ok((header_buf, SignatureStatus::Valid { key_identifier }));
+        }
+
+        // Try each of the other candidate keys, since the peer may be signing
+        // with a key that our copy of its manifest lists under a different
+        // identifier while it rotates its keys.
+        let verifying_key_identifier = public_keys
+            .iter()
+            .find(|(identifier, key)| **identifier != key_identifier && verifies(key))
+            .map(|(identifier, _)| identifier.clone());
+        let status = match (verifying_key_identifier, identified_key) {
+            (Some(verifying_key_identifier), _) => {
+                info!(
+                    self.logger, "verified header signature with key other than the identified key";
+                    "key_identifier" => &key_identifier,
+                    "verifying_key_identifier" => &verifying_key_identifier,
+                );
+                SignatureStatus::ValidWithOtherKey {
+                    key_identifier,
+                    verifying_key_identifier,
+                }
+            }
+            (None, Some(_)) => SignatureStatus::Invalid { key_identifier },
+            (None, None) => SignatureStatus::UnknownKey { key_identifier },
+        };
+        Ok((header_buf, status))
+    }
+
+    /// Return a PacketReader that yields the packets in the packet file, but
+    /// only if the whole file's digest matches the packet_file_digest field in
+    /// the provided header. The header is assumed to be trusted.
+    pub fn packet_file_reader(&mut self, header: &H) -> Result<PacketReader<'_, P>> {
+        let (packet_file, digest_status) = self.fetch_packet_file(header)?;
+        if let DigestStatus::Mismatch {
+            header_digest,
+            actual_digest,
+        } = digest_status
+        {
+            let error = ValidationError::PacketFileDigestMismatch {
+                header_digest,
+                actual_digest,
+            };
             if let Some(collector) = self.metrics_collector {
                 collector
                     .invalid_validation_batches
-                    .with_label_values(&["header"])
+                    .with_label_values(&["packet_file"])
                     .inc();
             }
             if self.permit_malformed_batch {
-                warn!(self.logger, "{}", message);
+                warn!(self.logger, "{}", error);
             } else {
-                return Err(anyhow!("{}", message));
+                return Err(error.into());
             }
         }
-        Ok(H::read(Cursor::new(header_buf))?)
+        self.packet_reader(packet_file)
     }
 
-    /// Return an avro_rs::Reader that yields the packets in the packet file,
-    /// but only if the whole file's digest matches the packet_file_digest field
-    /// in the provided header. The header is assumed to be trusted.
-    pub fn packet_file_reader(&mut self, header: &H) -> Result<Reader<Cursor<Vec<u8>>>> {
+    /// Return a PacketReader that yields the packets in the packet file along
+    /// with the outcome of checking the file's digest against the
+    /// packet_file_digest field in the provided header. Unlike
+    /// BatchReader::packet_file_reader, the reader is returned whether or not
+    /// the digests match, so this is only suitable for inspecting batches, and
+    /// never for processing them.
+    pub fn packet_file_reader_with_digest_status(
+        &mut self,
+        header: &H,
+    ) -> Result<(PacketReader<'_, P>, DigestStatus)> {
+        let (packet_file, digest_status) = self.fetch_packet_file(header)?;
+        Ok((self.packet_reader(packet_file)?, digest_status))
+    }
+
+    /// Fetches the packet file into a temporary file and computes its digest,
+    /// returning the temporary file, rewound to its start, and the outcome of
+    /// comparing the digest to the header's.
+    fn fetch_packet_file(&mut self, header: &H) -> Result<(File, DigestStatus)> {
         // Fetch packet file to validate its digest. It could be quite large so
         // so our intuition would be to stream the packets from the transport
         // and into a hasher and into the validation step, so that we wouldn't
@@ -219,53 +589,102 @@ impl<'a, H: Header, P: Packet> BatchReader<'a, H, P> {
         //       file until we've verified integrity+authenticity
         //   (2) we need to copy the entire file into storage we control before
         //       validating its digest to avoid TOCTOU vulnerabilities.
-        // We are assured by our friends writing ingestion servers that batches
-        // will be no more than 300-400 MB, which fits quite reasonably into the
-        // memory of anything we're going to run the facilitator on, so we load
-        // the entire packet file into memory ...
-        let mut packet_file_reader = self
-            .transport
-            .get(self.batch.packet_file_key(), self.trace_id)?;
-        // SidecarWriter takes a Vec of std::io::write so we wrap the Vec we
-        // want to read the file into in a Vec.
-        let entire_packet_file = vec![Vec::new()];
-        let digest_writer = DigestWriter::new();
-        let mut sidecar_writer = SidecarWriter::new(entire_packet_file, digest_writer);
-
-        std::io::copy(&mut packet_file_reader, &mut sidecar_writer)
-            .context("failed to load packet file")?;
-
-        // ... then verify the digest over it ...
-        let packet_file_digest = sidecar_writer.sidecar.finish();
-        if header.packet_file_digest().as_slice() != packet_file_digest.as_ref() {
-            let message = format!(
-                "packet file digest in header {} does not match actual packet file digest {}",
-                hex_dump(header.packet_file_digest()),
-                hex_dump(packet_file_digest.as_ref())
-            );
-            if let Some(collector) = self.metrics_collector {
-                collector
-                    .invalid_validation_batches
-                    .with_label_values(&["packet_file"])
-                    .inc();
-            }
-            if self.permit_malformed_batch {
-                warn!(self.logger, "{}", message);
-            } else {
-                return Err(anyhow!("{}", message));
+        // Batches may contain tens of millions of packets, so rather than
+        // loading the entire packet file into memory, we spool it into an
+        // anonymous temporary file, computing its digest as it streams in from
+        // the transport ...
+        let mut digest_reader = DigestReader::with_algorithm(
+            self.transport
+                .get(self.batch.packet_file_key(), self.trace_id)?,
+            self.digest_algorithm,
+        );
+        let mut packet_file = tempfile::tempfile().context("failed to create temporary file")?;
+        io::copy(&mut digest_reader, &mut packet_file).context("failed to load packet file")?;
+        packet_file
+            .seek(SeekFrom::Start(0))
+            .context("failed to rewind packet file")?;
+
+        // ... then compare the digest over it to the header's, after which
+        // packet_reader may decode packets from the temporary file as they are
+        // needed.
+        let packet_file_digest = digest_reader.finish();
+        let digest_status = if header.packet_file_digest().as_slice() == packet_file_digest.as_ref()
+        {
+            DigestStatus::Valid
+        } else {
+            DigestStatus::Mismatch {
+                header_digest: hex_dump(header.packet_file_digest()),
+                actual_digest: hex_dump(packet_file_digest.as_ref()),
             }
-        }
+        };
+        Ok((packet_file, digest_status))
+    }
 
-        // pop() should always succeed here because sidecar_writers.writers is
-        // entire_packet_file, above.
-        let packet_file = sidecar_writer
-            .writers
-            .pop()
-            .context("sidecar_writer.writers is empty?")?;
+    fn packet_reader(&self, packet_file: File) -> Result<PacketReader<'_, P>> {
+        // avro_rs::Writer writes nothing at all, not even the Avro header, if
+        // no packets are appended, so an empty packet file has no packets.
+        // Such files are written for the invalid packets of a sum part when
+        // there are none, and for a validation batch when every packet of the
+        // ingestion batch was dropped as a duplicate, and must be readable for
+        // the validation batch to be aggregated.
+        let packet_file_size = packet_file
+            .metadata()
+            .context("failed to stat packet file")?
+            .len();
+        let reader = if packet_file_size == 0 {
+            None
+        } else {
+            Some(
+                Reader::with_schema(&self.packet_schema, BufReader::new(packet_file))
+                    .context("failed to create Avro reader for packets")?,
+            )
+        };
+        Ok(PacketReader {
+            reader,
+            packet_file_size,
+            done: false,
+            phantom_packet: PhantomData,
+        })
+    }
+}
 
-        // ... then return a packet reader.
-        Reader::with_schema(&self.packet_schema, Cursor::new(packet_file))
-            .context("failed to create Avro reader for packets")
+/// An iterator over the packets in a batch's packet file, obtained from
+/// BatchReader::packet_file_reader. Packets are decoded one at a time as the
+/// iterator is advanced, so only one packet need be held in memory at once.
+/// Iteration ends after the last packet or the first error.
+pub struct PacketReader<'a, P> {
+    reader: Option<Reader<'a, BufReader<File>>>,
+    packet_file_size: u64,
+    done: bool,
+    phantom_packet: PhantomData<*const P>,
+}
+
+impl<'a, P> PacketReader<'a, P> {
+    /// Returns the size of the packet file in bytes.
+    pub fn packet_file_size(&self) -> u64 {
+        self.packet_file_size
+    }
+}
+
+impl<'a, P: Packet> Iterator for PacketReader<'a, P> {
+    type Item = Result<P, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let reader = match self.reader.as_mut() {
+            Some(reader) if !self.done => reader,
+            _ => return None,
+        };
+        match P::read(reader) {
+            Ok(packet) => Some(Ok(packet)),
+            Err(Error::EofError) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
     }
 }
 
@@ -277,6 +696,9 @@ pub struct BatchWriter<'a, H, P> {
     transport: &'a mut dyn Transport,
     packet_schema: Schema,
     trace_id: &'a str,
+    create_only: bool,
+    digest_algorithm: DigestAlgorithm,
+    packet_file_codec: Codec,
     phantom_header: PhantomData<*const H>,
     phantom_packet: PhantomData<*const P>,
 }
@@ -288,6 +710,9 @@ impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
             transport,
             packet_schema: P::schema(),
             trace_id,
+            create_only: false,
+            digest_algorithm: DigestAlgorithm::default(),
+            packet_file_codec: Codec::Null,
             phantom_header: PhantomData,
             phantom_packet: PhantomData,
         }
@@ -297,28 +722,118 @@ impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
         self.transport.path()
     }
 
-    /// Encode the provided header into Avro, sign that representation with the
-    /// provided key and write the header into the batch. Returns the signature
-    /// on success.
-    pub fn put_header(&mut self, header: &H, key: &EcdsaKeyPair) -> Result<Signature> {
-        let mut sidecar_writer = SidecarWriter::new(
-            vec![self.transport.put(self.batch.header_key(), self.trace_id)?],
-            Vec::new(),
+    /// Sets whether this BatchWriter refuses to overwrite existing files. If
+    /// set, writing any file of the batch that already exists fails with
+    /// TransportError::AlreadyExists.
+    pub fn set_create_only(&mut self, create_only: bool) {
+        self.create_only = create_only;
+    }
+
+    /// Sets the algorithm used to compute the digest of the packet file that
+    /// is returned by packet_file_writer and multi_packet_file_writer, which
+    /// should be that expected by the batch's recipient. Defaults to SHA-256.
+    pub fn set_digest_algorithm(&mut self, algorithm: DigestAlgorithm) {
+        self.digest_algorithm = algorithm;
+    }
+
+    /// Sets the codec with which the blocks of the packet files written by
+    /// packet_file_writer and multi_packet_file_writer are compressed. The
+    /// codec is recorded in the Avro header of the packet file, so readers,
+    /// including BatchReader, decompress the blocks without being told which
+    /// codec was used, but peers must run an Avro implementation that
+    /// supports the codec. Defaults to Codec::Null, i.e. no compression.
+    pub fn set_packet_file_codec(&mut self, codec: Codec) {
+        self.packet_file_codec = codec;
+    }
+
+    /// Returns true if the batch's signature, which is the last of its files
+    /// to be written, already exists in the transport.
+    pub fn is_complete(&mut self) -> Result<bool> {
+        self.transport
+            .exists(self.batch.signature_key(), self.trace_id)
+    }
+
+    /// Fetches the header of a batch that was already written, e.g. by an
+    /// earlier attempt at the same task, along with the outcome of checking
+    /// the digest of the existing packet file, computed with this
+    /// BatchWriter's digest algorithm, against the header's. Fails if the
+    /// batch was signed with a key other than the one identified by
+    /// key_identifier. The signature itself is not verified, since the writer
+    /// of a batch need not hold the public half of its signing key, so this
+    /// is only suitable for batches this data share processor wrote.
+    pub fn existing_header(&mut self, key_identifier: &str) -> Result<(H, DigestStatus)> {
+        let signature = BatchSignature::read(
+            self.transport
+                .get(self.batch.signature_key(), self.trace_id)?,
+        )?;
+        if signature.key_identifier != key_identifier {
+            return Err(ValidationError::HeaderMismatch(format!(
+                "existing batch {} signed with key {} rather than {}",
+                self.batch.header_key(),
+                signature.key_identifier,
+                key_identifier
+            ))
+            .into());
+        }
+
+        let header = H::read(self.transport.get(self.batch.header_key(), self.trace_id)?)?;
+
+        let mut digest_reader = DigestReader::with_algorithm(
+            self.transport
+                .get(self.batch.packet_file_key(), self.trace_id)?,
+            self.digest_algorithm,
         );
-        header.write(&mut sidecar_writer)?;
+        io::copy(&mut digest_reader, &mut io::sink())
+            .context("failed to read existing packet file")?;
+        let packet_file_digest = digest_reader.finish();
+        let digest_status = if header.packet_file_digest().as_slice() == packet_file_digest.as_ref()
+        {
+            DigestStatus::Valid
+        } else {
+            DigestStatus::Mismatch {
+                header_digest: hex_dump(header.packet_file_digest()),
+                actual_digest: hex_dump(packet_file_digest.as_ref()),
+            }
+        };
+        Ok((header, digest_status))
+    }
+
+    fn transport_writer(&mut self, key: fn(&Batch) -> &str) -> Result<Box<dyn TransportWriter>> {
+        let key = key(&self.batch);
+        if self.create_only {
+            self.transport.put_if_absent(key, self.trace_id)
+        } else {
+            self.transport.put(key, self.trace_id)
+        }
+    }
+
+    /// Encode the provided header into Avro, sign that representation with the
+    /// provided signer and write the header into the batch. Returns the
+    /// signature on success.
+    pub fn put_header(&mut self, header: &H, signer: &dyn BatchSigner) -> Result<Vec<u8>> {
+        let mut sidecar_writer =
+            SidecarWriter::new(vec![self.transport_writer(Batch::header_key)?], Vec::new());
+        if let Err(e) = header.write(&mut sidecar_writer) {
+            sidecar_writer.writers[0]
+                .cancel_upload()
+                .with_context(|| format!("Encountered while handling: {}", e))?;
+            return Err(e.into());
+        }
         sidecar_writer.writers[0]
             .complete_upload()
             .context("failed to complete batch header upload")?;
 
-        let header_signature = key
-            .sign(&SystemRandom::new(), &sidecar_writer.sidecar)
-            .context("failed to sign header file")?;
-        Ok(header_signature)
+        signer
+            .sign(&sidecar_writer.sidecar)
+            .context("failed to sign header file")
     }
 
     /// This is BatchWriter::packet_file_writer except that it takes a Vec of
     /// additional batch writers, and any content written to the Writer passed
-    /// to the callback will also be written to those writers.
+    /// to the callback will also be written to those writers. The digest is
+    /// computed with this BatchWriter's digest algorithm and the blocks are
+    /// compressed with its codec, regardless of those of the additional batch
+    /// writers.
     pub fn multi_packet_file_writer<F>(
         &mut self,
         mut more_batch_writers: Vec<&mut BatchWriter<H, P>>,
@@ -327,20 +842,19 @@ impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
     where
         F: FnOnce(&mut Writer<SidecarWriter<Box<dyn TransportWriter>, DigestWriter>>) -> Result<()>,
     {
-        let mut transport_writers = vec![self
-            .transport
-            .put(self.batch.packet_file_key(), self.trace_id)?];
+        let mut transport_writers = vec![self.transport_writer(Batch::packet_file_key)?];
         for batch_writer in &mut more_batch_writers {
-            transport_writers.push(
-                batch_writer
-                    .transport
-                    .put(batch_writer.batch.packet_file_key(), self.trace_id)?,
-            );
+            transport_writers.push(batch_writer.transport_writer(Batch::packet_file_key)?);
         }
-        let mut writer = Writer::new(
-            &self.packet_schema,
-            SidecarWriter::new(transport_writers, DigestWriter::new()),
-        );
+        let mut writer = Writer::builder()
+            .schema(&self.packet_schema)
+            .writer(SidecarWriter::new(
+                transport_writers,
+                DigestWriter::new(self.digest_algorithm),
+            ))
+            .block_size(PACKET_FILE_BLOCK_SIZE)
+            .codec(self.packet_file_codec)
+            .build();
 
         let result = operation(&mut writer);
         let mut sidecar_writer = writer
@@ -366,7 +880,10 @@ impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
 
     /// Creates an avro_rs::Writer and provides it to the caller-provided
     /// function, which may then write arbitrarily many packets into it. The
-    /// Avro encoding of the packet will be digested while they are written.
+    /// Avro encoding of the packets will be digested while they are written.
+    /// Packets are streamed to the transport in blocks of about
+    /// PACKET_FILE_BLOCK_SIZE bytes as they are written, so the whole packet
+    /// file is never held in memory.
     /// The operation should return Ok(()) when it has finished successfully or
     /// some Err() otherwise. packet_file_writer returns the digest of all the
     /// content written by the operation.
@@ -377,19 +894,40 @@ impl<'a, H: Header, P: Packet> BatchWriter<'a, H, P> {
         self.multi_packet_file_writer(vec![], operation)
     }
 
+    /// Writes the provided bytes to the batch's packet file as they are,
+    /// without checking that they are valid Avro, and returns their digest.
+    /// This is intended for generating malformed batches for testing.
+    pub fn put_packet_file(&mut self, packet_file: &[u8]) -> Result<Digest> {
+        let mut sidecar_writer = SidecarWriter::new(
+            vec![self.transport_writer(Batch::packet_file_key)?],
+            DigestWriter::new(self.digest_algorithm),
+        );
+        if let Err(e) = sidecar_writer.write_all(packet_file) {
+            sidecar_writer.writers[0]
+                .cancel_upload()
+                .with_context(|| format!("Encountered while handling: {}", e))?;
+            return Err(e).context("failed to write packet file");
+        }
+        sidecar_writer.writers[0]
+            .complete_upload()
+            .context("failed to complete packet file upload")?;
+        Ok(sidecar_writer.sidecar.finish())
+    }
+
     /// Constructs a signature structure from the provided buffers and writes it
     /// to the batch's signature file
-    pub fn put_signature(&mut self, signature: &Signature, key_identifier: &str) -> Result<()> {
+    pub fn put_signature(&mut self, signature: &[u8], key_identifier: &str) -> Result<()> {
         let batch_signature = BatchSignature {
-            batch_header_signature: signature.as_ref().to_vec(),
+            batch_header_signature: signature.to_vec(),
             key_identifier: key_identifier.to_string(),
         };
-        let mut writer = self
-            .transport
-            .put(self.batch.signature_key(), self.trace_id)?;
-        batch_signature
-            .write(&mut writer)
-            .context("failed to write signature")?;
+        let mut writer = self.transport_writer(Batch::signature_key)?;
+        if let Err(e) = batch_signature.write(&mut writer) {
+            writer
+                .cancel_upload()
+                .with_context(|| format!("Encountered while handling: {}", e))?;
+            return Err(e).context("failed to write signature");
+        }
         writer
             .complete_upload()
             .context("failed to complete signature upload")
@@ -406,10 +944,14 @@ mod tests {
             default_facilitator_signing_public_key, default_ingestor_private_key,
             default_ingestor_public_key,
         },
-        transport::LocalFileTransport,
-        Error,
+        transport::{LocalFileTransport, MemoryTransport},
+        DATE_FORMAT,
+    };
+    use chrono::NaiveDate;
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
     };
-    use assert_matches::assert_matches;
 
     #[allow(clippy::too_many_arguments)] // Grandfathered in
     fn roundtrip_batch<'a>(
@@ -420,7 +962,7 @@ mod tests {
         batch_writer: &mut BatchWriter<'a, IngestionHeader, IngestionDataSharePacket>,
         batch_reader: &mut BatchReader<'a, IngestionHeader, IngestionDataSharePacket>,
         transport: &mut LocalFileTransport,
-        write_key: &EcdsaKeyPair,
+        write_key: &dyn BatchSigner,
         read_key: &UnparsedPublicKey<Vec<u8>>,
         keys_match: bool,
     ) {
@@ -437,6 +979,7 @@ mod tests {
                 r_pit: 1,
                 version_configuration: Some("config-1".to_owned()),
                 device_nonce: None,
+                encryption_ciphersuite: None,
             },
             IngestionDataSharePacket {
                 uuid: Uuid::new_v4(),
@@ -445,6 +988,7 @@ mod tests {
                 r_pit: 2,
                 version_configuration: None,
                 device_nonce: Some(vec![8u8, 9u8, 10u8, 11u8]),
+                encryption_ciphersuite: None,
             },
             IngestionDataSharePacket {
                 uuid: Uuid::new_v4(),
@@ -453,6 +997,7 @@ mod tests {
                 r_pit: 3,
                 version_configuration: None,
                 device_nonce: None,
+                encryption_ciphersuite: None,
             },
         ];
 
@@ -511,21 +1056,265 @@ mod tests {
 
         assert_eq!(header, header_again, "header does not match");
 
-        let mut packet_file_reader = batch_reader
+        let packets_again = batch_reader
             .packet_file_reader(&header_again)
-            .expect("failed to get packet file reader");
+            .expect("failed to get packet file reader")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("failed to read packets");
+        assert_eq!(&packets[..], &packets_again[..], "packets do not match");
+    }
 
-        for packet in packets {
-            let packet_again = IngestionDataSharePacket::read(&mut packet_file_reader)
-                .expect("failed to read packet");
-            assert_eq!(packet, &packet_again, "packet does not match");
+    #[test]
+    fn parse_ingestion_signature_keys() {
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(1234567860, 0);
+        let batch = Batch::new_ingestion("fake-aggregation", &batch_id, &date);
+        assert_eq!(
+            Batch::parse_ingestion_signature_key(batch.signature_key()),
+            Some(("fake-aggregation".to_owned(), date, batch_id))
+        );
+
+        for key in &[
+            batch.header_key(),
+            batch.packet_file_key(),
+            Batch::new_validation("fake-aggregation", &batch_id, &date, true).signature_key(),
+            "fake-aggregation/2009/02/13/23/31/not-a-uuid.batch.sig",
+            "fake-aggregation/2009/02/13/23/not-a-date.batch.sig",
+            &format!("2009/02/13/23/31/{}.batch.sig", batch_id),
+            &format!("/2009/02/13/23/31/{}.batch.sig", batch_id),
+        ] {
+            assert_eq!(Batch::parse_ingestion_signature_key(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn parse_batch_keys() {
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(1234567860, 0);
+        for batch in &[
+            Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+            Batch::new_validation("fake-aggregation", &batch_id, &date, false),
+        ] {
+            for key in &[
+                batch.header_key(),
+                batch.signature_key(),
+                batch.packet_file_key(),
+            ] {
+                assert_eq!(
+                    Batch::parse_key(key),
+                    Some(("fake-aggregation".to_owned(), date, batch_id)),
+                    "{}",
+                    key
+                );
+            }
         }
 
-        // One more read should get EOF
-        assert_matches!(
-            IngestionDataSharePacket::read(&mut packet_file_reader),
-            Err(Error::EofError)
+        for key in &[
+            Batch::new_sum("instance", "fake-aggregation", &date, &date, true).header_key(),
+            "task-markers/intake-fake-aggregation-2009-02-13-23-31",
+            "fake-aggregation/2009/02/13/23/31/not-a-uuid.batch",
+            &format!("fake-aggregation/2009/02/13/23/31/{}", batch_id),
+        ] {
+            assert_eq!(Batch::parse_key(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn batch_key_variants() {
+        let batch_id = Uuid::parse_str("6e8bd1d0-2d7a-4a39-b0a7-3bd6d1ba1c2f").unwrap();
+        let date = NaiveDate::from_ymd(2021, 5, 1).and_hms(1, 2, 0);
+        let batch = Batch::new_ingestion("fake-aggregation", &batch_id, &date);
+        let variants: Vec<(&str, String)> = batch
+            .key_variants()
+            .into_iter()
+            .map(|(variant, batch)| (variant, batch.signature_key().to_owned()))
+            .collect();
+        assert_eq!(
+            variants,
+            vec![
+                (
+                    "uppercase-uuid",
+                    "fake-aggregation/2021/05/01/01/02/6E8BD1D0-2D7A-4A39-B0A7-3BD6D1BA1C2F.batch.sig"
+                        .to_owned()
+                ),
+                (
+                    "unpadded-date",
+                    "fake-aggregation/2021/5/1/1/2/6e8bd1d0-2d7a-4a39-b0a7-3bd6d1ba1c2f.batch.sig"
+                        .to_owned()
+                ),
+                (
+                    "uppercase-uuid-unpadded-date",
+                    "fake-aggregation/2021/5/1/1/2/6E8BD1D0-2D7A-4A39-B0A7-3BD6D1BA1C2F.batch.sig"
+                        .to_owned()
+                ),
+            ]
         );
+        // Variant keys parse to the same batch as the canonical ones
+        for (_, key) in &variants {
+            assert_eq!(
+                Batch::parse_ingestion_signature_key(key),
+                Some(("fake-aggregation".to_owned(), date, batch_id))
+            );
+        }
+
+        // Dates without any components to pad have no unpadded variants
+        let date = NaiveDate::from_ymd(2021, 12, 31).and_hms(23, 59, 0);
+        let batch = Batch::new_validation("fake-aggregation", &batch_id, &date, true);
+        let variants: Vec<&str> = batch
+            .key_variants()
+            .into_iter()
+            .map(|(variant, _)| variant)
+            .collect();
+        assert_eq!(variants, vec!["uppercase-uuid"]);
+
+        let sum = Batch::new_sum("instance", "fake-aggregation", &date, &date, true);
+        assert!(sum.key_variants().is_empty());
+    }
+
+    #[test]
+    fn read_batch_under_key_variant() {
+        let logger = setup_test_logging();
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDate::from_ymd(2021, 5, 1).and_hms(1, 2, 0);
+        let mut key_map = HashMap::new();
+        key_map.insert("key-identifier".to_owned(), default_ingestor_public_key());
+
+        for (variant, variant_batch) in
+            Batch::new_ingestion("fake-aggregation", &batch_id, &date).key_variants()
+        {
+            let mut transport = MemoryTransport::new();
+            let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchWriter::new(variant_batch, &mut transport, "trace-id");
+            let packet_file_digest = batch_writer.put_packet_file(&[]).unwrap();
+            let header = IngestionHeader {
+                batch_uuid: batch_id,
+                name: "fake-aggregation".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: 17,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time: 789456123,
+                batch_end_time: 789456321,
+                packet_file_digest: packet_file_digest.as_ref().to_vec(),
+            };
+            let signature = batch_writer
+                .put_header(&header, &default_ingestor_private_key())
+                .unwrap();
+            batch_writer
+                .put_signature(&signature, "key-identifier")
+                .unwrap();
+
+            let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(
+                    Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+                    &mut transport,
+                    false,
+                    "trace-id",
+                    &logger,
+                );
+            let header_again = batch_reader.header(&key_map).unwrap();
+            assert_eq!(header_again, header, "{:?}", variant);
+            assert_eq!(
+                batch_reader
+                    .packet_file_reader(&header_again)
+                    .unwrap()
+                    .count(),
+                0
+            );
+        }
+
+        // Batches that are under none of the variants are still not found
+        let mut transport = MemoryTransport::new();
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+                &mut transport,
+                false,
+                "trace-id",
+                &logger,
+            );
+        assert!(is_not_found_error(
+            &batch_reader.header(&key_map).unwrap_err()
+        ));
+    }
+
+    #[test]
+    fn read_empty_packet_file() {
+        let logger = setup_test_logging();
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDate::from_ymd(2021, 5, 1).and_hms(1, 2, 0);
+        let mut key_map = HashMap::new();
+        key_map.insert("key-identifier".to_owned(), default_ingestor_public_key());
+
+        // An empty packet file has no packets, but a packet file that is not
+        // an Avro object container file is still rejected.
+        for (packet_file, is_valid) in &[(&b""[..], true), (&b"not avro"[..], false)] {
+            let mut transport = MemoryTransport::new();
+            let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchWriter::new(
+                    Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+                    &mut transport,
+                    "trace-id",
+                );
+            let packet_file_digest = batch_writer.put_packet_file(packet_file).unwrap();
+            let header = IngestionHeader {
+                batch_uuid: batch_id,
+                name: "fake-aggregation".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: 17,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time: 789456123,
+                batch_end_time: 789456321,
+                packet_file_digest: packet_file_digest.as_ref().to_vec(),
+            };
+            let signature = batch_writer
+                .put_header(&header, &default_ingestor_private_key())
+                .unwrap();
+            batch_writer
+                .put_signature(&signature, "key-identifier")
+                .unwrap();
+
+            let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(
+                    Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+                    &mut transport,
+                    false,
+                    "trace-id",
+                    &logger,
+                );
+            let header = batch_reader.header(&key_map).unwrap();
+            match batch_reader.packet_file_reader(&header) {
+                Ok(packet_reader) => {
+                    assert!(is_valid);
+                    assert_eq!(packet_reader.count(), 0);
+                }
+                Err(_) => assert!(!is_valid),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_validation_signature_keys() {
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(1234567860, 0);
+        for is_first in &[true, false] {
+            let batch = Batch::new_validation("fake-aggregation", &batch_id, &date, *is_first);
+            assert_eq!(
+                Batch::parse_validation_signature_key(batch.signature_key(), *is_first),
+                Some(("fake-aggregation".to_owned(), date, batch_id))
+            );
+            assert_eq!(
+                Batch::parse_validation_signature_key(batch.signature_key(), !is_first),
+                None
+            );
+            assert_eq!(
+                Batch::parse_validation_signature_key(batch.header_key(), *is_first),
+                None
+            );
+        }
     }
 
     #[test]
@@ -586,7 +1375,7 @@ mod tests {
             &mut batch_writer,
             &mut batch_reader,
             &mut verify_transport,
-            &default_ingestor_private_key().key,
+            &default_ingestor_private_key(),
             &read_key,
             keys_match,
         )
@@ -670,7 +1459,7 @@ mod tests {
             &mut batch_writer,
             &mut batch_reader,
             &mut verify_transport,
-            &default_ingestor_private_key().key,
+            &default_ingestor_private_key(),
             &read_key,
             keys_match,
         )
@@ -757,7 +1546,7 @@ mod tests {
             &mut batch_writer,
             &mut batch_reader,
             &mut verify_transport,
-            &default_ingestor_private_key().key,
+            &default_ingestor_private_key(),
             &read_key,
             keys_match,
         )
@@ -791,7 +1580,7 @@ mod tests {
                 &logger,
             );
 
-        let metrics_collector = BatchReaderMetricsCollector::new("test").unwrap();
+        let metrics_collector = BatchReaderMetricsCollector::new("test", None).unwrap();
         batch_reader.set_metrics_collector(&metrics_collector);
 
         let packet = IngestionDataSharePacket {
@@ -801,6 +1590,7 @@ mod tests {
             r_pit: 1,
             version_configuration: Some("config-1".to_owned()),
             device_nonce: None,
+            encryption_ciphersuite: None,
         };
 
         batch_writer
@@ -825,7 +1615,7 @@ mod tests {
         };
 
         let header_signature = batch_writer
-            .put_header(&header, &default_ingestor_private_key().key)
+            .put_header(&header, &default_ingestor_private_key())
             .expect("failed to write header");
 
         let res = batch_writer.put_signature(&header_signature, "key-identifier");
@@ -876,4 +1666,369 @@ mod tests {
             1
         );
     }
+
+    #[test]
+    fn roundtrip_batch_sha384() {
+        let logger = setup_test_logging();
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut verify_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        let aggregation_name = "fake-aggregation";
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(
+                Batch::new_validation(aggregation_name, &batch_id, &date, true),
+                &mut write_transport,
+                "trace-id",
+            );
+        batch_writer.set_digest_algorithm(DigestAlgorithm::Sha384);
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_validation(aggregation_name, &batch_id, &date, true),
+                &mut read_transport,
+                false,
+                "trace-id",
+                &logger,
+            );
+        batch_reader.set_digest_algorithm(DigestAlgorithm::Sha384);
+        let base_path = format!(
+            "{}/{}/{}",
+            aggregation_name,
+            date.format(DATE_FORMAT),
+            batch_id.to_hyphenated()
+        );
+        roundtrip_batch(
+            aggregation_name.to_string(),
+            batch_id,
+            base_path,
+            &[
+                "validity_0".to_owned(),
+                "validity_0.avro".to_owned(),
+                "validity_0.sig".to_owned(),
+            ],
+            &mut batch_writer,
+            &mut batch_reader,
+            &mut verify_transport,
+            &default_ingestor_private_key(),
+            &default_ingestor_public_key(),
+            true,
+        );
+
+        // A reader expecting the default algorithm rejects the packet file
+        let mut mismatched_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_validation(aggregation_name, &batch_id, &date, true),
+                &mut verify_transport,
+                false,
+                "trace-id",
+                &logger,
+            );
+        let mut key_map = HashMap::new();
+        key_map.insert("key-identifier".to_owned(), default_ingestor_public_key());
+        let header = mismatched_reader.header(&key_map).unwrap();
+        assert_eq!(header.packet_file_digest.len(), 48);
+        assert!(mismatched_reader.packet_file_reader(&header).is_err());
+    }
+
+    #[test]
+    fn roundtrip_compressed_packet_files() {
+        let logger = setup_test_logging();
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDate::from_ymd(2021, 5, 1).and_hms(1, 2, 0);
+        let mut key_map = HashMap::new();
+        key_map.insert("key-identifier".to_owned(), default_ingestor_public_key());
+        // Compressible packets, like real ones, whose shares are mostly zeroes
+        let packets: Vec<IngestionDataSharePacket> = (0..100)
+            .map(|_| IngestionDataSharePacket {
+                uuid: Uuid::new_v4(),
+                encrypted_payload: vec![0u8; 256],
+                encryption_key_id: Some("fake-key-1".to_owned()),
+                r_pit: 1,
+                version_configuration: Some("config-1".to_owned()),
+                device_nonce: None,
+                encryption_ciphersuite: None,
+            })
+            .collect();
+
+        let mut packet_file_sizes = Vec::new();
+        for codec in &[Codec::Null, Codec::Deflate, Codec::Snappy] {
+            let mut transport = MemoryTransport::new();
+            let batch = || Batch::new_ingestion("fake-aggregation", &batch_id, &date);
+            let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchWriter::new(batch(), &mut transport, "trace-id");
+            batch_writer.set_packet_file_codec(*codec);
+            let packet_file_digest = batch_writer
+                .packet_file_writer(|packet_writer| {
+                    for packet in &packets {
+                        packet.write(packet_writer)?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            let header = IngestionHeader {
+                batch_uuid: batch_id,
+                name: "fake-aggregation".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: 17,
+                number_of_servers: 2,
+                hamming_weight: None,
+                batch_start_time: 789456123,
+                batch_end_time: 789456321,
+                packet_file_digest: packet_file_digest.as_ref().to_vec(),
+            };
+            let signature = batch_writer
+                .put_header(&header, &default_ingestor_private_key())
+                .unwrap();
+            batch_writer
+                .put_signature(&signature, "key-identifier")
+                .unwrap();
+
+            // A peer that reads with a plain Avro reader, e.g. one that never
+            // compresses its own packet files, sees the same packets.
+            let mut packet_file = Vec::new();
+            transport
+                .get(batch().packet_file_key(), "trace-id")
+                .unwrap()
+                .read_to_end(&mut packet_file)
+                .unwrap();
+            let peer_reader = Reader::new(packet_file.as_slice()).unwrap();
+            assert_eq!(peer_reader.count(), packets.len(), "{:?}", codec);
+            packet_file_sizes.push(packet_file.len());
+
+            let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(batch(), &mut transport, false, "trace-id", &logger);
+            let header_again = batch_reader.header(&key_map).unwrap();
+            let packets_again: Vec<IngestionDataSharePacket> = batch_reader
+                .packet_file_reader(&header_again)
+                .unwrap()
+                .map(|packet| packet.unwrap())
+                .collect();
+            assert_eq!(packets_again, packets, "{:?}", codec);
+        }
+
+        // Both codecs at least halve the size of the packet file
+        assert!(
+            packet_file_sizes[1..]
+                .iter()
+                .all(|size| *size < packet_file_sizes[0] / 2),
+            "{:?}",
+            packet_file_sizes
+        );
+    }
+
+    #[test]
+    fn header_verified_by_candidate_key() {
+        let logger = setup_test_logging();
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut write_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut read_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+        let header = IngestionHeader {
+            batch_uuid: batch_id,
+            name: "fake-aggregation".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest: vec![0u8, 1u8, 2u8, 3u8],
+        };
+
+        // Sign with the ingestor key, but identify it as "new-key"
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(
+                Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+                &mut write_transport,
+                "trace-id",
+            );
+        let signature = batch_writer
+            .put_header(&header, &default_ingestor_private_key())
+            .unwrap();
+        batch_writer.put_signature(&signature, "new-key").unwrap();
+
+        let mut batch_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion("fake-aggregation", &batch_id, &date),
+                &mut read_transport,
+                false,
+                "trace-id",
+                &logger,
+            );
+
+        // The identified key does not match, but another candidate does
+        let mut key_map = HashMap::new();
+        key_map.insert(
+            "new-key".to_owned(),
+            default_facilitator_signing_public_key(),
+        );
+        key_map.insert("previous-key".to_owned(), default_ingestor_public_key());
+        let (header_again, status) = batch_reader.header_with_signature_status(&key_map).unwrap();
+        assert_eq!(header_again, header);
+        assert_eq!(
+            status,
+            SignatureStatus::ValidWithOtherKey {
+                key_identifier: "new-key".to_owned(),
+                verifying_key_identifier: "previous-key".to_owned(),
+            }
+        );
+        assert!(status.is_valid());
+        assert_eq!(status.verifying_key_identifier(), Some("previous-key"));
+        assert_eq!(batch_reader.header(&key_map).unwrap(), header);
+
+        // The identified key is unknown, but another candidate matches
+        key_map.remove("new-key");
+        assert_eq!(batch_reader.header(&key_map).unwrap(), header);
+
+        // No candidate matches
+        key_map.insert(
+            "previous-key".to_owned(),
+            default_facilitator_signing_public_key(),
+        );
+        let (_, status) = batch_reader.header_with_signature_status(&key_map).unwrap();
+        assert_eq!(
+            status,
+            SignatureStatus::UnknownKey {
+                key_identifier: "new-key".to_owned(),
+            }
+        );
+        assert!(!status.is_valid());
+        assert_eq!(status.verifying_key_identifier(), None);
+        batch_reader.header(&key_map).unwrap_err();
+        key_map.insert(
+            "new-key".to_owned(),
+            default_facilitator_signing_public_key(),
+        );
+        let (_, status) = batch_reader.header_with_signature_status(&key_map).unwrap();
+        assert_eq!(
+            status,
+            SignatureStatus::Invalid {
+                key_identifier: "new-key".to_owned(),
+            }
+        );
+    }
+
+    /// A TransportWriter that records the size of each write made to it.
+    struct RecordingWriter {
+        writes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.lock().unwrap().push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TransportWriter for RecordingWriter {
+        fn complete_upload(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn cancel_upload(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A write-only transport whose writers record the writes made to them.
+    #[derive(Debug)]
+    struct RecordingTransport {
+        writes: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Transport for RecordingTransport {
+        fn path(&self) -> String {
+            "recording://".to_owned()
+        }
+
+        fn get(&mut self, _key: &str, _trace_id: &str) -> Result<Box<dyn Read>> {
+            unimplemented!()
+        }
+
+        fn put(&mut self, _key: &str, _trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+            Ok(Box::new(RecordingWriter {
+                writes: self.writes.clone(),
+            }))
+        }
+
+        fn list(&mut self, _prefix: &str, _trace_id: &str) -> Result<Vec<String>> {
+            unimplemented!()
+        }
+
+        fn delete(&mut self, _key: &str, _trace_id: &str) -> Result<()> {
+            unimplemented!()
+        }
+
+        fn exists(&mut self, _key: &str, _trace_id: &str) -> Result<bool> {
+            unimplemented!()
+        }
+
+        fn etag(&mut self, _key: &str, _trace_id: &str) -> Result<String> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn packet_file_writer_streams_to_transport() {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut transport = RecordingTransport {
+            writes: writes.clone(),
+        };
+        let mut batch_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(
+                Batch::new_ingestion(
+                    "fake-aggregation",
+                    &Uuid::new_v4(),
+                    &NaiveDateTime::from_timestamp(2234567890, 0),
+                ),
+                &mut transport,
+                "trace-id",
+            );
+
+        let packet = IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: vec![0u8; 100],
+            encryption_key_id: None,
+            r_pit: 1,
+            version_configuration: None,
+            device_nonce: None,
+            encryption_ciphersuite: None,
+        };
+        let packet_count = 50 * PACKET_FILE_BLOCK_SIZE / 100;
+        batch_writer
+            .packet_file_writer(|packet_writer| {
+                for _ in 0..packet_count {
+                    packet.write(packet_writer)?;
+                }
+                // Most of the packet file has already been handed to the
+                // transport before the operation is done.
+                let written: usize = writes.lock().unwrap().iter().sum();
+                assert!(written > 40 * PACKET_FILE_BLOCK_SIZE, "{}", written);
+                Ok(())
+            })
+            .unwrap();
+
+        // No single write is much bigger than a block.
+        let writes = writes.lock().unwrap();
+        assert!(writes.iter().sum::<usize>() > packet_count * 100);
+        assert!(
+            writes
+                .iter()
+                .all(|&len| len <= PACKET_FILE_BLOCK_SIZE + 200),
+            "{:?}",
+            writes.iter().max()
+        );
+    }
 }
@@ -0,0 +1,412 @@
+use crate::DATE_FORMAT;
+use chrono::NaiveDateTime;
+use std::fmt::Debug;
+use uuid::Uuid;
+
+/// The format of the dates that delimit aggregation windows in the paths of
+/// sum parts
+pub const AGGREGATION_DATE_FORMAT: &str = "%Y%m%d%H%M";
+
+/// The same layout as DATE_FORMAT, but without zero-padding the month, day,
+/// hour and minute, as some ingestors have been seen to write it
+const UNPADDED_DATE_FORMAT: &str = "%Y/%-m/%-d/%-H/%-M";
+
+/// Which of the files of a batch a key names
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchFile {
+    Header,
+    Signature,
+    PacketFile,
+}
+
+/// BatchPathScheme decides where in a bucket the files of batches and sum parts
+/// are, so that deployments whose peers or ingestors lay out buckets other than
+/// the way ENPA does, e.g. under per-locality prefixes or without date
+/// directories, can be supported by implementing it.
+///
+/// A batch's files are identified by a batch path and a filename, e.g.
+/// "batch" for ingestion batches, "validity_0" or "validity_1" for validation
+/// batches, "sum_0" or "sum_1" for the headers and signatures of sum parts and
+/// "invalid_uuid_0" or "invalid_uuid_1" for their packet files.
+pub trait BatchPathScheme: Debug + Send + Sync {
+    /// Returns the path of the ingestion or validation batch with the provided
+    /// aggregation name, UUID and date.
+    fn batch_path(&self, aggregation_name: &str, batch_id: &Uuid, date: &NaiveDateTime) -> String;
+
+    /// Parses a path returned by batch_path back into the batch's aggregation
+    /// name, date and UUID, or returns None if it is not such a path.
+    fn parse_batch_path(&self, batch_path: &str) -> Option<(String, NaiveDateTime, Uuid)>;
+
+    /// Returns the path of the sum part for the provided aggregation window.
+    fn sum_part_path(
+        &self,
+        instance_name: &str,
+        aggregation_name: &str,
+        aggregation_start: &NaiveDateTime,
+        aggregation_end: &NaiveDateTime,
+    ) -> String;
+
+    /// Returns the key of one of the files of the batch or sum part at the
+    /// provided path.
+    fn file_key(&self, batch_path: &str, filename: &str, file: BatchFile) -> String;
+
+    /// Splits a key returned by file_key into the batch path, the filename and
+    /// which file it names, or returns None if it is not such a key.
+    fn parse_file_key<'a>(&self, key: &'a str) -> Option<(&'a str, &'a str, BatchFile)>;
+
+    /// Returns the paths other than the one returned by batch_path under which
+    /// batches are known to have been written, each along with a name for the
+    /// variant, in the order BatchReader should try them if a batch is not
+    /// found under its canonical path. None by default.
+    fn batch_path_variants(
+        &self,
+        _aggregation_name: &str,
+        _batch_id: &Uuid,
+        _date: &NaiveDateTime,
+    ) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+
+    /// Parses the key of an ingestion batch's signature, as written by an
+    /// ingestor, into the batch's aggregation name, date and UUID. Returns None
+    /// if the key does not name the signature of an ingestion batch.
+    fn parse_ingestion_signature_key(&self, key: &str) -> Option<(String, NaiveDateTime, Uuid)> {
+        self.parse_signature_key(key, &ingestion_filename())
+    }
+
+    /// Parses the key of a validation batch's signature, as written by the
+    /// first or second data share processor depending on is_first, into the
+    /// batch's aggregation name, date and UUID. Returns None if the key does
+    /// not name the signature of such a validation batch.
+    fn parse_validation_signature_key(
+        &self,
+        key: &str,
+        is_first: bool,
+    ) -> Option<(String, NaiveDateTime, Uuid)> {
+        self.parse_signature_key(key, &validation_filename(is_first))
+    }
+
+    /// Parses the key of any of the files of an ingestion or validation batch
+    /// into the batch's aggregation name, date and UUID. Returns None if the
+    /// key does not name a file of such a batch.
+    fn parse_key(&self, key: &str) -> Option<(String, NaiveDateTime, Uuid)> {
+        let (batch_path, _, _) = self.parse_file_key(key)?;
+        self.parse_batch_path(batch_path)
+    }
+
+    /// Parses the key of the signature of a batch whose files have the
+    /// provided filename.
+    fn parse_signature_key(
+        &self,
+        key: &str,
+        filename: &str,
+    ) -> Option<(String, NaiveDateTime, Uuid)> {
+        match self.parse_file_key(key)? {
+            (batch_path, key_filename, BatchFile::Signature) if key_filename == filename => {
+                self.parse_batch_path(batch_path)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn ingestion_filename() -> String {
+    "batch".to_owned()
+}
+
+pub(crate) fn validation_filename(is_first: bool) -> String {
+    format!("validity_{}", if is_first { 0 } else { 1 })
+}
+
+/// The layout of the buckets of the Exposure Notifications Private Analytics
+/// deployment, and the default. Batches are at
+/// "{aggregation name}/{YYYY/mm/dd/HH/MM}/{UUID}", sum parts at
+/// "{instance name}/{aggregation name}/{YYYYmmddHHMM}-{YYYYmmddHHMM}", and
+/// each file is at "{path}.{filename}", with ".sig" appended for signatures
+/// and ".avro" for packet files.
+///
+/// Some ingestors have written batches with uppercase UUIDs or without
+/// zero-padding the date components, so those are offered as variants.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnpaPathScheme;
+
+impl EnpaPathScheme {
+    /// Names of the variants, and whether each has an uppercase UUID and an
+    /// unpadded date, in the order they are tried
+    const VARIANTS: [(&'static str, bool, bool); 3] = [
+        ("uppercase-uuid", true, false),
+        ("unpadded-date", false, true),
+        ("uppercase-uuid-unpadded-date", true, true),
+    ];
+
+    fn format_batch_path(
+        aggregation_name: &str,
+        batch_id: &Uuid,
+        date: &NaiveDateTime,
+        uppercase_uuid: bool,
+        unpadded_date: bool,
+    ) -> String {
+        let mut batch_id = batch_id.to_hyphenated().to_string();
+        if uppercase_uuid {
+            batch_id.make_ascii_uppercase();
+        }
+        format!(
+            "{}/{}/{}",
+            aggregation_name,
+            date.format(if unpadded_date {
+                UNPADDED_DATE_FORMAT
+            } else {
+                DATE_FORMAT
+            }),
+            batch_id
+        )
+    }
+}
+
+impl BatchPathScheme for EnpaPathScheme {
+    fn batch_path(&self, aggregation_name: &str, batch_id: &Uuid, date: &NaiveDateTime) -> String {
+        EnpaPathScheme::format_batch_path(aggregation_name, batch_id, date, false, false)
+    }
+
+    fn parse_batch_path(&self, batch_path: &str) -> Option<(String, NaiveDateTime, Uuid)> {
+        // Dates have five components, so splitting from the right yields the
+        // UUID, then the date, then the aggregation name.
+        let mut components = batch_path.rsplitn(7, '/');
+        let batch_id = Uuid::parse_str(components.next()?).ok()?;
+        let mut date_components: Vec<&str> = components.by_ref().take(5).collect();
+        date_components.reverse();
+        let date = NaiveDateTime::parse_from_str(&date_components.join("/"), DATE_FORMAT).ok()?;
+        let aggregation_name = components.next()?;
+        if aggregation_name.is_empty() {
+            return None;
+        }
+        Some((aggregation_name.to_owned(), date, batch_id))
+    }
+
+    fn sum_part_path(
+        &self,
+        instance_name: &str,
+        aggregation_name: &str,
+        aggregation_start: &NaiveDateTime,
+        aggregation_end: &NaiveDateTime,
+    ) -> String {
+        format!(
+            "{}/{}/{}-{}",
+            instance_name,
+            aggregation_name,
+            aggregation_start.format(AGGREGATION_DATE_FORMAT),
+            aggregation_end.format(AGGREGATION_DATE_FORMAT)
+        )
+    }
+
+    fn file_key(&self, batch_path: &str, filename: &str, file: BatchFile) -> String {
+        match file {
+            BatchFile::Header => format!("{}.{}", batch_path, filename),
+            BatchFile::Signature => format!("{}.{}.sig", batch_path, filename),
+            BatchFile::PacketFile => format!("{}.{}.avro", batch_path, filename),
+        }
+    }
+
+    fn parse_file_key<'a>(&self, key: &'a str) -> Option<(&'a str, &'a str, BatchFile)> {
+        // The batch path ends at the first '.' of the last path component
+        let filename_start = key.rfind('/').map_or(0, |slash| slash + 1);
+        let batch_path_end = filename_start + key[filename_start..].find('.')?;
+        let (filename, file) = match key[batch_path_end + 1..].split_once('.') {
+            None => (&key[batch_path_end + 1..], BatchFile::Header),
+            Some((filename, "sig")) => (filename, BatchFile::Signature),
+            Some((filename, "avro")) => (filename, BatchFile::PacketFile),
+            Some(_) => return None,
+        };
+        if filename.is_empty() {
+            return None;
+        }
+        Some((&key[..batch_path_end], filename, file))
+    }
+
+    fn batch_path_variants(
+        &self,
+        aggregation_name: &str,
+        batch_id: &Uuid,
+        date: &NaiveDateTime,
+    ) -> Vec<(&'static str, String)> {
+        let canonical = self.batch_path(aggregation_name, batch_id, date);
+        let mut variants: Vec<(&'static str, String)> = Vec::new();
+        for (name, uppercase_uuid, unpadded_date) in EnpaPathScheme::VARIANTS.iter() {
+            let path = EnpaPathScheme::format_batch_path(
+                aggregation_name,
+                batch_id,
+                date,
+                *uppercase_uuid,
+                *unpadded_date,
+            );
+            // Skip variants that coincide with the canonical path or an earlier
+            // variant, e.g. unpadded dates on December 31 at 23:59
+            if path != canonical && variants.iter().all(|(_, other)| path != *other) {
+                variants.push((name, path));
+            }
+        }
+        variants
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::Batch;
+    use chrono::NaiveDate;
+    use std::sync::Arc;
+
+    /// Lays batches out flat under a per-locality prefix, e.g.
+    /// "locality/aggregation-202105010102-{UUID}"
+    #[derive(Debug)]
+    struct FlatPathScheme {
+        locality: String,
+    }
+
+    impl BatchPathScheme for FlatPathScheme {
+        fn batch_path(
+            &self,
+            aggregation_name: &str,
+            batch_id: &Uuid,
+            date: &NaiveDateTime,
+        ) -> String {
+            format!(
+                "{}/{}-{}-{}",
+                self.locality,
+                aggregation_name,
+                date.format(AGGREGATION_DATE_FORMAT),
+                batch_id
+            )
+        }
+
+        fn parse_batch_path(&self, batch_path: &str) -> Option<(String, NaiveDateTime, Uuid)> {
+            let name = batch_path.strip_prefix(&format!("{}/", self.locality))?;
+            // UUIDs are 36 characters long and dates 12
+            let batch_id = Uuid::parse_str(name.get(name.len().checked_sub(36)?..)?).ok()?;
+            let rest = name.get(..name.len() - 37)?;
+            let date = NaiveDateTime::parse_from_str(
+                rest.get(rest.len().checked_sub(12)?..)?,
+                AGGREGATION_DATE_FORMAT,
+            )
+            .ok()?;
+            let aggregation_name = rest.get(..rest.len().checked_sub(13)?)?;
+            Some((aggregation_name.to_owned(), date, batch_id))
+        }
+
+        fn sum_part_path(
+            &self,
+            instance_name: &str,
+            aggregation_name: &str,
+            aggregation_start: &NaiveDateTime,
+            aggregation_end: &NaiveDateTime,
+        ) -> String {
+            EnpaPathScheme.sum_part_path(
+                &format!("{}/{}", self.locality, instance_name),
+                aggregation_name,
+                aggregation_start,
+                aggregation_end,
+            )
+        }
+
+        fn file_key(&self, batch_path: &str, filename: &str, file: BatchFile) -> String {
+            EnpaPathScheme.file_key(batch_path, filename, file)
+        }
+
+        fn parse_file_key<'a>(&self, key: &'a str) -> Option<(&'a str, &'a str, BatchFile)> {
+            EnpaPathScheme.parse_file_key(key)
+        }
+    }
+
+    #[test]
+    fn enpa_file_keys() {
+        let scheme = EnpaPathScheme;
+        for (key, expected) in &[
+            ("a/b.batch", Some(("a/b", "batch", BatchFile::Header))),
+            (
+                "a/b.batch.sig",
+                Some(("a/b", "batch", BatchFile::Signature)),
+            ),
+            (
+                "a/b.validity_0.avro",
+                Some(("a/b", "validity_0", BatchFile::PacketFile)),
+            ),
+            ("a.b/c.sum_1", Some(("a.b/c", "sum_1", BatchFile::Header))),
+            ("a/b", None),
+            ("a/b.", None),
+            ("a/b.batch.tmp", None),
+        ] {
+            assert_eq!(scheme.parse_file_key(key), *expected, "{}", key);
+        }
+
+        let batch_id = Uuid::new_v4();
+        let date = NaiveDate::from_ymd(2021, 5, 1).and_hms(1, 2, 0);
+        for (filename, file) in &[
+            ("batch", BatchFile::Header),
+            ("validity_1", BatchFile::Signature),
+            ("sum_0", BatchFile::PacketFile),
+        ] {
+            let batch_path = scheme.batch_path("fake-aggregation", &batch_id, &date);
+            let key = scheme.file_key(&batch_path, filename, *file);
+            assert_eq!(
+                scheme.parse_file_key(&key),
+                Some((batch_path.as_ref(), *filename, *file))
+            );
+        }
+    }
+
+    #[test]
+    fn alternate_scheme() {
+        let scheme = Arc::new(FlatPathScheme {
+            locality: "fake-locality".to_owned(),
+        });
+        let batch_id = Uuid::parse_str("6e8bd1d0-2d7a-4a39-b0a7-3bd6d1ba1c2f").unwrap();
+        let date = NaiveDate::from_ymd(2021, 5, 1).and_hms(1, 2, 0);
+
+        let batch =
+            Batch::new_ingestion_with_scheme(scheme.clone(), "fake-aggregation", &batch_id, &date);
+        assert_eq!(
+            batch.signature_key(),
+            "fake-locality/fake-aggregation-202105010102-6e8bd1d0-2d7a-4a39-b0a7-3bd6d1ba1c2f.batch.sig"
+        );
+        assert_eq!(
+            scheme.parse_ingestion_signature_key(batch.signature_key()),
+            Some(("fake-aggregation".to_owned(), date, batch_id))
+        );
+        assert_eq!(
+            scheme.parse_key(batch.packet_file_key()),
+            Some(("fake-aggregation".to_owned(), date, batch_id))
+        );
+        assert_eq!(
+            scheme.parse_validation_signature_key(batch.signature_key(), true),
+            None
+        );
+        assert!(batch.key_variants().is_empty());
+        // The default scheme doesn't recognize the alternate layout
+        assert_eq!(Batch::parse_key(batch.header_key()), None);
+
+        let batch = Batch::new_validation_with_scheme(
+            scheme.clone(),
+            "fake-aggregation",
+            &batch_id,
+            &date,
+            false,
+        );
+        assert_eq!(
+            scheme.parse_validation_signature_key(batch.signature_key(), false),
+            Some(("fake-aggregation".to_owned(), date, batch_id))
+        );
+
+        let sum = Batch::new_sum_with_scheme(
+            scheme,
+            "fake-instance",
+            "fake-aggregation",
+            &date,
+            &date,
+            true,
+        );
+        assert_eq!(
+            sum.packet_file_key(),
+            "fake-locality/fake-instance/fake-aggregation/202105010102-202105010102.invalid_uuid_0.avro"
+        );
+    }
+}
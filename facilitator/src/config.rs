@@ -2,28 +2,45 @@ use anyhow::{anyhow, Context, Result};
 use rusoto_core::{region::ParseRegionError, Region};
 use serde::{de, Deserialize, Deserializer};
 use std::{
+    collections::BTreeMap,
     fmt::{self, Display, Formatter},
-    path::PathBuf,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
 use crate::aws_credentials;
+use url::Url;
 
 /// Identity represents a cloud identity: Either an AWS IAM ARN (i.e. "arn:...")
 /// or a GCP ServiceAccount (i.e. "foo@bar.com").
 pub type Identity<'a> = Option<&'a str>;
 
-/// Parameters necessary to configure federation from AWS IAM to GCP IAM using
-/// GCP workload identity pool
+/// Parameters necessary to configure federation from AWS IAM or an OIDC
+/// identity provider to GCP IAM using GCP workload identity pool
 /// https://cloud.google.com/iam/docs/access-resources-aws
+/// https://cloud.google.com/iam/docs/access-resources-oidc
 pub struct WorkloadIdentityPoolParameters {
     /// Full identifier of the workload identity pool provider, like
     /// "//iam.googleapis.com/projects/12345678/locations/global/workloadIdentityPools/my-pool/providers/my-aws-provider"
     pub workload_identity_pool_provider: String,
+    /// The external credential that is exchanged for a GCP token
+    pub credential_source: WorkloadIdentityCredentialSource,
+}
+
+/// The external credentials that can be exchanged for GCP tokens via a
+/// workload identity pool provider.
+pub enum WorkloadIdentityCredentialSource {
     /// A credential provider that can get AWS credentials for an AWS IAM role
     /// or user that is permitted to impersonate a GCP service account via the
-    /// workload_identity_pool_provider
-    pub aws_credentials_provider: aws_credentials::Provider,
+    /// workload identity pool provider
+    Aws(Box<aws_credentials::Provider>),
+    /// The path to a file containing an OIDC ID token issued by an identity
+    /// provider that the workload identity pool provider trusts, such as a
+    /// projected Kubernetes service account token. The file is read each time a
+    /// token is exchanged, since such tokens are rotated.
+    OidcTokenFile(PathBuf),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -145,10 +162,156 @@ impl FromStr for GcsPath {
     }
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AzurePath {
+    pub storage_account: String,
+    pub container: String,
+    pub key: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AzurePathParseError {
+    #[error("Not an Azure path")]
+    NoPath,
+    #[error(
+        "Azure path must be in the format `azure://{{storage account}}/{{container}}/{{optional key prefix}}`"
+    )]
+    InvalidFormat,
+}
+
+impl AzurePath {
+    /// Returns `self`, possibly adding '/' at the end of the key to ensure it can be combined with another path as a directory prefix.
+    pub fn ensure_directory_prefix(mut self) -> Self {
+        if !self.key.is_empty() && !self.key.ends_with('/') {
+            self.key.push('/');
+        }
+        self
+    }
+}
+
+impl Display for AzurePath {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "azure://{}/{}/{}",
+            self.storage_account, self.container, self.key
+        )
+    }
+}
+
+impl FromStr for AzurePath {
+    type Err = AzurePathParseError;
+
+    fn from_str(s: &str) -> Result<Self, AzurePathParseError> {
+        let account_and_container = s
+            .strip_prefix("azure://")
+            .ok_or(AzurePathParseError::NoPath)?;
+
+        // As with S3, we require only a storage account and container name and
+        // leave further validation to Azure.
+        let mut components = account_and_container
+            .splitn(3, '/')
+            .take_while(|s| !s.is_empty());
+        let storage_account = components
+            .next()
+            .ok_or(AzurePathParseError::InvalidFormat)?
+            .to_owned();
+        let container = components
+            .next()
+            .ok_or(AzurePathParseError::InvalidFormat)?
+            .to_owned();
+        let key = components.next().map(|s| s.to_owned()).unwrap_or_default();
+        assert!(components.next().is_none());
+
+        Ok(AzurePath {
+            storage_account,
+            container,
+            key,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SftpPath {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    /// Directory relative to the user's login directory
+    pub key: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SftpPathParseError {
+    #[error("Not an SFTP path")]
+    NoPath,
+    #[error("SFTP path must be in the format `sftp://{{user}}@{{host}}[:{{port}}]/{{optional directory}}`")]
+    InvalidFormat,
+}
+
+impl SftpPath {
+    /// Returns `self`, possibly adding '/' at the end of the key to ensure it can be combined with another path as a directory prefix.
+    pub fn ensure_directory_prefix(mut self) -> Self {
+        if !self.key.is_empty() && !self.key.ends_with('/') {
+            self.key.push('/');
+        }
+        self
+    }
+}
+
+impl Display for SftpPath {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "sftp://{}@{}:{}/{}",
+            self.user, self.host, self.port, self.key
+        )
+    }
+}
+
+impl FromStr for SftpPath {
+    type Err = SftpPathParseError;
+
+    fn from_str(s: &str) -> Result<Self, SftpPathParseError> {
+        let rest = s
+            .strip_prefix("sftp://")
+            .ok_or(SftpPathParseError::NoPath)?;
+
+        let (authority, key) = match rest.split_once('/') {
+            Some((authority, key)) => (authority, key.to_owned()),
+            None => (rest, String::new()),
+        };
+        let (user, host_and_port) = authority
+            .split_once('@')
+            .ok_or(SftpPathParseError::InvalidFormat)?;
+        let (host, port) = match host_and_port.split_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| SftpPathParseError::InvalidFormat)?,
+            ),
+            None => (host_and_port, 22),
+        };
+        if user.is_empty() || host.is_empty() {
+            return Err(SftpPathParseError::InvalidFormat);
+        }
+
+        Ok(SftpPath {
+            user: user.to_owned(),
+            host: host.to_owned(),
+            port,
+            key,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum StoragePath {
     GcsPath(GcsPath),
     S3Path(S3Path),
+    AzurePath(AzurePath),
+    SftpPath(SftpPath),
+    /// A read-only directory on a web server, served over HTTPS.
+    HttpsPath(Url),
     LocalPath(PathBuf),
 }
 
@@ -166,6 +329,22 @@ impl FromStr for StoragePath {
             p => return Ok(StoragePath::GcsPath(p.context("parsing a GCS path")?)),
         }
 
+        match AzurePath::from_str(s) {
+            Err(AzurePathParseError::NoPath) => {}
+            p => return Ok(StoragePath::AzurePath(p.context("parsing an Azure path")?)),
+        }
+
+        match SftpPath::from_str(s) {
+            Err(SftpPathParseError::NoPath) => {}
+            p => return Ok(StoragePath::SftpPath(p.context("parsing an SFTP path")?)),
+        }
+
+        if s.starts_with("https://") {
+            return Ok(StoragePath::HttpsPath(
+                Url::parse(s).context("parsing an HTTPS path")?,
+            ));
+        }
+
         Ok(StoragePath::LocalPath(s.into()))
     }
 }
@@ -247,6 +426,7 @@ pub fn leak_string(s: String) -> &'static str {
 }
 
 /// The string "-input" or "-output", for appending to arg names.
+#[derive(Clone, Copy)]
 pub enum InOut {
     Input,
     Output,
@@ -289,6 +469,249 @@ impl Entity {
     }
 }
 
+/// ConfigFile holds arguments to the facilitator command loaded from a YAML
+/// file, so that deployments need not pass dozens of flags. Top level keys are
+/// the long names of arguments to `facilitator` itself, or the names of
+/// subcommands, whose values are maps of the long names of the subcommand's
+/// arguments to their values. For example:
+///
+/// ```yaml
+/// log-format: json
+/// intake-batch-worker:
+///   task-queue-kind: gcp-pubsub
+///   batch-signing-private-key-identifier: key-1
+///   packet-decryption-keys: [key-a, key-b]
+/// ```
+///
+/// Values may be strings, numbers, booleans or lists of those, which are
+/// passed as repeated arguments. Arguments given on the command line override
+/// those from the config file, which in turn override environment variables.
+///
+/// The top level key `localities` is a registry of the localities served by a
+/// deployment, mapping the name of each locality to the arguments that differ
+/// for it, such as its instance name, buckets, peer manifests and keys:
+///
+/// ```yaml
+/// localities:
+///   zc:
+///     instance-name: zc-megacorp
+///     ingestor-input: gs://zc-megacorp-ingestion
+///   ta:
+///     instance-name: ta-megacorp
+///     ingestor-input: gs://ta-megacorp-ingestion
+/// ```
+///
+/// Workers handle tasks naming a locality with the locality's arguments, which
+/// override those given for the subcommand in the config file.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigFile {
+    arguments: BTreeMap<String, Vec<String>>,
+    subcommands: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    localities: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+/// The top level key in a config file under which localities are listed
+const LOCALITIES_KEY: &str = "localities";
+
+impl ConfigFile {
+    /// Loads a config file from the provided path.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let file = File::open(path).context(format!("failed to open config file {:?}", path))?;
+        Self::from_reader(file).context(format!("invalid config file {:?}", path))
+    }
+
+    /// Loads a config file from the provided reader. Returns an error naming
+    /// the offending field if the file is not structured as described above.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let document: BTreeMap<String, serde_yaml::Value> =
+            serde_yaml::from_reader(reader).context("failed to parse config file as YAML")?;
+
+        let mut config = ConfigFile::default();
+        for (key, value) in document {
+            match value {
+                serde_yaml::Value::Mapping(mapping) if key == LOCALITIES_KEY => {
+                    for (locality, value) in mapping {
+                        let locality = locality.as_str().ok_or_else(|| {
+                            anyhow!("keys in config file field {} must be strings", key)
+                        })?;
+                        let field = format!("{}.{}", key, locality);
+                        let arguments = match value {
+                            serde_yaml::Value::Mapping(mapping) => {
+                                Self::argument_map(&field, mapping)?
+                            }
+                            _ => return Err(anyhow!("config file field {} must be a map", field)),
+                        };
+                        config.localities.insert(locality.to_owned(), arguments);
+                    }
+                }
+                serde_yaml::Value::Mapping(mapping) => {
+                    let arguments = Self::argument_map(&key, mapping)?;
+                    config.subcommands.insert(key, arguments);
+                }
+                value => {
+                    let values = Self::argument_values(&key, value)?;
+                    config.arguments.insert(key, values);
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn argument_map(
+        field: &str,
+        mapping: serde_yaml::Mapping,
+    ) -> Result<BTreeMap<String, Vec<String>>> {
+        let mut arguments = BTreeMap::new();
+        for (argument, value) in mapping {
+            let argument = argument
+                .as_str()
+                .ok_or_else(|| anyhow!("keys in config file field {} must be strings", field))?;
+            let field = format!("{}.{}", field, argument);
+            arguments.insert(argument.to_owned(), Self::argument_values(&field, value)?);
+        }
+        Ok(arguments)
+    }
+
+    fn argument_values(field: &str, value: serde_yaml::Value) -> Result<Vec<String>> {
+        let scalar = |value: &serde_yaml::Value| match value {
+            serde_yaml::Value::String(s) => Ok(s.clone()),
+            serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+            serde_yaml::Value::Number(n) => Ok(n.to_string()),
+            _ => Err(anyhow!(
+                "config file field {} must be a string, number, boolean or a list of them",
+                field
+            )),
+        };
+
+        match &value {
+            serde_yaml::Value::Sequence(values) => values.iter().map(scalar).collect(),
+            value => Ok(vec![scalar(value)?]),
+        }
+    }
+
+    /// Returns the command line `args`, including the program name, with
+    /// arguments from the config file added for `facilitator` and for the
+    /// subcommand named in `args`, if any. Arguments that appear in `args` are
+    /// not added, so that the command line overrides the config file.
+    pub fn apply(&self, args: &[String]) -> Vec<String> {
+        self.apply_with_overrides(args, &BTreeMap::new())
+    }
+
+    /// Like apply, but the arguments given for the provided locality are also
+    /// added for the subcommand, overriding those given for the subcommand in
+    /// the config file. Returns an error if the locality is not in the config
+    /// file.
+    pub fn apply_locality(&self, args: &[String], locality: &str) -> Result<Vec<String>> {
+        let arguments = self
+            .localities
+            .get(locality)
+            .context(format!("no locality {} in config file", locality))?;
+        Ok(self.apply_with_overrides(args, arguments))
+    }
+
+    fn apply_with_overrides(
+        &self,
+        args: &[String],
+        overrides: &BTreeMap<String, Vec<String>>,
+    ) -> Vec<String> {
+        let subcommand_index = subcommand_index(args);
+        let (top_level_args, subcommand_args) = args.split_at(subcommand_index);
+
+        let mut applied = top_level_args.to_vec();
+        applied.extend(Self::absent_arguments(&self.arguments, top_level_args));
+        if let Some((subcommand, subcommand_args)) = subcommand_args.split_first() {
+            applied.push(subcommand.clone());
+            applied.extend_from_slice(subcommand_args);
+            let mut arguments = self
+                .subcommands
+                .get(subcommand)
+                .cloned()
+                .unwrap_or_default();
+            arguments.extend(overrides.clone());
+            applied.extend(Self::absent_arguments(&arguments, subcommand_args));
+        }
+
+        applied
+    }
+
+    /// Returns the names of the localities in the config file.
+    pub fn localities(&self) -> impl Iterator<Item = &str> {
+        self.localities.keys().map(String::as_str)
+    }
+
+    /// Returns command line arguments for the entries in `arguments` that do
+    /// not appear in `present`. Values are attached with '=' so that they are
+    /// never mistaken for flags.
+    fn absent_arguments(
+        arguments: &BTreeMap<String, Vec<String>>,
+        present: &[String],
+    ) -> Vec<String> {
+        arguments
+            .iter()
+            .filter(|(name, _)| {
+                let flag = format!("--{}", name);
+                !present
+                    .iter()
+                    .any(|arg| *arg == flag || arg.starts_with(&format!("{}=", flag)))
+            })
+            .flat_map(|(name, values)| {
+                values
+                    .iter()
+                    .map(move |value| format!("--{}={}", name, value))
+            })
+            .collect()
+    }
+
+    /// Returns true if `argument` was added to the command line by this config
+    /// file, either for `facilitator`, for any subcommand or for any locality.
+    pub fn provides(&self, argument: &str) -> bool {
+        self.arguments.contains_key(argument)
+            || self
+                .subcommands
+                .values()
+                .chain(self.localities.values())
+                .any(|arguments| arguments.contains_key(argument))
+    }
+}
+
+/// Returns the path to a config file named by a `--config` argument to
+/// `facilitator` in the provided command line arguments, if any.
+pub fn config_file_path(args: &[String]) -> Option<PathBuf> {
+    let top_level_args = &args[..subcommand_index(args)];
+    top_level_args.iter().enumerate().find_map(|(index, arg)| {
+        if arg == "--config" {
+            top_level_args.get(index + 1).map(PathBuf::from)
+        } else {
+            arg.strip_prefix("--config=").map(PathBuf::from)
+        }
+    })
+}
+
+/// Returns the index of the subcommand in the provided command line arguments,
+/// or the number of arguments if there is none. All arguments to `facilitator`
+/// itself take a value, so the subcommand is the first argument that is
+/// neither a flag nor the value following a flag.
+fn subcommand_index(args: &[String]) -> usize {
+    let mut index = 1;
+    while index < args.len() {
+        let arg = &args[index];
+        if !arg.starts_with('-') {
+            return index;
+        }
+        if arg.starts_with("--") && !arg.contains('=') && !is_value_less_flag(arg) {
+            // Skip the flag's value
+            index += 1;
+        }
+        index += 1;
+    }
+    args.len()
+}
+
+fn is_value_less_flag(arg: &str) -> bool {
+    matches!(arg, "--help" | "--version")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -361,6 +784,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn deserialize_storagepath_httpspath() {
+        assert_de_tokens(
+            &StoragePath::HttpsPath(Url::parse("https://example.com/batches/").unwrap()),
+            &[Token::Str("https://example.com/batches/")],
+        );
+    }
+
     #[test]
     fn parse_gcspath() {
         let p1 = GcsPath::from_str("gs://the-bucket/path/to/object").unwrap();
@@ -393,9 +824,255 @@ mod tests {
         assert_eq!(p.key, "key-prefix/");
     }
 
+    #[test]
+    fn parse_azurepath() {
+        let p = AzurePath::from_str("azure://account/container/path/to/object").unwrap();
+        assert_eq!(p.storage_account, "account");
+        assert_eq!(p.container, "container");
+        assert_eq!(p.key, "path/to/object");
+
+        let p1 = AzurePath::from_str("azure://account/container").unwrap();
+        let p2 = AzurePath::from_str("azure://account/container/").unwrap();
+        assert_eq!(p1.key, "");
+        assert_eq!(p1, p2);
+    }
+
+    #[test]
+    fn parse_azure_invalid_paths() {
+        // no storage account
+        let e = AzurePath::from_str("azure://").unwrap_err();
+        assert_matches!(e, AzurePathParseError::InvalidFormat);
+        // no container
+        let e = AzurePath::from_str("azure://account/").unwrap_err();
+        assert_matches!(e, AzurePathParseError::InvalidFormat);
+        // wrong scheme
+        let e = AzurePath::from_str("gs://bucket-name/key").unwrap_err();
+        assert_matches!(e, AzurePathParseError::NoPath);
+    }
+
+    #[test]
+    fn parse_sftppath() {
+        let p = SftpPath::from_str("sftp://user@example.com:2222/drop/box").unwrap();
+        assert_eq!(p.user, "user");
+        assert_eq!(p.host, "example.com");
+        assert_eq!(p.port, 2222);
+        assert_eq!(p.key, "drop/box");
+
+        let p1 = SftpPath::from_str("sftp://user@example.com").unwrap();
+        let p2 = SftpPath::from_str("sftp://user@example.com/").unwrap();
+        assert_eq!(p1.port, 22);
+        assert_eq!(p1.key, "");
+        assert_eq!(p1, p2);
+    }
+
+    #[test]
+    fn parse_sftp_invalid_paths() {
+        // no user
+        let e = SftpPath::from_str("sftp://example.com/path").unwrap_err();
+        assert_matches!(e, SftpPathParseError::InvalidFormat);
+        // no host
+        let e = SftpPath::from_str("sftp://user@/path").unwrap_err();
+        assert_matches!(e, SftpPathParseError::InvalidFormat);
+        // bad port
+        let e = SftpPath::from_str("sftp://user@example.com:port/path").unwrap_err();
+        assert_matches!(e, SftpPathParseError::InvalidFormat);
+        // wrong scheme
+        let e = SftpPath::from_str("azure://account/container").unwrap_err();
+        assert_matches!(e, SftpPathParseError::NoPath);
+    }
+
+    #[test]
+    fn deserialize_storagepath_azurepath() {
+        assert_de_tokens(
+            &StoragePath::AzurePath("azure://account/container".parse().unwrap()),
+            &[Token::Str("azure://account/container")],
+        );
+    }
+
     #[test]
     fn entity_suffix() {
         let val = Entity::Peer.suffix(InOut::Input.str());
         assert_eq!(val, "peer-input");
     }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn load_config_file() {
+        let config = ConfigFile::from_reader(
+            r#"
+log-format: json
+manifest-cache-ttl: 60
+intake-batch-worker:
+  permit-malformed-batch: true
+  packet-decryption-keys:
+    - key-a
+    - key-b
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert!(config.provides("log-format"));
+        assert!(config.provides("packet-decryption-keys"));
+        assert!(!config.provides("own-output"));
+
+        assert_eq!(
+            config.apply(&args(&[
+                "facilitator",
+                "--log-format",
+                "text",
+                "intake-batch-worker",
+                "--permit-malformed-batch=false",
+            ])),
+            args(&[
+                "facilitator",
+                "--log-format",
+                "text",
+                "--manifest-cache-ttl=60",
+                "intake-batch-worker",
+                "--permit-malformed-batch=false",
+                "--packet-decryption-keys=key-a",
+                "--packet-decryption-keys=key-b",
+            ])
+        );
+
+        // Arguments for other subcommands are ignored
+        assert_eq!(
+            config.apply(&args(&["facilitator", "aggregate-worker"])),
+            args(&[
+                "facilitator",
+                "--log-format=json",
+                "--manifest-cache-ttl=60",
+                "aggregate-worker",
+            ])
+        );
+
+        // Without a subcommand, only top level arguments are applied
+        assert_eq!(
+            config.apply(&args(&["facilitator", "--help"])),
+            args(&[
+                "facilitator",
+                "--help",
+                "--log-format=json",
+                "--manifest-cache-ttl=60",
+            ])
+        );
+    }
+
+    #[test]
+    fn config_file_localities() {
+        let config = ConfigFile::from_reader(
+            r#"
+aggregate-worker:
+  instance-name: default-megacorp
+  is-first: true
+localities:
+  zc:
+    instance-name: zc-megacorp
+    packet-decryption-keys: [key-a, key-b]
+  ta:
+    instance-name: ta-megacorp
+"#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(config.localities().collect::<Vec<_>>(), vec!["ta", "zc"]);
+        assert!(config.provides("packet-decryption-keys"));
+
+        // Locality arguments override the subcommand's, but not the command
+        // line
+        assert_eq!(
+            config
+                .apply_locality(
+                    &args(&["facilitator", "aggregate-worker", "--is-first=false"]),
+                    "zc"
+                )
+                .unwrap(),
+            args(&[
+                "facilitator",
+                "aggregate-worker",
+                "--is-first=false",
+                "--instance-name=zc-megacorp",
+                "--packet-decryption-keys=key-a",
+                "--packet-decryption-keys=key-b",
+            ])
+        );
+
+        // Locality arguments are only applied when asked for
+        assert_eq!(
+            config.apply(&args(&["facilitator", "aggregate-worker"])),
+            args(&[
+                "facilitator",
+                "aggregate-worker",
+                "--instance-name=default-megacorp",
+                "--is-first=true",
+            ])
+        );
+
+        config
+            .apply_locality(&args(&["facilitator", "aggregate-worker"]), "xx")
+            .unwrap_err();
+    }
+
+    #[test]
+    fn invalid_config_file() {
+        for (config, field) in &[
+            ("log-format: ~", "log-format"),
+            (
+                "intake-batch-worker:\n  own-output: {a: b}",
+                "intake-batch-worker.own-output",
+            ),
+            (
+                "intake-batch-worker:\n  batch-id: [[a]]",
+                "intake-batch-worker.batch-id",
+            ),
+            ("localities:\n  zc: zc-megacorp", "localities.zc"),
+            (
+                "localities:\n  zc:\n    own-output: {a: b}",
+                "localities.zc.own-output",
+            ),
+        ] {
+            let error = ConfigFile::from_reader(config.as_bytes()).unwrap_err();
+            assert!(
+                error.to_string().contains(&format!("field {} ", field)),
+                "unexpected error {} for config {}",
+                error,
+                config
+            );
+        }
+
+        ConfigFile::from_reader("- not a map".as_bytes()).unwrap_err();
+    }
+
+    #[test]
+    fn find_config_file_path() {
+        assert_eq!(
+            config_file_path(&args(&[
+                "facilitator",
+                "--config",
+                "a.yaml",
+                "intake-batch"
+            ])),
+            Some(PathBuf::from("a.yaml"))
+        );
+        assert_eq!(
+            config_file_path(&args(&["facilitator", "--config=b.yaml", "intake-batch"])),
+            Some(PathBuf::from("b.yaml"))
+        );
+        // --config is an argument to facilitator, not to subcommands
+        assert_eq!(
+            config_file_path(&args(&[
+                "facilitator",
+                "intake-batch",
+                "--config",
+                "c.yaml"
+            ])),
+            None
+        );
+        assert_eq!(config_file_path(&args(&["facilitator"])), None);
+    }
 }
@@ -0,0 +1,636 @@
+use crate::{
+    dedup::PacketDeduplicator, field::EncodedField, hpke::HpkePrivateKey, logging::event,
+    packet_encryption::generate_share_key,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use prio::{
+    encrypt::{encrypt_share, PublicKey},
+    server::{Server, VerificationMessage},
+};
+use slog::{debug, o, Logger};
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+};
+use uuid::Uuid;
+
+// This module implements the helper's side of the aggregate flow of the
+// Distributed Aggregation Protocol (draft-ietf-ppm-dap-02), so that the
+// facilitator can aggregate report shares sent by a DAP leader as well as
+// batches of ingestion packets. Messages are encoded in the TLS presentation
+// language as the draft specifies. The VDAF is the Prio proof system that the
+// rest of the facilitator verifies, with the leader choosing the evaluation
+// point, rather than Prio3, which libprio-rs does not yet implement.
+
+/// The prefix of the HPKE info string with which clients encrypt input shares
+const INPUT_SHARE_INFO: &[u8] = b"dap-02 input share";
+/// Role identifiers, as they appear in HPKE info strings
+const ROLE_CLIENT: u8 = 0x01;
+const ROLE_HELPER: u8 = 0x03;
+
+/// Number of report IDs remembered in memory, past which they are spilled to
+/// temporary files, for the detection of replayed reports.
+const MAX_REPORT_IDS_IN_MEMORY: usize = 100_000;
+
+/// The reasons the helper rejects a report share, with the codes of the
+/// ReportShareError enum of the DAP specification. These are found in the
+/// errors returned by DapHelper::prepare_init.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PrepareError {
+    #[error("report was already prepared")]
+    ReportReplayed,
+    #[error("unknown HPKE config ID")]
+    HpkeUnknownConfigId,
+    #[error("input share could not be decrypted")]
+    HpkeDecryptError,
+    #[error("input share could not be prepared")]
+    VdafPrepError,
+    #[error("report is from after the task's expiration")]
+    TaskExpired,
+    #[error("report is too far in the future")]
+    ReportTooEarly,
+}
+
+impl PrepareError {
+    pub fn code(&self) -> u8 {
+        match self {
+            PrepareError::ReportReplayed => 1,
+            PrepareError::HpkeUnknownConfigId => 3,
+            PrepareError::HpkeDecryptError => 4,
+            PrepareError::VdafPrepError => 5,
+            PrepareError::TaskExpired => 7,
+            PrepareError::ReportTooEarly => 9,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TaskId(pub [u8; 32]);
+
+/// The metadata of a report. The time is in seconds since the Unix epoch.
+/// Report extensions are not supported, so reports that have any are
+/// rejected when decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportMetadata {
+    pub report_id: Uuid,
+    pub time: u64,
+}
+
+impl ReportMetadata {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(self.report_id.as_bytes());
+        bytes.extend_from_slice(&self.time.to_be_bytes());
+        // No extensions
+        bytes.extend_from_slice(&0u16.to_be_bytes());
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let report_id = Uuid::from_slice(reader.take(16)?)?;
+        let time = u64::from_be_bytes(reader.take(8)?.try_into()?);
+        ensure!(
+            reader.opaque16()?.is_empty(),
+            "report extensions are not supported"
+        );
+        Ok(ReportMetadata { report_id, time })
+    }
+}
+
+/// A message encrypted with HPKE to the key with the config ID
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HpkeCiphertext {
+    pub config_id: u8,
+    pub encapsulated_key: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// A report's metadata and public share, along with the input share
+/// encrypted to the helper.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportShare {
+    pub metadata: ReportMetadata,
+    pub public_share: Vec<u8>,
+    pub encrypted_input_share: HpkeCiphertext,
+}
+
+impl ReportShare {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.metadata.encode(&mut bytes);
+        put_opaque32(&mut bytes, &self.public_share);
+        let ciphertext = &self.encrypted_input_share;
+        bytes.push(ciphertext.config_id);
+        put_opaque16(&mut bytes, &ciphertext.encapsulated_key);
+        put_opaque32(&mut bytes, &ciphertext.payload);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes };
+        let report_share = ReportShare {
+            metadata: ReportMetadata::decode(&mut reader)?,
+            public_share: reader.opaque32()?.to_vec(),
+            encrypted_input_share: HpkeCiphertext {
+                config_id: reader.take(1)?[0],
+                encapsulated_key: reader.opaque16()?.to_vec(),
+                payload: reader.opaque32()?.to_vec(),
+            },
+        };
+        ensure!(
+            reader.bytes.is_empty(),
+            "{} trailing bytes after report share",
+            reader.bytes.len()
+        );
+        Ok(report_share)
+    }
+
+    /// The associated data with which the input share is encrypted.
+    pub fn input_share_aad(&self, task_id: &TaskId) -> Vec<u8> {
+        let mut aad = task_id.0.to_vec();
+        self.metadata.encode(&mut aad);
+        put_opaque32(&mut aad, &self.public_share);
+        aad
+    }
+}
+
+/// The HPKE info string with which clients encrypt input shares to the
+/// helper.
+pub fn helper_input_share_info() -> Vec<u8> {
+    let mut info = INPUT_SHARE_INFO.to_vec();
+    info.extend_from_slice(&[ROLE_CLIENT, ROLE_HELPER]);
+    info
+}
+
+/// The parameters of a DAP task that the helper needs.
+#[derive(Clone, Debug)]
+pub struct DapTask {
+    pub task_id: TaskId,
+    /// The number of elements in the measurements being aggregated
+    pub dimension: usize,
+    /// Reports from this time on, in seconds since the Unix epoch, are
+    /// rejected
+    pub expiration: u64,
+    /// How far in the future, in seconds, report times may be
+    pub tolerable_clock_skew: u64,
+}
+
+/// A report share that has been prepared, awaiting the leader's verification
+/// message.
+struct PreparedReport<F: EncodedField> {
+    encrypted_share: Vec<u8>,
+    verification_message: VerificationMessage<F>,
+}
+
+/// DapHelper verifies and aggregates the report shares of one DAP task. For
+/// each report, the leader sends the report share with an evaluation point,
+/// to which the helper replies with its verification message in
+/// prepare_init. The leader then sends its own verification message, and in
+/// prepare_finish the helper adds the share to its aggregate share if the two
+/// messages show the report to be valid.
+pub struct DapHelper<F: EncodedField> {
+    task: DapTask,
+    /// HPKE keys for input shares, by config ID
+    hpke_keys: HashMap<u8, HpkePrivateKey>,
+    /// libprio-rs' Server only accepts input shares encrypted with its own
+    /// ECIES scheme, so once decrypted with HPKE, input shares are encrypted
+    /// again with this key, which the server holds the private half of.
+    share_key: PublicKey,
+    server: Server<F>,
+    prepared_reports: HashMap<Uuid, PreparedReport<F>>,
+    seen_reports: PacketDeduplicator,
+    report_count: u64,
+    logger: Logger,
+}
+
+impl<F: EncodedField> DapHelper<F> {
+    /// Creates a helper for the task that decrypts input shares with the
+    /// provided HPKE keys. `max_reports` is the number of report IDs
+    /// remembered to detect replayed reports.
+    pub fn new(
+        task: DapTask,
+        hpke_keys: HashMap<u8, HpkePrivateKey>,
+        max_reports: usize,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        F::FIELD
+            .check_bins(i32::try_from(task.dimension).context("dimension is too large")?)
+            .context("invalid task dimension")?;
+        let share_key = generate_share_key()?;
+        let logger = parent_logger.new(o!(
+            "dap_task_id" => hex::encode(task.task_id.0),
+        ));
+
+        Ok(DapHelper {
+            server: Server::new(task.dimension, false, share_key.clone()),
+            share_key: PublicKey::from(&share_key),
+            task,
+            hpke_keys,
+            prepared_reports: HashMap::new(),
+            seen_reports: PacketDeduplicator::new(max_reports, MAX_REPORT_IDS_IN_MEMORY),
+            report_count: 0,
+            logger,
+        })
+    }
+
+    /// Validates and decrypts a report share, then computes the helper's
+    /// verification message at the evaluation point chosen by the leader.
+    /// Report shares that are rejected yield an error containing a
+    /// PrepareError.
+    pub fn prepare_init(
+        &mut self,
+        report_share: &ReportShare,
+        eval_at: F,
+        now: DateTime<Utc>,
+    ) -> Result<VerificationMessage<F>> {
+        let metadata = &report_share.metadata;
+        let logger = self.logger.new(o!(
+            event::PACKET_UUID => metadata.report_id.to_string(),
+        ));
+
+        if !self.seen_reports.insert(&metadata.report_id)? {
+            return Err(PrepareError::ReportReplayed.into());
+        }
+        if metadata.time >= self.task.expiration {
+            return Err(PrepareError::TaskExpired.into());
+        }
+        let latest_time = (now.timestamp().max(0) as u64) + self.task.tolerable_clock_skew;
+        if metadata.time > latest_time {
+            return Err(PrepareError::ReportTooEarly.into());
+        }
+
+        let ciphertext = &report_share.encrypted_input_share;
+        let hpke_key = self
+            .hpke_keys
+            .get(&ciphertext.config_id)
+            .ok_or(PrepareError::HpkeUnknownConfigId)?;
+        let input_share = hpke_key
+            .open(
+                &ciphertext.encapsulated_key,
+                &helper_input_share_info(),
+                &report_share.input_share_aad(&self.task.task_id),
+                &ciphertext.payload,
+            )
+            .map_err(|e| {
+                debug!(logger, "failed to decrypt input share"; "error" => format!("{:?}", e));
+                PrepareError::HpkeDecryptError
+            })?;
+
+        let encrypted_share = encrypt_share(&input_share, &self.share_key)
+            .map_err(|e| anyhow!("failed to encrypt input share: {:?}", e))?;
+        let verification_message = self
+            .server
+            .generate_verification_message(eval_at, &encrypted_share)
+            .map_err(|e| {
+                debug!(logger, "failed to prepare input share"; "error" => format!("{:?}", e));
+                PrepareError::VdafPrepError
+            })?;
+
+        let reply = VerificationMessage {
+            f_r: verification_message.f_r,
+            g_r: verification_message.g_r,
+            h_r: verification_message.h_r,
+        };
+        self.prepared_reports.insert(
+            metadata.report_id,
+            PreparedReport {
+                encrypted_share,
+                verification_message,
+            },
+        );
+        Ok(reply)
+    }
+
+    /// Combines the leader's verification message for a prepared report with
+    /// the helper's, adding the report's input share to the aggregate share if
+    /// the report is valid. Returns whether it was.
+    pub fn prepare_finish(
+        &mut self,
+        report_id: &Uuid,
+        leader_message: &VerificationMessage<F>,
+    ) -> Result<bool> {
+        let prepared = self
+            .prepared_reports
+            .remove(report_id)
+            .ok_or_else(|| anyhow!("report {} has not been prepared", report_id))?;
+        let valid = self
+            .server
+            .aggregate(
+                &prepared.encrypted_share,
+                leader_message,
+                &prepared.verification_message,
+            )
+            .context("failed to aggregate input share")?;
+        if valid {
+            self.report_count += 1;
+        } else {
+            debug!(
+                self.logger, "rejecting invalid report";
+                event::PACKET_UUID => report_id.to_string(),
+            );
+        }
+        Ok(valid)
+    }
+
+    /// The sum of the input shares of the valid reports aggregated so far
+    pub fn aggregate_share(&self) -> &[F] {
+        self.server.total_shares()
+    }
+
+    /// The number of valid reports aggregated so far
+    pub fn report_count(&self) -> u64 {
+        self.report_count
+    }
+}
+
+fn put_opaque16(bytes: &mut Vec<u8>, value: &[u8]) {
+    // Encapsulated keys are public keys, which are far shorter than 64 KiB.
+    bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(value);
+}
+
+fn put_opaque32(bytes: &mut Vec<u8>, value: &[u8]) {
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value);
+}
+
+/// Reads fields from the front of an encoded message.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        ensure!(
+            self.bytes.len() >= length,
+            "message is truncated: needed {} bytes, have {}",
+            length,
+            self.bytes.len()
+        );
+        let (value, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    fn opaque16(&mut self) -> Result<&'a [u8]> {
+        let length = u16::from_be_bytes(self.take(2)?.try_into()?);
+        self.take(usize::from(length))
+    }
+
+    fn opaque32(&mut self) -> Result<&'a [u8]> {
+        let length = u32::from_be_bytes(self.take(4)?.try_into()?);
+        self.take(usize::try_from(length)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hpke::{seal, Ciphersuite},
+        logging::setup_test_logging,
+        manifest::generate_packet_encryption_key,
+    };
+    use chrono::TimeZone;
+    use prio::{
+        client::Client,
+        encrypt::{decrypt_share, PrivateKey},
+        field::{Field32, Field64, FieldElement},
+    };
+    use std::str::FromStr;
+
+    const TASK_ID: TaskId = TaskId([7; 32]);
+    const CONFIG_ID: u8 = 1;
+
+    struct Fixture<F: EncodedField> {
+        helper: DapHelper<F>,
+        leader: Server<F>,
+        client: Client<F>,
+        /// The ECIES key to which the client encrypts helper shares
+        helper_key: PrivateKey,
+        hpke_key: HpkePrivateKey,
+        now: DateTime<Utc>,
+    }
+
+    fn ecies_key() -> PrivateKey {
+        PrivateKey::from_base64(&generate_packet_encryption_key("test").unwrap().private_key)
+            .unwrap()
+    }
+
+    impl<F: EncodedField> Fixture<F> {
+        fn new(dimension: usize) -> Self {
+            let ciphersuite =
+                Ciphersuite::from_str("x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm").unwrap();
+            let hpke_key = HpkePrivateKey::generate(ciphersuite).unwrap();
+            let mut hpke_keys = HashMap::new();
+            hpke_keys.insert(CONFIG_ID, hpke_key.clone());
+            let now = Utc.ymd(2022, 3, 1).and_hms(12, 0, 0);
+            let task = DapTask {
+                task_id: TASK_ID,
+                dimension,
+                expiration: now.timestamp() as u64 + 86400,
+                tolerable_clock_skew: 60,
+            };
+
+            let leader_key = ecies_key();
+            let helper_key = ecies_key();
+            Fixture {
+                helper: DapHelper::new(task, hpke_keys, 100, &setup_test_logging()).unwrap(),
+                leader: Server::new(dimension, true, leader_key.clone()),
+                client: Client::new(
+                    dimension,
+                    PublicKey::from(&leader_key),
+                    PublicKey::from(&helper_key),
+                )
+                .unwrap(),
+                helper_key,
+                hpke_key,
+                now,
+            }
+        }
+
+        /// Encodes a measurement, returning the leader's encrypted share and
+        /// the helper's report share.
+        fn report(&mut self, measurement: &[F], time: u64) -> (Vec<u8>, ReportShare) {
+            let (leader_share, helper_share) = self.client.encode_simple(measurement).unwrap();
+            let input_share = decrypt_share(&helper_share, &self.helper_key).unwrap();
+
+            let mut report_share = ReportShare {
+                metadata: ReportMetadata {
+                    report_id: Uuid::new_v4(),
+                    time,
+                },
+                public_share: vec![],
+                encrypted_input_share: HpkeCiphertext {
+                    config_id: CONFIG_ID,
+                    encapsulated_key: vec![],
+                    payload: vec![],
+                },
+            };
+            let (encapsulated_key, payload) = seal(
+                self.hpke_key.ciphersuite(),
+                self.hpke_key.public_key(),
+                &helper_input_share_info(),
+                &report_share.input_share_aad(&TASK_ID),
+                &input_share,
+            )
+            .unwrap();
+            report_share.encrypted_input_share.encapsulated_key = encapsulated_key;
+            report_share.encrypted_input_share.payload = payload;
+            (leader_share, report_share)
+        }
+
+        /// Runs the aggregate flow for a report, returning whether the helper
+        /// found it valid.
+        fn aggregate(&mut self, leader_share: &[u8], report_share: &ReportShare) -> bool {
+            let eval_at = self.leader.choose_eval_at();
+            let leader_message = self
+                .leader
+                .generate_verification_message(eval_at, leader_share)
+                .unwrap();
+            let helper_message = self
+                .helper
+                .prepare_init(
+                    &ReportShare::decode(&report_share.encode()).unwrap(),
+                    eval_at,
+                    self.now,
+                )
+                .unwrap();
+            self.leader
+                .aggregate(leader_share, &leader_message, &helper_message)
+                .unwrap();
+            self.helper
+                .prepare_finish(&report_share.metadata.report_id, &leader_message)
+                .unwrap()
+        }
+
+        fn prepare_error(&mut self, report_share: &ReportShare) -> PrepareError {
+            *self
+                .helper
+                .prepare_init(report_share, F::one(), self.now)
+                .map(|_| ())
+                .unwrap_err()
+                .downcast_ref::<PrepareError>()
+                .unwrap()
+        }
+    }
+
+    fn aggregate<F: EncodedField>() {
+        let mut fixture = Fixture::<F>::new(4);
+        let time = fixture.now.timestamp() as u64;
+        let measurements = [[1, 0, 1, 1], [0, 0, 1, 0], [1, 1, 1, 0]];
+        for measurement in &measurements {
+            let measurement: Vec<F> = measurement.iter().map(|m| F::reduce(*m)).collect();
+            let (leader_share, report_share) = fixture.report(&measurement, time);
+            assert!(fixture.aggregate(&leader_share, &report_share));
+        }
+
+        // A measurement that isn't a vector of bits fails verification.
+        let (leader_share, report_share) = fixture.report(&[F::reduce(2); 4], time);
+        assert!(!fixture.aggregate(&leader_share, &report_share));
+
+        assert_eq!(fixture.helper.report_count(), 3);
+        let sum: Vec<F> = fixture
+            .leader
+            .total_shares()
+            .iter()
+            .zip(fixture.helper.aggregate_share())
+            .map(|(leader, helper)| *leader + *helper)
+            .collect();
+        assert_eq!(
+            sum,
+            vec![F::reduce(2), F::reduce(1), F::reduce(3), F::reduce(1)]
+        );
+    }
+
+    #[test]
+    fn aggregate_field32() {
+        aggregate::<Field32>();
+    }
+
+    #[test]
+    fn aggregate_field64() {
+        aggregate::<Field64>();
+    }
+
+    #[test]
+    fn reject_report_shares() {
+        let mut fixture = Fixture::<Field32>::new(2);
+        let now = fixture.now.timestamp() as u64;
+        let measurement = [Field32::one(), Field32::zero()];
+
+        let (leader_share, report_share) = fixture.report(&measurement, now);
+        assert!(fixture.aggregate(&leader_share, &report_share));
+        assert_eq!(
+            fixture.prepare_error(&report_share),
+            PrepareError::ReportReplayed
+        );
+
+        let (_, report_share) = fixture.report(&measurement, now + 61);
+        assert_eq!(
+            fixture.prepare_error(&report_share),
+            PrepareError::ReportTooEarly
+        );
+
+        let (_, report_share) = fixture.report(&measurement, now + 86400);
+        assert_eq!(
+            fixture.prepare_error(&report_share),
+            PrepareError::TaskExpired
+        );
+
+        let (_, mut report_share) = fixture.report(&measurement, now);
+        report_share.encrypted_input_share.config_id = CONFIG_ID + 1;
+        assert_eq!(
+            fixture.prepare_error(&report_share),
+            PrepareError::HpkeUnknownConfigId
+        );
+
+        // The metadata is authenticated as associated data.
+        let (_, mut report_share) = fixture.report(&measurement, now);
+        report_share.metadata.time -= 1;
+        assert_eq!(
+            fixture.prepare_error(&report_share),
+            PrepareError::HpkeDecryptError
+        );
+
+        let (_, report_share) = fixture.report(&measurement, now);
+        fixture
+            .helper
+            .prepare_finish(
+                &report_share.metadata.report_id,
+                &VerificationMessage {
+                    f_r: Field32::one(),
+                    g_r: Field32::one(),
+                    h_r: Field32::one(),
+                },
+            )
+            .unwrap_err();
+        assert_eq!(fixture.helper.report_count(), 1);
+    }
+
+    #[test]
+    fn decode_report_share() {
+        let report_share = ReportShare {
+            metadata: ReportMetadata {
+                report_id: Uuid::new_v4(),
+                time: 1646136000,
+            },
+            public_share: vec![1, 2, 3],
+            encrypted_input_share: HpkeCiphertext {
+                config_id: 9,
+                encapsulated_key: vec![4; 32],
+                payload: vec![5; 48],
+            },
+        };
+        let encoded = report_share.encode();
+        assert_eq!(encoded.len(), 16 + 8 + 2 + 4 + 3 + 1 + 2 + 32 + 4 + 48);
+        assert_eq!(ReportShare::decode(&encoded).unwrap(), report_share);
+
+        ReportShare::decode(&encoded[..encoded.len() - 1]).unwrap_err();
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        ReportShare::decode(&trailing).unwrap_err();
+        let mut extensions = encoded;
+        extensions[25] = 1;
+        ReportShare::decode(&extensions).unwrap_err();
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    fs::File,
+    hash::{BuildHasher, Hash, Hasher},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+};
+use uuid::Uuid;
+
+/// Number of bits in the bloom filter per packet in the window, and the number
+/// of hash functions, chosen for a false positive rate of about 1%.
+const BLOOM_BITS_PER_PACKET: usize = 10;
+const BLOOM_HASH_COUNT: u64 = 7;
+
+/// PacketDeduplicator remembers the UUIDs of packets it has seen so that
+/// duplicate packets can be dropped, both within a batch and across the
+/// batches in a window. Membership is first checked against a bloom filter,
+/// so that the common case of a packet that has not been seen is cheap. On a
+/// possible match, an exact set of UUIDs is consulted. The exact set is kept
+/// in memory until it reaches a configurable size, after which it is spilled
+/// to sorted temporary files, so that large windows do not exhaust memory.
+///
+/// Once `window` packets have been inserted, the deduplicator forgets every
+/// packet it has seen and starts over, so duplicates are only detected if
+/// both copies fall in the same window.
+#[derive(Debug)]
+pub struct PacketDeduplicator {
+    window: usize,
+    max_in_memory: usize,
+    hash_state: RandomState,
+    bloom_filter: Vec<u64>,
+    in_memory: HashSet<Uuid>,
+    spill_files: Vec<SpillFile>,
+    inserted: usize,
+    duplicates: u64,
+}
+
+impl PacketDeduplicator {
+    /// Creates a PacketDeduplicator that remembers up to `window` packets,
+    /// keeping at most `max_in_memory` of their UUIDs in memory at once.
+    pub fn new(window: usize, max_in_memory: usize) -> Self {
+        let bloom_words = (window.max(1) * BLOOM_BITS_PER_PACKET + 63) / 64;
+        PacketDeduplicator {
+            window: window.max(1),
+            max_in_memory: max_in_memory.max(1),
+            hash_state: RandomState::new(),
+            bloom_filter: vec![0; bloom_words],
+            in_memory: HashSet::new(),
+            spill_files: Vec::new(),
+            inserted: 0,
+            duplicates: 0,
+        }
+    }
+
+    /// Returns true if the packet with the provided UUID has been inserted
+    /// since the start of the current window.
+    pub fn contains(&mut self, uuid: &Uuid) -> Result<bool> {
+        if !self
+            .bloom_bits(uuid)
+            .all(|(word, bit)| self.bloom_filter[word] & bit != 0)
+        {
+            return Ok(false);
+        }
+        if self.in_memory.contains(uuid) {
+            return Ok(true);
+        }
+        for spill_file in self.spill_files.iter_mut() {
+            if spill_file.contains(uuid)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Inserts the UUID of a packet. Returns true if the packet had not
+    /// already been seen in the current window, or false if it is a
+    /// duplicate, in which case it is counted in duplicates().
+    pub fn insert(&mut self, uuid: &Uuid) -> Result<bool> {
+        if self.contains(uuid)? {
+            self.duplicates += 1;
+            return Ok(false);
+        }
+        if self.inserted >= self.window {
+            self.reset();
+        }
+        let bits: Vec<(usize, u64)> = self.bloom_bits(uuid).collect();
+        for (word, bit) in bits {
+            self.bloom_filter[word] |= bit;
+        }
+        self.in_memory.insert(*uuid);
+        self.inserted += 1;
+        if self.in_memory.len() >= self.max_in_memory {
+            self.spill()?;
+        }
+        Ok(true)
+    }
+
+    /// Returns the number of duplicate packets passed to insert().
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Forgets every packet inserted so far.
+    fn reset(&mut self) {
+        self.bloom_filter.iter_mut().for_each(|word| *word = 0);
+        self.in_memory.clear();
+        self.spill_files.clear();
+        self.inserted = 0;
+    }
+
+    /// Writes the in-memory UUIDs to a new spill file and clears them.
+    fn spill(&mut self) -> Result<()> {
+        let mut uuids: Vec<Uuid> = self.in_memory.drain().collect();
+        uuids.sort_unstable();
+        let file = tempfile::tempfile().context("failed to create dedup spill file")?;
+        let mut writer = BufWriter::new(file);
+        for uuid in &uuids {
+            writer
+                .write_all(uuid.as_bytes())
+                .context("failed to write dedup spill file")?;
+        }
+        let file = writer
+            .into_inner()
+            .context("failed to flush dedup spill file")?;
+        self.spill_files.push(SpillFile {
+            file,
+            len: uuids.len() as u64,
+        });
+        Ok(())
+    }
+
+    /// Returns the word index and bit mask in the bloom filter for each of the
+    /// hash functions, using double hashing to derive them from two hashes.
+    fn bloom_bits(&self, uuid: &Uuid) -> impl Iterator<Item = (usize, u64)> {
+        let h1 = self.hash(uuid, 0);
+        let h2 = self.hash(uuid, 1);
+        let bit_count = self.bloom_filter.len() as u64 * 64;
+        (0..BLOOM_HASH_COUNT).map(move |i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % bit_count;
+            ((bit / 64) as usize, 1u64 << (bit % 64))
+        })
+    }
+
+    fn hash(&self, uuid: &Uuid, seed: u8) -> u64 {
+        let mut hasher = self.hash_state.build_hasher();
+        seed.hash(&mut hasher);
+        uuid.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// A temporary file containing sorted UUIDs, each encoded as 16 bytes.
+#[derive(Debug)]
+struct SpillFile {
+    file: File,
+    len: u64,
+}
+
+impl SpillFile {
+    fn contains(&mut self, uuid: &Uuid) -> Result<bool> {
+        let (mut low, mut high) = (0, self.len);
+        let mut buf = [0u8; 16];
+        while low < high {
+            let middle = low + (high - low) / 2;
+            self.file
+                .seek(SeekFrom::Start(middle * 16))
+                .and_then(|_| self.file.read_exact(&mut buf))
+                .context("failed to read dedup spill file")?;
+            match Uuid::from_bytes(buf).cmp(uuid) {
+                std::cmp::Ordering::Equal => return Ok(true),
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_in_memory() {
+        let mut dedup = PacketDeduplicator::new(1000, 1000);
+        let uuids: Vec<Uuid> = (0..100).map(|_| Uuid::new_v4()).collect();
+        for uuid in &uuids {
+            assert!(!dedup.contains(uuid).unwrap());
+            assert!(dedup.insert(uuid).unwrap());
+        }
+        for uuid in &uuids {
+            assert!(dedup.contains(uuid).unwrap());
+            assert!(!dedup.insert(uuid).unwrap());
+        }
+        assert_eq!(dedup.duplicates(), 100);
+        assert!(dedup.spill_files.is_empty());
+    }
+
+    #[test]
+    fn dedup_spilled() {
+        let mut dedup = PacketDeduplicator::new(1000, 7);
+        let uuids: Vec<Uuid> = (0..100).map(|_| Uuid::new_v4()).collect();
+        for uuid in &uuids {
+            assert!(dedup.insert(uuid).unwrap());
+        }
+        assert_eq!(dedup.spill_files.len(), 14);
+        for uuid in &uuids {
+            assert!(!dedup.insert(uuid).unwrap());
+        }
+        for _ in 0..1000 {
+            assert!(!dedup.contains(&Uuid::new_v4()).unwrap());
+        }
+        assert_eq!(dedup.duplicates(), 100);
+    }
+
+    #[test]
+    fn dedup_window() {
+        let mut dedup = PacketDeduplicator::new(10, 3);
+        let uuids: Vec<Uuid> = (0..11).map(|_| Uuid::new_v4()).collect();
+        for uuid in &uuids[..10] {
+            assert!(dedup.insert(uuid).unwrap());
+        }
+        assert!(!dedup.insert(&uuids[0]).unwrap());
+
+        // Inserting the eleventh packet starts a new window
+        assert!(dedup.insert(&uuids[10]).unwrap());
+        assert!(!dedup.contains(&uuids[0]).unwrap());
+        assert!(dedup.contains(&uuids[10]).unwrap());
+        assert_eq!(dedup.duplicates(), 1);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::{
+    batch::Batch,
+    logging::event,
+    state::{AggregationWindow, WindowRegistry, WindowState},
+    transport::Transport,
+};
+use anyhow::{Context, Result};
+use chrono::{prelude::Utc, DateTime, NaiveDateTime};
+use serde::Serialize;
+use slog::{debug, info, o, Logger};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::Duration,
+};
+use uuid::Uuid;
+
+/// What BatchExpirer did, or would have done in a dry run, with the batches in
+/// one bucket.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ExpiryReport {
+    /// The path of the bucket
+    pub storage_path: String,
+    /// How long batches are kept in the bucket
+    pub retention_seconds: u64,
+    /// How many expired batches were deleted
+    pub deleted_batches: usize,
+    /// The keys of the objects deleted, in the order they were deleted
+    pub deleted_keys: Vec<String>,
+    /// The IDs of the aggregation windows whose expired batches were kept
+    /// because the windows are not done
+    pub incomplete_windows: BTreeSet<String>,
+}
+
+/// The reports of an expiry run over several buckets, as printed by the
+/// expire-batches subcommand.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ExpiryReports {
+    /// Whether deletions were only logged rather than performed
+    pub dry_run: bool,
+    pub buckets: Vec<ExpiryReport>,
+}
+
+/// BatchExpirer deletes ingestion and validation batches once they are older
+/// than a bucket's retention period, so that buckets don't grow without bound.
+/// A batch is only deleted once the aggregation window it belongs to is
+/// recorded as done in the window registry, so that no batch is deleted before
+/// it has been summed, however long the retention period.
+///
+/// Batches are assigned to windows of length aggregation_period aligned to the
+/// Unix epoch, as TaskScheduler does. Batches whose windows were aggregated
+/// with other bounds, or without a window registry, are never deleted.
+pub struct BatchExpirer<'a> {
+    window_registry: &'a mut WindowRegistry,
+    aggregation_period: Duration,
+    /// Whether each window looked up so far is done, keyed by window ID
+    done_windows: HashMap<String, bool>,
+    logger: Logger,
+}
+
+impl<'a> BatchExpirer<'a> {
+    pub fn new(
+        window_registry: &'a mut WindowRegistry,
+        aggregation_period: Duration,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            aggregation_period.as_secs() > 0,
+            "aggregation period must be at least one second"
+        );
+        Ok(BatchExpirer {
+            window_registry,
+            aggregation_period,
+            done_windows: HashMap::new(),
+            logger: parent_logger.new(o!()),
+        })
+    }
+
+    /// Deletes the files of the batches in the transport that are dated more
+    /// than `retention` before now and whose aggregation windows are done.
+    /// Objects whose keys are not those of batch files are left alone.
+    pub fn expire(
+        &mut self,
+        transport: &mut dyn Transport,
+        retention: Duration,
+        now: DateTime<Utc>,
+        trace_id: &str,
+    ) -> Result<ExpiryReport> {
+        let logger = self.logger.new(o!(
+            event::STORAGE_PATH => transport.path(),
+            event::TRACE_ID => trace_id.to_owned(),
+        ));
+        let cutoff = now.naive_utc()
+            - chrono::Duration::from_std(retention).context("retention is too long")?;
+
+        let mut expired_batches: BTreeMap<(String, NaiveDateTime, Uuid), Vec<String>> =
+            BTreeMap::new();
+        for key in transport.list("", trace_id)? {
+            if let Some((aggregation_id, date, batch_id)) = Batch::parse_key(&key) {
+                if date < cutoff {
+                    expired_batches
+                        .entry((aggregation_id, date, batch_id))
+                        .or_default()
+                        .push(key);
+                }
+            }
+        }
+
+        let mut report = ExpiryReport {
+            storage_path: transport.path(),
+            retention_seconds: retention.as_secs(),
+            ..Default::default()
+        };
+        for ((aggregation_id, date, batch_id), mut keys) in expired_batches {
+            let window =
+                AggregationWindow::containing(&aggregation_id, date, self.aggregation_period);
+            if !self.is_done(&window)? {
+                debug!(
+                    logger, "keeping expired batch of incomplete window";
+                    event::BATCH_ID => batch_id.to_string(),
+                    "window_id" => window.id(),
+                );
+                report.incomplete_windows.insert(window.id());
+                continue;
+            }
+
+            // Delete the signature first, since batches are discovered by their
+            // signatures, so that a partially deleted batch is not picked up.
+            keys.sort_by_key(|key| !key.ends_with(".sig"));
+            for key in keys {
+                transport
+                    .delete(&key, trace_id)
+                    .context(format!("failed to delete {}", key))?;
+                report.deleted_keys.push(key);
+            }
+            report.deleted_batches += 1;
+        }
+
+        info!(
+            logger, "expired batches";
+            "deleted_batches" => report.deleted_batches,
+            "incomplete_windows" => report.incomplete_windows.len(),
+        );
+        Ok(report)
+    }
+
+    fn is_done(&mut self, window: &AggregationWindow) -> Result<bool> {
+        let window_id = window.id();
+        if let Some(done) = self.done_windows.get(&window_id) {
+            return Ok(*done);
+        }
+        let done = matches!(
+            self.window_registry.state(window)?,
+            Some(WindowState::Done { .. })
+        );
+        self.done_windows.insert(window_id, done);
+        Ok(done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logging::setup_test_logging,
+        state::{LocalFileWindowStore, WindowStart},
+        transport::MemoryTransport,
+    };
+    use chrono::{NaiveDate, TimeZone};
+    use tempfile::TempDir;
+
+    #[test]
+    fn expire_batches() {
+        let logger = setup_test_logging();
+        let temp_dir = TempDir::new().unwrap();
+        let mut registry = WindowRegistry::new(
+            Box::new(LocalFileWindowStore::new(
+                temp_dir.path().join("windows.json"),
+            )),
+            Duration::from_secs(3600),
+            &logger,
+        );
+        let mut transport = MemoryTransport::new();
+        let now = Utc.ymd(2021, 5, 10).and_hms(12, 0, 0);
+        let date = |hour| NaiveDate::from_ymd(2021, 5, 10).and_hms(hour, 30, 0);
+
+        // Batches from 01:30 and 02:30 are expired, but only the window from
+        // 01:00 to 02:00 is done. The batch from 10:30 is too recent.
+        let mut batches = Vec::new();
+        for hour in &[1, 2, 10] {
+            let batch = Batch::new_ingestion("fake-aggregation", &Uuid::new_v4(), &date(*hour));
+            for key in &[
+                batch.header_key(),
+                batch.packet_file_key(),
+                batch.signature_key(),
+            ] {
+                transport.put(key, "").unwrap().complete_upload().unwrap();
+            }
+            batches.push(batch);
+        }
+        let marker = "task-markers/aggregate-fake-aggregation";
+        transport
+            .put(marker, "")
+            .unwrap()
+            .complete_upload()
+            .unwrap();
+
+        let done_window =
+            AggregationWindow::containing("fake-aggregation", date(1), Duration::from_secs(3600));
+        assert_eq!(
+            registry.try_start(&done_window, "worker", now).unwrap(),
+            WindowStart::Started
+        );
+        registry
+            .complete(&done_window, "worker", "digest", now)
+            .unwrap();
+
+        let mut expirer =
+            BatchExpirer::new(&mut registry, Duration::from_secs(3600), &logger).unwrap();
+        let report = expirer
+            .expire(&mut transport, Duration::from_secs(6 * 3600), now, "")
+            .unwrap();
+
+        assert_eq!(
+            report,
+            ExpiryReport {
+                storage_path: transport.path(),
+                retention_seconds: 6 * 3600,
+                deleted_batches: 1,
+                deleted_keys: vec![
+                    batches[0].signature_key().to_owned(),
+                    batches[0].header_key().to_owned(),
+                    batches[0].packet_file_key().to_owned(),
+                ],
+                incomplete_windows: vec!["fake-aggregation-202105100200-202105100300".to_owned()]
+                    .into_iter()
+                    .collect(),
+            }
+        );
+        for key in report.deleted_keys {
+            assert!(!transport.exists(&key, "").unwrap());
+        }
+        for batch in &batches[1..] {
+            assert!(transport.exists(batch.signature_key(), "").unwrap());
+        }
+        assert!(transport.exists(marker, "").unwrap());
+    }
+}
@@ -0,0 +1,522 @@
+use crate::{
+    batch::{Batch, BatchReader},
+    field::{signed, EncodedField, PrioField},
+    idl::{InvalidPacket, SumPart},
+    transport::Transport,
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use chrono::NaiveDateTime;
+use parquet::{
+    basic::{ConvertedType, Repetition, Type as PhysicalType},
+    column::writer::ColumnWriter,
+    data_type::ByteArray,
+    file::{
+        properties::WriterProperties,
+        writer::{FileWriter, InMemoryWriteableCursor, SerializedFileWriter},
+    },
+    schema::types::Type as SchemaType,
+};
+use prio::field::{Field32, Field64};
+use ring::signature::UnparsedPublicKey;
+use slog::Logger;
+use std::{collections::HashMap, collections::HashSet, io::Write, str::FromStr, sync::Arc};
+
+/// Formats in which aggregate results may be exported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(anyhow!("unknown export format {}", s)),
+        }
+    }
+}
+
+/// The result of an aggregation, obtained by combining the sum parts written
+/// by both data share processors. Exported with one row per bin, each of which
+/// repeats the aggregation's metadata so that exports of several aggregations
+/// can simply be concatenated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregateResult {
+    pub aggregation_name: String,
+    pub aggregation_start: NaiveDateTime,
+    pub aggregation_end: NaiveDateTime,
+    pub bin_names: Vec<String>,
+    /// Sum for each bin. Sums are signed because differential privacy noise
+    /// may make small sums negative.
+    pub sums: Vec<i64>,
+    pub total_individual_clients: i64,
+    pub batch_count: usize,
+}
+
+impl AggregateResult {
+    /// Reads the sum parts written by both data share processors for the
+    /// aggregation from the transport, checking that they are signed by one
+    /// of the public keys, and combines them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn read(
+        transport: &mut dyn Transport,
+        instance_name: &str,
+        aggregation_name: &str,
+        aggregation_start: &NaiveDateTime,
+        aggregation_end: &NaiveDateTime,
+        public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+        trace_id: &str,
+        logger: &Logger,
+    ) -> Result<Self> {
+        let mut sum_parts = Vec::new();
+        for is_first in &[true, false] {
+            let batch = Batch::new_sum(
+                instance_name,
+                aggregation_name,
+                aggregation_start,
+                aggregation_end,
+                *is_first,
+            );
+            let mut reader: BatchReader<'_, SumPart, InvalidPacket> =
+                BatchReader::new(batch, transport, false, trace_id, logger);
+            sum_parts.push(reader.header(public_keys).with_context(|| {
+                format!(
+                    "failed to read sum part written by {} server",
+                    if *is_first { "first" } else { "second" }
+                )
+            })?);
+        }
+        AggregateResult::from_sum_parts(&sum_parts[0], &sum_parts[1])
+    }
+
+    /// Combines the sum parts written by both data share processors for an
+    /// aggregation, which must agree on everything but their shares of the
+    /// sum. Bins are named by their indices.
+    pub fn from_sum_parts(first: &SumPart, second: &SumPart) -> Result<Self> {
+        ensure!(
+            first.name == second.name
+                && first.bins == second.bins
+                && first.prime == second.prime
+                && first.aggregation_start_time == second.aggregation_start_time
+                && first.aggregation_end_time == second.aggregation_end_time,
+            "sum parts are for different aggregations"
+        );
+        ensure!(
+            first.batch_uuids.iter().collect::<HashSet<_>>()
+                == second.batch_uuids.iter().collect::<HashSet<_>>(),
+            "sum parts include different batches"
+        );
+        ensure!(
+            first.total_individual_clients == second.total_individual_clients,
+            "sum parts include different numbers of clients ({} and {})",
+            first.total_individual_clients,
+            second.total_individual_clients
+        );
+        let sums = match PrioField::from_prime(first.prime)? {
+            PrioField::Field32 => combine_sums::<Field32>(first, second)?,
+            PrioField::Field64 => combine_sums::<Field64>(first, second)?,
+        };
+
+        let timestamp = |millis: i64| {
+            NaiveDateTime::from_timestamp_opt(millis.div_euclid(1000), 0)
+                .context("invalid aggregation time in sum part")
+        };
+
+        Ok(AggregateResult {
+            aggregation_name: first.name.clone(),
+            aggregation_start: timestamp(first.aggregation_start_time)?,
+            aggregation_end: timestamp(first.aggregation_end_time)?,
+            bin_names: (0..sums.len()).map(|bin| bin.to_string()).collect(),
+            sums,
+            total_individual_clients: first.total_individual_clients,
+            batch_count: first.batch_uuids.len(),
+        })
+    }
+
+    /// Names the bins, in order. There must be a name for every bin.
+    pub fn set_bin_names(&mut self, bin_names: Vec<String>) -> Result<()> {
+        ensure!(
+            bin_names.len() == self.sums.len(),
+            "got {} bin names for {} bins",
+            bin_names.len(),
+            self.sums.len()
+        );
+        self.bin_names = bin_names;
+        Ok(())
+    }
+
+    /// Writes the result to the writer in the provided format.
+    pub fn write<W: Write>(&self, format: ExportFormat, writer: &mut W) -> Result<()> {
+        let columns = self.columns();
+        match format {
+            ExportFormat::Csv => write_csv(&columns, self.sums.len(), writer),
+            ExportFormat::Parquet => write_parquet(&columns, self.sums.len(), writer),
+        }
+        .context("failed to write aggregate result")
+    }
+
+    fn columns(&self) -> Vec<Column> {
+        let rows = self.sums.len();
+        let repeat = |value: i64| vec![value; rows];
+        vec![
+            Column::String {
+                name: "aggregation_name",
+                values: vec![self.aggregation_name.clone(); rows],
+            },
+            Column::Timestamp {
+                name: "aggregation_start",
+                values: repeat(self.aggregation_start.timestamp_millis()),
+            },
+            Column::Timestamp {
+                name: "aggregation_end",
+                values: repeat(self.aggregation_end.timestamp_millis()),
+            },
+            Column::Int64 {
+                name: "bin",
+                values: (0..rows as i64).collect(),
+            },
+            Column::String {
+                name: "bin_name",
+                values: self.bin_names.clone(),
+            },
+            Column::Int64 {
+                name: "sum",
+                values: self.sums.clone(),
+            },
+            Column::Int64 {
+                name: "total_individual_clients",
+                values: repeat(self.total_individual_clients),
+            },
+            Column::Int64 {
+                name: "batch_count",
+                values: repeat(self.batch_count as i64),
+            },
+        ]
+    }
+}
+
+/// Adds the shares of the sum in two sum parts, interpreting the upper half of
+/// the field as negative.
+fn combine_sums<F: EncodedField>(first: &SumPart, second: &SumPart) -> Result<Vec<i64>> {
+    let first_sum = first.sum::<F>().context("invalid sum in first sum part")?;
+    let second_sum = second
+        .sum::<F>()
+        .context("invalid sum in second sum part")?;
+    ensure!(
+        first_sum.len() == second_sum.len() && first_sum.len() == first.bins as usize,
+        "sum parts have sums of the wrong length"
+    );
+    Ok(first_sum
+        .iter()
+        .zip(second_sum.iter())
+        .map(|(a, b)| signed(*a + *b))
+        .collect())
+}
+
+/// A column of exported values. Timestamps are milliseconds since the epoch.
+enum Column {
+    String {
+        name: &'static str,
+        values: Vec<String>,
+    },
+    Int64 {
+        name: &'static str,
+        values: Vec<i64>,
+    },
+    Timestamp {
+        name: &'static str,
+        values: Vec<i64>,
+    },
+}
+
+impl Column {
+    fn name(&self) -> &'static str {
+        match self {
+            Column::String { name, .. } => name,
+            Column::Int64 { name, .. } => name,
+            Column::Timestamp { name, .. } => name,
+        }
+    }
+}
+
+fn write_csv<W: Write>(columns: &[Column], rows: usize, writer: &mut W) -> Result<()> {
+    let header: Vec<String> = columns.iter().map(|c| csv_field(c.name())).collect();
+    writeln!(writer, "{}", header.join(","))?;
+    for row in 0..rows {
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|column| match column {
+                Column::String { values, .. } => csv_field(&values[row]),
+                Column::Int64 { values, .. } => values[row].to_string(),
+                Column::Timestamp { values, .. } => {
+                    NaiveDateTime::from_timestamp_opt(values[row].div_euclid(1000), 0)
+                        .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+                        .unwrap_or_default()
+                }
+            })
+            .collect();
+        writeln!(writer, "{}", fields.join(","))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Quotes a CSV field if it contains a delimiter, quote or line break, as
+/// described in RFC 4180.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Writes the columns as a Parquet file with a single row group. All columns
+/// are required, so no repetition or definition levels are written.
+fn write_parquet<W: Write>(columns: &[Column], rows: usize, writer: &mut W) -> Result<()> {
+    let mut fields = columns
+        .iter()
+        .map(|column| {
+            let (physical_type, converted_type) = match column {
+                Column::String { .. } => (PhysicalType::BYTE_ARRAY, ConvertedType::UTF8),
+                Column::Int64 { .. } => (PhysicalType::INT64, ConvertedType::NONE),
+                Column::Timestamp { .. } => (PhysicalType::INT64, ConvertedType::TIMESTAMP_MILLIS),
+            };
+            Ok(Arc::new(
+                SchemaType::primitive_type_builder(column.name(), physical_type)
+                    .with_repetition(Repetition::REQUIRED)
+                    .with_converted_type(converted_type)
+                    .build()?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let schema = SchemaType::group_type_builder("schema")
+        .with_fields(&mut fields)
+        .build()?;
+    let properties = WriterProperties::builder()
+        .set_created_by(format!("facilitator version {}", env!("CARGO_PKG_VERSION")))
+        .build();
+
+    // SerializedFileWriter needs to seek in its output, so the file is
+    // assembled in memory and then copied to the writer.
+    let cursor = InMemoryWriteableCursor::default();
+    let mut file_writer =
+        SerializedFileWriter::new(cursor.clone(), Arc::new(schema), Arc::new(properties))?;
+    let mut row_group_writer = file_writer.next_row_group()?;
+    for column in columns {
+        let mut column_writer = row_group_writer
+            .next_column()?
+            .context("Parquet schema has fewer columns than the aggregate result")?;
+        let written = match (column, &mut column_writer) {
+            (Column::String { values, .. }, ColumnWriter::ByteArrayColumnWriter(writer)) => {
+                let values: Vec<ByteArray> = values.iter().map(|v| v.as_str().into()).collect();
+                writer.write_batch(&values, None, None)?
+            }
+            (Column::Int64 { values, .. }, ColumnWriter::Int64ColumnWriter(writer))
+            | (Column::Timestamp { values, .. }, ColumnWriter::Int64ColumnWriter(writer)) => {
+                writer.write_batch(values, None, None)?
+            }
+            _ => return Err(anyhow!("wrong Parquet column writer for {}", column.name())),
+        };
+        ensure!(
+            written == rows,
+            "wrote {} of {} values to Parquet column {}",
+            written,
+            rows,
+            column.name()
+        );
+        row_group_writer.close_column(column_writer)?;
+    }
+    file_writer.close_row_group(row_group_writer)?;
+    file_writer.close()?;
+
+    writer.write_all(&cursor.data())?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::{
+        file::{
+            reader::FileReader,
+            serialized_reader::{SerializedFileReader, SliceableCursor},
+        },
+        record::{Row, RowAccessor},
+    };
+    use prio::field::FieldElement;
+    use uuid::Uuid;
+
+    fn sum_parts() -> (SumPart, SumPart) {
+        let batch_uuids = vec![Uuid::new_v4(), Uuid::new_v4()];
+        let sum_part = |sum, batch_uuids| SumPart {
+            batch_uuids,
+            name: "kittens, seen".to_owned(),
+            bins: 3,
+            epsilon: 1.0,
+            prime: Field32::modulus() as i64,
+            number_of_servers: 2,
+            hamming_weight: None,
+            sum,
+            aggregation_start_time: 1_625_097_600_000,
+            aggregation_end_time: 1_625_184_000_000,
+            packet_file_digest: vec![],
+            total_individual_clients: 10,
+            excluded_batch_uuids: vec![],
+            noise_mechanism: None,
+            noise_epsilon: None,
+            noise_delta: None,
+            noise_scale: None,
+            facilitator_version: None,
+            instance_name: None,
+            is_first: None,
+            min_complete_batches_percent: None,
+            rejected_packet_count: None,
+            batch_uuids_digest: None,
+            excluded_batch_uuids_digest: None,
+        };
+        let modulus = Field32::modulus() as i64;
+        (
+            sum_part(vec![5, modulus - 1, 100], batch_uuids.clone()),
+            sum_part(
+                vec![modulus - 2, modulus - 2, 7],
+                batch_uuids.into_iter().rev().collect(),
+            ),
+        )
+    }
+
+    #[test]
+    fn combine_sum_parts() {
+        let (first, second) = sum_parts();
+        let mut result = AggregateResult::from_sum_parts(&first, &second).unwrap();
+        assert_eq!(result.sums, vec![3, -3, 107]);
+        assert_eq!(result.bin_names, vec!["0", "1", "2"]);
+        assert_eq!(result.batch_count, 2);
+        assert_eq!(result.total_individual_clients, 10);
+        assert_eq!(
+            result.aggregation_start,
+            NaiveDateTime::parse_from_str("2021/07/01/00/00", crate::DATE_FORMAT).unwrap()
+        );
+
+        result.set_bin_names(vec!["a".to_owned()]).unwrap_err();
+        result
+            .set_bin_names(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()])
+            .unwrap();
+
+        let (first, mut second) = sum_parts();
+        second.batch_uuids.pop();
+        AggregateResult::from_sum_parts(&first, &second).unwrap_err();
+        let (first, mut second) = sum_parts();
+        second.total_individual_clients = 9;
+        AggregateResult::from_sum_parts(&first, &second).unwrap_err();
+        let (first, mut second) = sum_parts();
+        second.sum.pop();
+        AggregateResult::from_sum_parts(&first, &second).unwrap_err();
+        let (first, mut second) = sum_parts();
+        second.prime = PrioField::Field64.prime();
+        AggregateResult::from_sum_parts(&first, &second).unwrap_err();
+    }
+
+    #[test]
+    fn combine_field64_sum_parts() {
+        let (mut first, mut second) = sum_parts();
+        let modulus = Field64::modulus();
+        first.prime = PrioField::Field64.prime();
+        first.sum = vec![5, (modulus - 1) as i64, 100];
+        second.prime = PrioField::Field64.prime();
+        second.sum = vec![(modulus - 2) as i64, (modulus - 2) as i64, 7];
+        let result = AggregateResult::from_sum_parts(&first, &second).unwrap();
+        assert_eq!(result.sums, vec![3, -3, 107]);
+
+        // -1 is encoded as 2^64 - 1, which is not an element of the field
+        let (mut first, mut second) = sum_parts();
+        first.prime = PrioField::Field64.prime();
+        second.prime = PrioField::Field64.prime();
+        second.sum[0] = -1;
+        AggregateResult::from_sum_parts(&first, &second).unwrap_err();
+    }
+
+    #[test]
+    fn export_csv() {
+        let (first, second) = sum_parts();
+        let mut result = AggregateResult::from_sum_parts(&first, &second).unwrap();
+        result
+            .set_bin_names(vec![
+                "a".to_owned(),
+                "b \"quoted\"".to_owned(),
+                "c".to_owned(),
+            ])
+            .unwrap();
+        let mut output = Vec::new();
+        result.write(ExportFormat::Csv, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "aggregation_name,aggregation_start,aggregation_end,bin,bin_name,sum,\
+            total_individual_clients,batch_count\n\
+            \"kittens, seen\",2021-07-01T00:00:00Z,2021-07-02T00:00:00Z,0,a,3,10,2\n\
+            \"kittens, seen\",2021-07-01T00:00:00Z,2021-07-02T00:00:00Z,1,\"b \"\"quoted\"\"\",-3,10,2\n\
+            \"kittens, seen\",2021-07-01T00:00:00Z,2021-07-02T00:00:00Z,2,c,107,10,2\n"
+        );
+    }
+
+    #[test]
+    fn export_parquet() {
+        let (first, second) = sum_parts();
+        let mut result = AggregateResult::from_sum_parts(&first, &second).unwrap();
+        result
+            .set_bin_names(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()])
+            .unwrap();
+        let mut output = Vec::new();
+        result.write(ExportFormat::Parquet, &mut output).unwrap();
+
+        let reader = SerializedFileReader::new(SliceableCursor::new(output)).unwrap();
+        let metadata = reader.metadata().file_metadata();
+        assert_eq!(metadata.num_rows(), 3);
+        let column_names: Vec<&str> = metadata
+            .schema_descr()
+            .columns()
+            .iter()
+            .map(|column| column.name())
+            .collect();
+        assert_eq!(
+            column_names,
+            vec![
+                "aggregation_name",
+                "aggregation_start",
+                "aggregation_end",
+                "bin",
+                "bin_name",
+                "sum",
+                "total_individual_clients",
+                "batch_count"
+            ]
+        );
+
+        let rows: Vec<Row> = reader.get_row_iter(None).unwrap().collect();
+        assert_eq!(rows.len(), 3);
+        for (bin, row) in rows.iter().enumerate() {
+            assert_eq!(row.get_string(0).unwrap(), "kittens, seen");
+            assert_eq!(row.get_timestamp_millis(1).unwrap(), 1_625_097_600_000);
+            assert_eq!(row.get_timestamp_millis(2).unwrap(), 1_625_184_000_000);
+            assert_eq!(row.get_long(3).unwrap(), bin as i64);
+            assert_eq!(row.get_string(4).unwrap(), &result.bin_names[bin]);
+            assert_eq!(row.get_long(5).unwrap(), result.sums[bin]);
+            assert_eq!(row.get_long(6).unwrap(), 10);
+            assert_eq!(row.get_long(7).unwrap(), 2);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::{anyhow, ensure, Result};
+use prio::field::{Field32, Field64, FieldElement};
+use std::{convert::TryFrom, str::FromStr};
+
+/// The finite fields in which Prio batches may be encoded. Batch headers
+/// identify the field by its prime modulus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrioField {
+    /// GF(4293918721), which allows vectors of up to 2^19 - 1 bins
+    Field32,
+    /// GF(15564440312192434177), which allows vectors of up to 2^58 - 1 bins
+    Field64,
+}
+
+impl PrioField {
+    /// Returns the field whose modulus is the prime in a batch header.
+    pub fn from_prime(prime: i64) -> Result<Self> {
+        [PrioField::Field32, PrioField::Field64]
+            .iter()
+            .copied()
+            .find(|field| field.prime() == prime)
+            .ok_or_else(|| anyhow!("unsupported prime {} in batch header", prime))
+    }
+
+    pub fn modulus(&self) -> u64 {
+        match self {
+            PrioField::Field32 => u64::from(Field32::modulus()),
+            PrioField::Field64 => Field64::modulus(),
+        }
+    }
+
+    /// Returns the prime as it is written in batch headers. Avro has no
+    /// unsigned integers, so moduli of 2^63 or more are written as the signed
+    /// integer with the same bits.
+    pub fn prime(&self) -> i64 {
+        self.modulus() as i64
+    }
+
+    /// Checks that vectors with the provided number of bins can be encoded in
+    /// the field. The proof for a vector of n bins evaluates polynomials at
+    /// 2 * (n + 1).next_power_of_two() roots of unity, so the field must have
+    /// at least that many.
+    pub fn check_bins(&self, bins: i32) -> Result<()> {
+        let bins = usize::try_from(bins)
+            .ok()
+            .filter(|bins| *bins > 0)
+            .ok_or_else(|| anyhow!("invalid bin count {}", bins))?;
+        let generator_order = match self {
+            PrioField::Field32 => u64::from(Field32::generator_order()),
+            PrioField::Field64 => Field64::generator_order(),
+        };
+        ensure!(
+            2 * (bins as u64 + 1).next_power_of_two() <= generator_order,
+            "{} bins are too many for {}",
+            bins,
+            self.as_str()
+        );
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PrioField::Field32 => "field32",
+            PrioField::Field64 => "field64",
+        }
+    }
+}
+
+impl FromStr for PrioField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "field32" => Ok(PrioField::Field32),
+            "field64" => Ok(PrioField::Field64),
+            _ => Err(anyhow!("unknown field {}", s)),
+        }
+    }
+}
+
+/// A field element that can be stored in the Avro long fields of validation
+/// packets and sum parts. Elements are encoded as their canonical
+/// representatives, and those of 2^63 or more as the signed integer with the
+/// same bits.
+pub trait EncodedField: FieldElement + Send + Sync + 'static {
+    const FIELD: PrioField;
+
+    fn encode(self) -> i64;
+
+    /// Decodes an element, failing if the value is not less than the field's
+    /// modulus.
+    fn decode(value: i64) -> Result<Self>;
+
+    /// Returns the element congruent to the provided integer.
+    fn reduce(value: u64) -> Self;
+}
+
+impl EncodedField for Field32 {
+    const FIELD: PrioField = PrioField::Field32;
+
+    fn encode(self) -> i64 {
+        i64::from(u32::from(self))
+    }
+
+    fn decode(value: i64) -> Result<Self> {
+        match u32::try_from(value) {
+            Ok(value) if value < Field32::modulus() => Ok(Field32::from(value)),
+            _ => Err(anyhow!(
+                "{} is not an element of {}",
+                value,
+                Self::FIELD.as_str()
+            )),
+        }
+    }
+
+    fn reduce(value: u64) -> Self {
+        Field32::from((value % Self::FIELD.modulus()) as u32)
+    }
+}
+
+impl EncodedField for Field64 {
+    const FIELD: PrioField = PrioField::Field64;
+
+    fn encode(self) -> i64 {
+        u64::from(self) as i64
+    }
+
+    fn decode(value: i64) -> Result<Self> {
+        let value = value as u64;
+        ensure!(
+            value < Field64::modulus(),
+            "{} is not an element of {}",
+            value,
+            Self::FIELD.as_str()
+        );
+        Ok(Field64::from(value))
+    }
+
+    fn reduce(value: u64) -> Self {
+        Field64::from(value % Self::FIELD.modulus())
+    }
+}
+
+/// Interprets field elements in the upper half of the field as negative.
+pub fn signed<F: EncodedField>(value: F) -> i64 {
+    let value = value.encode() as u64;
+    let modulus = F::FIELD.modulus();
+    if value > modulus / 2 {
+        (i128::from(value) - i128::from(modulus)) as i64
+    } else {
+        value as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_from_primes() {
+        for field in &[PrioField::Field32, PrioField::Field64] {
+            assert_eq!(PrioField::from_prime(field.prime()).unwrap(), *field);
+            assert_eq!(PrioField::from_str(field.as_str()).unwrap(), *field);
+        }
+        assert_eq!(PrioField::Field32.prime(), 4293918721);
+        assert!(PrioField::Field64.prime() < 0);
+        PrioField::from_prime(17).unwrap_err();
+    }
+
+    #[test]
+    fn check_bins() {
+        for field in &[PrioField::Field32, PrioField::Field64] {
+            field.check_bins(1).unwrap();
+            field.check_bins((1 << 19) - 1).unwrap();
+            field.check_bins(0).unwrap_err();
+            field.check_bins(-1).unwrap_err();
+        }
+        PrioField::Field32.check_bins(1 << 19).unwrap_err();
+        PrioField::Field64.check_bins(i32::MAX).unwrap();
+    }
+
+    fn roundtrip<F: EncodedField>() {
+        let max = F::zero() - F::one();
+        for element in &[F::zero(), F::one(), max, F::rand()] {
+            assert_eq!(F::decode(element.encode()).unwrap(), *element);
+        }
+        assert_eq!(signed(max), -1);
+        assert_eq!(signed(F::one()), 1);
+        assert_eq!(F::reduce(F::FIELD.modulus() + 1), F::one());
+        F::decode(F::FIELD.prime()).unwrap_err();
+    }
+
+    #[test]
+    fn encode_field32() {
+        roundtrip::<Field32>();
+        Field32::decode(-1).unwrap_err();
+    }
+
+    #[test]
+    fn encode_field64() {
+        roundtrip::<Field64>();
+        assert!((Field64::zero() - Field64::one()).encode() < 0);
+        Field64::decode(-1).unwrap_err();
+    }
+}
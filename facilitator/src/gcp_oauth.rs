@@ -1,30 +1,44 @@
 use anyhow::{anyhow, Context, Result};
 use chrono::{prelude::Utc, DateTime, Duration};
 use dyn_clone::DynClone;
-use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use jsonwebtoken::{dangerous_insecure_decode, encode, Algorithm, EncodingKey, Header};
+use once_cell::sync::{Lazy, OnceCell};
+use rand::Rng;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
 use rusoto_core::{credential::ProvideAwsCredentials, Region};
 use serde::{Deserialize, Serialize};
-use slog::{debug, o, Logger};
+use slog::{debug, o, warn, Logger};
 use std::{
+    collections::HashMap,
     fmt::{self, Debug},
-    io::Read,
+    fs,
+    io::{self, Read, Write},
+    path::PathBuf,
     str,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
-use ureq::Response;
 use url::Url;
 
 use crate::{
     aws_credentials::{self, basic_runtime, get_caller_identity_token},
-    config::WorkloadIdentityPoolParameters,
+    config::{WorkloadIdentityCredentialSource, WorkloadIdentityPoolParameters},
     http::{
-        Method, OauthTokenProvider, RequestParameters, RetryingAgent, StaticOauthTokenProvider,
+        Method, OauthTokenProvider, RequestClass, RequestParameters, Response, RetryingAgent,
+        StaticOauthTokenProvider,
     },
+    metrics::HTTP_METRICS,
 };
 
 const DEFAULT_METADATA_BASE_URL: &str = "http://metadata.google.internal:80";
 const DEFAULT_TOKEN_PATH: &str = "/computeMetadata/v1/instance/service-accounts/default/token";
+const DEFAULT_IDENTITY_TOKEN_PATH: &str =
+    "/computeMetadata/v1/instance/service-accounts/default/identity";
 const DEFAULT_IAM_BASE_URL: &str = "https://iamcredentials.googleapis.com";
+const DEFAULT_STS_TOKEN_URL: &str = "https://sts.googleapis.com/v1/token";
 
 fn default_oauth_token_url(base: &str) -> Url {
     let mut request_url = Url::parse(base).expect("unable to parse metadata.google.internal url");
@@ -54,6 +68,73 @@ fn access_token_path_for_service_account(service_account_to_impersonate: &str) -
     )
 }
 
+// API reference:
+// https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateIdToken
+fn id_token_url_for_service_account(
+    base: &str,
+    service_account_to_impersonate: &str,
+) -> Result<Url> {
+    let request_url = format!(
+        "{}/v1/projects/-/serviceAccounts/{}:generateIdToken",
+        base, service_account_to_impersonate
+    );
+
+    Url::parse(&request_url).context(format!("failed to parse: {}", request_url))
+}
+
+/// The subset of the claims in an OIDC identity token that we use.
+#[derive(Deserialize)]
+struct IdentityTokenClaims {
+    exp: i64,
+}
+
+/// Returns the expiration time of the provided OIDC identity token. The
+/// token's signature is not checked, since the token was obtained directly
+/// from the metadata service or GCP IAM and we only consult it to know when to
+/// refresh.
+fn identity_token_expiration(token: &str) -> Result<DateTime<Utc>> {
+    let claims = dangerous_insecure_decode::<IdentityTokenClaims>(token)
+        .context("failed to decode identity token")?
+        .claims;
+    Ok(DateTime::from_utc(
+        chrono::NaiveDateTime::from_timestamp(claims.exp, 0),
+        Utc,
+    ))
+}
+
+fn identity_token_from_metadata_service(
+    metadata_service_base_url: &str,
+    agent: &RetryingAgent,
+    audience: &str,
+    logger: &Logger,
+) -> Result<String> {
+    debug!(
+        logger,
+        "obtaining OIDC identity token from GKE metadata service"
+    );
+    let mut url = Url::parse(metadata_service_base_url)
+        .context("failed to parse metadata service base URL")?;
+    url.set_path(DEFAULT_IDENTITY_TOKEN_PATH);
+    url.query_pairs_mut()
+        .append_pair("audience", audience)
+        .finish();
+
+    let mut request = agent.prepare_request(RequestParameters {
+        url,
+        method: Method::Get,
+        ..Default::default()
+    })?;
+
+    request = request.set("Metadata-Flavor", "Google");
+
+    let response = agent
+        .call(logger, &request)
+        .context("failed to query GKE metadata service for identity token")?;
+    agent
+        .read_string(response)
+        .context("failed to read identity token from GKE metadata service")
+}
+
 /// Represents the claims encoded into JWTs when using a service account key
 /// file to authenticate as the default GCP service account.
 #[derive(Debug, Serialize, Deserialize)]
@@ -65,18 +146,419 @@ struct Claims {
     exp: i64,
 }
 
-/// A wrapper around an Oauth token and its expiration date.
+/// How long before they expire Oauth tokens are refreshed, unless configured
+/// otherwise with configure_oauth_token_refresh.
+pub const DEFAULT_OAUTH_TOKEN_REFRESH_SKEW: std::time::Duration =
+    std::time::Duration::from_secs(5 * 60);
+
+/// Upper bound on the random amount by which Oauth token refreshes are moved
+/// earlier, unless configured otherwise with configure_oauth_token_refresh.
+pub const DEFAULT_OAUTH_TOKEN_REFRESH_JITTER: std::time::Duration =
+    std::time::Duration::from_secs(60);
+
+/// When Oauth tokens obtained by this process are refreshed.
+static OAUTH_TOKEN_REFRESH_WINDOW: OnceCell<RefreshWindow> = OnceCell::new();
+
+/// Configures Oauth tokens to be refreshed `skew` before they expire, moved
+/// earlier by a random amount of up to `jitter` so that many workers started
+/// at once do not all refresh their tokens at the same time. Tokens are
+/// refreshed before they expire so that they do not expire while in use, e.g.
+/// midway through a multipart upload. Must be called before any token is
+/// obtained, and at most once.
+pub fn configure_oauth_token_refresh(
+    skew: std::time::Duration,
+    jitter: std::time::Duration,
+) -> Result<()> {
+    OAUTH_TOKEN_REFRESH_WINDOW
+        .set(RefreshWindow::new(skew, jitter)?)
+        .map_err(|_| anyhow!("Oauth token refresh is already configured"))
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RefreshWindow {
+    skew: Duration,
+    jitter: Duration,
+}
+
+impl RefreshWindow {
+    fn new(skew: std::time::Duration, jitter: std::time::Duration) -> Result<Self> {
+        Ok(RefreshWindow {
+            skew: Duration::from_std(skew).context("invalid Oauth token refresh skew")?,
+            jitter: Duration::from_std(jitter).context("invalid Oauth token refresh jitter")?,
+        })
+    }
+
+    fn get() -> Self {
+        *OAUTH_TOKEN_REFRESH_WINDOW.get_or_init(|| {
+            RefreshWindow::new(
+                DEFAULT_OAUTH_TOKEN_REFRESH_SKEW,
+                DEFAULT_OAUTH_TOKEN_REFRESH_JITTER,
+            )
+            .unwrap()
+        })
+    }
+
+    /// Returns when a token expiring at `expiration` should be refreshed.
+    fn refresh_time(&self, expiration: DateTime<Utc>) -> DateTime<Utc> {
+        let jitter = match self.jitter.num_milliseconds() {
+            0 => Duration::zero(),
+            jitter => Duration::milliseconds(rand::thread_rng().gen_range(0..=jitter)),
+        };
+        expiration - self.skew - jitter
+    }
+}
+
+/// Implementations of OauthTokenCache store tokens obtained by this process so
+/// that later processes may reuse them until they need to be refreshed rather
+/// than obtaining their own, e.g. so that consecutive short-lived jobs do not
+/// each request a token from the GKE metadata service, which is rate limited.
+/// Entries are opaque serialized tokens, and keys identify the credentials,
+/// account, scope or audience the token was obtained for.
+pub trait OauthTokenCache: Debug + Send + Sync {
+    /// Returns the entry stored under `key`, if there is one.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Stores `entry` under `key`, replacing any existing entry.
+    fn put(&self, key: &str, entry: &[u8]) -> Result<()>;
+}
+
+/// The cache in which tokens obtained by this process are stored, if any.
+static OAUTH_TOKEN_CACHE: OnceCell<Box<dyn OauthTokenCache>> = OnceCell::new();
+
+/// Configures tokens obtained by this process to be stored in and loaded from
+/// `cache`. Must be called before any token is obtained, and at most once.
+pub fn configure_oauth_token_cache(cache: Box<dyn OauthTokenCache>) -> Result<()> {
+    OAUTH_TOKEN_CACHE
+        .set(cache)
+        .map_err(|_| anyhow!("Oauth token cache is already configured"))
+}
+
+/// An OauthTokenCache that stores entries in files in a directory, encrypted
+/// with AES-256-GCM so that tokens are not exposed to anyone who can read the
+/// directory but does not hold the key. Entries are bound to their keys, so
+/// an entry cannot be moved to another key's file.
+#[derive(Debug)]
+pub struct EncryptedFileTokenCache {
+    directory: PathBuf,
+    key: LessSafeKey,
+}
+
+impl EncryptedFileTokenCache {
+    /// Creates a cache in `directory`, which is created if it does not exist,
+    /// using the provided 256 bit AES key.
+    pub fn new(directory: PathBuf, key: &[u8]) -> Result<Self> {
+        fs::create_dir_all(&directory).context(format!(
+            "failed to create Oauth token cache directory {}",
+            directory.display()
+        ))?;
+        let key = UnboundKey::new(&AES_256_GCM, key)
+            .map_err(|_| anyhow!("Oauth token cache key must be 32 bytes"))?;
+
+        Ok(EncryptedFileTokenCache {
+            directory,
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        // Hash the key so that it is a valid file name, and so that file names
+        // do not reveal which accounts tokens were obtained for
+        self.directory
+            .join(hex::encode(digest(&SHA256, key.as_bytes()).as_ref()))
+    }
+}
+
+impl OauthTokenCache for EncryptedFileTokenCache {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut sealed = match fs::read(self.path(key)) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("failed to read Oauth token cache file"),
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow!("Oauth token cache file is truncated"));
+        }
+
+        let mut ciphertext = sealed.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&sealed)
+            .map_err(|_| anyhow!("invalid nonce in Oauth token cache file"))?;
+        let entry = self
+            .key
+            .open_in_place(nonce, Aad::from(key.as_bytes()), &mut ciphertext)
+            .map_err(|_| anyhow!("failed to decrypt Oauth token cache file"))?;
+
+        Ok(Some(entry.to_vec()))
+    }
+
+    fn put(&self, key: &str, entry: &[u8]) -> Result<()> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("failed to generate nonce"))?;
+        let mut sealed = entry.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow!("failed to encrypt Oauth token"))?;
+
+        // Write to a temporary file that is moved into place so that readers
+        // never see a partially written entry. Temporary files are created
+        // readable only by their owner.
+        let mut temp_file = tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempfile_in(&self.directory)
+            .context("failed to create temporary Oauth token cache file")?;
+        temp_file
+            .write_all(&nonce)
+            .and_then(|_| temp_file.write_all(&sealed))
+            .context("failed to write Oauth token cache file")?;
+        temp_file
+            .persist(self.path(key))
+            .context("failed to move Oauth token cache file into place")?;
+
+        Ok(())
+    }
+}
+
+/// The representation of an OauthToken in an OauthTokenCache. The refresh time
+/// is not stored, so that each process that loads the token picks its own.
+#[derive(Serialize, Deserialize)]
+struct StoredOauthToken {
+    token: String,
+    expiration: DateTime<Utc>,
+}
+
+/// A wrapper around an Oauth token, its expiration date and the time at which
+/// it should be refreshed.
 #[derive(Clone)]
 struct OauthToken {
     token: String,
     expiration: DateTime<Utc>,
+    refresh_time: DateTime<Utc>,
 }
 
 impl OauthToken {
+    fn new(token: String, expiration: DateTime<Utc>, refresh_window: RefreshWindow) -> Self {
+        OauthToken {
+            token,
+            expiration,
+            refresh_time: refresh_window.refresh_time(expiration),
+        }
+    }
+
     /// Returns true if the token is expired.
     fn expired(&self) -> bool {
         Utc::now() >= self.expiration
     }
+
+    /// Returns true if the token should be refreshed, which happens some time
+    /// before it expires.
+    fn needs_refresh(&self) -> bool {
+        Utc::now() >= self.refresh_time
+    }
+}
+
+/// A token that may be shared by several token providers, which is None until
+/// the first successful request for it, though the contained token may be
+/// expired. The token is kept in an Arc+RwLock, so a CachedToken may be
+/// .clone()d liberally and shared across threads.
+#[derive(Clone, Default)]
+struct CachedToken {
+    token: Arc<RwLock<Option<OauthToken>>>,
+    /// Where the token is stored for use by other processes, if anywhere.
+    persistence: Option<TokenPersistence>,
+}
+
+/// The key under which a CachedToken is stored in an OauthTokenCache.
+#[derive(Clone)]
+struct TokenPersistence {
+    key: String,
+    cache: &'static dyn OauthTokenCache,
+}
+
+impl CachedToken {
+    /// Creates an empty CachedToken, which is stored in OAUTH_TOKEN_CACHE under
+    /// `key` if one is configured.
+    fn new(key: &TokenKey) -> Self {
+        CachedToken {
+            token: Arc::default(),
+            persistence: OAUTH_TOKEN_CACHE.get().map(|cache| TokenPersistence {
+                key: format!("{:?}", key),
+                cache: cache.as_ref(),
+            }),
+        }
+    }
+
+    /// Returns the cached token, if it does not yet need to be refreshed.
+    /// Otherwise obtains a new token using `refresh`, caches it and returns it.
+    /// If refreshing fails but the cached token has not yet expired, the cached
+    /// token is returned and the refresh is attempted again the next time the
+    /// token is needed.
+    /// The returned value is an owned reference because the cached token could
+    /// change while the caller is still holding the returned token.
+    fn get_or_refresh<F>(&self, description: &str, logger: &Logger, refresh: F) -> Result<String>
+    where
+        F: FnOnce() -> Result<OauthToken>,
+    {
+        if let Some(token) = &*self.token.read().unwrap() {
+            if !token.needs_refresh() {
+                debug!(logger, "cached {} is still valid", description);
+                return Ok(token.token.clone());
+            }
+        }
+
+        let mut cached = self.token.write().unwrap();
+
+        // The first time the token is needed, check whether another process
+        // stored one that is still valid
+        if cached.is_none() {
+            *cached = self.load(description, logger);
+        }
+
+        // Check if the token was updated between when we dropped the read lock
+        // and when we acquired the write lock
+        if let Some(token) = &*cached {
+            if !token.needs_refresh() {
+                debug!(logger, "cached {} is still valid", description);
+                return Ok(token.token.clone());
+            }
+        }
+
+        match refresh() {
+            Ok(token) => {
+                let access_token = token.token.clone();
+                self.store(&token, description, logger);
+                *cached = Some(token);
+                Ok(access_token)
+            }
+            Err(error) => match &*cached {
+                Some(token) if !token.expired() => {
+                    warn!(
+                        logger, "failed to refresh {}, using cached token until it expires", description;
+                        "error" => format!("{:?}", error),
+                        "expiration" => token.expiration.to_rfc3339(),
+                    );
+                    Ok(token.token.clone())
+                }
+                _ => Err(error),
+            },
+        }
+    }
+
+    /// Loads the token from the OauthTokenCache, if it is stored in one and
+    /// has not expired. Failure to load the token is not fatal, since it can
+    /// always be obtained again.
+    fn load(&self, description: &str, logger: &Logger) -> Option<OauthToken> {
+        let persistence = self.persistence.as_ref()?;
+        let stored = persistence
+            .cache
+            .get(&persistence.key)
+            .and_then(|entry| match entry {
+                Some(entry) => Ok(Some(
+                    serde_json::from_slice::<StoredOauthToken>(&entry)
+                        .context("failed to deserialize cached token")?,
+                )),
+                None => Ok(None),
+            });
+        match stored {
+            Ok(Some(stored)) => {
+                let token = OauthToken::new(stored.token, stored.expiration, RefreshWindow::get());
+                if token.expired() {
+                    return None;
+                }
+                debug!(logger, "loaded {} from Oauth token cache", description);
+                Some(token)
+            }
+            Ok(None) => None,
+            Err(error) => {
+                warn!(
+                    logger, "failed to load {} from Oauth token cache", description;
+                    "error" => format!("{:?}", error),
+                );
+                None
+            }
+        }
+    }
+
+    /// Stores the token in the OauthTokenCache, if there is one. Failure to
+    /// store the token is not fatal, since later processes can obtain their
+    /// own.
+    fn store(&self, token: &OauthToken, description: &str, logger: &Logger) {
+        let persistence = match &self.persistence {
+            Some(persistence) => persistence,
+            None => return,
+        };
+        let result = serde_json::to_vec(&StoredOauthToken {
+            token: token.token.clone(),
+            expiration: token.expiration,
+        })
+        .context("failed to serialize token")
+        .and_then(|entry| persistence.cache.put(&persistence.key, &entry));
+        if let Err(error) = result {
+            warn!(
+                logger, "failed to store {} in Oauth token cache", description;
+                "error" => format!("{:?}", error),
+            );
+        }
+    }
+}
+
+impl Debug for CachedToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self.token.read().unwrap() {
+            Some(_) => "Some(redacted)",
+            None => "None",
+        })
+    }
+}
+
+/// Identifies a token in TOKEN_REGISTRY and in OAUTH_TOKEN_CACHE.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum TokenKey {
+    /// An Oauth token obtained with the default credentials described by
+    /// `credentials`, as returned by ProvideDefaultToken::credentials.
+    Default { credentials: String, scope: String },
+    /// An Oauth token for `account`, obtained by impersonating it with the
+    /// default credentials described by `credentials`.
+    Impersonated {
+        credentials: String,
+        account: String,
+        scope: String,
+    },
+    /// An OIDC identity token for `audience`, obtained with the default
+    /// credentials described by `credentials`.
+    Identity {
+        credentials: String,
+        audience: String,
+    },
+    /// An OIDC identity token for `audience` for `account`, obtained by
+    /// impersonating it with the default credentials described by
+    /// `credentials`.
+    ImpersonatedIdentity {
+        credentials: String,
+        account: String,
+        audience: String,
+    },
+}
+
+/// Tokens obtained by this process, so that every token provider for the same
+/// credentials, scope and audience shares the same token rather than obtaining
+/// its own.
+static TOKEN_REGISTRY: Lazy<Mutex<HashMap<TokenKey, CachedToken>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the token registered under `key`, which is empty until the first
+/// time any provider obtains it.
+fn registered_token(key: TokenKey) -> CachedToken {
+    TOKEN_REGISTRY
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_insert_with(|| CachedToken::new(&key))
+        .clone()
 }
 
 /// Represents the response from a GET request to the GKE metadata service's
@@ -101,6 +583,14 @@ struct GenerateAccessTokenResponse {
     expire_time: DateTime<Utc>,
 }
 
+/// Represents the response from a POST request to the GCP IAM service's
+/// generateIdToken endpoint.
+/// https://cloud.google.com/iam/docs/reference/credentials/rest/v1/projects.serviceAccounts/generateIdToken
+#[derive(Deserialize, PartialEq)]
+struct GenerateIdTokenResponse {
+    token: String,
+}
+
 /// This is the subset of a GCP service account key file that we need to parse
 /// to construct a signed JWT.
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -121,6 +611,21 @@ struct ServiceAccountKeyFile {
 /// account Oauth token from GCP IAM.
 trait ProvideDefaultToken: DynClone + Debug {
     fn default_token(&self) -> Result<Response>;
+
+    /// Describes the credentials used to obtain the default token, so that
+    /// providers using the same credentials can share tokens.
+    fn credentials(&self) -> String;
+
+    /// Obtains an OIDC identity token for the default service account whose
+    /// aud claim will be `audience`. Only some default credentials can be used
+    /// to obtain identity tokens directly; others must impersonate a service
+    /// account.
+    fn identity_token(&self, _audience: &str) -> Result<String> {
+        Err(anyhow!(
+            "identity tokens cannot be obtained with {} without impersonating a service account",
+            self.credentials()
+        ))
+    }
 }
 
 dyn_clone::clone_trait_object!(ProvideDefaultToken);
@@ -147,6 +652,10 @@ impl GkeMetadataServiceDefaultTokenProvider {
 }
 
 impl ProvideDefaultToken for GkeMetadataServiceDefaultTokenProvider {
+    fn credentials(&self) -> String {
+        format!("GKE metadata service {}", self.metadata_service_base_url)
+    }
+
     fn default_token(&self) -> Result<Response> {
         debug!(
             self.logger,
@@ -165,6 +674,18 @@ impl ProvideDefaultToken for GkeMetadataServiceDefaultTokenProvider {
             .call(&self.logger, &request)
             .context("failed to query GKE metadata service")
     }
+
+    /// Obtains an OIDC identity token for the GCP SA mapped to the current
+    /// Kubernetes SA via GKE workload identity from the GKE metadata service.
+    /// https://cloud.google.com/compute/docs/instances/verifying-instance-identity#request_signature
+    fn identity_token(&self, audience: &str) -> Result<String> {
+        identity_token_from_metadata_service(
+            self.metadata_service_base_url,
+            &self.agent,
+            audience,
+            &self.logger,
+        )
+    }
 }
 
 /// Uses a GCP service account key file to authenticate to GCP IAM as some
@@ -178,6 +699,13 @@ struct ServiceAccountKeyFileDefaultTokenProvider {
 }
 
 impl ProvideDefaultToken for ServiceAccountKeyFileDefaultTokenProvider {
+    fn credentials(&self) -> String {
+        format!(
+            "key file for {} with key {}",
+            self.key_file.client_email, self.key_file.private_key_id
+        )
+    }
+
     fn default_token(&self) -> Result<Response> {
         debug!(self.logger, "obtaining account token from key file");
         // We construct the JWT per Google documentation:
@@ -245,6 +773,13 @@ impl Debug for AwsIamFederationViaWorkloadIdentityPoolDefaultTokenProvider {
 }
 
 impl ProvideDefaultToken for AwsIamFederationViaWorkloadIdentityPoolDefaultTokenProvider {
+    fn credentials(&self) -> String {
+        format!(
+            "{} via workload identity pool provider {}",
+            self.aws_credentials_provider, self.workload_identity_pool_provider
+        )
+    }
+
     fn default_token(&self) -> Result<Response> {
         debug!(
             self.logger,
@@ -275,52 +810,117 @@ impl ProvideDefaultToken for AwsIamFederationViaWorkloadIdentityPoolDefaultToken
             &credentials,
         )?;
 
+        debug!(
+            self.logger,
+            "obtaining federated access token. gci_token {}", get_caller_identity_token,
+        );
+
         // The GetCallerIdentity token goes into the body of the request sent to
         // sts.googleapis.com (one assumes that GCP relays that request to
         // sts.amazonaws.com to prove that we control the AWS IAM role).
-        let request_body = ureq::json!({
-            "audience": &self.workload_identity_pool_provider,
-            "grantType": "urn:ietf:params:oauth:grant-type:token-exchange",
-            "requestedTokenType": "urn:ietf:params:oauth:token-type:access_token",
-            "scope": "https://www.googleapis.com/auth/cloud-platform",
-            "subjectToken": urlencoding::encode(&get_caller_identity_token.to_string()),
-            "subjectTokenType": "urn:ietf:params:aws:token-type:aws4_request",
-        });
+        exchange_federated_token(
+            &self.agent,
+            DEFAULT_STS_TOKEN_URL,
+            &self.workload_identity_pool_provider,
+            &urlencoding::encode(&get_caller_identity_token.to_string()),
+            "urn:ietf:params:aws:token-type:aws4_request",
+            &self.logger,
+        )
+    }
+}
 
-        let request = self
-            .agent
-            .prepare_request(RequestParameters {
-                url: Url::parse("https://sts.googleapis.com/v1/token")
-                    .context("failed to construct sts.googleapis.com URL")?,
-                method: Method::Post,
-                // This request is unauthenticated, except for the signature and
-                // token on the inner subjectToken
-                token_provider: None,
-            })?
-            .set("Content-Type", "application/json; charset=utf-8");
+/// Uses a GCP Workload Identity Pool to federate GCP IAM with an OIDC identity
+/// provider, allowing workloads holding an OIDC ID token from that provider to
+/// impersonate a GCP SA without a service account key file.
+#[derive(Clone, Debug)]
+struct OidcFederationViaWorkloadIdentityPoolDefaultTokenProvider {
+    token_file: PathBuf,
+    workload_identity_pool_provider: String,
+    logger: Logger,
+    agent: RetryingAgent,
+    /// URL of the GCP STS token exchange method
+    sts_token_url: &'static str,
+}
+
+impl ProvideDefaultToken for OidcFederationViaWorkloadIdentityPoolDefaultTokenProvider {
+    fn credentials(&self) -> String {
+        format!(
+            "OIDC token in {} via workload identity pool provider {}",
+            self.token_file.display(),
+            self.workload_identity_pool_provider
+        )
+    }
 
+    fn default_token(&self) -> Result<Response> {
         debug!(
             self.logger,
-            "obtaining federated access token. request {:?}\ngci_token {}\nbody {}",
-            request,
-            get_caller_identity_token,
-            request_body
+            "getting GCP workload identity pool federated token for OIDC token"
         );
-        self.agent
-            .send_json_request(&self.logger, &request, &request_body)
-            .context("failed to obtain federated access token from sts.googleapis.com")
+        let oidc_token = fs::read_to_string(&self.token_file).context(format!(
+            "failed to read OIDC token from {}",
+            self.token_file.display()
+        ))?;
+
+        exchange_federated_token(
+            &self.agent,
+            self.sts_token_url,
+            &self.workload_identity_pool_provider,
+            oidc_token.trim(),
+            "urn:ietf:params:oauth:token-type:jwt",
+            &self.logger,
+        )
     }
 }
 
+/// Exchanges the provided external credential for a federated access token
+/// using sts.googleapis.com's token method.
+/// https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token
+fn exchange_federated_token(
+    agent: &RetryingAgent,
+    sts_token_url: &str,
+    workload_identity_pool_provider: &str,
+    subject_token: &str,
+    subject_token_type: &str,
+    logger: &Logger,
+) -> Result<Response> {
+    let request_body = serde_json::json!({
+        "audience": workload_identity_pool_provider,
+        "grantType": "urn:ietf:params:oauth:grant-type:token-exchange",
+        "requestedTokenType": "urn:ietf:params:oauth:token-type:access_token",
+        "scope": "https://www.googleapis.com/auth/cloud-platform",
+        "subjectToken": subject_token,
+        "subjectTokenType": subject_token_type,
+    });
+
+    let request = agent
+        .prepare_request(RequestParameters {
+            url: Url::parse(sts_token_url).context("failed to construct STS token URL")?,
+            method: Method::Post,
+            // This request is unauthenticated, except for the signature and
+            // token on the inner subjectToken
+            token_provider: None,
+        })?
+        .set("Content-Type", "application/json; charset=utf-8");
+
+    debug!(
+        logger,
+        "obtaining federated access token. request {:?}", request
+    );
+    agent
+        .send_json_request(logger, &request, &request_body)
+        .context("failed to obtain federated access token from sts.googleapis.com")
+}
+
 /// GcpOauthTokenProvider manages a default service account Oauth token (i.e. the
 /// one for a GCP service account mapped to a Kubernetes service account, or the
 /// one found in a JSON key file) and an Oauth token used to impersonate another
 /// service account.
 ///
 /// A note on thread safety: this struct stores any Oauth tokens it obtains in
-/// an Arc+Mutex, so an instance of GcpOauthTokenProvider may be .clone()d
-/// liberally and shared across threads, and credentials obtained from the GCP
-/// credentials API will be shared efficiently and safely.
+/// CachedTokens from TOKEN_REGISTRY, so an instance of GcpOauthTokenProvider
+/// may be .clone()d liberally and shared across threads, and credentials
+/// obtained from the GCP credentials API will be shared efficiently and safely,
+/// including with other instances using the same credentials and scope.
 #[derive(Clone)]
 pub(crate) struct GcpOauthTokenProvider {
     /// The Oauth scope for which tokens should be requested.
@@ -331,15 +931,14 @@ pub(crate) struct GcpOauthTokenProvider {
     /// Holds the service account email to impersonate, if one was provided to
     /// GcpOauthTokenProvider::new.
     account_to_impersonate: Option<String>,
-    /// This field is None after instantiation and is Some after the first
-    /// successful request for a token for the default service account, though
-    /// the contained token may be expired.
-    default_account_token: Arc<RwLock<Option<OauthToken>>>,
-    /// This field is None after instantiation and is Some after the first
-    /// successful request for a token for the impersonated service account,
-    /// though the contained token may be expired. This will always be None if
+    /// The token for the default service account, shared with other providers
+    /// using the same default credentials and scope.
+    default_account_token: CachedToken,
+    /// The token for the impersonated service account, shared with other
+    /// providers impersonating the same account with the same default
+    /// credentials and scope. This will always be empty if
     /// account_to_impersonate is None.
-    impersonated_account_token: Arc<RwLock<Option<OauthToken>>>,
+    impersonated_account_token: CachedToken,
     /// The agent will be used when making HTTP requests to GCP APIs to fetch
     /// Oauth tokens.
     agent: RetryingAgent,
@@ -354,23 +953,10 @@ impl fmt::Debug for GcpOauthTokenProvider {
         f.debug_struct("OauthTokenProvider")
             .field("account_to_impersonate", &self.account_to_impersonate)
             .field("default_token_provider", &self.default_token_provider)
-            .field(
-                "default_account_token",
-                &self
-                    .default_account_token
-                    .read()
-                    .unwrap()
-                    .as_ref()
-                    .map(|_| "redacted"),
-            )
+            .field("default_account_token", &self.default_account_token)
             .field(
                 "impersonated_account_token",
-                &self
-                    .default_account_token
-                    .read()
-                    .unwrap()
-                    .as_ref()
-                    .map(|_| "redacted"),
+                &self.impersonated_account_token,
             )
             .finish()
     }
@@ -391,6 +977,75 @@ impl OauthTokenProvider for GcpOauthTokenProvider {
     }
 }
 
+/// GcpIdentityTokenProvider is an OauthTokenProvider that provides OIDC
+/// identity tokens for a fixed audience obtained with a GcpOauthTokenProvider,
+/// so that they can be used in RequestParameters to authenticate to services
+/// that expect identity tokens rather than Oauth access tokens.
+#[derive(Clone, Debug)]
+pub(crate) struct GcpIdentityTokenProvider {
+    provider: GcpOauthTokenProvider,
+    audience: String,
+}
+
+impl GcpIdentityTokenProvider {
+    pub(crate) fn new(provider: GcpOauthTokenProvider, audience: String) -> Self {
+        GcpIdentityTokenProvider { provider, audience }
+    }
+}
+
+impl OauthTokenProvider for GcpIdentityTokenProvider {
+    fn ensure_oauth_token(&mut self) -> Result<String> {
+        self.provider.ensure_identity_token(&self.audience)
+    }
+}
+
+/// Error returned when a token could not be obtained because the GKE metadata
+/// service, GCP IAM or GCP STS kept failing in ways that are retried, such as
+/// HTTP 429 Too Many Requests or 503 Service Unavailable, until retries were
+/// exhausted. Such failures are likely to be transient, unlike failures due to
+/// misconfigured credentials.
+#[derive(Debug, thiserror::Error)]
+#[error("gave up obtaining {kind} token after retrying transient failures")]
+pub struct TokenRetriesExhaustedError {
+    kind: String,
+}
+
+/// Returns an agent for requests to the GKE metadata service and to GCP IAM
+/// and STS.
+fn token_service_agent() -> RetryingAgent {
+    RetryingAgent::new(
+        RequestClass::Token,
+        // The GKE metadata service rate limits requests and responds with
+        // HTTP 429 Too Many Requests, which should be retried with exponential
+        // backoff, as should 429s from GCP IAM. 5xx statuses such as 503
+        // Service Unavailable are always retried.
+        vec![429],
+    )
+}
+
+/// Obtains a token with the provided function, counting the attempt in
+/// HTTP_METRICS. If the request failed even though `agent` retried it, the
+/// error is marked with a TokenRetriesExhaustedError.
+fn record_token_refresh<F, T>(agent: &RetryingAgent, kind: &str, refresh: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let result = refresh();
+    HTTP_METRICS
+        .oauth_token_refreshes
+        .with_label_values(&[kind, if result.is_ok() { "ok" } else { "error" }])
+        .inc();
+    result.map_err(|error| {
+        if agent.is_retryable(&error) {
+            error.context(TokenRetriesExhaustedError {
+                kind: kind.to_owned(),
+            })
+        } else {
+            error
+        }
+    })
+}
+
 impl GcpOauthTokenProvider {
     /// Creates a token provider which can impersonate the specified service
     /// account.
@@ -405,13 +1060,13 @@ impl GcpOauthTokenProvider {
             "scope" => scope.to_owned(),
             "account_to_impersonate" => account_to_impersonate.clone().unwrap_or_else(|| "none".to_owned()),
         ));
-        let agent = RetryingAgent::default();
+        let agent = token_service_agent();
 
         let default_token_provider: Box<dyn ProvideDefaultToken> =
             match (key_file_reader, workload_identity_pool_params) {
                 (Some(_), Some(_)) => {
                     return Err(anyhow!(
-                        "either but not both of key_file_reader or aws_credentials may be provided"
+                        "either but not both of key_file_reader or workload_identity_pool_params may be provided"
                     ))
                 }
                 (Some(reader), None) => Box::new(ServiceAccountKeyFileDefaultTokenProvider {
@@ -421,26 +1076,53 @@ impl GcpOauthTokenProvider {
                     agent: agent.clone(),
                     logger: logger.clone(),
                 }),
-                (None, Some(parameters)) => Box::new(
-                    AwsIamFederationViaWorkloadIdentityPoolDefaultTokenProvider {
-                        aws_credentials_provider: parameters.aws_credentials_provider,
-                        workload_identity_pool_provider: parameters.workload_identity_pool_provider,
-                        logger: logger.clone(),
-                        agent: agent.clone(),
-                    },
-                ),
+                (None, Some(parameters)) => match parameters.credential_source {
+                    WorkloadIdentityCredentialSource::Aws(aws_credentials_provider) => Box::new(
+                        AwsIamFederationViaWorkloadIdentityPoolDefaultTokenProvider {
+                            aws_credentials_provider: *aws_credentials_provider,
+                            workload_identity_pool_provider: parameters
+                                .workload_identity_pool_provider,
+                            logger: logger.clone(),
+                            agent: agent.clone(),
+                        },
+                    ),
+                    WorkloadIdentityCredentialSource::OidcTokenFile(token_file) => Box::new(
+                        OidcFederationViaWorkloadIdentityPoolDefaultTokenProvider {
+                            token_file,
+                            workload_identity_pool_provider: parameters
+                                .workload_identity_pool_provider,
+                            logger: logger.clone(),
+                            agent: agent.clone(),
+                            sts_token_url: DEFAULT_STS_TOKEN_URL,
+                        },
+                    ),
+                },
                 (None, None) => Box::new(GkeMetadataServiceDefaultTokenProvider::new(
                     agent.clone(),
                     logger.clone(),
                 )),
             };
 
+        let credentials = default_token_provider.credentials();
+        let default_account_token = registered_token(TokenKey::Default {
+            credentials: credentials.clone(),
+            scope: scope.to_owned(),
+        });
+        let impersonated_account_token = match &account_to_impersonate {
+            Some(account) => registered_token(TokenKey::Impersonated {
+                credentials,
+                account: account.clone(),
+                scope: scope.to_owned(),
+            }),
+            None => CachedToken::default(),
+        };
+
         Ok(GcpOauthTokenProvider {
             scope: scope.to_owned(),
             default_token_provider,
             account_to_impersonate,
-            default_account_token: Arc::new(RwLock::new(None)),
-            impersonated_account_token: Arc::new(RwLock::new(None)),
+            default_account_token,
+            impersonated_account_token,
             agent,
             logger,
             iam_service_base_url: DEFAULT_IAM_BASE_URL,
@@ -448,65 +1130,55 @@ impl GcpOauthTokenProvider {
     }
 
     /// Returns the current OAuth token for the default service account, if it
-    /// is valid. Otherwise obtains and returns a new one.
-    /// The returned value is an owned reference because the token owned by this
-    /// struct could change while the caller is still holding the returned token
-    fn ensure_default_account_token(&mut self) -> Result<String> {
-        if let Some(token) = &*self.default_account_token.read().unwrap() {
-            if !token.expired() {
-                debug!(self.logger, "cached default account token is still valid");
-                return Ok(token.token.clone());
-            }
-        }
-
-        let mut default_account_token = self.default_account_token.write().unwrap();
-
-        // Check if the token was updated between when we dropped the read lock
-        // and when we acquired the write lock
-        if let Some(token) = &*default_account_token {
-            if !token.expired() {
-                debug!(self.logger, "cached default account token is still valid");
-                return Ok(token.token.clone());
-            }
-        }
+    /// does not yet need to be refreshed. Otherwise obtains and returns a new
+    /// one.
+    fn ensure_default_account_token(&self) -> Result<String> {
+        self.default_account_token
+            .get_or_refresh("default account token", &self.logger, || {
+                self.fetch_default_account_token()
+            })
+    }
 
-        let http_response = self.default_token_provider.default_token()?;
+    /// Obtains a new OAuth token for the default service account.
+    fn fetch_default_account_token(&self) -> Result<OauthToken> {
+        let http_response = record_token_refresh(&self.agent, "default", || {
+            self.default_token_provider.default_token()
+        })?;
 
-        let response = http_response
-            .into_json::<OauthTokenResponse>()
+        let response: OauthTokenResponse = self
+            .agent
+            .read_json(http_response)
             .context("failed to deserialize response from GCP token service")?;
 
         if response.token_type != "Bearer" {
             return Err(anyhow!("unexpected token type {}", response.token_type));
         }
 
-        *default_account_token = Some(OauthToken {
-            token: response.access_token.clone(),
-            expiration: Utc::now() + Duration::seconds(response.expires_in),
-        });
-
-        Ok(response.access_token)
+        Ok(OauthToken::new(
+            response.access_token,
+            Utc::now() + Duration::seconds(response.expires_in),
+            RefreshWindow::get(),
+        ))
     }
 
     /// Returns the current OAuth token for the impersonated service account, if
-    /// it is valid. Otherwise obtains and returns a new one.
-    fn ensure_impersonated_service_account_oauth_token(&mut self) -> Result<String> {
+    /// it does not yet need to be refreshed. Otherwise obtains and returns a new
+    /// one.
+    fn ensure_impersonated_service_account_oauth_token(&self) -> Result<String> {
         if self.account_to_impersonate.is_none() {
             return Err(anyhow!("no service account to impersonate was provided"));
         }
 
-        if let Some(token) = &*self.impersonated_account_token.read().unwrap() {
-            if !token.expired() {
-                debug!(
-                    self.logger,
-                    "cached token is still valid for impersonating service account"
-                );
-                return Ok(token.token.clone());
-            }
-        }
+        self.impersonated_account_token.get_or_refresh(
+            "impersonated account token",
+            &self.logger,
+            || self.fetch_impersonated_account_token(self.ensure_default_account_token()?),
+        )
+    }
 
-        let default_token = self.ensure_default_account_token()?;
-        let mut impersonated_account_token = self.impersonated_account_token.write().unwrap();
+    /// Obtains a new OAuth token for the impersonated service account, using
+    /// the provided default service account token.
+    fn fetch_impersonated_account_token(&self, default_token: String) -> Result<OauthToken> {
         let service_account_to_impersonate = self.account_to_impersonate.clone().unwrap();
 
         let request = self.agent.prepare_request(RequestParameters {
@@ -523,29 +1195,116 @@ impl GcpOauthTokenProvider {
             "obtaining token to impersonate service account"
         );
 
-        let http_response = self
-            .agent
-            .send_json_request(
+        let http_response = record_token_refresh(&self.agent, "impersonated", || {
+            self.agent.send_json_request(
                 &self.logger,
                 &request,
-                &ureq::json!({
+                &serde_json::json!({
                     "scope": [self.scope]
                 }),
             )
-            .context(format!(
-                "failed to get Oauth token to impersonate service account {}",
-                service_account_to_impersonate
-            ))?;
+        })
+        .context(format!(
+            "failed to get Oauth token to impersonate service account {}",
+            service_account_to_impersonate
+        ))?;
 
-        let response = http_response
-            .into_json::<GenerateAccessTokenResponse>()
+        let response: GenerateAccessTokenResponse = self
+            .agent
+            .read_json(http_response)
             .context("failed to deserialize response from IAM API")?;
-        *impersonated_account_token = Some(OauthToken {
-            token: response.access_token.clone(),
-            expiration: response.expire_time,
-        });
 
-        Ok(response.access_token)
+        Ok(OauthToken::new(
+            response.access_token,
+            response.expire_time,
+            RefreshWindow::get(),
+        ))
+    }
+
+    /// Returns an OIDC identity token whose aud claim is `audience`, for use
+    /// with APIs that authenticate callers by Google-signed identity tokens
+    /// rather than Oauth access tokens, fetching it or renewing it if
+    /// necessary. If a service account to impersonate was provided, the token
+    /// is obtained for that account from the GCP IAM API. Otherwise it is
+    /// obtained for the default service account, which is only possible when
+    /// running in GKE.
+    /// Identity tokens are shared by all providers in this process using the
+    /// same credentials and audience.
+    pub(crate) fn ensure_identity_token(&self, audience: &str) -> Result<String> {
+        let credentials = self.default_token_provider.credentials();
+        match &self.account_to_impersonate {
+            Some(account) => registered_token(TokenKey::ImpersonatedIdentity {
+                credentials,
+                account: account.clone(),
+                audience: audience.to_owned(),
+            })
+            .get_or_refresh("impersonated identity token", &self.logger, || {
+                self.fetch_impersonated_identity_token(
+                    audience,
+                    self.ensure_default_account_token()?,
+                )
+            }),
+            None => registered_token(TokenKey::Identity {
+                credentials,
+                audience: audience.to_owned(),
+            })
+            .get_or_refresh("identity token", &self.logger, || {
+                let token = record_token_refresh(&self.agent, "identity", || {
+                    self.default_token_provider.identity_token(audience)
+                })?;
+                let expiration = identity_token_expiration(&token)?;
+                Ok(OauthToken::new(token, expiration, RefreshWindow::get()))
+            }),
+        }
+    }
+
+    /// Obtains a new OIDC identity token for the impersonated service account,
+    /// using the provided default service account token.
+    fn fetch_impersonated_identity_token(
+        &self,
+        audience: &str,
+        default_token: String,
+    ) -> Result<OauthToken> {
+        let service_account_to_impersonate = self.account_to_impersonate.clone().unwrap();
+
+        let request = self.agent.prepare_request(RequestParameters {
+            url: id_token_url_for_service_account(
+                self.iam_service_base_url,
+                &service_account_to_impersonate,
+            )?,
+            method: Method::Post,
+            token_provider: Some(&mut StaticOauthTokenProvider::from(default_token)),
+        })?;
+
+        debug!(
+            self.logger,
+            "obtaining identity token for impersonated service account";
+            "audience" => audience,
+        );
+
+        let http_response = record_token_refresh(&self.agent, "impersonated_identity", || {
+            self.agent.send_json_request(
+                &self.logger,
+                &request,
+                &serde_json::json!({
+                    "audience": audience,
+                    "includeEmail": true,
+                }),
+            )
+        })
+        .context(format!(
+            "failed to get identity token for impersonated service account {}",
+            service_account_to_impersonate
+        ))?;
+
+        let token = self
+            .agent
+            .read_json::<GenerateIdTokenResponse>(http_response)
+            .context("failed to deserialize response from IAM API")?
+            .token;
+        let expiration = identity_token_expiration(&token)?;
+
+        Ok(OauthToken::new(token, expiration, RefreshWindow::get()))
     }
 }
 
@@ -583,13 +1342,142 @@ mod tests {
         };
 
         provider
-            .default_token()
+            .agent
+            .read_json::<OauthTokenResponse>(provider.default_token().unwrap())
+            .unwrap();
+        mocked_get.assert();
+    }
+
+    #[test]
+    fn token_requests_retried() {
+        let logger = setup_test_logging();
+        let mocked_get_429 = mock("GET", "/token-retries")
+            .match_header("Metadata-Flavor", "Google")
+            .with_status(429)
+            .expect(1)
+            .create();
+        let mocked_get = mock("GET", "/token-retries")
+            .match_header("Metadata-Flavor", "Google")
+            .with_status(200)
+            .with_body(
+                r#"{
+  "access_token": "fake-token",
+  "scope": "fake-scope",
+  "token_type": "Bearer",
+  "expires_in": 3600
+}
+"#,
+            )
+            .expect(1)
+            .create();
+
+        let agent = token_service_agent();
+        let request = agent
+            .prepare_request(RequestParameters {
+                url: Url::parse(&format!("{}/token-retries", mockito::server_url())).unwrap(),
+                method: Method::Get,
+                ..Default::default()
+            })
             .unwrap()
-            .into_json::<OauthTokenResponse>()
+            .set("Metadata-Flavor", "Google");
+
+        agent
+            .read_json::<OauthTokenResponse>(agent.call(&logger, &request).unwrap())
             .unwrap();
+        mocked_get_429.assert();
+        mocked_get.assert();
+    }
+
+    #[test]
+    fn token_retries_exhausted() {
+        let agent = token_service_agent();
+        let status_error = |status| -> Result<()> {
+            Err(crate::http::HttpError::Status(
+                status,
+                Response::new(status, "").unwrap(),
+            ))
+            .context("failed to get token")
+        };
+
+        for status in &[429, 503] {
+            let error = record_token_refresh(&agent, "default", || status_error(*status))
+                .context("more context")
+                .unwrap_err();
+            assert!(error.is::<TokenRetriesExhaustedError>(), "{:?}", error);
+            // The underlying error is still available
+            assert_eq!(crate::http::error_http_status(&error), Some(*status));
+        }
+
+        let error = record_token_refresh(&agent, "default", || status_error(403)).unwrap_err();
+        assert!(!error.is::<TokenRetriesExhaustedError>(), "{:?}", error);
+    }
+
+    #[test]
+    fn metadata_service_identity_token() {
+        let logger = setup_test_logging();
+        let mocked_get = mock("GET", DEFAULT_IDENTITY_TOKEN_PATH)
+            .match_header("Metadata-Flavor", "Google")
+            .match_query(Matcher::UrlEncoded(
+                "audience".to_owned(),
+                "sts.amazonaws.com/123456789012".to_owned(),
+            ))
+            .with_status(200)
+            .with_body("fake-identity-token")
+            .expect(1)
+            .create();
+
+        let token = identity_token_from_metadata_service(
+            &mockito::server_url(),
+            &RetryingAgent::default(),
+            "sts.amazonaws.com/123456789012",
+            &logger,
+        )
+        .unwrap();
+        assert_eq!(token, "fake-identity-token");
         mocked_get.assert();
     }
 
+    #[test]
+    fn oidc_federated_token() {
+        let logger = setup_test_logging();
+        let mut token_file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(token_file, "fake-oidc-token").unwrap();
+
+        let mocked_post = mock("POST", "/v1/token")
+            .match_body(Matcher::PartialJson(json!({
+                "audience": "fake-pool-provider",
+                "subjectToken": "fake-oidc-token",
+                "subjectTokenType": "urn:ietf:params:oauth:token-type:jwt",
+            })))
+            .with_status(200)
+            .with_body(
+                r#"{
+  "access_token": "fake-token",
+  "issued_token_type": "urn:ietf:params:oauth:token-type:access_token",
+  "token_type": "Bearer",
+  "expires_in": 3600
+}
+"#,
+            )
+            .expect(1)
+            .create();
+
+        let provider = OidcFederationViaWorkloadIdentityPoolDefaultTokenProvider {
+            token_file: token_file.path().to_path_buf(),
+            workload_identity_pool_provider: "fake-pool-provider".to_owned(),
+            logger,
+            agent: RetryingAgent::default(),
+            sts_token_url: leak_string(format!("{}/v1/token", mockito::server_url())),
+        };
+
+        let token = provider
+            .agent
+            .read_json::<OauthTokenResponse>(provider.default_token().unwrap())
+            .unwrap();
+        assert_eq!(token.access_token, "fake-token");
+        mocked_post.assert();
+    }
+
     #[test]
     fn get_token_with_key_file() {
         let logger = setup_test_logging();
@@ -655,9 +1543,8 @@ jbxbE/VdW03+iXZyrnDNFAFAsRR+XgjeYheAUVLelg9qBjM7jYNf
             logger,
         };
         provider
-            .default_token()
-            .unwrap()
-            .into_json::<OauthTokenResponse>()
+            .agent
+            .read_json::<OauthTokenResponse>(provider.default_token().unwrap())
             .unwrap();
 
         mocked_post.assert();
@@ -667,10 +1554,13 @@ jbxbE/VdW03+iXZyrnDNFAFAsRR+XgjeYheAUVLelg9qBjM7jYNf
     struct FakeDefaultTokenProvider {}
 
     impl ProvideDefaultToken for FakeDefaultTokenProvider {
+        fn credentials(&self) -> String {
+            "fake".to_owned()
+        }
+
         fn default_token(&self) -> Result<Response> {
             Response::new(
                 200,
-                "OK",
                 r#"{
   "access_token": "fake-default-token",
   "scope": "fake-scope",
@@ -704,12 +1594,12 @@ jbxbE/VdW03+iXZyrnDNFAFAsRR+XgjeYheAUVLelg9qBjM7jYNf
             .expect(1)
             .create();
 
-        let mut provider = GcpOauthTokenProvider {
+        let provider = GcpOauthTokenProvider {
             scope: "fake-scope".to_string(),
             default_token_provider: Box::new(FakeDefaultTokenProvider {}),
             account_to_impersonate: Some("fake-service-account".to_string()),
-            default_account_token: Arc::new(RwLock::new(None)),
-            impersonated_account_token: Arc::new(RwLock::new(None)),
+            default_account_token: CachedToken::default(),
+            impersonated_account_token: CachedToken::default(),
             agent: RetryingAgent::default(),
             logger,
             iam_service_base_url: leak_string(mockito::server_url()),
@@ -726,4 +1616,383 @@ jbxbE/VdW03+iXZyrnDNFAFAsRR+XgjeYheAUVLelg9qBjM7jYNf
 
         mocked_post_impersonated.assert();
     }
+
+    #[test]
+    fn encrypted_file_token_cache() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let cache =
+            EncryptedFileTokenCache::new(directory.path().join("tokens"), &[1; 32]).unwrap();
+
+        assert_eq!(cache.get("key").unwrap(), None);
+        cache.put("key", b"entry").unwrap();
+        cache.put("other-key", b"other entry").unwrap();
+        assert_eq!(cache.get("key").unwrap(), Some(b"entry".to_vec()));
+        cache.put("key", b"new entry").unwrap();
+        assert_eq!(cache.get("key").unwrap(), Some(b"new entry".to_vec()));
+
+        // Entries are not stored in the clear
+        for file in fs::read_dir(directory.path().join("tokens")).unwrap() {
+            let content = fs::read(file.unwrap().path()).unwrap();
+            assert!(!content.windows(5).any(|window| window == b"entry"));
+        }
+
+        // Entries can't be decrypted with another key or moved to another key
+        let other_cache =
+            EncryptedFileTokenCache::new(directory.path().join("tokens"), &[2; 32]).unwrap();
+        other_cache.get("key").unwrap_err();
+        fs::copy(cache.path("other-key"), cache.path("key")).unwrap();
+        cache.get("key").unwrap_err();
+
+        EncryptedFileTokenCache::new(directory.path().to_path_buf(), &[1; 16]).unwrap_err();
+    }
+
+    #[test]
+    fn tokens_shared_through_cache() {
+        let logger = setup_test_logging();
+        let directory = tempfile::TempDir::new().unwrap();
+        let cache: &'static EncryptedFileTokenCache = Box::leak(Box::new(
+            EncryptedFileTokenCache::new(directory.path().to_path_buf(), &[1; 32]).unwrap(),
+        ));
+        // Simulates the token as seen by consecutive processes
+        let new_cached_token = |key: &str| CachedToken {
+            token: Arc::default(),
+            persistence: Some(TokenPersistence {
+                key: key.to_owned(),
+                cache,
+            }),
+        };
+        let refreshes = RwLock::new(0);
+        let refresh = |expires_in| {
+            *refreshes.write().unwrap() += 1;
+            Ok(OauthToken::new(
+                format!("token-{}", *refreshes.read().unwrap()),
+                Utc::now() + Duration::seconds(expires_in),
+                RefreshWindow::new(
+                    std::time::Duration::from_secs(60),
+                    std::time::Duration::from_secs(0),
+                )
+                .unwrap(),
+            ))
+        };
+
+        assert_eq!(
+            new_cached_token("key")
+                .get_or_refresh("token", &logger, || refresh(3600))
+                .unwrap(),
+            "token-1"
+        );
+        assert_eq!(
+            new_cached_token("key")
+                .get_or_refresh("token", &logger, || refresh(3600))
+                .unwrap(),
+            "token-1"
+        );
+        assert_eq!(*refreshes.read().unwrap(), 1);
+
+        // Tokens for other keys are not shared
+        assert_eq!(
+            new_cached_token("other-key")
+                .get_or_refresh("token", &logger, || refresh(30))
+                .unwrap(),
+            "token-2"
+        );
+
+        // Tokens that need to be refreshed are refreshed and stored again
+        assert_eq!(
+            new_cached_token("other-key")
+                .get_or_refresh("token", &logger, || refresh(3600))
+                .unwrap(),
+            "token-3"
+        );
+        assert_eq!(
+            new_cached_token("other-key")
+                .get_or_refresh("token", &logger, || refresh(3600))
+                .unwrap(),
+            "token-3"
+        );
+    }
+
+    /// Constructs an unsigned identity token for `audience` that expires at
+    /// `exp`.
+    fn fake_identity_token(audience: &str, exp: i64) -> String {
+        let claims = base64::encode_config(
+            json!({ "aud": audience, "exp": exp }).to_string(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        format!("eyJhbGciOiJSUzI1NiJ9.{}.c2lnbmF0dXJl", claims)
+    }
+
+    #[test]
+    fn get_impersonated_identity_token() {
+        let logger = setup_test_logging();
+        let identity_token = fake_identity_token("impersonated-audience", 4094323200);
+
+        let mocked_post_identity = mock(
+            "POST",
+            "/v1/projects/-/serviceAccounts/fake-service-account:generateIdToken",
+        )
+        .match_header("Authorization", "Bearer fake-default-token")
+        .match_body(Matcher::Json(json!({
+            "audience": "impersonated-audience",
+            "includeEmail": true,
+        })))
+        .with_status(200)
+        .with_body(json!({ "token": identity_token }).to_string())
+        .expect(1)
+        .create();
+
+        let provider = GcpOauthTokenProvider {
+            scope: "fake-scope".to_string(),
+            default_token_provider: Box::new(FakeDefaultTokenProvider {}),
+            account_to_impersonate: Some("fake-service-account".to_string()),
+            default_account_token: CachedToken::default(),
+            impersonated_account_token: CachedToken::default(),
+            agent: RetryingAgent::default(),
+            logger,
+            iam_service_base_url: leak_string(mockito::server_url()),
+        };
+
+        assert_eq!(
+            provider
+                .ensure_identity_token("impersonated-audience")
+                .unwrap(),
+            identity_token
+        );
+        // Get the token again and we should not see any more network requests
+        assert_eq!(
+            provider
+                .ensure_identity_token("impersonated-audience")
+                .unwrap(),
+            identity_token
+        );
+        mocked_post_identity.assert();
+
+        // Without impersonation, the fake default credentials can't provide
+        // identity tokens
+        let provider = GcpOauthTokenProvider {
+            account_to_impersonate: None,
+            ..provider
+        };
+        provider
+            .ensure_identity_token("impersonated-audience")
+            .unwrap_err();
+    }
+
+    #[test]
+    fn get_metadata_service_identity_token() {
+        let logger = setup_test_logging();
+        let identity_token = fake_identity_token("metadata-audience", 4094323200);
+
+        let mocked_get = mock("GET", DEFAULT_IDENTITY_TOKEN_PATH)
+            .match_header("Metadata-Flavor", "Google")
+            .match_query(Matcher::UrlEncoded(
+                "audience".to_owned(),
+                "metadata-audience".to_owned(),
+            ))
+            .with_status(200)
+            .with_body(&identity_token)
+            .expect(1)
+            .create();
+
+        let agent = RetryingAgent::default();
+        let provider = GcpOauthTokenProvider {
+            scope: "fake-scope".to_string(),
+            default_token_provider: Box::new(GkeMetadataServiceDefaultTokenProvider {
+                agent: agent.clone(),
+                logger: logger.clone(),
+                metadata_service_base_url: leak_string(mockito::server_url()),
+            }),
+            account_to_impersonate: None,
+            default_account_token: CachedToken::default(),
+            impersonated_account_token: CachedToken::default(),
+            agent,
+            logger,
+            iam_service_base_url: leak_string(mockito::server_url()),
+        };
+
+        for _ in 0..2 {
+            assert_eq!(
+                provider.ensure_identity_token("metadata-audience").unwrap(),
+                identity_token
+            );
+        }
+        mocked_get.assert();
+    }
+
+    /// Provides default tokens expiring in a minute, or fails, counting the
+    /// number of tokens requested.
+    #[derive(Clone, Debug)]
+    struct ShortLivedDefaultTokenProvider {
+        fail: bool,
+        requests: Arc<RwLock<u32>>,
+    }
+
+    impl ProvideDefaultToken for ShortLivedDefaultTokenProvider {
+        fn credentials(&self) -> String {
+            "short lived".to_owned()
+        }
+
+        fn default_token(&self) -> Result<Response> {
+            *self.requests.write().unwrap() += 1;
+            if self.fail {
+                return Err(anyhow!("fake failure"));
+            }
+            Response::new(
+                200,
+                r#"{
+  "access_token": "fake-default-token",
+  "scope": "fake-scope",
+  "token_type": "Bearer",
+  "expires_in": 60
+}
+"#,
+            )
+            .context("failed to create response")
+        }
+    }
+
+    #[test]
+    fn refresh_token_before_expiry() {
+        let logger = setup_test_logging();
+        let requests = Arc::new(RwLock::new(0));
+        let mut provider = GcpOauthTokenProvider {
+            scope: "fake-scope".to_string(),
+            default_token_provider: Box::new(ShortLivedDefaultTokenProvider {
+                fail: false,
+                requests: requests.clone(),
+            }),
+            account_to_impersonate: None,
+            default_account_token: CachedToken::default(),
+            impersonated_account_token: CachedToken::default(),
+            agent: RetryingAgent::default(),
+            logger: logger.clone(),
+            iam_service_base_url: DEFAULT_IAM_BASE_URL,
+        };
+
+        // The token expires within the refresh skew, so it is refreshed every
+        // time it is used.
+        assert_eq!(provider.ensure_oauth_token().unwrap(), "fake-default-token");
+        assert_eq!(provider.ensure_oauth_token().unwrap(), "fake-default-token");
+        assert_eq!(*requests.read().unwrap(), 2);
+
+        // If refreshing fails, the cached token is used until it expires.
+        provider.default_token_provider = Box::new(ShortLivedDefaultTokenProvider {
+            fail: true,
+            requests: requests.clone(),
+        });
+        assert_eq!(provider.ensure_oauth_token().unwrap(), "fake-default-token");
+        assert_eq!(*requests.read().unwrap(), 3);
+
+        *provider.default_account_token.token.write().unwrap() = Some(OauthToken {
+            token: "fake-expired-token".to_owned(),
+            expiration: Utc::now() - Duration::seconds(1),
+            refresh_time: Utc::now() - Duration::seconds(301),
+        });
+        provider.ensure_oauth_token().unwrap_err();
+    }
+
+    #[test]
+    fn token_refresh_time() {
+        let expiration = Utc::now() + Duration::hours(1);
+
+        let window = RefreshWindow::new(
+            std::time::Duration::from_secs(300),
+            std::time::Duration::from_secs(0),
+        )
+        .unwrap();
+        assert_eq!(
+            window.refresh_time(expiration),
+            expiration - Duration::seconds(300)
+        );
+
+        let window = RefreshWindow::new(
+            std::time::Duration::from_secs(300),
+            std::time::Duration::from_secs(60),
+        )
+        .unwrap();
+        for _ in 0..100 {
+            let refresh_time = window.refresh_time(expiration);
+            assert!(refresh_time <= expiration - Duration::seconds(300));
+            assert!(refresh_time >= expiration - Duration::seconds(360));
+        }
+
+        let token = OauthToken::new("token".to_owned(), expiration, window);
+        assert!(!token.expired());
+        assert!(!token.needs_refresh());
+        let token = OauthToken::new(
+            "token".to_owned(),
+            Utc::now() + Duration::seconds(60),
+            window,
+        );
+        assert!(!token.expired());
+        assert!(token.needs_refresh());
+    }
+
+    #[test]
+    fn providers_share_registered_tokens() {
+        let logger = setup_test_logging();
+        let new_provider = |scope: &str, account_to_impersonate: Option<&str>| {
+            GcpOauthTokenProvider::new(
+                scope,
+                account_to_impersonate.map(str::to_owned),
+                None,
+                None,
+                &logger,
+            )
+            .unwrap()
+        };
+
+        let provider = new_provider("registry-test-scope", Some("registry-test-account"));
+        *provider.default_account_token.token.write().unwrap() = Some(OauthToken::new(
+            "shared-default-token".to_owned(),
+            Utc::now() + Duration::hours(1),
+            RefreshWindow::get(),
+        ));
+        *provider.impersonated_account_token.token.write().unwrap() = Some(OauthToken::new(
+            "shared-impersonated-token".to_owned(),
+            Utc::now() + Duration::hours(1),
+            RefreshWindow::get(),
+        ));
+
+        // Providers for the same credentials and scope get the same tokens
+        // without making any requests
+        assert_eq!(
+            new_provider("registry-test-scope", None)
+                .ensure_oauth_token()
+                .unwrap(),
+            "shared-default-token"
+        );
+        assert_eq!(
+            new_provider("registry-test-scope", Some("registry-test-account"))
+                .ensure_oauth_token()
+                .unwrap(),
+            "shared-impersonated-token"
+        );
+
+        // Providers for other scopes or accounts do not
+        assert!(new_provider("registry-test-other-scope", None)
+            .default_account_token
+            .token
+            .read()
+            .unwrap()
+            .is_none());
+        assert!(
+            new_provider("registry-test-scope", Some("registry-test-other-account"))
+                .impersonated_account_token
+                .token
+                .read()
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn identity_token_expiry() {
+        let token = fake_identity_token("audience", 1633046400);
+        assert_eq!(
+            identity_token_expiration(&token).unwrap(),
+            DateTime::parse_from_rfc3339("2021-10-01T00:00:00Z").unwrap()
+        );
+        identity_token_expiration("not-a-jwt").unwrap_err();
+    }
 }
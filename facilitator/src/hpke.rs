@@ -0,0 +1,605 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use elliptic_curve::sec1::ToEncodedPoint;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+// This module implements the base mode of Hybrid Public Key Encryption
+// (RFC 9180) for the single-shot encryption of one message per encapsulated
+// key, which is all that encrypting report and packet shares needs. Only the
+// algorithms ring provides are supported. ring only does key agreement with
+// ephemeral keys, so senders use it, but recipients do Diffie-Hellman with
+// their static keys using the p256 and x25519-dalek crates.
+
+const HPKE_VERSION: &[u8] = b"HPKE-v1";
+const MODE_BASE: u8 = 0x00;
+/// Length of the nonces of every supported AEAD
+const NONCE_LENGTH: usize = 12;
+
+/// A key encapsulation mechanism
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kem {
+    /// DHKEM(P-256, HKDF-SHA256)
+    P256HkdfSha256,
+    /// DHKEM(X25519, HKDF-SHA256)
+    X25519HkdfSha256,
+}
+
+impl Kem {
+    pub fn id(&self) -> u16 {
+        match self {
+            Kem::P256HkdfSha256 => 0x0010,
+            Kem::X25519HkdfSha256 => 0x0020,
+        }
+    }
+
+    fn algorithm(&self) -> &'static agreement::Algorithm {
+        match self {
+            Kem::P256HkdfSha256 => &agreement::ECDH_P256,
+            Kem::X25519HkdfSha256 => &agreement::X25519,
+        }
+    }
+
+    /// Length of private keys
+    fn private_key_length(&self) -> usize {
+        32
+    }
+
+    /// Computes the serialized public key for the provided private key.
+    fn public_key(&self, private_key: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Kem::P256HkdfSha256 => {
+                let public_key = p256::PublicKey::from_secret_scalar(&p256_scalar(private_key)?);
+                Ok(public_key.to_encoded_point(false).as_bytes().to_vec())
+            }
+            Kem::X25519HkdfSha256 => {
+                let secret = x25519_dalek::StaticSecret::from(x25519_bytes(private_key)?);
+                Ok(x25519_dalek::PublicKey::from(&secret).as_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Computes the Diffie-Hellman shared secret between the provided private
+    /// key and public key.
+    fn diffie_hellman(&self, private_key: &[u8], public_key: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Kem::P256HkdfSha256 => {
+                let public_key = p256::PublicKey::from_sec1_bytes(public_key)
+                    .map_err(|_| anyhow!("invalid P-256 public key"))?;
+                let shared_secret = elliptic_curve::ecdh::diffie_hellman(
+                    p256_scalar(private_key)?,
+                    public_key.as_affine(),
+                );
+                Ok(shared_secret.as_bytes().to_vec())
+            }
+            Kem::X25519HkdfSha256 => {
+                ensure!(
+                    public_key.len() == self.public_key_length(),
+                    "X25519 public key has length {}, expected {}",
+                    public_key.len(),
+                    self.public_key_length()
+                );
+                let mut public_key_bytes = [0u8; 32];
+                public_key_bytes.copy_from_slice(public_key);
+                let shared_secret = x25519_dalek::StaticSecret::from(x25519_bytes(private_key)?)
+                    .diffie_hellman(&x25519_dalek::PublicKey::from(public_key_bytes));
+                // RFC 9180 section 7.1.4 requires rejecting an all-zero
+                // shared secret, which results from small order public keys.
+                ensure!(
+                    shared_secret.as_bytes() != &[0u8; 32],
+                    "X25519 shared secret is zero"
+                );
+                Ok(shared_secret.as_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Length of public keys, and so of encapsulated keys
+    pub fn public_key_length(&self) -> usize {
+        match self {
+            Kem::P256HkdfSha256 => 65,
+            Kem::X25519HkdfSha256 => 32,
+        }
+    }
+
+    fn suite_id(&self) -> Vec<u8> {
+        let mut suite_id = b"KEM".to_vec();
+        suite_id.extend_from_slice(&self.id().to_be_bytes());
+        suite_id
+    }
+}
+
+/// A key derivation function
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kdf {
+    HkdfSha256,
+}
+
+impl Kdf {
+    pub fn id(&self) -> u16 {
+        match self {
+            Kdf::HkdfSha256 => 0x0001,
+        }
+    }
+}
+
+/// An authenticated encryption with associated data algorithm
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aead {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Aead {
+    pub fn id(&self) -> u16 {
+        match self {
+            Aead::Aes128Gcm => 0x0001,
+            Aead::Aes256Gcm => 0x0002,
+            Aead::ChaCha20Poly1305 => 0x0003,
+        }
+    }
+
+    fn algorithm(&self) -> &'static aead::Algorithm {
+        match self {
+            Aead::Aes128Gcm => &aead::AES_128_GCM,
+            Aead::Aes256Gcm => &aead::AES_256_GCM,
+            Aead::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// The combination of algorithms with which a message is encrypted. Its
+/// string form, used in configuration, is the names of the KEM, KDF and AEAD
+/// joined with slashes, e.g. "x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ciphersuite {
+    pub kem: Kem,
+    pub kdf: Kdf,
+    pub aead: Aead,
+}
+
+const KEMS: &[(Kem, &str)] = &[
+    (Kem::P256HkdfSha256, "p256-hkdf-sha256"),
+    (Kem::X25519HkdfSha256, "x25519-hkdf-sha256"),
+];
+const KDFS: &[(Kdf, &str)] = &[(Kdf::HkdfSha256, "hkdf-sha256")];
+const AEADS: &[(Aead, &str)] = &[
+    (Aead::Aes128Gcm, "aes-128-gcm"),
+    (Aead::Aes256Gcm, "aes-256-gcm"),
+    (Aead::ChaCha20Poly1305, "chacha20-poly1305"),
+];
+
+impl Ciphersuite {
+    /// Returns the ciphersuite with the provided IANA algorithm identifiers.
+    pub fn from_ids(kem_id: u16, kdf_id: u16, aead_id: u16) -> Result<Self> {
+        Ok(Ciphersuite {
+            kem: KEMS
+                .iter()
+                .map(|(kem, _)| *kem)
+                .find(|kem| kem.id() == kem_id)
+                .ok_or_else(|| anyhow!("unsupported HPKE KEM {:#06x}", kem_id))?,
+            kdf: KDFS
+                .iter()
+                .map(|(kdf, _)| *kdf)
+                .find(|kdf| kdf.id() == kdf_id)
+                .ok_or_else(|| anyhow!("unsupported HPKE KDF {:#06x}", kdf_id))?,
+            aead: AEADS
+                .iter()
+                .map(|(aead, _)| *aead)
+                .find(|aead| aead.id() == aead_id)
+                .ok_or_else(|| anyhow!("unsupported HPKE AEAD {:#06x}", aead_id))?,
+        })
+    }
+
+    fn suite_id(&self) -> Vec<u8> {
+        let mut suite_id = b"HPKE".to_vec();
+        suite_id.extend_from_slice(&self.kem.id().to_be_bytes());
+        suite_id.extend_from_slice(&self.kdf.id().to_be_bytes());
+        suite_id.extend_from_slice(&self.aead.id().to_be_bytes());
+        suite_id
+    }
+
+    /// Derives the AEAD key and nonce for the first message from the KEM's
+    /// shared secret, as KeySchedule does in base mode.
+    fn key_schedule(&self, shared_secret: &[u8], info: &[u8]) -> Result<(LessSafeKey, Nonce)> {
+        let suite_id = self.suite_id();
+        let psk_id_hash = labeled_extract(&[], &suite_id, b"psk_id_hash", &[]);
+        let info_hash = labeled_extract(&[], &suite_id, b"info_hash", info);
+        let mut context = vec![MODE_BASE];
+        context.extend_from_slice(&psk_id_hash);
+        context.extend_from_slice(&info_hash);
+
+        let secret = labeled_extract(shared_secret, &suite_id, b"secret", &[]);
+        let algorithm = self.aead.algorithm();
+        let key = labeled_expand(&secret, &suite_id, b"key", &context, algorithm.key_len());
+        let nonce = labeled_expand(&secret, &suite_id, b"base_nonce", &context, NONCE_LENGTH);
+
+        let key = UnboundKey::new(algorithm, &key).map_err(|_| anyhow!("invalid AEAD key"))?;
+        let nonce =
+            Nonce::try_assume_unique_for_key(&nonce).map_err(|_| anyhow!("invalid AEAD nonce"))?;
+        Ok((LessSafeKey::new(key), nonce))
+    }
+}
+
+impl fmt::Display for Ciphersuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}",
+            name(KEMS, self.kem),
+            name(KDFS, self.kdf),
+            name(AEADS, self.aead)
+        )
+    }
+}
+
+impl FromStr for Ciphersuite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('/').collect();
+        ensure!(parts.len() == 3, "invalid HPKE ciphersuite {}", s);
+        Ok(Ciphersuite {
+            kem: value(KEMS, parts[0])?,
+            kdf: value(KDFS, parts[1])?,
+            aead: value(AEADS, parts[2])?,
+        })
+    }
+}
+
+fn name<T: PartialEq>(names: &[(T, &'static str)], value: T) -> &'static str {
+    names
+        .iter()
+        .find(|(candidate, _)| *candidate == value)
+        .map(|(_, name)| *name)
+        .unwrap_or("unknown")
+}
+
+fn value<T: Copy>(names: &[(T, &'static str)], name: &str) -> Result<T> {
+    names
+        .iter()
+        .find(|(_, candidate)| *candidate == name)
+        .map(|(value, _)| *value)
+        .ok_or_else(|| anyhow!("unknown HPKE algorithm {}", name))
+}
+
+/// A recipient's private key for a ciphersuite, along with its public key.
+#[derive(Clone)]
+pub struct HpkePrivateKey {
+    ciphersuite: Ciphersuite,
+    private_key: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+impl fmt::Debug for HpkePrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HpkePrivateKey")
+            .field("ciphersuite", &self.ciphersuite)
+            .field("public_key", &hex::encode(&self.public_key))
+            .finish()
+    }
+}
+
+impl HpkePrivateKey {
+    /// Constructs a key from its serialization, which is the big-endian
+    /// scalar for P-256 and the little-endian scalar for X25519.
+    pub fn new(ciphersuite: Ciphersuite, private_key: &[u8]) -> Result<Self> {
+        let public_key = ciphersuite
+            .kem
+            .public_key(private_key)
+            .context("failed to load HPKE private key")?;
+        Ok(HpkePrivateKey {
+            ciphersuite,
+            private_key: private_key.to_vec(),
+            public_key,
+        })
+    }
+
+    /// Generates a new private key.
+    pub fn generate(ciphersuite: Ciphersuite) -> Result<Self> {
+        let rng = SystemRandom::new();
+        // A random string is a valid X25519 key, and a P-256 key with
+        // overwhelming probability.
+        let mut private_key = vec![0u8; ciphersuite.kem.private_key_length()];
+        loop {
+            rng.fill(&mut private_key)
+                .map_err(|_| anyhow!("failed to generate HPKE private key"))?;
+            if let Ok(key) = HpkePrivateKey::new(ciphersuite, &private_key) {
+                return Ok(key);
+            }
+        }
+    }
+
+    pub fn ciphersuite(&self) -> Ciphersuite {
+        self.ciphersuite
+    }
+
+    /// The serialized public key, to which senders encrypt.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Decrypts a message encrypted to this key with the provided info and
+    /// associated data, given the sender's encapsulated key.
+    pub fn open(
+        &self,
+        encapsulated_key: &[u8],
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        let kem = self.ciphersuite.kem;
+        let dh = kem
+            .diffie_hellman(&self.private_key, encapsulated_key)
+            .context("HPKE key agreement failed")?;
+        let shared_secret = extract_and_expand(kem, &dh, encapsulated_key, &self.public_key);
+
+        let (key, nonce) = self.ciphersuite.key_schedule(&shared_secret, info)?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext_length = key
+            .open_in_place(nonce, Aad::from(aad), &mut in_out)
+            .map_err(|_| anyhow!("failed to decrypt HPKE ciphertext"))?
+            .len();
+        in_out.truncate(plaintext_length);
+        Ok(in_out)
+    }
+}
+
+/// Encrypts a message to the provided recipient public key with the provided
+/// info and associated data. Returns the encapsulated key and the ciphertext.
+pub fn seal(
+    ciphersuite: Ciphersuite,
+    recipient_public_key: &[u8],
+    info: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let kem = ciphersuite.kem;
+    let ephemeral_key = EphemeralPrivateKey::generate(kem.algorithm(), &SystemRandom::new())
+        .map_err(|_| anyhow!("failed to generate HPKE ephemeral key"))?;
+    let encapsulated_key = ephemeral_key
+        .compute_public_key()
+        .map_err(|_| anyhow!("failed to compute HPKE ephemeral public key"))?
+        .as_ref()
+        .to_vec();
+    let shared_secret = agreement::agree_ephemeral(
+        ephemeral_key,
+        &UnparsedPublicKey::new(kem.algorithm(), recipient_public_key),
+        anyhow!("HPKE key agreement failed"),
+        |dh| {
+            Ok(extract_and_expand(
+                kem,
+                dh,
+                &encapsulated_key,
+                recipient_public_key,
+            ))
+        },
+    )?;
+
+    let (key, nonce) = ciphersuite.key_schedule(&shared_secret, info)?;
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(nonce, Aad::from(aad), &mut in_out)
+        .map_err(|_| anyhow!("failed to encrypt HPKE plaintext"))?;
+    Ok((encapsulated_key, in_out))
+}
+
+fn p256_scalar(private_key: &[u8]) -> Result<p256::NonZeroScalar> {
+    // Rejects scalars that are zero or not less than the group order
+    p256::NonZeroScalar::try_from(private_key).map_err(|_| anyhow!("invalid P-256 private key"))
+}
+
+fn x25519_bytes(private_key: &[u8]) -> Result<[u8; 32]> {
+    ensure!(
+        private_key.len() == 32,
+        "X25519 private key has length {}, expected 32",
+        private_key.len()
+    );
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(private_key);
+    Ok(bytes)
+}
+
+/// Derives the KEM shared secret from the Diffie-Hellman shared secret.
+fn extract_and_expand(
+    kem: Kem,
+    dh: &[u8],
+    encapsulated_key: &[u8],
+    recipient_public_key: &[u8],
+) -> Vec<u8> {
+    let suite_id = kem.suite_id();
+    let mut kem_context = encapsulated_key.to_vec();
+    kem_context.extend_from_slice(recipient_public_key);
+    let eae_prk = labeled_extract(&[], &suite_id, b"eae_prk", dh);
+    labeled_expand(
+        &eae_prk,
+        &suite_id,
+        b"shared_secret",
+        &kem_context,
+        hmac::HMAC_SHA256.digest_algorithm().output_len,
+    )
+}
+
+fn labeled_extract(salt: &[u8], suite_id: &[u8], label: &[u8], ikm: &[u8]) -> Vec<u8> {
+    let mut labeled_ikm = HPKE_VERSION.to_vec();
+    labeled_ikm.extend_from_slice(suite_id);
+    labeled_ikm.extend_from_slice(label);
+    labeled_ikm.extend_from_slice(ikm);
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, salt), &labeled_ikm)
+        .as_ref()
+        .to_vec()
+}
+
+fn labeled_expand(
+    prk: &[u8],
+    suite_id: &[u8],
+    label: &[u8],
+    info: &[u8],
+    length: usize,
+) -> Vec<u8> {
+    // Output lengths are those of keys, nonces and secrets, which fit in the
+    // two bytes that encode them.
+    let mut labeled_info = u16::try_from(length).unwrap().to_be_bytes().to_vec();
+    labeled_info.extend_from_slice(HPKE_VERSION);
+    labeled_info.extend_from_slice(suite_id);
+    labeled_info.extend_from_slice(label);
+    labeled_info.extend_from_slice(info);
+
+    // HKDF-Expand (RFC 5869)
+    let key = hmac::Key::new(hmac::HMAC_SHA256, prk);
+    let mut output = Vec::with_capacity(length);
+    let mut block: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+    while output.len() < length {
+        let mut context = hmac::Context::with_key(&key);
+        context.update(&block);
+        context.update(&labeled_info);
+        context.update(&[counter]);
+        block = context.sign().as_ref().to_vec();
+        output.extend_from_slice(&block);
+        counter += 1;
+    }
+    output.truncate(length);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_ciphersuites() -> Vec<Ciphersuite> {
+        let mut ciphersuites = Vec::new();
+        for (kem, _) in KEMS {
+            for (kdf, _) in KDFS {
+                for (aead, _) in AEADS {
+                    ciphersuites.push(Ciphersuite {
+                        kem: *kem,
+                        kdf: *kdf,
+                        aead: *aead,
+                    });
+                }
+            }
+        }
+        ciphersuites
+    }
+
+    #[test]
+    fn ciphersuite_names() {
+        for ciphersuite in all_ciphersuites() {
+            assert_eq!(
+                Ciphersuite::from_str(&ciphersuite.to_string()).unwrap(),
+                ciphersuite
+            );
+            assert_eq!(
+                Ciphersuite::from_ids(
+                    ciphersuite.kem.id(),
+                    ciphersuite.kdf.id(),
+                    ciphersuite.aead.id()
+                )
+                .unwrap(),
+                ciphersuite
+            );
+        }
+        assert_eq!(
+            Ciphersuite::from_str("x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm").unwrap(),
+            Ciphersuite {
+                kem: Kem::X25519HkdfSha256,
+                kdf: Kdf::HkdfSha256,
+                aead: Aead::Aes128Gcm,
+            }
+        );
+        Ciphersuite::from_str("x25519-hkdf-sha256/hkdf-sha256").unwrap_err();
+        Ciphersuite::from_str("x448-hkdf-sha512/hkdf-sha256/aes-128-gcm").unwrap_err();
+        Ciphersuite::from_ids(0x0021, 0x0001, 0x0001).unwrap_err();
+    }
+
+    #[test]
+    fn roundtrip() {
+        for ciphersuite in all_ciphersuites() {
+            let key = HpkePrivateKey::generate(ciphersuite).unwrap();
+            let (encapsulated_key, ciphertext) =
+                seal(ciphersuite, key.public_key(), b"info", b"aad", b"plaintext").unwrap();
+            assert_eq!(key.public_key().len(), ciphersuite.kem.public_key_length());
+            assert_eq!(encapsulated_key.len(), ciphersuite.kem.public_key_length());
+            assert_eq!(
+                key.open(&encapsulated_key, b"info", b"aad", &ciphertext)
+                    .unwrap(),
+                b"plaintext",
+                "ciphersuite {}",
+                ciphersuite
+            );
+
+            key.open(&encapsulated_key, b"other info", b"aad", &ciphertext)
+                .unwrap_err();
+            key.open(&encapsulated_key, b"info", b"other aad", &ciphertext)
+                .unwrap_err();
+            let mut corrupted = ciphertext.clone();
+            corrupted[0] ^= 1;
+            key.open(&encapsulated_key, b"info", b"aad", &corrupted)
+                .unwrap_err();
+            let other_key = HpkePrivateKey::generate(ciphersuite).unwrap();
+            other_key
+                .open(&encapsulated_key, b"info", b"aad", &ciphertext)
+                .unwrap_err();
+        }
+    }
+
+    #[test]
+    fn invalid_private_key() {
+        let ciphersuite =
+            Ciphersuite::from_str("p256-hkdf-sha256/hkdf-sha256/aes-128-gcm").unwrap();
+        HpkePrivateKey::new(ciphersuite, &[0u8; 31]).unwrap_err();
+        // Zero is not a valid P-256 scalar
+        HpkePrivateKey::new(ciphersuite, &[0u8; 32]).unwrap_err();
+        // Nor is the group order
+        HpkePrivateKey::new(
+            ciphersuite,
+            &hex::decode("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551")
+                .unwrap(),
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn small_order_public_key() {
+        let ciphersuite =
+            Ciphersuite::from_str("x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm").unwrap();
+        let key = HpkePrivateKey::generate(ciphersuite).unwrap();
+        // The identity point yields an all-zero shared secret
+        let error = key
+            .open(&[0u8; 32], b"info", b"aad", &[0u8; 32])
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("shared secret is zero"));
+    }
+
+    #[test]
+    fn rfc_9180_test_vector() {
+        // Appendix A.1.1: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM,
+        // base mode, first encryption
+        let ciphersuite = Ciphersuite::from_ids(0x0020, 0x0001, 0x0001).unwrap();
+        let key = HpkePrivateKey::new(
+            ciphersuite,
+            &hex::decode("4612c550263fc8ad58375df3f557aac531d26850903e55a9f23f21d8534e8ac8")
+                .unwrap(),
+        )
+        .unwrap();
+        let plaintext = key
+            .open(
+                &hex::decode("37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431")
+                    .unwrap(),
+                &hex::decode("4f6465206f6e2061204772656369616e2055726e").unwrap(),
+                &hex::decode("436f756e742d30").unwrap(),
+                &hex::decode(
+                    "f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a96d877\
+                    0ac83d07bea87e13c512a",
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(plaintext, b"Beauty is truth, truth beauty");
+    }
+}
@@ -0,0 +1,615 @@
+use crate::{
+    batch::{BatchReader, DigestStatus, SignatureStatus},
+    field::{EncodedField, PrioField},
+    idl::{Header, Packet, ValidationHeader, ValidationPacket},
+};
+use anyhow::{Context, Result};
+use prio::{
+    field::{Field32, Field64},
+    server::{is_valid_share, VerificationMessage},
+};
+use ring::signature::UnparsedPublicKey;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    io::Write,
+};
+use uuid::Uuid;
+
+/// A line of output from inspect_batch. Serialized as a JSON object with a
+/// single member whose name identifies what the line describes.
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Line<'a, H, P> {
+    Header(&'a H),
+    Signature(&'a SignatureStatus),
+    Packet(&'a P),
+}
+
+/// Writes the contents of the batch read by `reader` to `output` as
+/// line-delimited JSON: first a {"header": ...} line, then a
+/// {"signature": ...} line giving the status of the header's signature when
+/// checked against `public_keys`, then a {"packet": ...} line for each packet.
+/// The header is printed even if its signature is invalid. Packets are printed
+/// even if the packet file's digest does not match the header if `reader`
+/// permits malformed batches. Returns the number of packets in the batch.
+pub fn inspect_batch<H, P, W>(
+    reader: &mut BatchReader<'_, H, P>,
+    public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+    output: &mut W,
+) -> Result<usize>
+where
+    H: Header + Serialize,
+    P: Packet + Serialize,
+    W: Write,
+{
+    let (header, signature_status) = reader.header_with_signature_status(public_keys)?;
+    write_line::<H, P, W>(output, &Line::Header(&header))?;
+    write_line::<H, P, W>(output, &Line::Signature(&signature_status))?;
+
+    let mut packet_count = 0;
+    for packet in reader.packet_file_reader(&header)? {
+        let packet = packet.context(format!("failed to read packet {}", packet_count))?;
+        write_line::<H, P, W>(output, &Line::Packet(&packet))?;
+        packet_count += 1;
+    }
+    output.flush().context("failed to flush output")?;
+
+    Ok(packet_count)
+}
+
+/// A problem with a batch found by validate_batch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "violation", rename_all = "snake_case")]
+pub enum Violation {
+    /// The header's signature is invalid or was made with an unknown key.
+    HeaderSignature(SignatureStatus),
+    /// The packet file's digest does not match the header.
+    PacketFileDigest(DigestStatus),
+    /// The packet at the given index in the packet file could not be decoded.
+    /// Packets after it are not checked.
+    MalformedPacket { index: usize, error: String },
+    /// The packet file does not contain the expected number of packets.
+    PacketCount { expected: usize, actual: usize },
+    /// More than one packet in the packet file has this UUID.
+    DuplicatePacketUuid { uuid: Uuid },
+}
+
+/// The result of validating a batch with validate_batch.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ValidationReport {
+    /// The number of packets successfully decoded from the packet file.
+    pub packet_count: usize,
+    /// Every problem found with the batch.
+    pub violations: Vec<Violation>,
+}
+
+impl ValidationReport {
+    /// Returns true if no violations were found.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Checks the integrity of the batch read by `reader`: that the header's
+/// signature was made by one of `public_keys`, that the packet file's digest
+/// matches the header, that every packet can be decoded, that packet UUIDs are
+/// unique and, if `expected_packet_count` is provided, that the packet file
+/// contains that many packets. Unlike BatchReader::header and
+/// BatchReader::packet_file_reader, this does not stop at the first problem,
+/// but reports every violation it finds. Errors are returned only if the batch
+/// could not be fetched or its header could not be parsed.
+pub fn validate_batch<H: Header, P: Packet>(
+    reader: &mut BatchReader<'_, H, P>,
+    public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+    expected_packet_count: Option<usize>,
+) -> Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+
+    let (header, signature_status) = reader.header_with_signature_status(public_keys)?;
+    if !signature_status.is_valid() {
+        report
+            .violations
+            .push(Violation::HeaderSignature(signature_status));
+    }
+
+    let (packets, digest_status) = reader.packet_file_reader_with_digest_status(&header)?;
+    if digest_status != DigestStatus::Valid {
+        report
+            .violations
+            .push(Violation::PacketFileDigest(digest_status));
+    }
+
+    let mut uuids = HashSet::new();
+    let mut duplicate_uuids = HashSet::new();
+    for packet in packets {
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(error) => {
+                report.violations.push(Violation::MalformedPacket {
+                    index: report.packet_count,
+                    error: error.to_string(),
+                });
+                break;
+            }
+        };
+        let uuid = packet.uuid();
+        if !uuids.insert(uuid) && duplicate_uuids.insert(uuid) {
+            report
+                .violations
+                .push(Violation::DuplicatePacketUuid { uuid });
+        }
+        report.packet_count += 1;
+    }
+
+    if let Some(expected) = expected_packet_count {
+        if expected != report.packet_count {
+            report.violations.push(Violation::PacketCount {
+                expected,
+                actual: report.packet_count,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+/// A packet-level difference between our own and the peer's validation batches
+/// for the same ingestion batch, found by diff_validation_batches.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "difference", rename_all = "snake_case")]
+pub enum ValidationDifference {
+    /// The packet is in our own validation batch but not the peer's.
+    MissingFromPeer { uuid: Uuid },
+    /// The packet is in the peer's validation batch but not our own.
+    MissingFromOwn { uuid: Uuid },
+    /// The packet is in both validation batches, but combining them shows
+    /// that its proof is invalid.
+    InvalidProof { uuid: Uuid },
+}
+
+/// The result of comparing validation batches with diff_validation_batches.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ValidationDiff {
+    /// Whether the batches' headers agree on the aggregation's parameters.
+    pub parameters_match: bool,
+    /// The number of packets in our own validation batch.
+    pub own_packet_count: usize,
+    /// The number of packets in the peer's validation batch.
+    pub peer_packet_count: usize,
+    /// The number of packets in both batches whose proofs are valid.
+    pub valid_packet_count: usize,
+    /// Every packet that would be rejected during aggregation.
+    pub differences: Vec<ValidationDifference>,
+}
+
+impl ValidationDiff {
+    /// Returns true if the batches agree on every packet.
+    pub fn is_empty(&self) -> bool {
+        self.parameters_match && self.differences.is_empty()
+    }
+}
+
+/// Compares our own validation batch, read by `own_reader`, with the peer's
+/// validation batch for the same ingestion batch, read by `peer_reader`, and
+/// reports every packet that is missing from either of them or whose proof
+/// the two together show to be invalid, as aggregation would. Differences for
+/// packets in the peer's batch are listed in its order, followed by those for
+/// packets only in our own. Each batch's header must be signed by one of the
+/// corresponding public keys. Our own batch's packets are held in memory.
+pub fn diff_validation_batches(
+    own_reader: &mut BatchReader<'_, ValidationHeader, ValidationPacket>,
+    own_public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+    peer_reader: &mut BatchReader<'_, ValidationHeader, ValidationPacket>,
+    peer_public_keys: &HashMap<String, UnparsedPublicKey<Vec<u8>>>,
+) -> Result<ValidationDiff> {
+    let own_header = own_reader.header(own_public_keys)?;
+    let peer_header = peer_reader.header(peer_public_keys)?;
+    let mut diff = ValidationDiff {
+        parameters_match: own_header.check_parameters(&peer_header),
+        ..ValidationDiff::default()
+    };
+    let field = PrioField::from_prime(own_header.prime)?;
+
+    let mut own_packets = Vec::new();
+    let mut own_packet_indices = HashMap::new();
+    for packet in own_reader.packet_file_reader(&own_header)? {
+        let packet = packet?;
+        own_packet_indices.insert(packet.uuid, own_packets.len());
+        own_packets.push(Some(packet));
+    }
+    diff.own_packet_count = own_packets.len();
+
+    for peer_packet in peer_reader.packet_file_reader(&peer_header)? {
+        let peer_packet = peer_packet?;
+        diff.peer_packet_count += 1;
+        let own_packet = match own_packet_indices
+            .get(&peer_packet.uuid)
+            .and_then(|index| own_packets[*index].take())
+        {
+            Some(own_packet) => own_packet,
+            None => {
+                diff.differences.push(ValidationDifference::MissingFromOwn {
+                    uuid: peer_packet.uuid,
+                });
+                continue;
+            }
+        };
+        let is_valid = match field {
+            PrioField::Field32 => is_valid_packet::<Field32>(&own_packet, &peer_packet)?,
+            PrioField::Field64 => is_valid_packet::<Field64>(&own_packet, &peer_packet)?,
+        };
+        if is_valid {
+            diff.valid_packet_count += 1;
+        } else {
+            diff.differences.push(ValidationDifference::InvalidProof {
+                uuid: peer_packet.uuid,
+            });
+        }
+    }
+
+    for own_packet in own_packets.into_iter().flatten() {
+        diff.differences
+            .push(ValidationDifference::MissingFromPeer {
+                uuid: own_packet.uuid,
+            });
+    }
+
+    Ok(diff)
+}
+
+/// Returns true if the validation packets show the packet's proof to be valid.
+fn is_valid_packet<F: EncodedField>(
+    own_packet: &ValidationPacket,
+    peer_packet: &ValidationPacket,
+) -> Result<bool> {
+    Ok(is_valid_share(
+        &VerificationMessage::<F>::try_from(own_packet)?,
+        &VerificationMessage::<F>::try_from(peer_packet)?,
+    ))
+}
+
+fn write_line<H: Serialize, P: Serialize, W: Write>(
+    output: &mut W,
+    line: &Line<'_, H, P>,
+) -> Result<()> {
+    serde_json::to_writer(&mut *output, line).context("failed to write JSON")?;
+    output.write_all(b"\n").context("failed to write JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        batch::{Batch, BatchWriter},
+        idl::{IngestionDataSharePacket, IngestionHeader},
+        logging::setup_test_logging,
+        signing::BatchSigner,
+        test_utils::{
+            default_facilitator_signing_private_key, default_facilitator_signing_public_key,
+            default_ingestor_private_key, default_ingestor_public_key,
+            default_pha_signing_private_key, default_pha_signing_public_key,
+        },
+        transport::LocalFileTransport,
+    };
+    use assert_matches::assert_matches;
+    use chrono::NaiveDateTime;
+    use std::path::Path;
+
+    fn packets() -> Vec<IngestionDataSharePacket> {
+        vec![
+            IngestionDataSharePacket {
+                uuid: Uuid::new_v4(),
+                encrypted_payload: vec![0u8, 1u8],
+                encryption_key_id: Some("fake-key-1".to_owned()),
+                r_pit: 1,
+                version_configuration: None,
+                device_nonce: None,
+                encryption_ciphersuite: None,
+            },
+            IngestionDataSharePacket {
+                uuid: Uuid::new_v4(),
+                encrypted_payload: vec![2u8, 3u8],
+                encryption_key_id: None,
+                r_pit: 2,
+                version_configuration: Some("config-1".to_owned()),
+                device_nonce: Some(vec![4u8]),
+                encryption_ciphersuite: None,
+            },
+        ]
+    }
+
+    fn batch() -> Batch {
+        Batch::new_ingestion(
+            "fake-aggregation",
+            &Uuid::parse_str("f3b8f8b4-0b5e-4f5c-9c3f-4a1d4bd5b3a1").unwrap(),
+            &NaiveDateTime::from_timestamp(2234567890, 654321),
+        )
+    }
+
+    /// Writes an ingestion batch containing the provided packets to `path`,
+    /// signed with the default ingestor key. If `corrupt_digest` is true, the
+    /// header's packet_file_digest will not match the packet file.
+    fn write_batch(
+        path: &Path,
+        packets: &[IngestionDataSharePacket],
+        corrupt_digest: bool,
+    ) -> IngestionHeader {
+        let mut transport = LocalFileTransport::new(path.to_path_buf());
+        let mut writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(batch(), &mut transport, "trace-id");
+        let packet_file_digest = writer
+            .packet_file_writer(|packet_writer| {
+                for packet in packets {
+                    packet.write(packet_writer)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        let mut packet_file_digest = packet_file_digest.as_ref().to_vec();
+        if corrupt_digest {
+            packet_file_digest[0] ^= 1;
+        }
+        let header = IngestionHeader {
+            batch_uuid: Uuid::new_v4(),
+            name: "fake-aggregation".to_owned(),
+            bins: 2,
+            epsilon: 1.601,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 789456123,
+            batch_end_time: 789456321,
+            packet_file_digest,
+        };
+        let signature = writer
+            .put_header(&header, &default_ingestor_private_key())
+            .unwrap();
+        writer.put_signature(&signature, "key-identifier").unwrap();
+        header
+    }
+
+    fn public_keys(
+        public_key: UnparsedPublicKey<Vec<u8>>,
+    ) -> HashMap<String, UnparsedPublicKey<Vec<u8>>> {
+        let mut public_keys = HashMap::new();
+        public_keys.insert("key-identifier".to_owned(), public_key);
+        public_keys
+    }
+
+    #[test]
+    fn inspect_ingestion_batch() {
+        let logger = setup_test_logging();
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let packets = packets();
+        let header = write_batch(tempdir.path(), &packets, false);
+
+        let inspect = |public_key: UnparsedPublicKey<Vec<u8>>| {
+            let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+            let mut reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(batch(), &mut transport, true, "trace-id", &logger);
+            let mut output = Vec::new();
+            let packet_count =
+                inspect_batch(&mut reader, &public_keys(public_key), &mut output).unwrap();
+            assert_eq!(packet_count, packets.len());
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect::<Vec<serde_json::Value>>()
+        };
+
+        let lines = inspect(default_ingestor_public_key());
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["header"], serde_json::to_value(&header).unwrap());
+        assert_eq!(
+            lines[1]["signature"],
+            serde_json::json!({"status": "valid", "key_identifier": "key-identifier"})
+        );
+        for (line, packet) in lines[2..].iter().zip(&packets) {
+            assert_eq!(
+                serde_json::from_value::<IngestionDataSharePacket>(line["packet"].clone()).unwrap(),
+                *packet
+            );
+        }
+
+        // A batch whose signature doesn't verify can still be inspected
+        let lines = inspect(default_facilitator_signing_public_key());
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[1]["signature"],
+            serde_json::json!({"status": "invalid", "key_identifier": "key-identifier"})
+        );
+    }
+
+    #[test]
+    fn validate_valid_batch() {
+        let logger = setup_test_logging();
+        let tempdir = tempfile::TempDir::new().unwrap();
+        write_batch(tempdir.path(), &packets(), false);
+
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(batch(), &mut transport, false, "trace-id", &logger);
+        let report = validate_batch(
+            &mut reader,
+            &public_keys(default_ingestor_public_key()),
+            Some(2),
+        )
+        .unwrap();
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(report.packet_count, 2);
+    }
+
+    #[test]
+    fn validate_invalid_batch() {
+        let logger = setup_test_logging();
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut packets = packets();
+        let duplicate_uuid = packets[0].uuid;
+        for r_pit in 3..5 {
+            packets.push(IngestionDataSharePacket {
+                uuid: duplicate_uuid,
+                encrypted_payload: vec![5u8],
+                encryption_key_id: None,
+                r_pit,
+                version_configuration: None,
+                device_nonce: None,
+                encryption_ciphersuite: None,
+            });
+        }
+        write_batch(tempdir.path(), &packets, true);
+
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(batch(), &mut transport, false, "trace-id", &logger);
+        let report = validate_batch(&mut reader, &HashMap::new(), Some(2)).unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.packet_count, 4);
+        assert_eq!(report.violations.len(), 4, "{:?}", report);
+        assert_eq!(
+            report.violations[0],
+            Violation::HeaderSignature(SignatureStatus::UnknownKey {
+                key_identifier: "key-identifier".to_owned()
+            })
+        );
+        assert_matches!(
+            report.violations[1],
+            Violation::PacketFileDigest(DigestStatus::Mismatch { .. })
+        );
+        assert_eq!(
+            report.violations[2],
+            Violation::DuplicatePacketUuid {
+                uuid: duplicate_uuid
+            }
+        );
+        assert_eq!(
+            report.violations[3],
+            Violation::PacketCount {
+                expected: 2,
+                actual: 4
+            }
+        );
+
+        // The report is machine readable
+        assert_eq!(
+            serde_json::to_value(&report.violations[0]).unwrap(),
+            serde_json::json!({
+                "violation": "header_signature",
+                "status": "unknown_key",
+                "key_identifier": "key-identifier",
+            })
+        );
+    }
+
+    #[test]
+    fn diff_validations() {
+        let logger = setup_test_logging();
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let batch_uuid = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(2234567890, 654321);
+        let uuids: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let packet = |uuid: Uuid, f_r: i64, g_r: i64, h_r: i64| ValidationPacket {
+            uuid,
+            f_r,
+            g_r,
+            h_r,
+        };
+
+        // Combined, (1, 1, 1) and (1, 2, 5) give 2 * 3 == 6, a valid proof,
+        // and (1, 1, 1) and (1, 1, 1) give 2 * 2 != 2, an invalid one.
+        let own_packets = vec![
+            packet(uuids[0], 1, 1, 1),
+            packet(uuids[1], 1, 1, 1),
+            packet(uuids[2], 1, 1, 1),
+            packet(uuids[3], 1, 1, 1),
+        ];
+        let peer_packets = vec![
+            packet(uuids[4], 1, 2, 5),
+            packet(uuids[2], 1, 1, 1),
+            packet(uuids[0], 1, 2, 5),
+            packet(uuids[1], 1, 2, 5),
+        ];
+
+        let write = |is_first: bool, packets: &[ValidationPacket], signer: &dyn BatchSigner| {
+            let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+            let mut writer: BatchWriter<'_, ValidationHeader, ValidationPacket> = BatchWriter::new(
+                Batch::new_validation("fake-aggregation", &batch_uuid, &date, is_first),
+                &mut transport,
+                "trace-id",
+            );
+            let packet_file_digest = writer
+                .packet_file_writer(|packet_writer| {
+                    for packet in packets {
+                        packet.write(packet_writer)?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            let header = ValidationHeader {
+                batch_uuid,
+                name: "fake-aggregation".to_owned(),
+                bins: 2,
+                epsilon: 1.601,
+                prime: PrioField::Field32.prime(),
+                number_of_servers: 2,
+                hamming_weight: None,
+                packet_file_digest: packet_file_digest.as_ref().to_vec(),
+                skipped_packet_count: 0,
+            };
+            let signature = writer.put_header(&header, signer).unwrap();
+            writer.put_signature(&signature, "key-identifier").unwrap();
+        };
+        write(true, &own_packets, &default_pha_signing_private_key());
+        write(
+            false,
+            &peer_packets,
+            &default_facilitator_signing_private_key(),
+        );
+
+        let mut own_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut own_reader = BatchReader::new(
+            Batch::new_validation("fake-aggregation", &batch_uuid, &date, true),
+            &mut own_transport,
+            false,
+            "trace-id",
+            &logger,
+        );
+        let mut peer_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let mut peer_reader = BatchReader::new(
+            Batch::new_validation("fake-aggregation", &batch_uuid, &date, false),
+            &mut peer_transport,
+            false,
+            "trace-id",
+            &logger,
+        );
+        let diff = diff_validation_batches(
+            &mut own_reader,
+            &public_keys(default_pha_signing_public_key()),
+            &mut peer_reader,
+            &public_keys(default_facilitator_signing_public_key()),
+        )
+        .unwrap();
+
+        assert!(!diff.is_empty());
+        assert_eq!(
+            diff,
+            ValidationDiff {
+                parameters_match: true,
+                own_packet_count: 4,
+                peer_packet_count: 4,
+                valid_packet_count: 2,
+                differences: vec![
+                    ValidationDifference::MissingFromOwn { uuid: uuids[4] },
+                    ValidationDifference::InvalidProof { uuid: uuids[2] },
+                    ValidationDifference::MissingFromPeer { uuid: uuids[3] },
+                ],
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&diff.differences[0]).unwrap(),
+            serde_json::json!({"difference": "missing_from_own", "uuid": uuids[4]})
+        );
+    }
+}
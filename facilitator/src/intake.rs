@@ -1,41 +1,148 @@
 use crate::{
-    batch::{Batch, BatchReader, BatchWriter},
+    batch::{Batch, BatchReader, BatchWriter, DigestStatus, SignatureStatus},
+    dedup::PacketDeduplicator,
+    field::{EncodedField, PrioField},
+    hex_dump,
+    hpke::HpkePrivateKey,
     idl::{IngestionDataSharePacket, IngestionHeader, Packet, ValidationHeader, ValidationPacket},
+    ledger::{BatchLedger, LedgerEntry},
     logging::event,
     metrics::IntakeMetricsCollector,
-    transport::{SignableTransport, VerifiableAndDecryptableTransport},
-    BatchSigningKey, Error, DATE_FORMAT,
+    packet_encryption::HpkePacketDecryptor,
+    prio_crypto::{PrioBackend, PrioCrypto},
+    signing::BatchSigner,
+    transport::{
+        is_already_exists_error, SignableTransport, Transport, VerifiableAndDecryptableTransport,
+    },
+    CryptoError, DigestAlgorithm, Error, TaskError, ValidationError, DATE_FORMAT,
 };
 use anyhow::{anyhow, ensure, Context, Result};
-use chrono::NaiveDateTime;
+use avro_rs::Codec;
+use chrono::{prelude::Utc, DateTime, NaiveDateTime};
+use crossbeam_utils::thread;
 use prio::{
     encrypt::{PrivateKey, PublicKey},
-    field::Field32,
-    server::{Server, ServerError},
+    field::{Field32, Field64},
+    server::{ServerError, VerificationMessage},
 };
-use ring::signature::UnparsedPublicKey;
-use slog::{debug, info, o, Logger};
-use std::{collections::HashMap, convert::TryFrom, iter::Iterator};
+use ring::{digest::Digest, signature::UnparsedPublicKey};
+use serde::Serialize;
+use slog::{debug, error, info, o, warn, Logger};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    iter::Iterator,
+    sync::mpsc::{self, Receiver, SyncSender},
+    time::Duration,
+};
+use tracing::{field, info_span, Span};
 use uuid::Uuid;
 
+/// Lists the ingestion batches for the aggregation in the provided transport
+/// and returns the date and UUID of each one that has not been recorded in the
+/// ledger, in the order they are listed. Batches are only returned once their
+/// signature, which ingestors write last, exists.
+pub fn unprocessed_ingestion_batches(
+    ingestion_transport: &mut dyn Transport,
+    aggregation_name: &str,
+    ledger: &mut dyn BatchLedger,
+    trace_id: &str,
+) -> Result<Vec<(NaiveDateTime, Uuid)>> {
+    let mut batches = vec![];
+    for key in ingestion_transport.list(&format!("{}/", aggregation_name), trace_id)? {
+        let (key_aggregation_name, date, batch_id) =
+            match Batch::parse_ingestion_signature_key(&key) {
+                Some(batch) => batch,
+                None => continue,
+            };
+        if key_aggregation_name == aggregation_name && ledger.get(&batch_id, trace_id)?.is_none() {
+            batches.push((date, batch_id));
+        }
+    }
+    Ok(batches)
+}
+
+/// Returns true if the provided error, as returned by
+/// BatchIntaker::generate_validation_share, was caused by a packet that could
+/// not be decrypted with any of the packet decryption keys, which may mean that
+/// the keys were rotated since they were obtained. The decryption failure may
+/// be the cause of an error that was classified otherwise, e.g. as
+/// TaskError::TooManyMalformedPackets.
+pub fn is_packet_decryption_error(error: &Error) -> bool {
+    let error = match error {
+        Error::AnyhowError(error)
+        | Error::Transport(_, error)
+        | Error::Validation(_, error)
+        | Error::Crypto(_, error)
+        | Error::Task(_, error) => error,
+        _ => return false,
+    };
+    matches!(
+        error.downcast_ref::<CryptoError>(),
+        Some(CryptoError::PacketDecryption(_))
+    )
+}
+
+/// A summary of an ingestion batch intaken in validate-only mode.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct IntakeSummary {
+    pub batch_uuid: Uuid,
+    pub name: String,
+    pub bins: i32,
+    pub hamming_weight: Option<i32>,
+    /// Number of packets for which validation packets were generated
+    pub packet_count: u64,
+    /// Number of duplicate packets that were dropped
+    pub duplicate_packet_count: u64,
+    /// Number of malformed packets that were skipped
+    pub skipped_packet_count: u64,
+}
+
 /// BatchIntaker is responsible for validating a batch of data packet shares
 /// sent by the ingestion server and emitting validation shares to the other
 /// share processor.
 pub struct BatchIntaker<'a> {
+    trace_id: &'a str,
     intake_batch: BatchReader<'a, IngestionHeader, IngestionDataSharePacket>,
+    intake_batch_path: String,
     intake_public_keys: &'a HashMap<String, UnparsedPublicKey<Vec<u8>>>,
     packet_decryption_keys: &'a Vec<PrivateKey>,
+    hpke_packet_decryption_keys: &'a Vec<HpkePrivateKey>,
     peer_validation_batch: BatchWriter<'a, ValidationHeader, ValidationPacket>,
-    peer_validation_batch_signing_key: &'a BatchSigningKey,
+    peer_validation_batch_signer: &'a dyn BatchSigner,
     own_validation_batch: BatchWriter<'a, ValidationHeader, ValidationPacket>,
-    own_validation_batch_signing_key: &'a BatchSigningKey,
+    own_validation_batch_signer: &'a dyn BatchSigner,
     is_first: bool,
     callback_cadence: u32,
     metrics_collector: Option<&'a IntakeMetricsCollector>,
+    ingestion_server: String,
     use_bogus_packet_file_digest: bool,
+    batch_ledger: Option<&'a mut dyn BatchLedger>,
+    packet_deduplicator: Option<&'a mut PacketDeduplicator>,
+    packet_decryption_key_identifiers: HashMap<String, usize>,
+    peer_packet_encryption_key_versions: Option<HashSet<String>>,
+    verify_threads: usize,
+    prio_backend: PrioBackend,
+    validate_only: bool,
+    max_skipped_packets_percent: Option<f64>,
+    max_batch_future_skew: Option<Duration>,
+    max_batch_age: Option<Duration>,
+    summary: Option<IntakeSummary>,
+    span: Span,
     logger: Logger,
 }
 
+/// The ingestion server with which intake metrics are labeled until the
+/// ingestion batch's signature has been verified.
+const UNKNOWN_INGESTION_SERVER: &str = "unknown";
+
+/// How many ingestion packets each verification thread is given at a time.
+const PACKETS_PER_VERIFY_THREAD: usize = 256;
+
+/// How many chunks of ingestion packets may be waiting for or undergoing
+/// verification at once during intake.
+const PIPELINE_DEPTH: usize = 2;
+
 impl<'a> BatchIntaker<'a> {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -50,18 +157,40 @@ impl<'a> BatchIntaker<'a> {
         permit_malformed_batch: bool,
         parent_logger: &Logger,
     ) -> Result<BatchIntaker<'a>> {
+        let span = info_span!(
+            "intake_batch",
+            trace_id,
+            aggregation_name,
+            batch_id = %batch_id,
+            batch_date = %date.format(DATE_FORMAT),
+            ingestion_server = field::Empty,
+        );
         let logger = parent_logger.new(o!(
-            event::TRACE_ID => trace_id.to_owned(),
-            event::AGGREGATION_NAME => aggregation_name.to_owned(),
-            event::BATCH_ID => batch_id.to_string(),
-            event::BATCH_DATE => date.format(DATE_FORMAT).to_string(),
             event::INGESTION_PATH => ingestion_transport.transport.transport.path(),
             event::OWN_VALIDATION_PATH => own_validation_transport.transport.path(),
             event::PEER_VALIDATION_PATH => peer_validation_transport.transport.path(),
         ));
+        // Validation batches are never overwritten, since the peer may already
+        // have read them.
+        let mut peer_validation_batch = BatchWriter::new(
+            Batch::new_validation(aggregation_name, batch_id, date, is_first),
+            &mut *peer_validation_transport.transport,
+            trace_id,
+        );
+        peer_validation_batch.set_create_only(true);
+        let mut own_validation_batch = BatchWriter::new(
+            Batch::new_validation(aggregation_name, batch_id, date, is_first),
+            &mut *own_validation_transport.transport,
+            trace_id,
+        );
+        own_validation_batch.set_create_only(true);
+        let intake_batch = Batch::new_ingestion(aggregation_name, batch_id, date);
+
         Ok(BatchIntaker {
+            trace_id,
+            intake_batch_path: intake_batch.header_key().to_owned(),
             intake_batch: BatchReader::new(
-                Batch::new_ingestion(aggregation_name, batch_id, date),
+                intake_batch,
                 &mut *ingestion_transport.transport.transport,
                 permit_malformed_batch,
                 trace_id,
@@ -69,26 +198,45 @@ impl<'a> BatchIntaker<'a> {
             ),
             intake_public_keys: &ingestion_transport.transport.batch_signing_public_keys,
             packet_decryption_keys: &ingestion_transport.packet_decryption_keys,
-            peer_validation_batch: BatchWriter::new(
-                Batch::new_validation(aggregation_name, batch_id, date, is_first),
-                &mut *peer_validation_transport.transport,
-                trace_id,
-            ),
-            own_validation_batch: BatchWriter::new(
-                Batch::new_validation(aggregation_name, batch_id, date, is_first),
-                &mut *own_validation_transport.transport,
-                trace_id,
-            ),
-            peer_validation_batch_signing_key: &peer_validation_transport.batch_signing_key,
-            own_validation_batch_signing_key: &own_validation_transport.batch_signing_key,
+            hpke_packet_decryption_keys: &ingestion_transport.hpke_packet_decryption_keys,
+            peer_validation_batch,
+            own_validation_batch,
+            peer_validation_batch_signer: peer_validation_transport.batch_signer.as_ref(),
+            own_validation_batch_signer: own_validation_transport.batch_signer.as_ref(),
             is_first,
             callback_cadence: 1000,
             metrics_collector: None,
+            ingestion_server: UNKNOWN_INGESTION_SERVER.to_owned(),
             use_bogus_packet_file_digest: false,
+            batch_ledger: None,
+            packet_deduplicator: None,
+            packet_decryption_key_identifiers: HashMap::new(),
+            peer_packet_encryption_key_versions: None,
+            verify_threads: 1,
+            prio_backend: PrioBackend::default(),
+            validate_only: false,
+            max_skipped_packets_percent: None,
+            max_batch_future_skew: None,
+            max_batch_age: None,
+            summary: None,
+            span,
             logger,
         })
     }
 
+    /// Sets the number of threads across which verification of the packets in
+    /// the batch is spread. Packets are written to the validation batches in
+    /// the same order regardless of the number of threads.
+    pub fn set_verify_threads(&mut self, threads: usize) {
+        self.verify_threads = threads.max(1);
+    }
+
+    /// Sets the implementation of the Prio cryptography with which validation
+    /// shares are generated. Defaults to libprio-rs.
+    pub fn set_prio_backend(&mut self, backend: PrioBackend) {
+        self.prio_backend = backend;
+    }
+
     /// Set the cadence at which the callback passed to
     /// generate_validation_share is invoked, i.e., after how many processed
     /// packets. This function is not safe to call while a call to
@@ -110,120 +258,547 @@ impl<'a> BatchIntaker<'a> {
         self.use_bogus_packet_file_digest = bogus;
     }
 
+    /// Provide a ledger of processed batches. Before any validation batches
+    /// are written, the ingestion batch is recorded in the ledger, and if its
+    /// UUID was already recorded for a different batch, it is rejected so that
+    /// its contributions are not counted twice.
+    pub fn set_batch_ledger(&mut self, ledger: &'a mut dyn BatchLedger) {
+        self.batch_ledger = Some(ledger);
+    }
+
+    /// Provide a deduplicator with which to drop duplicate ingestion packets,
+    /// both within the batch and across the batches previously intaken with
+    /// the same deduplicator. The batch's packets are only inserted into the
+    /// deduplicator once its validation batches have been written, so a failed
+    /// attempt at a task does not cause a retry to drop every packet.
+    pub fn set_packet_deduplicator(&mut self, deduplicator: &'a mut PacketDeduplicator) {
+        self.packet_deduplicator = Some(deduplicator);
+    }
+
+    /// Provide the identifiers of the packet decryption keys, as a map of key
+    /// identifier to the index of the key in the ingestion transport's
+    /// packet_decryption_keys, e.g. as obtained from
+    /// SpecificManifest::packet_decryption_key_identifiers. Packets whose
+    /// encryption_key_id is one of these identifiers are decrypted with that
+    /// key first, and the other keys are only tried if it fails.
+    pub fn set_packet_decryption_key_identifiers(&mut self, identifiers: HashMap<String, usize>) {
+        self.packet_decryption_key_identifiers = identifiers;
+    }
+
+    /// Provide the packet encryption key versions advertised in the peer data
+    /// share processor's global manifest. The ingestion header does not name a
+    /// key version, so the encryption_key_id of each packet is checked instead,
+    /// and the batch is rejected if any packet claims a version the peer has
+    /// not advertised, since the peer may not yet be ready to process its
+    /// share of that packet.
+    pub fn set_peer_packet_encryption_key_versions(&mut self, versions: &[String]) {
+        self.peer_packet_encryption_key_versions = Some(versions.iter().cloned().collect());
+    }
+
+    /// Sets the algorithm used to compute the packet file digest in the
+    /// headers of the validation batches this BatchIntaker writes. This should
+    /// be the algorithm advertised in this data share processor's specific
+    /// manifest. Defaults to SHA-256.
+    pub fn set_digest_algorithm(&mut self, algorithm: DigestAlgorithm) {
+        self.peer_validation_batch.set_digest_algorithm(algorithm);
+        self.own_validation_batch.set_digest_algorithm(algorithm);
+    }
+
+    /// Sets the codec with which the blocks of the packet files of the
+    /// validation batches this BatchIntaker writes are compressed. The peer
+    /// must be able to decompress them. Defaults to no compression.
+    pub fn set_packet_file_codec(&mut self, codec: Codec) {
+        self.peer_validation_batch.set_packet_file_codec(codec);
+        self.own_validation_batch.set_packet_file_codec(codec);
+    }
+
+    /// Sets whether this BatchIntaker only validates the ingestion batch. In
+    /// validate-only mode, the ingestion batch is checked, its packets are
+    /// decrypted and validation packets are generated for them as usual, but
+    /// nothing is written to the validation transports, the batch is not
+    /// recorded in the batch ledger and its packets are not inserted into the
+    /// packet deduplicator. A summary of the batch may be retrieved with
+    /// summary once generate_validation_share returns.
+    pub fn set_validate_only(&mut self, validate_only: bool) {
+        self.validate_only = validate_only;
+    }
+
+    /// Allows intake to skip packets that cannot be decrypted or for which no
+    /// validation packet can otherwise be generated, as long as no more than
+    /// the provided percentage of the batch's packets are skipped. Skipped
+    /// packets are left out of the validation batches, so the peer rejects
+    /// them during aggregation, and counted in the validation header. By
+    /// default, any such packet causes intake of the whole batch to fail.
+    pub fn set_max_skipped_packets_percent(&mut self, percent: f64) {
+        self.max_skipped_packets_percent = Some(percent);
+    }
+
+    /// Rejects ingestion batches whose end time is more than the provided
+    /// duration after the current time. Ingestion servers' clocks may drift
+    /// from ours, so this should allow for some skew. The resulting
+    /// error is retryable, since the batch will be accepted once our clock
+    /// catches up. By default, batches are accepted however far in the future
+    /// they end.
+    pub fn set_max_batch_future_skew(&mut self, skew: Duration) {
+        self.max_batch_future_skew = Some(skew);
+    }
+
+    /// Rejects ingestion batches whose end time is more than the provided
+    /// duration before the current time. By default, batches are accepted
+    /// however old they are.
+    pub fn set_max_batch_age(&mut self, age: Duration) {
+        self.max_batch_age = Some(age);
+    }
+
+    /// Returns a summary of the ingestion batch, if it was intaken in
+    /// validate-only mode.
+    pub fn summary(&self) -> Option<&IntakeSummary> {
+        self.summary.as_ref()
+    }
+
     /// Fetches the ingestion batch, validates the signatures over its header
     /// and packet file, then computes validation shares and sends them to the
     /// peer share processor. The provided callback is invoked once for every
     /// thousand processed packets, unless set_callback_cadence has been called.
-    pub fn generate_validation_share<F>(&mut self, mut callback: F) -> Result<()>
+    /// If the callback returns an error, intake is abandoned, any uploads in
+    /// progress are cancelled and the error is returned. If both validation
+    /// batches were already written, e.g. by an earlier attempt at the same
+    /// task, and match the ingestion batch, they are left alone and this
+    /// succeeds without doing anything.
+    /// Failures are classified with Error::classify.
+    pub fn generate_validation_share<F>(&mut self, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(&Logger) -> Result<()>,
+    {
+        let _span = self.span.clone().entered();
+        self.generate_validation_share_impl(callback).map_err(|e| {
+            let error = Error::classify(e);
+            self.record_rejection(&error);
+            error
+        })
+    }
+
+    /// Counts the ingestion batch as rejected if intake failed with an error
+    /// that retrying the task can't fix.
+    fn record_rejection(&self, error: &Error) {
+        let collector = match self.metrics_collector {
+            Some(collector) if !error.is_retryable() => collector,
+            _ => return,
+        };
+        let reason = match error {
+            Error::Validation(..) => "validation",
+            Error::Crypto(..) => "crypto",
+            Error::Task(..) => "task",
+            _ => return,
+        };
+        collector
+            .batches_rejected
+            .with_label_values(&[&self.ingestion_server, reason])
+            .inc();
+    }
+
+    /// Identifies the ingestion server that wrote the ingestion batch by the
+    /// key from its manifest that the batch's signature was verified with.
+    /// Intake metrics and the batch's span are labeled with it from then on.
+    fn identify_ingestion_server(&mut self, signature_status: &SignatureStatus) {
+        if let Some(key_identifier) = signature_status.verifying_key_identifier() {
+            self.ingestion_server = key_identifier.to_owned();
+            self.span.record(event::INGESTION_SERVER, &key_identifier);
+        }
+    }
+
+    fn generate_validation_share_impl<F>(&mut self, callback: F) -> Result<()>
     where
-        F: FnMut(&Logger),
+        F: FnMut(&Logger) -> Result<()>,
     {
         info!(self.logger, "processing batch intake task");
 
-        let ingestion_header = self.intake_batch.header(self.intake_public_keys)?;
-        ensure!(
-            ingestion_header.bins > 0,
-            "invalid bin count {}",
-            ingestion_header.bins
-        );
+        if self.validate_only {
+            return self.write_validation_batches(callback);
+        }
+
+        if self.validation_batches_complete()? {
+            self.check_existing_validation_batches()?;
+            info!(self.logger, "validation batches already exist");
+            return Ok(());
+        }
+
+        match self.write_validation_batches(callback) {
+            // Another worker may have handled the same task concurrently
+            Err(e) if is_already_exists_error(&e) && self.validation_batches_complete()? => {
+                self.check_existing_validation_batches()?;
+                info!(self.logger, "validation batches were written concurrently");
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn validation_batches_complete(&mut self) -> Result<bool> {
+        Ok(self.peer_validation_batch.is_complete()? && self.own_validation_batch.is_complete()?)
+    }
+
+    /// Checks that the validation batches that already exist for this task
+    /// describe the ingestion batch, were signed with our keys and have packet
+    /// files matching their headers' digests, so that intake may be skipped.
+    /// Fails if they do not, since they may not be overwritten.
+    fn check_existing_validation_batches(&mut self) -> Result<()> {
+        let (ingestion_header, _, signature_status) = self
+            .intake_batch
+            .header_with_digest(self.intake_public_keys)?;
+        self.identify_ingestion_server(&signature_status);
+        for (batch, signer) in [
+            (
+                &mut self.peer_validation_batch,
+                self.peer_validation_batch_signer,
+            ),
+            (
+                &mut self.own_validation_batch,
+                self.own_validation_batch_signer,
+            ),
+        ] {
+            let (header, digest_status) = batch.existing_header(signer.key_identifier())?;
+            if !ingestion_header.check_parameters(&header) {
+                return Err(ValidationError::HeaderMismatch(format!(
+                    "ingestion header does not match existing validation header. Ingestion: {:?}\nValidation: {:?}",
+                    ingestion_header, header
+                ))
+                .into());
+            }
+            if let DigestStatus::Mismatch {
+                header_digest,
+                actual_digest,
+            } = digest_status
+            {
+                // A bogus digest is expected not to match
+                if !self.use_bogus_packet_file_digest {
+                    return Err(ValidationError::PacketFileDigestMismatch {
+                        header_digest,
+                        actual_digest,
+                    }
+                    .into());
+                }
+            }
+        }
+
+        if let Some(collector) = self.metrics_collector {
+            collector
+                .intake_tasks_already_processed
+                .with_label_values(&[&self.ingestion_server])
+                .inc();
+        }
+        Ok(())
+    }
+
+    fn write_validation_batches<F>(&mut self, callback: F) -> Result<()>
+    where
+        F: FnMut(&Logger) -> Result<()>,
+    {
+        let (ingestion_header, header_digest, signature_status) =
+            info_span!("read_ingestion_header").in_scope(|| {
+                self.intake_batch
+                    .header_with_digest(self.intake_public_keys)
+            })?;
+        self.identify_ingestion_server(&signature_status);
+        let field = PrioField::from_prime(ingestion_header.prime)
+            .map_err(|e| ValidationError::Malformed(format!("{:#}", e)))?;
+        field
+            .check_bins(ingestion_header.bins)
+            .map_err(|e| ValidationError::Malformed(format!("{:#}", e)))?;
+        self.check_batch_time(&ingestion_header, Utc::now())?;
+        if !self.validate_only {
+            self.record_in_batch_ledger(&ingestion_header, &header_digest)?;
+        }
 
-        // Ideally, we would use the encryption_key_id in the ingestion packet
-        // to figure out which private key to use for decryption, but that field
-        // is optional. Instead we try all the keys we have available until one
-        // works.
+        match field {
+            PrioField::Field32 => {
+                self.write_validation_batches_in_field::<Field32, _>(ingestion_header, callback)
+            }
+            PrioField::Field64 => {
+                self.write_validation_batches_in_field::<Field64, _>(ingestion_header, callback)
+            }
+        }
+    }
+
+    /// Generates validation packets for the ingestion batch, whose packets
+    /// are encoded in the field F, and writes them out.
+    fn write_validation_batches_in_field<F, C>(
+        &mut self,
+        ingestion_header: IngestionHeader,
+        mut callback: C,
+    ) -> Result<()>
+    where
+        F: EncodedField,
+        C: FnMut(&Logger) -> Result<()>,
+    {
+        // We use the encryption_key_id in the ingestion packet, if present and
+        // known, to figure out which private key to use for decryption. That
+        // field is optional, and during key rotation clients may encrypt to
+        // any of our keys, so failing that we try all the keys we have
+        // available until one works.
         // https://github.com/abetterinternet/prio-server/issues/73
-        let mut servers: Vec<Server<Field32>> = self
-            .packet_decryption_keys
-            .iter()
-            .map(|k| {
-                debug!(
-                    self.logger,
-                    "Public key for server is: {:?}",
-                    PublicKey::from(k)
-                );
-                Server::new(ingestion_header.bins as usize, self.is_first, k.clone())
+        for key in self.packet_decryption_keys {
+            debug!(
+                self.logger,
+                "Public key for server is: {:?}",
+                PublicKey::from(key)
+            );
+        }
+        debug!(
+            self.logger,
+            "We have {} servers.",
+            self.packet_decryption_keys.len()
+        );
+        // Packets encrypted with HPKE rather than ECIES are decrypted by an
+        // HpkePacketDecryptor and verified by its server, which follows those
+        // for the ECIES keys.
+        let hpke_decryptor = if self.hpke_packet_decryption_keys.is_empty() {
+            None
+        } else {
+            Some(HpkePacketDecryptor::new(self.hpke_packet_decryption_keys)?)
+        };
+        // Each verification thread needs its own servers, since they hold
+        // scratch memory used during verification.
+        let prio_backend = self.prio_backend;
+        let mut thread_servers: Vec<Vec<Box<dyn PrioCrypto<F>>>> = (0..self.verify_threads)
+            .map(|_| {
+                self.packet_decryption_keys
+                    .iter()
+                    .map(|k| {
+                        prio_backend.server(
+                            ingestion_header.bins as usize,
+                            self.is_first,
+                            k.clone(),
+                        )
+                    })
+                    .chain(hpke_decryptor.iter().map(|decryptor| {
+                        decryptor.server(
+                            prio_backend,
+                            ingestion_header.bins as usize,
+                            self.is_first,
+                        )
+                    }))
+                    .collect()
             })
             .collect();
 
-        debug!(self.logger, "We have {} servers.", &servers.len());
-
         // Read all the ingestion packets, generate a verification message for
         // each, and write them to the validation batch.
+        let validation_packets_span = info_span!("generate_validation_packets").entered();
         let mut ingestion_packet_reader =
             self.intake_batch.packet_file_reader(&ingestion_header)?;
+        let packet_file_size = ingestion_packet_reader.packet_file_size();
 
         let mut processed_packets = 0;
+        // UUIDs of the packets in this batch, if we are dropping duplicates
+        let mut batch_packet_uuids = HashSet::new();
+        let mut duplicate_packets = 0;
+        let mut skipped_packets: u64 = 0;
+        // The error for the first skipped packet, reported if too many are
+        // skipped
+        let mut first_skipped_packet_error = None;
         // Borrowing distinct parts of a struct works, but not under closures:
         // https://github.com/rust-lang/rust/issues/53488
         // The workaround is to borrow or copy fields outside the closure.
         let callback_cadence = self.callback_cadence;
         let logger = &self.logger;
+        let mut deduplicator = self.packet_deduplicator.as_deref_mut();
+        let key_identifiers = &self.packet_decryption_key_identifiers;
+        let hpke_decryptor = hpke_decryptor.as_ref();
+        let peer_key_versions = self.peer_packet_encryption_key_versions.as_ref();
+        let max_skipped_packets_percent = self.max_skipped_packets_percent;
+
+        // Generates validation packets for the ingestion packets and passes
+        // them to write_packet. Transports can't be shared across threads, so
+        // ingestion packets are read and validation packets written on this
+        // thread, while a verifier thread generates validation packets for the
+        // chunks of packets in between. That way reading, verification and
+        // writing all proceed concurrently.
+        let mut generate_packets =
+            |write_packet: &mut dyn FnMut(&ValidationPacket) -> Result<()>| {
+                let chunk_size = PACKETS_PER_VERIFY_THREAD * thread_servers.len();
+                let thread_servers = &mut thread_servers;
+
+                // Reads the next chunk of ingestion packets, which are
+                // deduplicated and checked in order, or returns None once they
+                // have all been read.
+                let mut read_chunk = || -> Result<Option<Vec<IngestionDataSharePacket>>> {
+                    let mut chunk = Vec::with_capacity(chunk_size);
+                    for packet in ingestion_packet_reader.by_ref() {
+                        let packet = packet?;
+
+                        if let Some(deduplicator) = deduplicator.as_deref_mut() {
+                            if !batch_packet_uuids.insert(packet.uuid)
+                                || deduplicator.contains(&packet.uuid)?
+                            {
+                                info!(
+                                    logger, "dropping duplicate packet";
+                                    event::PACKET_UUID => packet.uuid.to_string()
+                                );
+                                duplicate_packets += 1;
+                                continue;
+                            }
+                        }
+
+                        if let (Some(peer_key_versions), Some(key_version)) =
+                            (peer_key_versions, &packet.encryption_key_id)
+                        {
+                            ensure!(
+                                peer_key_versions.contains(key_version),
+                                "packet {} claims packet encryption key version {}, which is \
+                                not advertised in the peer's global manifest",
+                                packet.uuid,
+                                key_version
+                            );
+                        }
 
-        let packet_file_digest = self.peer_validation_batch.multi_packet_file_writer(
-            vec![&mut self.own_validation_batch],
-            |mut packet_writer| loop {
-                let packet = match IngestionDataSharePacket::read(&mut ingestion_packet_reader) {
-                    Ok(p) => p,
-                    Err(Error::EofError) => return Ok(()),
-                    Err(e) => return Err(e.into()),
+                        chunk.push(packet);
+                        if chunk.len() == chunk_size {
+                            break;
+                        }
+                    }
+                    Ok(if chunk.is_empty() { None } else { Some(chunk) })
                 };
 
-                let r_pit = u32::try_from(packet.r_pit)
-                    .with_context(|| format!("illegal r_pit value {}", packet.r_pit))?;
-
-                // TODO(timg): if this fails for a non-empty subset of the
-                // ingestion packets, do we abort handling of the entire
-                // batch (as implemented currently) or should we record it
-                // as an invalid UUID and emit a validation batch for the
-                // other packets?
-                let mut did_create_validation_packet = false;
-                for server in servers.iter_mut() {
-                    let validation_message = match server.generate_verification_message(
-                        Field32::from(r_pit),
-                        &packet.encrypted_payload,
-                    ) {
-                        Ok(m) => m,
-                        Err(ServerError::Encrypt(e)) => {
-                            debug!(
-                                logger,
-                                "Input share could not be decrypted. Will try \
-                                more packet decryption keys if available.";
-                                o!(
-                                    "decryption_error" => format!("{:?}", e),
+                let mut write_chunk = |(chunk, results): (
+                    Vec<IngestionDataSharePacket>,
+                    Vec<Result<ValidationPacket>>,
+                )| {
+                    for (packet, result) in chunk.iter().zip(results) {
+                        let validation_packet = match result {
+                            Ok(validation_packet) => validation_packet,
+                            Err(e) if max_skipped_packets_percent.is_some() => {
+                                info!(
+                                    logger, "skipping malformed packet";
                                     event::PACKET_UUID => packet.uuid.to_string(),
-                                )
-                            );
-                            continue;
+                                    "error" => format!("{:?}", e),
+                                );
+                                skipped_packets += 1;
+                                first_skipped_packet_error.get_or_insert(e);
+                                continue;
+                            }
+                            Err(e) => return Err(e),
+                        };
+                        write_packet(&validation_packet)?;
+                        processed_packets += 1;
+                        if processed_packets % callback_cadence == 0 {
+                            callback(logger)?;
                         }
-                        Err(e) => {
-                            return Err(anyhow::Error::new(e)
-                                .context("error generating verification message"));
+                    }
+                    Ok(())
+                };
+
+                thread::scope(|scope| {
+                    let (chunk_sender, chunk_receiver) =
+                        mpsc::sync_channel::<Vec<IngestionDataSharePacket>>(PIPELINE_DEPTH);
+                    let (result_sender, result_receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+                    let span = Span::current();
+                    let verifier = scope.spawn(move |_| {
+                        let _span = span.enter();
+                        for chunk in chunk_receiver {
+                            let results = verify_packets(
+                                thread_servers,
+                                hpke_decryptor,
+                                &chunk,
+                                key_identifiers,
+                                logger,
+                            )
+                            .map(|results| (chunk, results));
+                            let failed = results.is_err();
+                            if result_sender.send(results).is_err() || failed {
+                                return;
+                            }
                         }
-                    };
-
-                    let packet = ValidationPacket {
-                        uuid: packet.uuid,
-                        f_r: u32::from(validation_message.f_r) as i64,
-                        g_r: u32::from(validation_message.g_r) as i64,
-                        h_r: u32::from(validation_message.h_r) as i64,
-                    };
-                    packet.write(&mut packet_writer)?;
-                    did_create_validation_packet = true;
-                    break;
-                }
-                if !did_create_validation_packet {
-                    return Err(anyhow!(
-                        "failed to construct validation message for packet {}, \
-                        probably due to packet decryption key mismatch",
-                        packet.uuid
-                    ));
-                }
-                processed_packets += 1;
-                if processed_packets % callback_cadence == 0 {
-                    callback(&logger);
+                    });
+
+                    let result = run_pipeline(
+                        &chunk_sender,
+                        &result_receiver,
+                        &mut read_chunk,
+                        &mut write_chunk,
+                    );
+                    // Hanging up lets the verifier finish even if we failed
+                    drop(chunk_sender);
+                    drop(result_receiver);
+                    verifier
+                        .join()
+                        .map_err(|_| anyhow!("packet verification thread panicked"))?;
+                    result
+                })
+                .map_err(|_| anyhow!("packet verification thread panicked"))??;
+
+                // Checked before the packet files are completed, so that
+                // their uploads are canceled if the batch is rejected
+                if let (Some(max_percent), Some(error)) = (
+                    max_skipped_packets_percent,
+                    first_skipped_packet_error.take(),
+                ) {
+                    let total_packets = u64::from(processed_packets) + skipped_packets;
+                    let skipped_percent = 100.0 * skipped_packets as f64 / total_packets as f64;
+                    if skipped_percent > max_percent {
+                        return Err(error.context(TaskError::TooManyMalformedPackets {
+                            skipped: skipped_packets,
+                            total: total_packets,
+                            max_percent,
+                        }));
+                    }
                 }
-            },
-        )?;
+                Ok(())
+            };
+
+        if self.validate_only {
+            generate_packets(&mut |_| Ok(()))?;
+            drop(validation_packets_span);
+            let summary = IntakeSummary {
+                batch_uuid: ingestion_header.batch_uuid,
+                name: ingestion_header.name,
+                bins: ingestion_header.bins,
+                hamming_weight: ingestion_header.hamming_weight,
+                packet_count: processed_packets.into(),
+                duplicate_packet_count: duplicate_packets,
+                skipped_packet_count: skipped_packets,
+            };
+            info!(
+                self.logger, "validated ingestion batch without writing validation batches";
+                "packet_count" => summary.packet_count,
+                "duplicate_packet_count" => summary.duplicate_packet_count,
+                "skipped_packet_count" => summary.skipped_packet_count,
+            );
+            self.summary = Some(summary);
+            return Ok(());
+        }
+
+        let packet_file_digest = self
+            .peer_validation_batch
+            .multi_packet_file_writer(vec![&mut self.own_validation_batch], |packet_writer| {
+                generate_packets(&mut |packet| Ok(packet.write(packet_writer)?))
+            })?;
+        drop(validation_packets_span);
+
+        if duplicate_packets > 0 {
+            info!(
+                self.logger,
+                "dropped {} duplicate packets", duplicate_packets
+            );
+        }
+        if skipped_packets > 0 {
+            warn!(self.logger, "skipped {} malformed packets", skipped_packets);
+        }
+        if let Some(collector) = self.metrics_collector {
+            let labels = [self.ingestion_server.as_str()];
+            collector
+                .duplicate_packets_dropped
+                .with_label_values(&labels)
+                .inc_by(duplicate_packets);
+            collector
+                .malformed_packets_skipped
+                .with_label_values(&labels)
+                .inc_by(skipped_packets);
+            collector
+                .packets_processed
+                .with_label_values(&labels)
+                .inc_by(processed_packets.into());
+        }
 
         // If the caller requested it, we insert a bogus packet file digest into
         // the own and peer validaton batch headers instead of the real computed
@@ -237,6 +812,7 @@ impl<'a> BatchIntaker<'a> {
         };
 
         // Construct validation header and write it out
+        let _write_headers_span = info_span!("write_validation_headers").entered();
         let header = ValidationHeader {
             batch_uuid: ingestion_header.batch_uuid,
             name: ingestion_header.name,
@@ -246,23 +822,304 @@ impl<'a> BatchIntaker<'a> {
             number_of_servers: ingestion_header.number_of_servers,
             hamming_weight: ingestion_header.hamming_weight,
             packet_file_digest,
+            skipped_packet_count: i64::try_from(skipped_packets)?,
         };
         let peer_header_signature = self
             .peer_validation_batch
-            .put_header(&header, &self.peer_validation_batch_signing_key.key)?;
+            .put_header(&header, self.peer_validation_batch_signer)?;
         let own_header_signature = self
             .own_validation_batch
-            .put_header(&header, &self.own_validation_batch_signing_key.key)?;
+            .put_header(&header, self.own_validation_batch_signer)?;
 
         // Construct and write out signature
         self.peer_validation_batch.put_signature(
             &peer_header_signature,
-            &self.peer_validation_batch_signing_key.identifier,
+            self.peer_validation_batch_signer.key_identifier(),
         )?;
         self.own_validation_batch.put_signature(
             &own_header_signature,
-            &self.own_validation_batch_signing_key.identifier,
+            self.own_validation_batch_signer.key_identifier(),
+        )?;
+
+        if let Some(deduplicator) = self.packet_deduplicator.as_deref_mut() {
+            for uuid in &batch_packet_uuids {
+                deduplicator.insert(uuid)?;
+            }
+        }
+        if let Some(collector) = self.metrics_collector {
+            let labels = [self.ingestion_server.as_str()];
+            collector.batches_intaken.with_label_values(&labels).inc();
+            collector
+                .bytes_intaken
+                .with_label_values(&labels)
+                .inc_by(packet_file_size);
+        }
+        Ok(())
+    }
+
+    /// Checks the ingestion batch's end time against the acceptance window
+    /// around now configured with set_max_batch_future_skew and
+    /// set_max_batch_age.
+    fn check_batch_time(&self, header: &IngestionHeader, now: DateTime<Utc>) -> Result<()> {
+        let now = now.timestamp_millis();
+        let millis = |duration: Duration| i64::try_from(duration.as_millis()).unwrap_or(i64::MAX);
+        let (reason, error) = match (self.max_batch_future_skew, self.max_batch_age) {
+            (Some(max_future_skew), _)
+                if header.batch_end_time > now.saturating_add(millis(max_future_skew)) =>
+            {
+                (
+                    "too_far_in_future",
+                    ValidationError::BatchTooFarInFuture {
+                        batch_end_time: header.batch_end_time,
+                        now,
+                        max_future_skew,
+                    },
+                )
+            }
+            (_, Some(max_age)) if header.batch_end_time < now.saturating_sub(millis(max_age)) => (
+                "too_old",
+                ValidationError::BatchTooOld {
+                    batch_end_time: header.batch_end_time,
+                    now,
+                    max_age,
+                },
+            ),
+            _ => return Ok(()),
+        };
+        warn!(self.logger, "{}", error);
+        if let Some(collector) = self.metrics_collector {
+            collector
+                .batches_rejected_for_time
+                .with_label_values(&[&self.ingestion_server, reason])
+                .inc();
+        }
+        Err(error.into())
+    }
+
+    /// Records the ingestion batch in the batch ledger, if there is one. Fails
+    /// if the batch's UUID was already recorded for a different batch. An entry
+    /// for the same batch, e.g. from an earlier attempt at this task that
+    /// failed after recording it, is not an error.
+    fn record_in_batch_ledger(
+        &mut self,
+        header: &IngestionHeader,
+        header_digest: &Digest,
+    ) -> Result<()> {
+        let ledger = match self.batch_ledger.as_deref_mut() {
+            Some(ledger) => ledger,
+            None => return Ok(()),
+        };
+        let entry = LedgerEntry {
+            batch_uuid: header.batch_uuid,
+            batch_path: self.intake_batch_path.clone(),
+            header_digest: hex_dump(header_digest.as_ref()),
+            recorded_at: Utc::now(),
+            trace_id: self.trace_id.to_owned(),
+        };
+
+        let existing = match ledger.get(&entry.batch_uuid, self.trace_id)? {
+            Some(existing) => existing,
+            None => match ledger.record(&entry, self.trace_id) {
+                Ok(()) => return Ok(()),
+                // Another worker recorded the same batch UUID concurrently
+                Err(e) if is_already_exists_error(&e) => ledger
+                    .get(&entry.batch_uuid, self.trace_id)?
+                    .context("batch ledger entry disappeared")?,
+                Err(e) => return Err(e),
+            },
+        };
+        if existing.same_batch(&entry) {
+            info!(
+                self.logger, "batch already recorded in ledger";
+                "recorded_trace_id" => &existing.trace_id,
+            );
+            return Ok(());
+        }
+
+        error!(
+            self.logger, "batch UUID was already processed";
+            "recorded_batch_path" => &existing.batch_path,
+            "recorded_header_digest" => &existing.header_digest,
+            "recorded_trace_id" => &existing.trace_id,
+        );
+        Err(anyhow!(
+            "recorded at {} as {} (header digest {})",
+            existing.recorded_at,
+            existing.batch_path,
+            existing.header_digest,
         )
+        .context(TaskError::BatchAlreadyProcessed(existing.batch_uuid)))
+    }
+}
+
+/// Drives the intake pipeline: chunks obtained from `read_chunk` are sent to
+/// the verifier through `chunk_sender`, and the verified chunks it sends back
+/// through `result_receiver` are passed to `write_chunk`, in the same order.
+/// Up to PIPELINE_DEPTH chunks are sent ahead, so that the verifier has work
+/// to do while the next chunk is read and the previous one written.
+fn run_pipeline<C, V>(
+    chunk_sender: &SyncSender<C>,
+    result_receiver: &Receiver<Result<V>>,
+    read_chunk: &mut dyn FnMut() -> Result<Option<C>>,
+    write_chunk: &mut dyn FnMut(V) -> Result<()>,
+) -> Result<()> {
+    let stopped = || anyhow!("packet verification thread stopped unexpectedly");
+    let mut in_flight = 0;
+    let mut exhausted = false;
+    loop {
+        while !exhausted && in_flight < PIPELINE_DEPTH {
+            match read_chunk()? {
+                Some(chunk) => {
+                    chunk_sender.send(chunk).map_err(|_| stopped())?;
+                    in_flight += 1;
+                }
+                None => exhausted = true,
+            }
+        }
+        if in_flight == 0 {
+            return Ok(());
+        }
+        let verified = result_receiver.recv().map_err(|_| stopped())??;
+        in_flight -= 1;
+        write_chunk(verified)?;
+    }
+}
+
+/// Generates validation packets for the provided ingestion packets, spreading
+/// them across one thread per element of `thread_servers`. The results for
+/// each packet are returned in the same order as the ingestion packets.
+fn verify_packets<F: EncodedField>(
+    thread_servers: &mut [Vec<Box<dyn PrioCrypto<F>>>],
+    hpke_decryptor: Option<&HpkePacketDecryptor>,
+    packets: &[IngestionDataSharePacket],
+    key_identifiers: &HashMap<String, usize>,
+    logger: &Logger,
+) -> Result<Vec<Result<ValidationPacket>>> {
+    if thread_servers.len() == 1 || packets.len() <= PACKETS_PER_VERIFY_THREAD {
+        return Ok(packets
+            .iter()
+            .map(|packet| {
+                verify_packet(
+                    &mut thread_servers[0],
+                    hpke_decryptor,
+                    packet,
+                    key_identifiers,
+                    logger,
+                )
+            })
+            .collect());
+    }
+
+    let packets_per_thread = (packets.len() + thread_servers.len() - 1) / thread_servers.len();
+    let span = Span::current();
+    let thread_results = thread::scope(|scope| {
+        let handles: Vec<_> = packets
+            .chunks(packets_per_thread)
+            .zip(thread_servers.iter_mut())
+            .map(|(packets, servers)| {
+                let span = &span;
+                scope.spawn(move |_| {
+                    let _span = span.enter();
+                    packets
+                        .iter()
+                        .map(|packet| {
+                            verify_packet(servers, hpke_decryptor, packet, key_identifiers, logger)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("packet verification thread panicked"))
+            })
+            .collect::<Result<Vec<_>>>()
+    })
+    .map_err(|_| anyhow!("packet verification thread panicked"))??;
+
+    let mut results = Vec::with_capacity(packets.len());
+    for thread_result in thread_results {
+        results.extend(thread_result);
+    }
+    Ok(results)
+}
+
+/// Generates a validation packet for the provided ingestion packet, trying the
+/// server whose decryption key the packet identifies first, then the others.
+/// If there is an HPKE decryptor, the last server is its server, which is used
+/// for packets encrypted with HPKE.
+fn verify_packet<F: EncodedField>(
+    servers: &mut [Box<dyn PrioCrypto<F>>],
+    hpke_decryptor: Option<&HpkePacketDecryptor>,
+    packet: &IngestionDataSharePacket,
+    key_identifiers: &HashMap<String, usize>,
+    logger: &Logger,
+) -> Result<ValidationPacket> {
+    let r_pit =
+        F::decode(packet.r_pit).with_context(|| format!("illegal r_pit value {}", packet.r_pit))?;
+
+    let servers = match (hpke_decryptor, servers.split_last_mut()) {
+        (Some(hpke_decryptor), Some((hpke_server, ecies_servers))) => {
+            if let Some(share) = hpke_decryptor.decrypt(packet)? {
+                let validation_message = hpke_server
+                    .generate_verification_message(r_pit, &share)
+                    .context("error generating verification message")?;
+                return Ok(validation_packet(packet, validation_message));
+            }
+            ecies_servers
+        }
+        _ => servers,
+    };
+
+    let identified_server = packet
+        .encryption_key_id
+        .as_ref()
+        .and_then(|id| key_identifiers.get(id))
+        .copied()
+        .filter(|index| *index < servers.len());
+    let server_order = identified_server
+        .into_iter()
+        .chain((0..servers.len()).filter(|index| Some(*index) != identified_server));
+    for server_index in server_order {
+        let server = &mut servers[server_index];
+        let validation_message = match server
+            .generate_verification_message(r_pit, &packet.encrypted_payload)
+        {
+            Ok(m) => m,
+            Err(ServerError::Encrypt(e)) => {
+                debug!(
+                    logger,
+                    "Input share could not be decrypted. Will try \
+                    more packet decryption keys if available.";
+                    o!(
+                        "decryption_error" => format!("{:?}", e),
+                        event::PACKET_UUID => packet.uuid.to_string(),
+                    )
+                );
+                continue;
+            }
+            Err(e) => {
+                return Err(anyhow::Error::new(e).context("error generating verification message"));
+            }
+        };
+
+        return Ok(validation_packet(packet, validation_message));
+    }
+    Err(CryptoError::PacketDecryption(packet.uuid.to_string()).into())
+}
+
+fn validation_packet<F: EncodedField>(
+    packet: &IngestionDataSharePacket,
+    validation_message: VerificationMessage<F>,
+) -> ValidationPacket {
+    ValidationPacket {
+        uuid: packet.uuid,
+        f_r: validation_message.f_r.encode(),
+        g_r: validation_message.g_r.encode(),
+        h_r: validation_message.h_r.encode(),
     }
 }
 
@@ -270,19 +1127,120 @@ impl<'a> BatchIntaker<'a> {
 mod tests {
     use super::*;
     use crate::{
+        ledger::LocalFileBatchLedger,
         logging::setup_test_logging,
         sample::{SampleGenerator, SampleOutput},
         test_utils::{
             default_facilitator_signing_private_key, default_ingestor_private_key,
             default_ingestor_public_key, default_packet_encryption_certificate_signing_request,
-            default_pha_signing_private_key,
+            default_pha_signing_private_key, default_pha_signing_public_key,
             DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
             DEFAULT_PHA_ECIES_PRIVATE_KEY,
         },
-        transport::{LocalFileTransport, SignableTransport, VerifiableTransport},
+        transport::{LocalFileTransport, MemoryTransport, SignableTransport, VerifiableTransport},
     };
     use assert_matches::assert_matches;
     use prio::{encrypt::PublicKey, server::ServerError, util::SerializeError};
+    use std::cell::RefCell;
+
+    #[test]
+    fn pipeline() {
+        // A verifier that doubles each chunk, or fails on chunk 7
+        let run = |chunk_count: u32| {
+            thread::scope(|scope| {
+                let (chunk_sender, chunk_receiver) = mpsc::sync_channel::<u32>(PIPELINE_DEPTH);
+                let (result_sender, result_receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+                scope.spawn(move |_| {
+                    for chunk in chunk_receiver {
+                        let result = if chunk == 7 {
+                            Err(anyhow!("verification failed"))
+                        } else {
+                            Ok(chunk * 2)
+                        };
+                        if result_sender.send(result).is_err() {
+                            return;
+                        }
+                    }
+                });
+
+                let mut next_chunk = 0;
+                let written = RefCell::new(Vec::new());
+                let result = run_pipeline(
+                    &chunk_sender,
+                    &result_receiver,
+                    &mut || {
+                        // Never more than PIPELINE_DEPTH chunks ahead
+                        assert!(next_chunk <= written.borrow().len() + PIPELINE_DEPTH);
+                        next_chunk += 1;
+                        Ok(Some(next_chunk as u32).filter(|chunk| *chunk <= chunk_count))
+                    },
+                    &mut |verified| {
+                        written.borrow_mut().push(verified);
+                        Ok(())
+                    },
+                );
+                drop(chunk_sender);
+                drop(result_receiver);
+                result.map(|_| written.into_inner())
+            })
+            .unwrap()
+        };
+
+        assert_eq!(run(0).unwrap(), Vec::<u32>::new());
+        assert_eq!(run(5).unwrap(), vec![2, 4, 6, 8, 10]);
+        assert_eq!(
+            run(10).unwrap_err().to_string(),
+            "verification failed".to_owned()
+        );
+    }
+
+    #[test]
+    fn unprocessed_batches() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().join("ingestion"));
+        let mut ledger = LocalFileBatchLedger::new(tempdir.path().join("ledger"));
+        let date = NaiveDateTime::from_timestamp(1234567860, 0);
+        let processed = Uuid::new_v4();
+        let unprocessed = Uuid::new_v4();
+        let incomplete = Uuid::new_v4();
+        let other_aggregation = Uuid::new_v4();
+
+        let mut put = |key: &str| {
+            transport
+                .put(key, "None")
+                .unwrap()
+                .complete_upload()
+                .unwrap()
+        };
+        for batch_id in &[processed, unprocessed, incomplete] {
+            let batch = Batch::new_ingestion("fake-aggregation", batch_id, &date);
+            put(batch.header_key());
+            put(batch.packet_file_key());
+            if *batch_id != incomplete {
+                put(batch.signature_key());
+            }
+        }
+        put(Batch::new_ingestion("fake-aggregation-2", &other_aggregation, &date).signature_key());
+
+        ledger
+            .record(
+                &LedgerEntry {
+                    batch_uuid: processed,
+                    batch_path: "fake-aggregation".to_owned(),
+                    header_digest: "digest".to_owned(),
+                    recorded_at: Utc::now(),
+                    trace_id: "None".to_owned(),
+                },
+                "None",
+            )
+            .unwrap();
+
+        assert_eq!(
+            unprocessed_ingestion_batches(&mut transport, "fake-aggregation", &mut ledger, "None")
+                .unwrap(),
+            vec![(date, unprocessed)]
+        );
+    }
 
     #[test]
     fn share_validator() {
@@ -301,7 +1259,7 @@ mod tests {
         let mut pha_output = SampleOutput {
             transport: SignableTransport {
                 transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-                batch_signing_key: default_ingestor_private_key(),
+                batch_signer: Box::new(default_ingestor_private_key()),
             },
             packet_encryption_public_key: PublicKey::from_base64(
                 &packet_encryption_csr.base64_public_key().unwrap(),
@@ -315,7 +1273,7 @@ mod tests {
                 transport: Box::new(LocalFileTransport::new(
                     facilitator_tempdir.path().to_path_buf(),
                 )),
-                batch_signing_key: default_ingestor_private_key(),
+                batch_signer: Box::new(default_ingestor_private_key()),
             },
             packet_encryption_public_key: PublicKey::from_base64(
                 &packet_encryption_csr.base64_public_key().unwrap(),
@@ -353,6 +1311,7 @@ mod tests {
                 DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
             )
             .unwrap()],
+            hpke_packet_decryption_keys: vec![],
         };
 
         let mut facilitator_ingest_transport = VerifiableAndDecryptableTransport {
@@ -366,32 +1325,33 @@ mod tests {
                 DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
             )
             .unwrap()],
+            hpke_packet_decryption_keys: vec![],
         };
 
         let mut pha_peer_validate_transport = SignableTransport {
             transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-            batch_signing_key: default_pha_signing_private_key(),
+            batch_signer: Box::new(default_pha_signing_private_key()),
         };
 
         let mut facilitator_peer_validate_transport = SignableTransport {
             transport: Box::new(LocalFileTransport::new(
                 facilitator_tempdir.path().to_path_buf(),
             )),
-            batch_signing_key: default_facilitator_signing_private_key(),
+            batch_signer: Box::new(default_facilitator_signing_private_key()),
         };
 
         let mut pha_own_validate_transport = SignableTransport {
             transport: Box::new(LocalFileTransport::new(
                 pha_copy_tempdir.path().to_path_buf(),
             )),
-            batch_signing_key: default_pha_signing_private_key(),
+            batch_signer: Box::new(default_pha_signing_private_key()),
         };
 
         let mut facilitator_own_validate_transport = SignableTransport {
             transport: Box::new(LocalFileTransport::new(
                 facilitator_copy_tempdir.path().to_path_buf(),
             )),
-            batch_signing_key: default_facilitator_signing_private_key(),
+            batch_signer: Box::new(default_facilitator_signing_private_key()),
         };
 
         let mut pha_ingestor = BatchIntaker::new(
@@ -409,7 +1369,7 @@ mod tests {
         .unwrap();
 
         pha_ingestor
-            .generate_validation_share(|_| {})
+            .generate_validation_share(|_| Ok(()))
             .expect("PHA failed to generate validation");
 
         let mut facilitator_ingestor = BatchIntaker::new(
@@ -426,51 +1386,108 @@ mod tests {
         )
         .unwrap();
 
+        // An error from the callback abandons intake without writing any
+        // validation batches
+        facilitator_ingestor.set_callback_cadence(1);
+        let err = facilitator_ingestor
+            .generate_validation_share(|_| Err(anyhow!("shutting down")))
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("shutting down"), "{:?}", err);
+        assert!(!facilitator_ingestor.validation_batches_complete().unwrap());
+
         facilitator_ingestor
-            .generate_validation_share(|_| {})
+            .generate_validation_share(|_| Ok(()))
             .expect("facilitator failed to generate validation");
+
+        // Running the same task again leaves the existing validation batches
+        // alone and succeeds without processing any packets.
+        let mut pha_ingestor = BatchIntaker::new(
+            "None",
+            &aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut pha_ingest_transport,
+            &mut pha_peer_validate_transport,
+            &mut pha_own_validate_transport,
+            true,
+            false,
+            &logger,
+        )
+        .unwrap();
+        pha_ingestor.set_callback_cadence(1);
+        let mut processed_packets = 0;
+        pha_ingestor
+            .generate_validation_share(|_| {
+                processed_packets += 1;
+                Ok(())
+            })
+            .expect("PHA failed to rerun intake task");
+        assert_eq!(processed_packets, 0);
+
+        // Existing validation batches that don't match their headers are not
+        // mistaken for the outcome of an earlier attempt.
+        let packet_file = Batch::new_validation(&aggregation_name, &batch_uuid, &date, true);
+        std::fs::write(
+            pha_copy_tempdir.path().join(packet_file.packet_file_key()),
+            b"not the packet file",
+        )
+        .unwrap();
+        let mut pha_ingestor = BatchIntaker::new(
+            "None",
+            &aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut pha_ingest_transport,
+            &mut pha_peer_validate_transport,
+            &mut pha_own_validate_transport,
+            true,
+            false,
+            &logger,
+        )
+        .unwrap();
+        let err = pha_ingestor
+            .generate_validation_share(|_| Ok(()))
+            .unwrap_err();
+        assert_matches!(
+            err,
+            Error::Validation(ValidationError::PacketFileDigestMismatch { .. }, _)
+        );
+        assert!(!err.is_retryable());
     }
 
     #[test]
-    fn wrong_decryption_key() {
+    fn parallel_verification() {
         let logger = setup_test_logging();
         let pha_tempdir = tempfile::TempDir::new().unwrap();
-        let pha_copy_tempdir = tempfile::TempDir::new().unwrap();
         let facilitator_tempdir = tempfile::TempDir::new().unwrap();
 
         let aggregation_name = "fake-aggregation-1".to_owned();
         let date = NaiveDateTime::from_timestamp(1234567890, 654321);
         let batch_uuid = Uuid::new_v4();
-
         let packet_encryption_csr = default_packet_encryption_certificate_signing_request();
+        let packet_encryption_public_key =
+            PublicKey::from_base64(&packet_encryption_csr.base64_public_key().unwrap()).unwrap();
 
         let mut pha_output = SampleOutput {
             transport: SignableTransport {
                 transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-                batch_signing_key: default_ingestor_private_key(),
+                batch_signer: Box::new(default_ingestor_private_key()),
             },
-            packet_encryption_public_key: PublicKey::from_base64(
-                &packet_encryption_csr.base64_public_key().unwrap(),
-            )
-            .unwrap(),
+            packet_encryption_public_key: packet_encryption_public_key.clone(),
             drop_nth_packet: None,
         };
-
         let mut facilitator_output = SampleOutput {
             transport: SignableTransport {
                 transport: Box::new(LocalFileTransport::new(
                     facilitator_tempdir.path().to_path_buf(),
                 )),
-                batch_signing_key: default_ingestor_private_key(),
+                batch_signer: Box::new(default_ingestor_private_key()),
             },
-            packet_encryption_public_key: PublicKey::from_base64(
-                &packet_encryption_csr.base64_public_key().unwrap(),
-            )
-            .unwrap(),
+            packet_encryption_public_key,
             drop_nth_packet: None,
         };
-
-        let mut sample_generator = SampleGenerator::new(
+        // Enough packets that they are spread across several threads
+        SampleGenerator::new(
             &aggregation_name,
             10,
             0.11,
@@ -479,40 +1496,178 @@ mod tests {
             &mut pha_output,
             &mut facilitator_output,
             &logger,
-        );
-
-        sample_generator
-            .generate_ingestion_sample("trace-id", &batch_uuid, &date, 10)
-            .unwrap();
+        )
+        .generate_ingestion_sample(
+            "trace-id",
+            &batch_uuid,
+            &date,
+            2 * PACKETS_PER_VERIFY_THREAD + 1,
+        )
+        .unwrap();
 
         let mut ingestor_pub_keys = HashMap::new();
         ingestor_pub_keys.insert(
             default_ingestor_private_key().identifier,
             default_ingestor_public_key(),
         );
-        let mut pha_ingest_transport = VerifiableAndDecryptableTransport {
-            transport: VerifiableTransport {
-                transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-                batch_signing_public_keys: ingestor_pub_keys,
-            },
-            packet_decryption_keys: vec![
-                PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap()
-            ],
-        };
 
-        let mut pha_peer_validate_transport = SignableTransport {
-            transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-            batch_signing_key: default_pha_signing_private_key(),
-        };
+        // The validation packets must be the same, and in the same order,
+        // whether they are generated on one thread or several.
+        let mut validation_packets = vec![];
+        for verify_threads in &[1, 3] {
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut ingest_transport = VerifiableAndDecryptableTransport {
+                transport: VerifiableTransport {
+                    transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+                    batch_signing_public_keys: ingestor_pub_keys.clone(),
+                },
+                packet_decryption_keys: vec![PrivateKey::from_base64(
+                    DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
+                )
+                .unwrap()],
+                hpke_packet_decryption_keys: vec![],
+            };
+            let mut peer_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("peer"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut own_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("own"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
 
-        let mut pha_own_validate_transport = SignableTransport {
-            transport: Box::new(LocalFileTransport::new(
-                pha_copy_tempdir.path().to_path_buf(),
-            )),
-            batch_signing_key: default_pha_signing_private_key(),
-        };
+            let mut intaker = BatchIntaker::new(
+                "None",
+                &aggregation_name,
+                &batch_uuid,
+                &date,
+                &mut ingest_transport,
+                &mut peer_validate_transport,
+                &mut own_validate_transport,
+                true,
+                false,
+                &logger,
+            )
+            .unwrap();
+            intaker.set_verify_threads(*verify_threads);
+            intaker.set_callback_cadence(100);
+            let mut callbacks = 0;
+            intaker
+                .generate_validation_share(|_| {
+                    callbacks += 1;
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(callbacks, 5);
 
-        let mut pha_ingestor = BatchIntaker::new(
+            let packet_file_key =
+                Batch::new_validation(&aggregation_name, &batch_uuid, &date, true)
+                    .packet_file_key()
+                    .to_owned();
+            let packet_file =
+                std::fs::read(validation_tempdir.path().join("peer").join(packet_file_key))
+                    .unwrap();
+            validation_packets.push(
+                avro_rs::Reader::new(&packet_file[..])
+                    .unwrap()
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(
+            validation_packets[0].len(),
+            2 * PACKETS_PER_VERIFY_THREAD + 1
+        );
+        assert_eq!(validation_packets[0], validation_packets[1]);
+    }
+
+    #[test]
+    fn wrong_decryption_key() {
+        let logger = setup_test_logging();
+        let pha_tempdir = tempfile::TempDir::new().unwrap();
+        let pha_copy_tempdir = tempfile::TempDir::new().unwrap();
+        let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = NaiveDateTime::from_timestamp(1234567890, 654321);
+        let batch_uuid = Uuid::new_v4();
+
+        let packet_encryption_csr = default_packet_encryption_certificate_signing_request();
+
+        let mut pha_output = SampleOutput {
+            transport: SignableTransport {
+                transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+                batch_signer: Box::new(default_ingestor_private_key()),
+            },
+            packet_encryption_public_key: PublicKey::from_base64(
+                &packet_encryption_csr.base64_public_key().unwrap(),
+            )
+            .unwrap(),
+            drop_nth_packet: None,
+        };
+
+        let mut facilitator_output = SampleOutput {
+            transport: SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    facilitator_tempdir.path().to_path_buf(),
+                )),
+                batch_signer: Box::new(default_ingestor_private_key()),
+            },
+            packet_encryption_public_key: PublicKey::from_base64(
+                &packet_encryption_csr.base64_public_key().unwrap(),
+            )
+            .unwrap(),
+            drop_nth_packet: None,
+        };
+
+        let mut sample_generator = SampleGenerator::new(
+            &aggregation_name,
+            10,
+            0.11,
+            100,
+            100,
+            &mut pha_output,
+            &mut facilitator_output,
+            &logger,
+        );
+
+        sample_generator
+            .generate_ingestion_sample("trace-id", &batch_uuid, &date, 10)
+            .unwrap();
+
+        let mut ingestor_pub_keys = HashMap::new();
+        ingestor_pub_keys.insert(
+            default_ingestor_private_key().identifier,
+            default_ingestor_public_key(),
+        );
+        let mut pha_ingest_transport = VerifiableAndDecryptableTransport {
+            transport: VerifiableTransport {
+                transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+                batch_signing_public_keys: ingestor_pub_keys,
+            },
+            packet_decryption_keys: vec![
+                PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap()
+            ],
+            hpke_packet_decryption_keys: vec![],
+        };
+
+        let mut pha_peer_validate_transport = SignableTransport {
+            transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+            batch_signer: Box::new(default_pha_signing_private_key()),
+        };
+
+        let mut pha_own_validate_transport = SignableTransport {
+            transport: Box::new(LocalFileTransport::new(
+                pha_copy_tempdir.path().to_path_buf(),
+            )),
+            batch_signer: Box::new(default_pha_signing_private_key()),
+        };
+
+        let mut pha_ingestor = BatchIntaker::new(
             "None",
             &aggregation_name,
             &batch_uuid,
@@ -526,10 +1681,310 @@ mod tests {
         )
         .unwrap();
 
-        let err = pha_ingestor.generate_validation_share(|_| {}).unwrap_err();
+        let err = pha_ingestor
+            .generate_validation_share(|_| Ok(()))
+            .unwrap_err();
         assert!(err
             .to_string()
             .contains("failed to construct validation message for packet",));
+        assert!(is_packet_decryption_error(&err));
+    }
+
+    #[test]
+    fn malformed_packets_skipped() {
+        let logger = setup_test_logging();
+        let sample_tempdir = tempfile::TempDir::new().unwrap();
+        let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = NaiveDateTime::from_timestamp(1234567890, 654321);
+        let batch_uuid = Uuid::new_v4();
+
+        let packet_encryption_csr = default_packet_encryption_certificate_signing_request();
+        let sample_output = |path: &std::path::Path| SampleOutput {
+            transport: SignableTransport {
+                transport: Box::new(LocalFileTransport::new(path.to_path_buf())),
+                batch_signer: Box::new(default_ingestor_private_key()),
+            },
+            packet_encryption_public_key: PublicKey::from_base64(
+                &packet_encryption_csr.base64_public_key().unwrap(),
+            )
+            .unwrap(),
+            drop_nth_packet: None,
+        };
+        let mut pha_output = sample_output(sample_tempdir.path());
+        let mut facilitator_output = sample_output(facilitator_tempdir.path());
+        SampleGenerator::new(
+            &aggregation_name,
+            10,
+            0.11,
+            100,
+            100,
+            &mut pha_output,
+            &mut facilitator_output,
+            &logger,
+        )
+        .generate_ingestion_sample("trace-id", &batch_uuid, &date, 10)
+        .unwrap();
+
+        let mut ingestor_pub_keys = HashMap::new();
+        ingestor_pub_keys.insert(
+            default_ingestor_private_key().identifier,
+            default_ingestor_public_key(),
+        );
+
+        // Re-sign the sample batch with two of its ten packets corrupted
+        let mut sample_transport = LocalFileTransport::new(sample_tempdir.path().to_path_buf());
+        let mut sample_reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchReader::new(
+                Batch::new_ingestion(&aggregation_name, &batch_uuid, &date),
+                &mut sample_transport,
+                false,
+                "trace-id",
+                &logger,
+            );
+        let mut header = sample_reader.header(&ingestor_pub_keys).unwrap();
+        let mut packets: Vec<IngestionDataSharePacket> = sample_reader
+            .packet_file_reader(&header)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let corrupted = [packets[3].uuid, packets[7].uuid];
+        for index in &[3, 7] {
+            packets[*index].encrypted_payload = vec![1u8; 64];
+        }
+        let mut ingestion_transport =
+            LocalFileTransport::new(ingestion_tempdir.path().to_path_buf());
+        let mut ingestion_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(
+                Batch::new_ingestion(&aggregation_name, &batch_uuid, &date),
+                &mut ingestion_transport,
+                "trace-id",
+            );
+        header.packet_file_digest = ingestion_writer
+            .packet_file_writer(|writer| {
+                for packet in &packets {
+                    packet.write(writer)?;
+                }
+                Ok(())
+            })
+            .unwrap()
+            .as_ref()
+            .to_vec();
+        let signer = default_ingestor_private_key();
+        let signature = ingestion_writer.put_header(&header, &signer).unwrap();
+        ingestion_writer
+            .put_signature(&signature, signer.key_identifier())
+            .unwrap();
+
+        let intake = |max_skipped_packets_percent: Option<f64>| {
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut ingest_transport = VerifiableAndDecryptableTransport {
+                transport: VerifiableTransport {
+                    transport: Box::new(LocalFileTransport::new(
+                        ingestion_tempdir.path().to_path_buf(),
+                    )),
+                    batch_signing_public_keys: ingestor_pub_keys.clone(),
+                },
+                packet_decryption_keys: vec![PrivateKey::from_base64(
+                    DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
+                )
+                .unwrap()],
+                hpke_packet_decryption_keys: vec![],
+            };
+            let mut peer_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().to_path_buf(),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut own_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("own"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut intaker = BatchIntaker::new(
+                "trace-id",
+                &aggregation_name,
+                &batch_uuid,
+                &date,
+                &mut ingest_transport,
+                &mut peer_validate_transport,
+                &mut own_validate_transport,
+                true,
+                false,
+                &logger,
+            )
+            .unwrap();
+            if let Some(percent) = max_skipped_packets_percent {
+                intaker.set_max_skipped_packets_percent(percent);
+            }
+            intaker.generate_validation_share(|_| Ok(()))?;
+            drop(intaker);
+
+            let mut peer_validation_keys = HashMap::new();
+            peer_validation_keys.insert(
+                default_pha_signing_private_key().identifier,
+                default_pha_signing_public_key(),
+            );
+            let mut validation_transport =
+                LocalFileTransport::new(validation_tempdir.path().to_path_buf());
+            let mut validation_reader: BatchReader<'_, ValidationHeader, ValidationPacket> =
+                BatchReader::new(
+                    Batch::new_validation(&aggregation_name, &batch_uuid, &date, true),
+                    &mut validation_transport,
+                    false,
+                    "trace-id",
+                    &logger,
+                );
+            let header = validation_reader.header(&peer_validation_keys).unwrap();
+            let uuids: Vec<Uuid> = validation_reader
+                .packet_file_reader(&header)
+                .unwrap()
+                .map(|packet| packet.unwrap().uuid)
+                .collect();
+            Ok((header.skipped_packet_count, uuids))
+        };
+
+        let (skipped_packet_count, uuids) = intake(Some(20.0)).unwrap();
+        assert_eq!(skipped_packet_count, 2);
+        assert_eq!(uuids.len(), 8);
+        assert!(!uuids.iter().any(|uuid| corrupted.contains(uuid)));
+
+        // The batch fails if too many packets are malformed, or if malformed
+        // packets are not permitted at all
+        for max_skipped_packets_percent in &[Some(10.0), None] {
+            let err = intake(*max_skipped_packets_percent).unwrap_err();
+            assert!(is_packet_decryption_error(&err), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn packet_encryption_key_id() {
+        let logger = setup_test_logging();
+        let pha_tempdir = tempfile::TempDir::new().unwrap();
+        let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = NaiveDateTime::from_timestamp(1234567890, 654321);
+        let batch_uuid = Uuid::new_v4();
+
+        let packet_encryption_csr = default_packet_encryption_certificate_signing_request();
+        let sample_output = |path: &std::path::Path| SampleOutput {
+            transport: SignableTransport {
+                transport: Box::new(LocalFileTransport::new(path.to_path_buf())),
+                batch_signer: Box::new(default_ingestor_private_key()),
+            },
+            packet_encryption_public_key: PublicKey::from_base64(
+                &packet_encryption_csr.base64_public_key().unwrap(),
+            )
+            .unwrap(),
+            drop_nth_packet: None,
+        };
+        let mut pha_output = sample_output(pha_tempdir.path());
+        let mut facilitator_output = sample_output(facilitator_tempdir.path());
+        // The packets sent to the PHA have encryption key ID "pha-fake-key-1"
+        SampleGenerator::new(
+            &aggregation_name,
+            10,
+            0.11,
+            100,
+            100,
+            &mut pha_output,
+            &mut facilitator_output,
+            &logger,
+        )
+        .generate_ingestion_sample("trace-id", &batch_uuid, &date, 10)
+        .unwrap();
+
+        let intake = |key_identifiers: HashMap<String, usize>,
+                      peer_key_versions: Option<&[String]>| {
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut ingestor_pub_keys = HashMap::new();
+            ingestor_pub_keys.insert(
+                default_ingestor_private_key().identifier,
+                default_ingestor_public_key(),
+            );
+            // The key the packets were encrypted to is the second of two, as
+            // it might be during a key rotation
+            let mut ingest_transport = VerifiableAndDecryptableTransport {
+                transport: VerifiableTransport {
+                    transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
+                    batch_signing_public_keys: ingestor_pub_keys,
+                },
+                packet_decryption_keys: vec![
+                    PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+                    PrivateKey::from_base64(
+                        DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
+                    )
+                    .unwrap(),
+                ],
+                hpke_packet_decryption_keys: vec![],
+            };
+            let mut peer_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("peer"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut own_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("own"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut intaker = BatchIntaker::new(
+                "trace-id",
+                &aggregation_name,
+                &batch_uuid,
+                &date,
+                &mut ingest_transport,
+                &mut peer_validate_transport,
+                &mut own_validate_transport,
+                true,
+                false,
+                &logger,
+            )
+            .unwrap();
+            intaker.set_packet_decryption_key_identifiers(key_identifiers);
+            if let Some(peer_key_versions) = peer_key_versions {
+                intaker.set_peer_packet_encryption_key_versions(peer_key_versions);
+            }
+            intaker.generate_validation_share(|_| Ok(()))
+        };
+
+        // The packet's key is identified
+        intake(
+            vec![("pha-fake-key-1".to_owned(), 1)].into_iter().collect(),
+            None,
+        )
+        .unwrap();
+        // The packet's key is not identified
+        intake(HashMap::new(), None).unwrap();
+        // The packet's key is misidentified, or out of range, so we fall back
+        // to trying every key
+        intake(
+            vec![("pha-fake-key-1".to_owned(), 0)].into_iter().collect(),
+            None,
+        )
+        .unwrap();
+        intake(
+            vec![("pha-fake-key-1".to_owned(), 2)].into_iter().collect(),
+            None,
+        )
+        .unwrap();
+
+        // The packet's key version is advertised by the peer
+        intake(
+            HashMap::new(),
+            Some(&["pha-fake-key-0".to_owned(), "pha-fake-key-1".to_owned()]),
+        )
+        .unwrap();
+        // The packet's key version is not advertised by the peer
+        intake(HashMap::new(), Some(&["pha-fake-key-2".to_owned()])).unwrap_err();
+        intake(HashMap::new(), Some(&[])).unwrap_err();
     }
 
     #[test]
@@ -548,7 +2003,7 @@ mod tests {
         let mut pha_output = SampleOutput {
             transport: SignableTransport {
                 transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-                batch_signing_key: default_ingestor_private_key(),
+                batch_signer: Box::new(default_ingestor_private_key()),
             },
             packet_encryption_public_key: PublicKey::from_base64(
                 &packet_encryption_csr.base64_public_key().unwrap(),
@@ -562,7 +2017,7 @@ mod tests {
                 transport: Box::new(LocalFileTransport::new(
                     facilitator_tempdir.path().to_path_buf(),
                 )),
-                batch_signing_key: default_ingestor_private_key(),
+                batch_signer: Box::new(default_ingestor_private_key()),
             },
             packet_encryption_public_key: PublicKey::from_base64(
                 &packet_encryption_csr.base64_public_key().unwrap(),
@@ -601,18 +2056,19 @@ mod tests {
                 DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
             )
             .unwrap()],
+            hpke_packet_decryption_keys: vec![],
         };
 
         let mut pha_peer_validate_transport = SignableTransport {
             transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-            batch_signing_key: default_pha_signing_private_key(),
+            batch_signer: Box::new(default_pha_signing_private_key()),
         };
 
         let mut pha_own_validate_transport = SignableTransport {
             transport: Box::new(LocalFileTransport::new(
                 pha_copy_tempdir.path().to_path_buf(),
             )),
-            batch_signing_key: default_pha_signing_private_key(),
+            batch_signer: Box::new(default_pha_signing_private_key()),
         };
 
         let mut pha_ingestor = BatchIntaker::new(
@@ -629,12 +2085,540 @@ mod tests {
         )
         .unwrap();
 
-        let err = pha_ingestor.generate_validation_share(|_| {}).unwrap_err();
-        assert_matches!(
+        let err = pha_ingestor
+            .generate_validation_share(|_| Ok(()))
+            .unwrap_err();
+        assert_matches!(err, Error::AnyhowError(err) => assert_matches!(
             err.downcast(),
             Ok(ServerError::Serialize(
                 SerializeError::UnpackInputSizeMismatch
             ))
+        ));
+    }
+
+    #[test]
+    fn unsupported_prime() {
+        let logger = setup_test_logging();
+        let aggregation_name = "fake-aggregation";
+        let batch_uuid = Uuid::new_v4();
+        let date = NaiveDateTime::from_timestamp(1234567890, 0);
+
+        let ingestion_transport = MemoryTransport::new();
+        let mut writer_transport = ingestion_transport.clone();
+        let mut ingestion_writer: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
+            BatchWriter::new(
+                Batch::new_ingestion(aggregation_name, &batch_uuid, &date),
+                &mut writer_transport,
+                "trace-id",
+            );
+        let packet_file_digest = ingestion_writer.packet_file_writer(|_| Ok(())).unwrap();
+        let header = IngestionHeader {
+            batch_uuid,
+            name: aggregation_name.to_owned(),
+            bins: 10,
+            epsilon: 0.11,
+            prime: 17,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: 100,
+            batch_end_time: 100,
+            packet_file_digest: packet_file_digest.as_ref().to_vec(),
+        };
+        let signer = default_ingestor_private_key();
+        let signature = ingestion_writer.put_header(&header, &signer).unwrap();
+        ingestion_writer
+            .put_signature(&signature, signer.key_identifier())
+            .unwrap();
+
+        let mut ingestor_pub_keys = HashMap::new();
+        ingestor_pub_keys.insert(signer.identifier, default_ingestor_public_key());
+        let mut ingest_transport = VerifiableAndDecryptableTransport {
+            transport: VerifiableTransport {
+                transport: Box::new(ingestion_transport),
+                batch_signing_public_keys: ingestor_pub_keys,
+            },
+            packet_decryption_keys: vec![PrivateKey::from_base64(
+                DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
+            )
+            .unwrap()],
+            hpke_packet_decryption_keys: vec![],
+        };
+        let validate_transport = || SignableTransport {
+            transport: Box::new(MemoryTransport::new()),
+            batch_signer: Box::new(default_pha_signing_private_key()),
+        };
+        let mut peer_validate_transport = validate_transport();
+        let mut own_validate_transport = validate_transport();
+        let mut intaker = BatchIntaker::new(
+            "trace-id",
+            aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut ingest_transport,
+            &mut peer_validate_transport,
+            &mut own_validate_transport,
+            true,
+            false,
+            &logger,
+        )
+        .unwrap();
+
+        let err = intaker.generate_validation_share(|_| Ok(())).unwrap_err();
+        assert_matches!(err, Error::Validation(ValidationError::Malformed(_), _));
+    }
+
+    #[test]
+    fn batch_time_window() {
+        let logger = setup_test_logging();
+        let mut ingest_transport = VerifiableAndDecryptableTransport {
+            transport: VerifiableTransport {
+                transport: Box::new(MemoryTransport::new()),
+                batch_signing_public_keys: HashMap::new(),
+            },
+            packet_decryption_keys: vec![],
+            hpke_packet_decryption_keys: vec![],
+        };
+        let validate_transport = || SignableTransport {
+            transport: Box::new(MemoryTransport::new()),
+            batch_signer: Box::new(default_pha_signing_private_key()),
+        };
+        let mut peer_validate_transport = validate_transport();
+        let mut own_validate_transport = validate_transport();
+        let mut intaker = BatchIntaker::new(
+            "trace-id",
+            "fake-aggregation",
+            &Uuid::new_v4(),
+            &NaiveDateTime::from_timestamp(1234567890, 0),
+            &mut ingest_transport,
+            &mut peer_validate_transport,
+            &mut own_validate_transport,
+            true,
+            false,
+            &logger,
+        )
+        .unwrap();
+
+        let now = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(1234567890, 0), Utc);
+        let header = |batch_end_time: i64| IngestionHeader {
+            batch_uuid: Uuid::new_v4(),
+            name: "fake-aggregation".to_owned(),
+            bins: 10,
+            epsilon: 0.11,
+            prime: 4293918721,
+            number_of_servers: 2,
+            hamming_weight: None,
+            batch_start_time: batch_end_time - 3_600_000,
+            batch_end_time,
+            packet_file_digest: vec![],
+        };
+        let now_millis = now.timestamp_millis();
+        let check = |intaker: &BatchIntaker, batch_end_time: i64| {
+            intaker
+                .check_batch_time(&header(batch_end_time), now)
+                .map_err(Error::classify)
+        };
+
+        // Any time is acceptable by default
+        check(&intaker, now_millis + 3_600_000).unwrap();
+        check(&intaker, 0).unwrap();
+
+        intaker.set_max_batch_future_skew(Duration::from_secs(60));
+        intaker.set_max_batch_age(Duration::from_secs(86400));
+        check(&intaker, now_millis + 60_000).unwrap();
+        check(&intaker, now_millis - 86_400_000).unwrap();
+
+        let err = check(&intaker, now_millis + 60_001).unwrap_err();
+        assert_matches!(
+            err,
+            Error::Validation(ValidationError::BatchTooFarInFuture { batch_end_time, .. }, _) => {
+                assert_eq!(batch_end_time, now_millis + 60_001);
+            }
+        );
+        assert!(err.is_retryable());
+
+        let err = check(&intaker, now_millis - 86_400_001).unwrap_err();
+        assert_matches!(
+            err,
+            Error::Validation(ValidationError::BatchTooOld { max_age, .. }, _) => {
+                assert_eq!(max_age, Duration::from_secs(86400));
+            }
+        );
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn replayed_batch_rejected() {
+        let logger = setup_test_logging();
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = NaiveDateTime::from_timestamp(1234567890, 654321);
+        let replay_date = NaiveDateTime::from_timestamp(1234657890, 654321);
+        let batch_uuid = Uuid::new_v4();
+
+        let packet_encryption_csr = default_packet_encryption_certificate_signing_request();
+        let sample_output = |path: &std::path::Path| SampleOutput {
+            transport: SignableTransport {
+                transport: Box::new(LocalFileTransport::new(path.to_path_buf())),
+                batch_signer: Box::new(default_ingestor_private_key()),
+            },
+            packet_encryption_public_key: PublicKey::from_base64(
+                &packet_encryption_csr.base64_public_key().unwrap(),
+            )
+            .unwrap(),
+            drop_nth_packet: None,
+        };
+        let mut pha_output = sample_output(ingestion_tempdir.path());
+        let mut facilitator_output = sample_output(facilitator_tempdir.path());
+        let mut sample_generator = SampleGenerator::new(
+            &aggregation_name,
+            10,
+            0.11,
+            100,
+            100,
+            &mut pha_output,
+            &mut facilitator_output,
+            &logger,
+        );
+
+        // The ingestor sends a batch with the same UUID twice
+        for date in &[date, replay_date] {
+            sample_generator
+                .generate_ingestion_sample("trace-id", &batch_uuid, date, 10)
+                .unwrap();
+        }
+
+        let ledger_tempdir = tempfile::TempDir::new().unwrap();
+        let mut ledger = LocalFileBatchLedger::new(ledger_tempdir.path().join("ledger"));
+        // Collectors of the same metric must have the same labels, and other
+        // tests register intake metrics with a locality.
+        let metrics_collector = IntakeMetricsCollector::for_locality("replayed-batch").unwrap();
+
+        let intake = |date: &NaiveDateTime, ledger: &mut LocalFileBatchLedger| {
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut ingestor_pub_keys = HashMap::new();
+            ingestor_pub_keys.insert(
+                default_ingestor_private_key().identifier,
+                default_ingestor_public_key(),
+            );
+            let mut ingest_transport = VerifiableAndDecryptableTransport {
+                transport: VerifiableTransport {
+                    transport: Box::new(LocalFileTransport::new(
+                        ingestion_tempdir.path().to_path_buf(),
+                    )),
+                    batch_signing_public_keys: ingestor_pub_keys,
+                },
+                packet_decryption_keys: vec![PrivateKey::from_base64(
+                    DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
+                )
+                .unwrap()],
+                hpke_packet_decryption_keys: vec![],
+            };
+            let mut peer_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("peer"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut own_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("own"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut intaker = BatchIntaker::new(
+                "trace-id",
+                &aggregation_name,
+                &batch_uuid,
+                date,
+                &mut ingest_transport,
+                &mut peer_validate_transport,
+                &mut own_validate_transport,
+                true,
+                false,
+                &logger,
+            )
+            .unwrap();
+            intaker.set_batch_ledger(ledger);
+            intaker.set_metrics_collector(&metrics_collector);
+            intaker.generate_validation_share(|_| Ok(()))
+        };
+
+        intake(&date, &mut ledger).unwrap();
+        let entry = ledger.get(&batch_uuid, "trace-id").unwrap().unwrap();
+        assert_eq!(entry.batch_uuid, batch_uuid);
+
+        // Retrying the same batch is fine, even if the validation batches
+        // were not written
+        intake(&date, &mut ledger).unwrap();
+
+        // The replayed batch is rejected
+        let err = intake(&replay_date, &mut ledger).unwrap_err();
+        assert_matches!(
+            &err,
+            Error::Task(TaskError::BatchAlreadyProcessed(uuid), _) if *uuid == batch_uuid
+        );
+        assert!(!err.is_retryable());
+        assert_eq!(ledger.get(&batch_uuid, "trace-id").unwrap(), Some(entry));
+
+        // Metrics are labeled with the identifier of the ingestor's key
+        let key_identifier = default_ingestor_private_key().identifier;
+        let ingestion_server = [key_identifier.as_str()];
+        assert_eq!(
+            metrics_collector
+                .batches_intaken
+                .with_label_values(&ingestion_server)
+                .get(),
+            2
+        );
+        assert!(
+            metrics_collector
+                .bytes_intaken
+                .with_label_values(&ingestion_server)
+                .get()
+                > 0
+        );
+        assert_eq!(
+            metrics_collector
+                .packets_processed
+                .with_label_values(&ingestion_server)
+                .get(),
+            20
+        );
+        assert_eq!(
+            metrics_collector
+                .batches_rejected
+                .with_label_values(&[&key_identifier, "task"])
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn validate_only() {
+        let logger = setup_test_logging();
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+        let validation_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = NaiveDateTime::from_timestamp(1234567890, 654321);
+        let batch_uuid = Uuid::new_v4();
+
+        let packet_encryption_csr = default_packet_encryption_certificate_signing_request();
+        let sample_output = |path: &std::path::Path| SampleOutput {
+            transport: SignableTransport {
+                transport: Box::new(LocalFileTransport::new(path.to_path_buf())),
+                batch_signer: Box::new(default_ingestor_private_key()),
+            },
+            packet_encryption_public_key: PublicKey::from_base64(
+                &packet_encryption_csr.base64_public_key().unwrap(),
+            )
+            .unwrap(),
+            drop_nth_packet: None,
+        };
+        let mut pha_output = sample_output(ingestion_tempdir.path());
+        let mut facilitator_output = sample_output(facilitator_tempdir.path());
+        SampleGenerator::new(
+            &aggregation_name,
+            10,
+            0.11,
+            100,
+            100,
+            &mut pha_output,
+            &mut facilitator_output,
+            &logger,
+        )
+        .generate_ingestion_sample("trace-id", &batch_uuid, &date, 10)
+        .unwrap();
+
+        let mut ingestor_pub_keys = HashMap::new();
+        ingestor_pub_keys.insert(
+            default_ingestor_private_key().identifier,
+            default_ingestor_public_key(),
+        );
+        let mut ingest_transport = VerifiableAndDecryptableTransport {
+            transport: VerifiableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    ingestion_tempdir.path().to_path_buf(),
+                )),
+                batch_signing_public_keys: ingestor_pub_keys,
+            },
+            packet_decryption_keys: vec![PrivateKey::from_base64(
+                DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
+            )
+            .unwrap()],
+            hpke_packet_decryption_keys: vec![],
+        };
+        let mut peer_validate_transport = SignableTransport {
+            transport: Box::new(LocalFileTransport::new(
+                validation_tempdir.path().join("peer"),
+            )),
+            batch_signer: Box::new(default_pha_signing_private_key()),
+        };
+        let mut own_validate_transport = SignableTransport {
+            transport: Box::new(LocalFileTransport::new(
+                validation_tempdir.path().join("own"),
+            )),
+            batch_signer: Box::new(default_pha_signing_private_key()),
+        };
+        let ledger_tempdir = tempfile::TempDir::new().unwrap();
+        let mut ledger = LocalFileBatchLedger::new(ledger_tempdir.path().join("ledger"));
+
+        let mut intaker = BatchIntaker::new(
+            "trace-id",
+            &aggregation_name,
+            &batch_uuid,
+            &date,
+            &mut ingest_transport,
+            &mut peer_validate_transport,
+            &mut own_validate_transport,
+            true,
+            false,
+            &logger,
+        )
+        .unwrap();
+        intaker.set_batch_ledger(&mut ledger);
+        intaker.set_validate_only(true);
+        intaker.generate_validation_share(|_| Ok(())).unwrap();
+
+        assert_eq!(
+            intaker.summary(),
+            Some(&IntakeSummary {
+                batch_uuid,
+                name: aggregation_name.clone(),
+                bins: 10,
+                hamming_weight: None,
+                packet_count: 10,
+                duplicate_packet_count: 0,
+                skipped_packet_count: 0,
+            })
         );
+        drop(intaker);
+
+        // Nothing was written or recorded
+        assert_eq!(
+            std::fs::read_dir(validation_tempdir.path())
+                .unwrap()
+                .count(),
+            0
+        );
+        assert_eq!(ledger.get(&batch_uuid, "trace-id").unwrap(), None);
+    }
+
+    #[test]
+    fn duplicate_packets_dropped() {
+        let logger = setup_test_logging();
+        let ingestion_tempdir = tempfile::TempDir::new().unwrap();
+        let facilitator_tempdir = tempfile::TempDir::new().unwrap();
+
+        let aggregation_name = "fake-aggregation-1".to_owned();
+        let date = NaiveDateTime::from_timestamp(1234567890, 654321);
+        let batch_uuid = Uuid::new_v4();
+
+        let packet_encryption_csr = default_packet_encryption_certificate_signing_request();
+        let sample_output = |path: &std::path::Path| SampleOutput {
+            transport: SignableTransport {
+                transport: Box::new(LocalFileTransport::new(path.to_path_buf())),
+                batch_signer: Box::new(default_ingestor_private_key()),
+            },
+            packet_encryption_public_key: PublicKey::from_base64(
+                &packet_encryption_csr.base64_public_key().unwrap(),
+            )
+            .unwrap(),
+            drop_nth_packet: None,
+        };
+        let mut pha_output = sample_output(ingestion_tempdir.path());
+        let mut facilitator_output = sample_output(facilitator_tempdir.path());
+        SampleGenerator::new(
+            &aggregation_name,
+            10,
+            0.11,
+            100,
+            100,
+            &mut pha_output,
+            &mut facilitator_output,
+            &logger,
+        )
+        .generate_ingestion_sample("trace-id", &batch_uuid, &date, 10)
+        .unwrap();
+
+        // Intakes the batch, returning the number of packets in the resulting
+        // validation batch
+        let intake = |deduplicator: &mut PacketDeduplicator| -> usize {
+            let validation_tempdir = tempfile::TempDir::new().unwrap();
+            let mut ingestor_pub_keys = HashMap::new();
+            ingestor_pub_keys.insert(
+                default_ingestor_private_key().identifier,
+                default_ingestor_public_key(),
+            );
+            let mut ingest_transport = VerifiableAndDecryptableTransport {
+                transport: VerifiableTransport {
+                    transport: Box::new(LocalFileTransport::new(
+                        ingestion_tempdir.path().to_path_buf(),
+                    )),
+                    batch_signing_public_keys: ingestor_pub_keys,
+                },
+                packet_decryption_keys: vec![PrivateKey::from_base64(
+                    DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
+                )
+                .unwrap()],
+                hpke_packet_decryption_keys: vec![],
+            };
+            let mut peer_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("peer"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut own_validate_transport = SignableTransport {
+                transport: Box::new(LocalFileTransport::new(
+                    validation_tempdir.path().join("own"),
+                )),
+                batch_signer: Box::new(default_pha_signing_private_key()),
+            };
+            let mut intaker = BatchIntaker::new(
+                "trace-id",
+                &aggregation_name,
+                &batch_uuid,
+                &date,
+                &mut ingest_transport,
+                &mut peer_validate_transport,
+                &mut own_validate_transport,
+                true,
+                false,
+                &logger,
+            )
+            .unwrap();
+            intaker.set_packet_deduplicator(deduplicator);
+            intaker.generate_validation_share(|_| Ok(())).unwrap();
+
+            let mut pha_pub_keys = HashMap::new();
+            pha_pub_keys.insert(
+                default_pha_signing_private_key().identifier,
+                default_pha_signing_public_key(),
+            );
+            let mut validation_transport =
+                LocalFileTransport::new(validation_tempdir.path().join("own"));
+            let mut validation_batch: BatchReader<'_, ValidationHeader, ValidationPacket> =
+                BatchReader::new(
+                    Batch::new_validation(&aggregation_name, &batch_uuid, &date, true),
+                    &mut validation_transport,
+                    false,
+                    "trace-id",
+                    &logger,
+                );
+            let header = validation_batch.header(&pha_pub_keys).unwrap();
+            validation_batch
+                .packet_file_reader(&header)
+                .unwrap()
+                .count()
+        };
+
+        let mut deduplicator = PacketDeduplicator::new(1000, 1000);
+        assert_eq!(intake(&mut deduplicator), 10);
+        // Every packet in the batch was already seen
+        assert_eq!(intake(&mut deduplicator), 0);
     }
 }
@@ -0,0 +1,818 @@
+use anyhow::{anyhow, Context, Result};
+use derivative::Derivative;
+use once_cell::sync::Lazy;
+use rusoto_core::{signature::SignedRequest, Region, RusotoError};
+use serde::Deserialize;
+use serde_json::json;
+use slog::{debug, o, Logger};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Debug,
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+use url::Url;
+
+use crate::{
+    aws_credentials::{self, basic_runtime, retry_request},
+    config::Identity,
+    gcp_oauth::GcpOauthTokenProvider,
+    http::{Method, OauthTokenProvider, RequestParameters, RetryingAgent},
+    kubernetes::KubernetesClient,
+    logging::event,
+};
+
+/// A KeyProvider obtains key material, such as base64 encoded packet
+/// decryption or batch signing keys, from wherever it is kept, so that keys
+/// need not be passed on the command line, where they would appear in process
+/// listings.
+pub trait KeyProvider: Debug {
+    /// Returns the key material, with any surrounding whitespace removed.
+    fn key(&mut self) -> Result<String>;
+
+    /// Stores the provided key material where this provider obtains it from,
+    /// so that subsequent calls to key() return it. Providers that cannot store
+    /// keys return an error.
+    fn store_key(&mut self, _key: &str) -> Result<()> {
+        Err(anyhow!("{:?} does not support storing keys", self))
+    }
+}
+
+/// Returns a KeyProvider for the provided key reference, which is one of:
+///
+///   - `env:NAME`, for the value of environment variable NAME
+///   - `file:PATH`, for the contents of the file at PATH, e.g. a Kubernetes
+///     secret mounted into the container
+///   - `k8s-secret:NAMESPACE/NAME/KEY`, for the entry KEY in the data of the
+///     Kubernetes secret NAME in NAMESPACE, fetched from the Kubernetes API
+///   - `gcp-secret-manager:projects/*/secrets/*/versions/*`, for the payload
+///     of the GCP Secret Manager secret version with that resource name,
+///     fetched as the default service account
+///   - `aws-secrets-manager:ARN`, for the string value of the AWS Secrets
+///     Manager secret with that ARN, fetched with the default AWS credentials
+///   - `aws-ssm-parameter:ARN`, for the decrypted value of the AWS Systems
+///     Manager Parameter Store parameter with that ARN, fetched with the
+///     default AWS credentials
+///
+/// Key material fetched from Kubernetes or a cloud secret store is cached for
+/// KEY_CACHE_TTL. Anything else is taken to be the key material itself, so
+/// that keys may still be provided directly.
+pub fn key_provider(reference: &str, logger: &Logger) -> Result<Box<dyn KeyProvider>> {
+    if let Some(name) = reference.strip_prefix("env:") {
+        return Ok(Box::new(EnvironmentKeyProvider {
+            name: name.to_owned(),
+        }));
+    }
+    if let Some(path) = reference.strip_prefix("file:") {
+        return Ok(Box::new(FileKeyProvider {
+            path: PathBuf::from(path),
+        }));
+    }
+    let remote_provider: Box<dyn KeyProvider> =
+        if let Some(secret) = reference.strip_prefix("k8s-secret:") {
+            let parts: Vec<&str> = secret.split('/').collect();
+            match parts.as_slice() {
+                [namespace, secret_name, key]
+                    if !namespace.is_empty() && !secret_name.is_empty() && !key.is_empty() =>
+                {
+                    Box::new(KubernetesSecretKeyProvider {
+                        client: KubernetesClient::new((*namespace).to_owned()),
+                        secret_name: (*secret_name).to_owned(),
+                        key: (*key).to_owned(),
+                    })
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "invalid Kubernetes secret key reference {}: expected \
+                        k8s-secret:NAMESPACE/NAME/KEY",
+                        reference
+                    ))
+                }
+            }
+        } else if let Some(secret_version) = reference.strip_prefix("gcp-secret-manager:") {
+            Box::new(GcpSecretManagerKeyProvider::new(
+                secret_version,
+                None,
+                logger,
+            )?)
+        } else if let Some(secret_arn) = reference.strip_prefix("aws-secrets-manager:") {
+            Box::new(AwsKeyProvider::new(
+                AwsKeyStore::SecretsManager,
+                secret_arn,
+                default_aws_client(logger)?,
+                logger,
+            )?)
+        } else if let Some(parameter_arn) = reference.strip_prefix("aws-ssm-parameter:") {
+            Box::new(AwsKeyProvider::new(
+                AwsKeyStore::ParameterStore,
+                parameter_arn,
+                default_aws_client(logger)?,
+                logger,
+            )?)
+        } else {
+            return Ok(Box::new(LiteralKeyProvider {
+                key: reference.to_owned(),
+            }));
+        };
+
+    Ok(Box::new(CachingKeyProvider {
+        reference: reference.to_owned(),
+        inner: remote_provider,
+    }))
+}
+
+/// How long key material fetched from Kubernetes or a cloud secret store is
+/// used before it is fetched again.
+pub const KEY_CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// Key material fetched by CachingKeyProviders and when it was fetched, keyed
+/// by key reference. The cache is shared by the whole process since providers
+/// are typically constructed anew for each task.
+static KEY_CACHE: Lazy<Mutex<HashMap<String, (String, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Discards all cached key material, so that keys are fetched again the next
+/// time they are needed. This should be called when key material appears to be
+/// stale, e.g. when packets cannot be decrypted because keys were rotated.
+pub fn invalidate_cached_keys() {
+    KEY_CACHE.lock().unwrap().clear();
+}
+
+/// A KeyProvider that caches the key material obtained from another provider
+/// for KEY_CACHE_TTL.
+#[derive(Debug)]
+struct CachingKeyProvider {
+    reference: String,
+    inner: Box<dyn KeyProvider>,
+}
+
+impl KeyProvider for CachingKeyProvider {
+    fn key(&mut self) -> Result<String> {
+        if let Some((key, fetched_at)) = KEY_CACHE.lock().unwrap().get(&self.reference) {
+            if fetched_at.elapsed() < KEY_CACHE_TTL {
+                return Ok(key.clone());
+            }
+        }
+
+        let key = self.inner.key()?;
+        KEY_CACHE
+            .lock()
+            .unwrap()
+            .insert(self.reference.clone(), (key.clone(), Instant::now()));
+        Ok(key)
+    }
+
+    fn store_key(&mut self, key: &str) -> Result<()> {
+        KEY_CACHE.lock().unwrap().remove(&self.reference);
+        self.inner.store_key(key)
+    }
+}
+
+/// A KeyProvider for key material that was provided directly.
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct LiteralKeyProvider {
+    #[derivative(Debug = "ignore")]
+    key: String,
+}
+
+impl KeyProvider for LiteralKeyProvider {
+    fn key(&mut self) -> Result<String> {
+        Ok(self.key.trim().to_owned())
+    }
+}
+
+/// A KeyProvider that reads key material from an environment variable.
+#[derive(Debug)]
+struct EnvironmentKeyProvider {
+    name: String,
+}
+
+impl KeyProvider for EnvironmentKeyProvider {
+    fn key(&mut self) -> Result<String> {
+        Ok(std::env::var(&self.name)
+            .context(format!(
+                "failed to read key from environment variable {}",
+                self.name
+            ))?
+            .trim()
+            .to_owned())
+    }
+}
+
+/// A KeyProvider that reads key material from a file, such as a Kubernetes
+/// secret mounted as a volume.
+#[derive(Debug)]
+struct FileKeyProvider {
+    path: PathBuf,
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn key(&mut self) -> Result<String> {
+        Ok(fs::read_to_string(&self.path)
+            .context(format!("failed to read key from file {:?}", self.path))?
+            .trim()
+            .to_owned())
+    }
+
+    fn store_key(&mut self, key: &str) -> Result<()> {
+        fs::write(&self.path, key).context(format!("failed to write key to file {:?}", self.path))
+    }
+}
+
+/// A KeyProvider that fetches key material from a Kubernetes secret using the
+/// Kubernetes API.
+#[derive(Debug)]
+struct KubernetesSecretKeyProvider {
+    client: KubernetesClient,
+    secret_name: String,
+    key: String,
+}
+
+impl KeyProvider for KubernetesSecretKeyProvider {
+    fn key(&mut self) -> Result<String> {
+        let value = self.client.get_secret_value(&self.secret_name, &self.key)?;
+        Ok(String::from_utf8(value)
+            .context(format!(
+                "key {} in secret {} is not UTF-8",
+                self.key, self.secret_name
+            ))?
+            .trim()
+            .to_owned())
+    }
+
+    fn store_key(&mut self, key: &str) -> Result<()> {
+        self.client
+            .set_secret_value(&self.secret_name, &self.key, key.as_bytes())
+    }
+}
+
+fn gcp_secret_manager_api_base_url() -> Url {
+    Url::parse("https://secretmanager.googleapis.com/")
+        .expect("unable to parse Secret Manager API url")
+}
+
+/// Returns the resource name of the secret that the provided secret version
+/// belongs to, i.e. projects/*/secrets/* for projects/*/secrets/*/versions/*.
+fn gcp_secret_name(secret_version: &str) -> Result<&str> {
+    match secret_version.find("/versions/") {
+        Some(index) => Ok(&secret_version[..index]),
+        None => Err(anyhow!(
+            "{} is not a Secret Manager secret version",
+            secret_version
+        )),
+    }
+}
+
+/// Represents the subset of the response to a Secret Manager access request
+/// that we use.
+/// https://cloud.google.com/secret-manager/docs/reference/rest/v1/projects.secrets.versions/access#response-body
+#[derive(Deserialize)]
+struct AccessSecretVersionResponse {
+    payload: SecretPayload,
+}
+
+#[derive(Deserialize)]
+struct SecretPayload {
+    /// Base64 encoded secret
+    data: String,
+}
+
+/// A KeyProvider that fetches key material from GCP Secret Manager.
+#[derive(Debug)]
+pub struct GcpSecretManagerKeyProvider {
+    /// Resource name of the secret version, of the form
+    /// projects/*/secrets/*/versions/*
+    secret_version: String,
+    oauth_token_provider: Box<dyn OauthTokenProvider>,
+    agent: RetryingAgent,
+    api_base_url: Url,
+    logger: Logger,
+}
+
+impl GcpSecretManagerKeyProvider {
+    /// Creates a GcpSecretManagerKeyProvider that fetches the provided secret
+    /// version. If identity is None, the provider authenticates to Secret
+    /// Manager as the default service account. Otherwise, it impersonates the
+    /// service account whose email is in identity.
+    pub fn new(secret_version: &str, identity: Identity, parent_logger: &Logger) -> Result<Self> {
+        let oauth_token_provider = GcpOauthTokenProvider::new(
+            // This token is used to access Secret Manager
+            // https://developers.google.com/identity/protocols/oauth2/scopes#secretmanager
+            "https://www.googleapis.com/auth/cloud-platform",
+            identity.map(|x| x.to_string()),
+            None,
+            None,
+            parent_logger,
+        )?;
+        Ok(Self::new_with_token_provider(
+            secret_version,
+            Box::new(oauth_token_provider),
+            gcp_secret_manager_api_base_url(),
+            parent_logger,
+        ))
+    }
+
+    pub(crate) fn new_with_token_provider(
+        secret_version: &str,
+        oauth_token_provider: Box<dyn OauthTokenProvider>,
+        api_base_url: Url,
+        parent_logger: &Logger,
+    ) -> Self {
+        GcpSecretManagerKeyProvider {
+            secret_version: secret_version.to_owned(),
+            oauth_token_provider,
+            agent: RetryingAgent::default(),
+            api_base_url,
+            logger: parent_logger.new(o!("secret_version" => secret_version.to_owned())),
+        }
+    }
+}
+
+impl KeyProvider for GcpSecretManagerKeyProvider {
+    fn key(&mut self) -> Result<String> {
+        let logger = self
+            .logger
+            .new(o!(event::ACTION => "access GCP Secret Manager secret"));
+
+        // https://cloud.google.com/secret-manager/docs/reference/rest/v1/projects.secrets.versions/access
+        let url = self
+            .api_base_url
+            .join(&format!("v1/{}:access", self.secret_version))
+            .context("failed to construct Secret Manager URL")?;
+        let request = self.agent.prepare_request(RequestParameters {
+            url,
+            method: Method::Get,
+            token_provider: Some(self.oauth_token_provider.as_mut()),
+        })?;
+
+        let http_response = self
+            .agent
+            .call(&logger, &request)
+            .context(format!("failed to access {}", self.secret_version))?;
+        let response: AccessSecretVersionResponse = self
+            .agent
+            .read_json(http_response)
+            .context("failed to decode Secret Manager access response")?;
+
+        let key = base64::decode(&response.payload.data)
+            .context("failed to decode Secret Manager payload")?;
+        Ok(String::from_utf8(key)
+            .context(format!("secret {} is not UTF-8", self.secret_version))?
+            .trim()
+            .to_owned())
+    }
+
+    /// Adds a new version to the secret this provider's secret version belongs
+    /// to. The new version is only returned by key() if this provider was
+    /// created with the "latest" version alias.
+    fn store_key(&mut self, key: &str) -> Result<()> {
+        let logger = self
+            .logger
+            .new(o!(event::ACTION => "add GCP Secret Manager secret version"));
+
+        // https://cloud.google.com/secret-manager/docs/reference/rest/v1/projects.secrets/addVersion
+        let secret = gcp_secret_name(&self.secret_version)?;
+        let url = self
+            .api_base_url
+            .join(&format!("v1/{}:addVersion", secret))
+            .context("failed to construct Secret Manager URL")?;
+        let request = self.agent.prepare_request(RequestParameters {
+            url,
+            method: Method::Post,
+            token_provider: Some(self.oauth_token_provider.as_mut()),
+        })?;
+
+        self.agent
+            .send_json_request(
+                &logger,
+                &request,
+                &json!({ "payload": { "data": base64::encode(key) } }),
+            )
+            .context(format!("failed to add version to {}", secret))?;
+        Ok(())
+    }
+}
+
+/// The AWS services that AwsKeyProvider can fetch key material from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AwsKeyStore {
+    /// AWS Secrets Manager
+    SecretsManager,
+    /// AWS Systems Manager Parameter Store
+    ParameterStore,
+}
+
+impl AwsKeyStore {
+    /// The service name used in ARNs and request signatures
+    fn service(self) -> &'static str {
+        match self {
+            AwsKeyStore::SecretsManager => "secretsmanager",
+            AwsKeyStore::ParameterStore => "ssm",
+        }
+    }
+}
+
+/// Represents the subset of the response to a Secrets Manager GetSecretValue
+/// request that we use.
+/// https://docs.aws.amazon.com/secretsmanager/latest/apireference/API_GetSecretValue.html#API_GetSecretValue_ResponseSyntax
+#[derive(Deserialize)]
+struct GetSecretValueResponse {
+    #[serde(rename = "SecretString")]
+    secret_string: String,
+}
+
+/// Represents the subset of the response to a Systems Manager GetParameter
+/// request that we use.
+/// https://docs.aws.amazon.com/systems-manager/latest/APIReference/API_GetParameter.html#API_GetParameter_ResponseSyntax
+#[derive(Deserialize)]
+struct GetParameterResponse {
+    #[serde(rename = "Parameter")]
+    parameter: Parameter,
+}
+
+#[derive(Deserialize)]
+struct Parameter {
+    #[serde(rename = "Value")]
+    value: String,
+}
+
+fn default_aws_client(logger: &Logger) -> Result<rusoto_core::Client> {
+    Ok(rusoto_core::Client::new_with(
+        aws_credentials::Provider::new(None, true, "key provider", logger)?,
+        rusoto_core::HttpClient::new().context("failed to create HTTP client")?,
+    ))
+}
+
+/// A KeyProvider that fetches key material from AWS Secrets Manager or Systems
+/// Manager Parameter Store.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct AwsKeyProvider {
+    store: AwsKeyStore,
+    /// ARN of the secret or parameter
+    arn: String,
+    region: Region,
+    #[derivative(Debug = "ignore")]
+    client: rusoto_core::Client,
+    runtime: Runtime,
+    logger: Logger,
+}
+
+impl AwsKeyProvider {
+    /// Creates an AwsKeyProvider that fetches the secret or parameter with the
+    /// provided ARN from `store`. Requests are sent to the region in the ARN.
+    pub fn new(
+        store: AwsKeyStore,
+        arn: &str,
+        client: rusoto_core::Client,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        let region = match arn.split(':').collect::<Vec<&str>>().as_slice() {
+            ["arn", _, service, region, _, ..] if *service == store.service() => {
+                Region::from_str(region).context(format!("invalid region in {}", arn))?
+            }
+            _ => return Err(anyhow!("{} is not an ARN for {:?}", arn, store)),
+        };
+        Ok(AwsKeyProvider {
+            store,
+            arn: arn.to_owned(),
+            region,
+            client,
+            runtime: basic_runtime()?,
+            logger: parent_logger.new(o!("key_arn" => arn.to_owned())),
+        })
+    }
+}
+
+impl KeyProvider for AwsKeyProvider {
+    fn key(&mut self) -> Result<String> {
+        let logger = self
+            .logger
+            .new(o!(event::ACTION => format!("get key from {:?}", self.store)));
+        debug!(logger, "fetching key");
+
+        let response = match self.store {
+            // https://docs.aws.amazon.com/secretsmanager/latest/apireference/API_GetSecretValue.html
+            AwsKeyStore::SecretsManager => self.request(
+                &logger,
+                "secretsmanager.GetSecretValue",
+                json!({ "SecretId": self.arn }),
+            ),
+            // https://docs.aws.amazon.com/systems-manager/latest/APIReference/API_GetParameter.html
+            AwsKeyStore::ParameterStore => self.request(
+                &logger,
+                "AmazonSSM.GetParameter",
+                json!({ "Name": self.arn, "WithDecryption": true }),
+            ),
+        }
+        .context(format!("failed to get key from {}", self.arn))?;
+
+        let key = match self.store {
+            AwsKeyStore::SecretsManager => {
+                serde_json::from_slice::<GetSecretValueResponse>(&response)
+                    .context("failed to decode GetSecretValue response")?
+                    .secret_string
+            }
+            AwsKeyStore::ParameterStore => {
+                serde_json::from_slice::<GetParameterResponse>(&response)
+                    .context("failed to decode GetParameter response")?
+                    .parameter
+                    .value
+            }
+        };
+        Ok(key.trim().to_owned())
+    }
+
+    fn store_key(&mut self, key: &str) -> Result<()> {
+        let logger = self
+            .logger
+            .new(o!(event::ACTION => format!("store key in {:?}", self.store)));
+        debug!(logger, "storing key");
+
+        match self.store {
+            // https://docs.aws.amazon.com/secretsmanager/latest/apireference/API_PutSecretValue.html
+            AwsKeyStore::SecretsManager => self.request(
+                &logger,
+                "secretsmanager.PutSecretValue",
+                json!({ "SecretId": self.arn, "SecretString": key }),
+            ),
+            // https://docs.aws.amazon.com/systems-manager/latest/APIReference/API_PutParameter.html
+            AwsKeyStore::ParameterStore => self.request(
+                &logger,
+                "AmazonSSM.PutParameter",
+                json!({ "Name": self.arn, "Value": key, "Overwrite": true }),
+            ),
+        }
+        .context(format!("failed to store key in {}", self.arn))?;
+        Ok(())
+    }
+}
+
+impl AwsKeyProvider {
+    /// Sends a request with the provided JSON body to the action `target` of
+    /// this provider's service, returning the body of the response.
+    fn request(&self, logger: &Logger, target: &str, body: serde_json::Value) -> Result<Vec<u8>> {
+        let body = serde_json::to_vec(&body)?;
+        let response = retry_request(logger, || {
+            let mut request = SignedRequest::new("POST", self.store.service(), &self.region, "/");
+            request.set_content_type("application/x-amz-json-1.1".to_owned());
+            request.add_header("x-amz-target", target);
+            request.set_payload(Some(body.clone()));
+            self.runtime.block_on(async {
+                let mut response = self
+                    .client
+                    .sign_and_dispatch(request)
+                    .await
+                    .map_err(RusotoError::<Infallible>::from)?;
+                let response = response.buffer().await?;
+                if response.status.is_success() {
+                    Ok(response)
+                } else {
+                    Err(RusotoError::Unknown(response))
+                }
+            })
+        })?;
+        Ok(response.body.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http::StaticOauthTokenProvider, logging::setup_test_logging};
+    use mockito::mock;
+    use rusoto_core::credential::StaticProvider;
+    use rusoto_mock::MockRequestDispatcher;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn literal_environment_and_file_keys() {
+        let logger = setup_test_logging();
+
+        assert_eq!(
+            key_provider("bGl0ZXJhbA==", &logger)
+                .unwrap()
+                .key()
+                .unwrap(),
+            "bGl0ZXJhbA=="
+        );
+
+        std::env::set_var("KEY_PROVIDER_TEST_KEY", "ZW52aXJvbm1lbnQ=\n");
+        assert_eq!(
+            key_provider("env:KEY_PROVIDER_TEST_KEY", &logger)
+                .unwrap()
+                .key()
+                .unwrap(),
+            "ZW52aXJvbm1lbnQ="
+        );
+        key_provider("env:KEY_PROVIDER_TEST_MISSING_KEY", &logger)
+            .unwrap()
+            .key()
+            .unwrap_err();
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "ZmlsZQ==").unwrap();
+        let mut provider =
+            key_provider(&format!("file:{}", file.path().display()), &logger).unwrap();
+        assert_eq!(provider.key().unwrap(), "ZmlsZQ==");
+        provider.store_key("c3RvcmVk").unwrap();
+        assert_eq!(provider.key().unwrap(), "c3RvcmVk");
+
+        key_provider("bGl0ZXJhbA==", &logger)
+            .unwrap()
+            .store_key("c3RvcmVk")
+            .unwrap_err();
+    }
+
+    #[test]
+    fn invalid_kubernetes_secret_reference() {
+        let logger = setup_test_logging();
+        for reference in &[
+            "k8s-secret:",
+            "k8s-secret:namespace/name",
+            "k8s-secret:namespace//key",
+            "k8s-secret:namespace/name/key/extra",
+        ] {
+            key_provider(reference, &logger).unwrap_err();
+        }
+        key_provider("k8s-secret:namespace/name/key", &logger).unwrap();
+    }
+
+    #[test]
+    fn gcp_secret_manager_key() {
+        let logger = setup_test_logging();
+        let secret_version = "projects/p/secrets/s/versions/1";
+        let mocked_access = mock("GET", format!("/v1/{}:access", secret_version).as_str())
+            .match_header("Authorization", "Bearer fake-token")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "name": secret_version,
+                    "payload": { "data": base64::encode("c2VjcmV0\n") },
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let mut provider = GcpSecretManagerKeyProvider::new_with_token_provider(
+            secret_version,
+            Box::new(StaticOauthTokenProvider::from("fake-token".to_owned())),
+            Url::parse(&mockito::server_url()).unwrap(),
+            &logger,
+        );
+        assert_eq!(provider.key().unwrap(), "c2VjcmV0");
+        mocked_access.assert();
+
+        let mocked_add_version = mock("POST", "/v1/projects/p/secrets/s:addVersion")
+            .match_header("Authorization", "Bearer fake-token")
+            .match_body(mockito::Matcher::Json(
+                json!({ "payload": { "data": base64::encode("bmV3") } }),
+            ))
+            .with_status(200)
+            .with_body(json!({ "name": "projects/p/secrets/s/versions/2" }).to_string())
+            .expect(1)
+            .create();
+        provider.store_key("bmV3").unwrap();
+        mocked_add_version.assert();
+    }
+
+    #[test]
+    fn cached_keys() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "b2xk").unwrap();
+        let mut provider = CachingKeyProvider {
+            reference: format!("test-cached-keys:{}", file.path().display()),
+            inner: Box::new(FileKeyProvider {
+                path: file.path().to_owned(),
+            }),
+        };
+        assert_eq!(provider.key().unwrap(), "b2xk");
+
+        // The key is rotated, but the cached key is still used until the cache
+        // is invalidated
+        fs::write(file.path(), "bmV3").unwrap();
+        assert_eq!(provider.key().unwrap(), "b2xk");
+        invalidate_cached_keys();
+        assert_eq!(provider.key().unwrap(), "bmV3");
+    }
+
+    #[test]
+    fn aws_keys() {
+        let logger = setup_test_logging();
+        let cases = [
+            (
+                AwsKeyStore::SecretsManager,
+                "arn:aws:secretsmanager:us-west-2:111122223333:secret:key-AbCdEf",
+                "secretsmanager.GetSecretValue",
+                json!({ "ARN": "arn", "SecretString": "c2VjcmV0\n" }),
+            ),
+            (
+                AwsKeyStore::ParameterStore,
+                "arn:aws:ssm:us-west-2:111122223333:parameter/key",
+                "AmazonSSM.GetParameter",
+                json!({ "Parameter": { "Name": "key", "Value": "c2VjcmV0" } }),
+            ),
+        ];
+        for (store, arn, target, response) in cases.iter() {
+            let (store, target) = (*store, *target);
+            let dispatcher = MockRequestDispatcher::with_status(200)
+                .with_json_body(response.clone())
+                .with_request_checker(move |request| {
+                    assert_eq!(request.method, "POST");
+                    assert_eq!(request.service, store.service());
+                    assert_eq!(request.region, Region::UsWest2);
+                    assert_eq!(
+                        request.headers.get("x-amz-target"),
+                        Some(&vec![target.as_bytes().to_vec()])
+                    );
+                });
+            let client = rusoto_core::Client::new_with(
+                StaticProvider::new_minimal("key".to_owned(), "secret".to_owned()),
+                dispatcher,
+            );
+            let mut provider = AwsKeyProvider::new(store, arn, client, &logger).unwrap();
+            assert_eq!(provider.key().unwrap(), "c2VjcmV0");
+        }
+    }
+
+    #[test]
+    fn store_aws_keys() {
+        let logger = setup_test_logging();
+        let cases = [
+            (
+                AwsKeyStore::SecretsManager,
+                "arn:aws:secretsmanager:us-west-2:111122223333:secret:key-AbCdEf",
+                "secretsmanager.PutSecretValue",
+                json!({
+                    "SecretId": "arn:aws:secretsmanager:us-west-2:111122223333:secret:key-AbCdEf",
+                    "SecretString": "bmV3",
+                }),
+            ),
+            (
+                AwsKeyStore::ParameterStore,
+                "arn:aws:ssm:us-west-2:111122223333:parameter/key",
+                "AmazonSSM.PutParameter",
+                json!({
+                    "Name": "arn:aws:ssm:us-west-2:111122223333:parameter/key",
+                    "Value": "bmV3",
+                    "Overwrite": true,
+                }),
+            ),
+        ];
+        for (store, arn, target, expected_body) in cases.iter() {
+            let (target, expected_body) = (*target, expected_body.clone());
+            let dispatcher = MockRequestDispatcher::with_status(200)
+                .with_body("{}")
+                .with_request_checker(move |request| {
+                    assert_eq!(
+                        request.headers.get("x-amz-target"),
+                        Some(&vec![target.as_bytes().to_vec()])
+                    );
+                    let body = match request.payload.as_ref().unwrap() {
+                        rusoto_core::signature::SignedRequestPayload::Buffer(body) => body,
+                        _ => panic!("unexpected payload"),
+                    };
+                    assert_eq!(
+                        serde_json::from_slice::<serde_json::Value>(body).unwrap(),
+                        expected_body
+                    );
+                });
+            let client = rusoto_core::Client::new_with(
+                StaticProvider::new_minimal("key".to_owned(), "secret".to_owned()),
+                dispatcher,
+            );
+            let mut provider = AwsKeyProvider::new(*store, arn, client, &logger).unwrap();
+            provider.store_key("bmV3").unwrap();
+        }
+    }
+
+    #[test]
+    fn aws_key_bad_arn() {
+        let logger = setup_test_logging();
+        for (store, arn) in &[
+            (AwsKeyStore::SecretsManager, "key"),
+            (
+                AwsKeyStore::SecretsManager,
+                "arn:aws:ssm:us-west-2:111122223333:parameter/key",
+            ),
+            (
+                AwsKeyStore::ParameterStore,
+                "arn:aws:ssm:nowhere-1:111122223333:parameter/key",
+            ),
+        ] {
+            let client = rusoto_core::Client::new_with(
+                StaticProvider::new_minimal("key".to_owned(), "secret".to_owned()),
+                MockRequestDispatcher::default(),
+            );
+            AwsKeyProvider::new(*store, arn, client, &logger).unwrap_err();
+        }
+    }
+}
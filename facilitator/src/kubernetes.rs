@@ -1,11 +1,21 @@
 use anyhow::{anyhow, Context, Result};
 
+use chrono::{DateTime, Utc};
 use kube::{
-    api::{Api, ListParams},
+    api::{Api, ListParams, Patch, PatchParams, PostParams},
+    error::ErrorResponse,
     Client,
 };
+use serde_json::json;
+use std::time::Duration;
 
-use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::{
+    api::{
+        coordination::v1::{Lease, LeaseSpec},
+        core::v1::Secret,
+    },
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+};
 
 use tokio::runtime::Runtime;
 
@@ -82,6 +92,178 @@ impl KubernetesClient {
         Ok(items)
     }
 
+    /// Gets the value of the entry with the provided key in the data of the
+    /// named secret from the kubernetes API.
+    pub fn get_secret_value(&self, secret_name: &str, key: &str) -> Result<Vec<u8>> {
+        let runtime =
+            Runtime::new().expect("failed to create runtime for kubernetes get_secret_value");
+        runtime.block_on(self.get_secret_value_impl(secret_name, key))
+    }
+
+    async fn get_secret_value_impl(&self, secret_name: &str, key: &str) -> Result<Vec<u8>> {
+        let client = Self::create_client().await?;
+
+        let secrets: Api<Secret> = Api::namespaced(client, &self.namespace);
+
+        let secret = secrets
+            .get(secret_name)
+            .await
+            .context(format!("getting secret {} failed", secret_name))?;
+
+        let mut data = secret.data;
+        data.remove(key)
+            .map(|value| value.0)
+            .ok_or_else(|| anyhow!("secret {} has no key {}", secret_name, key))
+    }
+
+    /// Sets the value of the entry with the provided key in the data of the
+    /// named secret, which must already exist, using the kubernetes API.
+    pub fn set_secret_value(&self, secret_name: &str, key: &str, value: &[u8]) -> Result<()> {
+        let runtime =
+            Runtime::new().expect("failed to create runtime for kubernetes set_secret_value");
+        runtime.block_on(self.set_secret_value_impl(secret_name, key, value))
+    }
+
+    async fn set_secret_value_impl(
+        &self,
+        secret_name: &str,
+        key: &str,
+        value: &[u8],
+    ) -> Result<()> {
+        let client = Self::create_client().await?;
+
+        let secrets: Api<Secret> = Api::namespaced(client, &self.namespace);
+
+        let patch = json!({ "data": { key: base64::encode(value) } });
+        secrets
+            .patch(secret_name, &PatchParams::default(), &Patch::Merge(&patch))
+            .await
+            .context(format!("patching secret {} failed", secret_name))?;
+
+        Ok(())
+    }
+
+    /// Acquires the named Lease for the holder identity, or renews it if the
+    /// holder already has it, creating the Lease if it does not exist. Returns
+    /// false if another holder's lease on it has not expired by now. Updates
+    /// are conditional on the Lease's resource version, so that only one of
+    /// several candidates racing for an expired Lease gets it.
+    pub fn try_acquire_lease(
+        &self,
+        lease_name: &str,
+        identity: &str,
+        lease_duration: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<bool> {
+        let runtime =
+            Runtime::new().expect("failed to create runtime for kubernetes try_acquire_lease");
+        runtime.block_on(self.try_acquire_lease_impl(lease_name, identity, lease_duration, now))
+    }
+
+    async fn try_acquire_lease_impl(
+        &self,
+        lease_name: &str,
+        identity: &str,
+        lease_duration: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<bool> {
+        let client = Self::create_client().await?;
+
+        let leases: Api<Lease> = Api::namespaced(client, &self.namespace);
+
+        let lease_spec = |acquire_time, lease_transitions| LeaseSpec {
+            holder_identity: Some(identity.to_owned()),
+            lease_duration_seconds: Some(lease_duration.as_secs() as i32),
+            acquire_time: Some(MicroTime(acquire_time)),
+            renew_time: Some(MicroTime(now)),
+            lease_transitions: Some(lease_transitions),
+        };
+
+        let result = match leases.get(lease_name).await {
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(lease_name.to_owned()),
+                        ..Default::default()
+                    },
+                    spec: Some(lease_spec(now, 0)),
+                };
+                leases.create(&PostParams::default(), &lease).await
+            }
+            Err(e) => {
+                return Err(e).context(format!("getting lease {} failed", lease_name));
+            }
+            Ok(mut lease) => {
+                let spec = lease.spec.clone().unwrap_or_default();
+                let held = spec.holder_identity.as_deref() == Some(identity);
+                let expired = match (
+                    &spec.holder_identity,
+                    &spec.renew_time,
+                    spec.lease_duration_seconds,
+                ) {
+                    (Some(_), Some(renew_time), Some(seconds)) => {
+                        renew_time.0 + chrono::Duration::seconds(seconds.into()) < now
+                    }
+                    _ => true,
+                };
+                if !held && !expired {
+                    return Ok(false);
+                }
+                let transitions = spec.lease_transitions.unwrap_or(0);
+                lease.spec = Some(match (held, spec.acquire_time) {
+                    (true, Some(acquire_time)) => lease_spec(acquire_time.0, transitions),
+                    _ => lease_spec(now, transitions + 1),
+                });
+                // The lease's metadata still has the resource version we got,
+                // so the replacement fails if anyone updated it since.
+                leases
+                    .replace(lease_name, &PostParams::default(), &lease)
+                    .await
+            }
+        };
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => Ok(false),
+            Err(e) => Err(e).context(format!("updating lease {} failed", lease_name)),
+        }
+    }
+
+    /// Gives up the named Lease, if the holder identity holds it, so that
+    /// another candidate may acquire it without waiting for it to expire.
+    pub fn release_lease(&self, lease_name: &str, identity: &str) -> Result<()> {
+        let runtime =
+            Runtime::new().expect("failed to create runtime for kubernetes release_lease");
+        runtime.block_on(self.release_lease_impl(lease_name, identity))
+    }
+
+    async fn release_lease_impl(&self, lease_name: &str, identity: &str) -> Result<()> {
+        let client = Self::create_client().await?;
+
+        let leases: Api<Lease> = Api::namespaced(client, &self.namespace);
+
+        let mut lease = leases
+            .get(lease_name)
+            .await
+            .context(format!("getting lease {} failed", lease_name))?;
+        let mut spec = lease.spec.clone().unwrap_or_default();
+        if spec.holder_identity.as_deref() != Some(identity) {
+            return Ok(());
+        }
+        spec.holder_identity = None;
+        spec.renew_time = None;
+        lease.spec = Some(spec);
+
+        match leases
+            .replace(lease_name, &PostParams::default(), &lease)
+            .await
+        {
+            // Someone else updated the lease, so it is no longer ours
+            Ok(_) | Err(kube::Error::Api(ErrorResponse { code: 409, .. })) => Ok(()),
+            Err(e) => Err(e).context(format!("releasing lease {} failed", lease_name)),
+        }
+    }
+
     async fn create_client() -> Result<Client> {
         Client::try_default()
             .await
@@ -0,0 +1,322 @@
+use crate::{
+    kubernetes::KubernetesClient,
+    logging::event,
+    transport::{is_already_exists_error, Transport},
+};
+use anyhow::{Context, Result};
+use chrono::{prelude::Utc, DateTime};
+use serde::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use std::{
+    fmt::Debug,
+    io::{Read, Write},
+    time::{Duration, Instant},
+};
+
+/// A lock that may be held by one holder at a time for a limited duration, and
+/// that the holder must renew before the duration elapses to keep holding it.
+pub trait LeaseLock: Debug {
+    /// Acquires the lease for the holder identity, or renews it if the holder
+    /// already has it, so that it is held until lease_duration after now.
+    /// Returns Ok(false) if another holder's lease has not yet expired.
+    fn try_acquire_or_renew(
+        &mut self,
+        identity: &str,
+        lease_duration: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<bool>;
+
+    /// Gives up the lease, if the holder identity holds it, so that other
+    /// candidates may acquire it right away.
+    fn release(&mut self, identity: &str) -> Result<()>;
+}
+
+/// A LeaseLock backed by a Kubernetes coordination.k8s.io/v1 Lease, whose
+/// updates are conditional on its resource version, so that at most one
+/// candidate ever holds it.
+#[derive(Debug)]
+pub struct KubernetesLeaseLock {
+    client: KubernetesClient,
+    lease_name: String,
+}
+
+impl KubernetesLeaseLock {
+    pub fn new(namespace: &str, lease_name: &str) -> Self {
+        KubernetesLeaseLock {
+            client: KubernetesClient::new(namespace.to_owned()),
+            lease_name: lease_name.to_owned(),
+        }
+    }
+}
+
+impl LeaseLock for KubernetesLeaseLock {
+    fn try_acquire_or_renew(
+        &mut self,
+        identity: &str,
+        lease_duration: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<bool> {
+        self.client
+            .try_acquire_lease(&self.lease_name, identity, lease_duration, now)
+    }
+
+    fn release(&mut self, identity: &str) -> Result<()> {
+        self.client.release_lease(&self.lease_name, identity)
+    }
+}
+
+/// The content of the object in which TransportLeaseLock records the lease
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+struct LeaseRecord {
+    holder_identity: String,
+    renew_time: DateTime<Utc>,
+    lease_duration_seconds: u64,
+}
+
+impl LeaseRecord {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.renew_time + chrono::Duration::seconds(self.lease_duration_seconds as i64) < now
+    }
+}
+
+/// A LeaseLock backed by an object in a transport, for deployments that don't
+/// run in Kubernetes. The object is created exclusively when there is no lease,
+/// but object stores offer no way to conditionally replace an expired lease, so
+/// two candidates that take over an expired lease at the same moment may both
+/// believe they hold it until one of them next renews it and finds the other's
+/// record. Use it only where briefly having two holders is harmless, as it is
+/// for TaskScheduler, whose task markers keep tasks from being dispatched twice.
+#[derive(Debug)]
+pub struct TransportLeaseLock {
+    transport: Box<dyn Transport>,
+    key: String,
+}
+
+impl TransportLeaseLock {
+    pub fn new(transport: Box<dyn Transport>, key: &str) -> Self {
+        TransportLeaseLock {
+            transport,
+            key: key.to_owned(),
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<LeaseRecord>> {
+        if !self.transport.exists(&self.key, "None")? {
+            return Ok(None);
+        }
+        let mut content = Vec::new();
+        self.transport
+            .get(&self.key, "None")?
+            .read_to_end(&mut content)
+            .context("failed to read lease record")?;
+        Ok(Some(
+            serde_json::from_slice(&content).context("failed to parse lease record")?,
+        ))
+    }
+
+    fn write_record(&mut self, record: &LeaseRecord, exclusive: bool) -> Result<()> {
+        let mut writer = if exclusive {
+            self.transport.put_if_absent(&self.key, "None")?
+        } else {
+            self.transport.put(&self.key, "None")?
+        };
+        writer
+            .write_all(&serde_json::to_vec(record)?)
+            .context("failed to write lease record")?;
+        writer.complete_upload()
+    }
+}
+
+impl LeaseLock for TransportLeaseLock {
+    fn try_acquire_or_renew(
+        &mut self,
+        identity: &str,
+        lease_duration: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<bool> {
+        let record = LeaseRecord {
+            holder_identity: identity.to_owned(),
+            renew_time: now,
+            lease_duration_seconds: lease_duration.as_secs(),
+        };
+
+        match self.read_record()? {
+            None => match self.write_record(&record, true) {
+                Ok(()) => (),
+                Err(e) if is_already_exists_error(&e) => return Ok(false),
+                Err(e) => return Err(e),
+            },
+            Some(current) if current.holder_identity == identity || current.is_expired(now) => {
+                self.write_record(&record, false)?
+            }
+            Some(_) => return Ok(false),
+        }
+
+        // Read the record back, in case another candidate took over an expired
+        // lease concurrently and its write landed after ours.
+        Ok(self
+            .read_record()?
+            .map_or(false, |current| current.holder_identity == identity))
+    }
+
+    fn release(&mut self, identity: &str) -> Result<()> {
+        match self.read_record()? {
+            Some(current) if current.holder_identity == identity => {
+                self.transport.delete(&self.key, "None")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// LeaderElector lets one of several replicas of a singleton loop, such as the
+/// task scheduler, do the work while the others stand by. The leader renews its
+/// lease whenever a third of the lease duration has passed since it last did,
+/// so it must call is_leader at least that often, even while idle. Replicas on
+/// standby try to acquire the lease as often. If the leader goes away without
+/// releasing the lease, another replica takes over once the lease expires.
+#[derive(Debug)]
+pub struct LeaderElector {
+    lock: Box<dyn LeaseLock>,
+    identity: String,
+    lease_duration: Duration,
+    /// When we last tried to acquire or renew the lease
+    attempted_at: Option<Instant>,
+    /// Whether that attempt got us the lease
+    leader: bool,
+    logger: Logger,
+}
+
+impl LeaderElector {
+    pub fn new(
+        lock: Box<dyn LeaseLock>,
+        identity: &str,
+        lease_duration: Duration,
+        parent_logger: &Logger,
+    ) -> Self {
+        LeaderElector {
+            lock,
+            identity: identity.to_owned(),
+            lease_duration,
+            attempted_at: None,
+            leader: false,
+            logger: parent_logger.new(o!("leader_identity" => identity.to_owned())),
+        }
+    }
+
+    /// Returns true if this replica holds the lease, first trying to acquire
+    /// or renew it if that is due. If the lease can't be renewed, this replica
+    /// is no longer the leader and an error is returned.
+    pub fn is_leader(&mut self) -> Result<bool> {
+        if let Some(attempted_at) = self.attempted_at {
+            if attempted_at.elapsed() < self.lease_duration / 3 {
+                return Ok(self.leader);
+            }
+        }
+
+        let was_leader = self.leader;
+        self.attempted_at = Some(Instant::now());
+        self.leader = false;
+        self.leader = self
+            .lock
+            .try_acquire_or_renew(&self.identity, self.lease_duration, Utc::now())
+            .context("failed to acquire or renew leader lease")?;
+
+        match (was_leader, self.leader) {
+            (false, true) => info!(self.logger, "became leader"),
+            (true, false) => warn!(self.logger, "lost leadership"),
+            _ => (),
+        }
+        Ok(self.leader)
+    }
+
+    /// Releases the lease if this replica holds it, e.g. when shutting down.
+    pub fn step_down(&mut self) -> Result<()> {
+        self.attempted_at = None;
+        if std::mem::take(&mut self.leader) {
+            info!(self.logger, "stepping down"; event::ACTION => "release lease");
+            self.lock.release(&self.identity)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{logging::setup_test_logging, transport::MemoryTransport};
+    use chrono::TimeZone;
+
+    #[test]
+    fn transport_lease_lock() {
+        let transport = MemoryTransport::new();
+        let mut lock_a = TransportLeaseLock::new(Box::new(transport.clone()), "leader");
+        let mut lock_b = TransportLeaseLock::new(Box::new(transport.clone()), "leader");
+        let lease_duration = Duration::from_secs(60);
+        let start = Utc.ymd(2021, 5, 10).and_hms(7, 0, 0);
+        let later = |seconds| start + chrono::Duration::seconds(seconds);
+
+        assert!(lock_a
+            .try_acquire_or_renew("a", lease_duration, start)
+            .unwrap());
+        assert!(!lock_b
+            .try_acquire_or_renew("b", lease_duration, later(30))
+            .unwrap());
+        // Renewing extends the lease
+        assert!(lock_a
+            .try_acquire_or_renew("a", lease_duration, later(50))
+            .unwrap());
+        assert!(!lock_b
+            .try_acquire_or_renew("b", lease_duration, later(100))
+            .unwrap());
+        // Once the lease expires, another candidate takes over
+        assert!(lock_b
+            .try_acquire_or_renew("b", lease_duration, later(111))
+            .unwrap());
+        assert!(!lock_a
+            .try_acquire_or_renew("a", lease_duration, later(112))
+            .unwrap());
+
+        // Only the holder can release the lease
+        lock_a.release("a").unwrap();
+        assert!(!lock_a
+            .try_acquire_or_renew("a", lease_duration, later(113))
+            .unwrap());
+        lock_b.release("b").unwrap();
+        assert!(lock_a
+            .try_acquire_or_renew("a", lease_duration, later(114))
+            .unwrap());
+    }
+
+    #[test]
+    fn leader_elector() {
+        let logger = setup_test_logging();
+        let transport = MemoryTransport::new();
+        let elector = |identity| {
+            LeaderElector::new(
+                Box::new(TransportLeaseLock::new(
+                    Box::new(transport.clone()),
+                    "leader",
+                )),
+                identity,
+                Duration::from_secs(60),
+                &logger,
+            )
+        };
+        let mut leader = elector("a");
+        let mut standby = elector("b");
+
+        assert!(leader.is_leader().unwrap());
+        assert!(!standby.is_leader().unwrap());
+        assert!(leader.is_leader().unwrap());
+
+        // Standby replicas don't try again until a third of the lease duration
+        // has passed, but new ones can take over right away
+        leader.step_down().unwrap();
+        assert!(!standby.is_leader().unwrap());
+        let mut successor = elector("c");
+        assert!(successor.is_leader().unwrap());
+        assert!(!leader.is_leader().unwrap());
+    }
+}
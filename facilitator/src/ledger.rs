@@ -0,0 +1,210 @@
+use crate::{transport::Transport, TransportError};
+use anyhow::{Context, Result};
+use chrono::{prelude::Utc, DateTime};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::PathBuf,
+};
+use uuid::Uuid;
+
+/// A record that an ingestion batch has been processed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    /// The batch UUID from the batch's header.
+    pub batch_uuid: Uuid,
+    /// The key of the batch's header in the ingestion bucket.
+    pub batch_path: String,
+    /// Hex encoded SHA-256 digest of the batch's header, for auditing.
+    pub header_digest: String,
+    /// When the batch was recorded.
+    pub recorded_at: DateTime<Utc>,
+    /// The trace ID of the task that recorded the batch.
+    pub trace_id: String,
+}
+
+impl LedgerEntry {
+    /// Returns true if this entry and `other` describe the same batch, i.e.
+    /// they were both recorded for a batch with the same UUID, path and
+    /// header, perhaps by different attempts at the same task.
+    pub fn same_batch(&self, other: &LedgerEntry) -> bool {
+        self.batch_uuid == other.batch_uuid
+            && self.batch_path == other.batch_path
+            && self.header_digest == other.header_digest
+    }
+}
+
+/// A BatchLedger records which ingestion batches have been processed, so that
+/// a batch UUID that is submitted more than once, whether maliciously or by a
+/// buggy ingestor, is not counted twice.
+pub trait BatchLedger: Debug {
+    /// Returns the entry recorded for the batch with the provided UUID, if any.
+    fn get(&mut self, batch_uuid: &Uuid, trace_id: &str) -> Result<Option<LedgerEntry>>;
+
+    /// Records the provided entry. Fails with TransportError::AlreadyExists if an
+    /// entry has already been recorded for the same batch UUID, so that of two
+    /// concurrent attempts to record a batch, only one succeeds.
+    fn record(&mut self, entry: &LedgerEntry, trace_id: &str) -> Result<()>;
+}
+
+/// A BatchLedger that appends entries as lines of JSON to a local file. Suitable
+/// for a single facilitator process, e.g. in tests or local deployments.
+#[derive(Debug)]
+pub struct LocalFileBatchLedger {
+    path: PathBuf,
+}
+
+impl LocalFileBatchLedger {
+    /// Creates a LocalFileBatchLedger that keeps its entries in the file at
+    /// `path`, which is created if it does not exist.
+    pub fn new(path: PathBuf) -> Self {
+        LocalFileBatchLedger { path }
+    }
+}
+
+impl BatchLedger for LocalFileBatchLedger {
+    fn get(&mut self, batch_uuid: &Uuid, _trace_id: &str) -> Result<Option<LedgerEntry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).context(format!("failed to open ledger {}", self.path.display()))
+            }
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line.context("failed to read ledger")?;
+            let entry: LedgerEntry = serde_json::from_str(&line)
+                .context(format!("failed to parse ledger entry {}", line))?;
+            if entry.batch_uuid == *batch_uuid {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    fn record(&mut self, entry: &LedgerEntry, trace_id: &str) -> Result<()> {
+        if self.get(&entry.batch_uuid, trace_id)?.is_some() {
+            return Err(TransportError::AlreadyExists(entry.batch_uuid.to_string()).into());
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&line))
+            .context(format!(
+                "failed to append to ledger {}",
+                self.path.display()
+            ))
+    }
+}
+
+/// A BatchLedger that stores each entry as a JSON object, named for the batch
+/// UUID, in a Transport. Backed by a cloud storage bucket, this allows all the
+/// intake workers for an instance to share a ledger. Entries are written with
+/// Transport::put_if_absent, so concurrent attempts to record the same batch
+/// cannot both succeed on transports that create objects exclusively.
+#[derive(Debug)]
+pub struct TransportBatchLedger {
+    transport: Box<dyn Transport>,
+}
+
+impl TransportBatchLedger {
+    /// Creates a TransportBatchLedger that keeps its entries in the provided
+    /// transport.
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        TransportBatchLedger { transport }
+    }
+
+    fn key(batch_uuid: &Uuid) -> String {
+        format!("{}.json", batch_uuid.to_hyphenated())
+    }
+}
+
+impl BatchLedger for TransportBatchLedger {
+    fn get(&mut self, batch_uuid: &Uuid, trace_id: &str) -> Result<Option<LedgerEntry>> {
+        let key = Self::key(batch_uuid);
+        if !self.transport.exists(&key, trace_id)? {
+            return Ok(None);
+        }
+        let reader = self.transport.get(&key, trace_id)?;
+        let entry = serde_json::from_reader(reader)
+            .context(format!("failed to parse ledger entry {}", key))?;
+        Ok(Some(entry))
+    }
+
+    fn record(&mut self, entry: &LedgerEntry, trace_id: &str) -> Result<()> {
+        let key = Self::key(&entry.batch_uuid);
+        let mut writer = self.transport.put_if_absent(&key, trace_id)?;
+        let result = serde_json::to_writer(&mut writer, entry)
+            .context("failed to write ledger entry")
+            .and_then(|_| writer.complete_upload());
+        if result.is_err() {
+            // Don't mask the original error with any failure to cancel
+            let _ = writer.cancel_upload();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{is_already_exists_error, MemoryTransport};
+
+    fn entry(batch_uuid: Uuid, batch_path: &str) -> LedgerEntry {
+        LedgerEntry {
+            batch_uuid,
+            batch_path: batch_path.to_owned(),
+            header_digest: "abcdef".to_owned(),
+            recorded_at: Utc::now(),
+            trace_id: "trace-id".to_owned(),
+        }
+    }
+
+    fn check_ledger(ledger: &mut dyn BatchLedger) {
+        let first = entry(Uuid::new_v4(), "aggregation/2021/01/01/00/00/first.batch");
+        let second = entry(Uuid::new_v4(), "aggregation/2021/01/01/00/00/second.batch");
+        assert_eq!(ledger.get(&first.batch_uuid, "trace-id").unwrap(), None);
+
+        ledger.record(&first, "trace-id").unwrap();
+        ledger.record(&second, "trace-id").unwrap();
+        assert_eq!(
+            ledger.get(&first.batch_uuid, "trace-id").unwrap(),
+            Some(first.clone())
+        );
+        assert_eq!(
+            ledger.get(&second.batch_uuid, "trace-id").unwrap(),
+            Some(second.clone())
+        );
+
+        // A batch UUID can only be recorded once
+        let replay = entry(
+            first.batch_uuid,
+            "aggregation/2021/01/02/00/00/replay.batch",
+        );
+        let error = ledger.record(&replay, "trace-id").unwrap_err();
+        assert!(is_already_exists_error(&error), "{:?}", error);
+        let recorded = ledger.get(&first.batch_uuid, "trace-id").unwrap().unwrap();
+        assert!(recorded.same_batch(&first));
+        assert!(!recorded.same_batch(&replay));
+    }
+
+    #[test]
+    fn local_file_ledger() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        check_ledger(&mut LocalFileBatchLedger::new(
+            tempdir.path().join("ledger.jsonl"),
+        ));
+    }
+
+    #[test]
+    fn transport_ledger() {
+        check_ledger(&mut TransportBatchLedger::new(Box::new(
+            MemoryTransport::new(),
+        )));
+    }
+}
@@ -1,25 +1,55 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use ring::{digest, signature::EcdsaKeyPair};
-use std::io::Write;
+use serde::Deserialize;
+use std::{
+    fmt::{self, Display},
+    io::{Read, Write},
+    str::FromStr,
+    time::Duration,
+};
 
 pub mod aggregation;
 pub mod aws_credentials;
 pub mod batch;
 pub mod config;
+pub mod dap;
+pub mod dedup;
+pub mod expire;
+pub mod export;
+pub mod field;
 mod gcp_oauth;
+pub mod hpke;
 pub mod http;
 pub mod idl;
+pub mod inspect;
 pub mod intake;
+pub mod key_provider;
 pub mod kubernetes;
+pub mod leader;
+pub mod ledger;
+pub mod load_test;
 pub mod logging;
 pub mod manifest;
 pub mod metrics;
-mod retries;
+pub mod noise;
+pub mod packet_encryption;
+pub mod prio_crypto;
+pub mod retries;
+mod runtime;
 pub mod sample;
+pub mod schedule;
+pub mod shutdown;
+pub mod signing;
+pub mod state;
 pub mod task;
 pub mod test_utils;
 pub mod transport;
 
+pub use gcp_oauth::{
+    configure_oauth_token_cache, configure_oauth_token_refresh, EncryptedFileTokenCache,
+    OauthTokenCache, TokenRetriesExhaustedError,
+};
+
 pub const DATE_FORMAT: &str = "%Y/%m/%d/%H/%M";
 
 #[derive(Debug, thiserror::Error)]
@@ -39,24 +69,275 @@ pub enum Error {
     MalformedHeaderError(String),
     #[error("malformed data packet: {0}")]
     MalformedDataPacketError(String),
+    #[error("malformed record: {0}")]
+    MalformedRecordError(String),
     #[error("end of file")]
     EofError,
+
+    // The following variants are returned from the intake and aggregation
+    // entry points, classifying the failure of a task so that callers can
+    // decide whether to retry it. Each carries the full error, including the
+    // context added on its way up.
+    #[error("{1:#}")]
+    Transport(TransportError, anyhow::Error),
+    #[error("{1:#}")]
+    Validation(ValidationError, anyhow::Error),
+    #[error("{1:#}")]
+    Crypto(CryptoError, anyhow::Error),
+    #[error("{1:#}")]
+    Task(TaskError, anyhow::Error),
+}
+
+impl Error {
+    /// Classifies an error encountered while running a task by the most
+    /// specific of TaskError, CryptoError, ValidationError or TransportError
+    /// found among the error and its context, falling back to recognizable
+    /// failures of HTTP requests or of parsing batches. Errors that can't be
+    /// classified are returned as Error::AnyhowError.
+    pub fn classify(error: anyhow::Error) -> Error {
+        if let Some(kind) = error.downcast_ref::<TaskError>().cloned() {
+            return Error::Task(kind, error);
+        }
+        if let Some(kind) = error.downcast_ref::<CryptoError>().cloned() {
+            return Error::Crypto(kind, error);
+        }
+        if let Some(kind) = error.downcast_ref::<ValidationError>().cloned() {
+            return Error::Validation(kind, error);
+        }
+        if let Some(kind) = error.downcast_ref::<TransportError>().cloned() {
+            return Error::Transport(kind, error);
+        }
+        if let Some(status) = http::error_http_status(&error) {
+            return Error::Transport(TransportError::HttpStatus(status), error);
+        }
+        if let Some(http::HttpError::Transport(transport)) = error.downcast_ref::<http::HttpError>()
+        {
+            let kind = TransportError::Connection(transport.to_string());
+            return Error::Transport(kind, error);
+        }
+        let malformed = match error.downcast_ref::<Error>() {
+            Some(e @ Error::AvroError(..))
+            | Some(e @ Error::MalformedHeaderError(_))
+            | Some(e @ Error::MalformedDataPacketError(_))
+            | Some(e @ Error::MalformedRecordError(_))
+            | Some(e @ Error::EofError) => Some(e.to_string()),
+            _ => None,
+        };
+        match malformed {
+            Some(message) => Error::Validation(ValidationError::Malformed(message), error),
+            None => Error::AnyhowError(error),
+        }
+    }
+
+    /// Returns true if retrying the operation that failed with this error
+    /// could succeed. Errors that can't be classified are assumed to be
+    /// retryable, since task queues redeliver failed tasks anyway.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Transport(kind, _) => kind.is_retryable(),
+            Error::Validation(kind, _) => kind.is_retryable(),
+            Error::Crypto(kind, _) => kind.is_retryable(),
+            Error::Task(kind, _) => kind.is_retryable(),
+            Error::AvroError(..)
+            | Error::MalformedHeaderError(_)
+            | Error::MalformedDataPacketError(_)
+            | Error::MalformedRecordError(_)
+            | Error::EofError => false,
+            Error::AnyhowError(_) => true,
+        }
+    }
+}
+
+/// Failures to read or write objects in a transport.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum TransportError {
+    /// The object does not exist, e.g. because a peer has not written a
+    /// validation batch yet.
+    #[error("object not found: {0}")]
+    NotFound(String),
+    /// An object that may only be written once already exists.
+    #[error("object already exists: {0}")]
+    AlreadyExists(String),
+    /// The storage service responded with an unsuccessful HTTP status.
+    #[error("storage request failed with HTTP status {0}")]
+    HttpStatus(u16),
+    /// The storage service could not be reached.
+    #[error("failed to connect to storage service: {0}")]
+    Connection(String),
+}
+
+impl TransportError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TransportError::NotFound(_) => true,
+            TransportError::AlreadyExists(_) => false,
+            TransportError::HttpStatus(status) => *status == 429 || *status >= 500,
+            TransportError::Connection(_) => true,
+        }
+    }
+}
+
+/// Batches or packets whose contents are not acceptable.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ValidationError {
+    /// A header, packet or other record could not be parsed.
+    #[error("malformed batch: {0}")]
+    Malformed(String),
+    /// The digest of a packet file does not match the one in its header.
+    #[error(
+        "packet file digest in header {header_digest} does not match actual packet file \
+        digest {actual_digest}"
+    )]
+    PacketFileDigestMismatch {
+        header_digest: String,
+        actual_digest: String,
+    },
+    /// The headers of batches that must describe the same data disagree.
+    #[error("batch headers do not match: {0}")]
+    HeaderMismatch(String),
+    /// A batch's end time, in milliseconds since the UNIX epoch, is further
+    /// ahead of the current time than clock skew can account for.
+    #[error("batch end time {batch_end_time} is more than {max_future_skew:?} after {now}")]
+    BatchTooFarInFuture {
+        batch_end_time: i64,
+        now: i64,
+        max_future_skew: Duration,
+    },
+    /// A batch's end time, in milliseconds since the UNIX epoch, is further
+    /// behind the current time than batches may be.
+    #[error("batch end time {batch_end_time} is more than {max_age:?} before {now}")]
+    BatchTooOld {
+        batch_end_time: i64,
+        now: i64,
+        max_age: Duration,
+    },
+}
+
+impl ValidationError {
+    pub fn is_retryable(&self) -> bool {
+        // A batch from the future will be acceptable once our clock catches up
+        matches!(self, ValidationError::BatchTooFarInFuture { .. })
+    }
+}
+
+/// Failures to verify signatures or decrypt packets.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum CryptoError {
+    /// A batch's signature does not verify with any of the signer's keys.
+    #[error("invalid signature on header with key {key_identifier}")]
+    InvalidSignature { key_identifier: String },
+    /// A batch was signed with a key that isn't known, e.g. because the
+    /// signer rotated keys and our copy of its manifest is out of date.
+    #[error("key identifier {key_identifier} not present in key map")]
+    UnknownSigningKey { key_identifier: String },
+    /// A packet could not be decrypted with any packet decryption key.
+    #[error(
+        "failed to construct validation message for packet {0}, probably due to packet \
+        decryption key mismatch"
+    )]
+    PacketDecryption(String),
+}
+
+impl CryptoError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CryptoError::InvalidSignature { .. } => false,
+            // Keys may have been rotated since they were obtained
+            CryptoError::UnknownSigningKey { .. } | CryptoError::PacketDecryption(_) => true,
+        }
+    }
+}
+
+/// Failures of a task as a whole, rather than of any one object in it.
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum TaskError {
+    /// The batch's UUID was already recorded in the batch ledger for a
+    /// different batch.
+    #[error("batch UUID {0} was already processed")]
+    BatchAlreadyProcessed(uuid::Uuid),
+    /// More of a batch's packets were malformed than may be skipped.
+    #[error("{skipped} of {total} packets were malformed, more than {max_percent}%")]
+    TooManyMalformedPackets {
+        skipped: u64,
+        total: u64,
+        max_percent: f64,
+    },
+    /// A task message is of a newer version than this facilitator supports.
+    #[error("task message version {version} is newer than supported version {supported}")]
+    UnsupportedTaskVersion { version: u32, supported: u32 },
+    /// A task message's HMAC is missing or does not match the message.
+    #[error("task message could not be authenticated: {0}")]
+    UnauthenticatedTask(String),
+}
+
+impl TaskError {
+    pub fn is_retryable(&self) -> bool {
+        false
+    }
+}
+
+/// The digest algorithms that may be used to compute the packet file digests
+/// that are signed over in batch headers. SHA-256 is used unless a peer's
+/// specific manifest asks for something else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha384,
+}
+
+impl Default for DigestAlgorithm {
+    fn default() -> Self {
+        DigestAlgorithm::Sha256
+    }
+}
+
+impl DigestAlgorithm {
+    fn ring_algorithm(self) -> &'static digest::Algorithm {
+        match self {
+            DigestAlgorithm::Sha256 => &digest::SHA256,
+            DigestAlgorithm::Sha384 => &digest::SHA384,
+        }
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "sha384" => Ok(DigestAlgorithm::Sha384),
+            _ => Err(anyhow!("unknown digest algorithm {}", s)),
+        }
+    }
+}
+
+impl Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DigestAlgorithm::Sha256 => write!(f, "sha256"),
+            DigestAlgorithm::Sha384 => write!(f, "sha384"),
+        }
+    }
 }
 
-/// An implementation of transport::TransportWriter that computes a SHA256
-/// digest over the content it is provided.
+/// An implementation of transport::TransportWriter that computes a digest over
+/// the content it is provided.
 pub struct DigestWriter {
     context: digest::Context,
 }
 
 impl DigestWriter {
-    fn new() -> DigestWriter {
+    /// Creates a DigestWriter that computes a digest using the provided
+    /// algorithm.
+    fn new(algorithm: DigestAlgorithm) -> DigestWriter {
         DigestWriter {
-            context: digest::Context::new(&digest::SHA256),
+            context: digest::Context::new(algorithm.ring_algorithm()),
         }
     }
 
-    /// Consumes the DigestWriter and returns the computed SHA256 hash.
+    /// Consumes the DigestWriter and returns the computed hash.
     fn finish(self) -> digest::Digest {
         self.context.finish()
     }
@@ -73,6 +354,44 @@ impl Write for DigestWriter {
     }
 }
 
+/// An std::io::Read wrapper that computes a digest over the content that is
+/// read through it, allowing a digest to be computed over an object as it is
+/// streamed from a transport.
+pub struct DigestReader<R: Read> {
+    reader: R,
+    context: digest::Context,
+}
+
+impl<R: Read> DigestReader<R> {
+    /// Creates a DigestReader that computes a SHA256 digest.
+    pub fn new(reader: R) -> DigestReader<R> {
+        DigestReader::with_algorithm(reader, DigestAlgorithm::default())
+    }
+
+    /// Creates a DigestReader that computes a digest using the provided
+    /// algorithm.
+    pub fn with_algorithm(reader: R, algorithm: DigestAlgorithm) -> DigestReader<R> {
+        DigestReader {
+            reader,
+            context: digest::Context::new(algorithm.ring_algorithm()),
+        }
+    }
+
+    /// Consumes the DigestReader and returns the hash of the content
+    /// read through it so far.
+    pub fn finish(self) -> digest::Digest {
+        self.context.finish()
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let read = self.reader.read(buf)?;
+        self.context.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
 /// SidecarWriter wraps a vector of std::io::Writes of one type and writes all
 /// provided buffers to them. It also writes all buffers to an additional
 /// instance of std::io:Write that may be of a different type than the ones in
@@ -128,12 +447,17 @@ fn hex_dump(bytes: &[u8]) -> String {
 
 #[cfg(test)]
 mod tests {
-    use crate::DigestWriter;
-    use std::io::Write;
+    use crate::{
+        CryptoError, DigestAlgorithm, DigestReader, DigestWriter, Error, TaskError, TransportError,
+        ValidationError,
+    };
+    use anyhow::anyhow;
+    use assert_matches::assert_matches;
+    use std::io::{Read, Write};
 
     #[test]
     fn digest_writer_test() {
-        let mut writer = DigestWriter::new();
+        let mut writer = DigestWriter::new(DigestAlgorithm::Sha256);
         let written = writer
             .write("I expect to be written into sha256".to_string().as_bytes())
             .unwrap();
@@ -151,4 +475,115 @@ mod tests {
             "b1b64ca32c118bfd5d1f40fdb25314468f82c0e9427f4f107ddfa89ce357a3ec".to_string()
         )
     }
+
+    #[test]
+    fn digest_reader_test() {
+        let content = "I expect to be written into sha256".to_string();
+        let mut reader = DigestReader::new(content.as_bytes());
+
+        // Read in small chunks to exercise incremental digest computation
+        let mut read_content = Vec::new();
+        let mut buf = [0; 5];
+        loop {
+            let read = reader.read(&mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            read_content.extend_from_slice(&buf[..read]);
+        }
+        assert_eq!(read_content, content.as_bytes());
+
+        let mut writer = DigestWriter::new(DigestAlgorithm::Sha256);
+        writer.write_all(content.as_bytes()).unwrap();
+
+        assert_eq!(reader.finish().as_ref(), writer.finish().as_ref());
+    }
+
+    #[test]
+    fn digest_algorithms() {
+        let content = "I expect to be written into sha384".to_string();
+
+        let mut writer = DigestWriter::new(DigestAlgorithm::Sha384);
+        writer.write_all(content.as_bytes()).unwrap();
+        let digest = writer.finish();
+        assert_eq!(digest.as_ref().len(), 48);
+
+        let mut reader = DigestReader::with_algorithm(content.as_bytes(), DigestAlgorithm::Sha384);
+        std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+        assert_eq!(reader.finish().as_ref(), digest.as_ref());
+
+        // A SHA-256 digest over the same content differs
+        let mut writer = DigestWriter::new(DigestAlgorithm::Sha256);
+        writer.write_all(content.as_bytes()).unwrap();
+        assert_ne!(writer.finish().as_ref(), digest.as_ref());
+
+        for algorithm in &[DigestAlgorithm::Sha256, DigestAlgorithm::Sha384] {
+            assert_eq!(
+                algorithm.to_string().parse::<DigestAlgorithm>().unwrap(),
+                *algorithm
+            );
+        }
+        assert!("md5".parse::<DigestAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn classify_errors() {
+        let classify = |error: anyhow::Error| {
+            let error = Error::classify(error.context("failed to do something"));
+            (error.to_string(), error.is_retryable(), error)
+        };
+
+        let (message, retryable, error) = classify(anyhow::Error::new(TransportError::NotFound(
+            "key".to_owned(),
+        )));
+        assert_matches!(error, Error::Transport(TransportError::NotFound(key), _) if key == "key");
+        assert_eq!(message, "failed to do something: object not found: key");
+        assert!(retryable);
+
+        let (_, retryable, error) = classify(
+            anyhow!("no such file").context(TransportError::AlreadyExists("key".to_owned())),
+        );
+        assert_matches!(error, Error::Transport(TransportError::AlreadyExists(_), _));
+        assert!(!retryable);
+
+        // The task's failure is more specific than its cause
+        let (_, retryable, error) = classify(
+            anyhow::Error::new(CryptoError::PacketDecryption("uuid".to_owned())).context(
+                TaskError::TooManyMalformedPackets {
+                    skipped: 2,
+                    total: 3,
+                    max_percent: 10.0,
+                },
+            ),
+        );
+        assert_matches!(
+            error,
+            Error::Task(TaskError::TooManyMalformedPackets { .. }, _)
+        );
+        assert!(!retryable);
+
+        let (_, retryable, error) = classify(anyhow::Error::new(CryptoError::UnknownSigningKey {
+            key_identifier: "key".to_owned(),
+        }));
+        assert_matches!(
+            error,
+            Error::Crypto(CryptoError::UnknownSigningKey { .. }, _)
+        );
+        assert!(retryable);
+
+        let (_, retryable, error) = classify(anyhow::Error::new(Error::MalformedHeaderError(
+            "bad header".to_owned(),
+        )));
+        assert_matches!(error, Error::Validation(ValidationError::Malformed(_), _));
+        assert!(!retryable);
+
+        let (message, retryable, error) = classify(anyhow!("something else"));
+        assert_matches!(error, Error::AnyhowError(_));
+        assert_eq!(message, "failed to do something");
+        assert!(retryable);
+
+        assert!(TransportError::HttpStatus(503).is_retryable());
+        assert!(TransportError::HttpStatus(429).is_retryable());
+        assert!(!TransportError::HttpStatus(403).is_retryable());
+    }
 }
@@ -0,0 +1,201 @@
+use serde::Serialize;
+use std::{fs, time::Duration};
+
+/// Percentiles of a distribution of latencies, in milliseconds.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LatencyPercentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl LatencyPercentiles {
+    /// Computes nearest-rank percentiles of the provided latencies, or returns
+    /// None if there are none.
+    pub fn from_latencies(latencies: &[Duration]) -> Option<Self> {
+        let mut sorted = latencies.to_vec();
+        sorted.sort_unstable();
+        let percentile = |p: f64| -> Option<f64> {
+            let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+            sorted
+                .get(rank.max(1) - 1)
+                .map(|latency| latency.as_secs_f64() * 1000.0)
+        };
+        Some(LatencyPercentiles {
+            p50: percentile(50.0)?,
+            p90: percentile(90.0)?,
+            p99: percentile(99.0)?,
+            max: percentile(100.0)?,
+        })
+    }
+}
+
+/// The outcome of a load test, in which sample ingestion batches are
+/// generated at a target rate and concurrently intaken by both data share
+/// processors.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LoadTestReport {
+    /// Time from the start of the test until the last intake finished
+    pub elapsed_seconds: f64,
+    /// Rate at which sample batches were to be generated, per second
+    pub target_batch_rate: f64,
+    /// Number of sample batches that were generated, each of which yields an
+    /// ingestion batch for each data share processor
+    pub batches_generated: usize,
+    /// Rate at which sample batches were actually generated, per second
+    pub batch_rate: f64,
+    /// Number of sample batches whose generation failed
+    pub failed_generations: usize,
+    /// Number of ingestion batches that were intaken
+    pub ingestion_batches_intaken: usize,
+    /// Number of ingestion batches whose intake failed
+    pub failed_intakes: usize,
+    /// Number of packets in the ingestion batches that were intaken
+    pub packets_intaken: u64,
+    /// Sustained intake throughput over the whole test
+    pub packets_per_second: f64,
+    /// Time from an ingestion batch being written until its validation
+    /// batches were written, including time spent waiting for an intake worker
+    pub intake_latency_ms: Option<LatencyPercentiles>,
+    /// Time spent intaking an ingestion batch
+    pub intake_duration_ms: Option<LatencyPercentiles>,
+    /// The most memory that was resident at once, if it can be determined
+    pub peak_resident_memory_bytes: Option<u64>,
+}
+
+/// Accumulates the outcomes of the generation and intake of batches during a
+/// load test.
+#[derive(Debug, Default)]
+pub struct LoadTestStats {
+    batches_generated: usize,
+    failed_generations: usize,
+    failed_intakes: usize,
+    packets_intaken: u64,
+    intake_latencies: Vec<Duration>,
+    intake_durations: Vec<Duration>,
+}
+
+impl LoadTestStats {
+    pub fn record_generation(&mut self, succeeded: bool) {
+        if succeeded {
+            self.batches_generated += 1;
+        } else {
+            self.failed_generations += 1;
+        }
+    }
+
+    /// Records the successful intake of an ingestion batch containing
+    /// `packets` packets.
+    pub fn record_intake(&mut self, latency: Duration, duration: Duration, packets: u64) {
+        self.intake_latencies.push(latency);
+        self.intake_durations.push(duration);
+        self.packets_intaken += packets;
+    }
+
+    pub fn record_failed_intake(&mut self) {
+        self.failed_intakes += 1;
+    }
+
+    /// Summarizes the recorded outcomes of a load test that ran for `elapsed`.
+    pub fn report(&self, elapsed: Duration, target_batch_rate: f64) -> LoadTestReport {
+        let elapsed_seconds = elapsed.as_secs_f64();
+        let per_second = |count: f64| {
+            if elapsed_seconds > 0.0 {
+                count / elapsed_seconds
+            } else {
+                0.0
+            }
+        };
+        LoadTestReport {
+            elapsed_seconds,
+            target_batch_rate,
+            batches_generated: self.batches_generated,
+            batch_rate: per_second(self.batches_generated as f64),
+            failed_generations: self.failed_generations,
+            ingestion_batches_intaken: self.intake_latencies.len(),
+            failed_intakes: self.failed_intakes,
+            packets_intaken: self.packets_intaken,
+            packets_per_second: per_second(self.packets_intaken as f64),
+            intake_latency_ms: LatencyPercentiles::from_latencies(&self.intake_latencies),
+            intake_duration_ms: LatencyPercentiles::from_latencies(&self.intake_durations),
+            peak_resident_memory_bytes: peak_resident_memory_bytes(),
+        }
+    }
+}
+
+/// Returns the most memory that has been resident at once in this process, as
+/// reported by /proc/self/status, or None if that is not available, e.g.
+/// because this is not Linux.
+pub fn peak_resident_memory_bytes() -> Option<u64> {
+    parse_peak_resident_memory(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+fn parse_peak_resident_memory(status: &str) -> Option<u64> {
+    // The line looks like "VmHWM:     12345 kB"
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_percentiles() {
+        assert_eq!(LatencyPercentiles::from_latencies(&[]), None);
+
+        let latencies: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        assert_eq!(
+            LatencyPercentiles::from_latencies(&latencies).unwrap(),
+            LatencyPercentiles {
+                p50: 50.0,
+                p90: 90.0,
+                p99: 99.0,
+                max: 100.0,
+            }
+        );
+
+        let single = LatencyPercentiles::from_latencies(&[Duration::from_millis(7)]).unwrap();
+        assert_eq!(single.p50, 7.0);
+        assert_eq!(single.max, 7.0);
+    }
+
+    #[test]
+    fn report() {
+        let mut stats = LoadTestStats::default();
+        stats.record_generation(true);
+        stats.record_generation(true);
+        stats.record_generation(false);
+        stats.record_intake(Duration::from_millis(30), Duration::from_millis(10), 100);
+        stats.record_intake(Duration::from_millis(50), Duration::from_millis(20), 100);
+        stats.record_failed_intake();
+
+        let report = stats.report(Duration::from_secs(4), 1.0);
+        assert_eq!(report.elapsed_seconds, 4.0);
+        assert_eq!(report.batches_generated, 2);
+        assert_eq!(report.batch_rate, 0.5);
+        assert_eq!(report.failed_generations, 1);
+        assert_eq!(report.ingestion_batches_intaken, 2);
+        assert_eq!(report.failed_intakes, 1);
+        assert_eq!(report.packets_intaken, 200);
+        assert_eq!(report.packets_per_second, 50.0);
+        assert_eq!(report.intake_latency_ms.unwrap().max, 50.0);
+        assert_eq!(report.intake_duration_ms.unwrap().p50, 10.0);
+    }
+
+    #[test]
+    fn parse_status() {
+        let status =
+            "Name:\tfacilitator\nVmPeak:\t  200000 kB\nVmHWM:\t    1234 kB\nVmRSS:\t     999 kB\n";
+        assert_eq!(parse_peak_resident_memory(status), Some(1234 * 1024));
+        assert_eq!(parse_peak_resident_memory("Name:\tfacilitator\n"), None);
+    }
+}
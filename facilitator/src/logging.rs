@@ -1,18 +1,41 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use atty::{self, Stream};
+use opentelemetry::{
+    global,
+    propagation::TextMapPropagator,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    trace::TraceContextExt,
+    KeyValue,
+};
 use serde::Serialize;
-use slog::{o, Drain, FnValue, Level, LevelFilter, Logger, PushFnValue};
-use slog_json::Json;
-use slog_term::{FullFormat, PlainSyncDecorator, TermDecorator, TestStdoutWriter};
+use serde_json::{Map, Value};
+use slog::{o, Drain, FnValue, Key, Level, LevelFilter, Logger, Never, OwnedKVList, Record, KV};
+use slog_term::{FullFormat, PlainSyncDecorator, TestStdoutWriter};
 use std::{
+    collections::HashMap,
     convert::From,
     fmt::{self, Display, Formatter},
-    io::{stderr, Stderr},
+    io::stderr,
     str::FromStr,
     thread,
 };
+use tracing::{Event, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    field::Visit,
+    filter::Targets,
+    fmt::{format::JsonFields, FmtContext, FormatEvent, FormattedFields, MakeWriter},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+};
 
-/// `event` defines constants for structured events
+use crate::runtime::shared_runtime;
+
+/// `event` defines constants for structured events. Task context such as the
+/// trace, aggregation and batch IDs is recorded as the fields of tracing spans
+/// that tasks and batches are processed in, and events logged within a span
+/// carry its fields and those of the spans enclosing it (see
+/// docs/monitoring.md). Spans' field names match these keys.
 pub mod event {
     /// An event key is a key that could be encountered in the fields of a
     /// structured log message.
@@ -22,8 +45,12 @@ pub mod event {
     pub const TRACE_ID: EventKey = "trace_id";
     /// The task handle structure
     pub const TASK_HANDLE: EventKey = "task_handle";
+    /// The locality whose configuration a task is handled with
+    pub const LOCALITY: EventKey = "locality";
+    /// The name of the ingestion server whose batch is being intaken
+    pub const INGESTION_SERVER: EventKey = "ingestion_server";
     /// The name of the aggregation
-    pub(crate) const AGGREGATION_NAME: EventKey = "aggregation_name";
+    pub const AGGREGATION_NAME: EventKey = "aggregation_name";
     /// The storage path from which ingestion batches are read/written
     pub(crate) const INGESTION_PATH: EventKey = "ingestion_path";
     /// The storage path from which own validation batches are read/written
@@ -33,7 +60,7 @@ pub mod event {
     /// The UUID of a packet that something happened to
     pub(crate) const PACKET_UUID: EventKey = "packet_uuid";
     /// The ID (usually a UUID) of a batch that something happened to
-    pub(crate) const BATCH_ID: EventKey = "batch_id";
+    pub const BATCH_ID: EventKey = "batch_id";
     /// The date of a batch that something happened to
     pub(crate) const BATCH_DATE: EventKey = "batch_date";
     /// The path to some object store (e.g., an S3 bucket or a local directory)
@@ -48,15 +75,17 @@ pub mod event {
     /// Unique identifier for a task queue
     pub(crate) const TASK_QUEUE_ID: EventKey = "task_queue-id";
     /// Description of an action being retried
-    pub(crate) const ACTION: EventKey = "action";
+    pub const ACTION: EventKey = "action";
+    /// How long something took, in seconds
+    pub(crate) const DURATION_SECONDS: EventKey = "duration_seconds";
 }
 
-/// Severity maps `log::Level` to Google Cloud Platform's notion of Severity.
+/// Severity maps `tracing::Level` to Google Cloud Platform's notion of
+/// Severity.
 /// https://cloud.google.com/logging/docs/reference/v2/rest/v2/LogEntry#LogSeverity
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "UPPERCASE")]
 enum Severity {
-    Critical,
     Debug,
     Info,
     Warning,
@@ -66,7 +95,6 @@ enum Severity {
 impl Display for Severity {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let string = match self {
-            Severity::Critical => "CRITICAL",
             Severity::Debug => "DEBUG",
             Severity::Info => "INFO",
             Severity::Warning => "WARNING",
@@ -76,87 +104,363 @@ impl Display for Severity {
     }
 }
 
-impl From<Level> for Severity {
-    fn from(slog_level: Level) -> Self {
-        match slog_level {
-            Level::Critical => Severity::Critical,
-            Level::Error => Severity::Error,
-            Level::Warning => Severity::Warning,
-            Level::Info => Severity::Info,
-            Level::Debug => Severity::Debug,
-            Level::Trace => Severity::Debug,
+impl From<&tracing::Level> for Severity {
+    fn from(level: &tracing::Level) -> Self {
+        match *level {
+            tracing::Level::ERROR => Severity::Error,
+            tracing::Level::WARN => Severity::Warning,
+            tracing::Level::INFO => Severity::Info,
+            tracing::Level::DEBUG | tracing::Level::TRACE => Severity::Debug,
+        }
+    }
+}
+
+/// Maps a slog level to the tracing level that records at it are emitted at.
+/// tracing has no critical level, so critical records are errors.
+fn tracing_level(level: Level) -> tracing::Level {
+    match level {
+        Level::Critical | Level::Error => tracing::Level::ERROR,
+        Level::Warning => tracing::Level::WARN,
+        Level::Info => tracing::Level::INFO,
+        Level::Debug => tracing::Level::DEBUG,
+        Level::Trace => tracing::Level::TRACE,
+    }
+}
+
+/// The field of a tracing event emitted by TracingDrain in which the key-value
+/// pairs of the slog record are encoded as a JSON object.
+const SLOG_FIELDS: &str = "slog_fields";
+
+/// TracingDrain is a slog drain that emits records as tracing events, so that
+/// records logged through the `slog::Logger`s passed around the facilitator
+/// are written by the tracing subscriber along with the fields of the spans
+/// they were logged in. The drain must run on the thread the record was
+/// logged on, since that is where the current span is tracked.
+struct TracingDrain;
+
+impl Drain for TracingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record<'_>, values: &OwnedKVList) -> Result<(), Never> {
+        // Values from the record take precedence over those from the logger,
+        // and those from child loggers over those from their parents.
+        let mut serializer = JsonSerializer(Map::new());
+        let _ = record.kv().serialize(record, &mut serializer);
+        let _ = values.serialize(record, &mut serializer);
+        let fields = Value::Object(serializer.0);
+
+        // tracing's macros require a constant level
+        match tracing_level(record.level()) {
+            tracing::Level::ERROR => {
+                tracing::error!(message = %record.msg(), slog_fields = %fields)
+            }
+            tracing::Level::WARN => tracing::warn!(message = %record.msg(), slog_fields = %fields),
+            tracing::Level::INFO => tracing::info!(message = %record.msg(), slog_fields = %fields),
+            tracing::Level::DEBUG => {
+                tracing::debug!(message = %record.msg(), slog_fields = %fields)
+            }
+            tracing::Level::TRACE => {
+                tracing::trace!(message = %record.msg(), slog_fields = %fields)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// JsonSerializer collects the key-value pairs of a slog record into a JSON
+/// object, ignoring keys that were already collected.
+struct JsonSerializer(Map<String, Value>);
+
+impl JsonSerializer {
+    fn insert<V: Into<Value>>(&mut self, key: Key, value: V) -> slog::Result {
+        self.0.entry(key).or_insert_with(|| value.into());
+        Ok(())
+    }
+}
+
+impl slog::Serializer for JsonSerializer {
+    fn emit_arguments(&mut self, key: Key, value: &fmt::Arguments<'_>) -> slog::Result {
+        self.insert(key, value.to_string())
+    }
+
+    fn emit_str(&mut self, key: Key, value: &str) -> slog::Result {
+        self.insert(key, value)
+    }
+
+    fn emit_bool(&mut self, key: Key, value: bool) -> slog::Result {
+        self.insert(key, value)
+    }
+
+    fn emit_unit(&mut self, key: Key) -> slog::Result {
+        self.insert(key, Value::Null)
+    }
+
+    fn emit_none(&mut self, key: Key) -> slog::Result {
+        self.insert(key, Value::Null)
+    }
+
+    fn emit_usize(&mut self, key: Key, value: usize) -> slog::Result {
+        self.insert(key, value as u64)
+    }
+
+    fn emit_isize(&mut self, key: Key, value: isize) -> slog::Result {
+        self.insert(key, value as i64)
+    }
+
+    fn emit_u32(&mut self, key: Key, value: u32) -> slog::Result {
+        self.insert(key, value)
+    }
+
+    fn emit_i32(&mut self, key: Key, value: i32) -> slog::Result {
+        self.insert(key, value)
+    }
+
+    fn emit_u64(&mut self, key: Key, value: u64) -> slog::Result {
+        self.insert(key, value)
+    }
+
+    fn emit_i64(&mut self, key: Key, value: i64) -> slog::Result {
+        self.insert(key, value)
+    }
+
+    fn emit_f64(&mut self, key: Key, value: f64) -> slog::Result {
+        self.insert(key, value)
+    }
+}
+
+/// FieldVisitor collects the fields of a tracing event into a JSON object.
+/// The key-value pairs of records from TracingDrain are added to the object
+/// rather than nested in it.
+struct FieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        if field.name() == SLOG_FIELDS {
+            if let Ok(Value::Object(fields)) = serde_json::from_str(&value) {
+                self.0.extend(fields);
+            }
+            return;
+        }
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+}
+
+/// EventFormat writes tracing events with the fields of the spans they occur
+/// in, from the outermost span inwards, followed by the event's own fields,
+/// so that the innermost value of a field wins.
+struct EventFormat {
+    json: bool,
+}
+
+impl<S> FormatEvent<S, JsonFields> for EventFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        writer: &mut dyn fmt::Write,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = Map::new();
+        let mut spans = Vec::new();
+        ctx.visit_spans(|span| {
+            spans.push(Value::from(span.name()));
+            if let Some(span_fields) = span.extensions().get::<FormattedFields<JsonFields>>() {
+                if let Ok(Value::Object(span_fields)) = serde_json::from_str(&span_fields.fields) {
+                    fields.extend(span_fields);
+                }
+            }
+            Ok::<(), fmt::Error>(())
+        })?;
+        event.record(&mut FieldVisitor(&mut fields));
+        let message = fields.remove("message").unwrap_or_else(|| "".into());
+        let severity = Severity::from(event.metadata().level());
+
+        if self.json {
+            // The keys of the time, severity and message must match what the
+            // Google Cloud Logging agent expects:
+            // https://cloud.google.com/logging/docs/agent/configuration#process-payload
+            let mut entry = Map::new();
+            entry.insert("time".to_owned(), chrono::Local::now().to_rfc3339().into());
+            entry.insert("severity".to_owned(), severity.to_string().into());
+            entry.insert("message".to_owned(), message);
+            if !spans.is_empty() {
+                entry.insert("spans".to_owned(), spans.into());
+            }
+            entry.extend(fields);
+            writeln!(writer, "{}", Value::Object(entry))
+        } else {
+            write!(
+                writer,
+                "{} {} {}",
+                chrono::Local::now().format("%b %d %H:%M:%S%.3f"),
+                severity,
+                message.as_str().unwrap_or_default()
+            )?;
+            for (key, value) in fields {
+                match value {
+                    Value::String(value) => write!(writer, ", {}: {}", key, value)?,
+                    value => write!(writer, ", {}: {}", key, value)?,
+                }
+            }
+            writeln!(writer)
+        }
+    }
+}
+
+/// The format in which log messages are written to `stderr`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Format is determined by detecting whether `stderr` is a `tty`. If it
+    /// is, `Text` is used. Otherwise, `Json` is used.
+    Auto,
+    /// Messages are written as JSON objects by [tracing-subscriber][1], with
+    /// keys that Google Cloud Logging and CloudWatch Logs can ingest.
+    ///
+    /// [1]: https://docs.rs/tracing-subscriber
+    Json,
+    /// Messages are written as lines of text for humans.
+    Text,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(LogFormat::Auto),
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            _ => Err(anyhow!("{} is not a valid log format", s)),
         }
     }
 }
 
 /// Options for configuring logging in this application
 pub struct LoggingConfiguration {
-    /// If true, logging output will be forced to JSON format using
-    /// [slog-json][1]. If false, logging format will be determined by detecting
-    /// whether `stderr` is a `tty`. If it is, output is formatted using
-    /// [slog-term][2]. Otherwise, `slog-json` is used.
-    ///
-    /// [1]: https://docs.rs/slog-json
-    /// [2]: https://docs.rs/slog-term
-    pub force_json_output: bool,
+    /// The format in which log messages are written
+    pub format: LogFormat,
     /// A version string which shall be attached to all log messages
     pub version_string: &'static str,
     /// Messages above this log level will be discarded
     pub log_level: &'static str,
+    /// If set, spans are exported to the OpenTelemetry collector listening for
+    /// OTLP over gRPC at this endpoint (e.g. "http://localhost:4317")
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Returns a tracing subscriber that writes events at or above the level to
+/// writers obtained from make_writer, as JSON objects if json_output is true,
+/// and exports spans with tracer, if any. Events and spans from dependencies
+/// are discarded.
+fn subscriber<W>(
+    json_output: bool,
+    log_level: Level,
+    make_writer: W,
+    tracer: Option<trace::Tracer>,
+) -> impl Subscriber
+where
+    W: MakeWriter + Send + Sync + 'static,
+{
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .fmt_fields(JsonFields::new())
+        .event_format(EventFormat { json: json_output })
+        .with_writer(make_writer);
+    tracing_subscriber::registry()
+        .with(Targets::new().with_target("facilitator", tracing_level(log_level)))
+        .with(fmt_layer)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
 }
 
-/// IoErrorDrain is a supertrait that lets us work generically with
-/// `slog::Drain`s.
-trait IoErrorDrain: Drain<Ok = (), Err = std::io::Error> + Send {}
+/// Installs an OpenTelemetry tracer provider that exports spans in batches to
+/// the OTLP collector at endpoint, from the shared Tokio runtime, and returns a
+/// tracer from it.
+fn otlp_tracer(endpoint: &str) -> Result<trace::Tracer> {
+    let _runtime = shared_runtime()?.enter();
+    opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "facilitator",
+            )])),
+        )
+        .with_tonic()
+        .install_batch(opentelemetry::runtime::Tokio)
+        .context("failed to install OTLP span exporter")
+}
+
+/// Makes span continue the trace described by a W3C Trace Context traceparent,
+/// such as one carried in a task, so that it is exported as a child of the
+/// traceparent's span rather than of the span it was created in. Malformed
+/// traceparents are ignored.
+pub fn set_traceparent(span: &Span, traceparent: &str) {
+    let carrier: HashMap<String, String> = std::iter::once(("traceparent", traceparent))
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect();
+    let context = TraceContextPropagator::new().extract(&carrier);
+    if context.span().span_context().is_valid() {
+        span.set_parent(context);
+    }
+}
 
-// Opt the `slog::Drain` implementations we use into IoErrorDrain
-impl IoErrorDrain for Json<Stderr> {}
-impl IoErrorDrain for FullFormat<TermDecorator> {}
+/// Flushes spans that have yet to be exported. Should be called before the
+/// process exits.
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
 
-/// Initialize logging resources. On success, returns a root [`slog::Logger`][1]
-/// from which modules should create child loggers to add more key-value pairs
-/// to the events they log. Returns an error if `LoggingConfiguration` is
-/// invalid.
+/// Initialize logging resources. Installs a global tracing subscriber that
+/// writes events to `stderr` and, if an OTLP endpoint is configured, exports
+/// spans to it, and on success returns a root
+/// [`slog::Logger`][1] whose records are emitted as tracing events, from which
+/// modules should create child loggers to add more key-value pairs to the
+/// events they log. Returns an error if `LoggingConfiguration` is invalid or
+/// if a global tracing subscriber was already installed.
 ///
 /// [1]: https://docs.rs/slog/2.7.0/slog/struct.Logger.html
 pub fn setup_logging(config: &LoggingConfiguration) -> Result<Logger> {
-    // We have to box the Drain so that both branches return the same type
-    let drain: Box<dyn IoErrorDrain> = if atty::isnt(Stream::Stderr) || config.force_json_output {
+    let json_output = match config.format {
         // If stderr is not a tty, output logs as JSON structures on the
         // assumption that we are running in a cloud.
-        let json_drain = Json::new(stderr())
-            .set_newlines(true)
-            // slog_json::JsonBuilder::add_default_keys adds keys for
-            // timestamp, level and the actual message, but we need the JSON
-            // keys to match what the Google Cloud Logging agent expects:
-            // https://cloud.google.com/logging/docs/agent/configuration#process-payload
-            .add_key_value(o!(
-                "time" => FnValue(|_| {
-                    chrono::Local::now().to_rfc3339()
-                }),
-                "severity" => FnValue(|record| {
-                    Severity::from(record.level()).to_string()
-                }),
-                "message" => PushFnValue(|record, serializer| {
-                    serializer.emit(record.msg())
-                }),
-            ))
-            .build();
-        Box::new(json_drain)
-    } else {
-        // stderr is a tty and output was not forced to JSON, so use a
-        // terminal pretty-printer
-        let decorator = TermDecorator::new().stderr().build();
-        Box::new(FullFormat::new(decorator).build())
+        LogFormat::Auto => atty::isnt(Stream::Stderr),
+        LogFormat::Json => true,
+        LogFormat::Text => false,
     };
 
     // Create a filter to discard messages above desired level
     let log_level = slog::Level::from_str(config.log_level)
         .map_err(|_| anyhow!("{} is not a valid log level", config.log_level))?;
-    let level_filter = LevelFilter::new(drain, log_level);
+    let tracer = config
+        .otlp_endpoint
+        .as_deref()
+        .map(otlp_tracer)
+        .transpose()?;
+    tracing::subscriber::set_global_default(subscriber(json_output, log_level, stderr, tracer))
+        .context("failed to install tracing subscriber")?;
 
-    // Use slog_async to make it safe to clone loggers across threads
-    let drain = slog_async::Async::new(level_filter.fuse()).build().fuse();
+    // Records are emitted as tracing events synchronously, so that they are
+    // attributed to the span current on the thread they were logged on.
+    let drain = LevelFilter::new(TracingDrain, log_level).fuse();
     let root_logger = Logger::root(
         drain,
         o!(
@@ -186,3 +490,151 @@ pub fn setup_test_logging() -> Logger {
     let drain = FullFormat::new(decorator).build().fuse();
     Logger::root(drain, o!())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider;
+    use slog::{debug, info, trace};
+    use std::{
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+    use tracing::{field, info_span};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Logs to a logger whose records are emitted as tracing events written
+    /// to a buffer at debug level, returning the lines written.
+    fn log_lines<F: FnOnce(&Logger)>(json_output: bool, log: F) -> Vec<String> {
+        let buffer = SharedBuffer::default();
+        let writer = buffer.clone();
+        let logger = Logger::root(TracingDrain.fuse(), o!("version" => "1.0"));
+        tracing::subscriber::with_default(
+            subscriber(json_output, Level::Debug, move || writer.clone(), None),
+            || log(&logger),
+        );
+        let output = buffer.0.lock().unwrap().clone();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    fn log_in_spans(logger: &Logger) {
+        info!(logger, "outside spans");
+        let task_span = info_span!("intake_task", trace_id = "trace", batch_id = "outer");
+        let _task_span = task_span.enter();
+        let batch_span = info_span!(
+            "intake_batch",
+            batch_id = "inner",
+            ingestion_server = field::Empty
+        );
+        batch_span.record("ingestion_server", &"ingestor");
+        let _batch_span = batch_span.enter();
+        debug!(
+            logger, "processed {} packets", 3;
+            "packet_count" => 3, "ratio" => 0.5, "version" => "2.0",
+        );
+        trace!(logger, "discarded");
+    }
+
+    #[test]
+    fn json_events_carry_span_fields() {
+        let lines = log_lines(true, log_in_spans);
+        assert_eq!(lines.len(), 2);
+
+        let outside: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(outside["severity"], "INFO");
+        assert_eq!(outside["message"], "outside spans");
+        assert_eq!(outside["version"], "1.0");
+        assert!(outside.get("spans").is_none());
+        assert!(outside.get("trace_id").is_none());
+        assert!(outside["time"].is_string());
+
+        let inside: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(inside["severity"], "DEBUG");
+        assert_eq!(inside["message"], "processed 3 packets");
+        assert_eq!(
+            inside["spans"],
+            serde_json::json!(["intake_task", "intake_batch"])
+        );
+        assert_eq!(inside["trace_id"], "trace");
+        // The innermost span's field wins, and the record's over the logger's
+        assert_eq!(inside["batch_id"], "inner");
+        assert_eq!(inside["ingestion_server"], "ingestor");
+        assert_eq!(inside["version"], "2.0");
+        assert_eq!(inside["packet_count"], 3);
+        assert_eq!(inside["ratio"], 0.5);
+        assert!(inside.get(SLOG_FIELDS).is_none());
+    }
+
+    #[test]
+    fn text_events_carry_span_fields() {
+        let lines = log_lines(false, log_in_spans);
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(" INFO outside spans, version: 1.0"));
+        assert!(lines[1].contains(" DEBUG processed 3 packets, "));
+        for field in &[
+            "trace_id: trace",
+            "batch_id: inner",
+            "ingestion_server: ingestor",
+            "packet_count: 3",
+        ] {
+            assert!(
+                lines[1].contains(field),
+                "{} missing from {}",
+                field,
+                lines[1]
+            );
+        }
+    }
+
+    #[test]
+    fn spans_continue_traceparents() {
+        // Tracers only hold weak references to their providers
+        let provider = trace::TracerProvider::builder().build();
+        let tracer = provider.get_tracer("facilitator", None);
+        let subscriber = subscriber(false, Level::Info, io::sink, Some(tracer));
+        tracing::subscriber::with_default(subscriber, || {
+            let task_span = info_span!("intake_task");
+            set_traceparent(
+                &task_span,
+                "00-2e0831b886ed1ab0efc84bb2ad91a46f-d93bdb2a1f64e301-01",
+            );
+            let _task_span = task_span.enter();
+            let batch_span = info_span!("intake_batch");
+            for span in &[task_span.clone(), batch_span] {
+                assert_eq!(
+                    span.context().span().span_context().trace_id().to_hex(),
+                    "2e0831b886ed1ab0efc84bb2ad91a46f"
+                );
+            }
+
+            // Malformed traceparents don't detach spans from their parents
+            let stage_span = info_span!("read_ingestion_header");
+            set_traceparent(&stage_span, "not a traceparent");
+            assert_eq!(
+                stage_span
+                    .context()
+                    .span()
+                    .span_context()
+                    .trace_id()
+                    .to_hex(),
+                "2e0831b886ed1ab0efc84bb2ad91a46f"
+            );
+        });
+    }
+}
@@ -1,21 +1,47 @@
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
 use elliptic_curve::sec1::{EncodedPoint, ToEncodedPoint};
-use p256::{pkcs8::FromPublicKey, NistP256};
+use once_cell::sync::OnceCell;
+use p256::{
+    pkcs8::{FromPrivateKey, FromPublicKey},
+    NistP256,
+};
 use pkix::{
-    pem::{pem_to_der, PEM_CERTIFICATE_REQUEST},
-    pkcs10::DerCertificationRequest,
-    FromDer,
+    bit_vec::BitVec,
+    oid,
+    pem::{der_to_pem, pem_to_der, PEM_CERTIFICATE_REQUEST, PEM_PUBLIC_KEY},
+    pkcs10::{CertificationRequest, CertificationRequestInfo, DerCertificationRequest},
+    types::{DerSequence, Name, TaggedDerValue},
+    yasna::tags::TAG_UTF8STRING,
+    FromDer, ToDer,
 };
 use prio::encrypt::{decrypt_share, encrypt_share, PrivateKey, PublicKey};
 use ring::{
+    digest,
     rand::SystemRandom,
-    signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1},
+    signature::{
+        EcdsaKeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
+        ECDSA_P256_SHA256_ASN1_SIGNING,
+    },
+};
+use serde::{Deserialize, Serialize};
+use slog::{debug, o, warn, Logger};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
-use serde::Deserialize;
-use slog::Logger;
-use std::{collections::HashMap, str::FromStr};
 
-use crate::{config::StoragePath, http, BatchSigningKey};
+use crate::{
+    config::StoragePath,
+    http::{Method, RequestClass, RequestParameters, RetryingAgent},
+    signing::BatchSigner,
+    DigestAlgorithm,
+};
 
 // See discussion in SpecificManifest::batch_signing_public_key
 const ECDSA_P256_SPKI_PREFIX: &[u8] = &[
@@ -23,6 +49,12 @@ const ECDSA_P256_SPKI_PREFIX: &[u8] = &[
     0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
 ];
 
+// DER encoding of the AlgorithmIdentifier for ecdsa-with-SHA256 (RFC 5758,
+// section 3.2), used to sign the certificate signing requests we generate.
+const ECDSA_WITH_SHA256_ALGORITHM_IDENTIFIER: &[u8] = &[
+    0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02,
+];
+
 /// A set of batch signing public keys as might be found in a server's global
 /// or specific manifest. The keys are key identifiers and the values are public
 /// keys which may be used to verify batch signatures.
@@ -30,9 +62,9 @@ pub type BatchSigningPublicKeys = HashMap<String, UnparsedPublicKey<Vec<u8>>>;
 
 /// Represents the description of a batch signing public key in a specific
 /// manifest.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
-struct BatchSigningPublicKey {
+pub struct BatchSigningPublicKey {
     /// The PEM-armored base64 encoding of the ASN.1 encoding of the PKIX
     /// SubjectPublicKeyInfo structure of an ECDSA P256 key.
     public_key: String,
@@ -40,7 +72,7 @@ struct BatchSigningPublicKey {
     expiration: String,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub struct PacketEncryptionCertificateSigningRequest {
     /// The PEM-armored base64 encoding of the ASN.1 encoding of a PKCS#10
@@ -91,6 +123,10 @@ pub struct DataShareProcessorGlobalManifest {
     /// Identity used by the data share processor instances to access peer
     /// cloud resources
     server_identity: DataShareProcessorServerIdentity,
+    /// Identifiers of the packet encryption key versions this data share
+    /// processor is ready to process, used to gate the rollout of new packet
+    /// encryption keys. If absent, no key versions are gated.
+    packet_encryption_key_versions: Option<Vec<String>>,
 }
 
 /// Represents the server-identity map inside a data share processor global
@@ -126,6 +162,24 @@ impl DataShareProcessorGlobalManifest {
         }
         Ok(manifest)
     }
+
+    /// Returns the numeric ID of the AWS account this data share processor
+    /// uses to access peer cloud resources.
+    pub fn aws_account_id(&self) -> u64 {
+        self.server_identity.aws_account_id
+    }
+
+    /// Returns the email of the GCP service account this data share processor
+    /// uses to access peer cloud resources.
+    pub fn gcp_service_account_email(&self) -> &str {
+        &self.server_identity.gcp_service_account_email
+    }
+
+    /// Returns the packet encryption key versions advertised in this manifest,
+    /// or None if the manifest does not gate key versions.
+    pub fn packet_encryption_key_versions(&self) -> Option<&[String]> {
+        self.packet_encryption_key_versions.as_deref()
+    }
 }
 
 /// Represents a specific manifest, used to exchange configuration parameters
@@ -155,15 +209,23 @@ pub struct SpecificManifest {
     /// to encrypt ingestion share packets intended for this data share
     /// processor.
     packet_encryption_keys: PacketEncryptionCertificateSigningRequests,
+    /// The algorithm this data share processor uses to compute packet file
+    /// digests in the validation batches it writes. SHA-256 if absent.
+    packet_file_digest_algorithm: Option<DigestAlgorithm>,
 }
 
 impl SpecificManifest {
     /// Load the specific manifest for the specified peer relative to the
     /// provided base path. Returns an error if the manifest could not be
-    /// downloaded or parsed.
+    /// downloaded, parsed or validated.
     pub fn from_https(base_path: &str, peer_name: &str, logger: &Logger) -> Result<Self> {
         let manifest_url = format!("{}/{}-manifest.json", base_path, peer_name);
-        SpecificManifest::from_slice(fetch_manifest(&manifest_url, logger)?.as_bytes())
+        let manifest =
+            SpecificManifest::from_slice(fetch_manifest(&manifest_url, logger)?.as_bytes())?;
+        manifest
+            .validate()
+            .context(format!("invalid manifest {}", manifest_url))?;
+        Ok(manifest)
     }
 
     /// Loads the manifest from the provided String. Returns an error if
@@ -192,6 +254,45 @@ impl SpecificManifest {
         Ok(keys)
     }
 
+    /// Returns a map of key identifier to the fingerprint of each of the batch
+    /// signing public keys advertised in this manifest. See
+    /// batch_signing_key_fingerprint.
+    pub fn batch_signing_key_fingerprints(&self) -> Result<HashMap<String, String>> {
+        self.batch_signing_public_keys
+            .iter()
+            .map(|(identifier, public_key)| {
+                Ok((
+                    identifier.clone(),
+                    batch_signing_key_fingerprint(&public_key.public_key)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Checks that the fingerprint of every batch signing public key advertised
+    /// in this manifest is among the provided trusted fingerprints, so that a
+    /// tampered or compromised manifest cannot introduce keys that operators
+    /// have not agreed on out of band. Returns an error naming the first key
+    /// that is not trusted.
+    pub fn verify_batch_signing_key_fingerprints(
+        &self,
+        trusted_fingerprints: &[&str],
+    ) -> Result<()> {
+        for (identifier, fingerprint) in self.batch_signing_key_fingerprints()? {
+            if !trusted_fingerprints
+                .iter()
+                .any(|trusted| trusted.eq_ignore_ascii_case(&fingerprint))
+            {
+                return Err(anyhow!(
+                    "batch signing key {} has untrusted fingerprint {}",
+                    identifier,
+                    fingerprint
+                ));
+            }
+        }
+        Ok(())
+    }
+
     pub fn packet_decryption_keys(&self) -> Result<PacketEncryptionCertificateSigningRequests> {
         Ok(self.packet_encryption_keys.clone())
     }
@@ -211,6 +312,12 @@ impl SpecificManifest {
         self.validation_bucket()
             .context("bad manifest: valiation bucket")?;
         StoragePath::from_str(&self.ingestion_bucket).context("bad manifest: ingestion bucket")?;
+        for (identifier, csr) in &self.packet_encryption_keys {
+            csr.base64_public_key().context(format!(
+                "bad manifest: packet encryption key {}",
+                identifier
+            ))?;
+        }
         Ok(())
     }
 
@@ -222,32 +329,32 @@ impl SpecificManifest {
         self.ingestion_bucket.clone()
     }
 
+    /// Returns the digest algorithm used over packet files in validation
+    /// batches written by this data share processor.
+    pub fn packet_file_digest_algorithm(&self) -> DigestAlgorithm {
+        self.packet_file_digest_algorithm.unwrap_or_default()
+    }
+
     /// Checks if the batch signing public key in the manifest matches the
-    /// provided batch signing private key by signing a random message and
-    /// verifying the signature. Returns an error if the keys do not match.
-    pub fn verify_batch_signing_key(
-        &self,
-        batch_signing_private_key: &BatchSigningKey,
-    ) -> Result<()> {
+    /// provided batch signer by signing a random message and verifying the
+    /// signature. Returns an error if the keys do not match.
+    pub fn verify_batch_signing_key(&self, batch_signer: &dyn BatchSigner) -> Result<()> {
         let test_message: Vec<u8> = (0..100).map(|_| rand::random::<u8>()).collect();
-        let signature = batch_signing_private_key
-            .key
-            .sign(&SystemRandom::new(), &&test_message)
-            .context(format!(
-                "failed to sign test message with private key {}",
-                batch_signing_private_key.identifier
-            ))?;
+        let signature = batch_signer.sign(&test_message).context(format!(
+            "failed to sign test message with private key {}",
+            batch_signer.key_identifier()
+        ))?;
 
         self.batch_signing_public_keys()?
-            .get(&batch_signing_private_key.identifier)
+            .get(batch_signer.key_identifier())
             .context(format!(
                 "key identifier {} not present in manifest batch signing public keys",
-                batch_signing_private_key.identifier
+                batch_signer.key_identifier()
             ))?
-            .verify(&test_message, signature.as_ref())
+            .verify(&test_message, &signature)
             .context(format!(
                 "failed to verify signature over test message with key {}",
-                batch_signing_private_key.identifier
+                batch_signer.key_identifier()
             ))
     }
 
@@ -259,8 +366,35 @@ impl SpecificManifest {
         &self,
         packet_encryption_private_keys: &[PrivateKey],
     ) -> Result<()> {
+        let identifiers = self.packet_decryption_key_identifiers(packet_encryption_private_keys)?;
+        for identifier in self.packet_encryption_keys.keys() {
+            // If we made it here, then no private key was able to decrypt the
+            // test message and we have a key mismatch
+            if !identifiers.contains_key(identifier) {
+                return Err(anyhow!(
+                    "unable to decrypt test message encrypted with {} with any of {} available packet decryption keys",
+                    identifier,
+                    packet_encryption_private_keys.len(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Matches the packet encryption public keys in the manifest to the
+    /// provided packet encryption private keys by encrypting a random message
+    /// to each public key and decrypting it with each private key. Returns a
+    /// map of the identifier of each public key to the index in
+    /// packet_encryption_private_keys of the corresponding private key. Public
+    /// keys with no corresponding private key are omitted.
+    pub fn packet_decryption_key_identifiers(
+        &self,
+        packet_encryption_private_keys: &[PrivateKey],
+    ) -> Result<HashMap<String, usize>> {
         let test_message: Vec<u8> = (0..100).map(|_| rand::random::<u8>()).collect();
-        'outer: for (identifier, csr) in &self.packet_encryption_keys {
+        let mut identifiers = HashMap::new();
+        for (identifier, csr) in &self.packet_encryption_keys {
             let public_key = PublicKey::from_base64(&csr.base64_public_key()?).context(format!(
                 "failed to decode packet encryption public key {} from specific manifest",
                 identifier,
@@ -271,7 +405,7 @@ impl SpecificManifest {
                 identifier,
             ))?;
 
-            for private_key in packet_encryption_private_keys {
+            for (index, private_key) in packet_encryption_private_keys.iter().enumerate() {
                 match decrypt_share(&encrypted, &private_key) {
                     Ok(decrypted) => {
                         // AEAD decryption succeeding but yielding incorrect
@@ -285,24 +419,17 @@ impl SpecificManifest {
 
                         // AEAD decryption succeeded and the plaintext is good,
                         // so move on to the next packet encryption public key
-                        continue 'outer;
+                        identifiers.insert(identifier.clone(), index);
+                        break;
                     }
                     // AEAD decryption failing is expected if we are using the
                     // wrong private key, so move on to the next one
                     Err(_) => continue,
                 }
             }
-
-            // If we made it here, then no private key was able to decrypt the
-            // test message and we have a key mismatch
-            return Err(anyhow!(
-                "unable to decrypt test message encrypted with {} with any of {} available packet decryption keys",
-                identifier,
-                packet_encryption_private_keys.len(),
-            ));
         }
 
-        Ok(())
+        Ok(identifiers)
     }
 }
 
@@ -347,7 +474,13 @@ impl IngestionServerManifest {
     /// manifest for the specified locality. Returns an error if no manifest
     /// could be found at either location, or if either was unparseable.
     pub fn from_https(base_path: &str, locality: Option<&str>, logger: &Logger) -> Result<Self> {
-        IngestionServerManifest::from_http(base_path, locality, logger, fetch_manifest)
+        let manifest =
+            IngestionServerManifest::from_http(base_path, locality, logger, fetch_manifest)?;
+        manifest.validate().context(format!(
+            "invalid ingestion server manifest at {}",
+            base_path
+        ))?;
+        Ok(manifest)
     }
 
     fn from_http(
@@ -421,7 +554,13 @@ pub struct PortalServerGlobalManifest {
 impl PortalServerGlobalManifest {
     pub fn from_https(base_path: &str, logger: &Logger) -> Result<Self> {
         let manifest_url = format!("{}/global-manifest.json", base_path);
-        PortalServerGlobalManifest::from_slice(fetch_manifest(&manifest_url, logger)?.as_bytes())
+        let manifest = PortalServerGlobalManifest::from_slice(
+            fetch_manifest(&manifest_url, logger)?.as_bytes(),
+        )?;
+        manifest
+            .validate()
+            .context(format!("invalid manifest {}", manifest_url))?;
+        Ok(manifest)
     }
 
     /// Loads the manifest from the provided String. Returns an error if
@@ -461,23 +600,290 @@ impl PortalServerGlobalManifest {
 /// manifest body as a String on success.
 type ManifestFetcher = fn(&str, &Logger) -> Result<String>;
 
+/// How long a fetched manifest is used before it is revalidated, unless
+/// configured otherwise with configure_manifest_cache. Workers consult
+/// manifests for every task they handle, but manifests rarely change.
+pub const DEFAULT_MANIFEST_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Manifests fetched by this process, shared by every caller of
+/// fetch_manifest.
+static MANIFEST_CACHE: OnceCell<ManifestCache> = OnceCell::new();
+
+/// Configures the cache used when fetching manifests over HTTPS. Cached
+/// manifests are revalidated once they are older than `ttl`. If `directory` is
+/// provided, manifests are also cached in files in that directory, so that
+/// they survive restarts and can be used if the manifest host is unavailable.
+/// Must be called before any manifest is fetched, and at most once.
+pub fn configure_manifest_cache(ttl: Duration, directory: Option<PathBuf>) -> Result<()> {
+    if let Some(directory) = &directory {
+        fs::create_dir_all(directory).context(format!(
+            "failed to create manifest cache directory {}",
+            directory.display()
+        ))?;
+    }
+    MANIFEST_CACHE
+        .set(ManifestCache::new(ttl, directory))
+        .map_err(|_| anyhow!("manifest cache is already configured"))
+}
+
+/// A manifest body, along with the validators the server provided with it,
+/// which are used to revalidate it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct CachedManifest {
+    body: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// The outcome of a conditional request for a manifest.
+#[derive(Debug, PartialEq)]
+enum ConditionalFetch {
+    /// The manifest has changed since it was cached, or there was nothing to
+    /// revalidate.
+    Modified(CachedManifest),
+    /// The cached manifest is still current.
+    NotModified,
+}
+
+/// A function that fetches the manifest at the provided URL, making the request
+/// conditional on the provided previously fetched manifest, if any.
+type ConditionalManifestFetcher<'a> =
+    &'a dyn Fn(&str, Option<&CachedManifest>, &Logger) -> Result<ConditionalFetch>;
+
+/// A cache of manifests, keyed by URL. Entries are used without contacting the
+/// server until they are older than the TTL, after which they are revalidated
+/// with If-None-Match or If-Modified-Since requests. If revalidation fails, the
+/// stale entry is used rather than failing, so that an outage of the manifest
+/// host does not stop the facilitator from working. Failed fetches of
+/// manifests that are not cached are not cached.
+#[derive(Debug)]
+struct ManifestCache {
+    ttl: Duration,
+    directory: Option<PathBuf>,
+    entries: Mutex<HashMap<String, (Instant, CachedManifest)>>,
+}
+
+impl ManifestCache {
+    fn new(ttl: Duration, directory: Option<PathBuf>) -> Self {
+        ManifestCache {
+            ttl,
+            directory,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the body of the manifest at the provided URL, from the cache if
+    /// the cached entry has not expired, or else fetched or revalidated using
+    /// the provided fetcher.
+    fn fetch(
+        &self,
+        manifest_url: &str,
+        logger: &Logger,
+        fetcher: ConditionalManifestFetcher,
+    ) -> Result<String> {
+        let logger = logger.new(o!("manifest_url" => manifest_url.to_owned()));
+        let cached = self.entries.lock().unwrap().get(manifest_url).cloned();
+        if let Some((fetched_at, manifest)) = &cached {
+            if fetched_at.elapsed() < self.ttl {
+                debug!(logger, "using cached manifest");
+                return Ok(manifest.body.clone());
+            }
+        }
+        let cached = match cached {
+            Some((_, manifest)) => Some(manifest),
+            None => self.read_from_disk(manifest_url, &logger),
+        };
+
+        // The lock is not held while fetching, so concurrent callers may both
+        // fetch the same manifest, which is harmless.
+        let manifest = match (fetcher(manifest_url, cached.as_ref(), &logger), cached) {
+            (Ok(ConditionalFetch::Modified(manifest)), _) => {
+                debug!(logger, "fetched manifest");
+                self.write_to_disk(manifest_url, &manifest, &logger);
+                manifest
+            }
+            (Ok(ConditionalFetch::NotModified), Some(cached)) => {
+                debug!(logger, "revalidated cached manifest");
+                cached
+            }
+            (Ok(ConditionalFetch::NotModified), None) => {
+                return Err(anyhow!(
+                    "server reported {} unmodified, but it is not cached",
+                    manifest_url
+                ));
+            }
+            (Err(error), Some(cached)) => {
+                warn!(
+                    logger, "failed to revalidate manifest, using stale cached manifest";
+                    "error" => format!("{:?}", error),
+                );
+                // Keep the entry's age so that the next caller tries again
+                return Ok(cached.body);
+            }
+            (Err(error), None) => return Err(error),
+        };
+
+        let body = manifest.body.clone();
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(manifest_url.to_owned(), (Instant::now(), manifest));
+        Ok(body)
+    }
+
+    /// Path of the file in which the manifest at the provided URL is cached,
+    /// if there is a cache directory.
+    fn disk_path(&self, manifest_url: &str) -> Option<PathBuf> {
+        self.directory.as_ref().map(|directory| {
+            directory.join(format!(
+                "{}.json",
+                hex::encode(digest::digest(&digest::SHA256, manifest_url.as_bytes()))
+            ))
+        })
+    }
+
+    /// Reads the manifest at the provided URL from the cache directory. Any
+    /// failure is logged and treated as if the manifest was not cached.
+    fn read_from_disk(&self, manifest_url: &str, logger: &Logger) -> Option<CachedManifest> {
+        let path = self.disk_path(manifest_url)?;
+        match fs::read(&path) {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(manifest) => Some(manifest),
+                Err(error) => {
+                    warn!(
+                        logger, "ignoring unparseable cached manifest";
+                        "path" => path.display().to_string(),
+                        "error" => error.to_string(),
+                    );
+                    None
+                }
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => {
+                warn!(
+                    logger, "failed to read cached manifest";
+                    "path" => path.display().to_string(),
+                    "error" => error.to_string(),
+                );
+                None
+            }
+        }
+    }
+
+    /// Writes the manifest at the provided URL to the cache directory. Since
+    /// the cache directory is only an optimization, failures are logged and
+    /// otherwise ignored.
+    fn write_to_disk(&self, manifest_url: &str, manifest: &CachedManifest, logger: &Logger) {
+        let path = match self.disk_path(manifest_url) {
+            Some(path) => path,
+            None => return,
+        };
+        // Write to a temporary file and rename it so that concurrent readers
+        // never see a partially written manifest.
+        let result = serde_json::to_vec(manifest)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| {
+                let mut file = tempfile::NamedTempFile::new_in(path.parent().unwrap())?;
+                file.write_all(&contents)?;
+                file.persist(&path)?;
+                Ok(())
+            });
+        if let Err(error) = result {
+            warn!(
+                logger, "failed to write cached manifest";
+                "path" => path.display().to_string(),
+                "error" => format!("{:?}", error),
+            );
+        }
+    }
+}
+
 /// Obtains a manifest file from the provided URL, returning an error if the URL
-/// is not https or if a problem occurs during the transfer.
+/// is not https or if a problem occurs during the transfer. Manifests are
+/// cached as configured by configure_manifest_cache.
 fn fetch_manifest(manifest_url: &str, logger: &Logger) -> Result<String> {
     if !manifest_url.starts_with("https://") {
         return Err(anyhow!("Manifest must be fetched over HTTPS"));
     }
-    http::simple_get_request(
-        url::Url::parse(manifest_url)
-            .context(format!("failed to parse manifest url: {}", manifest_url))?,
-        logger,
-    )
+    MANIFEST_CACHE
+        .get_or_init(|| ManifestCache::new(DEFAULT_MANIFEST_CACHE_TTL, None))
+        .fetch(manifest_url, logger, &|manifest_url, cached, logger| {
+            fetch_manifest_conditionally(
+                &RetryingAgent::new(RequestClass::Manifest, vec![]),
+                manifest_url,
+                cached,
+                logger,
+            )
+        })
+}
+
+/// Fetches the manifest at the provided URL. If a previously fetched manifest
+/// is provided, the request is conditional on the validators the server sent
+/// with it, so the manifest body is only sent again if it has changed.
+fn fetch_manifest_conditionally(
+    agent: &RetryingAgent,
+    manifest_url: &str,
+    cached: Option<&CachedManifest>,
+    logger: &Logger,
+) -> Result<ConditionalFetch> {
+    let url = url::Url::parse(manifest_url)
+        .context(format!("failed to parse manifest url: {}", manifest_url))?;
+    let mut request = agent.prepare_request(RequestParameters {
+        url,
+        method: Method::Get,
+        ..Default::default()
+    })?;
+    if let Some(cached) = cached {
+        if let Some(etag) = &cached.etag {
+            request = request.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.set("If-Modified-Since", last_modified);
+        }
+    }
+
+    let response = agent
+        .call(logger, &request)
+        .context(format!("failed to fetch manifest {}", manifest_url))?;
+    if response.status() == 304 {
+        return Ok(ConditionalFetch::NotModified);
+    }
+    let etag = response.header("ETag").map(str::to_owned);
+    let last_modified = response.header("Last-Modified").map(str::to_owned);
+    let body = agent
+        .read_string(response)
+        .context("failed to read manifest body")?;
+    Ok(ConditionalFetch::Modified(CachedManifest {
+        body,
+        etag,
+        last_modified,
+    }))
+}
+
+/// Returns the fingerprint of the provided PEM encoded batch signing public
+/// key, which is the lowercase hex encoding of the SHA-256 digest of the DER
+/// encoded SubjectPublicKeyInfo structure. This is the same fingerprint as
+/// `openssl pkey -pubin -outform DER | sha256sum` yields.
+pub fn batch_signing_key_fingerprint(pem_key: &str) -> Result<String> {
+    let spki = p256_spki_from_pem(pem_key)?;
+    Ok(hex::encode(digest::digest(&digest::SHA256, &spki)))
 }
 
 /// Attempts to parse the provided string as a PEM encoded PKIX
 /// SubjectPublicKeyInfo structure containing an ECDSA P256 public key, and
 /// returns an UnparsedPublicKey containing that key on success.
 fn public_key_from_pem(pem_key: &str) -> Result<UnparsedPublicKey<Vec<u8>>> {
+    let spki = p256_spki_from_pem(pem_key)?;
+    Ok(UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_ASN1,
+        Vec::from(&spki[ECDSA_P256_SPKI_PREFIX.len()..]),
+    ))
+}
+
+/// Attempts to parse the provided string as a PEM encoded PKIX
+/// SubjectPublicKeyInfo structure containing an ECDSA P256 public key, and
+/// returns the DER encoded structure on success.
+fn p256_spki_from_pem(pem_key: &str) -> Result<Vec<u8>> {
     // No Rust crate that we have found gives us an easy way to parse PKIX
     // SubjectPublicKeyInfo structures to get at the public key which can
     // then be used in ring::signature. Since we know the keys we deal with
@@ -504,18 +910,106 @@ fn public_key_from_pem(pem_key: &str) -> Result<UnparsedPublicKey<Vec<u8>>> {
         ));
     }
 
-    let (prefix, key) = pem.contents.split_at(ECDSA_P256_SPKI_PREFIX.len());
-
-    if prefix != ECDSA_P256_SPKI_PREFIX {
+    if !pem.contents.starts_with(ECDSA_P256_SPKI_PREFIX) {
         return Err(anyhow!(
             "PEM contents are not ASN.1 encoded ECDSA P256 SubjectPublicKeyInfo"
         ));
     }
 
-    Ok(UnparsedPublicKey::new(
-        &ECDSA_P256_SHA256_ASN1,
-        Vec::from(key),
-    ))
+    Ok(pem.contents)
+}
+
+/// A batch signing key pair generated by generate_batch_signing_key.
+#[derive(Debug)]
+pub struct GeneratedBatchSigningKey {
+    /// The base64 encoded PKCS#8 document containing the private key, in the
+    /// form expected by --batch-signing-private-key.
+    pub private_key: String,
+    /// The public key, in the form it is advertised in a specific manifest.
+    pub public_key: BatchSigningPublicKey,
+}
+
+/// Generates a new ECDSA P256 batch signing key pair whose public key expires
+/// at the provided time.
+pub fn generate_batch_signing_key(expiration: DateTime<Utc>) -> Result<GeneratedBatchSigningKey> {
+    let (pkcs8, key_pair) = generate_p256_key_pair()?;
+
+    let mut spki = ECDSA_P256_SPKI_PREFIX.to_vec();
+    spki.extend_from_slice(key_pair.public_key().as_ref());
+    let public_key = der_to_pem(&spki, PEM_PUBLIC_KEY);
+
+    Ok(GeneratedBatchSigningKey {
+        private_key: base64::encode(&pkcs8),
+        public_key: BatchSigningPublicKey {
+            public_key,
+            expiration: expiration.to_rfc3339_opts(SecondsFormat::Secs, true),
+        },
+    })
+}
+
+/// A packet encryption key pair generated by generate_packet_encryption_key.
+#[derive(Debug)]
+pub struct GeneratedPacketEncryptionKey {
+    /// The base64 encoding of the X9.62 uncompressed public key concatenated
+    /// with the secret scalar, in the form expected by libprio-rs and
+    /// --packet-decryption-keys.
+    pub private_key: String,
+    /// The certificate signing request containing the public key, in the form
+    /// it is advertised in a specific manifest.
+    pub certificate_signing_request: PacketEncryptionCertificateSigningRequest,
+}
+
+/// Generates a new ECDSA P256 packet encryption key pair, along with a PKCS#10
+/// certificate signing request for the public key whose subject has the
+/// provided common name, like deploy-tool does.
+pub fn generate_packet_encryption_key(common_name: &str) -> Result<GeneratedPacketEncryptionKey> {
+    let (pkcs8, key_pair) = generate_p256_key_pair()?;
+
+    // ring will not give us the secret scalar, so we get it from the PKCS#8
+    // document instead.
+    let secret_key = p256::SecretKey::from_pkcs8_der(&pkcs8)
+        .map_err(|e| anyhow!("failed to parse generated PKCS#8 document: {:?}", e))?;
+    let mut private_key = key_pair.public_key().as_ref().to_vec();
+    private_key.extend_from_slice(&secret_key.to_bytes());
+
+    let mut spki = ECDSA_P256_SPKI_PREFIX.to_vec();
+    spki.extend_from_slice(key_pair.public_key().as_ref());
+    let request_info = CertificationRequestInfo {
+        subject: Name {
+            value: vec![(
+                oid::commonName.clone(),
+                TaggedDerValue::from_tag_and_bytes(TAG_UTF8STRING, common_name.as_bytes().to_vec()),
+            )],
+        },
+        spki: DerSequence::from(spki),
+        attributes: vec![],
+    };
+    let signature = key_pair
+        .sign(&SystemRandom::new(), &request_info.to_der())
+        .map_err(|_| anyhow!("failed to sign certificate signing request"))?;
+    let request = CertificationRequest {
+        reqinfo: request_info,
+        sigalg: DerSequence::from(ECDSA_WITH_SHA256_ALGORITHM_IDENTIFIER),
+        sig: BitVec::from_bytes(signature.as_ref()),
+    };
+
+    Ok(GeneratedPacketEncryptionKey {
+        private_key: base64::encode(&private_key),
+        certificate_signing_request: PacketEncryptionCertificateSigningRequest::new(der_to_pem(
+            &request.to_der(),
+            PEM_CERTIFICATE_REQUEST,
+        )),
+    })
+}
+
+/// Generates a new ECDSA P256 key pair, returning the PKCS#8 document
+/// containing it and the parsed key pair.
+fn generate_p256_key_pair() -> Result<(Vec<u8>, EcdsaKeyPair)> {
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+        .map_err(|_| anyhow!("failed to generate ECDSA P256 key pair"))?;
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+        .map_err(|e| anyhow!("failed to parse generated key pair: {}", e))?;
+    Ok((pkcs8.as_ref().to_vec(), key_pair))
 }
 
 #[cfg(test)]
@@ -530,15 +1024,23 @@ mod tests {
             DEFAULT_PACKET_ENCRYPTION_CERTIFICATE_SIGNING_REQUEST_PRIVATE_KEY,
             DEFAULT_PACKET_ENCRYPTION_CSR,
         },
+        BatchSigningKey,
     };
     use assert_matches::assert_matches;
-    use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
     use rusoto_core::Region;
-    use std::array::IntoIter;
-    use url::Url;
+    use std::{array::IntoIter, cell::Cell};
 
     fn url_fetcher(url: &str, logger: &Logger) -> Result<String> {
-        http::simple_get_request(Url::parse(url)?, logger)
+        let agent = RetryingAgent::default();
+        let request = agent.prepare_request(RequestParameters {
+            url: url::Url::parse(url)?,
+            ..Default::default()
+        })?;
+        agent.read_string(agent.call(logger, &request)?)
     }
 
     #[test]
@@ -562,6 +1064,28 @@ mod tests {
                     .to_owned(),
             }
         );
+        assert_eq!(manifest.aws_account_id(), 12345678901234567);
+        assert_eq!(
+            manifest.gcp_service_account_email(),
+            "service-account@project-name.iam.gserviceaccount.com"
+        );
+        assert_eq!(manifest.packet_encryption_key_versions(), None);
+
+        let json = br#"
+{
+    "format": 0,
+    "server-identity": {
+        "aws-account-id": 12345678901234567,
+        "gcp-service-account-email": "service-account@project-name.iam.gserviceaccount.com"
+    },
+    "packet-encryption-key-versions": ["key-2021-01", "key-2021-02"]
+}
+            "#;
+        let manifest = DataShareProcessorGlobalManifest::from_slice(json).unwrap();
+        assert_eq!(
+            manifest.packet_encryption_key_versions(),
+            Some(&["key-2021-01".to_owned(), "key-2021-02".to_owned()][..])
+        );
     }
 
     #[test]
@@ -650,6 +1174,17 @@ mod tests {
         "gcp-service-account-email": "service-account@project-name.iam.gserviceaccount.com",
         "unexpected": "some value"
     }
+}
+        "#,
+            // non-list packet encryption key versions
+            r#"
+{
+    "format": 0,
+    "server-identity": {
+        "aws-account-id": 12345678901234567,
+        "gcp-service-account-email": "service-account@project-name.iam.gserviceaccount.com"
+    },
+    "packet-encryption-key-versions": "key-2021-01"
 }
         "#,
         ];
@@ -713,8 +1248,13 @@ mod tests {
             ingestion_bucket: "s3://us-west-1/ingestion".to_string(),
             ingestion_identity: Some("arn:aws:iam:something:fake".to_owned()),
             peer_validation_bucket: "gs://validation/path/fragment".to_string(),
+            packet_file_digest_algorithm: None,
         };
         assert_eq!(manifest, expected_manifest);
+        assert_eq!(
+            manifest.packet_file_digest_algorithm(),
+            DigestAlgorithm::Sha256
+        );
         let batch_signing_keys = manifest.batch_signing_public_keys().unwrap();
         let content = b"some content";
         let signature = default_ingestor_private_key()
@@ -745,6 +1285,260 @@ mod tests {
         packet_decryption_key.base64_public_key().unwrap();
 
         manifest.validate().unwrap();
+
+        let fingerprint = "cce25aec825ca80e994b0c8ccca58f185e21a35c06cd08b56bc60cb16c94200d";
+        assert_eq!(
+            manifest.batch_signing_key_fingerprints().unwrap(),
+            vec![("fake-key-2".to_owned(), fingerprint.to_owned())]
+                .into_iter()
+                .collect()
+        );
+        manifest
+            .verify_batch_signing_key_fingerprints(&["unrelated", fingerprint])
+            .unwrap();
+        manifest
+            .verify_batch_signing_key_fingerprints(&[&fingerprint.to_uppercase()])
+            .unwrap();
+        manifest
+            .verify_batch_signing_key_fingerprints(&["unrelated"])
+            .unwrap_err();
+        manifest
+            .verify_batch_signing_key_fingerprints(&[])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn invalid_specific_manifest_packet_encryption_key() {
+        let json = format!(
+            r#"
+{{
+    "format": 1,
+    "packet-encryption-keys": {{
+        "fake-key-1": {{
+            "certificate-signing-request": "-----BEGIN CERTIFICATE REQUEST-----\nfoo\n-----END CERTIFICATE REQUEST-----\n"
+        }}
+    }},
+    "batch-signing-public-keys": {{
+        "fake-key-2": {{
+        "expiration": "",
+        "public-key": "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n"
+      }}
+    }},
+    "ingestion-bucket": "s3://us-west-1/ingestion",
+    "peer-validation-bucket": "gs://validation/path/fragment"
+}}
+    "#,
+            DEFAULT_INGESTOR_SUBJECT_PUBLIC_KEY_INFO
+        );
+        let manifest = SpecificManifest::from_slice(json.as_bytes()).unwrap();
+        manifest.validate().unwrap_err();
+    }
+
+    #[test]
+    fn manifest_cache() {
+        let fetches = Cell::new(0);
+        let counting_fetcher = |url: &str, _: Option<&CachedManifest>, _: &Logger| {
+            fetches.set(fetches.get() + 1);
+            if url.ends_with("missing-manifest.json") {
+                return Err(anyhow!("no manifest at {}", url));
+            }
+            Ok(ConditionalFetch::Modified(CachedManifest {
+                body: format!("manifest at {}", url),
+                etag: None,
+                last_modified: None,
+            }))
+        };
+
+        let logger = setup_test_logging();
+        let cache = ManifestCache::new(Duration::from_secs(60), None);
+        for _ in 0..3 {
+            assert_eq!(
+                cache
+                    .fetch(
+                        "https://example.com/manifest.json",
+                        &logger,
+                        &counting_fetcher
+                    )
+                    .unwrap(),
+                "manifest at https://example.com/manifest.json"
+            );
+        }
+        assert_eq!(fetches.get(), 1);
+
+        // Failures are not cached
+        for _ in 0..2 {
+            cache
+                .fetch(
+                    "https://example.com/missing-manifest.json",
+                    &logger,
+                    &counting_fetcher,
+                )
+                .unwrap_err();
+        }
+        assert_eq!(fetches.get(), 3);
+
+        // Expired entries are fetched again
+        let cache = ManifestCache::new(Duration::from_secs(0), None);
+        for _ in 0..2 {
+            cache
+                .fetch(
+                    "https://example.com/manifest.json",
+                    &logger,
+                    &counting_fetcher,
+                )
+                .unwrap();
+        }
+        assert_eq!(fetches.get(), 5);
+    }
+
+    #[test]
+    fn manifest_cache_revalidation() {
+        let logger = setup_test_logging();
+        let url = "https://example.com/manifest.json";
+        let cached_manifest = CachedManifest {
+            body: "manifest".to_owned(),
+            etag: Some("\"etag\"".to_owned()),
+            last_modified: None,
+        };
+        let cache = ManifestCache::new(Duration::from_secs(0), None);
+
+        cache
+            .fetch(url, &logger, &|_, previous, _| {
+                assert_eq!(previous, None);
+                Ok(ConditionalFetch::Modified(cached_manifest.clone()))
+            })
+            .unwrap();
+
+        // Expired entry is revalidated and reused if unmodified
+        assert_eq!(
+            cache
+                .fetch(url, &logger, &|_, previous, _| {
+                    assert_eq!(previous, Some(&cached_manifest));
+                    Ok(ConditionalFetch::NotModified)
+                })
+                .unwrap(),
+            "manifest"
+        );
+
+        // Stale entry is used if revalidation fails
+        assert_eq!(
+            cache
+                .fetch(url, &logger, &|_, _, _| Err(anyhow!("server unavailable")))
+                .unwrap(),
+            "manifest"
+        );
+
+        // Unmodified response without a cached entry is an error
+        cache
+            .fetch(
+                "https://example.com/other-manifest.json",
+                &logger,
+                &|_, _, _| Ok(ConditionalFetch::NotModified),
+            )
+            .unwrap_err();
+    }
+
+    #[test]
+    fn manifest_cache_directory() {
+        let logger = setup_test_logging();
+        let url = "https://example.com/manifest.json";
+        let cached_manifest = CachedManifest {
+            body: "manifest".to_owned(),
+            etag: None,
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_owned()),
+        };
+        let directory = tempfile::tempdir().unwrap();
+
+        ManifestCache::new(Duration::from_secs(60), Some(directory.path().to_owned()))
+            .fetch(url, &logger, &|_, _, _| {
+                Ok(ConditionalFetch::Modified(cached_manifest.clone()))
+            })
+            .unwrap();
+
+        // A new cache, as in a restarted process, revalidates the manifest
+        // cached on disk, or uses it if the server is unavailable.
+        let cache = ManifestCache::new(Duration::from_secs(60), Some(directory.path().to_owned()));
+        assert_eq!(
+            cache
+                .fetch(url, &logger, &|_, previous, _| {
+                    assert_eq!(previous, Some(&cached_manifest));
+                    Err(anyhow!("server unavailable"))
+                })
+                .unwrap(),
+            "manifest"
+        );
+    }
+
+    #[test]
+    fn conditional_manifest_fetch() {
+        let logger = setup_test_logging();
+        let agent = RetryingAgent::default();
+        let url = format!("{}/manifest.json", mockito::server_url());
+
+        let unconditional_mock = mockito::mock("GET", "/manifest.json")
+            .match_header("If-None-Match", mockito::Matcher::Missing)
+            .with_status(200)
+            .with_header("ETag", "\"etag\"")
+            .with_header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .with_body("manifest")
+            .expect(1)
+            .create();
+        let fetched = match fetch_manifest_conditionally(&agent, &url, None, &logger).unwrap() {
+            ConditionalFetch::Modified(manifest) => manifest,
+            ConditionalFetch::NotModified => panic!("unexpected unmodified response"),
+        };
+        assert_eq!(
+            fetched,
+            CachedManifest {
+                body: "manifest".to_owned(),
+                etag: Some("\"etag\"".to_owned()),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_owned()),
+            }
+        );
+        unconditional_mock.assert();
+
+        let conditional_mock = mockito::mock("GET", "/manifest.json")
+            .match_header("If-None-Match", "\"etag\"")
+            .match_header("If-Modified-Since", "Wed, 21 Oct 2015 07:28:00 GMT")
+            .with_status(304)
+            .expect(1)
+            .create();
+        assert_eq!(
+            fetch_manifest_conditionally(&agent, &url, Some(&fetched), &logger).unwrap(),
+            ConditionalFetch::NotModified
+        );
+        conditional_mock.assert();
+    }
+
+    #[test]
+    fn specific_manifest_packet_file_digest_algorithm() {
+        let manifest = |algorithm: &str| {
+            format!(
+                r#"
+{{
+    "format": 1,
+    "packet-encryption-keys": {{}},
+    "batch-signing-public-keys": {{}},
+    "ingestion-bucket": "s3://us-west-1/ingestion",
+    "peer-validation-bucket": "gs://validation",
+    "packet-file-digest-algorithm": "{}"
+}}
+    "#,
+                algorithm
+            )
+        };
+
+        let sha384 = SpecificManifest::from_slice(manifest("sha384").as_bytes()).unwrap();
+        assert_eq!(
+            sha384.packet_file_digest_algorithm(),
+            DigestAlgorithm::Sha384
+        );
+        let sha256 = SpecificManifest::from_slice(manifest("sha256").as_bytes()).unwrap();
+        assert_eq!(
+            sha256.packet_file_digest_algorithm(),
+            DigestAlgorithm::Sha256
+        );
+        assert!(SpecificManifest::from_slice(manifest("md5").as_bytes()).is_err());
     }
 
     #[test]
@@ -1507,6 +2301,7 @@ mod tests {
                 ),
             ])
             .collect(),
+            packet_file_digest_algorithm: None,
         };
 
         // Passes because manifest has corresponding public key
@@ -1541,17 +2336,76 @@ mod tests {
         // Fails because one of the private keys corresponding to the manifest's
         // public keys is missing
         specific_manifest
-            .verify_packet_encryption_keys(&[packet_encryption_key_1_private])
+            .verify_packet_encryption_keys(std::slice::from_ref(&packet_encryption_key_1_private))
             .unwrap_err();
         // Fails because one of the private keys corresponding to the manifest's
         // public keys is missing
         specific_manifest
-            .verify_packet_encryption_keys(&[packet_encryption_key_2_private])
+            .verify_packet_encryption_keys(std::slice::from_ref(&packet_encryption_key_2_private))
             .unwrap_err();
         // Fails because none of the private keys corresponding to the manifest
         // public keys
         specific_manifest
-            .verify_packet_encryption_keys(&[packet_encryption_key_unrelated_private])
+            .verify_packet_encryption_keys(std::slice::from_ref(
+                &packet_encryption_key_unrelated_private,
+            ))
             .unwrap_err();
+
+        // Each public key is matched to the index of its private key
+        assert_eq!(
+            specific_manifest
+                .packet_decryption_key_identifiers(&[
+                    packet_encryption_key_unrelated_private,
+                    packet_encryption_key_2_private,
+                    packet_encryption_key_1_private,
+                ])
+                .unwrap(),
+            vec![
+                ("packet-encryption-key-1".to_owned(), 2),
+                ("packet-encryption-key-2".to_owned(), 1),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn generated_keys_round_trip() {
+        let expiration = Utc::now();
+        let batch_signing_key = generate_batch_signing_key(expiration).unwrap();
+        let packet_encryption_key = generate_packet_encryption_key("localhost").unwrap();
+
+        let json = serde_json::json!({
+            "format": 1,
+            "packet-encryption-keys": {
+                "packet-key": packet_encryption_key.certificate_signing_request,
+            },
+            "batch-signing-public-keys": {
+                "signing-key": batch_signing_key.public_key,
+            },
+            "ingestion-bucket": "s3://us-west-1/ingestion",
+            "peer-validation-bucket": "gs://validation/path/fragment",
+        });
+        let manifest = SpecificManifest::from_slice(json.to_string().as_bytes()).unwrap();
+
+        assert_eq!(
+            manifest.batch_signing_public_keys["signing-key"].expiration,
+            expiration.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        let batch_signer = BatchSigningKey {
+            key: EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P256_SHA256_ASN1_SIGNING,
+                &base64::decode(&batch_signing_key.private_key).unwrap(),
+            )
+            .unwrap(),
+            identifier: "signing-key".to_owned(),
+        };
+        manifest.verify_batch_signing_key(&batch_signer).unwrap();
+
+        let packet_decryption_key =
+            PrivateKey::from_base64(&packet_encryption_key.private_key).unwrap();
+        manifest
+            .verify_packet_encryption_keys(&[packet_decryption_key])
+            .unwrap();
     }
 }
@@ -1,18 +1,35 @@
 use anyhow::{Context, Result};
 use http::Response;
+use once_cell::sync::Lazy;
 use prometheus::{
-    register_int_counter, register_int_counter_vec, Encoder, IntCounter, IntCounterVec, TextEncoder,
+    histogram_opts, opts, register, register_int_counter, register_int_counter_vec, Encoder,
+    HistogramVec, IntCounter, IntCounterVec, Opts, TextEncoder,
 };
 use slog::{error, info, o, Logger};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
 use tokio::runtime::Runtime;
-use warp::Filter;
+use warp::{Filter, Rejection, Reply};
+
+/// A function that returns an error if this instance is not ready to handle
+/// work. Readiness checks may block, so they are run on a thread where blocking
+/// is allowed.
+pub type ReadinessCheck = Arc<dyn Fn() -> Result<()> + Send + Sync>;
 
 /// Starts listening on an HTTP endpoint so that Prometheus can scrape metrics
-/// from this instance. On success, returns a Runtime value that the caller must
-/// keep live, or the task that handles Prometheus scrapes will not run. Returns
-/// an error if something goes wrong setting up the endpoint.
-pub fn start_metrics_scrape_endpoint(port: u16, parent_logger: &Logger) -> Result<Runtime> {
+/// from this instance and Kubernetes can probe it. `/metrics` serves metrics,
+/// `/healthz` succeeds as long as the process is serving requests and `/readyz`
+/// succeeds if `readiness_check` does. On success, returns a Runtime value that
+/// the caller must keep live, or the task that handles Prometheus scrapes will
+/// not run. Returns an error if something goes wrong setting up the endpoint.
+pub fn start_metrics_scrape_endpoint(
+    port: u16,
+    readiness_check: ReadinessCheck,
+    parent_logger: &Logger,
+) -> Result<Runtime> {
     // The default, multi-threaded runtime should suffice for our needs
     let runtime = Runtime::new().context("failed to create runtime for metrics endpoint")?;
 
@@ -21,29 +38,8 @@ pub fn start_metrics_scrape_endpoint(port: u16, parent_logger: &Logger) -> Resul
 
     // This task will run forever, so we intentionally drop the returned handle
     runtime.spawn(async move {
-        // Clone scrape_logger so it can safely be moved into the closure that
-        // handles metrics scrapes.
-        let scrape_logger_clone = scrape_logger.clone();
-        let endpoint = warp::get().and(warp::path("metrics")).map(move || {
-            match handle_scrape() {
-                Ok(body) => {
-                    Response::builder()
-                        // https://github.com/prometheus/docs/blob/master/content/docs/instrumenting/exposition_formats.md
-                        .header("Content-Type", "text/plain; version=0.0.4")
-                        .body(body)
-                }
-                Err(err) => {
-                    error!(
-                        scrape_logger_clone,
-                        "unable to scrape Prometheus metrics: {}", err
-                    );
-                    Response::builder().status(500).body(vec![])
-                }
-            }
-        });
-
         info!(scrape_logger, "serving metrics scrapes on 0.0.0.0:{}", port);
-        warp::serve(endpoint)
+        warp::serve(routes(readiness_check, scrape_logger))
             .run(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port))
             .await;
     });
@@ -51,6 +47,59 @@ pub fn start_metrics_scrape_endpoint(port: u16, parent_logger: &Logger) -> Resul
     Ok(runtime)
 }
 
+fn routes(
+    readiness_check: ReadinessCheck,
+    logger: Logger,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    // Clone logger so it can safely be moved into the closure that handles
+    // metrics scrapes.
+    let scrape_logger = logger.clone();
+    let metrics = warp::get().and(warp::path("metrics")).map(move || {
+        match handle_scrape() {
+            Ok(body) => {
+                Response::builder()
+                    // https://github.com/prometheus/docs/blob/master/content/docs/instrumenting/exposition_formats.md
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .body(body)
+            }
+            Err(err) => {
+                error!(
+                    scrape_logger,
+                    "unable to scrape Prometheus metrics: {}", err
+                );
+                Response::builder().status(500).body(vec![])
+            }
+        }
+    });
+
+    let healthz = warp::get()
+        .and(warp::path("healthz"))
+        .map(|| Response::builder().body("ok".to_owned()));
+
+    let readyz = warp::get().and(warp::path("readyz")).and_then(move || {
+        let readiness_check = readiness_check.clone();
+        let logger = logger.clone();
+        async move {
+            let response = match tokio::task::spawn_blocking(move || readiness_check()).await {
+                Ok(Ok(())) => Response::builder().body("ok".to_owned()),
+                Ok(Err(err)) => {
+                    error!(logger, "readiness check failed: {:?}", err);
+                    Response::builder()
+                        .status(503)
+                        .body(format!("not ready: {:?}", err))
+                }
+                Err(err) => {
+                    error!(logger, "readiness check panicked: {}", err);
+                    Response::builder().status(500).body(String::new())
+                }
+            };
+            Ok::<_, Infallible>(response)
+        }
+    });
+
+    metrics.or(healthz).or(readyz)
+}
+
 fn handle_scrape() -> Result<Vec<u8>> {
     let mut buffer = Vec::new();
     TextEncoder::new()
@@ -59,31 +108,165 @@ fn handle_scrape() -> Result<Vec<u8>> {
     Ok(buffer)
 }
 
-/// A group of collectors for intake tasks.
+/// Returns the options for a metric named `name`, with a constant "locality"
+/// label if a locality is provided, so that the metrics of the localities
+/// served by a worker can be told apart.
+fn locality_opts(name: &str, help: &str, locality: Option<&str>) -> Opts {
+    let opts = Opts::new(name, help);
+    match locality {
+        Some(locality) => opts.const_label("locality", locality),
+        None => opts,
+    }
+}
+
+/// A group of collectors for intake tasks. Collectors that count batches,
+/// packets or bytes carry an "ingestion_server" label identifying the ingestion
+/// server that wrote the batch, so that alerts can fire when one server stops
+/// producing batches or has batches rejected. Ingestion batches don't name the
+/// server that wrote them, so it is identified by the key from its manifest
+/// that the batch's signature was verified with, or "unknown" if the batch
+/// was rejected before its signature was verified.
 #[derive(Debug)]
 pub struct IntakeMetricsCollector {
     pub intake_tasks_started: IntCounter,
     pub intake_tasks_finished: IntCounterVec,
+    /// Intake tasks skipped because their validation batches already existed.
+    pub intake_tasks_already_processed: IntCounterVec,
+    /// Ingestion batches rejected because of their end time, labeled by
+    /// whether they were too old or too far in the future.
+    pub batches_rejected_for_time: IntCounterVec,
+    pub duplicate_packets_dropped: IntCounterVec,
+    pub malformed_packets_skipped: IntCounterVec,
+    pub packets_processed: IntCounterVec,
+    /// Ingestion batches for which validation batches were written.
+    pub batches_intaken: IntCounterVec,
+    /// Size of the packet files of the ingestion batches that were intaken.
+    pub bytes_intaken: IntCounterVec,
+    /// Ingestion batches whose intake failed in a way that retrying can't fix,
+    /// labeled by the kind of error ("validation", "crypto" or "task").
+    pub batches_rejected: IntCounterVec,
 }
 
 impl IntakeMetricsCollector {
     pub fn new() -> Result<Self> {
-        let intake_tasks_started: IntCounter = register_int_counter!(
+        Self::with_locality(None)
+    }
+
+    /// Creates collectors for the intake tasks of one of the localities served
+    /// by a worker, whose metrics carry a "locality" label.
+    pub fn for_locality(locality: &str) -> Result<Self> {
+        Self::with_locality(Some(locality))
+    }
+
+    fn with_locality(locality: Option<&str>) -> Result<Self> {
+        let intake_tasks_started: IntCounter = register_int_counter!(locality_opts(
             "facilitator_intake_tasks_started",
-            "Number of intake-batch tasks that started (on the facilitator side)"
-        )
+            "Number of intake-batch tasks that started (on the facilitator side)",
+            locality,
+        ))
         .context("failed to register metrics counter for started intakes")?;
 
         let intake_tasks_finished = register_int_counter_vec!(
-            "facilitator_intake_tasks_finished",
-            "Number of intake-batch tasks that finished (on the facilitator side)",
+            locality_opts(
+                "facilitator_intake_tasks_finished",
+                "Number of intake-batch tasks that finished (on the facilitator side)",
+                locality
+            ),
             &["status"]
         )
         .context("failed to register metrics counter for finished intakes")?;
 
+        let intake_tasks_already_processed = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_intake_tasks_already_processed",
+                "Number of intake-batch tasks skipped because their validation batches already existed",
+                locality
+            ),
+            &["ingestion_server"]
+        )
+        .context("failed to register metrics counter for already processed intakes")?;
+
+        let batches_rejected_for_time = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_intake_batches_rejected_for_time",
+                "Number of ingestion batches rejected because their end time was too old or too far in the future",
+                locality
+            ),
+            &["ingestion_server", "reason"]
+        )
+        .context("failed to register metrics counter for batches rejected for time")?;
+
+        let duplicate_packets_dropped = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_intake_duplicate_packets_dropped",
+                "Number of duplicate ingestion packets dropped during intake",
+                locality
+            ),
+            &["ingestion_server"]
+        )
+        .context("failed to register metrics counter for duplicate intake packets")?;
+
+        let malformed_packets_skipped = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_intake_malformed_packets_skipped",
+                "Number of malformed ingestion packets skipped during intake",
+                locality
+            ),
+            &["ingestion_server"]
+        )
+        .context("failed to register metrics counter for malformed intake packets")?;
+
+        let packets_processed = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_intake_packets_processed",
+                "Number of ingestion packets for which validation packets were written",
+                locality
+            ),
+            &["ingestion_server"]
+        )
+        .context("failed to register metrics counter for processed intake packets")?;
+
+        let batches_intaken = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_intake_batches_intaken",
+                "Number of ingestion batches for which validation batches were written",
+                locality
+            ),
+            &["ingestion_server"]
+        )
+        .context("failed to register metrics counter for intaken batches")?;
+
+        let bytes_intaken = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_intake_bytes_intaken",
+                "Number of bytes in the packet files of intaken ingestion batches",
+                locality
+            ),
+            &["ingestion_server"]
+        )
+        .context("failed to register metrics counter for intaken bytes")?;
+
+        let batches_rejected = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_intake_batches_rejected",
+                "Number of ingestion batches rejected during intake",
+                locality
+            ),
+            &["ingestion_server", "reason"]
+        )
+        .context("failed to register metrics counter for rejected batches")?;
+
         Ok(Self {
             intake_tasks_started,
             intake_tasks_finished,
+            intake_tasks_already_processed,
+            batches_rejected_for_time,
+            duplicate_packets_dropped,
+            malformed_packets_skipped,
+            packets_processed,
+            batches_intaken,
+            bytes_intaken,
+            batches_rejected,
         })
     }
 }
@@ -92,30 +275,89 @@ impl IntakeMetricsCollector {
 pub struct AggregateMetricsCollector {
     pub aggregate_tasks_started: IntCounter,
     pub aggregate_tasks_finished: IntCounterVec,
+    pub duplicate_packets_dropped: IntCounter,
+    pub packets_aggregated: IntCounter,
+    /// Packets excluded from the sum, labeled by reason.
+    pub packets_rejected: IntCounterVec,
+    /// Batches excluded from the sum because a validation batch was missing.
+    pub batches_excluded: IntCounter,
     pub own_validation_batches_reader_metrics: BatchReaderMetricsCollector,
     pub peer_validation_batches_reader_metrics: BatchReaderMetricsCollector,
 }
 
 impl AggregateMetricsCollector {
     pub fn new() -> Result<Self> {
-        let aggregate_tasks_started: IntCounter = register_int_counter!(
+        Self::with_locality(None)
+    }
+
+    /// Creates collectors for the aggregate tasks of one of the localities
+    /// served by a worker, whose metrics carry a "locality" label.
+    pub fn for_locality(locality: &str) -> Result<Self> {
+        Self::with_locality(Some(locality))
+    }
+
+    fn with_locality(locality: Option<&str>) -> Result<Self> {
+        let aggregate_tasks_started: IntCounter = register_int_counter!(locality_opts(
             "facilitator_aggregate_tasks_started",
-            "Number of aggregate tasks that started (on the facilitator side)"
-        )
+            "Number of aggregate tasks that started (on the facilitator side)",
+            locality,
+        ))
         .context("failed to register metrics counter for started aggregations")?;
 
         let aggregate_tasks_finished = register_int_counter_vec!(
-            "facilitator_aggregate_tasks_finished",
-            "Number of aggregate tasks that finished (on the facilitator side)",
+            locality_opts(
+                "facilitator_aggregate_tasks_finished",
+                "Number of aggregate tasks that finished (on the facilitator side)",
+                locality
+            ),
             &["status"]
         )
         .context("failed to register metrics counter for finished aggregations")?;
 
+        let duplicate_packets_dropped: IntCounter = register_int_counter!(locality_opts(
+            "facilitator_aggregate_duplicate_packets_dropped",
+            "Number of duplicate ingestion packets dropped during aggregation",
+            locality,
+        ))
+        .context("failed to register metrics counter for duplicate aggregate packets")?;
+
+        let packets_aggregated: IntCounter = register_int_counter!(locality_opts(
+            "facilitator_aggregate_packets_aggregated",
+            "Number of ingestion packets whose shares were aggregated",
+            locality,
+        ))
+        .context("failed to register metrics counter for aggregated packets")?;
+
+        let packets_rejected = register_int_counter_vec!(
+            locality_opts(
+                "facilitator_aggregate_packets_rejected",
+                "Number of ingestion packets excluded from aggregation",
+                locality
+            ),
+            &["reason"]
+        )
+        .context("failed to register metrics counter for rejected aggregate packets")?;
+
+        let batches_excluded: IntCounter = register_int_counter!(locality_opts(
+            "facilitator_aggregate_batches_excluded",
+            "Number of batches excluded from aggregation because a validation batch was missing",
+            locality,
+        ))
+        .context("failed to register metrics counter for excluded aggregate batches")?;
+
         Ok(Self {
             aggregate_tasks_started,
             aggregate_tasks_finished,
-            own_validation_batches_reader_metrics: BatchReaderMetricsCollector::new("own")?,
-            peer_validation_batches_reader_metrics: BatchReaderMetricsCollector::new("peer")?,
+            duplicate_packets_dropped,
+            packets_aggregated,
+            packets_rejected,
+            batches_excluded,
+            own_validation_batches_reader_metrics: BatchReaderMetricsCollector::new(
+                "own", locality,
+            )?,
+            peer_validation_batches_reader_metrics: BatchReaderMetricsCollector::new(
+                "peer", locality,
+            )?,
         })
     }
 }
@@ -126,12 +368,15 @@ pub struct BatchReaderMetricsCollector {
 }
 
 impl BatchReaderMetricsCollector {
-    pub fn new(ownership: &str) -> Result<Self> {
+    pub fn new(ownership: &str, locality: Option<&str>) -> Result<Self> {
         let invalid_validation_batches = register_int_counter_vec!(
-            format!("facilitator_invalid_{}_validation_batches", ownership),
-            format!(
-                "Number of invalid {} validation batches encountered during aggregation",
-                ownership
+            locality_opts(
+                &format!("facilitator_invalid_{}_validation_batches", ownership),
+                &format!(
+                    "Number of invalid {} validation batches encountered during aggregation",
+                    ownership
+                ),
+                locality,
             ),
             &["reason"]
         )
@@ -142,3 +387,207 @@ impl BatchReaderMetricsCollector {
         })
     }
 }
+
+/// A group of collectors for requests made by transports, labeled with the
+/// kind of storage backend and the bucket being accessed.
+#[derive(Clone, Debug)]
+pub struct TransportMetricsCollector {
+    /// Requests made, labeled by backend, bucket, operation and status ("ok"
+    /// or "error").
+    pub requests: IntCounterVec,
+    /// Request latency in seconds, labeled by backend, bucket and operation.
+    pub request_duration: HistogramVec,
+    /// Bytes read from objects, labeled by backend and bucket.
+    pub bytes_read: IntCounterVec,
+    /// Bytes written to objects, labeled by backend and bucket.
+    pub bytes_written: IntCounterVec,
+    /// Time from the start of an upload until it is completed, in seconds,
+    /// labeled by backend and bucket.
+    pub upload_duration: HistogramVec,
+}
+
+impl TransportMetricsCollector {
+    /// Creates a TransportMetricsCollector and registers its collectors with
+    /// the default registry. Since collectors can only be registered once,
+    /// callers should create a single TransportMetricsCollector and share it
+    /// between transports.
+    pub fn new() -> Result<Self> {
+        let collector = Self::new_unregistered()?;
+        register(Box::new(collector.requests.clone()))
+            .context("failed to register metrics counter for transport requests")?;
+        register(Box::new(collector.request_duration.clone()))
+            .context("failed to register metrics histogram for transport request duration")?;
+        register(Box::new(collector.bytes_read.clone()))
+            .context("failed to register metrics counter for transport bytes read")?;
+        register(Box::new(collector.bytes_written.clone()))
+            .context("failed to register metrics counter for transport bytes written")?;
+        register(Box::new(collector.upload_duration.clone()))
+            .context("failed to register metrics histogram for transport upload duration")?;
+        Ok(collector)
+    }
+
+    /// Creates a TransportMetricsCollector without registering its collectors.
+    pub(crate) fn new_unregistered() -> Result<Self> {
+        Ok(Self {
+            requests: IntCounterVec::new(
+                opts!(
+                    "facilitator_transport_requests",
+                    "Number of requests made to storage backends"
+                ),
+                &["backend", "bucket", "operation", "status"],
+            )
+            .context("failed to create metrics counter for transport requests")?,
+            request_duration: HistogramVec::new(
+                histogram_opts!(
+                    "facilitator_transport_request_duration_seconds",
+                    "Time taken by requests to storage backends"
+                ),
+                &["backend", "bucket", "operation"],
+            )
+            .context("failed to create metrics histogram for transport request duration")?,
+            bytes_read: IntCounterVec::new(
+                opts!(
+                    "facilitator_transport_bytes_read",
+                    "Number of bytes read from objects in storage backends"
+                ),
+                &["backend", "bucket"],
+            )
+            .context("failed to create metrics counter for transport bytes read")?,
+            bytes_written: IntCounterVec::new(
+                opts!(
+                    "facilitator_transport_bytes_written",
+                    "Number of bytes written to objects in storage backends"
+                ),
+                &["backend", "bucket"],
+            )
+            .context("failed to create metrics counter for transport bytes written")?,
+            upload_duration: HistogramVec::new(
+                histogram_opts!(
+                    "facilitator_transport_upload_duration_seconds",
+                    "Time taken to upload objects to storage backends",
+                    vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0]
+                ),
+                &["backend", "bucket"],
+            )
+            .context("failed to create metrics histogram for transport upload duration")?,
+        })
+    }
+}
+
+/// Collectors for HTTP requests made by RetryingAgent, registered with the
+/// default registry on first use. Unlike the other collectors, these are
+/// process wide statics because agents are created throughout the crate, by
+/// transports, task queues, token providers and manifest fetches.
+pub(crate) static HTTP_METRICS: Lazy<HttpMetricsCollector> = Lazy::new(|| {
+    let collector =
+        HttpMetricsCollector::new_unregistered().expect("failed to create HTTP metrics");
+    // Registration can only fail if a collector with the same name was already
+    // registered, in which case requests are still counted in the collector,
+    // but not reported.
+    let _ = register(Box::new(collector.requests.clone()));
+    let _ = register(Box::new(collector.request_duration.clone()));
+    let _ = register(Box::new(collector.oauth_token_refreshes.clone()));
+    collector
+});
+
+/// A group of collectors for HTTP requests and the OAuth tokens used to
+/// authenticate them.
+#[derive(Clone, Debug)]
+pub struct HttpMetricsCollector {
+    /// Request attempts, labeled by method, host and status, which is either
+    /// the HTTP status code or "transport_error".
+    pub requests: IntCounterVec,
+    /// Request attempt latency in seconds, labeled by method and host.
+    pub request_duration: HistogramVec,
+    /// OAuth and identity tokens obtained, labeled by kind of token
+    /// ("default", "impersonated", "identity" or "impersonated_identity") and
+    /// status ("ok" or "error").
+    pub oauth_token_refreshes: IntCounterVec,
+}
+
+impl HttpMetricsCollector {
+    /// Creates an HttpMetricsCollector without registering its collectors.
+    pub(crate) fn new_unregistered() -> Result<Self> {
+        Ok(Self {
+            requests: IntCounterVec::new(
+                opts!(
+                    "facilitator_http_requests",
+                    "Number of HTTP request attempts, including retries"
+                ),
+                &["method", "host", "status"],
+            )
+            .context("failed to create metrics counter for HTTP requests")?,
+            request_duration: HistogramVec::new(
+                histogram_opts!(
+                    "facilitator_http_request_duration_seconds",
+                    "Time taken by HTTP request attempts"
+                ),
+                &["method", "host"],
+            )
+            .context("failed to create metrics histogram for HTTP request duration")?,
+            oauth_token_refreshes: IntCounterVec::new(
+                opts!(
+                    "facilitator_oauth_token_refreshes",
+                    "Number of OAuth tokens obtained to authenticate to cloud APIs"
+                ),
+                &["kind", "status"],
+            )
+            .context("failed to create metrics counter for OAuth token refreshes")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::setup_test_logging;
+    use anyhow::anyhow;
+
+    #[tokio::test]
+    async fn health_and_readiness() {
+        let logger = setup_test_logging();
+        let ready = routes(Arc::new(|| Ok(())), logger.clone());
+        let not_ready = routes(Arc::new(|| Err(anyhow!("no queue"))), logger);
+
+        for routes in [&ready, &not_ready] {
+            let response = warp::test::request().path("/healthz").reply(routes).await;
+            assert_eq!(response.status(), 200);
+        }
+
+        let response = warp::test::request().path("/readyz").reply(&ready).await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .path("/readyz")
+            .reply(&not_ready)
+            .await;
+        assert_eq!(response.status(), 503);
+        assert!(String::from_utf8_lossy(response.body()).contains("no queue"));
+    }
+
+    #[test]
+    fn locality_metrics() {
+        // Collectors for several localities can be registered side by side
+        let zc = IntakeMetricsCollector::for_locality("zc").unwrap();
+        let ta = IntakeMetricsCollector::for_locality("ta").unwrap();
+        zc.packets_processed
+            .with_label_values(&["megacorp"])
+            .inc_by(3);
+        ta.packets_processed.with_label_values(&["megacorp"]).inc();
+        zc.batches_rejected
+            .with_label_values(&["other-corp", "crypto"])
+            .inc();
+        IntakeMetricsCollector::for_locality("zc").unwrap_err();
+
+        let scrape = String::from_utf8(handle_scrape().unwrap()).unwrap();
+        assert!(scrape.contains(
+            r#"facilitator_intake_packets_processed{ingestion_server="megacorp",locality="zc"} 3"#
+        ));
+        assert!(scrape.contains(
+            r#"facilitator_intake_packets_processed{ingestion_server="megacorp",locality="ta"} 1"#
+        ));
+        assert!(scrape.contains(
+            r#"facilitator_intake_batches_rejected{ingestion_server="other-corp",locality="zc",reason="crypto"} 1"#
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::field::EncodedField;
+use anyhow::{anyhow, ensure, Result};
+use rand::Rng;
+use std::str::FromStr;
+
+/// The distribution from which differential privacy noise is drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseMechanism {
+    /// Discrete Laplace (two-sided geometric) noise, providing pure
+    /// epsilon-differential privacy
+    Laplace,
+    /// Discrete Gaussian noise, providing (epsilon, delta)-differential privacy
+    DiscreteGaussian,
+}
+
+impl NoiseMechanism {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoiseMechanism::Laplace => "laplace",
+            NoiseMechanism::DiscreteGaussian => "discrete-gaussian",
+        }
+    }
+}
+
+impl FromStr for NoiseMechanism {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "laplace" => Ok(NoiseMechanism::Laplace),
+            "discrete-gaussian" => Ok(NoiseMechanism::DiscreteGaussian),
+            _ => Err(anyhow!("unknown noise mechanism {}", s)),
+        }
+    }
+}
+
+/// DifferentialPrivacy configures the noise added to aggregate sums before
+/// they are published.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DifferentialPrivacy {
+    mechanism: NoiseMechanism,
+    epsilon: f64,
+    delta: f64,
+}
+
+impl DifferentialPrivacy {
+    /// Creates a DifferentialPrivacy. Epsilon must be positive, and less than 1
+    /// for the discrete Gaussian mechanism, whose calibration is only known to
+    /// provide the privacy guarantee in that range. Delta must be in (0, 1) for
+    /// the discrete Gaussian mechanism and is ignored by the Laplace mechanism.
+    pub fn new(mechanism: NoiseMechanism, epsilon: f64, delta: f64) -> Result<Self> {
+        ensure!(
+            epsilon.is_finite() && epsilon > 0.0,
+            "epsilon must be positive"
+        );
+        let delta = match mechanism {
+            NoiseMechanism::Laplace => 0.0,
+            NoiseMechanism::DiscreteGaussian => {
+                ensure!(
+                    epsilon < 1.0,
+                    "epsilon must be less than 1 for the discrete Gaussian mechanism"
+                );
+                ensure!(
+                    delta > 0.0 && delta < 1.0,
+                    "delta must be between 0 and 1 for the discrete Gaussian mechanism"
+                );
+                delta
+            }
+        };
+        Ok(DifferentialPrivacy {
+            mechanism,
+            epsilon,
+            delta,
+        })
+    }
+
+    /// Calibrates noise to a sum of vectors of zeroes and ones, of which each
+    /// client contributes one, with at most `max_ones` ones set.
+    pub fn calibrate(&self, max_ones: u32) -> NoiseParameters {
+        let sensitivity = f64::from(max_ones.max(1));
+        let scale = match self.mechanism {
+            // L1 sensitivity over epsilon
+            NoiseMechanism::Laplace => sensitivity / self.epsilon,
+            // L2 sensitivity times sqrt(2 ln(1.25 / delta)) over epsilon, the
+            // classical Gaussian mechanism's calibration (Dwork and Roth,
+            // theorem A.1), which holds for epsilon < 1. Canonne, Kamath and
+            // Steinke show the discrete Gaussian with the same variance is at
+            // least as private.
+            NoiseMechanism::DiscreteGaussian => {
+                sensitivity.sqrt() * (2.0 * (1.25 / self.delta).ln()).sqrt() / self.epsilon
+            }
+        };
+        NoiseParameters {
+            mechanism: self.mechanism,
+            epsilon: self.epsilon,
+            delta: self.delta,
+            scale,
+        }
+    }
+}
+
+/// The parameters of the noise added to a sum part, as recorded in its header.
+/// Scale is the Laplace scale parameter or the Gaussian standard deviation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NoiseParameters {
+    pub mechanism: NoiseMechanism,
+    pub epsilon: f64,
+    pub delta: f64,
+    pub scale: f64,
+}
+
+impl NoiseParameters {
+    /// Adds independently drawn noise to each of the shares. Each data share
+    /// processor adds noise to its own share of the sum, so the published sum
+    /// stays private even if the other processor does not add any.
+    pub fn add_noise<F: EncodedField, R: Rng + ?Sized>(&self, shares: &mut [F], rng: &mut R) {
+        for share in shares.iter_mut() {
+            let noise = match self.mechanism {
+                NoiseMechanism::Laplace => discrete_laplace(self.scale, rng),
+                NoiseMechanism::DiscreteGaussian => discrete_gaussian(self.scale, rng),
+            };
+            // Noise far beyond the field's modulus is vanishingly unlikely, and
+            // would make the sum meaningless anyway.
+            let magnitude = F::reduce(noise.unsigned_abs());
+            if noise < 0 {
+                *share -= magnitude;
+            } else {
+                *share += magnitude;
+            }
+        }
+    }
+}
+
+/// Draws from the discrete Laplace distribution, for which the probability of
+/// x is proportional to exp(-|x| / scale), as the difference of two geometric
+/// variables.
+fn discrete_laplace<R: Rng + ?Sized>(scale: f64, rng: &mut R) -> i64 {
+    geometric(scale, rng) - geometric(scale, rng)
+}
+
+/// Draws the number of failures before the first success of trials that
+/// succeed with probability 1 - exp(-1 / scale).
+fn geometric<R: Rng + ?Sized>(scale: f64, rng: &mut R) -> i64 {
+    // gen() is in [0, 1), so this is in (0, 1]
+    let uniform = 1.0 - rng.gen::<f64>();
+    (-scale * uniform.ln()).floor() as i64
+}
+
+/// Draws from the discrete Gaussian distribution with the provided standard
+/// deviation by rejection sampling from the discrete Laplace distribution, as
+/// described in Algorithm 3 of Canonne, Kamath and Steinke, "The Discrete
+/// Gaussian for Differential Privacy" (https://arxiv.org/abs/2004.00010).
+fn discrete_gaussian<R: Rng + ?Sized>(sigma: f64, rng: &mut R) -> i64 {
+    let t = sigma.floor() + 1.0;
+    loop {
+        let y = discrete_laplace(t, rng);
+        let acceptance =
+            (-((y.abs() as f64) - sigma * sigma / t).powi(2) / (2.0 * sigma * sigma)).exp();
+        if rng.gen::<f64>() < acceptance {
+            return y;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prio::field::{Field32, FieldElement};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn mean_and_variance(samples: &[i64]) -> (f64, f64) {
+        let count = samples.len() as f64;
+        let mean = samples.iter().sum::<i64>() as f64 / count;
+        let variance = samples
+            .iter()
+            .map(|s| (*s as f64 - mean).powi(2))
+            .sum::<f64>()
+            / count;
+        (mean, variance)
+    }
+
+    #[test]
+    fn discrete_laplace_distribution() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let scale = 4.0;
+        let samples: Vec<i64> = (0..100_000)
+            .map(|_| discrete_laplace(scale, &mut rng))
+            .collect();
+        let (mean, variance) = mean_and_variance(&samples);
+        let q = (-1.0 / scale).exp();
+        let expected_variance = 2.0 * q / (1.0 - q).powi(2);
+        assert!(mean.abs() < 0.1, "mean {}", mean);
+        assert!(
+            (variance / expected_variance - 1.0).abs() < 0.05,
+            "variance {} expected {}",
+            variance,
+            expected_variance
+        );
+    }
+
+    #[test]
+    fn discrete_gaussian_distribution() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let sigma = 5.0;
+        let samples: Vec<i64> = (0..100_000)
+            .map(|_| discrete_gaussian(sigma, &mut rng))
+            .collect();
+        let (mean, variance) = mean_and_variance(&samples);
+        assert!(mean.abs() < 0.1, "mean {}", mean);
+        assert!(
+            (variance / (sigma * sigma) - 1.0).abs() < 0.05,
+            "variance {} expected {}",
+            variance,
+            sigma * sigma
+        );
+    }
+
+    #[test]
+    fn calibration() {
+        let laplace = DifferentialPrivacy::new(NoiseMechanism::Laplace, 0.5, 0.3).unwrap();
+        assert_eq!(
+            laplace.calibrate(2),
+            NoiseParameters {
+                mechanism: NoiseMechanism::Laplace,
+                epsilon: 0.5,
+                delta: 0.0,
+                scale: 4.0,
+            }
+        );
+
+        let gaussian =
+            DifferentialPrivacy::new(NoiseMechanism::DiscreteGaussian, 0.5, 1e-6).unwrap();
+        let parameters = gaussian.calibrate(4);
+        assert_eq!(parameters.delta, 1e-6);
+        assert!((parameters.scale - 4.0 * (2.0 * 1.25e6f64.ln()).sqrt()).abs() < 1e-9);
+
+        DifferentialPrivacy::new(NoiseMechanism::Laplace, 0.0, 0.0).unwrap_err();
+        DifferentialPrivacy::new(NoiseMechanism::DiscreteGaussian, 0.5, 0.0).unwrap_err();
+        DifferentialPrivacy::new(NoiseMechanism::DiscreteGaussian, 0.5, 1.0).unwrap_err();
+        // The Gaussian mechanism's calibration only holds for epsilon < 1
+        DifferentialPrivacy::new(NoiseMechanism::DiscreteGaussian, 1.0, 1e-6).unwrap_err();
+        DifferentialPrivacy::new(NoiseMechanism::DiscreteGaussian, 2.0, 1e-6).unwrap_err();
+        DifferentialPrivacy::new(NoiseMechanism::Laplace, 2.0, 0.0).unwrap();
+    }
+
+    #[test]
+    fn add_noise_to_shares() {
+        let parameters = DifferentialPrivacy::new(NoiseMechanism::Laplace, 1.0, 0.0)
+            .unwrap()
+            .calibrate(1);
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut shares = vec![Field32::from(0); 1000];
+        parameters.add_noise(&mut shares, &mut rng);
+
+        // Negative noise wraps around the field's modulus
+        let noise: Vec<i64> = shares
+            .iter()
+            .map(|share| {
+                let value = u32::from(*share);
+                if value > Field32::modulus() / 2 {
+                    -i64::from(Field32::modulus() - value)
+                } else {
+                    i64::from(value)
+                }
+            })
+            .collect();
+        assert!(noise.iter().any(|n| *n < 0));
+        assert!(noise.iter().any(|n| *n > 0));
+        assert!(noise.iter().all(|n| n.abs() < 100));
+    }
+
+    #[test]
+    fn mechanism_names() {
+        for mechanism in &[NoiseMechanism::Laplace, NoiseMechanism::DiscreteGaussian] {
+            assert_eq!(
+                NoiseMechanism::from_str(mechanism.as_str()).unwrap(),
+                *mechanism
+            );
+        }
+        NoiseMechanism::from_str("gaussian").unwrap_err();
+    }
+}
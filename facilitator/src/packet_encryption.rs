@@ -0,0 +1,256 @@
+use crate::{
+    hpke::{self, Ciphersuite, HpkePrivateKey},
+    idl::IngestionDataSharePacket,
+    manifest::generate_packet_encryption_key,
+    prio_crypto::{PrioBackend, PrioCrypto},
+    CryptoError,
+};
+use anyhow::{anyhow, Context, Result};
+use prio::{
+    encrypt::{encrypt_share, PrivateKey, PublicKey},
+    field::FieldElement,
+};
+use std::str::FromStr;
+
+/// The HPKE info string with which the payloads of ingestion packets are
+/// encrypted. The associated data is the packet's UUID, so that payloads
+/// can't be moved between packets.
+const PACKET_PAYLOAD_INFO: &[u8] = b"prio-server ingestion packet";
+
+/// The prefix of HPKE packet decryption keys in configuration
+const HPKE_KEY_PREFIX: &str = "hpke:";
+
+/// A key with which the payloads of ingestion packets are decrypted. In
+/// configuration, ECIES keys are written in libprio-rs' base64 encoding, and
+/// HPKE keys as "hpke:<ciphersuite>:<private key>", where the private key is
+/// base64 encoded, e.g. "hpke:x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm:...".
+#[derive(Clone, Debug)]
+pub enum PacketDecryptionKey {
+    Ecies(PrivateKey),
+    Hpke(HpkePrivateKey),
+}
+
+impl FromStr for PacketDecryptionKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix(HPKE_KEY_PREFIX) {
+            Some(hpke_key) => {
+                let (ciphersuite, private_key) = hpke_key
+                    .rsplit_once(':')
+                    .context("HPKE packet decryption key is missing a ciphersuite")?;
+                let ciphersuite = Ciphersuite::from_str(ciphersuite)?;
+                let private_key = base64::decode(private_key)
+                    .context("could not decode HPKE packet decryption key")?;
+                Ok(PacketDecryptionKey::Hpke(HpkePrivateKey::new(
+                    ciphersuite,
+                    &private_key,
+                )?))
+            }
+            None => Ok(PacketDecryptionKey::Ecies(
+                PrivateKey::from_base64(s).map_err(|e| {
+                    anyhow!("could not parse encoded packet encryption key: {:?}", e)
+                })?,
+            )),
+        }
+    }
+}
+
+/// Encrypts a packet's share with HPKE, returning the payload to put in the
+/// packet, which is the encapsulated key followed by the ciphertext.
+pub fn seal_packet_payload(
+    ciphersuite: Ciphersuite,
+    recipient_public_key: &[u8],
+    packet: &IngestionDataSharePacket,
+    share: &[u8],
+) -> Result<Vec<u8>> {
+    let (mut payload, ciphertext) = hpke::seal(
+        ciphersuite,
+        recipient_public_key,
+        PACKET_PAYLOAD_INFO,
+        packet.uuid.as_bytes(),
+        share,
+    )?;
+    payload.extend_from_slice(&ciphertext);
+    Ok(payload)
+}
+
+/// Generates an ECIES key with which shares are encrypted again after being
+/// decrypted with HPKE, since PrioCrypto implementations only accept shares
+/// encrypted with libprio-rs' ECIES scheme. The key never leaves the process.
+pub(crate) fn generate_share_key() -> Result<PrivateKey> {
+    PrivateKey::from_base64(&generate_packet_encryption_key("share-key")?.private_key)
+        .map_err(|e| anyhow!("failed to generate share encryption key: {:?}", e))
+}
+
+/// HpkePacketDecryptor decrypts the payloads of ingestion packets whose
+/// encryption_ciphersuite is set. The decrypted shares are encrypted again for
+/// the server returned by server(), which must be used to process them.
+/// Packets without an encryption_ciphersuite are encrypted with ECIES and are
+/// processed with servers constructed from the ECIES packet decryption keys as
+/// before, so both kinds of packets may appear in the same batch.
+#[derive(Debug)]
+pub struct HpkePacketDecryptor {
+    keys: Vec<HpkePrivateKey>,
+    share_key: PrivateKey,
+    share_public_key: PublicKey,
+}
+
+impl HpkePacketDecryptor {
+    pub fn new(keys: &[HpkePrivateKey]) -> Result<Self> {
+        let share_key = generate_share_key()?;
+        Ok(HpkePacketDecryptor {
+            keys: keys.to_vec(),
+            share_public_key: PublicKey::from(&share_key),
+            share_key,
+        })
+    }
+
+    /// Returns a server implemented by the backend for the shares returned by
+    /// decrypt.
+    pub fn server<F: FieldElement + Send + 'static>(
+        &self,
+        backend: PrioBackend,
+        dimension: usize,
+        is_first: bool,
+    ) -> Box<dyn PrioCrypto<F>> {
+        backend.server(dimension, is_first, self.share_key.clone())
+    }
+
+    /// If the packet's payload is encrypted with HPKE, decrypts it with the
+    /// first of the keys for its ciphersuite that works, and returns the share
+    /// encrypted for the server returned by server(). Returns None if the
+    /// payload is encrypted with ECIES.
+    pub fn decrypt(&self, packet: &IngestionDataSharePacket) -> Result<Option<Vec<u8>>> {
+        let ciphersuite = match &packet.encryption_ciphersuite {
+            Some(ciphersuite) => Ciphersuite::from_str(ciphersuite)
+                .with_context(|| format!("invalid ciphersuite in packet {}", packet.uuid))?,
+            None => return Ok(None),
+        };
+        let encapsulated_key_length = ciphersuite.kem.public_key_length();
+        if packet.encrypted_payload.len() < encapsulated_key_length {
+            return Err(
+                anyhow::Error::new(CryptoError::PacketDecryption(packet.uuid.to_string()))
+                    .context("HPKE payload is shorter than its encapsulated key"),
+            );
+        }
+        let (encapsulated_key, ciphertext) =
+            packet.encrypted_payload.split_at(encapsulated_key_length);
+
+        let share = self
+            .keys
+            .iter()
+            .filter(|key| key.ciphersuite() == ciphersuite)
+            .find_map(|key| {
+                key.open(
+                    encapsulated_key,
+                    PACKET_PAYLOAD_INFO,
+                    packet.uuid.as_bytes(),
+                    ciphertext,
+                )
+                .ok()
+            })
+            .ok_or_else(|| {
+                anyhow::Error::new(CryptoError::PacketDecryption(packet.uuid.to_string()))
+                    .context(format!("no HPKE key for {} decrypts packet", ciphersuite))
+            })?;
+
+        encrypt_share(&share, &self.share_public_key)
+            .map(Some)
+            .map_err(|e| anyhow!("failed to encrypt decrypted share: {:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prio::encrypt::decrypt_share;
+    use uuid::Uuid;
+
+    fn packet(encryption_ciphersuite: Option<Ciphersuite>) -> IngestionDataSharePacket {
+        IngestionDataSharePacket {
+            uuid: Uuid::new_v4(),
+            encrypted_payload: vec![],
+            encryption_key_id: None,
+            r_pit: 1,
+            version_configuration: None,
+            device_nonce: None,
+            encryption_ciphersuite: encryption_ciphersuite.map(|c| c.to_string()),
+        }
+    }
+
+    #[test]
+    fn decrypt_hpke_packets() {
+        let x25519 = Ciphersuite::from_str("x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm").unwrap();
+        let p256 = Ciphersuite::from_str("p256-hkdf-sha256/hkdf-sha256/chacha20-poly1305").unwrap();
+        let keys = vec![
+            HpkePrivateKey::generate(x25519).unwrap(),
+            HpkePrivateKey::generate(x25519).unwrap(),
+            HpkePrivateKey::generate(p256).unwrap(),
+        ];
+        let decryptor = HpkePacketDecryptor::new(&keys).unwrap();
+
+        for (ciphersuite, key) in &[(x25519, &keys[1]), (p256, &keys[2])] {
+            let mut packet = packet(Some(*ciphersuite));
+            packet.encrypted_payload =
+                seal_packet_payload(*ciphersuite, key.public_key(), &packet, b"share").unwrap();
+            let share = decryptor.decrypt(&packet).unwrap().unwrap();
+            assert_eq!(
+                decrypt_share(&share, &decryptor.share_key).unwrap(),
+                b"share"
+            );
+
+            // The payload is bound to the packet's UUID.
+            packet.uuid = Uuid::new_v4();
+            let error = decryptor.decrypt(&packet).unwrap_err();
+            assert!(error.downcast_ref::<CryptoError>().is_some(), "{:?}", error);
+        }
+
+        // ECIES packets are left alone.
+        assert_eq!(decryptor.decrypt(&packet(None)).unwrap(), None);
+
+        // There is no key for this ciphersuite.
+        let aes256 = Ciphersuite::from_str("x25519-hkdf-sha256/hkdf-sha256/aes-256-gcm").unwrap();
+        let other_key = HpkePrivateKey::generate(aes256).unwrap();
+        let mut packet = packet(Some(aes256));
+        packet.encrypted_payload =
+            seal_packet_payload(aes256, other_key.public_key(), &packet, b"share").unwrap();
+        decryptor.decrypt(&packet).unwrap_err();
+
+        packet.encrypted_payload.truncate(10);
+        decryptor.decrypt(&packet).unwrap_err();
+        packet.encryption_ciphersuite = Some("x448".to_owned());
+        decryptor.decrypt(&packet).unwrap_err();
+    }
+
+    #[test]
+    fn parse_packet_decryption_keys() {
+        let ecies_key = generate_packet_encryption_key("test").unwrap().private_key;
+        assert!(matches!(
+            PacketDecryptionKey::from_str(&ecies_key).unwrap(),
+            PacketDecryptionKey::Ecies(_)
+        ));
+
+        let ciphersuite =
+            Ciphersuite::from_str("x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm").unwrap();
+        let private_key = [3u8; 32];
+        let hpke_key = format!("hpke:{}:{}", ciphersuite, base64::encode(private_key));
+        match PacketDecryptionKey::from_str(&hpke_key).unwrap() {
+            PacketDecryptionKey::Hpke(key) => {
+                assert_eq!(key.ciphersuite(), ciphersuite);
+                assert_eq!(
+                    key.public_key(),
+                    HpkePrivateKey::new(ciphersuite, &private_key)
+                        .unwrap()
+                        .public_key()
+                );
+            }
+            key => panic!("unexpected key {:?}", key),
+        }
+
+        PacketDecryptionKey::from_str("not-a-key").unwrap_err();
+        PacketDecryptionKey::from_str("hpke:AAAA").unwrap_err();
+        PacketDecryptionKey::from_str(&format!("hpke:{}:AAAA", ciphersuite)).unwrap_err();
+        PacketDecryptionKey::from_str("hpke:x448/hkdf-sha256/aes-128-gcm:AAAA").unwrap_err();
+    }
+}
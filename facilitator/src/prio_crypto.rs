@@ -0,0 +1,533 @@
+use aes_ctr::{
+    stream_cipher::{generic_array::GenericArray, NewStreamCipher, SyncStreamCipher},
+    Aes128Ctr,
+};
+use anyhow::{anyhow, Result};
+use prio::{
+    encrypt::{decrypt_share, PrivateKey},
+    field::{FieldElement, FieldError},
+    server::{Server, ServerError, VerificationMessage},
+    util::{proof_length, SerializeError},
+};
+use std::{convert::TryFrom, str::FromStr};
+
+/// PrioCrypto is the cryptography a facilitator performs on Prio shares: it
+/// generates the verification message for a share of a proof during intake,
+/// and during aggregation it decides whether a share is valid given both
+/// servers' verification messages and sums the valid ones.
+pub trait PrioCrypto<F: FieldElement>: Send {
+    /// Decrypts the share and generates its verification message, with the
+    /// polynomials evaluated at `eval_at`. A share that can't be decrypted
+    /// with this server's key fails with ServerError::Encrypt.
+    fn generate_verification_message(
+        &mut self,
+        eval_at: F,
+        share: &[u8],
+    ) -> Result<VerificationMessage<F>, ServerError>;
+
+    /// Decrypts the share and adds it to the total if the verification
+    /// messages show that it is valid, returning whether it is valid.
+    fn aggregate(
+        &mut self,
+        share: &[u8],
+        v1: &VerificationMessage<F>,
+        v2: &VerificationMessage<F>,
+    ) -> Result<bool, ServerError>;
+
+    /// Returns the sum of the valid shares aggregated so far.
+    fn total_shares(&self) -> &[F];
+
+    /// Adds the provided shares to the total.
+    fn merge_total_shares(&mut self, other_total_shares: &[F]) -> Result<(), ServerError>;
+}
+
+impl<F: FieldElement + Send> PrioCrypto<F> for Server<F> {
+    fn generate_verification_message(
+        &mut self,
+        eval_at: F,
+        share: &[u8],
+    ) -> Result<VerificationMessage<F>, ServerError> {
+        Server::generate_verification_message(self, eval_at, share)
+    }
+
+    fn aggregate(
+        &mut self,
+        share: &[u8],
+        v1: &VerificationMessage<F>,
+        v2: &VerificationMessage<F>,
+    ) -> Result<bool, ServerError> {
+        Server::aggregate(self, share, v1, v2)
+    }
+
+    fn total_shares(&self) -> &[F] {
+        Server::total_shares(self)
+    }
+
+    fn merge_total_shares(&mut self, other_total_shares: &[F]) -> Result<(), ServerError> {
+        Server::merge_total_shares(self, other_total_shares)
+    }
+}
+
+/// The implementations of PrioCrypto that a facilitator can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrioBackend {
+    /// prio::server::Server from libprio-rs, which interpolates the proof's
+    /// polynomials with an FFT
+    LibprioRs,
+    /// ReferenceServer, a straightforward implementation of the same protocol
+    /// that evaluates the proof's polynomials with Lagrange interpolation. It
+    /// is slower, and exists to check the results of libprio-rs.
+    Reference,
+}
+
+impl PrioBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PrioBackend::LibprioRs => "libprio-rs",
+            PrioBackend::Reference => "reference",
+        }
+    }
+
+    /// Returns a server for vectors of `dimension` elements that decrypts
+    /// shares with `private_key`. Exactly one of the two servers verifying
+    /// each share must be the first.
+    pub fn server<F: FieldElement + Send + 'static>(
+        &self,
+        dimension: usize,
+        is_first: bool,
+        private_key: PrivateKey,
+    ) -> Box<dyn PrioCrypto<F>> {
+        match self {
+            PrioBackend::LibprioRs => Box::new(Server::new(dimension, is_first, private_key)),
+            PrioBackend::Reference => {
+                Box::new(ReferenceServer::new(dimension, is_first, private_key))
+            }
+        }
+    }
+}
+
+impl Default for PrioBackend {
+    fn default() -> Self {
+        PrioBackend::LibprioRs
+    }
+}
+
+impl FromStr for PrioBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "libprio-rs" => Ok(PrioBackend::LibprioRs),
+            "reference" => Ok(PrioBackend::Reference),
+            _ => Err(anyhow!("unknown Prio backend {}", s)),
+        }
+    }
+}
+
+/// Length of the seed from which the second server's share is expanded: an
+/// AES-128 key followed by the initial counter block.
+const SEED_LENGTH: usize = 32;
+
+/// ReferenceServer implements the Prio proof validation performed by
+/// libprio-rs without sharing its code, other than for decryption and field
+/// arithmetic.
+///
+/// A client proves that each element x_i of its vector of n elements is 0 or 1
+/// by constructing polynomials f and g with f(w^(i+1)) = x_i and
+/// g(w^(i+1)) = x_i - 1, where w is a principal N-th root of unity with
+/// N = (n + 1).next_power_of_two(), and h = f * g, which is zero at those
+/// points. The proof consists of the data, f(1), g(1), h(1), and h evaluated
+/// at the odd powers of a principal 2N-th root of unity, all secret shared
+/// between the two servers. Each server evaluates its shares of f, g and h at
+/// a random point r, and the share is valid if f(r) * g(r) = h(r).
+#[derive(Debug)]
+pub struct ReferenceServer<F: FieldElement> {
+    dimension: usize,
+    is_first: bool,
+    private_key: PrivateKey,
+    accumulator: Vec<F>,
+    // Powers of a principal N-th and 2N-th root of unity, where
+    // N = (dimension + 1).next_power_of_two()
+    roots_n: Vec<F>,
+    roots_2n: Vec<F>,
+}
+
+impl<F: FieldElement> ReferenceServer<F> {
+    pub fn new(dimension: usize, is_first: bool, private_key: PrivateKey) -> Self {
+        let n = (dimension + 1).next_power_of_two();
+        ReferenceServer {
+            dimension,
+            is_first,
+            private_key,
+            accumulator: vec![F::zero(); dimension],
+            roots_n: powers_of_root_of_unity(n),
+            roots_2n: powers_of_root_of_unity(2 * n),
+        }
+    }
+
+    /// Decrypts a share and decodes the proof in it. The first server's share
+    /// is the encoded proof, while the second server's is a seed from which
+    /// its share of the proof is generated.
+    fn decrypt_proof(&self, share: &[u8]) -> Result<Vec<F>, ServerError> {
+        let share = decrypt_share(share, &self.private_key)?;
+        let proof = if self.is_first {
+            if share.len() % F::BYTES != 0 {
+                return Err(SerializeError::IncompleteChunk.into());
+            }
+            share
+                .chunks_exact(F::BYTES)
+                .map(F::read_from)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(SerializeError::Field)?
+        } else {
+            expand_seed(&share, proof_length(self.dimension))?
+        };
+        if proof.len() != proof_length(self.dimension) {
+            return Err(SerializeError::UnpackInputSizeMismatch.into());
+        }
+        Ok(proof)
+    }
+}
+
+impl<F: FieldElement + Send> PrioCrypto<F> for ReferenceServer<F> {
+    fn generate_verification_message(
+        &mut self,
+        eval_at: F,
+        share: &[u8],
+    ) -> Result<VerificationMessage<F>, ServerError> {
+        let proof = self.decrypt_proof(share)?;
+        let (data, rest) = proof.split_at(self.dimension);
+        let (zero_terms, h_odd_points) = rest.split_at(3);
+        let n = self.roots_n.len();
+
+        let mut f_points = vec![F::zero(); n];
+        let mut g_points = vec![F::zero(); n];
+        f_points[0] = zero_terms[0];
+        g_points[0] = zero_terms[1];
+        for (i, x) in data.iter().enumerate() {
+            f_points[i + 1] = *x;
+            // g = f - 1 at the data points, so one of the servers subtracts 1
+            // from its share.
+            g_points[i + 1] = if self.is_first { *x - F::one() } else { *x };
+        }
+
+        // h is zero at the even powers of the 2N-th root of unity, which are
+        // the N-th roots of unity at which f or g are zero, other than 1.
+        let mut h_points = vec![F::zero(); 2 * n];
+        h_points[0] = zero_terms[2];
+        for (i, point) in h_odd_points.iter().enumerate() {
+            h_points[2 * i + 1] = *point;
+        }
+
+        Ok(VerificationMessage {
+            f_r: interpolate_at(&f_points, &self.roots_n, eval_at),
+            g_r: interpolate_at(&g_points, &self.roots_n, eval_at),
+            h_r: interpolate_at(&h_points, &self.roots_2n, eval_at),
+        })
+    }
+
+    fn aggregate(
+        &mut self,
+        share: &[u8],
+        v1: &VerificationMessage<F>,
+        v2: &VerificationMessage<F>,
+    ) -> Result<bool, ServerError> {
+        let proof = self.decrypt_proof(share)?;
+        let f_r = v1.f_r + v2.f_r;
+        let g_r = v1.g_r + v2.g_r;
+        let h_r = v1.h_r + v2.h_r;
+        let is_valid = f_r * g_r == h_r;
+        if is_valid {
+            self.merge_total_shares(&proof[..self.dimension])?;
+        }
+        Ok(is_valid)
+    }
+
+    fn total_shares(&self) -> &[F] {
+        &self.accumulator
+    }
+
+    fn merge_total_shares(&mut self, other_total_shares: &[F]) -> Result<(), ServerError> {
+        if other_total_shares.len() != self.accumulator.len() {
+            return Err(FieldError::InputSizeMismatch.into());
+        }
+        for (total, share) in self.accumulator.iter_mut().zip(other_total_shares) {
+            *total += *share;
+        }
+        Ok(())
+    }
+}
+
+/// Returns 1, w, w^2, ..., w^(count - 1), where w is the principal root of
+/// unity of order `count` in the subgroup generated by F::generator().
+fn powers_of_root_of_unity<F: FieldElement>(count: usize) -> Vec<F> {
+    let count_integer = F::Integer::try_from(count).unwrap();
+    let root = F::generator().pow(F::generator_order() / count_integer);
+    let mut powers = Vec::with_capacity(count);
+    let mut power = F::one();
+    for _ in 0..count {
+        powers.push(power);
+        power *= root;
+    }
+    powers
+}
+
+/// Evaluates at x the polynomial of degree less than N that takes the value
+/// `points[i]` at `roots[i]`, where `roots` are the powers of a principal N-th
+/// root of unity, using the barycentric form of the Lagrange polynomial:
+///
+///   p(x) = (x^N - 1) / N * sum_i points[i] * roots[i] / (x - roots[i])
+fn interpolate_at<F: FieldElement>(points: &[F], roots: &[F], x: F) -> F {
+    if let Some(i) = roots.iter().position(|root| *root == x) {
+        return points[i];
+    }
+
+    let n = F::Integer::try_from(roots.len()).unwrap();
+    let differences: Vec<F> = roots.iter().map(|root| x - *root).collect();
+    let sum = batch_invert(&differences)
+        .iter()
+        .zip(points.iter().zip(roots))
+        .fold(F::zero(), |sum, (inverse, (point, root))| {
+            sum + *point * *root * *inverse
+        });
+    (x.pow(n) - F::one()) * F::from(n).inv() * sum
+}
+
+/// Inverts all of the non-zero elements with a single field inversion, using
+/// Montgomery's trick.
+fn batch_invert<F: FieldElement>(elements: &[F]) -> Vec<F> {
+    let mut prefix_products = Vec::with_capacity(elements.len());
+    let mut product = F::one();
+    for element in elements {
+        prefix_products.push(product);
+        product *= *element;
+    }
+
+    let mut inverse = product.inv();
+    let mut inverses = vec![F::zero(); elements.len()];
+    for i in (0..elements.len()).rev() {
+        inverses[i] = inverse * prefix_products[i];
+        inverse *= elements[i];
+    }
+    inverses
+}
+
+/// Expands the seed into `length` field elements. The bytes of the AES-128-CTR
+/// keystream keyed by the seed are read as a sequence of field elements, and
+/// those not less than the modulus are rejected.
+fn expand_seed<F: FieldElement>(seed: &[u8], length: usize) -> Result<Vec<F>, ServerError> {
+    if seed.len() != SEED_LENGTH {
+        return Err(SerializeError::UnpackInputSizeMismatch.into());
+    }
+    let (key, nonce) = seed.split_at(SEED_LENGTH / 2);
+    let mut cipher = Aes128Ctr::new(
+        GenericArray::from_slice(key),
+        GenericArray::from_slice(nonce),
+    );
+
+    let mut elements = Vec::with_capacity(length);
+    let mut bytes = vec![0; F::BYTES];
+    while elements.len() < length {
+        bytes.iter_mut().for_each(|byte| *byte = 0);
+        cipher.apply_keystream(&mut bytes);
+        match F::read_from(&bytes) {
+            Ok(element) => elements.push(element),
+            Err(FieldError::FromBytesModulusOverflow) => continue,
+            Err(e) => return Err(SerializeError::Field(e).into()),
+        }
+    }
+    Ok(elements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY};
+    use assert_matches::assert_matches;
+    use prio::{
+        client::Client,
+        encrypt::PublicKey,
+        field::{Field32, Field64},
+    };
+
+    const BACKENDS: [PrioBackend; 2] = [PrioBackend::LibprioRs, PrioBackend::Reference];
+
+    struct Keys {
+        pha: PrivateKey,
+        facilitator: PrivateKey,
+    }
+
+    fn keys() -> Keys {
+        Keys {
+            pha: PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+            facilitator: PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+        }
+    }
+
+    /// Encodes shares of the provided vectors, which are invalid unless all of
+    /// their elements are 0 or 1.
+    fn encode_shares<F: FieldElement>(keys: &Keys, vectors: &[Vec<u64>]) -> Vec<(Vec<u8>, Vec<u8>)>
+    where
+        F::Integer: TryFrom<u64>,
+        <F::Integer as TryFrom<u64>>::Error: std::fmt::Debug,
+    {
+        let mut client = Client::new(
+            vectors[0].len(),
+            PublicKey::from(&keys.pha),
+            PublicKey::from(&keys.facilitator),
+        )
+        .unwrap();
+        vectors
+            .iter()
+            .map(|vector| {
+                let data: Vec<F> = vector
+                    .iter()
+                    .map(|x| F::from(F::Integer::try_from(*x).unwrap()))
+                    .collect();
+                client.encode_simple(&data).unwrap()
+            })
+            .collect()
+    }
+
+    /// Runs intake and aggregation of the shares with each backend, checking
+    /// that they produce the same verification messages, validity verdicts
+    /// and sums, and returns the verdicts and the sum.
+    fn cross_verify<F: FieldElement + Send + 'static>(
+        keys: &Keys,
+        dimension: usize,
+        shares: &[(Vec<u8>, Vec<u8>)],
+    ) -> (Vec<bool>, Vec<F>) {
+        let eval_ats: Vec<F> = (0..shares.len())
+            .map(|_| Server::<F>::new(dimension, true, keys.pha.clone()).choose_eval_at())
+            .collect();
+
+        let results: Vec<_> = BACKENDS
+            .iter()
+            .map(|backend| {
+                let mut pha = backend.server::<F>(dimension, true, keys.pha.clone());
+                let mut facilitator =
+                    backend.server::<F>(dimension, false, keys.facilitator.clone());
+                let mut messages = Vec::new();
+                let mut verdicts = Vec::new();
+                for ((pha_share, facilitator_share), eval_at) in shares.iter().zip(&eval_ats) {
+                    let v1 = pha
+                        .generate_verification_message(*eval_at, pha_share)
+                        .unwrap();
+                    let v2 = facilitator
+                        .generate_verification_message(*eval_at, facilitator_share)
+                        .unwrap();
+                    let pha_valid = pha.aggregate(pha_share, &v1, &v2).unwrap();
+                    let facilitator_valid =
+                        facilitator.aggregate(facilitator_share, &v1, &v2).unwrap();
+                    assert_eq!(pha_valid, facilitator_valid);
+                    verdicts.push(pha_valid);
+                    messages.push([v1.f_r, v1.g_r, v1.h_r, v2.f_r, v2.g_r, v2.h_r]);
+                }
+                let sum: Vec<F> = pha
+                    .total_shares()
+                    .iter()
+                    .zip(facilitator.total_shares())
+                    .map(|(a, b)| *a + *b)
+                    .collect();
+                (messages, verdicts, sum)
+            })
+            .collect();
+
+        assert_eq!(results[0].0, results[1].0, "verification messages differ");
+        assert_eq!(results[0].1, results[1].1, "validity verdicts differ");
+        assert_eq!(results[0].2, results[1].2, "sums differ");
+        let (_, verdicts, sum) = results.into_iter().next().unwrap();
+        (verdicts, sum)
+    }
+
+    #[test]
+    fn backends_agree_field32() {
+        let keys = keys();
+        let vectors = vec![
+            vec![1, 0, 1, 1, 0, 0, 1],
+            vec![0, 0, 0, 0, 0, 0, 0],
+            // Not a vector of zeroes and ones
+            vec![2, 0, 1, 1, 0, 0, 1],
+            vec![1, 1, 1, 1, 1, 1, 1],
+        ];
+        let shares = encode_shares::<Field32>(&keys, &vectors);
+        let (verdicts, sum) = cross_verify::<Field32>(&keys, 7, &shares);
+        assert_eq!(verdicts, vec![true, true, false, true]);
+        let expected: Vec<Field32> = [2u32, 1, 2, 2, 1, 1, 2]
+            .iter()
+            .map(|x| Field32::from(*x))
+            .collect();
+        assert_eq!(sum, expected);
+    }
+
+    #[test]
+    fn backends_agree_field64() {
+        let keys = keys();
+        // Dimensions one less than a power of two fill the proof's domain
+        for dimension in &[1, 3, 4, 15, 100] {
+            let vectors: Vec<Vec<u64>> = (0..4)
+                .map(|i| (0..*dimension).map(|j| ((i + j) % 2) as u64).collect())
+                .chain(std::iter::once(vec![5; *dimension]))
+                .collect();
+            let shares = encode_shares::<Field64>(&keys, &vectors);
+            let (verdicts, _) = cross_verify::<Field64>(&keys, *dimension, &shares);
+            assert_eq!(verdicts, vec![true, true, true, true, false]);
+        }
+    }
+
+    #[test]
+    fn backends_reject_undecryptable_shares() {
+        let keys = keys();
+        let shares = encode_shares::<Field32>(&keys, &[vec![1, 0, 1]]);
+        for backend in &BACKENDS {
+            // Shares encrypted to the other server's key
+            let mut pha = backend.server::<Field32>(3, true, keys.pha.clone());
+            assert_matches!(
+                pha.generate_verification_message(Field32::from(12313), &shares[0].1)
+                    .map(|_| ()),
+                Err(ServerError::Encrypt(_))
+            );
+            // Shares for vectors of a different dimension
+            let mut pha = backend.server::<Field32>(4, true, keys.pha.clone());
+            assert_matches!(
+                pha.generate_verification_message(Field32::from(12313), &shares[0].0)
+                    .map(|_| ()),
+                Err(ServerError::Serialize(_))
+            );
+        }
+    }
+
+    #[test]
+    fn merge_total_shares() {
+        for backend in &BACKENDS {
+            let mut server = backend.server::<Field32>(3, true, keys().pha);
+            let shares: Vec<Field32> = vec![1.into(), 2.into(), 3.into()];
+            server.merge_total_shares(&shares).unwrap();
+            server.merge_total_shares(&shares).unwrap();
+            assert_eq!(
+                server.total_shares(),
+                &[Field32::from(2), Field32::from(4), Field32::from(6)]
+            );
+            server.merge_total_shares(&shares[..2]).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn interpolation() {
+        // p(x) = 3x^2 + 2x + 1
+        let roots = powers_of_root_of_unity::<Field64>(4);
+        let p = |x: Field64| Field64::from(3) * x * x + Field64::from(2) * x + Field64::one();
+        let points: Vec<Field64> = roots.iter().map(|root| p(*root)).collect();
+        for x in &[Field64::from(0), Field64::from(7), roots[2]] {
+            assert_eq!(interpolate_at(&points, &roots, *x), p(*x));
+        }
+    }
+
+    #[test]
+    fn backend_from_str() {
+        for backend in &BACKENDS {
+            assert_eq!(PrioBackend::from_str(backend.as_str()).unwrap(), *backend);
+        }
+        PrioBackend::from_str("libprio").unwrap_err();
+    }
+}
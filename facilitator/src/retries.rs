@@ -1,6 +1,42 @@
-use backoff::{retry, ExponentialBackoff};
+use backoff::{backoff::Backoff, retry, ExponentialBackoff};
 use slog::{debug, warn, Logger};
-use std::{fmt::Debug, time::Duration};
+use std::{fmt::Debug, future::Future, time::Duration};
+
+/// Parameters controlling how failed requests are retried with exponential
+/// backoff. Intervals between retries are randomized to avoid many clients
+/// retrying in lockstep.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryParameters {
+    /// Interval to wait after the first failure.
+    pub initial_interval: Duration,
+    /// Upper bound on the interval between retries.
+    pub max_interval: Duration,
+    /// Retries stop once this much time has elapsed since the first attempt.
+    pub max_elapsed_time: Duration,
+    /// If set, no more than this many attempts (including the first one) are
+    /// made, even if max_elapsed_time has not elapsed yet.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for RetryParameters {
+    fn default() -> Self {
+        // Default ExponentialBackoff parameters are borrowed from the parameters
+        // used in the GCP Go SDK[1]. AWS doesn't give us specific guidance on what
+        // intervals to use, but the GCP implementation cites AWS blog posts so the
+        // same parameters are probably fine for both.
+        // [1] https://github.com/googleapis/gax-go/blob/fbaf9882acf3297573f3a7cb832e54c7d8f40635/v2/call_option.go#L120
+        RetryParameters {
+            initial_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(30),
+            // We don't have explicit guidance from Google on how long to retry
+            // before giving up but the Google Cloud Storage guide for retries
+            // suggests 600 seconds.
+            // https://cloud.google.com/storage/docs/retry-strategy#exponential-backoff
+            max_elapsed_time: Duration::from_secs(600),
+            max_attempts: None,
+        }
+    }
+}
 
 /// Executes the provided action `f`, retrying with exponential backoff if the
 /// error returned by `f` is deemed retryable by `is_retryable`. On success,
@@ -13,32 +49,14 @@ where
     R: FnMut(&E) -> bool,
     E: Debug,
 {
-    // Default ExponentialBackoff parameters are borrowed from the parameters
-    // used in the GCP Go SDK[1]. AWS doesn't give us specific guidance on what
-    // intervals to use, but the GCP implementation cites AWS blog posts so the
-    // same parameters are probably fine for both.
-    // [1] https://github.com/googleapis/gax-go/blob/fbaf9882acf3297573f3a7cb832e54c7d8f40635/v2/call_option.go#L120
-    retry_request_with_params(
-        logger,
-        Duration::from_secs(1),
-        Duration::from_secs(30),
-        // We don't have explicit guidance from Google on how long to retry
-        // before giving up but the Google Cloud Storage guide for retries
-        // suggests 600 seconds.
-        // https://cloud.google.com/storage/docs/retry-strategy#exponential-backoff
-        Duration::from_secs(600),
-        f,
-        is_retryable,
-    )
+    retry_request_with_parameters(logger, &RetryParameters::default(), f, is_retryable)
 }
 
-/// Private version of retry_request that exposes parameters for backoff. Should
-/// only be used for testing. Othewise behaves identically to `retry_request`.
-fn retry_request_with_params<F, T, E, R>(
+/// Version of retry_request that retries according to the provided parameters
+/// rather than the defaults. Othewise behaves identically to `retry_request`.
+pub(crate) fn retry_request_with_parameters<F, T, E, R>(
     logger: &Logger,
-    backoff_initial_interval: Duration,
-    backoff_max_interval: Duration,
-    backoff_max_elapsed: Duration,
+    parameters: &RetryParameters,
     mut f: F,
     mut is_retryable: R,
 ) -> Result<T, E>
@@ -47,25 +65,14 @@ where
     R: FnMut(&E) -> bool,
     E: Debug,
 {
-    let backoff = ExponentialBackoff {
-        initial_interval: backoff_initial_interval,
-        max_interval: backoff_max_interval,
-        multiplier: 2.0,
-        max_elapsed_time: Some(backoff_max_elapsed),
-        ..Default::default()
-    };
-
-    retry(backoff, || {
+    let mut attempts = 0;
+    retry(parameters.backoff(), || {
+        attempts += 1;
         // Invoke the function and wrap its E into backoff::Error
         f().map_err(|error| {
-            if is_retryable(&error) {
-                warn!(
-                    logger, "encountered retryable error";
-                    "error" => format!("{:?}", error),
-                );
+            if should_retry(logger, parameters, attempts, &error, is_retryable(&error)) {
                 backoff::Error::Transient(error)
             } else {
-                debug!(logger, "encountered non-retryable error");
                 backoff::Error::Permanent(error)
             }
         })
@@ -78,6 +85,123 @@ where
     })
 }
 
+/// Async version of retry_request, which awaits the futures returned by `f`
+/// and waits between attempts without blocking the thread. Otherwise behaves
+/// identically to `retry_request`.
+pub(crate) async fn retry_request_async<F, Fut, T, E, R>(
+    logger: &Logger,
+    mut f: F,
+    mut is_retryable: R,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    R: FnMut(&E) -> bool,
+    E: Debug,
+{
+    let parameters = RetryParameters::default();
+    let mut retrier = Retrier::new(logger, &parameters);
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(error) => {
+                let retryable = is_retryable(&error);
+                if !retrier.retry(&error, retryable).await {
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+/// Retrier tracks the attempts at a request made from async code, for callers
+/// whose attempts need mutable access to state that a closure passed to
+/// retry_request_async could not lend to the futures it returns. After each
+/// failed attempt, the caller asks the Retrier whether to make another.
+pub(crate) struct Retrier<'a> {
+    logger: &'a Logger,
+    parameters: &'a RetryParameters,
+    backoff: ExponentialBackoff,
+    attempts: u32,
+}
+
+impl<'a> Retrier<'a> {
+    pub(crate) fn new(logger: &'a Logger, parameters: &'a RetryParameters) -> Self {
+        Retrier {
+            logger,
+            parameters,
+            backoff: parameters.backoff(),
+            attempts: 0,
+        }
+    }
+
+    /// Decides whether to make another attempt after one failed with `error`,
+    /// which `retryable` says whether is retryable, logging the failure like
+    /// retry_request does. If another attempt should be made, returns true once
+    /// the backoff interval has elapsed.
+    pub(crate) async fn retry<E: Debug>(&mut self, error: &E, retryable: bool) -> bool {
+        self.attempts += 1;
+        if !should_retry(
+            self.logger,
+            self.parameters,
+            self.attempts,
+            error,
+            retryable,
+        ) {
+            return false;
+        }
+        match self.backoff.next_backoff() {
+            Some(interval) => {
+                tokio::time::sleep(interval).await;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl RetryParameters {
+    fn backoff(&self) -> ExponentialBackoff {
+        let mut backoff = ExponentialBackoff {
+            initial_interval: self.initial_interval,
+            max_interval: self.max_interval,
+            multiplier: 2.0,
+            max_elapsed_time: Some(self.max_elapsed_time),
+            ..Default::default()
+        };
+        backoff.reset();
+        backoff
+    }
+}
+
+/// Returns true if another attempt should be made after attempt number
+/// `attempts` failed with `error`, logging the failure.
+fn should_retry<E: Debug>(
+    logger: &Logger,
+    parameters: &RetryParameters,
+    attempts: u32,
+    error: &E,
+    retryable: bool,
+) -> bool {
+    if !retryable {
+        debug!(logger, "encountered non-retryable error");
+        false
+    } else if parameters.max_attempts.map_or(false, |max| attempts >= max) {
+        warn!(
+            logger, "encountered retryable error but attempts are exhausted";
+            "error" => format!("{:?}", error),
+            "attempts" => attempts,
+        );
+        false
+    } else {
+        warn!(
+            logger, "encountered retryable error";
+            "error" => format!("{:?}", error),
+        );
+        true
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -92,11 +216,14 @@ mod tests {
             Ok(())
         };
 
-        retry_request_with_params(
+        retry_request_with_parameters(
             &logger,
-            Duration::from_millis(10),
-            Duration::from_millis(10),
-            Duration::from_millis(10),
+            &RetryParameters {
+                initial_interval: Duration::from_millis(10),
+                max_interval: Duration::from_millis(10),
+                max_elapsed_time: Duration::from_millis(10),
+                max_attempts: None,
+            },
             f,
             |_| false,
         )
@@ -117,11 +244,14 @@ mod tests {
             }
         };
 
-        retry_request_with_params(
+        retry_request_with_parameters(
             &logger,
-            Duration::from_millis(10),
-            Duration::from_millis(10),
-            Duration::from_millis(30),
+            &RetryParameters {
+                initial_interval: Duration::from_millis(10),
+                max_interval: Duration::from_millis(10),
+                max_elapsed_time: Duration::from_millis(30),
+                max_attempts: None,
+            },
             f,
             |_| true,
         )
@@ -138,11 +268,14 @@ mod tests {
             Err(false)
         };
 
-        retry_request_with_params(
+        retry_request_with_parameters(
             &logger,
-            Duration::from_millis(10),
-            Duration::from_millis(10),
-            Duration::from_millis(30),
+            &RetryParameters {
+                initial_interval: Duration::from_millis(10),
+                max_interval: Duration::from_millis(10),
+                max_elapsed_time: Duration::from_millis(30),
+                max_attempts: None,
+            },
             f,
             |_| true,
         )
@@ -159,15 +292,62 @@ mod tests {
             Err(false)
         };
 
-        retry_request_with_params(
+        retry_request_with_parameters(
             &logger,
-            Duration::from_millis(10),
-            Duration::from_millis(10),
-            Duration::from_millis(30),
+            &RetryParameters {
+                initial_interval: Duration::from_millis(10),
+                max_interval: Duration::from_millis(10),
+                max_elapsed_time: Duration::from_millis(30),
+                max_attempts: None,
+            },
             f,
             |_| false,
         )
         .unwrap_err();
         assert_eq!(counter, 1);
     }
+
+    #[test]
+    fn retryable_failure_exhaust_max_attempts() {
+        let logger = setup_test_logging();
+        let mut counter = 0;
+        let f = || -> std::result::Result<(), bool> {
+            counter += 1;
+            Err(false)
+        };
+
+        retry_request_with_parameters(
+            &logger,
+            &RetryParameters {
+                initial_interval: Duration::from_millis(1),
+                max_interval: Duration::from_millis(1),
+                max_elapsed_time: Duration::from_secs(60),
+                max_attempts: Some(3),
+            },
+            f,
+            |_| true,
+        )
+        .unwrap_err();
+        assert_eq!(counter, 3);
+    }
+
+    #[tokio::test]
+    async fn retrier() {
+        let logger = setup_test_logging();
+        let parameters = RetryParameters {
+            initial_interval: Duration::from_millis(1),
+            max_interval: Duration::from_millis(1),
+            max_elapsed_time: Duration::from_secs(60),
+            max_attempts: Some(3),
+        };
+
+        let mut retrier = Retrier::new(&logger, &parameters);
+        assert!(!retrier.retry(&"fake failure", false).await);
+
+        let mut retrier = Retrier::new(&logger, &parameters);
+        assert!(retrier.retry(&"fake failure", true).await);
+        assert!(retrier.retry(&"fake failure", true).await);
+        // The third attempt is the last one
+        assert!(!retrier.retry(&"fake failure", true).await);
+    }
 }
@@ -0,0 +1,375 @@
+use crate::{transport::TransportWriter, Error};
+use anyhow::{anyhow, Context, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use once_cell::sync::OnceCell;
+use std::{
+    future::Future,
+    io::{self, Read, Write},
+    mem,
+};
+use tokio::{
+    runtime::{Builder, Runtime},
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+/// Size of the chunks in which PrefetchingReader reads ahead and in which
+/// BackgroundWriter hands content to its worker.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Number of chunks PrefetchingReader reads ahead of its caller, or that
+/// BackgroundWriter may queue before writes block, bounding the memory each
+/// uses for content to (CHUNK_DEPTH + 1) * CHUNK_SIZE.
+const CHUNK_DEPTH: usize = 8;
+
+/// Returns the Tokio runtime shared by all transports. The runtime is created
+/// the first time this is called, so that we don't spin up a new runtime and
+/// its worker threads for every request. RetryingAgent's HTTP requests and
+/// S3Transport's Rusoto requests are made on it, PrefetchingReader downloads
+/// object contents in tasks spawned on it and BackgroundWriter runs uploads on
+/// its blocking thread pool, so that transfers overlap with whatever the caller
+/// is doing in between reads and writes. The Transport trait and RetryingAgent
+/// present blocking facades over this, implemented with block_on.
+pub(crate) fn shared_runtime() -> Result<&'static Runtime> {
+    static RUNTIME: OnceCell<Runtime> = OnceCell::new();
+    RUNTIME
+        .get_or_try_init(|| {
+            Builder::new_multi_thread()
+                .thread_name("facilitator-io")
+                .enable_all()
+                .build()
+        })
+        .context("failed to create shared Tokio runtime")
+}
+
+/// Runs the provided future to completion on the shared runtime, blocking the
+/// calling thread until it is done. The blocking facades over async requests
+/// and transfers are implemented with this. It may be called from a thread
+/// that is driving the shared runtime's tasks, as happens when a Rusoto
+/// credentials provider calls a blocking token provider, in which case the
+/// thread's other tasks are handed off to another thread while it blocks.
+pub(crate) fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    let runtime = shared_runtime()?;
+    Ok(task::block_in_place(|| runtime.block_on(future)))
+}
+
+/// PrefetchingReader is a blocking std::io::Read facade over a stream of
+/// content, such as the body of an HTTP response, that is polled in a task on
+/// the shared runtime. The content is gathered into chunks of CHUNK_SIZE, up to
+/// CHUNK_DEPTH of which are read ahead of the caller, so the download continues
+/// while the caller processes what it has already read. Errors from the stream
+/// are returned in order once the caller has consumed the content read before
+/// them. Dropping the PrefetchingReader stops the download after at most one
+/// more chunk.
+pub(crate) struct PrefetchingReader {
+    receiver: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl PrefetchingReader {
+    pub(crate) fn from_stream<S>(stream: S) -> Result<Self>
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(CHUNK_DEPTH);
+        shared_runtime()?.spawn(async move {
+            let mut stream = Box::pin(stream);
+            let mut chunk = BytesMut::with_capacity(CHUNK_SIZE);
+            loop {
+                let next = stream.next().await;
+                if let Some(Ok(content)) = &next {
+                    chunk.extend_from_slice(content);
+                    if chunk.len() < CHUNK_SIZE {
+                        continue;
+                    }
+                }
+                // Hand over whatever was read before the end of the stream or
+                // an error. If the PrefetchingReader was dropped, nobody wants
+                // the rest.
+                if !chunk.is_empty() && sender.send(Ok(chunk.split().freeze())).await.is_err() {
+                    return;
+                }
+                match next {
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        let _ = sender.send(Err(e)).await;
+                        return;
+                    }
+                    None => return,
+                }
+            }
+        });
+        Ok(PrefetchingReader {
+            receiver,
+            chunk: Bytes::new(),
+        })
+    }
+}
+
+impl Read for PrefetchingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let read = self.chunk.len().min(buf.len());
+        buf[..read].copy_from_slice(&self.chunk[..read]);
+        self.chunk.advance(read);
+        Ok(read)
+    }
+}
+
+/// BackgroundWriter is a TransportWriter facade over another TransportWriter
+/// that is written to on the shared runtime's blocking thread pool. Content is
+/// handed to the worker in chunks, so the upload of one chunk proceeds while
+/// the caller produces the next, and writes only block once CHUNK_DEPTH chunks
+/// are waiting to be written. A failure to write is reported by the next call
+/// to write or complete_upload, after the worker has canceled the underlying
+/// upload.
+pub(crate) struct BackgroundWriter {
+    // None once the upload has been completed or canceled
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+    worker: Option<JoinHandle<Result<Box<dyn TransportWriter + Send>>>>,
+    buffer: Vec<u8>,
+    runtime: &'static Runtime,
+}
+
+impl BackgroundWriter {
+    pub(crate) fn new<W: TransportWriter + Send + 'static>(writer: W) -> Result<Self> {
+        let runtime = shared_runtime()?;
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(CHUNK_DEPTH);
+        let worker = runtime.spawn_blocking(move || {
+            let mut writer: Box<dyn TransportWriter + Send> = Box::new(writer);
+            while let Some(chunk) = receiver.blocking_recv() {
+                if let Err(e) = writer.write_all(&chunk) {
+                    let error = anyhow!(e).context("failed to write to transport");
+                    if let Err(cancel) = writer.cancel_upload() {
+                        return Err(cancel.context(error));
+                    }
+                    return Err(error);
+                }
+            }
+            Ok(writer)
+        });
+        Ok(BackgroundWriter {
+            sender: Some(sender),
+            worker: Some(worker),
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            runtime,
+        })
+    }
+
+    /// Hands the buffered content to the worker, blocking if it is already
+    /// CHUNK_DEPTH chunks behind.
+    fn send_buffer(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        let sender = self
+            .sender
+            .as_ref()
+            .context("upload was already completed or canceled")?;
+        if sender.blocking_send(chunk).is_err() {
+            // The worker only stops receiving if it failed to write
+            self.sender = None;
+            return Err(self
+                .join_worker()
+                .err()
+                .unwrap_or_else(|| anyhow!("transport writer stopped unexpectedly")));
+        }
+        Ok(())
+    }
+
+    /// Waits for the worker to write everything it was handed, returning the
+    /// underlying writer.
+    fn join_worker(&mut self) -> Result<Box<dyn TransportWriter + Send>> {
+        let worker = self
+            .worker
+            .take()
+            .context("upload was already completed or canceled")?;
+        self.runtime
+            .block_on(worker)
+            .context("failed to join transport writer task")?
+    }
+}
+
+impl Write for BackgroundWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            let space = CHUNK_SIZE - self.buffer.len();
+            let (chunk, rest) = remaining.split_at(space.min(remaining.len()));
+            self.buffer.extend_from_slice(chunk);
+            remaining = rest;
+
+            if self.buffer.len() >= CHUNK_SIZE {
+                self.send_buffer()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, Error::AnyhowError(e)))?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TransportWriter for BackgroundWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        self.send_buffer()?;
+        // Dropping the sender lets the worker finish once it has written
+        // everything it was handed.
+        self.sender = None;
+        self.join_worker()?.complete_upload()
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.buffer.clear();
+        self.sender = None;
+        match self.join_worker() {
+            Ok(mut writer) => writer.cancel_upload(),
+            // The worker already canceled the upload when it failed
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        // Dropping the sender stops the worker, which then drops the
+        // underlying writer without completing its upload, so no object is
+        // written. The GCS and Azure writers don't clean up when dropped, so
+        // the resumable upload session or the uncommitted blocks are left for
+        // the storage service to expire.
+        self.sender = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct RecordingWriter {
+        content: Arc<Mutex<Vec<u8>>>,
+        completed: Arc<Mutex<bool>>,
+        canceled: Arc<Mutex<bool>>,
+        fail_after: Option<usize>,
+    }
+
+    impl Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut content = self.content.lock().unwrap();
+            if let Some(limit) = self.fail_after {
+                if content.len() + buf.len() > limit {
+                    return Err(io::Error::new(io::ErrorKind::Other, "fake failure"));
+                }
+            }
+            content.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TransportWriter for RecordingWriter {
+        fn complete_upload(&mut self) -> Result<()> {
+            *self.completed.lock().unwrap() = true;
+            Ok(())
+        }
+
+        fn cancel_upload(&mut self) -> Result<()> {
+            *self.canceled.lock().unwrap() = true;
+            Ok(())
+        }
+    }
+
+    fn content(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Returns a stream yielding the content in pieces of 1000 bytes, followed
+    /// by the error, if any.
+    fn content_stream(
+        content: &[u8],
+        error: Option<io::Error>,
+    ) -> impl Stream<Item = io::Result<Bytes>> {
+        let mut items: Vec<_> = content
+            .chunks(1000)
+            .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+            .collect();
+        items.extend(error.map(Err));
+        futures::stream::iter(items)
+    }
+
+    #[test]
+    fn prefetching_reader() {
+        let expected = content(CHUNK_SIZE * (CHUNK_DEPTH + 3) + 17);
+        let mut reader = PrefetchingReader::from_stream(content_stream(&expected, None)).unwrap();
+        let mut actual = Vec::new();
+        reader.read_to_end(&mut actual).unwrap();
+        assert_eq!(actual, expected);
+
+        let mut reader = PrefetchingReader::from_stream(content_stream(&[], None)).unwrap();
+        assert_eq!(reader.read(&mut [0; 10]).unwrap(), 0);
+    }
+
+    #[test]
+    fn prefetching_reader_error() {
+        let error = io::Error::new(io::ErrorKind::Other, "fake failure");
+        let mut reader =
+            PrefetchingReader::from_stream(content_stream(&[7; 2500], Some(error))).unwrap();
+        let mut actual = Vec::new();
+        reader.read_to_end(&mut actual).unwrap_err();
+        // Content read before the failure is still delivered
+        assert_eq!(actual, vec![7; 2500]);
+    }
+
+    #[test]
+    fn background_writer() {
+        let recording = RecordingWriter::default();
+        let expected = content(CHUNK_SIZE * (CHUNK_DEPTH + 3) + 17);
+        let mut writer = BackgroundWriter::new(recording.clone()).unwrap();
+        for piece in expected.chunks(10_000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.complete_upload().unwrap();
+        assert_eq!(*recording.content.lock().unwrap(), expected);
+        assert!(*recording.completed.lock().unwrap());
+        assert!(!*recording.canceled.lock().unwrap());
+
+        let recording = RecordingWriter::default();
+        let mut writer = BackgroundWriter::new(recording.clone()).unwrap();
+        writer.write_all(&content(100)).unwrap();
+        writer.cancel_upload().unwrap();
+        assert!(recording.content.lock().unwrap().is_empty());
+        assert!(!*recording.completed.lock().unwrap());
+        assert!(*recording.canceled.lock().unwrap());
+    }
+
+    #[test]
+    fn background_writer_error() {
+        let recording = RecordingWriter {
+            fail_after: Some(CHUNK_SIZE),
+            ..Default::default()
+        };
+        let mut writer = BackgroundWriter::new(recording.clone()).unwrap();
+        let content = content(CHUNK_SIZE * (CHUNK_DEPTH + 3));
+        // The failure surfaces either from a later write or from completing
+        let result = writer
+            .write_all(&content)
+            .map_err(anyhow::Error::new)
+            .and_then(|_| writer.complete_upload());
+        assert!(result.is_err());
+        assert!(!*recording.completed.lock().unwrap());
+        assert!(*recording.canceled.lock().unwrap());
+    }
+}
@@ -1,19 +1,25 @@
 use crate::{
     batch::{Batch, BatchWriter},
+    field::EncodedField,
+    hpke::Ciphersuite,
     idl::{IngestionDataSharePacket, IngestionHeader, Packet},
     logging::event,
+    packet_encryption::{generate_share_key, seal_packet_payload},
     transport::SignableTransport,
     DATE_FORMAT,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
+use avro_rs::{Schema, Writer};
 use chrono::NaiveDateTime;
 use prio::{
     client::Client,
-    encrypt::PublicKey,
+    encrypt::{decrypt_share, encrypt_share, PublicKey},
     field::{Field32, FieldElement},
+    util::{proof_length, serialize},
 };
 use rand::{thread_rng, Rng};
 use slog::{info, o, Logger};
+use std::str::FromStr;
 use uuid::Uuid;
 
 /// Configuration for output from sample generation.
@@ -40,21 +46,149 @@ impl SampleOutput {
     }
 }
 
+/// Faults that may be injected into generated ingestion batches, so that
+/// their rejection can be tested.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BatchFault {
+    /// The header signatures do not verify.
+    BadSignature,
+    /// The packet files are cut off partway through, though their digests
+    /// match those in the headers.
+    TruncatedPacketFile,
+    /// The packet file digests in the headers do not match the packet files.
+    DigestMismatch,
+    /// The packet files are written with a schema that lacks the r_pit field.
+    WrongSchema,
+    /// The PHA share of the first packet contains a field element that is not
+    /// less than the field's modulus. That packet is not included in the
+    /// reference sum.
+    OutOfRangeFieldElement,
+}
+
+impl BatchFault {
+    pub const ALL: [BatchFault; 5] = [
+        BatchFault::BadSignature,
+        BatchFault::TruncatedPacketFile,
+        BatchFault::DigestMismatch,
+        BatchFault::WrongSchema,
+        BatchFault::OutOfRangeFieldElement,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatchFault::BadSignature => "bad-signature",
+            BatchFault::TruncatedPacketFile => "truncated-packet-file",
+            BatchFault::DigestMismatch => "digest-mismatch",
+            BatchFault::WrongSchema => "wrong-schema",
+            BatchFault::OutOfRangeFieldElement => "out-of-range-field-element",
+        }
+    }
+}
+
+impl FromStr for BatchFault {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        BatchFault::ALL
+            .iter()
+            .find(|fault| fault.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("unknown batch fault {}", s))
+    }
+}
+
+/// The distribution from which the values of generated data packets are
+/// drawn.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueDistribution {
+    /// Each bin is independently one with probability one half
+    Uniform,
+    /// Exactly one bin is one, and the probability that it is the bin at index
+    /// i is proportional to 1 / (i + 1)^exponent
+    Zipf { exponent: f64 },
+    /// Each bin is independently one with the corresponding probability
+    Bernoulli { probabilities: Vec<f64> },
+}
+
+impl ValueDistribution {
+    /// Checks that this distribution can generate vectors of the provided
+    /// dimension.
+    fn check(&self, dimension: usize) -> Result<()> {
+        match self {
+            ValueDistribution::Uniform => {}
+            ValueDistribution::Zipf { exponent } => ensure!(
+                exponent.is_finite() && *exponent >= 0.0,
+                "zipf exponent must be a non-negative number"
+            ),
+            ValueDistribution::Bernoulli { probabilities } => {
+                ensure!(
+                    probabilities.len() == dimension,
+                    "{} bernoulli probabilities provided for dimension {}",
+                    probabilities.len(),
+                    dimension
+                );
+                ensure!(
+                    probabilities.iter().all(|p| (0.0..=1.0).contains(p)),
+                    "bernoulli probabilities must be between 0 and 1"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws a vector of zeroes and ones of the provided dimension.
+    fn sample<F: EncodedField, R: Rng + ?Sized>(&self, dimension: usize, rng: &mut R) -> Vec<F> {
+        match self {
+            ValueDistribution::Uniform => (0..dimension)
+                .map(|_| F::reduce(rng.gen_range(0..2)))
+                .collect(),
+            ValueDistribution::Zipf { exponent } => {
+                let weight = |index: usize| ((index + 1) as f64).powf(-exponent);
+                let mut target = rng.gen::<f64>() * (0..dimension).map(weight).sum::<f64>();
+                // Rounding may leave a little of the target after the last
+                // bin, in which case the last bin is set.
+                let set_bin = (0..dimension)
+                    .find(|index| {
+                        target -= weight(*index);
+                        target < 0.0
+                    })
+                    .unwrap_or(dimension - 1);
+                (0..dimension)
+                    .map(|index| F::reduce((index == set_bin) as u64))
+                    .collect()
+            }
+            ValueDistribution::Bernoulli { probabilities } => probabilities
+                .iter()
+                .map(|p| F::reduce(rng.gen_bool(*p) as u64))
+                .collect(),
+        }
+    }
+}
+
 /// The reference sum from a generated sample, along with metadata about the
 /// generated sample.
 #[derive(Debug)]
-pub struct ReferenceSum {
+pub struct ReferenceSum<F: FieldElement = Field32> {
     /// The reference sum, covering those packets whose shares appear in both
     /// PHA and facilitator ingestion batches.
-    pub sum: Vec<Field32>,
+    pub sum: Vec<F>,
     /// The number of contributions that went into the reference sum.
     pub contributions: usize,
     /// UUIDs of PHA packets that were dropped
     pub pha_dropped_packets: Vec<Uuid>,
     /// UUIDs of facilitator packets that were dropped
     pub facilitator_dropped_packets: Vec<Uuid>,
+    /// UUIDs of packets generated with invalid proofs, which are not included
+    /// in the reference sum
+    pub invalid_proof_packets: Vec<Uuid>,
+    /// UUIDs of packets that were written more than once
+    pub duplicate_packets: Vec<Uuid>,
 }
 
+/// Writes a packet to each of the PHA and facilitator packet files
+type WritePackets<'a> = dyn FnMut(Option<&IngestionDataSharePacket>, Option<&IngestionDataSharePacket>) -> Result<()>
+    + 'a;
+
 /// SampleGenerator constructs random data and splits it into two shares which
 /// may be processed by data share processors. It allows tampering with
 /// generated data to support various test cases.
@@ -82,6 +216,20 @@ pub struct SampleGenerator<'a> {
     /// deserialization and proof unpacking will fail. This is intended for
     /// testing.
     generate_short_packet: Option<usize>,
+    /// The distribution from which data packets are drawn
+    value_distribution: ValueDistribution,
+    /// Percentage of packets that are generated with data that is not a
+    /// vector of zeroes and ones, so that their proofs will not be valid
+    invalid_proof_percent: f64,
+    /// Percentage of packets that are written a second time, with the same
+    /// UUID and shares, in addition to the requested number of packets. This
+    /// mimics clients retrying uploads.
+    duplicate_packet_percent: f64,
+    /// If this is Some, the fault is injected into both ingestion batches
+    batch_fault: Option<BatchFault>,
+    /// If this is Some, the facilitator's shares are encrypted with HPKE in
+    /// the ciphersuite to the public key, rather than with ECIES
+    facilitator_hpke_public_key: Option<(Ciphersuite, Vec<u8>)>,
     /// Describes where the PHA/"first" server's shares should be written and
     /// how
     pha_output: &'a mut SampleOutput,
@@ -116,6 +264,11 @@ impl<'a> SampleGenerator<'a> {
             batch_start_time,
             batch_end_time,
             generate_short_packet: None,
+            value_distribution: ValueDistribution::Uniform,
+            invalid_proof_percent: 0.0,
+            duplicate_packet_percent: 0.0,
+            batch_fault: None,
+            facilitator_hpke_public_key: None,
             pha_output,
             facilitator_output,
             logger,
@@ -139,11 +292,41 @@ impl<'a> SampleGenerator<'a> {
         self.generate_short_packet = Some(count);
     }
 
+    /// Draw data packets from the provided distribution instead of the uniform
+    /// distribution.
+    pub fn set_value_distribution(&mut self, value_distribution: ValueDistribution) {
+        self.value_distribution = value_distribution;
+    }
+
+    /// Generate the provided percentage of packets with invalid proofs.
+    pub fn set_invalid_proof_percent(&mut self, percent: f64) {
+        self.invalid_proof_percent = percent;
+    }
+
+    /// Write the provided percentage of packets twice.
+    pub fn set_duplicate_packet_percent(&mut self, percent: f64) {
+        self.duplicate_packet_percent = percent;
+    }
+
+    /// Inject the provided fault into the generated ingestion batches. This is
+    /// intended for testing that malformed batches are rejected.
+    pub fn set_batch_fault(&mut self, fault: BatchFault) {
+        self.batch_fault = Some(fault);
+    }
+
+    /// Encrypt the facilitator's shares with HPKE in the provided ciphersuite
+    /// to the provided public key, instead of with ECIES to the facilitator
+    /// output's packet_encryption_public_key.
+    pub fn set_facilitator_hpke_public_key(&mut self, ciphersuite: Ciphersuite, public_key: &[u8]) {
+        self.facilitator_hpke_public_key = Some((ciphersuite, public_key.to_vec()));
+    }
+
     /// Generate random sample data, split it into shares, and transmit it to
     /// facilitator servers.
     ///
     /// The provided `batch_uuid` and `date` are used to construct filenames.
-    /// `packet_count` packets are generated.
+    /// `packet_count` distinct packets are generated, not counting
+    /// duplicates.
     /// The PHA/"first" server's shares are written to `pha_output`, and the
     /// facilitator/"second" server's shares are written to
     /// `facilitator_output`.
@@ -156,6 +339,18 @@ impl<'a> SampleGenerator<'a> {
         date: &NaiveDateTime,
         packet_count: usize,
     ) -> Result<ReferenceSum> {
+        self.generate_ingestion_sample_in_field(trace_id, batch_uuid, date, packet_count)
+    }
+
+    /// Like generate_ingestion_sample, but encodes the data in the field F
+    /// rather than Field32.
+    pub fn generate_ingestion_sample_in_field<F: EncodedField>(
+        &mut self,
+        trace_id: &str,
+        batch_uuid: &Uuid,
+        date: &NaiveDateTime,
+        packet_count: usize,
+    ) -> Result<ReferenceSum<F>> {
         let local_logger = self.logger.new(o!(
             event::TRACE_ID => trace_id.to_owned(),
             event::BATCH_ID => batch_uuid.to_string(),
@@ -168,6 +363,13 @@ impl<'a> SampleGenerator<'a> {
         if self.dimension <= 0 {
             return Err(anyhow!("dimension must be an integer greater than zero"));
         }
+        self.value_distribution.check(self.dimension as usize)?;
+        for percent in &[self.invalid_proof_percent, self.duplicate_packet_percent] {
+            ensure!(
+                (0.0..=100.0).contains(percent),
+                "percentages must be between 0 and 100"
+            );
+        }
 
         let mut pha_ingestion_batch: BatchWriter<'_, IngestionHeader, IngestionDataSharePacket> =
             BatchWriter::new(
@@ -188,174 +390,288 @@ impl<'a> SampleGenerator<'a> {
         // Generate random data packets and write into data share packets
         let mut thread_rng = thread_rng();
 
-        let mut client = Client::new(
+        // libprio-rs' Client only encrypts shares with ECIES, so to encrypt
+        // the facilitator's shares with HPKE instead, the client encrypts them
+        // to a key of our own, with which we decrypt them again.
+        let facilitator_share_key = match self.facilitator_hpke_public_key {
+            Some(_) => Some(generate_share_key()?),
+            None => None,
+        };
+        let facilitator_packet_encryption_public_key = match &facilitator_share_key {
+            Some(share_key) => PublicKey::from(share_key),
+            None => self.facilitator_output.packet_encryption_public_key.clone(),
+        };
+
+        let mut client = Client::<F>::new(
             // usize is probably bigger than i32 and we have checked that dim is
             // positive so this is safe
             self.dimension as usize,
             self.pha_output.packet_encryption_public_key.clone(),
-            self.facilitator_output.packet_encryption_public_key.clone(),
+            facilitator_packet_encryption_public_key.clone(),
         )
         .context("failed to create client (bad dimension parameter?)")?;
 
-        let mut short_packet_client = Client::new(
+        let mut short_packet_client = Client::<F>::new(
             (self.dimension - 1) as usize,
             self.pha_output.packet_encryption_public_key.clone(),
-            self.facilitator_output.packet_encryption_public_key.clone(),
+            facilitator_packet_encryption_public_key,
         )
         .context("failed to create client (bad dimension parameter?)")?;
 
         // Borrowing distinct parts of a struct like the SampleOutputs works, but
         // not under closures: https://github.com/rust-lang/rust/issues/53488
         // The workaround is to borrow or copy fields outside the closure.
-        let facilitator_batch_signing_key_ref =
-            &self.facilitator_output.transport.batch_signing_key;
+        let pha_packet_encryption_public_key = &self.pha_output.packet_encryption_public_key;
         let drop_nth_pha_packet = self.pha_output.drop_nth_packet;
         let drop_nth_facilitator_packet = self.facilitator_output.drop_nth_packet;
         let generate_short_packet = self.generate_short_packet;
+        let value_distribution = &self.value_distribution;
+        let invalid_proof_probability = self.invalid_proof_percent / 100.0;
+        let duplicate_packet_probability = self.duplicate_packet_percent / 100.0;
+        let batch_fault = self.batch_fault;
         let dimension = self.dimension;
-        let aggregation_name = self.aggregation_name;
-        let epsilon = self.epsilon;
-        let batch_start_time = self.batch_start_time;
-        let batch_end_time = self.batch_end_time;
+        let facilitator_hpke_public_key = self.facilitator_hpke_public_key.as_ref();
 
-        let mut reference_sum = vec![Field32::from(0); self.dimension as usize];
+        let mut reference_sum = vec![F::zero(); self.dimension as usize];
         let mut contributions = 0;
         let mut pha_dropped_packets = Vec::new();
         let mut facilitator_dropped_packets = Vec::new();
+        let mut invalid_proof_packets = Vec::new();
+        let mut duplicate_packets = Vec::new();
+
+        // Generates the packets and passes them to write_packets, which should
+        // write them to the PHA and facilitator packet files, respectively. A
+        // packet is None if it was dropped from that batch.
+        let mut generate_packets = |write_packets: &mut WritePackets<'_>| -> Result<()> {
+            for count in 0..packet_count {
+                let packet_uuid = Uuid::new_v4();
+
+                // Generate random bit vector
+                let mut data = value_distribution.sample(dimension as usize, &mut thread_rng);
+                if Self::short_packet(generate_short_packet, count) {
+                    data.truncate((dimension - 1) as usize);
+                }
+
+                // A value other than zero or one in any bin makes the proof
+                // invalid.
+                let invalid_proof = thread_rng.gen_bool(invalid_proof_probability);
+                if invalid_proof {
+                    let bin = thread_rng.gen_range(0..data.len());
+                    data[bin] = F::reduce(2);
+                    invalid_proof_packets.push(packet_uuid);
+                }
+
+                let out_of_range_packet =
+                    count == 0 && batch_fault == Some(BatchFault::OutOfRangeFieldElement);
+
+                // If we are dropping the packet from either output, or it is
+                // invalid, do not include it in the reference sum
+                if !SampleOutput::drop_packet(drop_nth_pha_packet, count)
+                    && !SampleOutput::drop_packet(drop_nth_facilitator_packet, count)
+                    && !invalid_proof
+                    && !out_of_range_packet
+                {
+                    for (r, d) in reference_sum.iter_mut().zip(data.iter()) {
+                        *r += *d
+                    }
+                    contributions += 1;
+                }
+
+                let curr_client = if Self::short_packet(generate_short_packet, count) {
+                    &mut short_packet_client
+                } else {
+                    &mut client
+                };
+
+                let (mut pha_share, facilitator_share) = curr_client
+                    .encode_simple(&data)
+                    .context("failed to encode data")?;
+                if out_of_range_packet {
+                    pha_share =
+                        out_of_range_share::<F>(data.len(), pha_packet_encryption_public_key)?;
+                }
+
+                // Hardcoded r_pit value
+                // This value can be dynamic by running an instance of libprio::Server
+                // However, libprio::Server takes in a private key for initialization
+                // which we don't have in this context. Using a constant value removes
+                // the libprio::Server dependency for creating samples
+                let r_pit: u32 = 998314904;
+                let duplicate = thread_rng.gen_bool(duplicate_packet_probability);
+                if duplicate {
+                    duplicate_packets.push(packet_uuid);
+                }
+
+                let pha_packet = IngestionDataSharePacket {
+                    uuid: packet_uuid,
+                    encrypted_payload: pha_share,
+                    encryption_key_id: Some("pha-fake-key-1".to_owned()),
+                    r_pit: r_pit as i64,
+                    version_configuration: Some("config-1".to_owned()),
+                    device_nonce: None,
+                    encryption_ciphersuite: None,
+                };
 
-        // We nest the closures here to get both packet writers in one scope
-        let pha_packet_file_digest =
-            pha_ingestion_batch.packet_file_writer(|mut pha_packet_writer| {
-                let facilitator_packet_file_digest = facilitator_ingestion_batch
-                    .packet_file_writer(|mut facilitator_packet_writer| {
-                        for count in 0..packet_count {
-                            // Generate random bit vector
-                            let data_len = if Self::short_packet(generate_short_packet, count) {
-                                dimension - 1
-                            } else {
-                                dimension
-                            };
-
-                            let data: Vec<Field32> = (0..data_len)
-                                .map(|_| Field32::from(thread_rng.gen_range(0..2)))
-                                .collect();
-
-                            // If we are dropping the packet from either output, do
-                            // not include it in the reference sum
-                            if !SampleOutput::drop_packet(drop_nth_pha_packet, count)
-                                && !SampleOutput::drop_packet(drop_nth_facilitator_packet, count)
-                            {
-                                for (r, d) in reference_sum.iter_mut().zip(data.iter()) {
-                                    *r += *d
-                                }
-                                contributions += 1;
-                            }
-
-                            let curr_client = if Self::short_packet(generate_short_packet, count) {
-                                &mut short_packet_client
-                            } else {
-                                &mut client
-                            };
-
-                            let (pha_share, facilitator_share) =
-                                curr_client
-                                    .encode_simple(&data)
-                                    .context("failed to encode data")?;
-
-                            // Hardcoded r_pit value
-                            // This value can be dynamic by running an instance of libprio::Server
-                            // However, libprio::Server takes in a private key for initialization
-                            // which we don't have in this context. Using a constant value removes
-                            // the libprio::Server dependency for creating samples
-                            let r_pit: u32 = 998314904;
-                            let packet_uuid = Uuid::new_v4();
-
-                            let pha_packet = IngestionDataSharePacket {
-                                uuid: packet_uuid,
-                                encrypted_payload: pha_share,
-                                encryption_key_id: Some("pha-fake-key-1".to_owned()),
-                                r_pit: r_pit as i64,
-                                version_configuration: Some("config-1".to_owned()),
-                                device_nonce: None,
-                            };
-
-                            if SampleOutput::drop_packet(drop_nth_pha_packet, count) {
-                                info!(
-                                    local_logger,
-                                    "dropping packet #{} {} from PHA ingestion batch",
-                                    count,
-                                    packet_uuid
-                                );
-                                pha_dropped_packets.push(packet_uuid);
-                            } else {
-                                pha_packet.write(&mut pha_packet_writer)?;
-                            }
-
-                            let facilitator_packet = IngestionDataSharePacket {
-                                uuid: packet_uuid,
-                                encrypted_payload: facilitator_share,
-                                encryption_key_id: None,
-                                r_pit: r_pit as i64,
-                                version_configuration: Some("config-1".to_owned()),
-                                device_nonce: None,
-                            };
-
-                            if SampleOutput::drop_packet(drop_nth_facilitator_packet, count) {
-                                info!(
-                                    local_logger,
-                                    "dropping packet #{} {} from facilitator ingestion batch",
-                                    count,
-                                    packet_uuid
-                                );
-                                facilitator_dropped_packets.push(packet_uuid);
-                            } else {
-                                facilitator_packet.write(&mut facilitator_packet_writer)?;
-                            }
-                        }
+                let pha_packet = if SampleOutput::drop_packet(drop_nth_pha_packet, count) {
+                    info!(
+                        local_logger,
+                        "dropping packet #{} {} from PHA ingestion batch", count, packet_uuid
+                    );
+                    pha_dropped_packets.push(packet_uuid);
+                    None
+                } else {
+                    Some(&pha_packet)
+                };
+
+                let mut facilitator_packet = IngestionDataSharePacket {
+                    uuid: packet_uuid,
+                    encrypted_payload: facilitator_share,
+                    encryption_key_id: None,
+                    r_pit: r_pit as i64,
+                    version_configuration: Some("config-1".to_owned()),
+                    device_nonce: None,
+                    encryption_ciphersuite: None,
+                };
+                if let (Some((ciphersuite, public_key)), Some(share_key)) =
+                    (facilitator_hpke_public_key, &facilitator_share_key)
+                {
+                    let share = decrypt_share(&facilitator_packet.encrypted_payload, share_key)
+                        .map_err(|e| anyhow!("failed to decrypt facilitator share: {:?}", e))?;
+                    facilitator_packet.encrypted_payload =
+                        seal_packet_payload(*ciphersuite, public_key, &facilitator_packet, &share)?;
+                    facilitator_packet.encryption_ciphersuite = Some(ciphersuite.to_string());
+                }
+
+                let facilitator_packet =
+                    if SampleOutput::drop_packet(drop_nth_facilitator_packet, count) {
+                        info!(
+                            local_logger,
+                            "dropping packet #{} {} from facilitator ingestion batch",
+                            count,
+                            packet_uuid
+                        );
+                        facilitator_dropped_packets.push(packet_uuid);
+                        None
+                    } else {
+                        Some(&facilitator_packet)
+                    };
+
+                write_packets(pha_packet, facilitator_packet)?;
+                if duplicate {
+                    write_packets(pha_packet, facilitator_packet)?;
+                }
+            }
+            Ok(())
+        };
+
+        let (pha_packet_file_digest, facilitator_packet_file_digest) = match batch_fault {
+            Some(fault @ BatchFault::TruncatedPacketFile)
+            | Some(fault @ BatchFault::WrongSchema) => {
+                // These faults are injected into the encoded packet files, so
+                // they are assembled in memory before being written out.
+                let schema = if fault == BatchFault::WrongSchema {
+                    wrong_packet_schema()?
+                } else {
+                    IngestionDataSharePacket::schema()
+                };
+                let mut pha_packet_writer = Writer::new(&schema, Vec::new());
+                let mut facilitator_packet_writer = Writer::new(&schema, Vec::new());
+                generate_packets(&mut |pha_packet, facilitator_packet| {
+                    if let Some(packet) = pha_packet {
+                        packet.write(&mut pha_packet_writer)?;
+                    }
+                    if let Some(packet) = facilitator_packet {
+                        packet.write(&mut facilitator_packet_writer)?;
+                    }
+                    Ok(())
+                })?;
+
+                let mut packet_files = [
+                    pha_packet_writer
+                        .into_inner()
+                        .context("failed to flush Avro writer")?,
+                    facilitator_packet_writer
+                        .into_inner()
+                        .context("failed to flush Avro writer")?,
+                ];
+                if fault == BatchFault::TruncatedPacketFile {
+                    for packet_file in &mut packet_files {
+                        packet_file.truncate(packet_file.len() / 2);
+                    }
+                }
+                (
+                    pha_ingestion_batch.put_packet_file(&packet_files[0])?,
+                    facilitator_ingestion_batch.put_packet_file(&packet_files[1])?,
+                )
+            }
+            _ => {
+                // We nest the closures here to get both packet writers in one
+                // scope
+                let mut facilitator_packet_file_digest = None;
+                let pha_packet_file_digest =
+                    pha_ingestion_batch.packet_file_writer(|pha_packet_writer| {
+                        facilitator_packet_file_digest =
+                            Some(facilitator_ingestion_batch.packet_file_writer(
+                                |facilitator_packet_writer| {
+                                    generate_packets(&mut |pha_packet, facilitator_packet| {
+                                        if let Some(packet) = pha_packet {
+                                            packet.write(pha_packet_writer)?;
+                                        }
+                                        if let Some(packet) = facilitator_packet {
+                                            packet.write(facilitator_packet_writer)?;
+                                        }
+                                        Ok(())
+                                    })
+                                },
+                            )?);
                         Ok(())
                     })?;
-
-                let facilitator_header_signature = facilitator_ingestion_batch.put_header(
-                    &IngestionHeader {
-                        batch_uuid: *batch_uuid,
-                        name: aggregation_name.to_owned(),
-                        bins: dimension,
-                        epsilon,
-                        prime: Field32::modulus() as i64,
-                        number_of_servers: 2,
-                        hamming_weight: None,
-                        batch_start_time,
-                        batch_end_time,
-                        packet_file_digest: facilitator_packet_file_digest.as_ref().to_vec(),
-                    },
-                    &facilitator_batch_signing_key_ref.key,
-                )?;
-
-                facilitator_ingestion_batch.put_signature(
-                    &facilitator_header_signature,
-                    &facilitator_batch_signing_key_ref.identifier,
+                (
+                    pha_packet_file_digest,
+                    facilitator_packet_file_digest
+                        .context("facilitator packet file was not written")?,
                 )
-            })?;
+            }
+        };
 
-        let pha_header_signature = pha_ingestion_batch.put_header(
-            &IngestionHeader {
+        for (batch_writer, packet_file_digest, batch_signer) in &mut [
+            (
+                &mut pha_ingestion_batch,
+                pha_packet_file_digest,
+                self.pha_output.transport.batch_signer.as_ref(),
+            ),
+            (
+                &mut facilitator_ingestion_batch,
+                facilitator_packet_file_digest,
+                self.facilitator_output.transport.batch_signer.as_ref(),
+            ),
+        ] {
+            let mut header = IngestionHeader {
                 batch_uuid: *batch_uuid,
-                name: aggregation_name.to_owned(),
-                bins: dimension,
-                epsilon,
-                prime: Field32::modulus() as i64,
+                name: self.aggregation_name.to_owned(),
+                bins: self.dimension,
+                epsilon: self.epsilon,
+                prime: F::FIELD.prime(),
                 number_of_servers: 2,
                 hamming_weight: None,
-                batch_start_time,
-                batch_end_time,
-                packet_file_digest: pha_packet_file_digest.as_ref().to_vec(),
-            },
-            &self.pha_output.transport.batch_signing_key.key,
-        )?;
-        pha_ingestion_batch.put_signature(
-            &pha_header_signature,
-            &self.pha_output.transport.batch_signing_key.identifier,
-        )?;
+                batch_start_time: self.batch_start_time,
+                batch_end_time: self.batch_end_time,
+                packet_file_digest: packet_file_digest.as_ref().to_vec(),
+            };
+            if batch_fault == Some(BatchFault::DigestMismatch) {
+                header.packet_file_digest[0] ^= 0xff;
+            }
+
+            let mut header_signature = batch_writer.put_header(&header, *batch_signer)?;
+            // The last byte of a DER encoded ECDSA signature is part of the
+            // signature itself, so changing it leaves the encoding valid.
+            if batch_fault == Some(BatchFault::BadSignature) {
+                if let Some(byte) = header_signature.last_mut() {
+                    *byte ^= 0xff;
+                }
+            }
+            batch_writer.put_signature(&header_signature, batch_signer.key_identifier())?;
+        }
 
         info!(local_logger, "done");
         Ok(ReferenceSum {
@@ -363,24 +679,58 @@ impl<'a> SampleGenerator<'a> {
             contributions,
             pha_dropped_packets,
             facilitator_dropped_packets,
+            invalid_proof_packets,
+            duplicate_packets,
         })
     }
 }
 
+/// Returns the ingestion packet schema without its r_pit field, which readers
+/// cannot resolve against the actual schema.
+fn wrong_packet_schema() -> Result<Schema> {
+    let mut schema: serde_json::Value =
+        serde_json::from_str(IngestionDataSharePacket::schema_raw())
+            .context("failed to parse packet schema")?;
+    schema["fields"]
+        .as_array_mut()
+        .context("packet schema has no fields")?
+        .retain(|field| field["name"] != "r_pit");
+    Schema::parse(&schema).context("failed to parse modified packet schema")
+}
+
+/// Returns a PHA share for data of the provided dimension, encrypted to the
+/// provided key, whose first field element is not less than the field's
+/// modulus.
+fn out_of_range_share<F: FieldElement>(dimension: usize, key: &PublicKey) -> Result<Vec<u8>> {
+    let mut share = serialize(&vec![F::zero(); proof_length(dimension)]);
+    // No field element has every bit set
+    for byte in &mut share[..F::BYTES] {
+        *byte = 0xff;
+    }
+    encrypt_share(&share, key).context("failed to encrypt share")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
+        batch::BatchReader,
         idl::Header,
         logging::setup_test_logging,
         test_utils::{
-            default_ingestor_private_key, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
+            default_ingestor_private_key, default_ingestor_public_key,
+            default_pha_packet_encryption_public_key, DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY,
             DEFAULT_PHA_ECIES_PRIVATE_KEY,
         },
         transport::{LocalFileTransport, Transport},
     };
     use chrono::NaiveDate;
-    use prio::encrypt::PrivateKey;
+    use prio::{
+        encrypt::PrivateKey,
+        server::{is_valid_share, Server},
+    };
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{collections::HashMap, path::Path};
 
     #[test]
     #[allow(clippy::float_cmp)] // No arithmetic done on floats
@@ -394,7 +744,7 @@ mod tests {
                 transport: Box::new(LocalFileTransport::new(
                     tempdir.path().to_path_buf().join("pha"),
                 )),
-                batch_signing_key: default_ingestor_private_key(),
+                batch_signer: Box::new(default_ingestor_private_key()),
             },
             packet_encryption_public_key: PublicKey::from(
                 &PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
@@ -406,7 +756,7 @@ mod tests {
                 transport: Box::new(LocalFileTransport::new(
                     tempdir.path().to_path_buf().join("facilitator"),
                 )),
-                batch_signing_key: default_ingestor_private_key(),
+                batch_signer: Box::new(default_ingestor_private_key()),
             },
             packet_encryption_public_key: PublicKey::from(
                 &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
@@ -454,4 +804,277 @@ mod tests {
             assert_eq!(parsed_header.batch_end_time, 100);
         }
     }
+
+    #[test]
+    fn value_distributions() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let samples = 20_000;
+        let bin_frequencies = |distribution: &ValueDistribution, rng: &mut StdRng| {
+            let mut counts = [0u32; 3];
+            for _ in 0..samples {
+                let data = distribution.sample::<Field32, _>(3, rng);
+                assert_eq!(data.len(), 3);
+                for (count, value) in counts.iter_mut().zip(data) {
+                    let value = u32::from(value);
+                    assert!(value <= 1);
+                    *count += value;
+                }
+            }
+            counts
+                .iter()
+                .map(|count| f64::from(*count) / f64::from(samples))
+                .collect::<Vec<_>>()
+        };
+
+        let uniform = bin_frequencies(&ValueDistribution::Uniform, &mut rng);
+        assert!(
+            uniform.iter().all(|f| (f - 0.5).abs() < 0.02),
+            "{:?}",
+            uniform
+        );
+
+        // Weights 1, 1/2 and 1/3 sum to 11/6
+        let zipf = ValueDistribution::Zipf { exponent: 1.0 };
+        for _ in 0..100 {
+            let data = zipf.sample::<Field32, _>(3, &mut rng);
+            assert_eq!(data.iter().map(|v| u32::from(*v)).sum::<u32>(), 1);
+        }
+        let frequencies = bin_frequencies(&zipf, &mut rng);
+        for (frequency, expected) in frequencies
+            .iter()
+            .zip(&[6.0 / 11.0, 3.0 / 11.0, 2.0 / 11.0])
+        {
+            assert!((frequency - expected).abs() < 0.02, "{:?}", frequencies);
+        }
+
+        let bernoulli = ValueDistribution::Bernoulli {
+            probabilities: vec![0.0, 1.0, 0.25],
+        };
+        let frequencies = bin_frequencies(&bernoulli, &mut rng);
+        assert_eq!(frequencies[0], 0.0);
+        assert_eq!(frequencies[1], 1.0);
+        assert!((frequencies[2] - 0.25).abs() < 0.02, "{:?}", frequencies);
+
+        bernoulli.check(3).unwrap();
+        bernoulli.check(4).unwrap_err();
+        ValueDistribution::Bernoulli {
+            probabilities: vec![1.5],
+        }
+        .check(1)
+        .unwrap_err();
+        ValueDistribution::Zipf { exponent: -1.0 }
+            .check(3)
+            .unwrap_err();
+    }
+
+    fn sample_output(path: &Path, packet_encryption_public_key: PublicKey) -> SampleOutput {
+        SampleOutput {
+            transport: SignableTransport {
+                transport: Box::new(LocalFileTransport::new(path.to_path_buf())),
+                batch_signer: Box::new(default_ingestor_private_key()),
+            },
+            packet_encryption_public_key,
+            drop_nth_packet: None,
+        }
+    }
+
+    #[test]
+    fn invalid_proofs_and_duplicate_packets() {
+        let logger = setup_test_logging();
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let batch_uuid = Uuid::new_v4();
+        let date = NaiveDate::from_ymd(2009, 2, 13).and_hms(23, 31, 0);
+        let packet_count = 40;
+
+        let mut pha_output = sample_output(
+            &tempdir.path().join("pha"),
+            default_pha_packet_encryption_public_key(),
+        );
+        let mut facilitator_output = sample_output(
+            &tempdir.path().join("facilitator"),
+            PublicKey::from(
+                &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+            ),
+        );
+        let mut sample_generator = SampleGenerator::new(
+            "fake-aggregation",
+            10,
+            0.11,
+            100,
+            100,
+            &mut pha_output,
+            &mut facilitator_output,
+            &logger,
+        );
+        sample_generator.set_value_distribution(ValueDistribution::Zipf { exponent: 1.5 });
+        sample_generator.set_invalid_proof_percent(50.0);
+        sample_generator.set_duplicate_packet_percent(50.0);
+
+        let reference_sum = sample_generator
+            .generate_ingestion_sample("trace-id", &batch_uuid, &date, packet_count)
+            .unwrap();
+        assert!(!reference_sum.invalid_proof_packets.is_empty());
+        assert!(!reference_sum.duplicate_packets.is_empty());
+        assert_eq!(
+            reference_sum.contributions,
+            packet_count - reference_sum.invalid_proof_packets.len()
+        );
+        // Every valid packet sets exactly one bin
+        assert_eq!(
+            reference_sum.sum.iter().map(|v| u32::from(*v)).sum::<u32>() as usize,
+            reference_sum.contributions
+        );
+
+        let mut ingestor_public_keys = HashMap::new();
+        ingestor_public_keys.insert(
+            default_ingestor_private_key().identifier,
+            default_ingestor_public_key(),
+        );
+        let mut packets = Vec::new();
+        for (directory, key) in &[
+            ("pha", DEFAULT_PHA_ECIES_PRIVATE_KEY),
+            ("facilitator", DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY),
+        ] {
+            let mut transport = LocalFileTransport::new(tempdir.path().join(directory));
+            let mut reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(
+                    Batch::new_ingestion("fake-aggregation", &batch_uuid, &date),
+                    &mut transport,
+                    false,
+                    "trace-id",
+                    &logger,
+                );
+            let header = reader.header(&ingestor_public_keys).unwrap();
+            let mut server = Server::new(
+                10,
+                *directory == "pha",
+                PrivateKey::from_base64(key).unwrap(),
+            );
+            let batch_packets: Vec<(Uuid, _)> = reader
+                .packet_file_reader(&header)
+                .unwrap()
+                .map(|packet| {
+                    let packet = packet.unwrap();
+                    let message = server
+                        .generate_verification_message(
+                            Field32::from(packet.r_pit as u32),
+                            &packet.encrypted_payload,
+                        )
+                        .unwrap();
+                    (packet.uuid, message)
+                })
+                .collect();
+            assert_eq!(
+                batch_packets.len(),
+                packet_count + reference_sum.duplicate_packets.len()
+            );
+            packets.push(batch_packets);
+        }
+
+        let mut occurrences: HashMap<Uuid, usize> = HashMap::new();
+        for ((pha_uuid, pha_message), (facilitator_uuid, facilitator_message)) in
+            packets[0].iter().zip(&packets[1])
+        {
+            assert_eq!(pha_uuid, facilitator_uuid);
+            *occurrences.entry(*pha_uuid).or_default() += 1;
+            assert_eq!(
+                is_valid_share(pha_message, facilitator_message),
+                !reference_sum.invalid_proof_packets.contains(pha_uuid)
+            );
+        }
+        assert_eq!(occurrences.len(), packet_count);
+        for (uuid, count) in occurrences {
+            let expected = if reference_sum.duplicate_packets.contains(&uuid) {
+                2
+            } else {
+                1
+            };
+            assert_eq!(count, expected);
+        }
+    }
+
+    #[test]
+    fn batch_faults() {
+        let logger = setup_test_logging();
+        let date = NaiveDate::from_ymd(2009, 2, 13).and_hms(23, 31, 0);
+        let mut ingestor_public_keys = HashMap::new();
+        ingestor_public_keys.insert(
+            default_ingestor_private_key().identifier,
+            default_ingestor_public_key(),
+        );
+
+        for fault in &BatchFault::ALL {
+            assert_eq!(BatchFault::from_str(fault.as_str()).unwrap(), *fault);
+
+            let tempdir = tempfile::TempDir::new().unwrap();
+            let batch_uuid = Uuid::new_v4();
+            let mut pha_output = sample_output(
+                &tempdir.path().join("pha"),
+                default_pha_packet_encryption_public_key(),
+            );
+            let mut facilitator_output = sample_output(
+                &tempdir.path().join("facilitator"),
+                PublicKey::from(
+                    &PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
+                ),
+            );
+            let mut sample_generator = SampleGenerator::new(
+                "fake-aggregation",
+                10,
+                0.11,
+                100,
+                100,
+                &mut pha_output,
+                &mut facilitator_output,
+                &logger,
+            );
+            sample_generator.set_batch_fault(*fault);
+            let reference_sum = sample_generator
+                .generate_ingestion_sample("trace-id", &batch_uuid, &date, 10)
+                .unwrap();
+
+            let mut transport = LocalFileTransport::new(tempdir.path().join("pha"));
+            let mut reader: BatchReader<'_, IngestionHeader, IngestionDataSharePacket> =
+                BatchReader::new(
+                    Batch::new_ingestion("fake-aggregation", &batch_uuid, &date),
+                    &mut transport,
+                    false,
+                    "trace-id",
+                    &logger,
+                );
+            let header = reader.header(&ingestor_public_keys);
+            if *fault == BatchFault::BadSignature {
+                assert!(header.is_err());
+                continue;
+            }
+            let header = header.unwrap();
+            let packets: Result<Vec<IngestionDataSharePacket>> = reader
+                .packet_file_reader(&header)
+                .and_then(|packet_reader| {
+                    packet_reader
+                        .map(|packet| packet.map_err(anyhow::Error::from))
+                        .collect()
+                });
+            match fault {
+                BatchFault::OutOfRangeFieldElement => {
+                    let packets = packets.unwrap();
+                    assert_eq!(packets.len(), 10);
+                    assert_eq!(reference_sum.contributions, 9);
+                    let mut server: Server<Field32> = Server::new(
+                        10,
+                        true,
+                        PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
+                    );
+                    for (index, packet) in packets.iter().enumerate() {
+                        let result = server.generate_verification_message(
+                            Field32::from(packet.r_pit as u32),
+                            &packet.encrypted_payload,
+                        );
+                        assert_eq!(result.is_err(), index == 0);
+                    }
+                }
+                _ => assert!(packets.is_err(), "{:?}", fault),
+            }
+        }
+    }
 }
@@ -0,0 +1,764 @@
+use crate::{
+    batch::Batch,
+    logging::event,
+    state::{AggregationWindow, WindowRegistry, WindowState},
+    task::{self, AggregationTask, IntakeBatchTask, Task, TaskQueue},
+    transport::{is_already_exists_error, Transport},
+    DATE_FORMAT,
+};
+use anyhow::{Context, Result};
+use chrono::{prelude::Utc, DateTime, Duration, NaiveDateTime};
+use slog::{debug, info, o, Logger};
+use std::collections::{BTreeSet, HashSet};
+use uuid::Uuid;
+
+/// Prefix of the keys of the markers the scheduler writes to the own validation
+/// transport to record which tasks it has already dispatched.
+const TASK_MARKER_PREFIX: &str = "task-markers/";
+
+/// Format of the dates in task marker keys, which matches what the
+/// workflow-manager writes so that the two can be used interchangeably.
+const TASK_MARKER_DATE_FORMAT: &str = "%Y-%m-%d-%H-%M";
+
+/// Ingestion batches dated up to this many hours in the future are still
+/// scheduled, to allow for clock skew between ingestors and data share
+/// processors.
+const INTAKE_FUTURE_WINDOW_HOURS: i64 = 24;
+
+/// The number of tasks dispatched by a call to TaskScheduler::schedule_tasks
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScheduleSummary {
+    pub intake_tasks: usize,
+    pub aggregation_tasks: usize,
+}
+
+/// TaskScheduler discovers the work to be done for an aggregation and
+/// dispatches intake and aggregation tasks for it onto task queues, doing what
+/// the workflow-manager does for data share processors that run without it.
+///
+/// An intake task is dispatched for every recent ingestion batch for which this
+/// data share processor has not yet written a validation batch. An aggregation
+/// task is dispatched for the most recent complete aggregation window once it
+/// is over, covering the batches for which validations from both data share
+/// processors exist.
+///
+/// Before dispatching a task, the scheduler claims a marker object for it in
+/// the own validation transport, which may only be created once. A task is thus
+/// dispatched at most once, even if several schedulers run concurrently or
+/// their runs overlap. If the task cannot be enqueued, the marker is deleted so
+/// that a later run can try again.
+pub struct TaskScheduler<'a> {
+    ingestion_transport: &'a mut dyn Transport,
+    own_validation_transport: &'a mut dyn Transport,
+    peer_validation_transport: &'a mut dyn Transport,
+    intake_task_queue: &'a mut dyn TaskQueue<IntakeBatchTask>,
+    aggregation_task_queue: &'a mut dyn TaskQueue<AggregationTask>,
+    is_first: bool,
+    intake_max_age: Duration,
+    aggregation_period: Duration,
+    aggregation_grace_period: Duration,
+    task_hmac_key: Option<Vec<u8>>,
+    locality: Option<String>,
+    window_registry: Option<&'a mut WindowRegistry>,
+    logger: Logger,
+}
+
+impl<'a> TaskScheduler<'a> {
+    /// Creates a scheduler that dispatches intake tasks for ingestion batches
+    /// no older than intake_max_age and aggregation tasks for windows of length
+    /// aggregation_period, aligned to the Unix epoch, once
+    /// aggregation_grace_period has elapsed since the end of the window.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        ingestion_transport: &'a mut dyn Transport,
+        own_validation_transport: &'a mut dyn Transport,
+        peer_validation_transport: &'a mut dyn Transport,
+        intake_task_queue: &'a mut dyn TaskQueue<IntakeBatchTask>,
+        aggregation_task_queue: &'a mut dyn TaskQueue<AggregationTask>,
+        is_first: bool,
+        intake_max_age: std::time::Duration,
+        aggregation_period: std::time::Duration,
+        aggregation_grace_period: std::time::Duration,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            aggregation_period.as_secs() > 0,
+            "aggregation period must be at least one second"
+        );
+        let duration = |duration| Duration::from_std(duration).context("duration is too long");
+        let logger = parent_logger.new(o!(
+            event::INGESTION_PATH => ingestion_transport.path(),
+            event::OWN_VALIDATION_PATH => own_validation_transport.path(),
+            event::PEER_VALIDATION_PATH => peer_validation_transport.path(),
+        ));
+        Ok(TaskScheduler {
+            ingestion_transport,
+            own_validation_transport,
+            peer_validation_transport,
+            intake_task_queue,
+            aggregation_task_queue,
+            is_first,
+            intake_max_age: duration(intake_max_age)?,
+            aggregation_period: duration(aggregation_period)?,
+            aggregation_grace_period: duration(aggregation_grace_period)?,
+            task_hmac_key: None,
+            locality: None,
+            window_registry: None,
+            logger,
+        })
+    }
+
+    /// Sets the key with which dispatched tasks are signed, so that workers
+    /// that require authenticated tasks accept them.
+    pub fn set_task_hmac_key(&mut self, key: Option<Vec<u8>>) {
+        self.task_hmac_key = key;
+    }
+
+    /// Sets the locality recorded in dispatched tasks, so that workers serving
+    /// several localities handle them with that locality's arguments.
+    pub fn set_locality(&mut self, locality: Option<String>) {
+        self.locality = locality;
+    }
+
+    /// Provide a registry of aggregation windows, in which windows are recorded
+    /// as pending when their tasks are dispatched. No aggregation task is
+    /// dispatched for a window that is done or in progress.
+    pub fn set_window_registry(&mut self, registry: &'a mut WindowRegistry) {
+        self.window_registry = Some(registry);
+    }
+
+    /// Returns the IDs of the aggregations for which there are ingestion
+    /// batches, which are the first components of the keys in the ingestion
+    /// transport.
+    pub fn aggregation_ids(&mut self, trace_id: &str) -> Result<Vec<String>> {
+        let aggregation_ids: BTreeSet<String> = self
+            .ingestion_transport
+            .list("", trace_id)?
+            .iter()
+            .filter_map(|key| key.split_once('/'))
+            .map(|(aggregation_id, _)| aggregation_id.to_owned())
+            .filter(|aggregation_id| format!("{}/", aggregation_id) != TASK_MARKER_PREFIX)
+            .collect();
+        Ok(aggregation_ids.into_iter().collect())
+    }
+
+    /// Dispatches the intake and aggregation tasks that are due for the
+    /// aggregation at the provided time and have not been dispatched before.
+    pub fn schedule_tasks(
+        &mut self,
+        aggregation_id: &str,
+        now: DateTime<Utc>,
+        trace_id: &str,
+    ) -> Result<ScheduleSummary> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::AGGREGATION_NAME => aggregation_id.to_owned(),
+        ));
+        let now = now.naive_utc();
+        let summary = ScheduleSummary {
+            intake_tasks: self.schedule_intake_tasks(aggregation_id, now, trace_id, &logger)?,
+            aggregation_tasks: self.schedule_aggregation_task(
+                aggregation_id,
+                now,
+                trace_id,
+                &logger,
+            )?,
+        };
+        info!(
+            logger, "scheduled tasks";
+            "intake_tasks" => summary.intake_tasks,
+            "aggregation_tasks" => summary.aggregation_tasks,
+        );
+        Ok(summary)
+    }
+
+    fn schedule_intake_tasks(
+        &mut self,
+        aggregation_id: &str,
+        now: NaiveDateTime,
+        trace_id: &str,
+        logger: &Logger,
+    ) -> Result<usize> {
+        let interval_start = now - self.intake_max_age;
+        let interval_end = now + Duration::hours(INTAKE_FUTURE_WINDOW_HOURS);
+
+        let validated = validated_batches(
+            self.own_validation_transport,
+            aggregation_id,
+            self.is_first,
+            trace_id,
+        )?;
+        let markers = self.existing_markers(&format!("intake-{}-", aggregation_id), trace_id)?;
+
+        let mut scheduled = 0;
+        for key in self
+            .ingestion_transport
+            .list(&format!("{}/", aggregation_id), trace_id)?
+        {
+            let (key_aggregation_id, date, batch_id) =
+                match Batch::parse_ingestion_signature_key(&key) {
+                    Some(batch) => batch,
+                    None => continue,
+                };
+            if key_aggregation_id != aggregation_id
+                || date < interval_start
+                || date >= interval_end
+                || validated.contains(&(date, batch_id))
+            {
+                continue;
+            }
+            let marker = format!(
+                "intake-{}-{}-{}",
+                aggregation_id,
+                date.format(TASK_MARKER_DATE_FORMAT),
+                batch_id
+            );
+            if markers.contains(&marker) {
+                continue;
+            }
+
+            let mut task = IntakeBatchTask {
+                version: task::TASK_SCHEMA_VERSION,
+                trace_id: Some(Uuid::new_v4()),
+                traceparent: Some(task::batch_traceparent(
+                    aggregation_id,
+                    &batch_id.to_string(),
+                )),
+                aggregation_id: aggregation_id.to_owned(),
+                batch_id: batch_id.to_string(),
+                date: date.format(DATE_FORMAT).to_string(),
+                locality: self.locality.clone(),
+                hmac: None,
+            };
+            let logger = logger.new(o!(
+                event::BATCH_ID => batch_id.to_string(),
+                event::BATCH_DATE => date.to_string(),
+            ));
+            if dispatch(
+                self.own_validation_transport,
+                &mut *self.intake_task_queue,
+                &mut task,
+                &marker,
+                self.task_hmac_key.as_deref(),
+                trace_id,
+                &logger,
+            )? {
+                scheduled += 1;
+            }
+        }
+
+        Ok(scheduled)
+    }
+
+    fn schedule_aggregation_task(
+        &mut self,
+        aggregation_id: &str,
+        now: NaiveDateTime,
+        trace_id: &str,
+        logger: &Logger,
+    ) -> Result<usize> {
+        let (aggregation_start, aggregation_end) = self.aggregation_window(now);
+        let marker = format!(
+            "aggregate-{}-{}-{}",
+            aggregation_id,
+            aggregation_start.format(TASK_MARKER_DATE_FORMAT),
+            aggregation_end.format(TASK_MARKER_DATE_FORMAT)
+        );
+        if !self.existing_markers(&marker, trace_id)?.contains(&marker) {
+            let window = AggregationWindow {
+                aggregation_id: aggregation_id.to_owned(),
+                start: aggregation_start,
+                end: aggregation_end,
+            };
+            if let Some(registry) = self.window_registry.as_deref_mut() {
+                match registry.state(&window)? {
+                    None | Some(WindowState::Pending) => (),
+                    Some(state) => {
+                        debug!(
+                            logger, "aggregation window is not pending";
+                            "window_id" => window.id(),
+                            "window_state" => format!("{:?}", state),
+                        );
+                        return Ok(0);
+                    }
+                }
+            }
+
+            let own_validated = validated_batches(
+                self.own_validation_transport,
+                aggregation_id,
+                self.is_first,
+                trace_id,
+            )?;
+            let peer_validated = validated_batches(
+                self.peer_validation_transport,
+                aggregation_id,
+                !self.is_first,
+                trace_id,
+            )?;
+            let mut batches: Vec<&(NaiveDateTime, Uuid)> = own_validated
+                .intersection(&peer_validated)
+                .filter(|(date, _)| *date >= aggregation_start && *date < aggregation_end)
+                .collect();
+            batches.sort();
+
+            if batches.is_empty() {
+                debug!(
+                    logger, "no batches to aggregate";
+                    "aggregation_start" => aggregation_start.to_string(),
+                    "aggregation_end" => aggregation_end.to_string(),
+                );
+                return Ok(0);
+            }
+
+            let mut task = AggregationTask {
+                version: task::TASK_SCHEMA_VERSION,
+                trace_id: Some(Uuid::new_v4()),
+                traceparent: Some(task::new_traceparent()),
+                aggregation_id: aggregation_id.to_owned(),
+                aggregation_start: aggregation_start.format(DATE_FORMAT).to_string(),
+                aggregation_end: aggregation_end.format(DATE_FORMAT).to_string(),
+                batches: batches
+                    .into_iter()
+                    .map(|(date, batch_id)| task::Batch {
+                        id: batch_id.to_string(),
+                        time: date.format(DATE_FORMAT).to_string(),
+                        traceparent: Some(task::batch_traceparent(
+                            aggregation_id,
+                            &batch_id.to_string(),
+                        )),
+                    })
+                    .collect(),
+                locality: self.locality.clone(),
+                hmac: None,
+            };
+            if let Some(registry) = self.window_registry.as_deref_mut() {
+                registry.record_pending(&window)?;
+            }
+            if dispatch(
+                self.own_validation_transport,
+                &mut *self.aggregation_task_queue,
+                &mut task,
+                &marker,
+                self.task_hmac_key.as_deref(),
+                trace_id,
+                logger,
+            )? {
+                return Ok(1);
+            }
+        }
+
+        Ok(0)
+    }
+
+    /// Returns the start and end of the most recent aggregation window that
+    /// ended at least the grace period before now. Windows are aligned to
+    /// multiples of the aggregation period since the Unix epoch.
+    fn aggregation_window(&self, now: NaiveDateTime) -> (NaiveDateTime, NaiveDateTime) {
+        let period = self.aggregation_period.num_seconds();
+        let latest_end = (now - self.aggregation_grace_period).timestamp();
+        let end = NaiveDateTime::from_timestamp(latest_end - latest_end.rem_euclid(period), 0);
+        (end - self.aggregation_period, end)
+    }
+
+    /// Returns the names of the task markers that begin with the provided
+    /// prefix.
+    fn existing_markers(&mut self, prefix: &str, trace_id: &str) -> Result<HashSet<String>> {
+        Ok(self
+            .own_validation_transport
+            .list(&format!("{}{}", TASK_MARKER_PREFIX, prefix), trace_id)?
+            .into_iter()
+            .filter_map(|key| {
+                key.strip_prefix(TASK_MARKER_PREFIX)
+                    .map(|marker| marker.to_owned())
+            })
+            .collect())
+    }
+}
+
+/// Returns the dates and IDs of the validation batches written by the first or
+/// second data share processor, depending on is_first, for the aggregation.
+fn validated_batches(
+    transport: &mut dyn Transport,
+    aggregation_id: &str,
+    is_first: bool,
+    trace_id: &str,
+) -> Result<HashSet<(NaiveDateTime, Uuid)>> {
+    Ok(transport
+        .list(&format!("{}/", aggregation_id), trace_id)?
+        .iter()
+        .filter_map(|key| Batch::parse_validation_signature_key(key, is_first))
+        .filter(|(key_aggregation_id, _, _)| key_aggregation_id == aggregation_id)
+        .map(|(_, date, batch_id)| (date, batch_id))
+        .collect())
+}
+
+/// Claims the marker for the task and enqueues it. Returns Ok(false) if the
+/// marker was already claimed, in which case the task was dispatched before.
+/// If the task cannot be enqueued, the marker is released again.
+fn dispatch<T: Task>(
+    marker_transport: &mut dyn Transport,
+    task_queue: &mut dyn TaskQueue<T>,
+    task: &mut T,
+    marker: &str,
+    task_hmac_key: Option<&[u8]>,
+    trace_id: &str,
+    logger: &Logger,
+) -> Result<bool> {
+    let marker_key = format!("{}{}", TASK_MARKER_PREFIX, marker);
+    let claimed = marker_transport
+        .put_if_absent(&marker_key, trace_id)
+        .and_then(|mut writer| writer.complete_upload());
+    match claimed {
+        Ok(()) => (),
+        Err(error) if is_already_exists_error(&error) => {
+            debug!(logger, "task was already dispatched"; event::STORAGE_KEY => &marker_key);
+            return Ok(false);
+        }
+        Err(error) => {
+            return Err(error.context(format!("failed to write task marker {}", marker_key)))
+        }
+    }
+
+    if let Some(key) = task_hmac_key {
+        task.sign(key)?;
+    }
+    if let Err(error) = task_queue.enqueue(task) {
+        marker_transport
+            .delete(&marker_key, trace_id)
+            .with_context(|| format!("failed to delete task marker {}", marker_key))?;
+        return Err(error.context(format!("failed to enqueue task {}", task.key())));
+    }
+
+    info!(logger, "dispatched task"; "task_key" => task.key());
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logging::setup_test_logging,
+        state::{LocalFileWindowStore, WindowStart},
+        task::TaskHandle,
+        transport::MemoryTransport,
+    };
+    use chrono::{NaiveDate, TimeZone};
+    use tempfile::TempDir;
+
+    /// A task queue that keeps enqueued tasks in memory and can't dequeue
+    #[derive(Debug)]
+    struct FakeTaskQueue<T: Task> {
+        tasks: Vec<T>,
+        fail: bool,
+    }
+
+    impl<T: Task> FakeTaskQueue<T> {
+        fn new(fail: bool) -> Self {
+            FakeTaskQueue {
+                tasks: Vec::new(),
+                fail,
+            }
+        }
+    }
+
+    impl<T: Task> TaskQueue<T> for FakeTaskQueue<T> {
+        fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>> {
+            unimplemented!()
+        }
+
+        fn enqueue(&mut self, task: &T) -> Result<()> {
+            if self.fail {
+                return Err(anyhow::anyhow!("fake failure"));
+            }
+            self.tasks.push(task.clone());
+            Ok(())
+        }
+
+        fn acknowledge_task(&mut self, _handle: TaskHandle<T>) -> Result<()> {
+            unimplemented!()
+        }
+
+        fn nacknowledge_task(&mut self, _handle: TaskHandle<T>) -> Result<()> {
+            unimplemented!()
+        }
+
+        fn release_task(&mut self, _handle: TaskHandle<T>) -> Result<()> {
+            unimplemented!()
+        }
+
+        fn check_reachable(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn extend_task_deadline(
+            &mut self,
+            _handle: &TaskHandle<T>,
+            _increment: &std::time::Duration,
+        ) -> Result<()> {
+            unimplemented!()
+        }
+    }
+
+    fn put(transport: &mut MemoryTransport, key: &str) {
+        transport.put(key, "").unwrap().complete_upload().unwrap();
+    }
+
+    #[test]
+    fn schedule_tasks() {
+        let logger = setup_test_logging();
+        let mut ingestion = MemoryTransport::new();
+        let mut own_validation = MemoryTransport::new();
+        let mut peer_validation = MemoryTransport::new();
+        let mut intake_queue = FakeTaskQueue::<IntakeBatchTask>::new(false);
+        let mut aggregation_queue = FakeTaskQueue::<AggregationTask>::new(false);
+
+        let now = Utc.ymd(2021, 5, 10).and_hms(7, 30, 0);
+        let date = |hour, minute| {
+            NaiveDateTime::parse_from_str(
+                &format!("2021/05/10/{:02}/{:02}", hour, minute),
+                DATE_FORMAT,
+            )
+            .unwrap()
+        };
+        let validated_id = Uuid::new_v4();
+        let unvalidated_id = Uuid::new_v4();
+        let stale_id = Uuid::new_v4();
+        for (batch_id, date) in &[
+            (validated_id, date(3, 15)),
+            (unvalidated_id, date(7, 0)),
+            (stale_id, date(5, 0)),
+        ] {
+            put(
+                &mut ingestion,
+                Batch::new_ingestion("fake-aggregation", batch_id, date).signature_key(),
+            );
+        }
+        put(
+            &mut own_validation,
+            Batch::new_validation("fake-aggregation", &validated_id, &date(3, 15), true)
+                .signature_key(),
+        );
+        put(
+            &mut peer_validation,
+            Batch::new_validation("fake-aggregation", &validated_id, &date(3, 15), false)
+                .signature_key(),
+        );
+
+        let mut scheduler = TaskScheduler::new(
+            &mut ingestion,
+            &mut own_validation,
+            &mut peer_validation,
+            &mut intake_queue,
+            &mut aggregation_queue,
+            true,
+            std::time::Duration::from_secs(2 * 3600),
+            std::time::Duration::from_secs(3 * 3600),
+            std::time::Duration::from_secs(3600),
+            &logger,
+        )
+        .unwrap();
+        scheduler.set_task_hmac_key(Some(b"fake-key".to_vec()));
+        scheduler.set_locality(Some("zc".to_owned()));
+
+        assert_eq!(
+            scheduler.aggregation_ids("").unwrap(),
+            vec!["fake-aggregation"]
+        );
+
+        // The batch from 05:00 is too old to be scheduled and the one from
+        // 03:15 was already validated. The window from 03:00 to 06:00 is the
+        // most recent one that ended more than an hour ago.
+        let summary = scheduler
+            .schedule_tasks("fake-aggregation", now, "")
+            .unwrap();
+        assert_eq!(
+            summary,
+            ScheduleSummary {
+                intake_tasks: 1,
+                aggregation_tasks: 1
+            }
+        );
+
+        // Running again dispatches nothing, since the markers were claimed
+        let summary = scheduler
+            .schedule_tasks("fake-aggregation", now, "")
+            .unwrap();
+        assert_eq!(summary, ScheduleSummary::default());
+
+        assert_eq!(intake_queue.tasks.len(), 1);
+        let intake_task = &intake_queue.tasks[0];
+        assert_eq!(intake_task.aggregation_id, "fake-aggregation");
+        assert_eq!(intake_task.batch_id, unvalidated_id.to_string());
+        assert_eq!(intake_task.date, "2021/05/10/07/00");
+        assert_eq!(
+            intake_task.traceparent,
+            Some(task::batch_traceparent(
+                "fake-aggregation",
+                &unvalidated_id.to_string()
+            ))
+        );
+        assert_eq!(intake_task.locality.as_deref(), Some("zc"));
+        intake_task.verify(Some(b"fake-key")).unwrap();
+
+        assert_eq!(aggregation_queue.tasks.len(), 1);
+        let aggregation_task = &aggregation_queue.tasks[0];
+        assert_eq!(aggregation_task.aggregation_start, "2021/05/10/03/00");
+        assert_eq!(aggregation_task.aggregation_end, "2021/05/10/06/00");
+        assert_eq!(aggregation_task.locality.as_deref(), Some("zc"));
+        assert_eq!(
+            aggregation_task.batches,
+            vec![task::Batch {
+                id: validated_id.to_string(),
+                time: "2021/05/10/03/15".to_owned(),
+                traceparent: Some(task::batch_traceparent(
+                    "fake-aggregation",
+                    &validated_id.to_string()
+                )),
+            }]
+        );
+        aggregation_task.verify(Some(b"fake-key")).unwrap();
+
+        assert!(own_validation
+            .exists(
+                &format!(
+                    "task-markers/intake-fake-aggregation-2021-05-10-07-00-{}",
+                    unvalidated_id
+                ),
+                ""
+            )
+            .unwrap());
+        assert!(own_validation
+            .exists(
+                "task-markers/aggregate-fake-aggregation-2021-05-10-03-00-2021-05-10-06-00",
+                ""
+            )
+            .unwrap());
+    }
+
+    #[test]
+    fn schedule_tasks_enqueue_failure() {
+        let logger = setup_test_logging();
+        let mut ingestion = MemoryTransport::new();
+        let mut own_validation = MemoryTransport::new();
+        let mut peer_validation = MemoryTransport::new();
+        let mut intake_queue = FakeTaskQueue::<IntakeBatchTask>::new(true);
+        let mut aggregation_queue = FakeTaskQueue::<AggregationTask>::new(false);
+
+        let now = Utc.ymd(2021, 5, 10).and_hms(7, 30, 0);
+        let date = NaiveDateTime::parse_from_str("2021/05/10/07/00", DATE_FORMAT).unwrap();
+        put(
+            &mut ingestion,
+            Batch::new_ingestion("fake-aggregation", &Uuid::new_v4(), &date).signature_key(),
+        );
+
+        let mut scheduler = TaskScheduler::new(
+            &mut ingestion,
+            &mut own_validation,
+            &mut peer_validation,
+            &mut intake_queue,
+            &mut aggregation_queue,
+            false,
+            std::time::Duration::from_secs(3600),
+            std::time::Duration::from_secs(3600),
+            std::time::Duration::from_secs(0),
+            &logger,
+        )
+        .unwrap();
+        scheduler
+            .schedule_tasks("fake-aggregation", now, "")
+            .unwrap_err();
+
+        // The marker was released so that the task is dispatched later
+        assert!(own_validation
+            .list(TASK_MARKER_PREFIX, "")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn schedule_tasks_window_registry() {
+        let logger = setup_test_logging();
+        let mut ingestion = MemoryTransport::new();
+        let mut own_validation = MemoryTransport::new();
+        let mut peer_validation = MemoryTransport::new();
+        let mut intake_queue = FakeTaskQueue::<IntakeBatchTask>::new(false);
+        let mut aggregation_queue = FakeTaskQueue::<AggregationTask>::new(false);
+        let temp_dir = TempDir::new().unwrap();
+        let registry = || {
+            WindowRegistry::new(
+                Box::new(LocalFileWindowStore::new(
+                    temp_dir.path().join("windows.json"),
+                )),
+                std::time::Duration::from_secs(3600),
+                &logger,
+            )
+        };
+        let mut scheduler_registry = registry();
+        let mut worker_registry = registry();
+
+        let now = Utc.ymd(2021, 5, 10).and_hms(7, 30, 0);
+        let window = |hour| AggregationWindow {
+            aggregation_id: "fake-aggregation".to_owned(),
+            start: NaiveDate::from_ymd(2021, 5, 10).and_hms(hour, 0, 0),
+            end: NaiveDate::from_ymd(2021, 5, 10).and_hms(hour + 1, 0, 0),
+        };
+        for hour in &[5, 6] {
+            let date = NaiveDate::from_ymd(2021, 5, 10).and_hms(*hour, 15, 0);
+            let batch_id = Uuid::new_v4();
+            put(
+                &mut own_validation,
+                Batch::new_validation("fake-aggregation", &batch_id, &date, false).signature_key(),
+            );
+            put(
+                &mut peer_validation,
+                Batch::new_validation("fake-aggregation", &batch_id, &date, true).signature_key(),
+            );
+        }
+
+        // The window from 05:00 to 06:00 was already summed, e.g. by a task
+        // dispatched by the workflow-manager
+        assert_eq!(
+            worker_registry
+                .try_start(&window(5), "worker", now - Duration::hours(1))
+                .unwrap(),
+            WindowStart::Started
+        );
+        worker_registry
+            .complete(&window(5), "worker", "digest", now - Duration::hours(1))
+            .unwrap();
+
+        let mut scheduler = TaskScheduler::new(
+            &mut ingestion,
+            &mut own_validation,
+            &mut peer_validation,
+            &mut intake_queue,
+            &mut aggregation_queue,
+            false,
+            std::time::Duration::from_secs(3600),
+            std::time::Duration::from_secs(3600),
+            std::time::Duration::from_secs(0),
+            &logger,
+        )
+        .unwrap();
+        scheduler.set_window_registry(&mut scheduler_registry);
+
+        let summary = scheduler
+            .schedule_tasks("fake-aggregation", now - Duration::hours(1), "")
+            .unwrap();
+        assert_eq!(summary.aggregation_tasks, 0);
+        let summary = scheduler
+            .schedule_tasks("fake-aggregation", now, "")
+            .unwrap();
+        assert_eq!(summary.aggregation_tasks, 1);
+        assert_eq!(aggregation_queue.tasks.len(), 1);
+        assert_eq!(
+            aggregation_queue.tasks[0].aggregation_start,
+            "2021/05/10/06/00"
+        );
+        assert_eq!(
+            scheduler_registry.state(&window(6)).unwrap(),
+            Some(WindowState::Pending)
+        );
+    }
+}
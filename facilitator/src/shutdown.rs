@@ -0,0 +1,115 @@
+use anyhow::{anyhow, Context, Result};
+use slog::{info, warn, Logger};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::aws_credentials::basic_runtime;
+
+/// Shutdown tracks whether the process has been asked to exit, so that worker
+/// loops can stop pulling new tasks and finish the task in flight. Work that is
+/// still in flight once the grace period has elapsed is abandoned, so that the
+/// process can cancel uploads and return its task to the queue before it is
+/// killed. Kubernetes waits terminationGracePeriodSeconds after sending SIGTERM
+/// before sending SIGKILL, so the grace period should be shorter than that.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    grace_period: Duration,
+    requested_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Shutdown {
+    pub fn new(grace_period: Duration) -> Self {
+        Shutdown {
+            grace_period,
+            requested_at: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Creates a Shutdown which is requested when the process receives SIGTERM
+    /// or SIGINT. Signals are handled on a dedicated thread.
+    pub fn from_signals(grace_period: Duration, logger: &Logger) -> Result<Self> {
+        let shutdown = Shutdown::new(grace_period);
+        let runtime = basic_runtime()?;
+        // Signal streams must be created within a runtime, and handlers are
+        // installed when they are created, so we create them here rather than
+        // on the signal handling thread to be sure we don't miss any signals.
+        let (mut sigterm, mut sigint) = runtime.block_on(async {
+            Ok::<_, std::io::Error>((
+                signal(SignalKind::terminate())?,
+                signal(SignalKind::interrupt())?,
+            ))
+        })?;
+
+        let thread_shutdown = shutdown.clone();
+        let logger = logger.clone();
+        thread::Builder::new()
+            .name("signal-handler".to_owned())
+            .spawn(move || {
+                runtime.block_on(async {
+                    tokio::select! {
+                        _ = sigterm.recv() => info!(logger, "received SIGTERM"),
+                        _ = sigint.recv() => info!(logger, "received SIGINT"),
+                    }
+                });
+                thread_shutdown.request();
+                warn!(
+                    logger, "shutting down after current task";
+                    "grace_period" => format!("{:?}", thread_shutdown.grace_period),
+                );
+            })
+            .context("failed to spawn signal handling thread")?;
+
+        Ok(shutdown)
+    }
+
+    /// Requests that the process shut down. Requesting shutdown more than once
+    /// does not extend the grace period.
+    pub fn request(&self) {
+        self.requested_at
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+    }
+
+    /// Returns true if shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        self.requested_at.lock().unwrap().is_some()
+    }
+
+    /// Returns an error if shutdown was requested longer than the grace period
+    /// ago, meaning that work in flight should be abandoned.
+    pub fn check_deadline(&self) -> Result<()> {
+        match *self.requested_at.lock().unwrap() {
+            Some(requested_at) if requested_at.elapsed() >= self.grace_period => Err(anyhow!(
+                "shutdown grace period of {:?} elapsed",
+                self.grace_period
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_deadline() {
+        let shutdown = Shutdown::new(Duration::from_secs(3600));
+        assert!(!shutdown.is_requested());
+        shutdown.check_deadline().unwrap();
+
+        shutdown.clone().request();
+        assert!(shutdown.is_requested());
+        shutdown.check_deadline().unwrap();
+
+        let shutdown = Shutdown::new(Duration::from_secs(0));
+        shutdown.check_deadline().unwrap();
+        shutdown.request();
+        shutdown.check_deadline().unwrap_err();
+    }
+}
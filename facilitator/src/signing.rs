@@ -0,0 +1,390 @@
+use crate::{
+    aws_credentials::{self, basic_runtime, retry_request},
+    config::{Identity, WorkloadIdentityPoolParameters},
+    gcp_oauth::GcpOauthTokenProvider,
+    http::{Method, OauthTokenProvider, RequestParameters, RetryingAgent},
+    logging::event,
+    BatchSigningKey,
+};
+use anyhow::{anyhow, Context, Result};
+use derivative::Derivative;
+use ring::{digest, rand::SystemRandom};
+use rusoto_core::{signature::SignedRequest, Region, RusotoError};
+use serde::Deserialize;
+use serde_json::json;
+use slog::{debug, o, Logger};
+use std::{cell::RefCell, convert::Infallible, fmt::Debug, io::Read, str::FromStr};
+use tokio::runtime::Runtime;
+use url::Url;
+
+/// A BatchSigner signs the headers of the batches this data share processor
+/// writes. Signatures are ASN.1 DER encoded ECDSA P-256 signatures over the
+/// SHA-256 digest of the message, which peers verify using the public key
+/// listed under the signer's key identifier in our specific manifest.
+/// Implementations may keep the private key in memory or delegate signing to a
+/// key management service, so that the private key never leaves it.
+pub trait BatchSigner: Debug {
+    /// The identifier of the signing key, which must correspond to a
+    /// batch-signing-key in this data share processor's specific manifest.
+    fn key_identifier(&self) -> &str;
+
+    /// Signs the provided message.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl BatchSigner for BatchSigningKey {
+    fn key_identifier(&self) -> &str {
+        &self.identifier
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(self
+            .key
+            .sign(&SystemRandom::new(), message)
+            .context("failed to sign message")?
+            .as_ref()
+            .to_vec())
+    }
+}
+
+fn gcp_kms_api_base_url() -> Url {
+    Url::parse("https://cloudkms.googleapis.com/").expect("unable to parse Cloud KMS API url")
+}
+
+/// Represents the subset of the response to a Cloud KMS asymmetricSign request
+/// that we use.
+/// https://cloud.google.com/kms/docs/reference/rest/v1/projects.locations.keyRings.cryptoKeys.cryptoKeyVersions/asymmetricSign#response-body
+#[derive(Deserialize)]
+struct GcpAsymmetricSignResponse {
+    signature: String,
+}
+
+/// A BatchSigner that signs using an asymmetric signing key version in GCP
+/// Cloud KMS. The key version's algorithm must be EC_SIGN_P256_SHA256.
+#[derive(Debug)]
+pub struct GcpKmsBatchSigner {
+    /// Resource name of the key version, of the form
+    /// projects/*/locations/*/keyRings/*/cryptoKeys/*/cryptoKeyVersions/*
+    key_version: String,
+    key_identifier: String,
+    // OauthTokenProvider::ensure_oauth_token needs a mutable reference, but
+    // BatchSigner::sign does not get one.
+    oauth_token_provider: RefCell<Box<dyn OauthTokenProvider>>,
+    agent: RetryingAgent,
+    api_base_url: Url,
+    logger: Logger,
+}
+
+impl GcpKmsBatchSigner {
+    /// Creates a GcpKmsBatchSigner that signs with the provided key version.
+    /// If identity is None, the signer authenticates to Cloud KMS as the
+    /// default service account. Otherwise, it impersonates the service account
+    /// whose email is in identity.
+    pub fn new(
+        key_version: &str,
+        key_identifier: &str,
+        identity: Identity,
+        key_file_reader: Option<Box<dyn Read>>,
+        workload_identity_pool_params: Option<WorkloadIdentityPoolParameters>,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        let oauth_token_provider = GcpOauthTokenProvider::new(
+            // This token is used to access Cloud KMS
+            // https://developers.google.com/identity/protocols/oauth2/scopes#cloudkms
+            "https://www.googleapis.com/auth/cloudkms",
+            identity.map(|x| x.to_string()),
+            key_file_reader,
+            workload_identity_pool_params,
+            parent_logger,
+        )?;
+        Ok(Self::new_with_token_provider(
+            key_version,
+            key_identifier,
+            Box::new(oauth_token_provider),
+            gcp_kms_api_base_url(),
+            parent_logger,
+        ))
+    }
+
+    pub(crate) fn new_with_token_provider(
+        key_version: &str,
+        key_identifier: &str,
+        oauth_token_provider: Box<dyn OauthTokenProvider>,
+        api_base_url: Url,
+        parent_logger: &Logger,
+    ) -> Self {
+        GcpKmsBatchSigner {
+            key_version: key_version.to_owned(),
+            key_identifier: key_identifier.to_owned(),
+            oauth_token_provider: RefCell::new(oauth_token_provider),
+            agent: RetryingAgent::default(),
+            api_base_url,
+            logger: parent_logger.new(o!(
+                "kms_key" => key_version.to_owned(),
+                "key_identifier" => key_identifier.to_owned(),
+            )),
+        }
+    }
+}
+
+impl BatchSigner for GcpKmsBatchSigner {
+    fn key_identifier(&self) -> &str {
+        &self.key_identifier
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let logger = self
+            .logger
+            .new(o!(event::ACTION => "sign with GCP Cloud KMS"));
+        debug!(logger, "signing");
+
+        // https://cloud.google.com/kms/docs/reference/rest/v1/projects.locations.keyRings.cryptoKeys.cryptoKeyVersions/asymmetricSign
+        let url = self
+            .api_base_url
+            .join(&format!("v1/{}:asymmetricSign", self.key_version))
+            .context("failed to construct Cloud KMS URL")?;
+        let mut oauth_token_provider = self.oauth_token_provider.borrow_mut();
+        let request = self.agent.prepare_request(RequestParameters {
+            url,
+            method: Method::Post,
+            token_provider: Some(oauth_token_provider.as_mut()),
+        })?;
+
+        let digest = digest::digest(&digest::SHA256, message);
+        let http_response = self
+            .agent
+            .send_json_request(
+                &logger,
+                &request,
+                &json!({ "digest": { "sha256": base64::encode(digest.as_ref()) } }),
+            )
+            .context(format!("failed to sign with {}", self.key_version))?;
+        let response: GcpAsymmetricSignResponse = self
+            .agent
+            .read_json(http_response)
+            .context("failed to decode Cloud KMS asymmetricSign response")?;
+
+        base64::decode(&response.signature).context("failed to decode Cloud KMS signature")
+    }
+}
+
+/// Represents the subset of the response to an AWS KMS Sign request that we
+/// use.
+/// https://docs.aws.amazon.com/kms/latest/APIReference/API_Sign.html#API_Sign_ResponseSyntax
+#[derive(Deserialize)]
+struct AwsSignResponse {
+    #[serde(rename = "Signature")]
+    signature: String,
+}
+
+/// A BatchSigner that signs using an asymmetric key in AWS KMS. The key's spec
+/// must be ECC_NIST_P256.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct AwsKmsBatchSigner {
+    /// ARN of the KMS key
+    key_arn: String,
+    key_identifier: String,
+    region: Region,
+    #[derivative(Debug = "ignore")]
+    client: rusoto_core::Client,
+    runtime: Runtime,
+    logger: Logger,
+}
+
+impl AwsKmsBatchSigner {
+    /// Creates an AwsKmsBatchSigner that signs with the KMS key with the
+    /// provided ARN, which must be of the form
+    /// arn:aws:kms:<region>:<account>:key/<key ID>.
+    pub fn new(
+        key_arn: &str,
+        key_identifier: &str,
+        credentials_provider: aws_credentials::Provider,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        let client = rusoto_core::Client::new_with(
+            credentials_provider,
+            rusoto_core::HttpClient::new().context("failed to create HTTP client")?,
+        );
+        Self::new_with_client(key_arn, key_identifier, client, parent_logger)
+    }
+
+    pub(crate) fn new_with_client(
+        key_arn: &str,
+        key_identifier: &str,
+        client: rusoto_core::Client,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        let region = match key_arn.split(':').collect::<Vec<&str>>().as_slice() {
+            ["arn", _, "kms", region, _, resource] if resource.starts_with("key/") => {
+                Region::from_str(region).context(format!("invalid region in {}", key_arn))?
+            }
+            _ => return Err(anyhow!("{} is not an AWS KMS key ARN", key_arn)),
+        };
+        Ok(AwsKmsBatchSigner {
+            key_arn: key_arn.to_owned(),
+            key_identifier: key_identifier.to_owned(),
+            region,
+            client,
+            runtime: basic_runtime()?,
+            logger: parent_logger.new(o!(
+                "kms_key" => key_arn.to_owned(),
+                "key_identifier" => key_identifier.to_owned(),
+            )),
+        })
+    }
+}
+
+impl BatchSigner for AwsKmsBatchSigner {
+    fn key_identifier(&self) -> &str {
+        &self.key_identifier
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let logger = self.logger.new(o!(event::ACTION => "sign with AWS KMS"));
+        debug!(logger, "signing");
+
+        // https://docs.aws.amazon.com/kms/latest/APIReference/API_Sign.html
+        let digest = digest::digest(&digest::SHA256, message);
+        let body = serde_json::to_vec(&json!({
+            "KeyId": self.key_arn,
+            "Message": base64::encode(digest.as_ref()),
+            "MessageType": "DIGEST",
+            "SigningAlgorithm": "ECDSA_SHA_256",
+        }))?;
+
+        let response = retry_request(&logger, || {
+            let mut request = SignedRequest::new("POST", "kms", &self.region, "/");
+            request.set_content_type("application/x-amz-json-1.1".to_owned());
+            request.add_header("x-amz-target", "TrentService.Sign");
+            request.set_payload(Some(body.clone()));
+            self.runtime.block_on(async {
+                let mut response = self
+                    .client
+                    .sign_and_dispatch(request)
+                    .await
+                    .map_err(RusotoError::<Infallible>::from)?;
+                let response = response.buffer().await?;
+                if response.status.is_success() {
+                    Ok(response)
+                } else {
+                    Err(RusotoError::Unknown(response))
+                }
+            })
+        })
+        .context(format!("failed to sign with {}", self.key_arn))?;
+
+        let response: AwsSignResponse =
+            serde_json::from_slice(&response.body).context("failed to decode KMS Sign response")?;
+        base64::decode(&response.signature).context("failed to decode AWS KMS signature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http::StaticOauthTokenProvider,
+        logging::setup_test_logging,
+        test_utils::{default_ingestor_private_key, default_ingestor_public_key},
+    };
+    use mockito::{mock, Matcher};
+    use rusoto_core::credential::StaticProvider;
+    use rusoto_mock::MockRequestDispatcher;
+
+    #[test]
+    fn in_memory_signer() {
+        let key = default_ingestor_private_key();
+        assert_eq!(key.key_identifier(), key.identifier);
+        let signature = key.sign(b"message").unwrap();
+        default_ingestor_public_key()
+            .verify(b"message", &signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn gcp_kms_signer() {
+        let logger = setup_test_logging();
+        let key_version = "projects/p/locations/global/keyRings/r/cryptoKeys/k/cryptoKeyVersions/1";
+        // Cloud KMS signs the digest of the message, but since the request is
+        // mocked, we produce the signature over the message ourselves.
+        let signature = default_ingestor_private_key().sign(b"message").unwrap();
+        let mocked_sign = mock(
+            "POST",
+            format!("/v1/{}:asymmetricSign", key_version).as_str(),
+        )
+        .match_header("Authorization", "Bearer fake-token")
+        .match_body(Matcher::Json(json!({
+            "digest": {
+                "sha256": base64::encode(digest::digest(&digest::SHA256, b"message")),
+            }
+        })))
+        .with_status(200)
+        .with_body(json!({ "signature": base64::encode(&signature) }).to_string())
+        .expect(1)
+        .create();
+
+        let signer = GcpKmsBatchSigner::new_with_token_provider(
+            key_version,
+            "key-identifier",
+            Box::new(StaticOauthTokenProvider::from("fake-token".to_owned())),
+            Url::parse(&mockito::server_url()).unwrap(),
+            &logger,
+        );
+        assert_eq!(signer.key_identifier(), "key-identifier");
+        let kms_signature = signer.sign(b"message").unwrap();
+        mocked_sign.assert();
+        assert_eq!(kms_signature, signature);
+        default_ingestor_public_key()
+            .verify(b"message", &kms_signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn aws_kms_signer() {
+        let logger = setup_test_logging();
+        let key_arn = "arn:aws:kms:us-west-2:111122223333:key/1234abcd";
+        let signature = default_ingestor_private_key().sign(b"message").unwrap();
+        let dispatcher = MockRequestDispatcher::with_status(200)
+            .with_json_body(json!({
+                "KeyId": key_arn,
+                "Signature": base64::encode(&signature),
+                "SigningAlgorithm": "ECDSA_SHA_256",
+            }))
+            .with_request_checker(|request| {
+                assert_eq!(request.method, "POST");
+                assert_eq!(request.service, "kms");
+                assert_eq!(request.region, Region::UsWest2);
+                assert_eq!(
+                    request.headers.get("x-amz-target"),
+                    Some(&vec![b"TrentService.Sign".to_vec()])
+                );
+            });
+        let client = rusoto_core::Client::new_with(
+            StaticProvider::new_minimal("key".to_owned(), "secret".to_owned()),
+            dispatcher,
+        );
+
+        let signer =
+            AwsKmsBatchSigner::new_with_client(key_arn, "key-identifier", client, &logger).unwrap();
+        assert_eq!(signer.key_identifier(), "key-identifier");
+        assert_eq!(signer.sign(b"message").unwrap(), signature);
+    }
+
+    #[test]
+    fn aws_kms_signer_bad_arn() {
+        let logger = setup_test_logging();
+        for arn in &[
+            "1234abcd",
+            "arn:aws:s3:::bucket",
+            "arn:aws:kms:us-west-2:111122223333:alias/my-key",
+            "arn:aws:kms:nowhere-1:111122223333:key/1234abcd",
+        ] {
+            let client = rusoto_core::Client::new_with(
+                StaticProvider::new_minimal("key".to_owned(), "secret".to_owned()),
+                MockRequestDispatcher::default(),
+            );
+            AwsKmsBatchSigner::new_with_client(arn, "key-identifier", client, &logger).unwrap_err();
+        }
+    }
+}
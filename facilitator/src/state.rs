@@ -0,0 +1,1133 @@
+use crate::{
+    aws_credentials::{basic_runtime, retry_request},
+    config::Identity,
+    gcp_oauth::GcpOauthTokenProvider,
+    http::{
+        error_http_status, HttpError, Method, OauthTokenProvider, RequestClass, RequestParameters,
+        RetryingAgent,
+    },
+    logging::event,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{prelude::Utc, DateTime, NaiveDateTime};
+use derivative::Derivative;
+use rusoto_core::{signature::SignedRequest, Region, RusotoError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Debug,
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+use url::Url;
+
+const FIRESTORE_API_BASE_URL: &str = "https://firestore.googleapis.com";
+
+/// How many times WindowRegistry re-reads a window's state and tries again
+/// after losing a race to update it.
+const MAX_UPDATE_ATTEMPTS: usize = 5;
+
+/// An aggregation window: the batches of an aggregation whose times fall within
+/// [start, end).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AggregationWindow {
+    pub aggregation_id: String,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+impl AggregationWindow {
+    /// Returns the window of the aggregation of length `period` that contains
+    /// `time`, with windows aligned to multiples of the period since the Unix
+    /// epoch, as TaskScheduler aligns them.
+    pub fn containing(aggregation_id: &str, time: NaiveDateTime, period: Duration) -> Self {
+        let period = period.as_secs() as i64;
+        let timestamp = time.timestamp();
+        let start = timestamp - timestamp.rem_euclid(period);
+        AggregationWindow {
+            aggregation_id: aggregation_id.to_owned(),
+            start: NaiveDateTime::from_timestamp(start, 0),
+            end: NaiveDateTime::from_timestamp(start + period, 0),
+        }
+    }
+
+    /// Returns the identifier under which the window's state is stored.
+    pub fn id(&self) -> String {
+        format!(
+            "{}-{}-{}",
+            self.aggregation_id,
+            self.start.format("%Y%m%d%H%M"),
+            self.end.format("%Y%m%d%H%M")
+        )
+    }
+}
+
+/// The status of an aggregation window.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum WindowState {
+    /// An aggregation task has been dispatched for the window, but no worker
+    /// has started it.
+    Pending,
+    /// A worker is summing the window. Other workers must not until the
+    /// holder's lease expires.
+    #[serde(rename_all = "kebab-case")]
+    InProgress {
+        holder: String,
+        lease_expiry: DateTime<Utc>,
+    },
+    /// The window's sum part has been written.
+    #[serde(rename_all = "kebab-case")]
+    Done {
+        /// Hex encoded SHA-256 digest of the Avro encoded sum part header
+        sum_part_digest: String,
+        completed_at: DateTime<Utc>,
+    },
+}
+
+/// A window's state as it is stored, along with an opaque version that changes
+/// whenever the state is updated.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredWindowState {
+    pub state: WindowState,
+    pub version: String,
+}
+
+/// A WindowStateStore keeps the state of aggregation windows somewhere shared
+/// by the task scheduler and all the aggregate workers for an instance.
+pub trait WindowStateStore: Debug {
+    /// Returns the stored state of the window with the provided ID, if any.
+    fn get(&mut self, window_id: &str) -> Result<Option<StoredWindowState>>;
+
+    /// Stores `state` for the window with the provided ID, if and only if the
+    /// stored version of its state is still `expected_version`, or, if that is
+    /// None, no state is stored for it yet. Returns Ok(false) if the condition
+    /// did not hold, because some other writer got there first.
+    fn compare_and_swap(
+        &mut self,
+        window_id: &str,
+        expected_version: Option<&str>,
+        state: &WindowState,
+    ) -> Result<bool>;
+}
+
+/// A WindowStateStore that keeps the state of all windows in a JSON file on
+/// local disk. Suitable for a single facilitator process, e.g. in tests or
+/// local deployments, since nothing stops two processes from updating the file
+/// concurrently.
+#[derive(Debug)]
+pub struct LocalFileWindowStore {
+    path: PathBuf,
+}
+
+impl LocalFileWindowStore {
+    /// Creates a LocalFileWindowStore that keeps window state in the file at
+    /// `path`, which is created if it does not exist.
+    pub fn new(path: PathBuf) -> Self {
+        LocalFileWindowStore { path }
+    }
+
+    fn read(&self) -> Result<BTreeMap<String, StoredWindowState>> {
+        match fs::read(&self.path) {
+            Ok(content) => serde_json::from_slice(&content).context(format!(
+                "failed to parse window state file {}",
+                self.path.display()
+            )),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e).context(format!(
+                "failed to read window state file {}",
+                self.path.display()
+            )),
+        }
+    }
+}
+
+impl WindowStateStore for LocalFileWindowStore {
+    fn get(&mut self, window_id: &str) -> Result<Option<StoredWindowState>> {
+        Ok(self.read()?.remove(window_id))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        window_id: &str,
+        expected_version: Option<&str>,
+        state: &WindowState,
+    ) -> Result<bool> {
+        let mut windows = self.read()?;
+        let current_version = windows.get(window_id).map(|stored| stored.version.as_str());
+        if current_version != expected_version {
+            return Ok(false);
+        }
+        let version = current_version
+            .map(u64::from_str)
+            .transpose()
+            .context("invalid version in window state file")?
+            .map_or(1, |version| version + 1);
+        windows.insert(
+            window_id.to_owned(),
+            StoredWindowState {
+                state: state.clone(),
+                version: version.to_string(),
+            },
+        );
+
+        // Write to a temporary file and rename it over the state file, so that
+        // the state file is never left partially written.
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&windows)?)
+            .and_then(|_| fs::rename(&temp_path, &self.path))
+            .context(format!(
+                "failed to write window state file {}",
+                self.path.display()
+            ))?;
+        Ok(true)
+    }
+}
+
+/// Represents the subset of a Firestore document that we use.
+/// https://cloud.google.com/firestore/docs/reference/rest/v1/projects.databases.documents#Document
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FirestoreDocument {
+    fields: FirestoreFields,
+    update_time: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FirestoreFields {
+    state: FirestoreStringValue,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FirestoreStringValue {
+    string_value: String,
+}
+
+/// A WindowStateStore backed by a collection of documents in a GCP Firestore
+/// database in Native mode, one per window. The document's update time serves
+/// as its version, and updates are made conditional on it using Firestore
+/// preconditions.
+#[derive(Debug)]
+pub struct FirestoreWindowStore {
+    /// Resource name of the database, of the form
+    /// projects/*/databases/(default)
+    database: String,
+    collection: String,
+    oauth_token_provider: Box<dyn OauthTokenProvider>,
+    agent: RetryingAgent,
+    api_base_url: Url,
+    logger: Logger,
+}
+
+impl FirestoreWindowStore {
+    /// Creates a FirestoreWindowStore that keeps window state in the provided
+    /// collection of the default database of the GCP project. If identity is
+    /// None, the store authenticates to Firestore as the default service
+    /// account. Otherwise, it impersonates the service account whose email is
+    /// in identity.
+    pub fn new(
+        api_endpoint: Option<&str>,
+        gcp_project_id: &str,
+        collection: &str,
+        identity: Identity,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        let oauth_token_provider = GcpOauthTokenProvider::new(
+            // This token is used to access Firestore
+            // https://developers.google.com/identity/protocols/oauth2/scopes#firestore
+            "https://www.googleapis.com/auth/datastore",
+            identity.map(|x| x.to_string()),
+            None,
+            None,
+            parent_logger,
+        )?;
+        Self::new_with_token_provider(
+            gcp_project_id,
+            collection,
+            Box::new(oauth_token_provider),
+            Url::parse(api_endpoint.unwrap_or(FIRESTORE_API_BASE_URL))
+                .context("invalid Firestore API endpoint")?,
+            parent_logger,
+        )
+    }
+
+    pub(crate) fn new_with_token_provider(
+        gcp_project_id: &str,
+        collection: &str,
+        oauth_token_provider: Box<dyn OauthTokenProvider>,
+        api_base_url: Url,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        Ok(FirestoreWindowStore {
+            database: format!("projects/{}/databases/(default)", gcp_project_id),
+            collection: collection.to_owned(),
+            oauth_token_provider,
+            // Per Google documentation, 429 Too Many Requests and 409 Aborted
+            // should be retried, but we don't retry 409 since that is also how
+            // Firestore reports that a document we are creating already exists.
+            // https://cloud.google.com/firestore/docs/understand-error-codes
+            agent: RetryingAgent::new(RequestClass::Api, vec![429]),
+            api_base_url,
+            logger: parent_logger.new(o!(
+                "gcp_project_id" => gcp_project_id.to_owned(),
+                "window_state_collection" => collection.to_owned(),
+            )),
+        })
+    }
+
+    fn document_name(&self, window_id: &str) -> String {
+        format!(
+            "{}/documents/{}/{}",
+            self.database, self.collection, window_id
+        )
+    }
+}
+
+impl WindowStateStore for FirestoreWindowStore {
+    fn get(&mut self, window_id: &str) -> Result<Option<StoredWindowState>> {
+        let logger = self.logger.new(o!(
+            event::ACTION => "get window state",
+            "window_id" => window_id.to_owned(),
+        ));
+
+        // https://cloud.google.com/firestore/docs/reference/rest/v1/projects.databases.documents/get
+        let url = self
+            .api_base_url
+            .join(&format!("v1/{}", self.document_name(window_id)))
+            .context("failed to construct Firestore URL")?;
+        let request = self.agent.prepare_request(RequestParameters {
+            url,
+            method: Method::Get,
+            token_provider: Some(self.oauth_token_provider.as_mut()),
+        })?;
+
+        let http_response = match self.agent.call(&logger, &request) {
+            Ok(response) => response,
+            Err(e) if error_http_status(&e) == Some(404) => return Ok(None),
+            Err(e) => return Err(e.context(format!("failed to get window {}", window_id))),
+        };
+        let document: FirestoreDocument = self
+            .agent
+            .read_json(http_response)
+            .context("failed to decode Firestore document")?;
+
+        Ok(Some(StoredWindowState {
+            state: serde_json::from_str(&document.fields.state.string_value)
+                .context(format!("failed to parse state of window {}", window_id))?,
+            version: document.update_time,
+        }))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        window_id: &str,
+        expected_version: Option<&str>,
+        state: &WindowState,
+    ) -> Result<bool> {
+        let logger = self.logger.new(o!(
+            event::ACTION => "update window state",
+            "window_id" => window_id.to_owned(),
+        ));
+
+        // https://cloud.google.com/firestore/docs/reference/rest/v1/projects.databases.documents/commit
+        let url = self
+            .api_base_url
+            .join(&format!("v1/{}/documents:commit", self.database))
+            .context("failed to construct Firestore URL")?;
+        let request = self.agent.prepare_request(RequestParameters {
+            url,
+            method: Method::Post,
+            token_provider: Some(self.oauth_token_provider.as_mut()),
+        })?;
+
+        let precondition = match expected_version {
+            Some(update_time) => json!({ "updateTime": update_time }),
+            None => json!({ "exists": false }),
+        };
+        let body = json!({
+            "writes": [{
+                "update": {
+                    "name": self.document_name(window_id),
+                    "fields": {
+                        "state": { "stringValue": serde_json::to_string(state)? },
+                    },
+                },
+                "currentDocument": precondition,
+            }],
+        });
+
+        match self.agent.send_json_request(&logger, &request, &body) {
+            Ok(_) => Ok(true),
+            // Firestore reports that the document already exists with 409
+            // ALREADY_EXISTS and that its update time differs with 400
+            // FAILED_PRECONDITION.
+            Err(e) => {
+                match e.downcast::<HttpError>() {
+                    Ok(HttpError::Status(409, _)) => Ok(false),
+                    Ok(HttpError::Status(400, response)) => {
+                        let body = self.agent.read_string(response).unwrap_or_default();
+                        if body.contains("FAILED_PRECONDITION") {
+                            Ok(false)
+                        } else {
+                            Err(anyhow!(
+                                "failed to update window {}: bad request: {}",
+                                window_id,
+                                body
+                            ))
+                        }
+                    }
+                    Ok(e) => Err(anyhow::Error::new(e)
+                        .context(format!("failed to update window {}", window_id))),
+                    Err(e) => Err(e.context(format!("failed to update window {}", window_id))),
+                }
+            }
+        }
+    }
+}
+
+/// Represents the subset of the response to a DynamoDB GetItem request that
+/// we use.
+/// https://docs.aws.amazon.com/amazondynamodb/latest/APIReference/API_GetItem.html#API_GetItem_ResponseSyntax
+#[derive(Debug, Deserialize)]
+struct GetItemResponse {
+    #[serde(rename = "Item")]
+    item: Option<DynamoDbItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DynamoDbItem {
+    state: DynamoDbString,
+    version: DynamoDbNumber,
+}
+
+#[derive(Debug, Deserialize)]
+struct DynamoDbString {
+    #[serde(rename = "S")]
+    s: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DynamoDbNumber {
+    #[serde(rename = "N")]
+    n: String,
+}
+
+/// A WindowStateStore backed by an AWS DynamoDB table whose partition key is
+/// the string attribute `window_id`. Each item holds the window's state and a
+/// numeric version that is incremented by every update, and updates are made
+/// conditional on it using condition expressions.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct DynamoDbWindowStore {
+    table_name: String,
+    region: Region,
+    #[derivative(Debug = "ignore")]
+    client: rusoto_core::Client,
+    runtime: Runtime,
+    logger: Logger,
+}
+
+impl DynamoDbWindowStore {
+    /// Creates a DynamoDbWindowStore that keeps window state in the named table
+    /// in the provided region, using the provided client to make requests.
+    pub fn new(
+        table_name: &str,
+        region: &str,
+        client: rusoto_core::Client,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        Ok(DynamoDbWindowStore {
+            table_name: table_name.to_owned(),
+            region: Region::from_str(region).context("invalid AWS region")?,
+            client,
+            runtime: basic_runtime()?,
+            logger: parent_logger.new(o!("window_state_table" => table_name.to_owned())),
+        })
+    }
+
+    /// Sends a request with the provided JSON body to the DynamoDB action
+    /// `target`, returning the body of the response.
+    fn request(&self, logger: &Logger, target: &str, body: serde_json::Value) -> Result<Vec<u8>> {
+        let body = serde_json::to_vec(&body)?;
+        let response = retry_request(logger, || {
+            let mut request = SignedRequest::new("POST", "dynamodb", &self.region, "/");
+            request.set_content_type("application/x-amz-json-1.0".to_owned());
+            request.add_header("x-amz-target", target);
+            request.set_payload(Some(body.clone()));
+            self.runtime.block_on(async {
+                let mut response = self
+                    .client
+                    .sign_and_dispatch(request)
+                    .await
+                    .map_err(RusotoError::<Infallible>::from)?;
+                let response = response.buffer().await?;
+                if response.status.is_success() {
+                    Ok(response)
+                } else {
+                    Err(RusotoError::Unknown(response))
+                }
+            })
+        })?;
+        Ok(response.body.to_vec())
+    }
+}
+
+impl WindowStateStore for DynamoDbWindowStore {
+    fn get(&mut self, window_id: &str) -> Result<Option<StoredWindowState>> {
+        let logger = self.logger.new(o!(
+            event::ACTION => "get window state",
+            "window_id" => window_id.to_owned(),
+        ));
+
+        // https://docs.aws.amazon.com/amazondynamodb/latest/APIReference/API_GetItem.html
+        let response = self
+            .request(
+                &logger,
+                "DynamoDB_20120810.GetItem",
+                json!({
+                    "TableName": self.table_name,
+                    "Key": { "window_id": { "S": window_id } },
+                    "ConsistentRead": true,
+                }),
+            )
+            .context(format!("failed to get window {}", window_id))?;
+        let response: GetItemResponse =
+            serde_json::from_slice(&response).context("failed to decode GetItem response")?;
+
+        response
+            .item
+            .map(|item| {
+                Ok(StoredWindowState {
+                    state: serde_json::from_str(&item.state.s)
+                        .context(format!("failed to parse state of window {}", window_id))?,
+                    version: item.version.n,
+                })
+            })
+            .transpose()
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        window_id: &str,
+        expected_version: Option<&str>,
+        state: &WindowState,
+    ) -> Result<bool> {
+        let logger = self.logger.new(o!(
+            event::ACTION => "update window state",
+            "window_id" => window_id.to_owned(),
+        ));
+
+        let version = expected_version
+            .map(u64::from_str)
+            .transpose()
+            .context("invalid window state version")?
+            .map_or(1, |version| version + 1);
+        let mut body = json!({
+            "TableName": self.table_name,
+            "Item": {
+                "window_id": { "S": window_id },
+                "state": { "S": serde_json::to_string(state)? },
+                "version": { "N": version.to_string() },
+            },
+        });
+        match expected_version {
+            Some(expected_version) => {
+                body["ConditionExpression"] = json!("version = :version");
+                body["ExpressionAttributeValues"] =
+                    json!({ ":version": { "N": expected_version } });
+            }
+            None => body["ConditionExpression"] = json!("attribute_not_exists(window_id)"),
+        }
+
+        // https://docs.aws.amazon.com/amazondynamodb/latest/APIReference/API_PutItem.html
+        match self.request(&logger, "DynamoDB_20120810.PutItem", body) {
+            Ok(_) => Ok(true),
+            Err(e) => match e.downcast_ref::<RusotoError<Infallible>>() {
+                Some(RusotoError::Unknown(response))
+                    if response.status == 400
+                        && response
+                            .body_as_str()
+                            .contains("ConditionalCheckFailedException") =>
+                {
+                    Ok(false)
+                }
+                _ => Err(e.context(format!("failed to update window {}", window_id))),
+            },
+        }
+    }
+}
+
+/// The outcome of WindowRegistry::try_start.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WindowStart {
+    /// The caller now holds the lease on the window and should sum it.
+    Started,
+    /// Another worker holds an unexpired lease on the window.
+    InProgress { holder: String },
+    /// The window's sum part has already been written.
+    AlreadyDone { sum_part_digest: String },
+}
+
+/// WindowRegistry tracks the state of aggregation windows in a
+/// WindowStateStore, so that each window's sum part is emitted exactly once
+/// even if its aggregation task is dispatched or delivered more than once. The
+/// scheduler records windows as pending when it dispatches tasks for them. A
+/// worker must start a window before summing it, which gives it a lease on the
+/// window that it renews while it works and that lets another worker take over
+/// if it goes away. Once the sum part is written, the worker marks the window
+/// done and no worker will start it again.
+#[derive(Debug)]
+pub struct WindowRegistry {
+    store: Box<dyn WindowStateStore>,
+    lease_duration: Duration,
+    /// When the lease on the window being summed was last acquired or renewed
+    renewed_at: Option<Instant>,
+    logger: Logger,
+}
+
+impl WindowRegistry {
+    pub fn new(
+        store: Box<dyn WindowStateStore>,
+        lease_duration: Duration,
+        parent_logger: &Logger,
+    ) -> Self {
+        WindowRegistry {
+            store,
+            lease_duration,
+            renewed_at: None,
+            logger: parent_logger.new(o!()),
+        }
+    }
+
+    fn lease_expiry(&self, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+        Ok(now + chrono::Duration::from_std(self.lease_duration)?)
+    }
+
+    /// Returns the state of the window, if any has been recorded.
+    pub fn state(&mut self, window: &AggregationWindow) -> Result<Option<WindowState>> {
+        Ok(self.store.get(&window.id())?.map(|stored| stored.state))
+    }
+
+    /// Records the window as pending if no state has been recorded for it.
+    /// Returns the state already recorded for the window, if any, in which case
+    /// it is left unchanged.
+    pub fn record_pending(&mut self, window: &AggregationWindow) -> Result<Option<WindowState>> {
+        let window_id = window.id();
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            if let Some(stored) = self.store.get(&window_id)? {
+                return Ok(Some(stored.state));
+            }
+            if self
+                .store
+                .compare_and_swap(&window_id, None, &WindowState::Pending)?
+            {
+                debug!(self.logger, "recorded window as pending"; "window_id" => window_id);
+                return Ok(None);
+            }
+        }
+        Err(anyhow!("window {} is being updated too often", window_id))
+    }
+
+    /// Tries to start summing the window on behalf of holder. Unless the window
+    /// is done or another holder has an unexpired lease on it, the holder is
+    /// given a lease on it until lease_duration after now.
+    pub fn try_start(
+        &mut self,
+        window: &AggregationWindow,
+        holder: &str,
+        now: DateTime<Utc>,
+    ) -> Result<WindowStart> {
+        let window_id = window.id();
+        let in_progress = WindowState::InProgress {
+            holder: holder.to_owned(),
+            lease_expiry: self.lease_expiry(now)?,
+        };
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let stored = self.store.get(&window_id)?;
+            match stored.as_ref().map(|stored| &stored.state) {
+                Some(WindowState::Done {
+                    sum_part_digest, ..
+                }) => {
+                    return Ok(WindowStart::AlreadyDone {
+                        sum_part_digest: sum_part_digest.clone(),
+                    })
+                }
+                Some(WindowState::InProgress {
+                    holder: current_holder,
+                    lease_expiry,
+                }) if current_holder != holder && *lease_expiry > now => {
+                    return Ok(WindowStart::InProgress {
+                        holder: current_holder.clone(),
+                    })
+                }
+                Some(WindowState::InProgress {
+                    holder: current_holder,
+                    ..
+                }) if current_holder != holder => {
+                    warn!(
+                        self.logger, "taking over window whose lease expired";
+                        "window_id" => &window_id,
+                        "previous_holder" => current_holder,
+                    );
+                }
+                _ => (),
+            }
+
+            let expected_version = stored.as_ref().map(|stored| stored.version.as_str());
+            if self
+                .store
+                .compare_and_swap(&window_id, expected_version, &in_progress)?
+            {
+                info!(self.logger, "started window"; "window_id" => window_id, "holder" => holder);
+                self.renewed_at = Some(Instant::now());
+                return Ok(WindowStart::Started);
+            }
+        }
+        Err(anyhow!("window {} is being updated too often", window_id))
+    }
+
+    /// Extends holder's lease on the window if a third of the lease duration
+    /// has passed since it was last acquired or renewed. Returns an error if
+    /// holder no longer holds the lease, in which case it must stop summing
+    /// the window.
+    pub fn renew_if_due(
+        &mut self,
+        window: &AggregationWindow,
+        holder: &str,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        if let Some(renewed_at) = self.renewed_at {
+            if renewed_at.elapsed() < self.lease_duration / 3 {
+                return Ok(());
+            }
+        }
+        let state = WindowState::InProgress {
+            holder: holder.to_owned(),
+            lease_expiry: self.lease_expiry(now)?,
+        };
+        self.update_held(window, holder, &state)
+            .context("failed to renew lease on window")?;
+        debug!(self.logger, "renewed lease on window"; "window_id" => window.id());
+        self.renewed_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Marks the window done, recording the digest of its sum part. Returns an
+    /// error if holder no longer holds the lease on the window.
+    pub fn complete(
+        &mut self,
+        window: &AggregationWindow,
+        holder: &str,
+        sum_part_digest: &str,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let state = WindowState::Done {
+            sum_part_digest: sum_part_digest.to_owned(),
+            completed_at: now,
+        };
+        self.update_held(window, holder, &state)
+            .context("failed to mark window done")?;
+        info!(
+            self.logger, "completed window";
+            "window_id" => window.id(),
+            "sum_part_digest" => sum_part_digest,
+        );
+        self.renewed_at = None;
+        Ok(())
+    }
+
+    /// Returns the window to pending if holder holds the lease on it, so that
+    /// another worker may start it right away.
+    pub fn abandon(&mut self, window: &AggregationWindow, holder: &str) -> Result<()> {
+        self.renewed_at = None;
+        match self.update_held(window, holder, &WindowState::Pending) {
+            Ok(()) => {
+                info!(self.logger, "abandoned window"; "window_id" => window.id());
+                Ok(())
+            }
+            Err(e) => Err(e.context("failed to abandon window")),
+        }
+    }
+
+    /// Stores `state` for the window if holder holds the lease on it, expired
+    /// or not, so long as no one else has taken it over.
+    fn update_held(
+        &mut self,
+        window: &AggregationWindow,
+        holder: &str,
+        state: &WindowState,
+    ) -> Result<()> {
+        let window_id = window.id();
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let stored = self.store.get(&window_id)?;
+            match stored {
+                Some(StoredWindowState {
+                    state:
+                        WindowState::InProgress {
+                            holder: ref current_holder,
+                            ..
+                        },
+                    ref version,
+                }) if current_holder == holder => {
+                    if self
+                        .store
+                        .compare_and_swap(&window_id, Some(version), state)?
+                    {
+                        return Ok(());
+                    }
+                }
+                other => {
+                    return Err(anyhow!(
+                        "{} does not hold the lease on window {}: {:?}",
+                        holder,
+                        window_id,
+                        other.map(|stored| stored.state)
+                    ))
+                }
+            }
+        }
+        Err(anyhow!("window {} is being updated too often", window_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        aws_credentials::Provider, http::StaticOauthTokenProvider, logging::setup_test_logging,
+    };
+    use chrono::{NaiveDate, TimeZone};
+    use mockito::{mock, Matcher};
+    use rusoto_mock::MockRequestDispatcher;
+    use tempfile::TempDir;
+
+    fn window() -> AggregationWindow {
+        AggregationWindow {
+            aggregation_id: "kittens-seen".to_owned(),
+            start: NaiveDate::from_ymd(2021, 5, 10).and_hms(0, 0, 0),
+            end: NaiveDate::from_ymd(2021, 5, 10).and_hms(8, 0, 0),
+        }
+    }
+
+    #[test]
+    fn window_id() {
+        assert_eq!(window().id(), "kittens-seen-202105100000-202105100800");
+    }
+
+    #[test]
+    fn window_containing() {
+        let period = Duration::from_secs(8 * 3600);
+        for (hour, minute) in &[(0, 0), (3, 15), (7, 59)] {
+            assert_eq!(
+                AggregationWindow::containing(
+                    "kittens-seen",
+                    NaiveDate::from_ymd(2021, 5, 10).and_hms(*hour, *minute, 0),
+                    period
+                ),
+                window()
+            );
+        }
+        assert_ne!(
+            AggregationWindow::containing(
+                "kittens-seen",
+                NaiveDate::from_ymd(2021, 5, 10).and_hms(8, 0, 0),
+                period
+            ),
+            window()
+        );
+    }
+
+    #[test]
+    fn window_state_encoding() {
+        let state = WindowState::InProgress {
+            holder: "worker".to_owned(),
+            lease_expiry: Utc.ymd(2021, 5, 10).and_hms(9, 0, 0),
+        };
+        let encoded = serde_json::to_value(&state).unwrap();
+        assert_eq!(
+            encoded,
+            json!({
+                "status": "in-progress",
+                "holder": "worker",
+                "lease-expiry": "2021-05-10T09:00:00Z",
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<WindowState>(encoded).unwrap(),
+            state
+        );
+    }
+
+    #[test]
+    fn local_file_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = LocalFileWindowStore::new(temp_dir.path().join("windows.json"));
+
+        assert_eq!(store.get("window").unwrap(), None);
+        assert!(store
+            .compare_and_swap("window", None, &WindowState::Pending)
+            .unwrap());
+        assert!(!store
+            .compare_and_swap("window", None, &WindowState::Pending)
+            .unwrap());
+        let stored = store.get("window").unwrap().unwrap();
+        assert_eq!(stored.state, WindowState::Pending);
+
+        let done = WindowState::Done {
+            sum_part_digest: "digest".to_owned(),
+            completed_at: Utc.ymd(2021, 5, 10).and_hms(9, 0, 0),
+        };
+        assert!(store
+            .compare_and_swap("window", Some(&stored.version), &done)
+            .unwrap());
+        assert!(!store
+            .compare_and_swap("window", Some(&stored.version), &WindowState::Pending)
+            .unwrap());
+        assert_eq!(store.get("window").unwrap().unwrap().state, done);
+        assert_eq!(store.get("other-window").unwrap(), None);
+    }
+
+    #[test]
+    fn registry() {
+        let logger = setup_test_logging();
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("windows.json");
+        let registry = || {
+            WindowRegistry::new(
+                Box::new(LocalFileWindowStore::new(path.clone())),
+                Duration::from_secs(3600),
+                &logger,
+            )
+        };
+        let mut scheduler = registry();
+        let mut worker_a = registry();
+        let mut worker_b = registry();
+        let window = window();
+        let start = Utc.ymd(2021, 5, 10).and_hms(9, 0, 0);
+        let later = |minutes| start + chrono::Duration::minutes(minutes);
+
+        assert_eq!(scheduler.record_pending(&window).unwrap(), None);
+        assert_eq!(
+            scheduler.record_pending(&window).unwrap(),
+            Some(WindowState::Pending)
+        );
+
+        assert_eq!(
+            worker_a.try_start(&window, "a", start).unwrap(),
+            WindowStart::Started
+        );
+        assert_eq!(
+            worker_b.try_start(&window, "b", later(30)).unwrap(),
+            WindowStart::InProgress {
+                holder: "a".to_owned()
+            }
+        );
+
+        // Once a's lease expires, b takes over and a can no longer renew it or
+        // complete the window
+        assert_eq!(
+            worker_b.try_start(&window, "b", later(61)).unwrap(),
+            WindowStart::Started
+        );
+        worker_a.renewed_at = None;
+        worker_a.renew_if_due(&window, "a", later(62)).unwrap_err();
+        worker_a
+            .complete(&window, "a", "digest-a", later(63))
+            .unwrap_err();
+
+        // Abandoning a window lets another worker start it right away
+        worker_b.abandon(&window, "b").unwrap();
+        assert_eq!(
+            scheduler.state(&window).unwrap(),
+            Some(WindowState::Pending)
+        );
+        assert_eq!(
+            worker_a.try_start(&window, "a", later(64)).unwrap(),
+            WindowStart::Started
+        );
+        worker_a.renewed_at = None;
+        worker_a.renew_if_due(&window, "a", later(65)).unwrap();
+        worker_a
+            .complete(&window, "a", "digest-a", later(66))
+            .unwrap();
+
+        // No one starts a window that is done
+        assert_eq!(
+            worker_b.try_start(&window, "b", later(200)).unwrap(),
+            WindowStart::AlreadyDone {
+                sum_part_digest: "digest-a".to_owned()
+            }
+        );
+        assert_eq!(
+            scheduler.record_pending(&window).unwrap(),
+            Some(WindowState::Done {
+                sum_part_digest: "digest-a".to_owned(),
+                completed_at: later(66),
+            })
+        );
+    }
+
+    #[test]
+    fn firestore_store() {
+        let logger = setup_test_logging();
+        let document_path =
+            "/v1/projects/p/databases/(default)/documents/aggregation-windows/window";
+        let mut store = FirestoreWindowStore::new_with_token_provider(
+            "p",
+            "aggregation-windows",
+            Box::new(StaticOauthTokenProvider::from("fake-token".to_owned())),
+            Url::parse(&mockito::server_url()).unwrap(),
+            &logger,
+        )
+        .unwrap();
+
+        let mocked_get_missing = mock("GET", document_path)
+            .match_header("Authorization", "Bearer fake-token")
+            .with_status(404)
+            .with_body(json!({ "error": { "status": "NOT_FOUND" } }).to_string())
+            .expect(1)
+            .create();
+        assert_eq!(store.get("window").unwrap(), None);
+        mocked_get_missing.assert();
+
+        let commit_path = "/v1/projects/p/databases/(default)/documents:commit";
+        let mocked_create = mock("POST", commit_path)
+            .match_header("Authorization", "Bearer fake-token")
+            .match_body(Matcher::Json(json!({
+                "writes": [{
+                    "update": {
+                        "name": "projects/p/databases/(default)/documents/aggregation-windows/window",
+                        "fields": { "state": { "stringValue": "{\"status\":\"pending\"}" } },
+                    },
+                    "currentDocument": { "exists": false },
+                }],
+            })))
+            .with_status(200)
+            .with_body("{}")
+            .expect(1)
+            .create();
+        assert!(store
+            .compare_and_swap("window", None, &WindowState::Pending)
+            .unwrap());
+        mocked_create.assert();
+
+        let mocked_get = mock("GET", document_path)
+            .with_status(200)
+            .with_body(
+                json!({
+                    "name": "projects/p/databases/(default)/documents/aggregation-windows/window",
+                    "fields": { "state": { "stringValue": "{\"status\":\"pending\"}" } },
+                    "createTime": "2021-05-10T09:00:00.000000Z",
+                    "updateTime": "2021-05-10T09:00:00.000000Z",
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+        assert_eq!(
+            store.get("window").unwrap(),
+            Some(StoredWindowState {
+                state: WindowState::Pending,
+                version: "2021-05-10T09:00:00.000000Z".to_owned(),
+            })
+        );
+        mocked_get.assert();
+
+        let mocked_conflict = mock("POST", commit_path)
+            .match_body(Matcher::PartialJson(json!({
+                "writes": [{
+                    "currentDocument": { "updateTime": "2021-05-10T09:00:00.000000Z" },
+                }],
+            })))
+            .with_status(400)
+            .with_body(json!({ "error": { "status": "FAILED_PRECONDITION" } }).to_string())
+            .expect(1)
+            .create();
+        assert!(!store
+            .compare_and_swap(
+                "window",
+                Some("2021-05-10T09:00:00.000000Z"),
+                &WindowState::Pending
+            )
+            .unwrap());
+        mocked_conflict.assert();
+    }
+
+    #[test]
+    fn dynamodb_store() {
+        let logger = setup_test_logging();
+        let store = |status, body: serde_json::Value, target: &'static str| {
+            let dispatcher = MockRequestDispatcher::with_status(status)
+                .with_json_body(body)
+                .with_request_checker(move |request| {
+                    assert_eq!(request.service, "dynamodb");
+                    assert_eq!(
+                        request.headers.get("x-amz-target"),
+                        Some(&vec![target.as_bytes().to_vec()])
+                    );
+                });
+            DynamoDbWindowStore::new(
+                "windows",
+                "us-west-2",
+                rusoto_core::Client::new_with(Provider::new_mock(), dispatcher),
+                &logger,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            store(200, json!({}), "DynamoDB_20120810.GetItem")
+                .get("window")
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            store(
+                200,
+                json!({
+                    "Item": {
+                        "window_id": { "S": "window" },
+                        "state": { "S": "{\"status\":\"pending\"}" },
+                        "version": { "N": "3" },
+                    },
+                }),
+                "DynamoDB_20120810.GetItem"
+            )
+            .get("window")
+            .unwrap(),
+            Some(StoredWindowState {
+                state: WindowState::Pending,
+                version: "3".to_owned(),
+            })
+        );
+
+        assert!(store(200, json!({}), "DynamoDB_20120810.PutItem")
+            .compare_and_swap("window", Some("3"), &WindowState::Pending)
+            .unwrap());
+        assert!(!store(
+            400,
+            json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                "message": "The conditional request failed",
+            }),
+            "DynamoDB_20120810.PutItem"
+        )
+        .compare_and_swap("window", Some("3"), &WindowState::Pending)
+        .unwrap());
+        store(
+            400,
+            json!({ "__type": "com.amazonaws.dynamodb.v20120810#ValidationException" }),
+            "DynamoDB_20120810.PutItem",
+        )
+        .compare_and_swap("window", None, &WindowState::Pending)
+        .unwrap_err();
+    }
+}
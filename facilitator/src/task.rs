@@ -1,18 +1,24 @@
 mod pubsub;
 mod sqs;
 
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use slog::{Key, Record, Serializer, Value};
 use std::{
+    collections::HashMap,
     fmt,
     fmt::{Debug, Display},
+    io::Write,
     time::Duration,
 };
 use uuid::Uuid;
 
+use crate::{transport::Transport, TaskError};
+
 pub use pubsub::GcpPubSubTaskQueue;
-pub use sqs::AwsSqsTaskQueue;
+pub use sqs::{AwsSqsTaskQueue, DeadLetterQueue};
 
 /// A queue of tasks to be executed
 pub trait TaskQueue<T: Task>: Debug {
@@ -25,6 +31,10 @@ pub trait TaskQueue<T: Task>: Debug {
     /// re-delivered via dequeue().
     fn dequeue(&mut self) -> Result<Option<TaskHandle<T>>>;
 
+    /// Add a task to the queue, from which it will eventually be dequeued by
+    /// some worker.
+    fn enqueue(&mut self, task: &T) -> Result<()>;
+
     /// Signal to the task queue that the task has been handled and should be
     /// removed from the queue.
     fn acknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()>;
@@ -33,6 +43,15 @@ pub trait TaskQueue<T: Task>: Debug {
     /// retried later.
     fn nacknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()>;
 
+    /// Signal to the task queue that the task was not handled, but not because
+    /// it failed, e.g. because the worker is shutting down, so the task should
+    /// be made available to other workers immediately.
+    fn release_task(&mut self, handle: TaskHandle<T>) -> Result<()>;
+
+    /// Verify that the task queue can be reached with this queue's credentials,
+    /// without dequeuing any tasks. Returns an error if it cannot.
+    fn check_reachable(&mut self) -> Result<()>;
+
     /// Signal to the task queue that more time is needed to handle the task.
     fn extend_task_deadline(&mut self, handle: &TaskHandle<T>, increment: &Duration) -> Result<()>;
 
@@ -61,16 +80,143 @@ pub trait TaskQueue<T: Task>: Debug {
     }
 }
 
+/// The shortest delay before a nacknowledged task is redelivered.
+const MINIMUM_NACK_BACKOFF: Duration = Duration::from_secs(10);
+
+/// The longest delay before a nacknowledged task is redelivered. Both PubSub
+/// ack deadlines and SQS visibility timeouts may be set this high.
+const MAXIMUM_NACK_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Computes how long a task that failed should be hidden from workers before
+/// being redelivered, given how many times it has been delivered. The delay
+/// doubles with each delivery so that a task that fails repeatedly, perhaps
+/// because some dependency is unavailable, does not consume all of the
+/// workers' time.
+pub(crate) fn nack_backoff(delivery_attempt: Option<u32>) -> Duration {
+    let exponent = delivery_attempt.unwrap_or(1).saturating_sub(1).min(16);
+    (MINIMUM_NACK_BACKOFF * 2u32.pow(exponent)).min(MAXIMUM_NACK_BACKOFF)
+}
+
+/// The version of the task message schema that this facilitator understands.
+/// Task messages that don't specify a version are assumed to be of version 1,
+/// which is what the workflow-manager has always sent. Messages of any later
+/// version are rejected, since they may describe work this facilitator does
+/// not know how to do correctly.
+pub const TASK_SCHEMA_VERSION: u32 = 1;
+
+/// Returns the W3C Trace Context traceparent for everything that happens to a
+/// batch. Intake tasks for the batch carry it, so that the intake is traced as
+/// part of it, and it is carried alongside the batch in aggregation tasks, so
+/// that aggregating the batch is traced as part of it too. Its trace and parent
+/// span IDs are derived from the aggregation and batch IDs so that it can be
+/// recomputed when scheduling the aggregation, which may be long after the
+/// intake was scheduled. The workflow-manager derives it the same way.
+/// https://www.w3.org/TR/trace-context/#traceparent-header
+pub fn batch_traceparent(aggregation_id: &str, batch_id: &str) -> String {
+    let digest = Sha256::digest(format!("{}/{}", aggregation_id, batch_id).as_bytes());
+    traceparent(&digest[..16], &digest[16..24])
+}
+
+/// Returns a W3C Trace Context traceparent that starts a new trace, with random
+/// trace and parent span IDs.
+pub fn new_traceparent() -> String {
+    traceparent(&rand::random::<[u8; 16]>(), &rand::random::<[u8; 8]>())
+}
+
+/// Formats a sampled, version 00 traceparent.
+fn traceparent(trace_id: &[u8], parent_id: &[u8]) -> String {
+    format!("00-{}-{}-01", hex::encode(trace_id), hex::encode(parent_id))
+}
+
+fn default_task_version() -> u32 {
+    1
+}
+
 /// Represents a task that can be assigned to a worker
-pub trait Task: Debug + Display + Sized + serde::de::DeserializeOwned + Clone {}
+pub trait Task: Debug + Display + Sized + serde::de::DeserializeOwned + Serialize + Clone {
+    /// Returns a string that uniquely identifies the work described by the
+    /// task, and which is suitable for use in storage keys.
+    fn key(&self) -> String;
+
+    /// Returns the version of the task message schema the task was written
+    /// with.
+    fn version(&self) -> u32;
+
+    /// Returns the base64 encoded HMAC over the task, if it has one.
+    fn hmac(&self) -> Option<&str>;
+
+    /// Sets or clears the HMAC over the task.
+    fn set_hmac(&mut self, hmac: Option<String>);
+
+    /// Computes the HMAC-SHA256 over the task with the provided key and sets
+    /// it on the task, as the workflow-manager does when sending it.
+    fn sign(&mut self, key: &[u8]) -> Result<()> {
+        let mac = task_mac(self, key)?;
+        self.set_hmac(Some(base64::encode(mac.finalize().into_bytes())));
+        Ok(())
+    }
+
+    /// Checks that the task is of a version this facilitator understands and,
+    /// if a key is provided, that it carries a valid HMAC made with that key,
+    /// so that tasks that did not come from the trusted workflow-manager or
+    /// were tampered with in the queue are rejected. Failures are reported as
+    /// TaskError.
+    fn verify(&self, key: Option<&[u8]>) -> Result<()> {
+        if self.version() > TASK_SCHEMA_VERSION {
+            return Err(TaskError::UnsupportedTaskVersion {
+                version: self.version(),
+                supported: TASK_SCHEMA_VERSION,
+            }
+            .into());
+        }
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let hmac = self
+            .hmac()
+            .ok_or_else(|| TaskError::UnauthenticatedTask("task has no HMAC".to_owned()))?;
+        let hmac = base64::decode(hmac)
+            .map_err(|e| TaskError::UnauthenticatedTask(format!("malformed HMAC: {}", e)))?;
+        task_mac(self, key)?
+            .verify(&hmac)
+            .map_err(|_| TaskError::UnauthenticatedTask("HMAC does not match task".to_owned()))?;
+        Ok(())
+    }
+}
+
+/// Returns an HMAC-SHA256 instance into which the task's canonical encoding
+/// has been fed. The canonical encoding is the task's JSON encoding, without
+/// the hmac field, with absent optional fields left out, object keys in
+/// lexicographic order and no insignificant whitespace.
+fn task_mac<T: Task>(task: &T, key: &[u8]) -> Result<Hmac<Sha256>> {
+    let mut task = task.clone();
+    task.set_hmac(None);
+    // serde_json::Value keeps object keys sorted
+    let canonical =
+        serde_json::to_vec(&serde_json::to_value(&task).context("failed to encode task to JSON")?)
+            .context("failed to encode task to JSON")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .map_err(|e| anyhow::anyhow!("invalid task HMAC key: {}", e))?;
+    mac.update(&canonical);
+    Ok(mac)
+}
 
 /// Represents an intake batch task to be executed
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct IntakeBatchTask {
+    /// The version of the task message schema
+    #[serde(default = "default_task_version")]
+    pub version: u32,
     /// The trace identifier for the intake
     /// TODO: https://github.com/abetterinternet/prio-server/issues/452
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub trace_id: Option<Uuid>,
+    /// The W3C Trace Context traceparent of the batch (see batch_traceparent),
+    /// which the intake is traced as part of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
     /// The identifier for the aggregation
     pub aggregation_id: String,
     /// The identifier of the batch, typically a UUID
@@ -78,9 +224,37 @@ pub struct IntakeBatchTask {
     /// The UTC timestamp on the batch, with minute precision, formatted like
     /// "2006/01/02/15/04"
     pub date: String,
+    /// The locality whose batch this is, which selects the arguments with
+    /// which a worker serving several localities handles the task. Tasks
+    /// without a locality are handled with the worker's own arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locality: Option<String>,
+    /// The base64 encoded HMAC-SHA256 over the task, made by the
+    /// workflow-manager
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmac: Option<String>,
 }
 
-impl Task for IntakeBatchTask {}
+impl Task for IntakeBatchTask {
+    fn key(&self) -> String {
+        format!(
+            "intake/{}/{}/{}",
+            self.aggregation_id, self.date, self.batch_id
+        )
+    }
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn hmac(&self) -> Option<&str> {
+        self.hmac.as_deref()
+    }
+
+    fn set_hmac(&mut self, hmac: Option<String>) {
+        self.hmac = hmac;
+    }
+}
 
 impl Display for IntakeBatchTask {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -96,12 +270,20 @@ impl Display for IntakeBatchTask {
 }
 
 /// Represents an aggregation task to be executed
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct AggregationTask {
+    /// The version of the task message schema
+    #[serde(default = "default_task_version")]
+    pub version: u32,
     /// The trace identifier for the aggregation
     /// TODO: https://github.com/abetterinternet/prio-server/issues/452
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub trace_id: Option<Uuid>,
+    /// The W3C Trace Context traceparent of the trace that the aggregation is
+    /// traced as part of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
     /// The identifier for the aggregation
     pub aggregation_id: String,
     /// The start of the range of time covered by the aggregation in UTC, with
@@ -112,9 +294,39 @@ pub struct AggregationTask {
     pub aggregation_end: String,
     // The list of batches aggregated by this task
     pub batches: Vec<Batch>,
+    /// The locality whose batches are aggregated, which selects the arguments
+    /// with which a worker serving several localities handles the task. Tasks
+    /// without a locality are handled with the worker's own arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locality: Option<String>,
+    /// The base64 encoded HMAC-SHA256 over the task, made by the
+    /// workflow-manager
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmac: Option<String>,
 }
 
-impl Task for AggregationTask {}
+impl Task for AggregationTask {
+    fn key(&self) -> String {
+        format!(
+            "aggregation/{}/{}-{}",
+            self.aggregation_id,
+            self.aggregation_start.replace('/', ""),
+            self.aggregation_end.replace('/', "")
+        )
+    }
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn hmac(&self) -> Option<&str> {
+        self.hmac.as_deref()
+    }
+
+    fn set_hmac(&mut self, hmac: Option<String>) {
+        self.hmac = hmac;
+    }
+}
 
 impl Display for AggregationTask {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -133,7 +345,7 @@ impl Display for AggregationTask {
 }
 
 /// Represents a batch included in an aggregation
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct Batch {
     /// The identifier of the batch. Typically a UUID.
@@ -141,6 +353,10 @@ pub struct Batch {
     /// The timestamp on the batch, in UTC, with minute precision, formatted
     /// like "2006/01/02/15/04".
     pub time: String,
+    /// The W3C Trace Context traceparent of the batch (see batch_traceparent),
+    /// which aggregating the batch is traced as part of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<String>,
 }
 
 /// A TaskHandle wraps a Task along with whatever metadata is needed by a
@@ -149,10 +365,21 @@ pub struct Batch {
 pub struct TaskHandle<T: Task> {
     /// The acknowledgment ID for the task
     acknowledgment_id: String,
+    /// How many times the task has been delivered, including this delivery,
+    /// if the task queue tracks it
+    delivery_attempt: Option<u32>,
     /// The task
     pub task: T,
 }
 
+impl<T: Task> TaskHandle<T> {
+    /// Returns how many times the task has been delivered, including this
+    /// delivery, if the task queue tracks it.
+    pub fn delivery_attempt(&self) -> Option<u32> {
+        self.delivery_attempt
+    }
+}
+
 // Implementing slog::Value allows us to put TaskHandles in structured events
 // with minimal ceremony.
 impl<T: Task> Value for TaskHandle<T> {
@@ -166,3 +393,289 @@ impl<T: Task> Display for TaskHandle<T> {
         write!(f, "ack ID: {}\ntask: {}", self.acknowledgment_id, self.task)
     }
 }
+
+/// Counts failures to handle tasks so that a task that fails every time it is
+/// handled, like one describing a malformed batch, can be quarantined instead
+/// of being retried forever. Failures are counted both by this worker and by
+/// the task queue, if it tracks delivery attempts, so that failures in other
+/// workers or before a restart are accounted for.
+#[derive(Debug)]
+pub struct TaskFailureTracker {
+    max_failures: u32,
+    failures: HashMap<String, u32>,
+}
+
+impl TaskFailureTracker {
+    pub fn new(max_failures: u32) -> Self {
+        TaskFailureTracker {
+            max_failures,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Records a failure to handle the task, returning true if the task has
+    /// now failed often enough that it should be quarantined.
+    pub fn record_failure<T: Task>(&mut self, handle: &TaskHandle<T>) -> bool {
+        let local_failures = self.failures.entry(handle.task.key()).or_default();
+        *local_failures += 1;
+        let failures = (*local_failures).max(handle.delivery_attempt.unwrap_or(0));
+        failures >= self.max_failures
+    }
+
+    /// Discards the failure count for the task, once it has been handled or
+    /// quarantined.
+    pub fn forget<T: Task>(&mut self, handle: &TaskHandle<T>) {
+        self.failures.remove(&handle.task.key());
+    }
+}
+
+/// Writes a marker object describing the task and the error that caused it to
+/// be quarantined into the provided transport, so that operators can find and
+/// investigate the task after it has been removed from the queue.
+pub fn quarantine_task<T: Task>(
+    transport: &mut dyn Transport,
+    handle: &TaskHandle<T>,
+    error: &anyhow::Error,
+    trace_id: &str,
+) -> Result<()> {
+    let key = format!("quarantine/{}.json", handle.task.key());
+    let marker = serde_json::to_vec_pretty(&serde_json::json!({
+        "task": handle.task.to_string(),
+        "delivery-attempt": handle.delivery_attempt,
+        "error": format!("{:?}", error),
+    }))?;
+
+    let mut writer = transport.put(&key, trace_id)?;
+    if let Err(e) = writer.write_all(&marker) {
+        writer.cancel_upload()?;
+        return Err(e).context(format!("failed to write quarantine marker {}", key));
+    }
+    writer
+        .complete_upload()
+        .context(format!("failed to complete quarantine marker {}", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::LocalFileTransport;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn nack_backoff_grows_exponentially() {
+        assert_eq!(nack_backoff(None), Duration::from_secs(10));
+        assert_eq!(nack_backoff(Some(0)), Duration::from_secs(10));
+        assert_eq!(nack_backoff(Some(1)), Duration::from_secs(10));
+        assert_eq!(nack_backoff(Some(2)), Duration::from_secs(20));
+        assert_eq!(nack_backoff(Some(4)), Duration::from_secs(80));
+        assert_eq!(nack_backoff(Some(7)), Duration::from_secs(600));
+        assert_eq!(nack_backoff(Some(u32::MAX)), Duration::from_secs(600));
+    }
+
+    fn intake_task_handle(delivery_attempt: Option<u32>) -> TaskHandle<IntakeBatchTask> {
+        TaskHandle {
+            acknowledgment_id: "ack-id".to_owned(),
+            delivery_attempt,
+            task: IntakeBatchTask {
+                version: 1,
+                trace_id: None,
+                traceparent: None,
+                aggregation_id: "kittens-seen".to_owned(),
+                batch_id: "b8a5579a-f984-460a-a42d-2813cbf57771".to_owned(),
+                date: "2020/09/11/21/11".to_owned(),
+                locality: None,
+                hmac: None,
+            },
+        }
+    }
+
+    #[test]
+    fn task_keys() {
+        assert_eq!(
+            intake_task_handle(None).task.key(),
+            "intake/kittens-seen/2020/09/11/21/11/b8a5579a-f984-460a-a42d-2813cbf57771"
+        );
+        let task = AggregationTask {
+            version: 1,
+            trace_id: None,
+            traceparent: None,
+            aggregation_id: "kittens-seen".to_owned(),
+            aggregation_start: "2020/09/11/20/00".to_owned(),
+            aggregation_end: "2020/09/11/22/00".to_owned(),
+            batches: vec![],
+            locality: None,
+            hmac: None,
+        };
+        assert_eq!(
+            task.key(),
+            "aggregation/kittens-seen/202009112000-202009112200"
+        );
+    }
+
+    #[test]
+    fn task_messages() {
+        // Messages from before the schema was versioned are version 1
+        let task: IntakeBatchTask = serde_json::from_str(
+            r#"{"trace-id": null, "aggregation-id": "kittens-seen",
+            "batch-id": "b8a5579a-f984-460a-a42d-2813cbf57771", "date": "2020/09/11/21/11"}"#,
+        )
+        .unwrap();
+        assert_eq!(task, intake_task_handle(None).task);
+        task.verify(None).unwrap();
+
+        let task: AggregationTask = serde_json::from_str(
+            r#"{"version": 1, "aggregation-id": "kittens-seen",
+            "aggregation-start": "2020/09/11/20/00", "aggregation-end": "2020/09/11/22/00",
+            "batches": [{"id": "b8a5579a-f984-460a-a42d-2813cbf57771", "time": "2020/09/11/21/11"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(task.batches.len(), 1);
+        assert_eq!(task.batches[0].traceparent, None);
+        assert_eq!(task.locality, None);
+        task.verify(None).unwrap();
+
+        let task: AggregationTask = serde_json::from_str(
+            r#"{"aggregation-id": "kittens-seen",
+            "aggregation-start": "2020/09/11/20/00", "aggregation-end": "2020/09/11/22/00",
+            "batches": [{"id": "b8a5579a-f984-460a-a42d-2813cbf57771", "time": "2020/09/11/21/11",
+            "traceparent": "00-2e0831b886ed1ab0efc84bb2ad91a46f-d93bdb2a1f64e301-01"}],
+            "traceparent": "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            task.batches[0].traceparent.as_deref(),
+            Some("00-2e0831b886ed1ab0efc84bb2ad91a46f-d93bdb2a1f64e301-01")
+        );
+        assert_eq!(
+            task.traceparent.as_deref(),
+            Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+        );
+
+        let task: IntakeBatchTask = serde_json::from_str(
+            r#"{"aggregation-id": "kittens-seen", "locality": "zc",
+            "batch-id": "b8a5579a-f984-460a-a42d-2813cbf57771", "date": "2020/09/11/21/11"}"#,
+        )
+        .unwrap();
+        assert_eq!(task.locality.as_deref(), Some("zc"));
+
+        let mut task = intake_task_handle(None).task;
+        task.version = 2;
+        let err = task.verify(None).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TaskError>(),
+            Some(&TaskError::UnsupportedTaskVersion {
+                version: 2,
+                supported: 1
+            })
+        );
+    }
+
+    #[test]
+    fn batch_traceparents() {
+        // The workflow-manager must derive the same traceparent for a batch
+        let traceparent = batch_traceparent("kittens-seen", "b8a5579a-f984-460a-a42d-2813cbf57771");
+        assert_eq!(
+            traceparent,
+            "00-2e0831b886ed1ab0efc84bb2ad91a46f-d93bdb2a1f64e301-01"
+        );
+        assert_ne!(
+            traceparent,
+            batch_traceparent("kittens-seen", "c0b1f4a4-1b50-4f02-9ab0-5b6cbb0b4a6f")
+        );
+        assert_ne!(
+            traceparent,
+            batch_traceparent("puppies-seen", "b8a5579a-f984-460a-a42d-2813cbf57771")
+        );
+
+        let traceparent = new_traceparent();
+        assert_eq!(traceparent.len(), 55);
+        assert!(traceparent.starts_with("00-") && traceparent.ends_with("-01"));
+        assert_ne!(traceparent, new_traceparent());
+    }
+
+    #[test]
+    fn task_hmac() {
+        let key = b"workflow-manager-key";
+        let unauthenticated = |result: Result<()>| {
+            assert_matches!(
+                result.unwrap_err().downcast_ref::<TaskError>(),
+                Some(TaskError::UnauthenticatedTask(_))
+            );
+        };
+
+        let mut task = intake_task_handle(None).task;
+        unauthenticated(task.verify(Some(key)));
+        task.sign(key).unwrap();
+        task.verify(Some(key)).unwrap();
+        unauthenticated(task.verify(Some(b"some-other-key")));
+
+        // The HMAC survives a round trip through JSON, regardless of the order
+        // in which fields appear in the message
+        let json = serde_json::to_value(&task).unwrap();
+        let reordered = format!(
+            r#"{{"hmac": {}, "date": {}, "batch-id": {}, "aggregation-id": {}}}"#,
+            json["hmac"], json["date"], json["batch-id"], json["aggregation-id"]
+        );
+        let decoded: IntakeBatchTask = serde_json::from_str(&reordered).unwrap();
+        decoded.verify(Some(key)).unwrap();
+
+        // Tampered tasks are rejected
+        let mut tampered = task.clone();
+        tampered.batch_id = "e4b9a2a0-0c3a-4f5e-9a3d-5f6f1f8d1c2b".to_owned();
+        unauthenticated(tampered.verify(Some(key)));
+        let mut tampered = task.clone();
+        tampered.trace_id = Some(Uuid::new_v4());
+        unauthenticated(tampered.verify(Some(key)));
+        let mut tampered = task.clone();
+        tampered.traceparent = Some(new_traceparent());
+        unauthenticated(tampered.verify(Some(key)));
+        let mut tampered = task.clone();
+        tampered.locality = Some("zc".to_owned());
+        unauthenticated(tampered.verify(Some(key)));
+        let mut tampered = task;
+        tampered.hmac = Some("not base64!".to_owned());
+        unauthenticated(tampered.verify(Some(key)));
+    }
+
+    #[test]
+    fn failure_tracker() {
+        let mut tracker = TaskFailureTracker::new(3);
+
+        let handle = intake_task_handle(None);
+        assert!(!tracker.record_failure(&handle));
+        assert!(!tracker.record_failure(&handle));
+        assert!(tracker.record_failure(&handle));
+
+        tracker.forget(&handle);
+        assert!(!tracker.record_failure(&handle));
+
+        // Delivery attempts counted by the queue are taken into account
+        let mut tracker = TaskFailureTracker::new(3);
+        assert!(tracker.record_failure(&intake_task_handle(Some(3))));
+    }
+
+    #[test]
+    fn quarantine_marker() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        let handle = intake_task_handle(Some(5));
+
+        quarantine_task(
+            &mut transport,
+            &handle,
+            &anyhow::anyhow!("malformed batch"),
+            "None",
+        )
+        .unwrap();
+
+        let marker: serde_json::Value = serde_json::from_reader(
+            transport
+                .get(&format!("quarantine/{}.json", handle.task.key()), "None")
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(marker["task"], handle.task.to_string());
+        assert_eq!(marker["delivery-attempt"], 5);
+        assert_eq!(marker["error"], "malformed batch");
+    }
+}
@@ -1,15 +1,14 @@
 use crate::{
     config::Identity,
     gcp_oauth::GcpOauthTokenProvider,
-    http::{Method, RequestParameters, RetryingAgent},
+    http::{Method, RequestClass, RequestParameters, RetryingAgent},
     logging::event,
-    task::{Task, TaskHandle, TaskQueue},
+    task::{nack_backoff, Task, TaskHandle, TaskQueue},
 };
 use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
 use slog::{info, o, Logger};
 use std::{io::Cursor, marker::PhantomData, time::Duration};
-use ureq::AgentBuilder;
 use url::Url;
 
 const PUBSUB_API_BASE_URL: &str = "https://pubsub.googleapis.com";
@@ -30,6 +29,38 @@ fn gcp_pubsub_pull_url(
     ))
 }
 
+// API reference: https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.topics/publish
+fn gcp_pubsub_publish_url(
+    pubsub_api_endpoint: &str,
+    gcp_project_id: &str,
+    topic_id: &str,
+) -> Result<Url> {
+    let request_url = format!(
+        "{}/v1/projects/{}/topics/{}:publish",
+        pubsub_api_endpoint, gcp_project_id, topic_id
+    );
+    Url::parse(&request_url).context(format!(
+        "failed to parse gcp_pubsub_publish_url: {}",
+        request_url
+    ))
+}
+
+// API reference: https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.subscriptions/get
+fn gcp_pubsub_subscription_url(
+    pubsub_api_endpoint: &str,
+    gcp_project_id: &str,
+    subscription_id: &str,
+) -> Result<Url> {
+    let request_url = format!(
+        "{}/v1/projects/{}/subscriptions/{}",
+        pubsub_api_endpoint, gcp_project_id, subscription_id
+    );
+    Url::parse(&request_url).context(format!(
+        "failed to parse gcp_pubsub_subscription_url: {}",
+        request_url
+    ))
+}
+
 // API reference: https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.subscriptions/acknowledge
 fn gcp_pubsub_ack_url(
     pubsub_api_endpoint: &str,
@@ -79,6 +110,8 @@ struct PullResponse {
 struct ReceivedMessage {
     ack_id: String,
     message: GcpPubSubMessage,
+    /// Only present if the subscription has a dead letter policy
+    delivery_attempt: Option<u32>,
 }
 
 /// The portion of a PubSubMessage that we are interested in. See API doc for
@@ -118,15 +151,8 @@ impl<T: Task> GcpPubSubTaskQueue<T> {
             event::TASK_QUEUE_ID => subscription_id.to_owned(),
             event::IDENTITY => identity.unwrap_or("default identity").to_owned(),
         ));
-        let ureq_agent = AgentBuilder::new()
-            // Empirically, if there are no messages available in the
-            // subscription, the PubSub API will wait about 90 seconds to send
-            // an HTTP 200 with an empty JSON body. We set higher timeouts than
-            // usual to allow for this.
-            .timeout(Duration::from_secs(180))
-            .build();
         let retrying_agent = RetryingAgent::new(
-            ureq_agent,
+            RequestClass::TaskQueue,
             // Per Google documentation, 429 Too Many Requests should be retried
             // with exponential backoff
             // https://cloud.google.com/pubsub/docs/reference/error-codes
@@ -178,15 +204,16 @@ impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
             .send_json_request(
                 &self.logger,
                 &request,
-                &ureq::json!({
+                &serde_json::json!({
                     // Dequeue one task at a time
                     "maxMessages": 1
                 }),
             )
             .context("failed to pull messages from PubSub topic")?;
 
-        let response = http_response
-            .into_json::<PullResponse>()
+        let response: PullResponse = self
+            .agent
+            .read_json(http_response)
             .context("failed to deserialize response from PubSub API")?;
 
         let received_messages = match response.received_messages {
@@ -215,11 +242,41 @@ impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
         let handle = TaskHandle {
             task,
             acknowledgment_id: received_messages[0].ack_id.clone(),
+            delivery_attempt: received_messages[0].delivery_attempt,
         };
 
         Ok(Some(handle))
     }
 
+    fn enqueue(&mut self, task: &T) -> Result<()> {
+        info!(self.logger, "publish task"; "task_key" => task.key());
+
+        // Tasks are published to the topic with the same ID as the
+        // subscription, as created by the workflow-manager.
+        let request = self.agent.prepare_request(RequestParameters {
+            url: gcp_pubsub_publish_url(
+                &self.pubsub_api_endpoint,
+                &self.gcp_project_id,
+                &self.subscription_id,
+            )?,
+            method: Method::Post,
+            token_provider: Some(&mut self.oauth_token_provider),
+        })?;
+
+        let task_json = serde_json::to_vec(task).context("failed to encode task to JSON")?;
+        self.agent
+            .send_json_request(
+                &self.logger,
+                &request,
+                &serde_json::json!({
+                    "messages": [{"data": base64::encode(&task_json)}]
+                }),
+            )
+            .context("failed to publish task to PubSub topic")?;
+
+        Ok(())
+    }
+
     fn acknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
         let logger = self.logger.new(o!(
             event::TASK_ACKNOWLEDGEMENT_ID => handle.acknowledgment_id.to_owned(),
@@ -240,7 +297,7 @@ impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
             .send_json_request(
                 &logger,
                 &request,
-                &ureq::json!({
+                &serde_json::json!({
                     "ackIds": [handle.acknowledgment_id]
                 }),
             )
@@ -250,15 +307,50 @@ impl<T: Task> TaskQueue<T> for GcpPubSubTaskQueue<T> {
     }
 
     fn nacknowledge_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
+        // Rather than setting the ack deadline to 0, which makes PubSub
+        // redeliver the message immediately, we back off so that a task that
+        // keeps failing is not retried in a tight loop.
+        let backoff = nack_backoff(handle.delivery_attempt);
         info!(
             self.logger, "nacknowledging task";
             event::TASK_ACKNOWLEDGEMENT_ID => &handle.acknowledgment_id,
+            "backoff" => format!("{:?}", backoff),
         );
 
-        self.modify_ack_deadline(&handle, &Duration::from_secs(0))
+        self.modify_ack_deadline(&handle, &backoff)
             .context("failed to nacknowledge task")
     }
 
+    fn release_task(&mut self, handle: TaskHandle<T>) -> Result<()> {
+        info!(
+            self.logger, "releasing task";
+            event::TASK_ACKNOWLEDGEMENT_ID => &handle.acknowledgment_id,
+        );
+
+        self.modify_ack_deadline(&handle, &Duration::from_secs(0))
+            .context("failed to release task")
+    }
+
+    fn check_reachable(&mut self) -> Result<()> {
+        // Fetching the subscription requires an OAuth token, so this also
+        // checks that we can obtain one.
+        let request = self.agent.prepare_request(RequestParameters {
+            url: gcp_pubsub_subscription_url(
+                &self.pubsub_api_endpoint,
+                &self.gcp_project_id,
+                &self.subscription_id,
+            )?,
+            method: Method::Get,
+            token_provider: Some(&mut self.oauth_token_provider),
+        })?;
+
+        self.agent
+            .call(&self.logger, &request)
+            .context("failed to get PubSub subscription")?;
+
+        Ok(())
+    }
+
     fn extend_task_deadline(&mut self, handle: &TaskHandle<T>, increment: &Duration) -> Result<()> {
         info!(
             self.logger, "extending deadline on task";
@@ -303,7 +395,7 @@ impl<T: Task> GcpPubSubTaskQueue<T> {
             .send_json_request(
                 &logger,
                 &request,
-                &ureq::json!({
+                &serde_json::json!({
                     "ackIds": [handle.acknowledgment_id],
                     "ackDeadlineSeconds": ack_deadline.as_secs(),
                 }),
@@ -316,3 +408,58 @@ impl<T: Task> GcpPubSubTaskQueue<T> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize_pull_response() {
+        let response: PullResponse = serde_json::from_str(
+            r#"
+{
+    "receivedMessages": [
+        {
+            "ackId": "ack-id",
+            "message": {
+                "data": "e30=",
+                "messageId": "message-id",
+                "publishTime": "2021-01-01T00:00:00Z"
+            },
+            "deliveryAttempt": 3
+        }
+    ]
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            PullResponse {
+                received_messages: Some(vec![ReceivedMessage {
+                    ack_id: "ack-id".to_owned(),
+                    message: GcpPubSubMessage {
+                        data: "e30=".to_owned(),
+                        message_id: "message-id".to_owned(),
+                        publish_time: "2021-01-01T00:00:00Z".to_owned(),
+                    },
+                    delivery_attempt: Some(3),
+                }]),
+            }
+        );
+
+        // Subscriptions without a dead letter policy do not report delivery
+        // attempts
+        let response: PullResponse = serde_json::from_str(
+            r#"{"receivedMessages": [{"ackId": "ack-id", "message": {"data": "e30=", "messageId": "message-id", "publishTime": "2021-01-01T00:00:00Z"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.received_messages.unwrap()[0].delivery_attempt,
+            None
+        );
+
+        let response: PullResponse = serde_json::from_str("{}").unwrap();
+        assert_eq!(response.received_messages, None);
+    }
+}
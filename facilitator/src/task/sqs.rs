@@ -2,19 +2,38 @@ use anyhow::{anyhow, Context, Result};
 use derivative::Derivative;
 use rusoto_core::Region;
 use rusoto_sqs::{
-    ChangeMessageVisibilityRequest, DeleteMessageRequest, ReceiveMessageRequest, Sqs, SqsClient,
+    ChangeMessageVisibilityRequest, DeleteMessageRequest, GetQueueAttributesRequest, Message,
+    ReceiveMessageRequest, SendMessageRequest, Sqs, SqsClient,
+};
+use slog::{info, o, warn, Logger};
+use std::{
+    collections::HashMap, convert::TryFrom, marker::PhantomData, str::FromStr, time::Duration,
 };
-use slog::{info, o, Logger};
-use std::{convert::TryFrom, marker::PhantomData, str::FromStr, time::Duration};
 use tokio::runtime::Runtime;
 
 use crate::aws_credentials;
 use crate::{
     aws_credentials::{basic_runtime, retry_request},
     logging::event,
-    task::{Task, TaskHandle, TaskQueue},
+    task::{nack_backoff, Task, TaskHandle, TaskQueue},
 };
 
+/// The SQS message system attribute counting how many times a message has been
+/// received.
+/// https://docs.aws.amazon.com/AWSSimpleQueueService/latest/APIReference/API_ReceiveMessage.html
+const APPROXIMATE_RECEIVE_COUNT: &str = "ApproximateReceiveCount";
+
+/// Where tasks that fail repeatedly are sent so that they stop being delivered
+/// to workers and can be inspected by an operator.
+#[derive(Clone, Debug)]
+pub struct DeadLetterQueue {
+    /// URL of the SQS queue to send failed tasks to
+    pub queue_url: String,
+    /// Tasks are sent to the dead letter queue instead of being retried once
+    /// they have been received this many times
+    pub max_receive_count: u32,
+}
+
 /// A task queue backed by AWS SQS
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -24,6 +43,11 @@ pub struct AwsSqsTaskQueue<T: Task> {
     runtime: Runtime,
     #[derivative(Debug = "ignore")]
     credentials_provider: aws_credentials::Provider,
+    dead_letter_queue: Option<DeadLetterQueue>,
+    /// Bodies of the messages that have been dequeued but not yet acknowledged
+    /// or nacknowledged, keyed by receipt handle, so that they can be sent to
+    /// the dead letter queue.
+    message_bodies: HashMap<String, String>,
     logger: Logger,
     phantom_task: PhantomData<*const T>,
 }
@@ -33,6 +57,7 @@ impl<T: Task> AwsSqsTaskQueue<T> {
         region: &str,
         queue_url: &str,
         credentials_provider: aws_credentials::Provider,
+        dead_letter_queue: Option<DeadLetterQueue>,
         parent_logger: &Logger,
     ) -> Result<Self> {
         let region = Region::from_str(region).context("invalid AWS region")?;
@@ -47,6 +72,8 @@ impl<T: Task> AwsSqsTaskQueue<T> {
             queue_url: queue_url.to_owned(),
             runtime,
             credentials_provider,
+            dead_letter_queue,
+            message_bodies: HashMap::new(),
             logger,
             phantom_task: PhantomData,
         })
@@ -74,6 +101,7 @@ impl<T: Task> TaskQueue<T> for AwsSqsTaskQueue<T> {
                     // visible again to other queue consumers. We set it to 600s =
                     // 10 minutes.
                     visibility_timeout: Some(600),
+                    attribute_names: Some(vec![APPROXIMATE_RECEIVE_COUNT.to_owned()]),
                     ..Default::default()
                 };
 
@@ -110,13 +138,39 @@ impl<T: Task> TaskQueue<T> for AwsSqsTaskQueue<T> {
         let task = serde_json::from_reader(body.as_bytes())
             .context(format!("failed to decode JSON task {:?}", body))?;
 
+        self.message_bodies
+            .insert(receipt_handle.to_owned(), body.to_owned());
+
         Ok(Some(TaskHandle {
             task,
             acknowledgment_id: receipt_handle.to_owned(),
+            delivery_attempt: receive_count(&received_messages[0]),
         }))
     }
 
+    fn enqueue(&mut self, task: &T) -> Result<()> {
+        info!(self.logger, "send task"; "task_key" => task.key());
+
+        let body = serde_json::to_string(task).context("failed to encode task to JSON")?;
+        let client = self.sqs_client()?;
+        retry_request(
+            &self.logger.new(o!(event::ACTION => "send message")),
+            || {
+                let request = SendMessageRequest {
+                    queue_url: self.queue_url.clone(),
+                    message_body: body.clone(),
+                    ..Default::default()
+                };
+                self.runtime.block_on(client.send_message(request))
+            },
+        )
+        .context("failed to send message to SQS")?;
+
+        Ok(())
+    }
+
     fn acknowledge_task(&mut self, task: TaskHandle<T>) -> Result<()> {
+        self.message_bodies.remove(&task.acknowledgment_id);
         info!(
             self.logger, "acknowledging task";
             event::TASK_ACKNOWLEDGEMENT_ID => &task.acknowledgment_id,
@@ -140,18 +194,59 @@ impl<T: Task> TaskQueue<T> for AwsSqsTaskQueue<T> {
     }
 
     fn nacknowledge_task(&mut self, task: TaskHandle<T>) -> Result<()> {
+        let body = self.message_bodies.remove(&task.acknowledgment_id);
+        if let (Some(dead_letter_queue), Some(body)) = (self.dead_letter_queue.clone(), body) {
+            if should_dead_letter(task.delivery_attempt, &dead_letter_queue) {
+                return self
+                    .dead_letter(task, &dead_letter_queue, body)
+                    .context("failed to move task to dead letter queue");
+            }
+        }
+
         // In SQS, messages are nacked by changing the message visibility
-        // timeout to 0
+        // timeout. We use a backoff rather than 0, which would make the message
+        // visible to other consumers immediately.
         // https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/sqs-visibility-timeout.html#terminating-message-visibility-timeout
+        let backoff = nack_backoff(task.delivery_attempt);
         info!(
             self.logger, "nacknowledging task";
             event::TASK_ACKNOWLEDGEMENT_ID => &task.acknowledgment_id,
+            "backoff" => format!("{:?}", backoff),
         );
 
-        self.change_message_visibility(&task, &Duration::from_secs(0))
+        self.change_message_visibility(&task, &backoff)
             .context("failed to nacknowledge task")
     }
 
+    fn release_task(&mut self, task: TaskHandle<T>) -> Result<()> {
+        self.message_bodies.remove(&task.acknowledgment_id);
+        info!(
+            self.logger, "releasing task";
+            event::TASK_ACKNOWLEDGEMENT_ID => &task.acknowledgment_id,
+        );
+
+        self.change_message_visibility(&task, &Duration::from_secs(0))
+            .context("failed to release task")
+    }
+
+    fn check_reachable(&mut self) -> Result<()> {
+        let client = self.sqs_client()?;
+
+        retry_request(
+            &self.logger.new(o!(event::ACTION => "get queue attributes")),
+            || {
+                let request = GetQueueAttributesRequest {
+                    queue_url: self.queue_url.clone(),
+                    attribute_names: Some(vec!["QueueArn".to_owned()]),
+                };
+                self.runtime.block_on(client.get_queue_attributes(request))
+            },
+        )
+        .context("failed to get SQS queue attributes")?;
+
+        Ok(())
+    }
+
     fn extend_task_deadline(&mut self, task: &TaskHandle<T>, increment: &Duration) -> Result<()> {
         info!(
             self.logger, "extending deadline on task by 10 minutes";
@@ -164,6 +259,41 @@ impl<T: Task> TaskQueue<T> for AwsSqsTaskQueue<T> {
 }
 
 impl<T: Task> AwsSqsTaskQueue<T> {
+    /// Sends the message backing the task to the dead letter queue, then
+    /// deletes it from this queue. If deletion fails after the message was
+    /// sent, the task may appear in the dead letter queue more than once.
+    fn dead_letter(
+        &mut self,
+        task: TaskHandle<T>,
+        dead_letter_queue: &DeadLetterQueue,
+        body: String,
+    ) -> Result<()> {
+        warn!(
+            self.logger, "moving repeatedly failing task to dead letter queue";
+            event::TASK_ACKNOWLEDGEMENT_ID => &task.acknowledgment_id,
+            "dead_letter_queue_url" => &dead_letter_queue.queue_url,
+            "receive_count" => task.delivery_attempt,
+        );
+
+        let client = self.sqs_client()?;
+        retry_request(
+            &self
+                .logger
+                .new(o!(event::ACTION => "send message to dead letter queue")),
+            || {
+                let request = SendMessageRequest {
+                    queue_url: dead_letter_queue.queue_url.clone(),
+                    message_body: body.clone(),
+                    ..Default::default()
+                };
+                self.runtime.block_on(client.send_message(request))
+            },
+        )
+        .context("failed to send message to SQS dead letter queue")?;
+
+        self.acknowledge_task(task)
+    }
+
     /// Returns a configured SqsClient, or an error on failure.
     fn sqs_client(&self) -> Result<SqsClient> {
         // Rusoto has outstanding issues where either the remote end or the
@@ -212,3 +342,61 @@ impl<T: Task> AwsSqsTaskQueue<T> {
         .context("failed to change message visibility message in SQS")
     }
 }
+
+/// Returns how many times the message has been received, if SQS reported it.
+fn receive_count(message: &Message) -> Option<u32> {
+    message
+        .attributes
+        .as_ref()?
+        .get(APPROXIMATE_RECEIVE_COUNT)?
+        .parse()
+        .ok()
+}
+
+/// Returns true if a task that failed after being received the provided number
+/// of times should be sent to the dead letter queue rather than retried.
+fn should_dead_letter(receive_count: Option<u32>, dead_letter_queue: &DeadLetterQueue) -> bool {
+    receive_count.map_or(false, |count| count >= dead_letter_queue.max_receive_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_receive_count() {
+        let message = |attributes: Option<Vec<(&str, &str)>>| Message {
+            attributes: attributes.map(|attributes| {
+                attributes
+                    .into_iter()
+                    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(receive_count(&message(None)), None);
+        assert_eq!(receive_count(&message(Some(vec![]))), None);
+        assert_eq!(
+            receive_count(&message(Some(vec![(APPROXIMATE_RECEIVE_COUNT, "3")]))),
+            Some(3)
+        );
+        assert_eq!(
+            receive_count(&message(Some(vec![(APPROXIMATE_RECEIVE_COUNT, "many")]))),
+            None
+        );
+    }
+
+    #[test]
+    fn dead_letter_threshold() {
+        let dead_letter_queue = DeadLetterQueue {
+            queue_url: "https://sqs.us-west-2.amazonaws.com/12345678901/dead-letters".to_owned(),
+            max_receive_count: 3,
+        };
+
+        assert!(!should_dead_letter(None, &dead_letter_queue));
+        assert!(!should_dead_letter(Some(2), &dead_letter_queue));
+        assert!(should_dead_letter(Some(3), &dead_letter_queue));
+        assert!(should_dead_letter(Some(4), &dead_letter_queue));
+    }
+}
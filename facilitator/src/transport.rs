@@ -1,9 +1,22 @@
+mod azure;
+mod caching;
+mod compressing;
+mod dry_run;
+mod encrypting;
 mod gcs;
+mod https;
 mod local;
+mod memory;
+mod metered;
+mod portal;
 mod s3;
+mod sftp;
+mod throttled;
 
-use crate::{manifest::BatchSigningPublicKeys, BatchSigningKey};
-use anyhow::Result;
+use crate::{
+    hpke::HpkePrivateKey, manifest::BatchSigningPublicKeys, signing::BatchSigner, TransportError,
+};
+use anyhow::{Context, Result};
 use derivative::Derivative;
 use prio::encrypt::PrivateKey;
 use std::{
@@ -13,8 +26,19 @@ use std::{
 };
 
 pub use self::s3::S3Transport;
+pub use azure::{AzureBlobTransport, AzureCredentials};
+pub use caching::{CachingTransport, ObjectCache};
+pub use compressing::CompressingTransport;
+pub use dry_run::{DryRunTransport, RecordedWrite};
+pub use encrypting::EncryptingTransport;
 pub use gcs::GcsTransport;
+pub use https::HttpsTransport;
 pub use local::LocalFileTransport;
+pub use memory::MemoryTransport;
+pub use metered::MeteredTransport;
+pub use portal::{PortalAuthentication, PortalUploader};
+pub use sftp::{SftpCredentials, SftpTransport};
+pub use throttled::{ThrottleParameters, ThrottledTransport};
 
 /// A transport along with the public keys that can be used to verify signatures
 /// on the batches read from the transport.
@@ -29,17 +53,25 @@ pub struct VerifiableTransport {
 #[derive(Debug)]
 pub struct VerifiableAndDecryptableTransport {
     pub transport: VerifiableTransport,
+    /// Keys for the packets encrypted with libprio-rs' ECIES scheme
     pub packet_decryption_keys: Vec<PrivateKey>,
+    /// Keys for the packets encrypted with HPKE, i.e. whose
+    /// encryption_ciphersuite is set
+    pub hpke_packet_decryption_keys: Vec<HpkePrivateKey>,
 }
 
 #[derive(Debug)]
 pub struct SignableTransport {
     pub transport: Box<dyn Transport>,
-    pub batch_signing_key: BatchSigningKey,
+    pub batch_signer: Box<dyn BatchSigner>,
 }
 
 /// A TransportWriter extends std::io::Write but adds methods that explicitly
-/// allow callers to complete or cancel an upload.
+/// allow callers to complete or cancel an upload. Content written to a
+/// TransportWriter must not become visible to readers of the transport until
+/// complete_upload is called, and flush must not be used to complete uploads.
+/// Implementations should treat a TransportWriter that is dropped before either
+/// method is called as canceled.
 pub trait TransportWriter: Write {
     /// Complete an upload operation, flushing any buffered writes and cleaning
     /// up any related resources. Callers must call this method or cancel_upload
@@ -76,6 +108,182 @@ pub trait Transport: Debug {
     /// Returns an std::io::Write instance into which the contents of the value
     /// may be written.
     fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>>;
+    /// Like put(), but the upload fails with TransportError::AlreadyExists if an
+    /// object with the provided key already exists, either when this is called
+    /// or when the upload is completed, so that existing objects are never
+    /// overwritten. The default implementation checks whether the object
+    /// exists before starting the upload, which leaves a window in which a
+    /// concurrent upload could be clobbered. Transports that can create
+    /// objects exclusively should override it.
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        if self.exists(key, trace_id)? {
+            return Err(TransportError::AlreadyExists(key.to_owned()).into());
+        }
+        self.put(key, trace_id)
+    }
+    /// Returns the keys of all the objects in the transport whose keys begin
+    /// with the provided prefix, in lexicographic order. The returned keys are
+    /// relative to the transport's path, so they may be passed to get().
+    /// Prefixes are matched against whole keys and not only path components,
+    /// so "2021/05/0" matches both "2021/05/01/x" and "2021/05/02/y".
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>>;
+    /// Deletes the object with the provided key. Deleting a key that does not
+    /// exist is not an error.
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()>;
+    /// Returns true if an object with the provided key exists, without reading
+    /// its contents.
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool>;
+    /// Returns an opaque identifier for the current content of the object with
+    /// the provided key, such as its ETag, without reading the content. The
+    /// identifier changes whenever the object is overwritten, so it can be used
+    /// to tell whether a cached copy of the object is stale. Returns an error
+    /// if the object does not exist.
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String>;
 
     fn path(&self) -> String;
 }
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        (**self).get(key, trace_id)
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        (**self).put(key, trace_id)
+    }
+
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        (**self).put_if_absent(key, trace_id)
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        (**self).list(prefix, trace_id)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        (**self).delete(key, trace_id)
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        (**self).exists(key, trace_id)
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        (**self).etag(key, trace_id)
+    }
+
+    fn path(&self) -> String {
+        (**self).path()
+    }
+}
+
+/// Returns true if the provided error was caused by an attempt to create an
+/// object that already exists with Transport::put_if_absent.
+pub fn is_already_exists_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<TransportError>(),
+        Some(TransportError::AlreadyExists(_))
+    )
+}
+
+/// Returns true if the provided error was caused by an attempt to get an object
+/// that does not exist.
+pub fn is_not_found_error(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<TransportError>(),
+        Some(TransportError::NotFound(_))
+    )
+}
+
+/// Copies the object with the provided key from `source` to `destination`,
+/// streaming its content rather than holding all of it in memory. Returns the
+/// number of bytes copied. If reading from the source or writing to the
+/// destination fails, the upload to the destination is canceled.
+pub fn copy(
+    source: &mut dyn Transport,
+    destination: &mut dyn Transport,
+    key: &str,
+    trace_id: &str,
+) -> Result<u64> {
+    let mut reader = source
+        .get(key, trace_id)
+        .with_context(|| format!("failed to get {} from {}", key, source.path()))?;
+    let mut writer = destination
+        .put(key, trace_id)
+        .with_context(|| format!("failed to put {} to {}", key, destination.path()))?;
+
+    match std::io::copy(&mut reader, &mut writer) {
+        Ok(len) => {
+            writer.complete_upload()?;
+            Ok(len)
+        }
+        Err(e) => {
+            writer.cancel_upload()?;
+            Err(e).context(format!(
+                "failed to copy {} from {} to {}",
+                key,
+                source.path(),
+                destination.path()
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_between_transports() {
+        let mut source = MemoryTransport::new();
+        let mut destination = MemoryTransport::new();
+        let content = vec![3; 100_000];
+
+        let mut writer = source.put("key", "").unwrap();
+        writer.write_all(&content).unwrap();
+        writer.complete_upload().unwrap();
+
+        assert_eq!(
+            copy(&mut source, &mut destination, "key", "").unwrap(),
+            content.len() as u64
+        );
+        let mut content_again = Vec::new();
+        destination
+            .get("key", "")
+            .unwrap()
+            .read_to_end(&mut content_again)
+            .unwrap();
+        assert_eq!(content_again, content);
+
+        assert!(copy(&mut source, &mut destination, "missing-key", "").is_err());
+        assert_eq!(destination.keys(), vec!["key"]);
+    }
+
+    #[test]
+    fn copy_cancels_failed_upload() {
+        // Encrypted objects fail to decrypt midway through if they have been
+        // tampered with, after the copy has started writing.
+        let memory_transport = MemoryTransport::new();
+        let mut source =
+            EncryptingTransport::new(memory_transport.clone(), vec![vec![1; 32]]).unwrap();
+        let mut writer = source.put("key", "").unwrap();
+        writer.write_all(&vec![0; 200_000]).unwrap();
+        writer.complete_upload().unwrap();
+
+        let mut stored = Vec::new();
+        memory_transport
+            .clone()
+            .get("key", "")
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+        *stored.last_mut().unwrap() ^= 1;
+        let mut writer = memory_transport.clone().put("key", "").unwrap();
+        writer.write_all(&stored).unwrap();
+        writer.complete_upload().unwrap();
+
+        let mut destination = MemoryTransport::new();
+        assert!(copy(&mut source, &mut destination, "key", "").is_err());
+        assert!(destination.keys().is_empty());
+    }
+}
@@ -0,0 +1,1019 @@
+use crate::{
+    config::AzurePath,
+    http::{
+        error_http_status, Method, OauthTokenProvider, Request, RequestClass, RequestParameters,
+        RetryingAgent,
+    },
+    logging::event,
+    runtime::BackgroundWriter,
+    transport::{Transport, TransportWriter},
+    Error, TransportError,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{prelude::Utc, DateTime, Duration as ChronoDuration};
+use derivative::Derivative;
+use serde::Deserialize;
+use slog::{debug, info, o, Logger};
+use std::io::{self, Read, Write};
+use url::Url;
+use xml::reader::{EventReader, XmlEvent};
+
+/// The version of the Azure Storage REST API we target. Requests that do not
+/// specify a version are handled with whatever semantics the storage account
+/// defaults to, so we always send one explicitly.
+/// https://docs.microsoft.com/en-us/rest/api/storageservices/versioning-for-the-azure-storage-services
+const AZURE_STORAGE_API_VERSION: &str = "2020-04-08";
+
+/// The OAuth scope that grants access to Azure Storage.
+/// https://docs.microsoft.com/en-us/azure/storage/common/storage-auth-aad-app
+const AZURE_STORAGE_SCOPE: &str = "https://storage.azure.com/.default";
+
+fn blob_service_base_url(storage_account: &str) -> Result<Url> {
+    let url = format!("https://{}.blob.core.windows.net/", storage_account);
+    Url::parse(&url).context(format!("failed to parse: {}", url))
+}
+
+fn azure_ad_base_url() -> Url {
+    Url::parse("https://login.microsoftonline.com/").expect("unable to parse Azure AD url")
+}
+
+/// Credentials used to authenticate requests to Azure Blob Storage.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub enum AzureCredentials {
+    /// A shared access signature, which is appended to the query string of
+    /// every request. The token may be provided with or without a leading '?'.
+    /// https://docs.microsoft.com/en-us/rest/api/storageservices/delegate-access-with-shared-access-signature
+    SasToken(#[derivative(Debug = "ignore")] String),
+    /// An Azure AD application's client credentials, used to obtain OAuth
+    /// tokens that are sent as bearer tokens in an Authorization header.
+    /// https://docs.microsoft.com/en-us/azure/active-directory/develop/v2-oauth2-client-creds-grant-flow
+    ClientSecret {
+        tenant_id: String,
+        client_id: String,
+        #[derivative(Debug = "ignore")]
+        client_secret: String,
+    },
+}
+
+/// Represents the response from Azure AD's token endpoint.
+#[derive(Deserialize)]
+struct AzureAdTokenResponse {
+    access_token: String,
+    expires_in: i64,
+}
+
+/// Obtains OAuth tokens from Azure AD using the client credentials grant and
+/// caches them until they expire.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+struct AzureAdTokenProvider {
+    tenant_id: String,
+    client_id: String,
+    #[derivative(Debug = "ignore")]
+    client_secret: String,
+    azure_ad_base_url: Url,
+    #[derivative(Debug = "ignore")]
+    token: Option<(String, DateTime<Utc>)>,
+    agent: RetryingAgent,
+    logger: Logger,
+}
+
+impl OauthTokenProvider for AzureAdTokenProvider {
+    fn ensure_oauth_token(&mut self) -> Result<String> {
+        if let Some((token, expiration)) = &self.token {
+            if Utc::now() < *expiration {
+                return Ok(token.clone());
+            }
+        }
+
+        let url = self
+            .azure_ad_base_url
+            .join(&format!("{}/oauth2/v2.0/token", self.tenant_id))
+            .context("failed to construct Azure AD token URL")?;
+        debug!(self.logger, "obtaining Azure AD token"; "url" => url.to_string());
+
+        let request = self.agent.prepare_request(RequestParameters {
+            url,
+            method: Method::Post,
+            ..Default::default()
+        })?;
+        let http_response = self
+            .agent
+            .send_form(
+                &self.logger,
+                &request,
+                &[
+                    ("grant_type", "client_credentials"),
+                    ("client_id", &self.client_id),
+                    ("client_secret", &self.client_secret),
+                    ("scope", AZURE_STORAGE_SCOPE),
+                ],
+            )
+            .context("failed to obtain Azure AD token")?;
+        let response: AzureAdTokenResponse = self
+            .agent
+            .read_json(http_response)
+            .context("failed to deserialize response from Azure AD")?;
+
+        self.token = Some((
+            response.access_token.clone(),
+            Utc::now() + ChronoDuration::seconds(response.expires_in),
+        ));
+
+        Ok(response.access_token)
+    }
+}
+
+/// Either a SAS token or a provider of Azure AD OAuth tokens, used to
+/// authenticate individual requests.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+enum RequestAuthenticator {
+    SasToken(#[derivative(Debug = "ignore")] String),
+    AzureAd(Box<AzureAdTokenProvider>),
+}
+
+impl RequestAuthenticator {
+    /// Prepares a request for the provided URL and method, adding whichever
+    /// authentication and versioning the Azure Storage API requires.
+    fn prepare_request(
+        &mut self,
+        agent: &RetryingAgent,
+        mut url: Url,
+        method: Method,
+    ) -> Result<Request> {
+        let token_provider: Option<&mut dyn OauthTokenProvider> = match self {
+            RequestAuthenticator::SasToken(sas_token) => {
+                // Merge the SAS token's parameters into any query the URL
+                // already has.
+                let query = match url.query() {
+                    Some(query) => format!("{}&{}", query, sas_token),
+                    None => sas_token.clone(),
+                };
+                url.set_query(Some(&query));
+                None
+            }
+            RequestAuthenticator::AzureAd(provider) => Some(provider.as_mut()),
+        };
+
+        Ok(agent
+            .prepare_request(RequestParameters {
+                url,
+                method,
+                token_provider,
+            })?
+            .set("x-ms-version", AZURE_STORAGE_API_VERSION))
+    }
+}
+
+/// AzureBlobTransport manages reading and writing from Azure Blob Storage
+/// containers, authenticating either with a SAS token or with OAuth tokens
+/// obtained from Azure AD.
+#[derive(Debug)]
+pub struct AzureBlobTransport {
+    path: AzurePath,
+    blob_service_url: Url,
+    authenticator: RequestAuthenticator,
+    agent: RetryingAgent,
+    logger: Logger,
+}
+
+impl AzureBlobTransport {
+    /// Instantiate a new AzureBlobTransport to read or write objects from or to
+    /// the provided path, authenticating with the provided credentials.
+    pub fn new(
+        path: AzurePath,
+        credentials: AzureCredentials,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        let blob_service_url = blob_service_base_url(&path.storage_account)?;
+        Self::new_with_api_urls(
+            path,
+            credentials,
+            blob_service_url,
+            azure_ad_base_url(),
+            parent_logger,
+        )
+    }
+
+    fn new_with_api_urls(
+        path: AzurePath,
+        credentials: AzureCredentials,
+        blob_service_url: Url,
+        azure_ad_base_url: Url,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        let logger = parent_logger.new(o!(
+            event::STORAGE_PATH => path.to_string(),
+        ));
+        let agent = RetryingAgent::new(
+            RequestClass::Storage,
+            // Azure Storage signals throttling with HTTP 503, which is already
+            // retried, but HTTP 408 Request Timeout should also be retried.
+            // https://docs.microsoft.com/en-us/azure/architecture/best-practices/retry-service-specific#azure-storage
+            vec![408],
+        );
+        let authenticator = match credentials {
+            AzureCredentials::SasToken(token) => {
+                let token = token.trim_start_matches('?').to_owned();
+                if token.is_empty() {
+                    return Err(anyhow!("SAS token may not be empty"));
+                }
+                RequestAuthenticator::SasToken(token)
+            }
+            AzureCredentials::ClientSecret {
+                tenant_id,
+                client_id,
+                client_secret,
+            } => RequestAuthenticator::AzureAd(Box::new(AzureAdTokenProvider {
+                tenant_id,
+                client_id,
+                client_secret,
+                azure_ad_base_url,
+                token: None,
+                agent: RetryingAgent::default(),
+                logger: logger.clone(),
+            })),
+        };
+
+        Ok(AzureBlobTransport {
+            path: path.ensure_directory_prefix(),
+            blob_service_url,
+            authenticator,
+            agent,
+            logger,
+        })
+    }
+
+    fn container_url(&self) -> Result<Url> {
+        let mut url = self.blob_service_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("blob service URL cannot be a base"))?
+            .pop_if_empty()
+            .push(&self.path.container);
+        Ok(url)
+    }
+
+    fn blob_url(&self, key: &str) -> Result<Url> {
+        let mut url = self.container_url()?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("blob service URL cannot be a base"))?
+            .extend([&self.path.key, key].concat().split('/'));
+        Ok(url)
+    }
+
+    /// Starts a block blob upload of the provided key. If exclusive is true,
+    /// the upload fails with TransportError::AlreadyExists if the blob already
+    /// exists.
+    fn writer(
+        &mut self,
+        key: &str,
+        trace_id: &str,
+        exclusive: bool,
+    ) -> Result<Box<dyn TransportWriter>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+            event::ACTION => "put Azure blob",
+        ));
+        info!(logger, "put"; "exclusive" => exclusive);
+
+        // The writer gets its own copy of the authenticator so that it can
+        // refresh Azure AD tokens independently of this transport.
+        let authenticator = self.authenticator.clone();
+
+        // Blocks are staged while the caller writes the next one
+        Ok(Box::new(BackgroundWriter::new(BlockBlobWriter::new(
+            self.blob_url(key)?,
+            exclusive,
+            authenticator,
+            // Blocks may be up to 4000 MiB, but we stage them in memory so we
+            // use a block size in line with what we use for GCS.
+            // https://docs.microsoft.com/en-us/rest/api/storageservices/put-block#remarks
+            8_388_608,
+            self.agent.clone(),
+            &logger,
+        ))?))
+    }
+}
+
+impl Transport for AzureBlobTransport {
+    fn path(&self) -> String {
+        self.path.to_string()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+            event::ACTION => "get Azure blob",
+        ));
+        info!(logger, "get");
+
+        // https://docs.microsoft.com/en-us/rest/api/storageservices/get-blob
+        let url = self.blob_url(key)?;
+        let request = self
+            .authenticator
+            .prepare_request(&self.agent, url.clone(), Method::Get)?;
+
+        let response = self.agent.call(&logger, &request).map_err(|e| {
+            let not_found = error_http_status(&e) == Some(404);
+            let error = e.context(format!("failed to fetch blob {} from Azure", url));
+            if not_found {
+                error.context(TransportError::NotFound(key.to_owned()))
+            } else {
+                error
+            }
+        })?;
+
+        Ok(Box::new(response.into_reader()?))
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.writer(key, trace_id, false)
+    }
+
+    /// Commits the block list with If-None-Match: *, so Azure only creates the
+    /// blob if it does not exist when the upload completes.
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.writer(key, trace_id, true)
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => prefix.to_owned(),
+            event::ACTION => "list Azure blobs",
+        ));
+        info!(logger, "list");
+
+        // Results are paginated, so we follow continuation markers until there
+        // are no more results.
+        // https://docs.microsoft.com/en-us/rest/api/storageservices/list-blobs
+        let mut keys = Vec::new();
+        let mut marker: Option<String> = None;
+        loop {
+            let mut url = self.container_url()?;
+            {
+                let mut query_pairs = url.query_pairs_mut();
+                query_pairs
+                    .append_pair("restype", "container")
+                    .append_pair("comp", "list")
+                    .append_pair("prefix", &[&self.path.key, prefix].concat());
+                if let Some(marker) = &marker {
+                    query_pairs.append_pair("marker", marker);
+                }
+            }
+            let request =
+                self.authenticator
+                    .prepare_request(&self.agent, url.clone(), Method::Get)?;
+
+            let response = self
+                .agent
+                .call(&logger, &request)
+                .context(format!("failed to list blobs in Azure: {}", url))?;
+            let (names, next_marker) = parse_list_blobs_response(response.into_reader()?)?;
+
+            for name in names {
+                keys.push(
+                    name.strip_prefix(&self.path.key)
+                        .context(format!("listed key {} outside of Azure path", name))?
+                        .to_owned(),
+                );
+            }
+
+            match next_marker {
+                Some(next_marker) => marker = Some(next_marker),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+            event::ACTION => "delete Azure blob",
+        ));
+        info!(logger, "delete");
+
+        // https://docs.microsoft.com/en-us/rest/api/storageservices/delete-blob
+        let url = self.blob_url(key)?;
+        let request =
+            self.authenticator
+                .prepare_request(&self.agent, url.clone(), Method::Delete)?;
+
+        match self.agent.call(&logger, &request) {
+            Ok(_) => Ok(()),
+            Err(e) if error_http_status(&e) == Some(404) => Ok(()),
+            Err(e) => Err(e).context(format!("failed to delete blob {} from Azure", url)),
+        }
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+            event::ACTION => "get Azure blob properties",
+        ));
+        info!(logger, "exists");
+
+        // https://docs.microsoft.com/en-us/rest/api/storageservices/get-blob-properties
+        let url = self.blob_url(key)?;
+        let request = self
+            .authenticator
+            .prepare_request(&self.agent, url.clone(), Method::Head)?;
+
+        match self.agent.call(&logger, &request) {
+            Ok(_) => Ok(true),
+            Err(e) if error_http_status(&e) == Some(404) => Ok(false),
+            Err(e) => Err(e).context(format!("failed to get properties of blob {}", url)),
+        }
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+            event::ACTION => "get Azure blob properties",
+        ));
+        info!(logger, "etag");
+
+        // https://docs.microsoft.com/en-us/rest/api/storageservices/get-blob-properties
+        let url = self.blob_url(key)?;
+        let request = self
+            .authenticator
+            .prepare_request(&self.agent, url.clone(), Method::Head)?;
+
+        let response = self
+            .agent
+            .call(&logger, &request)
+            .context(format!("failed to get properties of blob {}", url))?;
+        response
+            .header("ETag")
+            .map(String::from)
+            .context(format!("no ETag in properties of blob {}", url))
+    }
+}
+
+/// Parses the XML body of a List Blobs response, returning the names of the
+/// listed blobs and the marker for the next page of results, if there is one.
+/// https://docs.microsoft.com/en-us/rest/api/storageservices/list-blobs#response-body
+fn parse_list_blobs_response<R: Read>(body: R) -> Result<(Vec<String>, Option<String>)> {
+    let mut names = Vec::new();
+    let mut next_marker = None;
+    let mut element_path: Vec<String> = Vec::new();
+
+    for event in EventReader::new(body) {
+        match event.context("failed to parse List Blobs response")? {
+            XmlEvent::StartElement { name, .. } => element_path.push(name.local_name),
+            XmlEvent::EndElement { .. } => {
+                element_path.pop();
+            }
+            XmlEvent::Characters(text) => {
+                let path: Vec<&str> = element_path.iter().map(String::as_str).collect();
+                match path.as_slice() {
+                    ["EnumerationResults", "Blobs", "Blob", "Name"] => names.push(text),
+                    ["EnumerationResults", "NextMarker"] => next_marker = Some(text),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok((names, next_marker))
+}
+
+/// BlockBlobWriter uploads an Azure block blob by staging blocks of content
+/// with Put Block requests as it is written to, and then committing the staged
+/// blocks with a Put Block List request when the upload is completed. This is
+/// analogous to S3's multipart uploads. Staged blocks that are never committed
+/// are garbage collected by Azure after a week, so cancelling an upload only
+/// requires that we not commit the block list.
+/// https://docs.microsoft.com/en-us/rest/api/storageservices/understanding-block-blobs--append-blobs--and-page-blobs
+struct BlockBlobWriter {
+    blob_url: Url,
+    /// If true, the block list is only committed if the blob does not exist
+    exclusive: bool,
+    authenticator: RequestAuthenticator,
+    block_size: usize,
+    block_ids: Vec<String>,
+    buffer: Vec<u8>,
+    agent: RetryingAgent,
+    logger: Logger,
+}
+
+impl BlockBlobWriter {
+    fn new(
+        blob_url: Url,
+        exclusive: bool,
+        authenticator: RequestAuthenticator,
+        block_size: usize,
+        agent: RetryingAgent,
+        parent_logger: &Logger,
+    ) -> Self {
+        BlockBlobWriter {
+            blob_url,
+            exclusive,
+            authenticator,
+            block_size,
+            block_ids: Vec::new(),
+            buffer: Vec::with_capacity(block_size * 2),
+            agent,
+            logger: parent_logger.clone(),
+        }
+    }
+
+    /// Block IDs must be base64 encoded strings of at most 64 bytes, and all
+    /// blocks in a blob must have IDs of the same length, so we encode the
+    /// zero-padded index of the block.
+    fn block_id(index: usize) -> String {
+        base64::encode(format!("{:010}", index))
+    }
+
+    /// Stages up to block_size bytes from the buffer as a new block.
+    fn stage_block(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let block_id = Self::block_id(self.block_ids.len());
+        let length = self.buffer.len().min(self.block_size);
+        debug!(
+            self.logger, "staging block";
+            "block_id" => &block_id,
+            "length" => length,
+        );
+
+        // https://docs.microsoft.com/en-us/rest/api/storageservices/put-block
+        let mut url = self.blob_url.clone();
+        url.query_pairs_mut()
+            .append_pair("comp", "block")
+            .append_pair("blockid", &block_id);
+        let request = self
+            .authenticator
+            .prepare_request(&self.agent, url, Method::Put)?;
+
+        self.agent
+            .send_bytes(&self.logger, &request, &self.buffer[..length])
+            .context(format!("failed to stage block in {}", self.blob_url))?;
+
+        self.buffer.drain(..length);
+        self.block_ids.push(block_id);
+        Ok(())
+    }
+
+    /// Constructs the body of a Put Block List request committing all the
+    /// blocks staged so far.
+    fn block_list_body(&self) -> String {
+        let mut body = r#"<?xml version="1.0" encoding="utf-8"?><BlockList>"#.to_owned();
+        for block_id in &self.block_ids {
+            body.push_str(&format!("<Latest>{}</Latest>", block_id));
+        }
+        body.push_str("</BlockList>");
+        body
+    }
+}
+
+impl Write for BlockBlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Write into memory buffer, and stage a block if we have accumulated
+        // enough content.
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= self.block_size {
+            self.stage_block()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, Error::AnyhowError(e)))?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Staged blocks are not visible until committed in complete_upload, so
+        // there is nothing useful to do here.
+        Ok(())
+    }
+}
+
+impl TransportWriter for BlockBlobWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        while !self.buffer.is_empty() {
+            self.stage_block()?;
+        }
+
+        debug!(
+            self.logger, "committing block list";
+            "blocks" => self.block_ids.len(),
+        );
+
+        // https://docs.microsoft.com/en-us/rest/api/storageservices/put-block-list
+        let mut url = self.blob_url.clone();
+        url.query_pairs_mut().append_pair("comp", "blocklist");
+        let mut request = self
+            .authenticator
+            .prepare_request(&self.agent, url, Method::Put)?
+            .set("Content-Type", "application/xml");
+        if self.exclusive {
+            // https://docs.microsoft.com/en-us/rest/api/storageservices/specifying-conditional-headers-for-blob-service-operations
+            request = request.set("If-None-Match", "*");
+        }
+
+        self.agent
+            .send_bytes(&self.logger, &request, self.block_list_body().as_bytes())
+            .map_err(|e| {
+                // Azure answers a failed If-None-Match: * on a write with 409
+                // BlobAlreadyExists, but a 412 means the same thing.
+                let already_exists = matches!(error_http_status(&e), Some(409) | Some(412));
+                let error = e.context(format!("failed to commit block list for {}", self.blob_url));
+                if already_exists {
+                    error.context(TransportError::AlreadyExists(self.blob_url.to_string()))
+                } else {
+                    error
+                }
+            })?;
+
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        debug!(self.logger, "canceling upload"; "blob_url" => self.blob_url.to_string());
+        // Uncommitted blocks are discarded by Azure, so we need only drop
+        // whatever we have buffered.
+        // https://docs.microsoft.com/en-us/rest/api/storageservices/put-block#remarks
+        self.buffer.clear();
+        self.block_ids.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::setup_test_logging;
+    use mockito::{mock, Matcher};
+    use std::str::FromStr;
+
+    fn mockito_url() -> Url {
+        Url::parse(&format!("{}/", mockito::server_url()))
+            .expect("unable to parse mockito server url")
+    }
+
+    #[test]
+    fn sas_token_upload() {
+        let logger = setup_test_logging();
+
+        let mut transport = AzureBlobTransport::new_with_api_urls(
+            AzurePath::from_str("azure://account/sas-container/prefix").unwrap(),
+            AzureCredentials::SasToken("?sv=fake&sig=fake-signature".to_owned()),
+            mockito_url(),
+            mockito_url(),
+            &logger,
+        )
+        .unwrap();
+
+        let mocked_put_blocks: Vec<_> = ["0123", "4567", "89"]
+            .iter()
+            .enumerate()
+            .map(|(index, content)| {
+                mock("PUT", "/sas-container/prefix/object")
+                    .match_header("x-ms-version", AZURE_STORAGE_API_VERSION)
+                    .match_query(Matcher::AllOf(vec![
+                        Matcher::UrlEncoded("comp".to_owned(), "block".to_owned()),
+                        Matcher::UrlEncoded("blockid".to_owned(), BlockBlobWriter::block_id(index)),
+                        Matcher::UrlEncoded("sig".to_owned(), "fake-signature".to_owned()),
+                    ]))
+                    .match_body(*content)
+                    .with_status(201)
+                    .expect(1)
+                    .create()
+            })
+            .collect();
+
+        let mocked_put_block_list = mock("PUT", "/sas-container/prefix/object")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("comp".to_owned(), "blocklist".to_owned()),
+                Matcher::UrlEncoded("sig".to_owned(), "fake-signature".to_owned()),
+            ]))
+            .match_body(Matcher::Regex(format!(
+                "<BlockList><Latest>{}</Latest><Latest>{}</Latest><Latest>{}</Latest></BlockList>",
+                BlockBlobWriter::block_id(0),
+                BlockBlobWriter::block_id(1),
+                BlockBlobWriter::block_id(2),
+            )))
+            .with_status(201)
+            .expect(1)
+            .create();
+
+        let mut writer = BlockBlobWriter::new(
+            transport.blob_url("object").unwrap(),
+            false,
+            RequestAuthenticator::SasToken("sv=fake&sig=fake-signature".to_owned()),
+            4,
+            RetryingAgent::default(),
+            &logger,
+        );
+        assert_eq!(writer.write(b"0123456789").unwrap(), 10);
+        writer.complete_upload().unwrap();
+
+        for mocked_put_block in mocked_put_blocks {
+            mocked_put_block.assert();
+        }
+        mocked_put_block_list.assert();
+
+        let mocked_get = mock("GET", "/sas-container/prefix/object")
+            .match_query(Matcher::UrlEncoded(
+                "sig".to_owned(),
+                "fake-signature".to_owned(),
+            ))
+            .with_status(200)
+            .with_body("0123456789")
+            .expect(1)
+            .create();
+
+        let mut content = Vec::new();
+        transport
+            .get("object", "trace-id")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"0123456789");
+        mocked_get.assert();
+    }
+
+    #[test]
+    fn azure_ad_get() {
+        let logger = setup_test_logging();
+
+        let mocked_token = mock("POST", "/fake-tenant/oauth2/v2.0/token")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("grant_type".to_owned(), "client_credentials".to_owned()),
+                Matcher::UrlEncoded("client_id".to_owned(), "fake-client".to_owned()),
+                Matcher::UrlEncoded("client_secret".to_owned(), "fake-secret".to_owned()),
+                Matcher::UrlEncoded("scope".to_owned(), AZURE_STORAGE_SCOPE.to_owned()),
+            ]))
+            .with_status(200)
+            .with_body(r#"{"token_type":"Bearer","expires_in":3599,"access_token":"fake-token"}"#)
+            .expect(1)
+            .create();
+
+        let mocked_get = mock("GET", "/ad-container/object")
+            .match_header("Authorization", "Bearer fake-token")
+            .match_header("x-ms-version", AZURE_STORAGE_API_VERSION)
+            .with_status(200)
+            .with_body("content")
+            .expect(2)
+            .create();
+
+        let mut transport = AzureBlobTransport::new_with_api_urls(
+            AzurePath::from_str("azure://account/ad-container").unwrap(),
+            AzureCredentials::ClientSecret {
+                tenant_id: "fake-tenant".to_owned(),
+                client_id: "fake-client".to_owned(),
+                client_secret: "fake-secret".to_owned(),
+            },
+            mockito_url(),
+            mockito_url(),
+            &logger,
+        )
+        .unwrap();
+
+        // The second get should reuse the cached token
+        for _ in 0..2 {
+            let mut content = String::new();
+            transport
+                .get("object", "trace-id")
+                .unwrap()
+                .read_to_string(&mut content)
+                .unwrap();
+            assert_eq!(content, "content");
+        }
+
+        mocked_token.assert();
+        mocked_get.assert();
+    }
+
+    #[test]
+    fn list_blobs() {
+        let logger = setup_test_logging();
+
+        let mocked_first_page = mock("GET", "/list-container")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("restype".to_owned(), "container".to_owned()),
+                Matcher::UrlEncoded("comp".to_owned(), "list".to_owned()),
+                Matcher::UrlEncoded("prefix".to_owned(), "prefix/2021/".to_owned()),
+            ]))
+            .with_status(200)
+            .with_body(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults ServiceEndpoint="https://account.blob.core.windows.net/" ContainerName="list-container">
+  <Prefix>prefix/2021/</Prefix>
+  <Blobs>
+    <Blob><Name>prefix/2021/a</Name><Properties><Content-Length>1</Content-Length></Properties></Blob>
+    <Blob><Name>prefix/2021/b</Name><Properties><Content-Length>1</Content-Length></Properties></Blob>
+  </Blobs>
+  <NextMarker>fake-marker</NextMarker>
+</EnumerationResults>"#,
+            )
+            .expect(1)
+            .create();
+
+        let mocked_second_page = mock("GET", "/list-container")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("comp".to_owned(), "list".to_owned()),
+                Matcher::UrlEncoded("marker".to_owned(), "fake-marker".to_owned()),
+            ]))
+            .with_status(200)
+            .with_body(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults ServiceEndpoint="https://account.blob.core.windows.net/" ContainerName="list-container">
+  <Prefix>prefix/2021/</Prefix>
+  <Blobs>
+    <Blob><Name>prefix/2021/c</Name></Blob>
+  </Blobs>
+  <NextMarker />
+</EnumerationResults>"#,
+            )
+            .expect(1)
+            .create();
+
+        let mut transport = AzureBlobTransport::new_with_api_urls(
+            AzurePath::from_str("azure://account/list-container/prefix").unwrap(),
+            AzureCredentials::SasToken("sig=fake-signature".to_owned()),
+            mockito_url(),
+            mockito_url(),
+            &logger,
+        )
+        .unwrap();
+
+        assert_eq!(
+            transport.list("2021/", "trace-id").unwrap(),
+            vec!["2021/a", "2021/b", "2021/c"]
+        );
+
+        mocked_first_page.assert();
+        mocked_second_page.assert();
+    }
+
+    #[test]
+    fn delete_and_exists() {
+        let logger = setup_test_logging();
+
+        let mut transport = AzureBlobTransport::new_with_api_urls(
+            AzurePath::from_str("azure://account/delete-container").unwrap(),
+            AzureCredentials::SasToken("sig=fake-signature".to_owned()),
+            mockito_url(),
+            mockito_url(),
+            &logger,
+        )
+        .unwrap();
+
+        let mocked_head_present = mock("HEAD", "/delete-container/present")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .expect(1)
+            .create();
+        let mocked_head_missing = mock("HEAD", "/delete-container/missing")
+            .match_query(Matcher::Any)
+            .with_status(404)
+            .expect(1)
+            .create();
+        let mocked_delete_present = mock("DELETE", "/delete-container/present")
+            .match_query(Matcher::Any)
+            .with_status(202)
+            .expect(1)
+            .create();
+        let mocked_delete_missing = mock("DELETE", "/delete-container/missing")
+            .match_query(Matcher::Any)
+            .with_status(404)
+            .expect(1)
+            .create();
+        let mocked_delete_forbidden = mock("DELETE", "/delete-container/forbidden")
+            .match_query(Matcher::Any)
+            .with_status(403)
+            .expect(1)
+            .create();
+
+        assert!(transport.exists("present", "trace-id").unwrap());
+        assert!(!transport.exists("missing", "trace-id").unwrap());
+        transport.delete("present", "trace-id").unwrap();
+        transport.delete("missing", "trace-id").unwrap();
+        transport.delete("forbidden", "trace-id").unwrap_err();
+
+        mocked_head_present.assert();
+        mocked_head_missing.assert();
+        mocked_delete_present.assert();
+        mocked_delete_missing.assert();
+        mocked_delete_forbidden.assert();
+    }
+
+    #[test]
+    fn etag() {
+        let logger = setup_test_logging();
+
+        let mut transport = AzureBlobTransport::new_with_api_urls(
+            AzurePath::from_str("azure://account/etag-container").unwrap(),
+            AzureCredentials::SasToken("sig=fake-signature".to_owned()),
+            mockito_url(),
+            mockito_url(),
+            &logger,
+        )
+        .unwrap();
+
+        let mocked_head_present = mock("HEAD", "/etag-container/present")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_header("ETag", "\"0x8D4BCC2E4835CD0\"")
+            .expect(1)
+            .create();
+        let mocked_head_missing = mock("HEAD", "/etag-container/missing")
+            .match_query(Matcher::Any)
+            .with_status(404)
+            .expect(1)
+            .create();
+
+        assert_eq!(
+            transport.etag("present", "trace-id").unwrap(),
+            "\"0x8D4BCC2E4835CD0\""
+        );
+        transport.etag("missing", "trace-id").unwrap_err();
+
+        mocked_head_present.assert();
+        mocked_head_missing.assert();
+    }
+
+    #[test]
+    fn cancel_upload_commits_nothing() {
+        let logger = setup_test_logging();
+
+        let mocked_put_block_list = mock("PUT", "/cancel-container/object")
+            .match_query(Matcher::UrlEncoded(
+                "comp".to_owned(),
+                "blocklist".to_owned(),
+            ))
+            .expect(0)
+            .create();
+
+        let mut writer = BlockBlobWriter::new(
+            Url::parse(&format!(
+                "{}/cancel-container/object",
+                mockito::server_url()
+            ))
+            .unwrap(),
+            false,
+            RequestAuthenticator::SasToken("sig=fake-signature".to_owned()),
+            100,
+            RetryingAgent::default(),
+            &logger,
+        );
+        writer.write_all(b"content").unwrap();
+        writer.cancel_upload().unwrap();
+
+        mocked_put_block_list.assert();
+    }
+
+    #[test]
+    fn exclusive_upload_of_existing_blob() {
+        let logger = setup_test_logging();
+
+        let mocked_put_block = mock("PUT", "/exclusive-container/object")
+            .match_query(Matcher::UrlEncoded("comp".to_owned(), "block".to_owned()))
+            .with_status(201)
+            .expect(1)
+            .create();
+        let mocked_put_block_list = mock("PUT", "/exclusive-container/object")
+            .match_query(Matcher::UrlEncoded(
+                "comp".to_owned(),
+                "blocklist".to_owned(),
+            ))
+            .match_header("If-None-Match", "*")
+            .with_status(409)
+            .expect(1)
+            .create();
+
+        let mut writer = BlockBlobWriter::new(
+            Url::parse(&format!(
+                "{}/exclusive-container/object",
+                mockito::server_url()
+            ))
+            .unwrap(),
+            true,
+            RequestAuthenticator::SasToken("sig=fake-signature".to_owned()),
+            100,
+            RetryingAgent::default(),
+            &logger,
+        );
+        writer.write_all(b"content").unwrap();
+        let error = writer.complete_upload().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TransportError>(),
+            Some(TransportError::AlreadyExists(_))
+        ));
+
+        mocked_put_block.assert();
+        mocked_put_block_list.assert();
+    }
+}
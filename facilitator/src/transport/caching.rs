@@ -0,0 +1,454 @@
+use crate::{
+    logging::event,
+    transport::{Transport, TransportWriter},
+};
+use anyhow::{Context, Result};
+use ring::digest::{digest, SHA256};
+use slog::{debug, o, warn, Logger};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{create_dir_all, read_dir, remove_file, File},
+    io::{self, Cursor, ErrorKind, Read, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tempfile::Builder;
+
+/// Prefix of the names of temporary files that cached objects are written to
+/// before they are moved into place in an on-disk cache.
+const TEMP_FILE_PREFIX: &str = ".cache-insert-";
+
+/// A size-bounded cache of object contents, shared by CachingTransports.
+/// Entries are evicted in least recently used order once the total size of
+/// cached objects would exceed the maximum size. Clones of an ObjectCache
+/// share the same entries.
+#[derive(Clone, Debug)]
+pub struct ObjectCache {
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl ObjectCache {
+    /// Creates an ObjectCache that keeps up to `max_size` bytes of objects in
+    /// memory.
+    pub fn in_memory(max_size: u64) -> Self {
+        Self::new(Storage::Memory(HashMap::new()), max_size)
+    }
+
+    /// Creates an ObjectCache that keeps up to `max_size` bytes of objects in
+    /// files in the provided directory, which is created if necessary. Objects
+    /// already cached in the directory, e.g. by a previous run, are reused.
+    pub fn on_disk(directory: PathBuf, max_size: u64) -> Result<Self> {
+        create_dir_all(&directory)
+            .with_context(|| format!("failed to create cache directory {}", directory.display()))?;
+
+        // Index existing entries, oldest first, so that the least recently
+        // written are the first to be evicted.
+        let mut existing = Vec::new();
+        for entry in read_dir(&directory)
+            .with_context(|| format!("failed to read cache directory {}", directory.display()))?
+        {
+            let entry = entry.context("failed to read cache directory entry")?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(TEMP_FILE_PREFIX) {
+                // Left behind by an interrupted insert
+                remove_file(entry.path()).context("failed to remove temporary cache file")?;
+                continue;
+            }
+            let metadata = entry
+                .metadata()
+                .context("failed to get cache file metadata")?;
+            if metadata.is_file() {
+                existing.push((metadata.modified().ok(), name, metadata.len()));
+            }
+        }
+        existing.sort();
+
+        let cache = Self::new(Storage::Disk(directory), max_size);
+        {
+            let mut state = cache.state.lock().unwrap();
+            for (_, name, size) in existing {
+                state.index(name, size);
+            }
+            state.evict(0)?;
+        }
+        Ok(cache)
+    }
+
+    fn new(storage: Storage, max_size: u64) -> Self {
+        ObjectCache {
+            state: Arc::new(Mutex::new(CacheState {
+                storage,
+                max_size,
+                size: 0,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+            })),
+        }
+    }
+
+    fn get(&self, cache_key: &str) -> Result<Option<Box<dyn Read>>> {
+        self.state.lock().unwrap().get(cache_key)
+    }
+
+    fn insert(&self, cache_key: &str, content: Vec<u8>) -> Result<()> {
+        self.state.lock().unwrap().insert(cache_key, content)
+    }
+
+    fn max_size(&self) -> u64 {
+        self.state.lock().unwrap().max_size
+    }
+}
+
+#[derive(Debug)]
+enum Storage {
+    Memory(HashMap<String, Arc<[u8]>>),
+    Disk(PathBuf),
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    storage: Storage,
+    max_size: u64,
+    // Total size of cached entries
+    size: u64,
+    entries: HashMap<String, CacheEntry>,
+    // Cache keys ordered by when they were last used
+    lru: BTreeMap<u64, String>,
+    // Incremented whenever an entry is used
+    clock: u64,
+}
+
+impl CacheState {
+    /// Adds an entry to the index or marks an existing entry as most recently
+    /// used.
+    fn index(&mut self, cache_key: String, size: u64) {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(&cache_key) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = self.clock;
+        } else {
+            self.size += size;
+            self.entries.insert(
+                cache_key.clone(),
+                CacheEntry {
+                    size,
+                    last_used: self.clock,
+                },
+            );
+        }
+        self.lru.insert(self.clock, cache_key);
+    }
+
+    fn remove(&mut self, cache_key: &str) -> Result<()> {
+        if let Some(entry) = self.entries.remove(cache_key) {
+            self.lru.remove(&entry.last_used);
+            self.size -= entry.size;
+            match &mut self.storage {
+                Storage::Memory(objects) => {
+                    objects.remove(cache_key);
+                }
+                Storage::Disk(directory) => match remove_file(directory.join(cache_key)) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(e).context("failed to remove cache file");
+                    }
+                    _ => {}
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Evicts least recently used entries until `additional_size` more bytes
+    /// fit in the cache.
+    fn evict(&mut self, additional_size: u64) -> Result<()> {
+        while self.size + additional_size > self.max_size {
+            let oldest = match self.lru.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest)?;
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, cache_key: &str) -> Result<Option<Box<dyn Read>>> {
+        let size = match self.entries.get(cache_key) {
+            Some(entry) => entry.size,
+            None => return Ok(None),
+        };
+        let reader: Box<dyn Read> = match &self.storage {
+            Storage::Memory(objects) => Box::new(Cursor::new(objects[cache_key].clone())),
+            Storage::Disk(directory) => match File::open(directory.join(cache_key)) {
+                Ok(file) => Box::new(file),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    // Someone removed the file from under us
+                    self.remove(cache_key)?;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e).context("failed to open cache file"),
+            },
+        };
+        self.index(cache_key.to_owned(), size);
+        Ok(Some(reader))
+    }
+
+    fn insert(&mut self, cache_key: &str, content: Vec<u8>) -> Result<()> {
+        let size = content.len() as u64;
+        if size > self.max_size || self.entries.contains_key(cache_key) {
+            return Ok(());
+        }
+        self.evict(size)?;
+
+        match &mut self.storage {
+            Storage::Memory(objects) => {
+                objects.insert(cache_key.to_owned(), content.into());
+            }
+            Storage::Disk(directory) => {
+                // Write to a temporary file that is moved into place so that
+                // readers never see a partially written entry.
+                let mut temp_file = Builder::new()
+                    .prefix(TEMP_FILE_PREFIX)
+                    .tempfile_in(&directory)
+                    .context("failed to create temporary cache file")?;
+                temp_file
+                    .write_all(&content)
+                    .context("failed to write cache file")?;
+                temp_file
+                    .persist(directory.join(cache_key))
+                    .context("failed to move cache file into place")?;
+            }
+        }
+        self.index(cache_key.to_owned(), size);
+        Ok(())
+    }
+}
+
+/// A transport that wraps another transport, caching the contents of objects
+/// read with `get` in an ObjectCache. Entries are keyed by the inner
+/// transport's path, the object's key and its ETag, and every `get` first
+/// fetches the object's current ETag from the inner transport, so that a stale
+/// copy of an object that has since been overwritten is never served. This
+/// saves transferring the content of objects that are read many times, at the
+/// cost of an extra metadata request when an object isn't cached. Objects
+/// larger than the cache's maximum size are not cached. All other requests
+/// are passed through to the inner transport.
+#[derive(Debug)]
+pub struct CachingTransport<T: Transport> {
+    inner: T,
+    cache: ObjectCache,
+    logger: Logger,
+}
+
+impl<T: Transport> CachingTransport<T> {
+    /// Creates a CachingTransport that caches objects read from the provided
+    /// transport in `cache`.
+    pub fn new(inner: T, cache: ObjectCache, parent_logger: &Logger) -> Self {
+        let logger = parent_logger.new(o!(
+            event::STORAGE_PATH => inner.path(),
+        ));
+        CachingTransport {
+            inner,
+            cache,
+            logger,
+        }
+    }
+}
+
+impl<T: Transport> Transport for CachingTransport<T> {
+    fn path(&self) -> String {
+        self.inner.path()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        let etag = self.inner.etag(key, trace_id)?;
+
+        // Hash the components of the cache key so that it is a valid file name
+        let cache_key = hex::encode(
+            digest(
+                &SHA256,
+                format!("{}\n{}\n{}", self.inner.path(), key, etag).as_bytes(),
+            )
+            .as_ref(),
+        );
+
+        match self.cache.get(&cache_key) {
+            Ok(Some(reader)) => {
+                debug!(logger, "serving object from cache");
+                return Ok(reader);
+            }
+            Ok(None) => {}
+            Err(e) => warn!(logger, "failed to read object from cache: {:?}", e),
+        }
+
+        Ok(Box::new(CachingReader {
+            reader: self.inner.get(key, trace_id)?,
+            buffer: Some(Vec::new()),
+            max_size: self.cache.max_size(),
+            cache: self.cache.clone(),
+            cache_key,
+            logger,
+        }))
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.inner.put(key, trace_id)
+    }
+
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.inner.put_if_absent(key, trace_id)
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix, trace_id)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        self.inner.delete(key, trace_id)
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        self.inner.exists(key, trace_id)
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        self.inner.etag(key, trace_id)
+    }
+}
+
+/// Passes through content read from an object, keeping a copy which is
+/// inserted into the cache once the whole object has been read.
+struct CachingReader {
+    reader: Box<dyn Read>,
+    // None if the object is too big to be cached or was already inserted
+    buffer: Option<Vec<u8>>,
+    max_size: u64,
+    cache: ObjectCache,
+    cache_key: String,
+    logger: Logger,
+}
+
+impl Read for CachingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        if len == 0 && !buf.is_empty() {
+            if let Some(buffer) = self.buffer.take() {
+                if let Err(e) = self.cache.insert(&self.cache_key, buffer) {
+                    warn!(self.logger, "failed to insert object into cache: {:?}", e);
+                }
+            }
+        } else if let Some(buffer) = &mut self.buffer {
+            if (buffer.len() + len) as u64 > self.max_size {
+                self.buffer = None;
+            } else {
+                buffer.extend_from_slice(&buf[..len]);
+            }
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{logging::setup_test_logging, transport::MemoryTransport};
+
+    fn put_object(transport: &mut dyn Transport, key: &str, content: &[u8]) {
+        let mut writer = transport.put(key, "").unwrap();
+        writer.write_all(content).unwrap();
+        writer.complete_upload().unwrap();
+    }
+
+    fn get_object(transport: &mut dyn Transport, key: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        transport
+            .get(key, "")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        content
+    }
+
+    fn check_caching(cache: ObjectCache) {
+        let logger = setup_test_logging();
+        let mut memory_transport = MemoryTransport::new();
+        let mut transport = CachingTransport::new(memory_transport.clone(), cache, &logger);
+
+        put_object(&mut memory_transport, "key", b"content");
+        assert_eq!(get_object(&mut transport, "key"), b"content");
+
+        // Further reads are served from the cache while the ETag is unchanged
+        assert_eq!(transport.cache.state.lock().unwrap().entries.len(), 1);
+        assert_eq!(get_object(&mut transport, "key"), b"content");
+
+        // Overwritten objects are never served from the cache
+        put_object(&mut memory_transport, "key", b"new content");
+        assert_eq!(get_object(&mut transport, "key"), b"new content");
+        assert_eq!(get_object(&mut transport, "key"), b"new content");
+
+        // Deleted objects are not served from the cache
+        memory_transport.delete("key", "").unwrap();
+        assert!(transport.get("key", "").is_err());
+    }
+
+    #[test]
+    fn in_memory_cache() {
+        check_caching(ObjectCache::in_memory(1024));
+    }
+
+    #[test]
+    fn on_disk_cache() {
+        let directory = tempfile::tempdir().unwrap();
+        check_caching(ObjectCache::on_disk(directory.path().to_owned(), 1024).unwrap());
+
+        // Entries are reused by new caches in the same directory
+        let cache = ObjectCache::on_disk(directory.path().to_owned(), 1024).unwrap();
+        assert_eq!(cache.state.lock().unwrap().size, 18);
+
+        // ... and evicted if they don't fit
+        let cache = ObjectCache::on_disk(directory.path().to_owned(), 11).unwrap();
+        assert!(cache.state.lock().unwrap().size <= 11);
+        assert_eq!(read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = ObjectCache::in_memory(10);
+        cache.insert("a", vec![0; 4]).unwrap();
+        cache.insert("b", vec![0; 4]).unwrap();
+        // Using "a" makes "b" the least recently used entry
+        assert!(cache.get("a").unwrap().is_some());
+        cache.insert("c", vec![0; 4]).unwrap();
+
+        assert!(cache.get("a").unwrap().is_some());
+        assert!(cache.get("b").unwrap().is_none());
+        assert!(cache.get("c").unwrap().is_some());
+
+        // Entries bigger than the cache are not inserted
+        cache.insert("d", vec![0; 11]).unwrap();
+        assert!(cache.get("d").unwrap().is_none());
+        assert!(cache.get("a").unwrap().is_some());
+    }
+
+    #[test]
+    fn large_objects_not_cached() {
+        let logger = setup_test_logging();
+        let mut memory_transport = MemoryTransport::new();
+        let cache = ObjectCache::in_memory(4);
+        let mut transport = CachingTransport::new(memory_transport.clone(), cache.clone(), &logger);
+
+        put_object(&mut memory_transport, "key", b"content");
+        assert_eq!(get_object(&mut transport, "key"), b"content");
+        assert!(cache.state.lock().unwrap().entries.is_empty());
+    }
+}
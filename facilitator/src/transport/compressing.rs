@@ -0,0 +1,200 @@
+use crate::transport::{Transport, TransportWriter};
+use anyhow::{Context, Result};
+use libflate::gzip::{Decoder, Encoder};
+use std::io::{self, Cursor, Read, Write};
+
+/// The first two bytes of any gzip stream.
+/// https://datatracker.ietf.org/doc/html/rfc1952#page-5
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A transport that wraps another transport, gzipping objects written with
+/// `put` before they reach the inner transport. Objects read with `get` are
+/// gunzipped if they begin with the gzip magic bytes and are otherwise
+/// returned unchanged, so a CompressingTransport can read objects written
+/// before compression was enabled, or by peers that don't compress. Keys are
+/// not modified.
+#[derive(Debug)]
+pub struct CompressingTransport<T: Transport> {
+    inner: T,
+}
+
+impl<T: Transport> CompressingTransport<T> {
+    /// Creates a CompressingTransport that stores compressed objects in the
+    /// provided transport.
+    pub fn new(inner: T) -> Self {
+        CompressingTransport { inner }
+    }
+
+    fn gzip_writer(writer: Box<dyn TransportWriter>) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(GzipWriter {
+            encoder: Some(Encoder::new(writer).context("failed to write gzip header")?),
+        }))
+    }
+}
+
+impl<T: Transport> Transport for CompressingTransport<T> {
+    fn path(&self) -> String {
+        self.inner.path()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        let mut reader = self.inner.get(key, trace_id)?;
+
+        // Peek at the start of the object to see if it is gzipped, then put
+        // the bytes we read back in front of the rest of the object.
+        let mut magic = Vec::with_capacity(GZIP_MAGIC.len());
+        (&mut reader)
+            .take(GZIP_MAGIC.len() as u64)
+            .read_to_end(&mut magic)
+            .context("failed to read start of object")?;
+        let is_gzipped = magic == GZIP_MAGIC;
+        let reader = Cursor::new(magic).chain(reader);
+
+        if is_gzipped {
+            Ok(Box::new(
+                Decoder::new(reader).context("failed to read gzip header")?,
+            ))
+        } else {
+            Ok(Box::new(reader))
+        }
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        Self::gzip_writer(self.inner.put(key, trace_id)?)
+    }
+
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        Self::gzip_writer(self.inner.put_if_absent(key, trace_id)?)
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix, trace_id)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        self.inner.delete(key, trace_id)
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        self.inner.exists(key, trace_id)
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        self.inner.etag(key, trace_id)
+    }
+}
+
+/// Gzips content written to it into another TransportWriter.
+struct GzipWriter {
+    // None once the upload has been completed or canceled
+    encoder: Option<Encoder<Box<dyn TransportWriter>>>,
+}
+
+impl Write for GzipWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.write(buf),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "upload was already completed or canceled",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TransportWriter for GzipWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            // Finishing the encoder writes the gzip trailer
+            let mut writer = encoder
+                .finish()
+                .into_result()
+                .context("failed to finish gzip stream")?;
+            writer.complete_upload()?;
+        }
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        if let Some(mut encoder) = self.encoder.take() {
+            encoder.as_inner_mut().cancel_upload()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    #[test]
+    fn roundtrip_compressing_transport() {
+        let memory_transport = MemoryTransport::new();
+        let mut transport = CompressingTransport::new(memory_transport.clone());
+        let content = vec![7; 10_000];
+
+        let mut writer = transport.put("key", "").unwrap();
+        writer.write_all(&content).unwrap();
+        writer.complete_upload().unwrap();
+
+        // The object in the inner transport is gzipped
+        let mut stored = Vec::new();
+        memory_transport
+            .clone()
+            .get("key", "")
+            .unwrap()
+            .read_to_end(&mut stored)
+            .unwrap();
+        assert_eq!(stored[..2], GZIP_MAGIC);
+        assert!(stored.len() < content.len());
+
+        let mut content_again = Vec::new();
+        transport
+            .get("key", "")
+            .unwrap()
+            .read_to_end(&mut content_again)
+            .unwrap();
+        assert_eq!(content_again, content);
+        assert_eq!(transport.list("", "").unwrap(), vec!["key"]);
+    }
+
+    #[test]
+    fn get_uncompressed_objects() {
+        let mut memory_transport = MemoryTransport::new();
+        for content in &[&b""[..], b"a", b"uncompressed content"] {
+            let mut writer = memory_transport.put("key", "").unwrap();
+            writer.write_all(content).unwrap();
+            writer.complete_upload().unwrap();
+
+            let mut transport = CompressingTransport::new(memory_transport.clone());
+            let mut content_again = Vec::new();
+            transport
+                .get("key", "")
+                .unwrap()
+                .read_to_end(&mut content_again)
+                .unwrap();
+            assert_eq!(&content_again, content);
+        }
+    }
+
+    #[test]
+    fn cancel_upload() {
+        let memory_transport = MemoryTransport::new();
+        let mut transport = CompressingTransport::new(memory_transport.clone());
+
+        let mut writer = transport.put("key", "").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.cancel_upload().unwrap();
+
+        assert!(memory_transport.keys().is_empty());
+        assert!(writer.write_all(b"content").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    logging::event,
+    transport::{Transport, TransportWriter},
+    TransportError,
+};
+use anyhow::Result;
+use slog::{info, o, Logger};
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+/// A write that a DryRunTransport swallowed instead of passing it on to its
+/// inner transport.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedWrite {
+    /// The key of the object that would have been written.
+    pub key: String,
+    /// The number of bytes that would have been written to the object.
+    pub bytes: u64,
+}
+
+/// A transport that wraps another transport, passing reads through to it but
+/// swallowing writes and deletions, so that a task can be run against real
+/// storage without modifying it. Completed writes are logged and recorded,
+/// and may be retrieved with `writes`; canceled writes are not recorded.
+#[derive(Debug)]
+pub struct DryRunTransport<T: Transport> {
+    inner: T,
+    writes: Arc<Mutex<Vec<RecordedWrite>>>,
+    logger: Logger,
+}
+
+impl<T: Transport> DryRunTransport<T> {
+    /// Creates a DryRunTransport that reads from the provided transport and
+    /// never writes to it.
+    pub fn new(inner: T, parent_logger: &Logger) -> Self {
+        let logger = parent_logger.new(o!(
+            event::STORAGE_PATH => inner.path(),
+        ));
+        DryRunTransport {
+            inner,
+            writes: Arc::new(Mutex::new(Vec::new())),
+            logger,
+        }
+    }
+
+    /// Returns the writes that would have been made to the inner transport,
+    /// in the order in which they were completed.
+    pub fn writes(&self) -> Vec<RecordedWrite> {
+        self.writes.lock().unwrap().clone()
+    }
+
+    fn writer(&self, key: &str, trace_id: &str) -> Box<dyn TransportWriter> {
+        Box::new(DryRunWriter {
+            key: key.to_owned(),
+            bytes: 0,
+            writes: self.writes.clone(),
+            logger: self.logger.new(o!(
+                event::TRACE_ID => trace_id.to_owned(),
+                event::STORAGE_KEY => key.to_owned(),
+            )),
+        })
+    }
+}
+
+impl<T: Transport> Transport for DryRunTransport<T> {
+    fn path(&self) -> String {
+        self.inner.path()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        self.inner.get(key, trace_id)
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(self.writer(key, trace_id))
+    }
+
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        // Fail the same way a real conditional upload would, so that a dry run
+        // takes the same path through the task as a real one.
+        if self.inner.exists(key, trace_id)? {
+            return Err(TransportError::AlreadyExists(key.to_owned()).into());
+        }
+        Ok(self.writer(key, trace_id))
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix, trace_id)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        info!(
+            self.logger, "dry run: not deleting object";
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        );
+        Ok(())
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        self.inner.exists(key, trace_id)
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        self.inner.etag(key, trace_id)
+    }
+}
+
+/// Counts the bytes written to it and discards them, recording the write when
+/// the upload is completed.
+struct DryRunWriter {
+    key: String,
+    bytes: u64,
+    writes: Arc<Mutex<Vec<RecordedWrite>>>,
+    logger: Logger,
+}
+
+impl Write for DryRunWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TransportWriter for DryRunWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        info!(
+            self.logger, "dry run: not writing object";
+            "bytes" => self.bytes,
+        );
+        self.writes.lock().unwrap().push(RecordedWrite {
+            key: self.key.clone(),
+            bytes: self.bytes,
+        });
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logging::setup_test_logging,
+        transport::{is_already_exists_error, MemoryTransport},
+    };
+
+    #[test]
+    fn dry_run_transport() {
+        let logger = setup_test_logging();
+        let mut memory = MemoryTransport::new();
+        let mut writer = memory.put("existing", "").unwrap();
+        writer.write_all(b"existing content").unwrap();
+        writer.complete_upload().unwrap();
+
+        let mut transport = DryRunTransport::new(memory.clone(), &logger);
+
+        // Reads are passed through
+        let mut content = Vec::new();
+        transport
+            .get("existing", "")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"existing content");
+        assert_eq!(transport.list("", "").unwrap(), vec!["existing"]);
+
+        // Writes and deletions are swallowed
+        let mut writer = transport.put("new", "").unwrap();
+        writer.write_all(b"new content").unwrap();
+        writer.complete_upload().unwrap();
+        let mut writer = transport.put("canceled", "").unwrap();
+        writer.write_all(b"canceled content").unwrap();
+        writer.cancel_upload().unwrap();
+        let mut writer = transport.put("existing", "").unwrap();
+        writer.write_all(b"overwritten").unwrap();
+        writer.complete_upload().unwrap();
+        transport.delete("existing", "").unwrap();
+
+        assert_eq!(memory.keys(), vec!["existing"]);
+        assert!(!transport.exists("new", "").unwrap());
+        assert_eq!(
+            transport.writes(),
+            vec![
+                RecordedWrite {
+                    key: "new".to_owned(),
+                    bytes: 11,
+                },
+                RecordedWrite {
+                    key: "existing".to_owned(),
+                    bytes: 11,
+                },
+            ]
+        );
+
+        // Conditional writes fail if the object really exists
+        let error = transport.put_if_absent("existing", "").err().unwrap();
+        assert!(is_already_exists_error(&error), "{:?}", error);
+        transport
+            .put_if_absent("absent", "")
+            .unwrap()
+            .complete_upload()
+            .unwrap();
+        assert_eq!(transport.writes().len(), 3);
+    }
+}
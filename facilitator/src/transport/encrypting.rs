@@ -0,0 +1,519 @@
+use crate::transport::{Transport, TransportWriter};
+use anyhow::{anyhow, Context, Result};
+use derivative::Derivative;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use std::io::{self, Read, Write};
+
+/// Bytes identifying an object written by EncryptingTransport, and the version
+/// of the format.
+const MAGIC: [u8; 4] = *b"PSE\x01";
+
+/// Length in bytes of the random nonce prefix stored after the magic bytes.
+/// The remaining bytes of each chunk's nonce are a 32 bit big-endian chunk
+/// counter and a flag indicating whether the chunk is the last one.
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 5;
+
+/// Size of the plaintext in every chunk but the last one, which may be
+/// shorter (or empty).
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Length of the authentication tag appended to each chunk by AES-256-GCM.
+const TAG_LEN: usize = 16;
+
+/// Length of a ciphertext chunk that holds CHUNK_SIZE bytes of plaintext.
+const CIPHERTEXT_CHUNK_SIZE: usize = CHUNK_SIZE + TAG_LEN;
+
+/// Length in bytes of AES-256-GCM keys.
+const STORAGE_ENCRYPTION_KEY_LEN: usize = 32;
+
+/// A transport that wraps another transport, encrypting objects written with
+/// `put` before they reach the inner transport and decrypting and
+/// authenticating objects read with `get`.
+///
+/// Objects are encrypted with AES-256-GCM in fixed size chunks, following the
+/// STREAM construction[1], so that they can be streamed without being held in
+/// memory. Each object gets a random nonce prefix, each chunk's nonce includes
+/// its index and whether it is the last chunk, and the object's key is used as
+/// associated data, so reordering, truncating, extending or moving objects is
+/// detected when they are read.
+///
+/// Objects are encrypted with the first of the provided keys. When reading,
+/// each key is tried in turn until one works, which allows keys to be rotated.
+/// Objects that were not written by an EncryptingTransport can't be read.
+/// Keys are not modified, and neither object keys nor sizes are hidden.
+///
+/// [1] https://eprint.iacr.org/2015/189.pdf
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct EncryptingTransport<T: Transport> {
+    inner: T,
+    #[derivative(Debug = "ignore")]
+    keys: Vec<Vec<u8>>,
+}
+
+impl<T: Transport> EncryptingTransport<T> {
+    /// Creates an EncryptingTransport that stores objects in the provided
+    /// transport, encrypted with the first of the provided keys. Returns an
+    /// error if no keys are provided or if any key is not a 32 byte AES-256
+    /// key.
+    pub fn new(inner: T, keys: Vec<Vec<u8>>) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(anyhow!("at least one storage encryption key is required"));
+        }
+        for key in &keys {
+            aead_key(key)?;
+        }
+        Ok(EncryptingTransport { inner, keys })
+    }
+
+    /// Writes the encryption header to the provided writer and returns a
+    /// writer that encrypts content for the object with the provided key.
+    fn encrypting_writer(
+        &self,
+        mut writer: Box<dyn TransportWriter>,
+        key: &str,
+    ) -> Result<Box<dyn TransportWriter>> {
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce_prefix)
+            .map_err(|_| anyhow!("failed to generate nonce prefix"))?;
+
+        if let Err(error) = writer
+            .write_all(&MAGIC)
+            .and_then(|_| writer.write_all(&nonce_prefix))
+        {
+            writer.cancel_upload()?;
+            return Err(error).context("failed to write encryption header");
+        }
+
+        Ok(Box::new(EncryptingWriter {
+            writer: Some(writer),
+            key: aead_key(&self.keys[0])?,
+            nonce_prefix,
+            aad: key.to_owned(),
+            counter: 0,
+            buffer: Vec::with_capacity(CIPHERTEXT_CHUNK_SIZE),
+        }))
+    }
+}
+
+impl<T: Transport> Transport for EncryptingTransport<T> {
+    fn path(&self) -> String {
+        self.inner.path()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        let mut reader = self.inner.get(key, trace_id)?;
+
+        let mut header = [0; MAGIC.len() + NONCE_PREFIX_LEN];
+        reader
+            .read_exact(&mut header)
+            .with_context(|| format!("failed to read encryption header of {}", key))?;
+        if header[..MAGIC.len()] != MAGIC {
+            return Err(anyhow!("object {} is not encrypted", key));
+        }
+        let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+        nonce_prefix.copy_from_slice(&header[MAGIC.len()..]);
+
+        // Find the key the object was encrypted with by trying each of them on
+        // the first chunk.
+        let mut carry = Vec::with_capacity(CIPHERTEXT_CHUNK_SIZE + 1);
+        let (first_chunk, last) = read_chunk(&mut reader, &mut carry)
+            .with_context(|| format!("failed to read first chunk of {}", key))?;
+        for aead_key in self.keys.iter().map(|k| aead_key(k)) {
+            let aead_key = aead_key?;
+            if let Ok(plaintext) =
+                open_chunk(&aead_key, &nonce_prefix, 0, last, key, first_chunk.clone())
+            {
+                return Ok(Box::new(DecryptingReader {
+                    reader,
+                    key: aead_key,
+                    nonce_prefix,
+                    aad: key.to_owned(),
+                    counter: 0,
+                    carry,
+                    plaintext,
+                    position: 0,
+                    finished: last,
+                }));
+            }
+        }
+
+        Err(anyhow!(
+            "object {} could not be decrypted with any storage encryption key",
+            key
+        ))
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        let writer = self.inner.put(key, trace_id)?;
+        self.encrypting_writer(writer, key)
+    }
+
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        let writer = self.inner.put_if_absent(key, trace_id)?;
+        self.encrypting_writer(writer, key)
+    }
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix, trace_id)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        self.inner.delete(key, trace_id)
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        self.inner.exists(key, trace_id)
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        self.inner.etag(key, trace_id)
+    }
+}
+
+fn aead_key(key: &[u8]) -> Result<LessSafeKey> {
+    if key.len() != STORAGE_ENCRYPTION_KEY_LEN {
+        return Err(anyhow!(
+            "storage encryption key must be {} bytes, not {}",
+            STORAGE_ENCRYPTION_KEY_LEN,
+            key.len()
+        ));
+    }
+    let key = UnboundKey::new(&AES_256_GCM, key)
+        .map_err(|_| anyhow!("invalid storage encryption key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn chunk_nonce(nonce_prefix: &[u8; NONCE_PREFIX_LEN], counter: u32, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(nonce_prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Decrypts and authenticates a ciphertext chunk, returning the plaintext.
+fn open_chunk(
+    key: &LessSafeKey,
+    nonce_prefix: &[u8; NONCE_PREFIX_LEN],
+    counter: u32,
+    last: bool,
+    aad: &str,
+    mut chunk: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let plaintext_len = key
+        .open_in_place(
+            chunk_nonce(nonce_prefix, counter, last),
+            Aad::from(aad.as_bytes()),
+            &mut chunk,
+        )
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to decrypt chunk {} of {}", counter, aad),
+            )
+        })?
+        .len();
+    chunk.truncate(plaintext_len);
+    Ok(chunk)
+}
+
+/// Reads the next ciphertext chunk from the reader, returning the chunk and
+/// whether it is the last one. The last chunk is the one that is followed by
+/// EOF, so one byte past the chunk is read ahead into `carry`, which must be
+/// passed to subsequent calls.
+fn read_chunk(reader: &mut dyn Read, carry: &mut Vec<u8>) -> io::Result<(Vec<u8>, bool)> {
+    let wanted = CIPHERTEXT_CHUNK_SIZE + 1 - carry.len();
+    reader.take(wanted as u64).read_to_end(carry)?;
+
+    if carry.len() > CIPHERTEXT_CHUNK_SIZE {
+        let rest = carry.split_off(CIPHERTEXT_CHUNK_SIZE);
+        let chunk = std::mem::replace(carry, rest);
+        Ok((chunk, false))
+    } else {
+        Ok((std::mem::take(carry), true))
+    }
+}
+
+/// Decrypts an object written by EncryptingWriter, one chunk at a time.
+struct DecryptingReader {
+    reader: Box<dyn Read>,
+    key: LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    aad: String,
+    // Index of the chunk in plaintext
+    counter: u32,
+    // Ciphertext read ahead of the current chunk
+    carry: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+    // True once the last chunk has been decrypted
+    finished: bool,
+}
+
+impl Read for DecryptingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.counter = self.counter.checked_add(1).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "too many chunks in object")
+            })?;
+            let (chunk, last) = read_chunk(&mut self.reader, &mut self.carry)?;
+            self.plaintext = open_chunk(
+                &self.key,
+                &self.nonce_prefix,
+                self.counter,
+                last,
+                &self.aad,
+                chunk,
+            )?;
+            self.position = 0;
+            self.finished = last;
+        }
+
+        let len = buf.len().min(self.plaintext.len() - self.position);
+        buf[..len].copy_from_slice(&self.plaintext[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Encrypts content written to it into another TransportWriter, one chunk at a
+/// time. The final chunk is only written when the upload is completed.
+struct EncryptingWriter {
+    // None once the upload has been completed or canceled
+    writer: Option<Box<dyn TransportWriter>>,
+    key: LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    aad: String,
+    // Index of the chunk being buffered
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl EncryptingWriter {
+    /// Encrypts the buffered chunk and writes it to the inner writer.
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let writer = self.writer.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "upload was already completed or canceled",
+            )
+        })?;
+
+        let mut chunk =
+            std::mem::replace(&mut self.buffer, Vec::with_capacity(CIPHERTEXT_CHUNK_SIZE));
+        self.key
+            .seal_in_place_append_tag(
+                chunk_nonce(&self.nonce_prefix, self.counter, last),
+                Aad::from(self.aad.as_bytes()),
+                &mut chunk,
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to encrypt chunk"))?;
+        writer.write_all(&chunk)?;
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "too many chunks in object"))?;
+        Ok(())
+    }
+}
+
+impl Write for EncryptingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.writer.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "upload was already completed or canceled",
+            ));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // A full chunk is only sealed once more content arrives, since we
+        // can't know whether it's the last chunk until then.
+        if self.buffer.len() == CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+        let len = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TransportWriter for EncryptingWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        if self.writer.is_some() {
+            self.seal_chunk(true)
+                .context("failed to write final encrypted chunk")?;
+            if let Some(mut writer) = self.writer.take() {
+                writer.complete_upload()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.buffer.clear();
+        if let Some(mut writer) = self.writer.take() {
+            writer.cancel_upload()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    fn test_key(byte: u8) -> Vec<u8> {
+        vec![byte; STORAGE_ENCRYPTION_KEY_LEN]
+    }
+
+    fn put_object(transport: &mut dyn Transport, key: &str, content: &[u8]) {
+        let mut writer = transport.put(key, "").unwrap();
+        writer.write_all(content).unwrap();
+        writer.complete_upload().unwrap();
+    }
+
+    fn get_object(transport: &mut dyn Transport, key: &str) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        transport.get(key, "")?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    #[test]
+    fn roundtrip_encrypting_transport() {
+        let mut memory_transport = MemoryTransport::new();
+        let mut transport =
+            EncryptingTransport::new(memory_transport.clone(), vec![test_key(1)]).unwrap();
+
+        for &len in &[
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE + 17,
+        ] {
+            let content: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            put_object(&mut transport, "key", &content);
+
+            // The object in the inner transport is not the plaintext
+            let stored = get_object(&mut memory_transport, "key").unwrap();
+            assert_eq!(stored[..MAGIC.len()], MAGIC);
+            let chunks = if len == 0 {
+                1
+            } else {
+                (len - 1) / CHUNK_SIZE + 1
+            };
+            assert_eq!(
+                stored.len(),
+                MAGIC.len() + NONCE_PREFIX_LEN + len + chunks * TAG_LEN
+            );
+
+            assert_eq!(get_object(&mut transport, "key").unwrap(), content);
+        }
+        assert_eq!(transport.list("", "").unwrap(), vec!["key"]);
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(EncryptingTransport::new(MemoryTransport::new(), vec![]).is_err());
+        assert!(EncryptingTransport::new(MemoryTransport::new(), vec![vec![1; 16]]).is_err());
+    }
+
+    #[test]
+    fn key_rotation() {
+        let memory_transport = MemoryTransport::new();
+        let mut old_transport =
+            EncryptingTransport::new(memory_transport.clone(), vec![test_key(1)]).unwrap();
+        put_object(&mut old_transport, "old", b"old content");
+
+        let mut new_transport =
+            EncryptingTransport::new(memory_transport.clone(), vec![test_key(2), test_key(1)])
+                .unwrap();
+        put_object(&mut new_transport, "new", b"new content");
+
+        assert_eq!(
+            get_object(&mut new_transport, "old").unwrap(),
+            b"old content"
+        );
+        assert_eq!(
+            get_object(&mut new_transport, "new").unwrap(),
+            b"new content"
+        );
+        assert!(get_object(&mut old_transport, "new").is_err());
+    }
+
+    #[test]
+    fn tampering_detected() {
+        let mut memory_transport = MemoryTransport::new();
+        let mut transport =
+            EncryptingTransport::new(memory_transport.clone(), vec![test_key(1)]).unwrap();
+        let content = vec![7; 2 * CHUNK_SIZE + 100];
+        put_object(&mut transport, "key", &content);
+        let stored = get_object(&mut memory_transport, "key").unwrap();
+        let header_len = MAGIC.len() + NONCE_PREFIX_LEN;
+
+        // Flipped bit in the last chunk
+        let mut modified = stored.clone();
+        *modified.last_mut().unwrap() ^= 1;
+        put_object(&mut memory_transport, "key", &modified);
+        assert!(get_object(&mut transport, "key").is_err());
+
+        // Truncated at a chunk boundary
+        put_object(
+            &mut memory_transport,
+            "key",
+            &stored[..header_len + 2 * CIPHERTEXT_CHUNK_SIZE],
+        );
+        assert!(get_object(&mut transport, "key").is_err());
+
+        // Chunks reordered
+        let mut reordered = stored[..header_len].to_vec();
+        reordered.extend_from_slice(
+            &stored[header_len + CIPHERTEXT_CHUNK_SIZE..][..CIPHERTEXT_CHUNK_SIZE],
+        );
+        reordered.extend_from_slice(&stored[header_len..][..CIPHERTEXT_CHUNK_SIZE]);
+        reordered.extend_from_slice(&stored[header_len + 2 * CIPHERTEXT_CHUNK_SIZE..]);
+        put_object(&mut memory_transport, "key", &reordered);
+        assert!(get_object(&mut transport, "key").is_err());
+
+        // Moved to another key
+        put_object(&mut memory_transport, "other-key", &stored);
+        assert!(get_object(&mut transport, "other-key").is_err());
+
+        // Not encrypted at all
+        put_object(&mut memory_transport, "plaintext", b"plaintext content");
+        assert!(get_object(&mut transport, "plaintext").is_err());
+    }
+
+    #[test]
+    fn cancel_upload() {
+        let memory_transport = MemoryTransport::new();
+        let mut transport =
+            EncryptingTransport::new(memory_transport.clone(), vec![test_key(1)]).unwrap();
+
+        let mut writer = transport.put("key", "").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.cancel_upload().unwrap();
+
+        assert!(memory_transport.keys().is_empty());
+        assert!(writer.write_all(b"content").is_err());
+    }
+}
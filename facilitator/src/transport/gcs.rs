@@ -2,19 +2,18 @@ use crate::{
     config::{GcsPath, Identity, WorkloadIdentityPoolParameters},
     gcp_oauth::GcpOauthTokenProvider,
     http::{
-        Method, OauthTokenProvider, RequestParameters, RetryingAgent, StaticOauthTokenProvider,
+        error_http_status, Method, OauthTokenProvider, RequestClass, RequestParameters,
+        RetryingAgent, StaticOauthTokenProvider,
     },
     logging::event,
+    runtime::BackgroundWriter,
     transport::{Transport, TransportWriter},
-    Error,
+    Error, TransportError,
 };
 use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
 use slog::{debug, info, o, Logger};
-use std::{
-    io::{self, Read, Write},
-    time::Duration,
-};
-use ureq::AgentBuilder;
+use std::io::{self, Read, Write};
 use url::Url;
 
 fn storage_api_base_url() -> Url {
@@ -32,6 +31,35 @@ fn gcp_object_url(bucket: &str, encoded_key: &str) -> Result<Url> {
     Url::parse(request_url).context(format!("failed to parse: {}", request_url))
 }
 
+fn gcp_list_objects_url(bucket: &str) -> Result<Url> {
+    let request_url = &format!("{}storage/v1/b/{}/o", storage_api_base_url(), bucket);
+
+    Url::parse(request_url).context(format!("failed to parse: {}", request_url))
+}
+
+/// Represents the subset of the response to a GCS objects.list request that we
+/// use.
+/// https://cloud.google.com/storage/docs/json_api/v1/objects/list#response
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListObjectsResponse {
+    #[serde(default)]
+    items: Vec<ListedObject>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListedObject {
+    name: String,
+}
+
+/// Represents the subset of a GCS object resource that we use.
+/// https://cloud.google.com/storage/docs/json_api/v1/objects#resource
+#[derive(Deserialize)]
+struct ObjectMetadata {
+    etag: String,
+}
+
 fn gcp_upload_object_url(storage_api_url: &str, bucket: &str) -> Result<Url> {
     let request_url = &format!("{}upload/storage/v1/b/{}/o/", storage_api_url, bucket);
 
@@ -68,14 +96,8 @@ impl GcsTransport {
             event::STORAGE_PATH => path.to_string(),
             event::IDENTITY => identity.unwrap_or("default identity").to_owned(),
         ));
-        let ureq_agent = AgentBuilder::new()
-            // We set an unusually long timeout for uploads to GCS, per Google's
-            // recommendation:
-            // https://cloud.google.com/storage/docs/best-practices#uploading
-            .timeout(Duration::from_secs(120))
-            .build();
         let retrying_agent = RetryingAgent::new(
-            ureq_agent,
+            RequestClass::Storage,
             // Per Google documentation, HTTP 408 Request Timeout and HTTP 429
             // Too Many Requests shouldbe retried
             // https://cloud.google.com/storage/docs/retry-strategy
@@ -96,6 +118,40 @@ impl GcsTransport {
             logger,
         })
     }
+
+    /// Starts a streaming upload of the provided key. If exclusive is true,
+    /// the upload fails with TransportError::AlreadyExists if the object
+    /// already exists.
+    fn writer(
+        &mut self,
+        key: &str,
+        trace_id: &str,
+        exclusive: bool,
+    ) -> Result<Box<dyn TransportWriter>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+            event::ACTION => "put GCS object",
+        ));
+        info!(logger, "put"; "exclusive" => exclusive);
+
+        // The Oauth token will only be used once, during the call to
+        // StreamingTransferWriter::new, so we don't have to worry about it
+        // expiring during the lifetime of that object, and so obtain a token
+        // here instead of passing the token provider into the
+        // StreamingTransferWriter.
+        let oauth_token = self.oauth_token_provider.ensure_oauth_token()?;
+        let writer = StreamingTransferWriter::new(
+            self.path.bucket.to_owned(),
+            [&self.path.key, key].concat(),
+            exclusive,
+            oauth_token,
+            self.agent.clone(),
+            &logger,
+        )?;
+        // Chunks are uploaded while the caller writes the next one
+        Ok(Box::new(BackgroundWriter::new(writer)?))
+    }
 }
 
 impl Transport for GcsTransport {
@@ -127,36 +183,170 @@ impl Transport for GcsTransport {
             token_provider: Some(&mut self.oauth_token_provider),
         })?;
 
-        let response = self
-            .agent
-            .call(&logger, &request)
-            .context(format!("failed to fetch object {} from GCS", url))?;
+        let response = self.agent.call(&logger, &request).map_err(|e| {
+            let not_found = error_http_status(&e) == Some(404);
+            let error = e.context(format!("failed to fetch object {} from GCS", url));
+            if not_found {
+                error.context(TransportError::NotFound(key.to_owned()))
+            } else {
+                error
+            }
+        })?;
 
-        Ok(Box::new(response.into_reader()))
+        Ok(Box::new(response.into_reader()?))
     }
 
     fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.writer(key, trace_id, false)
+    }
+
+    /// Uploads with the ifGenerationMatch=0 precondition, so GCS only creates
+    /// the object if no live version of it exists when the upload completes.
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.writer(key, trace_id, true)
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => prefix.to_owned(),
+            event::ACTION => "list GCS objects",
+        ));
+        info!(logger, "list");
+
+        // Results are paginated, so we follow page tokens until there are no
+        // more results.
+        // API reference: https://cloud.google.com/storage/docs/json_api/v1/objects/list
+        let mut keys = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = gcp_list_objects_url(&self.path.bucket)?;
+            {
+                let mut query_pairs = url.query_pairs_mut();
+                query_pairs
+                    .append_pair("prefix", &[&self.path.key, prefix].concat())
+                    .append_pair("fields", "items(name),nextPageToken");
+                if let Some(page_token) = &page_token {
+                    query_pairs.append_pair("pageToken", page_token);
+                }
+            }
+
+            let request = self.agent.prepare_request(RequestParameters {
+                url: url.clone(),
+                method: Method::Get,
+                token_provider: Some(&mut self.oauth_token_provider),
+            })?;
+
+            let http_response = self
+                .agent
+                .call(&logger, &request)
+                .context(format!("failed to list objects in GCS: {}", url))?;
+            let response: ListObjectsResponse = self
+                .agent
+                .read_json(http_response)
+                .context("failed to deserialize objects.list response")?;
+
+            for item in response.items {
+                keys.push(
+                    item.name
+                        .strip_prefix(&self.path.key)
+                        .context(format!("listed key {} outside of GCS path", item.name))?
+                        .to_owned(),
+                );
+            }
+
+            match response.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
         let logger = self.logger.new(o!(
             event::TRACE_ID => trace_id.to_owned(),
             event::STORAGE_KEY => key.to_owned(),
-            event::ACTION => "put GCS object",
+            event::ACTION => "delete GCS object",
         ));
-        info!(logger, "put");
+        info!(logger, "delete");
 
-        // The Oauth token will only be used once, during the call to
-        // StreamingTransferWriter::new, so we don't have to worry about it
-        // expiring during the lifetime of that object, and so obtain a token
-        // here instead of passing the token provider into the
-        // StreamingTransferWriter.
-        let oauth_token = self.oauth_token_provider.ensure_oauth_token()?;
-        let writer = StreamingTransferWriter::new(
-            self.path.bucket.to_owned(),
-            [&self.path.key, key].concat(),
-            oauth_token,
-            self.agent.clone(),
-            &logger,
-        )?;
-        Ok(Box::new(writer))
+        // API reference: https://cloud.google.com/storage/docs/json_api/v1/objects/delete
+        let encoded_key = urlencoding::encode(&[&self.path.key, key].concat());
+        let url = gcp_object_url(&self.path.bucket, &encoded_key)?;
+
+        let request = self.agent.prepare_request(RequestParameters {
+            url: url.clone(),
+            method: Method::Delete,
+            token_provider: Some(&mut self.oauth_token_provider),
+        })?;
+
+        match self.agent.call(&logger, &request) {
+            Ok(_) => Ok(()),
+            Err(e) if error_http_status(&e) == Some(404) => Ok(()),
+            Err(e) => Err(e).context(format!("failed to delete object {} from GCS", url)),
+        }
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+            event::ACTION => "get GCS object metadata",
+        ));
+        info!(logger, "exists");
+
+        // Without the alt=media parameter, objects.get returns only the
+        // object's metadata.
+        // API reference: https://cloud.google.com/storage/docs/json_api/v1/objects/get
+        let encoded_key = urlencoding::encode(&[&self.path.key, key].concat());
+        let url = gcp_object_url(&self.path.bucket, &encoded_key)?;
+
+        let request = self.agent.prepare_request(RequestParameters {
+            url: url.clone(),
+            method: Method::Get,
+            token_provider: Some(&mut self.oauth_token_provider),
+        })?;
+
+        match self.agent.call(&logger, &request) {
+            Ok(_) => Ok(true),
+            Err(e) if error_http_status(&e) == Some(404) => Ok(false),
+            Err(e) => Err(e).context(format!(
+                "failed to get metadata for object {} from GCS",
+                url
+            )),
+        }
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+            event::ACTION => "get GCS object metadata",
+        ));
+        info!(logger, "etag");
+
+        // API reference: https://cloud.google.com/storage/docs/json_api/v1/objects/get
+        let encoded_key = urlencoding::encode(&[&self.path.key, key].concat());
+        let url = gcp_object_url(&self.path.bucket, &encoded_key)?;
+
+        let request = self.agent.prepare_request(RequestParameters {
+            url: url.clone(),
+            method: Method::Get,
+            token_provider: Some(&mut self.oauth_token_provider),
+        })?;
+
+        let http_response = self.agent.call(&logger, &request).context(format!(
+            "failed to get metadata for object {} from GCS",
+            url
+        ))?;
+        let metadata: ObjectMetadata = self
+            .agent
+            .read_json(http_response)
+            .context("failed to parse GCS object metadata")?;
+
+        Ok(metadata.etag)
     }
 }
 
@@ -185,6 +375,7 @@ impl Transport for GcsTransport {
 // upload_chunk when we know it's the last chunk: (1) we construct the Content-
 // Range header without any asterisks (2) we drain self.buffer.
 struct StreamingTransferWriter {
+    object: String,
     upload_session_uri: Url,
     minimum_upload_chunk_size: usize,
     object_upload_position: usize,
@@ -196,11 +387,14 @@ struct StreamingTransferWriter {
 impl StreamingTransferWriter {
     /// Creates a new writer that streams content in chunks into GCS. Bucket is
     /// the name of the GCS bucket. Object is the full name of the object being
-    /// uploaded, which may contain path separators or file extensions.
-    /// oauth_token is used to initiate the initial resumable upload request.
+    /// uploaded, which may contain path separators or file extensions. If
+    /// exclusive is true, the upload only creates the object if it does not
+    /// exist yet. oauth_token is used to initiate the initial resumable upload
+    /// request.
     fn new(
         bucket: String,
         object: String,
+        exclusive: bool,
         oauth_token: String,
         agent: RetryingAgent,
         parent_logger: &Logger,
@@ -208,6 +402,7 @@ impl StreamingTransferWriter {
         StreamingTransferWriter::new_with_api_url(
             bucket,
             object,
+            exclusive,
             oauth_token,
             // GCP documentation recommends setting upload part size to 8 MiB.
             // https://cloud.google.com/storage/docs/performing-resumable-uploads#chunked-upload
@@ -218,9 +413,11 @@ impl StreamingTransferWriter {
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new_with_api_url(
         bucket: String,
         object: String,
+        exclusive: bool,
         oauth_token: String,
         minimum_upload_chunk_size: usize,
         storage_api_base_url: Url,
@@ -233,8 +430,16 @@ impl StreamingTransferWriter {
         upload_url
             .query_pairs_mut()
             .append_pair("uploadType", "resumable")
-            .append_pair("name", &object)
-            .finish();
+            .append_pair("name", &object);
+        if exclusive {
+            // A generation of 0 matches only if there is no live version of
+            // the object. GCS checks the precondition both here and when the
+            // last chunk is uploaded.
+            // https://cloud.google.com/storage/docs/request-preconditions#special-case
+            upload_url
+                .query_pairs_mut()
+                .append_pair("ifGenerationMatch", "0");
+        }
 
         debug!(parent_logger, "initiating multi-part upload");
         let request = agent.prepare_request(RequestParameters {
@@ -245,7 +450,15 @@ impl StreamingTransferWriter {
 
         let http_response = agent
             .send_bytes(parent_logger, &request, &[])
-            .context(format!("uploading to gs://{} failed", bucket))?;
+            .map_err(|e| {
+                let already_exists = error_http_status(&e) == Some(412);
+                let error = e.context(format!("uploading to gs://{} failed", bucket));
+                if already_exists {
+                    error.context(TransportError::AlreadyExists(object.clone()))
+                } else {
+                    error
+                }
+            })?;
 
         // The upload session URI authenticates subsequent upload requests for
         // this upload, so we no longer need the impersonated service account's
@@ -257,6 +470,7 @@ impl StreamingTransferWriter {
             .context("no Location header in response when initiating streaming transfer")?;
 
         Ok(StreamingTransferWriter {
+            object,
             minimum_upload_chunk_size,
             buffer: Vec::with_capacity(minimum_upload_chunk_size * 2),
             object_upload_position: 0,
@@ -319,10 +533,18 @@ impl StreamingTransferWriter {
         let http_response = self
             .agent
             .send_bytes(&self.logger, &request, body)
-            .context(format!(
-                "failed in sending bytes to gcs upload session: {}",
-                &self.upload_session_uri
-            ))?;
+            .map_err(|e| {
+                let already_exists = error_http_status(&e) == Some(412);
+                let error = e.context(format!(
+                    "failed in sending bytes to gcs upload session: {}",
+                    &self.upload_session_uri
+                ));
+                if already_exists {
+                    error.context(TransportError::AlreadyExists(self.object.clone()))
+                } else {
+                    error
+                }
+            })?;
 
         // On success we expect HTTP 308 Resume Incomplete and a Range: header,
         // unless this is the last part and the server accepts the entire
@@ -337,9 +559,9 @@ impl StreamingTransferWriter {
             200 | 201 => Err(anyhow!(
                 "received HTTP 200 or 201 response with chunks remaining"
             )),
-            308 if !http_response.has("Range") => Err(anyhow!(
+            308 if http_response.header("Range").is_none() => Err(anyhow!(
                 "No range header in response from GCS: {:?}",
-                http_response.into_string()
+                self.agent.read_string(http_response)
             )),
             308 => {
                 let range_header = http_response.header("Range").unwrap();
@@ -376,7 +598,7 @@ impl StreamingTransferWriter {
             status => Err(anyhow!(
                 "failed to upload part to GCS: {} \n{:?}",
                 status,
-                http_response.into_string()
+                self.agent.read_string(http_response)
             )),
         }
     }
@@ -467,6 +689,7 @@ mod tests {
         let mut writer = StreamingTransferWriter::new_with_api_url(
             "fake-bucket".to_string(),
             "fake-object".to_string(),
+            false,
             "fake-token".to_string(),
             10,
             Url::parse(&mockito::server_url()).expect("unable to parse mockito server url"),
@@ -514,6 +737,7 @@ mod tests {
         let mut writer = StreamingTransferWriter::new_with_api_url(
             "fake-bucket".to_string(),
             "fake-object".to_string(),
+            false,
             "fake-token".to_string(),
             4,
             Url::parse(&mockito::server_url()).expect("unable to parse mockito server url"),
@@ -557,4 +781,49 @@ mod tests {
         second_mocked_put.assert();
         final_mocked_put.assert();
     }
+
+    #[test]
+    fn exclusive_upload_of_existing_object() {
+        let logger = setup_test_logging();
+        let fake_upload_session_uri =
+            format!("{}/fake-exclusive-session-uri", mockito::server_url());
+        let mocked_post = mock("POST", "/upload/storage/v1/b/fake-bucket/o/")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("name".to_owned(), "fake-exclusive-object".to_owned()),
+                Matcher::UrlEncoded("ifGenerationMatch".to_owned(), "0".to_owned()),
+            ]))
+            .with_status(200)
+            .with_header("Location", &fake_upload_session_uri)
+            .expect_at_most(1)
+            .create();
+
+        let mut writer = StreamingTransferWriter::new_with_api_url(
+            "fake-bucket".to_string(),
+            "fake-exclusive-object".to_string(),
+            true,
+            "fake-token".to_string(),
+            10,
+            Url::parse(&mockito::server_url()).expect("unable to parse mockito server url"),
+            RetryingAgent::default(),
+            &logger,
+        )
+        .unwrap();
+
+        mocked_post.assert();
+
+        // GCS checks the precondition again when the object is finalized
+        let mocked_put = mock("PUT", "/fake-exclusive-session-uri")
+            .with_status(412)
+            .expect(1)
+            .create();
+
+        assert_eq!(writer.write(b"content").unwrap(), 7);
+        let error = writer.complete_upload().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TransportError>(),
+            Some(TransportError::AlreadyExists(_))
+        ));
+
+        mocked_put.assert();
+    }
 }
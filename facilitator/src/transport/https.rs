@@ -0,0 +1,215 @@
+use crate::{
+    http::{error_http_status, Method, RequestParameters, Response, RetryingAgent},
+    logging::event,
+    transport::{Transport, TransportWriter},
+    TransportError,
+};
+use anyhow::{anyhow, Context, Result};
+use slog::{info, o, Logger};
+use std::io::Read;
+use url::Url;
+
+/// A read-only transport implementation backed by a web server. Keys are
+/// interpreted as paths relative to the base URL, and objects are fetched with
+/// unauthenticated GET requests. HTTP offers no way to write, delete or list
+/// objects, so those operations always fail.
+#[derive(Debug)]
+pub struct HttpsTransport {
+    base_url: Url,
+    agent: RetryingAgent,
+    logger: Logger,
+}
+
+impl HttpsTransport {
+    /// Creates an HttpsTransport that reads objects under the provided base
+    /// URL. Returns an error if the URL does not use HTTPS.
+    pub fn new(base_url: Url, parent_logger: &Logger) -> Result<Self> {
+        if base_url.scheme() != "https" {
+            return Err(anyhow!("{} is not an HTTPS URL", base_url));
+        }
+        Ok(Self::new_with_agent(
+            base_url,
+            RetryingAgent::default(),
+            parent_logger,
+        ))
+    }
+
+    fn new_with_agent(mut base_url: Url, agent: RetryingAgent, parent_logger: &Logger) -> Self {
+        // Keys are joined onto the base URL, which only works as intended if
+        // the base URL's path ends in a slash.
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+        let logger = parent_logger.new(o!(
+            event::STORAGE_PATH => base_url.to_string(),
+        ));
+        HttpsTransport {
+            base_url,
+            agent,
+            logger,
+        }
+    }
+
+    fn object_url(&self, key: &str) -> Result<Url> {
+        self.base_url
+            .join(key)
+            .context(format!("failed to construct URL for key {}", key))
+    }
+
+    fn head(&self, key: &str, trace_id: &str, operation: &str) -> Result<Response> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "{}", operation);
+
+        let request = self.agent.prepare_request(RequestParameters {
+            url: self.object_url(key)?,
+            method: Method::Head,
+            ..Default::default()
+        })?;
+        self.agent.call(&logger, &request)
+    }
+}
+
+fn read_only_error(operation: &str) -> anyhow::Error {
+    anyhow!(
+        "{} is not supported by read-only HTTPS transport",
+        operation
+    )
+}
+
+impl Transport for HttpsTransport {
+    fn path(&self) -> String {
+        self.base_url.to_string()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "get");
+
+        let url = self.object_url(key)?;
+        let request = self.agent.prepare_request(RequestParameters {
+            url: url.clone(),
+            method: Method::Get,
+            ..Default::default()
+        })?;
+        let response = self.agent.call(&logger, &request).map_err(|e| {
+            let not_found = error_http_status(&e) == Some(404);
+            let error = e.context(format!("failed to fetch {}", url));
+            if not_found {
+                error.context(TransportError::NotFound(key.to_owned()))
+            } else {
+                error
+            }
+        })?;
+
+        Ok(Box::new(response.into_reader()?))
+    }
+
+    fn put(&mut self, _key: &str, _trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        Err(read_only_error("put"))
+    }
+
+    fn list(&mut self, _prefix: &str, _trace_id: &str) -> Result<Vec<String>> {
+        Err(read_only_error("list"))
+    }
+
+    fn delete(&mut self, _key: &str, _trace_id: &str) -> Result<()> {
+        Err(read_only_error("delete"))
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        match self.head(key, trace_id, "exists") {
+            Ok(_) => Ok(true),
+            Err(e) if error_http_status(&e) == Some(404) => Ok(false),
+            Err(e) => Err(e).context(format!("failed to check whether {} exists", key)),
+        }
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        let response = self
+            .head(key, trace_id, "etag")
+            .context(format!("failed to get headers of {}", key))?;
+        response
+            .header("ETag")
+            .map(String::from)
+            .context(format!("no ETag in response for {}", key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::setup_test_logging;
+    use mockito::mock;
+
+    fn mockito_transport(path: &str) -> HttpsTransport {
+        HttpsTransport::new_with_agent(
+            Url::parse(&format!("{}{}", mockito::server_url(), path)).unwrap(),
+            RetryingAgent::default(),
+            &setup_test_logging(),
+        )
+    }
+
+    #[test]
+    fn reject_insecure_url() {
+        let logger = setup_test_logging();
+        HttpsTransport::new(Url::parse("http://example.com/").unwrap(), &logger).unwrap_err();
+        HttpsTransport::new(Url::parse("https://example.com/").unwrap(), &logger).unwrap();
+    }
+
+    #[test]
+    fn get_and_exists() {
+        let mut transport = mockito_transport("/https-transport");
+        assert_eq!(
+            transport.path(),
+            format!("{}/https-transport/", mockito::server_url())
+        );
+
+        let mocked_get = mock("GET", "/https-transport/dir/object")
+            .with_status(200)
+            .with_body("content")
+            .expect(1)
+            .create();
+        let mocked_head_present = mock("HEAD", "/https-transport/dir/object")
+            .with_status(200)
+            .with_header("ETag", "\"abc123\"")
+            .expect(2)
+            .create();
+        let mocked_head_missing = mock("HEAD", "/https-transport/missing")
+            .with_status(404)
+            .expect(1)
+            .create();
+
+        let mut content = String::new();
+        transport
+            .get("dir/object", "trace-id")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "content");
+        assert!(transport.exists("dir/object", "trace-id").unwrap());
+        assert!(!transport.exists("missing", "trace-id").unwrap());
+        assert_eq!(
+            transport.etag("dir/object", "trace-id").unwrap(),
+            "\"abc123\""
+        );
+
+        mocked_get.assert();
+        mocked_head_present.assert();
+        mocked_head_missing.assert();
+    }
+
+    #[test]
+    fn writes_fail() {
+        let mut transport = mockito_transport("/https-transport-writes");
+        assert!(transport.put("key", "trace-id").is_err());
+        assert!(transport.delete("key", "trace-id").is_err());
+        assert!(transport.list("", "trace-id").is_err());
+    }
+}
@@ -1,13 +1,22 @@
-use crate::transport::{Transport, TransportWriter};
+use crate::{
+    transport::{Transport, TransportWriter},
+    TransportError,
+};
 use anyhow::{Context, Result};
+use tempfile::{Builder, NamedTempFile};
 
 use std::{
     boxed::Box,
-    fs::{create_dir_all, File},
-    io::Read,
-    path::{PathBuf, MAIN_SEPARATOR},
+    fs::{create_dir_all, read_dir, remove_file, File},
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf, MAIN_SEPARATOR},
+    time::UNIX_EPOCH,
 };
 
+/// Prefix of the names of temporary files that objects are written to before
+/// they are moved into place. Files with this prefix are not listed.
+const TEMP_FILE_PREFIX: &str = ".facilitator-upload-";
+
 /// A transport implementation backed by the local filesystem.
 #[derive(Debug)]
 pub struct LocalFileTransport {
@@ -27,6 +36,62 @@ impl LocalFileTransport {
     fn relative_path(key: &str) -> PathBuf {
         PathBuf::from(key.replace("/", &MAIN_SEPARATOR.to_string()))
     }
+
+    /// The inverse of relative_path: converts a path relative to the
+    /// transport's directory into a key using "/" as a separator.
+    fn key(relative_path: &Path) -> String {
+        relative_path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Creates a writer that writes to a temporary file which is moved to the
+    /// path for `key` when the upload is completed. If `overwrite` is false,
+    /// completing the upload fails if that path already exists.
+    fn writer(&self, key: &str, overwrite: bool) -> Result<Box<dyn TransportWriter>> {
+        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        if !overwrite && path.exists() {
+            return Err(TransportError::AlreadyExists(key.to_owned()).into());
+        }
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)
+                .with_context(|| format!("creating parent directories {}", parent.display()))?;
+        }
+        // Write to a temporary file in the same directory as the destination
+        // so that it can be atomically renamed into place once complete, and
+        // readers never see a partially written object.
+        let directory = path.parent().unwrap_or(&self.directory);
+        let temp_file = Builder::new()
+            .prefix(TEMP_FILE_PREFIX)
+            .tempfile_in(directory)
+            .with_context(|| format!("creating temporary file in {}", directory.display()))?;
+        Ok(Box::new(LocalFileWriter {
+            key: key.to_owned(),
+            path,
+            temp_file: Some(temp_file),
+            overwrite,
+        }))
+    }
+
+    /// Recursively walks the provided directory, appending the paths of any
+    /// files found to `files`.
+    fn walk_directory(directory: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in read_dir(directory)
+            .with_context(|| format!("reading directory {}", directory.display()))?
+        {
+            let path = entry
+                .with_context(|| format!("reading directory {}", directory.display()))?
+                .path();
+            if path.is_dir() {
+                LocalFileTransport::walk_directory(&path, files)?;
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Transport for LocalFileTransport {
@@ -36,31 +101,145 @@ impl Transport for LocalFileTransport {
 
     fn get(&mut self, key: &str, _trace_id: &str) -> Result<Box<dyn Read>> {
         let path = self.directory.join(LocalFileTransport::relative_path(key));
-        let f =
-            File::open(path.as_path()).with_context(|| format!("opening {}", path.display()))?;
+        let f = File::open(path.as_path()).map_err(|e| {
+            let not_found = e.kind() == ErrorKind::NotFound;
+            let error = anyhow::Error::new(e).context(format!("opening {}", path.display()));
+            if not_found {
+                error.context(TransportError::NotFound(key.to_owned()))
+            } else {
+                error
+            }
+        })?;
         Ok(Box::new(f))
     }
 
     fn put(&mut self, key: &str, _trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.writer(key, true)
+    }
+
+    fn put_if_absent(&mut self, key: &str, _trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.writer(key, false)
+    }
+
+    fn list(&mut self, prefix: &str, _trace_id: &str) -> Result<Vec<String>> {
+        // Only walk the deepest directory named by the prefix, since no file
+        // outside of it could match.
+        let prefix_directory = match prefix.rfind('/') {
+            Some(index) => &prefix[..index],
+            None => "",
+        };
+        let start = self
+            .directory
+            .join(LocalFileTransport::relative_path(prefix_directory));
+        if !start.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut files = vec![];
+        LocalFileTransport::walk_directory(&start, &mut files)?;
+
+        let mut keys = files
+            .iter()
+            .filter(|path| {
+                !path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().starts_with(TEMP_FILE_PREFIX))
+                    .unwrap_or(false)
+            })
+            .filter_map(|path| path.strip_prefix(&self.directory).ok())
+            .map(LocalFileTransport::key)
+            .filter(|key| key.starts_with(prefix))
+            .collect::<Vec<_>>();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str, _trace_id: &str) -> Result<()> {
         let path = self.directory.join(LocalFileTransport::relative_path(key));
-        if let Some(parent) = path.parent() {
-            create_dir_all(parent)
-                .with_context(|| format!("creating parent directories {}", parent.display()))?;
+        match remove_file(path.as_path()) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            r => r.with_context(|| format!("removing {}", path.display())),
+        }
+    }
+
+    fn exists(&mut self, key: &str, _trace_id: &str) -> Result<bool> {
+        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        Ok(path.is_file())
+    }
+
+    fn etag(&mut self, key: &str, _trace_id: &str) -> Result<String> {
+        // Like many web servers, derive an ETag from the file's modification
+        // time and size. Since uploads replace files by renaming a temporary
+        // file into place, any upload changes the modification time.
+        let path = self.directory.join(LocalFileTransport::relative_path(key));
+        let metadata = path
+            .metadata()
+            .with_context(|| format!("failed to get metadata of {}", path.display()))?;
+        let modified = metadata
+            .modified()
+            .context("file modification time not supported")?
+            .duration_since(UNIX_EPOCH)
+            .context("file modification time is before Unix epoch")?;
+        Ok(format!("{:x}-{:x}", modified.as_nanos(), metadata.len()))
+    }
+}
+
+/// Writes content into a temporary file, which is moved to the destination
+/// path when the upload is completed or deleted if the upload is canceled.
+struct LocalFileWriter {
+    key: String,
+    path: PathBuf,
+    // None once the upload has been completed or canceled
+    temp_file: Option<NamedTempFile>,
+    // If false, the upload fails rather than replace an existing file
+    overwrite: bool,
+}
+
+impl Write for LocalFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.temp_file {
+            Some(temp_file) => temp_file.write(buf),
+            None => Err(io::Error::new(
+                ErrorKind::Other,
+                "upload was already completed or canceled",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.temp_file {
+            Some(temp_file) => temp_file.flush(),
+            None => Ok(()),
         }
-        let f =
-            File::create(path.as_path()).with_context(|| format!("creating {}", path.display()))?;
-        Ok(Box::new(f))
     }
 }
 
-impl TransportWriter for File {
+impl TransportWriter for LocalFileWriter {
     fn complete_upload(&mut self) -> Result<()> {
-        // This method is a no-op for local files
+        if let Some(temp_file) = self.temp_file.take() {
+            if self.overwrite {
+                temp_file.persist(&self.path).map_err(|e| e.error)
+            } else {
+                // persist_noclobber links the temporary file into place, which
+                // fails atomically if the destination exists.
+                match temp_file.persist_noclobber(&self.path) {
+                    Err(e) if e.error.kind() == ErrorKind::AlreadyExists => {
+                        return Err(TransportError::AlreadyExists(self.key.clone()).into())
+                    }
+                    result => result.map_err(|e| e.error),
+                }
+            }
+            .with_context(|| format!("moving temporary file to {}", self.path.display()))?;
+        }
         Ok(())
     }
 
     fn cancel_upload(&mut self) -> Result<()> {
-        // This method is a no-op for local files
+        if let Some(temp_file) = self.temp_file.take() {
+            temp_file
+                .close()
+                .context("deleting temporary file for canceled upload")?;
+        }
         Ok(())
     }
 }
@@ -68,6 +247,7 @@ impl TransportWriter for File {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::transport::is_already_exists_error;
 
     #[test]
     fn roundtrip_file_transport() {
@@ -84,10 +264,12 @@ mod tests {
             let writer = file_transport.put(path, "");
             assert!(writer.is_ok(), "unexpected error {:?}", writer.err());
 
-            writer
-                .unwrap()
-                .write_all(&content)
-                .expect("failed to write");
+            let mut writer = writer.unwrap();
+            writer.write_all(&content).expect("failed to write");
+
+            // Content is not visible until the upload is completed
+            assert!(file_transport.get(path, "").is_err());
+            writer.complete_upload().expect("failed to complete upload");
 
             let reader = file_transport.get(path, "");
             assert!(reader.is_ok(), "create reader failed: {:?}", reader.err());
@@ -100,4 +282,137 @@ mod tests {
             assert_eq!(content_again, content);
         }
     }
+
+    #[test]
+    fn list_file_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        assert!(file_transport.list("", "").unwrap().is_empty());
+        assert!(file_transport.list("missing/", "").unwrap().is_empty());
+
+        for key in &[
+            "a/2021/01/x",
+            "a/2021/01/y",
+            "a/2021/02/z",
+            "b/2021/01/x",
+            "c",
+        ] {
+            file_transport
+                .put(key, "")
+                .unwrap()
+                .complete_upload()
+                .unwrap();
+        }
+
+        assert_eq!(
+            file_transport.list("", "").unwrap(),
+            vec![
+                "a/2021/01/x",
+                "a/2021/01/y",
+                "a/2021/02/z",
+                "b/2021/01/x",
+                "c"
+            ]
+        );
+        assert_eq!(
+            file_transport.list("a/2021/0", "").unwrap(),
+            vec!["a/2021/01/x", "a/2021/01/y", "a/2021/02/z"]
+        );
+        assert_eq!(
+            file_transport.list("a/2021/01/", "").unwrap(),
+            vec!["a/2021/01/x", "a/2021/01/y"]
+        );
+        assert_eq!(file_transport.list("c", "").unwrap(), vec!["c"]);
+    }
+
+    #[test]
+    fn delete_file_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        assert!(!file_transport.exists("path/to/key", "").unwrap());
+        file_transport.delete("path/to/key", "").unwrap();
+
+        let mut writer = file_transport.put("path/to/key", "").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.complete_upload().unwrap();
+        assert!(file_transport.exists("path/to/key", "").unwrap());
+        // Directories are not objects
+        assert!(!file_transport.exists("path/to", "").unwrap());
+
+        file_transport.delete("path/to/key", "").unwrap();
+        assert!(!file_transport.exists("path/to/key", "").unwrap());
+        assert!(file_transport.get("path/to/key", "").is_err());
+    }
+
+    #[test]
+    fn etag_file_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+        assert!(file_transport.etag("key", "").is_err());
+
+        let mut writer = file_transport.put("key", "").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.complete_upload().unwrap();
+        let etag = file_transport.etag("key", "").unwrap();
+        assert_eq!(file_transport.etag("key", "").unwrap(), etag);
+
+        let mut writer = file_transport.put("key", "").unwrap();
+        writer.write_all(b"new content").unwrap();
+        writer.complete_upload().unwrap();
+        assert_ne!(file_transport.etag("key", "").unwrap(), etag);
+    }
+
+    #[test]
+    fn cancel_upload_file_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        let mut writer = file_transport.put("path/to/key", "").unwrap();
+        writer.write_all(b"content").unwrap();
+        // Temporary files are not listed
+        assert!(file_transport.list("", "").unwrap().is_empty());
+        writer.cancel_upload().unwrap();
+
+        assert!(!file_transport.exists("path/to/key", "").unwrap());
+        // The temporary file should be gone
+        assert_eq!(
+            read_dir(tempdir.path().join("path").join("to"))
+                .unwrap()
+                .count(),
+            0
+        );
+        assert!(writer.write_all(b"more content").is_err());
+    }
+
+    #[test]
+    fn put_if_absent_file_transport() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let mut file_transport = LocalFileTransport::new(tempdir.path().to_path_buf());
+
+        // Two uploads to the same absent key race, and only the first to
+        // complete wins.
+        let mut first = file_transport.put_if_absent("key", "").unwrap();
+        let mut second = file_transport.put_if_absent("key", "").unwrap();
+        first.write_all(b"first").unwrap();
+        second.write_all(b"second").unwrap();
+        first.complete_upload().unwrap();
+        let error = second.complete_upload().unwrap_err();
+        assert!(is_already_exists_error(&error), "{:?}", error);
+
+        // Once the object exists, uploads fail right away.
+        let error = file_transport.put_if_absent("key", "").err().unwrap();
+        assert!(is_already_exists_error(&error), "{:?}", error);
+
+        let mut content = Vec::new();
+        file_transport
+            .get("key", "")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"first");
+        // The loser's temporary file should be gone
+        assert_eq!(read_dir(tempdir.path()).unwrap().count(), 1);
+    }
 }
@@ -0,0 +1,224 @@
+use crate::{
+    transport::{Transport, TransportWriter},
+    TransportError,
+};
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+/// A transport implementation backed by a map of keys to buffers in memory.
+/// Clones of a MemoryTransport share the same underlying storage, so a clone
+/// may be handed to a component that writes objects while the original is
+/// used to read them back, possibly from another thread. Objects written with
+/// `put` only become visible once the writer's `complete_upload` is called.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryTransport {
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryTransport {
+    /// Creates an empty MemoryTransport.
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    /// Returns the keys of all objects stored in this transport, in no
+    /// particular order.
+    pub fn keys(&self) -> Vec<String> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn path(&self) -> String {
+        "memory://".to_owned()
+    }
+
+    fn get(&mut self, key: &str, _trace_id: &str) -> Result<Box<dyn Read>> {
+        let objects = self.objects.lock().unwrap();
+        let content = objects
+            .get(key)
+            .ok_or_else(|| TransportError::NotFound(key.to_owned()))?;
+        Ok(Box::new(Cursor::new(content.clone())))
+    }
+
+    fn put(&mut self, key: &str, _trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(MemoryWriter {
+            key: key.to_owned(),
+            buffer: Vec::new(),
+            objects: self.objects.clone(),
+            overwrite: true,
+        }))
+    }
+
+    fn put_if_absent(&mut self, key: &str, _trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        if self.objects.lock().unwrap().contains_key(key) {
+            return Err(TransportError::AlreadyExists(key.to_owned()).into());
+        }
+        Ok(Box::new(MemoryWriter {
+            key: key.to_owned(),
+            buffer: Vec::new(),
+            objects: self.objects.clone(),
+            overwrite: false,
+        }))
+    }
+
+    fn list(&mut self, prefix: &str, _trace_id: &str) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str, _trace_id: &str) -> Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn exists(&mut self, key: &str, _trace_id: &str) -> Result<bool> {
+        Ok(self.objects.lock().unwrap().contains_key(key))
+    }
+
+    fn etag(&mut self, key: &str, _trace_id: &str) -> Result<String> {
+        let objects = self.objects.lock().unwrap();
+        let content = objects
+            .get(key)
+            .ok_or_else(|| TransportError::NotFound(key.to_owned()))?;
+        Ok(format!("{:x}", md5::compute(content)))
+    }
+}
+
+/// Accumulates content in a buffer and inserts it into the owning
+/// MemoryTransport's storage when the upload is completed.
+struct MemoryWriter {
+    key: String,
+    buffer: Vec<u8>,
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    // If false, the upload fails rather than replace an existing object
+    overwrite: bool,
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TransportWriter for MemoryWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        if !self.overwrite && objects.contains_key(&self.key) {
+            return Err(TransportError::AlreadyExists(self.key.clone()).into());
+        }
+        objects.insert(self.key.clone(), std::mem::take(&mut self.buffer));
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{is_already_exists_error, CompressingTransport};
+
+    #[test]
+    fn roundtrip_memory_transport() {
+        let mut transport = MemoryTransport::new();
+        let content = vec![1, 2, 3, 4, 5, 6, 7, 8];
+
+        assert!(transport.get("path", "").is_err());
+
+        let mut writer = transport.put("path", "").unwrap();
+        writer.write_all(&content).unwrap();
+
+        // Content is not visible until the upload is completed
+        assert!(transport.get("path", "").is_err());
+        writer.complete_upload().unwrap();
+
+        // Content written through one handle is visible through clones
+        let mut clone = transport.clone();
+        let mut content_again = Vec::new();
+        clone
+            .get("path", "")
+            .unwrap()
+            .read_to_end(&mut content_again)
+            .unwrap();
+        assert_eq!(content_again, content);
+        assert_eq!(transport.keys(), vec!["path".to_owned()]);
+    }
+
+    #[test]
+    fn cancel_upload() {
+        let mut transport = MemoryTransport::new();
+
+        let mut writer = transport.put("path", "").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.cancel_upload().unwrap();
+
+        assert!(transport.get("path", "").is_err());
+        assert!(transport.keys().is_empty());
+    }
+
+    #[test]
+    fn list_memory_transport() {
+        let mut transport = MemoryTransport::new();
+        for key in &["a/2", "a/1", "b/1"] {
+            transport.put(key, "").unwrap().complete_upload().unwrap();
+        }
+
+        assert_eq!(transport.list("", "").unwrap(), vec!["a/1", "a/2", "b/1"]);
+        assert_eq!(transport.list("a/", "").unwrap(), vec!["a/1", "a/2"]);
+        assert!(transport.list("c", "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn put_if_absent_memory_transport() {
+        let mut transport = MemoryTransport::new();
+
+        let mut first = transport.put_if_absent("key", "").unwrap();
+        let mut second = transport.put_if_absent("key", "").unwrap();
+        first.write_all(b"first").unwrap();
+        second.write_all(b"second").unwrap();
+        first.complete_upload().unwrap();
+        let error = second.complete_upload().unwrap_err();
+        assert!(is_already_exists_error(&error), "{:?}", error);
+
+        let error = transport.put_if_absent("key", "").err().unwrap();
+        assert!(is_already_exists_error(&error), "{:?}", error);
+        assert_eq!(transport.objects.lock().unwrap()["key"], b"first");
+
+        // Wrappers forward put_if_absent to the transport they wrap
+        let mut compressing = CompressingTransport::new(transport.clone());
+        let error = compressing.put_if_absent("key", "").err().unwrap();
+        assert!(is_already_exists_error(&error), "{:?}", error);
+    }
+
+    #[test]
+    fn delete_memory_transport() {
+        let mut transport = MemoryTransport::new();
+        transport.put("key", "").unwrap().complete_upload().unwrap();
+        assert!(transport.exists("key", "").unwrap());
+
+        transport.delete("key", "").unwrap();
+        assert!(!transport.exists("key", "").unwrap());
+        // Deleting a missing key succeeds
+        transport.delete("key", "").unwrap();
+    }
+}
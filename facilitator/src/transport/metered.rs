@@ -0,0 +1,268 @@
+use crate::{
+    metrics::TransportMetricsCollector,
+    transport::{Transport, TransportWriter},
+};
+use anyhow::Result;
+use std::{
+    io::{self, Read, Write},
+    time::Instant,
+};
+
+/// A transport that wraps another transport, recording the number, outcome and
+/// latency of requests made to it, as well as the number of bytes read from
+/// and written to its objects, in a TransportMetricsCollector. Metrics are
+/// labeled with the name of the storage backend and the inner transport's
+/// path, so that slow or failing buckets can be told apart.
+#[derive(Debug)]
+pub struct MeteredTransport<T: Transport> {
+    inner: T,
+    recorder: Recorder,
+}
+
+impl<T: Transport> MeteredTransport<T> {
+    /// Creates a MeteredTransport that records metrics about requests to the
+    /// provided transport in `metrics`. `backend` should identify the kind of
+    /// storage, e.g. "s3" or "gcs".
+    pub fn new(inner: T, backend: &str, metrics: &TransportMetricsCollector) -> Self {
+        let bucket = inner.path();
+        MeteredTransport {
+            inner,
+            recorder: Recorder {
+                backend: backend.to_owned(),
+                bucket,
+                metrics: metrics.clone(),
+            },
+        }
+    }
+}
+
+impl<T: Transport> Transport for MeteredTransport<T> {
+    fn path(&self) -> String {
+        self.inner.path()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        let start = Instant::now();
+        let result = self.inner.get(key, trace_id);
+        self.recorder.record("get", start, result.is_ok());
+        Ok(Box::new(MeteredReader {
+            reader: result?,
+            recorder: self.recorder.clone(),
+        }))
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        let start = Instant::now();
+        let result = self.inner.put(key, trace_id);
+        self.recorder.record("put", start, result.is_ok());
+        Ok(Box::new(MeteredWriter {
+            writer: result?,
+            recorder: self.recorder.clone(),
+            started_at: start,
+        }))
+    }
+
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        let start = Instant::now();
+        let result = self.inner.put_if_absent(key, trace_id);
+        self.recorder.record("put_if_absent", start, result.is_ok());
+        Ok(Box::new(MeteredWriter {
+            writer: result?,
+            recorder: self.recorder.clone(),
+            started_at: start,
+        }))
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        let start = Instant::now();
+        let result = self.inner.list(prefix, trace_id);
+        self.recorder.record("list", start, result.is_ok());
+        result
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.delete(key, trace_id);
+        self.recorder.record("delete", start, result.is_ok());
+        result
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.exists(key, trace_id);
+        self.recorder.record("exists", start, result.is_ok());
+        result
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        let start = Instant::now();
+        let result = self.inner.etag(key, trace_id);
+        self.recorder.record("etag", start, result.is_ok());
+        result
+    }
+}
+
+/// Records metrics with a MeteredTransport's labels. Shared with the readers
+/// and writers it returns.
+#[derive(Clone, Debug)]
+struct Recorder {
+    backend: String,
+    bucket: String,
+    metrics: TransportMetricsCollector,
+}
+
+impl Recorder {
+    fn record(&self, operation: &str, start: Instant, ok: bool) {
+        self.metrics
+            .request_duration
+            .with_label_values(&[&self.backend, &self.bucket, operation])
+            .observe(start.elapsed().as_secs_f64());
+        self.metrics
+            .requests
+            .with_label_values(&[
+                &self.backend,
+                &self.bucket,
+                operation,
+                if ok { "ok" } else { "error" },
+            ])
+            .inc();
+    }
+
+    fn record_bytes_read(&self, len: usize) {
+        self.metrics
+            .bytes_read
+            .with_label_values(&[&self.backend, &self.bucket])
+            .inc_by(len as u64);
+    }
+
+    fn record_upload_duration(&self, started_at: Instant) {
+        self.metrics
+            .upload_duration
+            .with_label_values(&[&self.backend, &self.bucket])
+            .observe(started_at.elapsed().as_secs_f64());
+    }
+
+    fn record_bytes_written(&self, len: usize) {
+        self.metrics
+            .bytes_written
+            .with_label_values(&[&self.backend, &self.bucket])
+            .inc_by(len as u64);
+    }
+}
+
+struct MeteredReader {
+    reader: Box<dyn Read>,
+    recorder: Recorder,
+}
+
+impl Read for MeteredReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.recorder.record_bytes_read(len);
+        Ok(len)
+    }
+}
+
+struct MeteredWriter {
+    writer: Box<dyn TransportWriter>,
+    recorder: Recorder,
+    started_at: Instant,
+}
+
+impl Write for MeteredWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.recorder.record_bytes_written(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl TransportWriter for MeteredWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.writer.complete_upload();
+        self.recorder
+            .record("complete_upload", start, result.is_ok());
+        if result.is_ok() {
+            self.recorder.record_upload_duration(self.started_at);
+        }
+        result
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.writer.cancel_upload();
+        self.recorder.record("cancel_upload", start, result.is_ok());
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    #[test]
+    fn metered_transport() {
+        let metrics = TransportMetricsCollector::new_unregistered().unwrap();
+        let mut transport = MeteredTransport::new(MemoryTransport::new(), "memory", &metrics);
+        let requests = |operation, status| {
+            metrics
+                .requests
+                .with_label_values(&["memory", "memory://", operation, status])
+                .get()
+        };
+
+        let mut writer = transport.put("key", "").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.complete_upload().unwrap();
+
+        let mut content = Vec::new();
+        transport
+            .get("key", "")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert!(transport.get("missing-key", "").is_err());
+        assert!(transport.exists("key", "").unwrap());
+
+        assert_eq!(requests("put", "ok"), 1);
+        assert_eq!(requests("complete_upload", "ok"), 1);
+        assert_eq!(requests("get", "ok"), 1);
+        assert_eq!(requests("get", "error"), 1);
+        assert_eq!(requests("exists", "ok"), 1);
+        assert_eq!(requests("list", "ok"), 0);
+        assert_eq!(
+            metrics
+                .request_duration
+                .with_label_values(&["memory", "memory://", "get"])
+                .get_sample_count(),
+            2
+        );
+        assert_eq!(
+            metrics
+                .upload_duration
+                .with_label_values(&["memory", "memory://"])
+                .get_sample_count(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .bytes_written
+                .with_label_values(&["memory", "memory://"])
+                .get(),
+            7
+        );
+        assert_eq!(
+            metrics
+                .bytes_read
+                .with_label_values(&["memory", "memory://"])
+                .get(),
+            7
+        );
+    }
+}
@@ -0,0 +1,629 @@
+use crate::{
+    config::{Identity, WorkloadIdentityPoolParameters},
+    gcp_oauth::{GcpIdentityTokenProvider, GcpOauthTokenProvider},
+    http::{
+        error_http_status, Method, OauthTokenProvider, RequestClass, RequestParameters, Response,
+        RetryingAgent, StaticOauthTokenProvider,
+    },
+    logging::event,
+    transport::{Transport, TransportWriter},
+    Error, TransportError,
+};
+use anyhow::{anyhow, Context, Result};
+use slog::{debug, info, o, warn, Logger};
+use std::io::{self, Read, Write};
+use url::Url;
+
+/// Size of the chunks in which objects are uploaded to the portal server.
+const UPLOAD_CHUNK_SIZE: usize = 8_388_608;
+
+/// Number of consecutive attempts to upload a chunk that may fail to make any
+/// progress before an upload is abandoned.
+const MAX_ATTEMPTS_WITHOUT_PROGRESS: u32 = 5;
+
+/// How PortalUploader authenticates to the portal server's upload API. Either
+/// way, tokens are obtained for the GCP service account given to
+/// PortalUploader::new, or the default service account.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PortalAuthentication {
+    /// Requests carry an Oauth access token with the provided scope.
+    OauthToken { scope: String },
+    /// Requests carry an OIDC identity token whose aud claim is the provided
+    /// audience.
+    IdentityToken { audience: String },
+}
+
+/// PortalUploader is a write-only transport that uploads objects, such as sum
+/// parts, to a portal server's authenticated HTTPS upload API rather than to a
+/// bucket. Keys are interpreted as paths relative to the base URL. An upload
+/// is a session started by a POST to the object's URL, whose response carries
+/// the session URL in a Location header. Content is then sent in chunks with
+/// PUTs to the session URL whose Content-Range header is "bytes x-y/*", or
+/// "bytes x-y/<total size>" for the last chunk. The server acknowledges
+/// chunks with HTTP 308 and a Range header "bytes=0-n" giving how much of the
+/// object it has, and the last chunk with HTTP 200 or 201. If a chunk fails,
+/// the uploader asks the server how much it has with a PUT whose Content-Range
+/// is "bytes */*", and resumes from there. Every request is authenticated with
+/// a bearer token.
+#[derive(Debug)]
+pub struct PortalUploader {
+    base_url: Url,
+    token_provider: Box<dyn OauthTokenProvider>,
+    agent: RetryingAgent,
+    upload_chunk_size: usize,
+    logger: Logger,
+}
+
+impl PortalUploader {
+    /// Creates a PortalUploader that uploads objects under the provided base
+    /// URL, which must use HTTPS. If identity is None, tokens are obtained for
+    /// the default service account. If identity contains a service account
+    /// email, the GCP IAM API is used to obtain tokens to impersonate it.
+    pub fn new(
+        base_url: Url,
+        authentication: PortalAuthentication,
+        identity: Identity,
+        key_file_reader: Option<Box<dyn Read>>,
+        workload_identity_pool_params: Option<WorkloadIdentityPoolParameters>,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        if base_url.scheme() != "https" {
+            return Err(anyhow!("{} is not an HTTPS URL", base_url));
+        }
+        let logger = parent_logger.new(o!(
+            event::IDENTITY => identity.unwrap_or("default identity").to_owned(),
+        ));
+        let token_provider: Box<dyn OauthTokenProvider> = match authentication {
+            PortalAuthentication::OauthToken { scope } => Box::new(GcpOauthTokenProvider::new(
+                &scope,
+                identity.map(String::from),
+                key_file_reader,
+                workload_identity_pool_params,
+                &logger,
+            )?),
+            PortalAuthentication::IdentityToken { audience } => {
+                Box::new(GcpIdentityTokenProvider::new(
+                    GcpOauthTokenProvider::new(
+                        // The default service account's token is only used
+                        // to impersonate another account, if any
+                        "https://www.googleapis.com/auth/cloud-platform",
+                        identity.map(String::from),
+                        key_file_reader,
+                        workload_identity_pool_params,
+                        &logger,
+                    )?,
+                    audience,
+                ))
+            }
+        };
+        Ok(Self::new_with_token_provider(
+            base_url,
+            token_provider,
+            UPLOAD_CHUNK_SIZE,
+            &logger,
+        ))
+    }
+
+    fn new_with_token_provider(
+        mut base_url: Url,
+        token_provider: Box<dyn OauthTokenProvider>,
+        upload_chunk_size: usize,
+        parent_logger: &Logger,
+    ) -> Self {
+        // Keys are joined onto the base URL, which only works as intended if
+        // the base URL's path ends in a slash.
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+        let logger = parent_logger.new(o!(
+            event::STORAGE_PATH => base_url.to_string(),
+        ));
+        PortalUploader {
+            base_url,
+            token_provider,
+            agent: RetryingAgent::new(RequestClass::Storage, vec![408, 429]),
+            upload_chunk_size,
+            logger,
+        }
+    }
+
+    fn object_url(&self, key: &str) -> Result<Url> {
+        self.base_url
+            .join(key)
+            .context(format!("failed to construct URL for key {}", key))
+    }
+
+    fn head(&mut self, key: &str, trace_id: &str, operation: &str) -> Result<Response> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "{}", operation);
+
+        let request = self.agent.prepare_request(RequestParameters {
+            url: self.object_url(key)?,
+            method: Method::Head,
+            token_provider: Some(self.token_provider.as_mut()),
+        })?;
+        self.agent.call(&logger, &request)
+    }
+
+    /// Starts an upload session for the provided key. If exclusive is true,
+    /// the server is asked not to overwrite an existing object.
+    fn start_upload(
+        &mut self,
+        key: &str,
+        trace_id: &str,
+        exclusive: bool,
+    ) -> Result<Box<dyn TransportWriter>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "put"; "exclusive" => exclusive);
+
+        // The token is obtained once per upload, like GcsTransport does, since
+        // uploads to the portal server are expected to be much shorter than
+        // the token's lifetime.
+        let token = self.token_provider.ensure_oauth_token()?;
+        let mut request = self.agent.prepare_request(RequestParameters {
+            url: self.object_url(key)?,
+            method: Method::Post,
+            token_provider: Some(&mut StaticOauthTokenProvider::from(token.clone())),
+        })?;
+        if exclusive {
+            request = request.set("If-None-Match", "*");
+        }
+
+        let response = self.agent.send_bytes(&logger, &request, &[]).map_err(|e| {
+            if error_http_status(&e) == Some(412) {
+                e.context(TransportError::AlreadyExists(key.to_owned()))
+            } else {
+                e.context(format!("failed to start upload of {}", key))
+            }
+        })?;
+        let session_url = response
+            .header("Location")
+            .context("no Location header in response when starting upload")?;
+        let session_url = self.base_url.join(session_url).context(format!(
+            "failed to parse upload session URL {}",
+            session_url
+        ))?;
+
+        Ok(Box::new(PortalUploadWriter {
+            session_url,
+            upload_chunk_size: self.upload_chunk_size,
+            position: 0,
+            buffer: Vec::new(),
+            attempts_without_progress: 0,
+            complete: false,
+            token,
+            agent: self.agent.clone(),
+            logger,
+        }))
+    }
+}
+
+fn write_only_error(operation: &str) -> anyhow::Error {
+    anyhow!("{} is not supported by portal uploader", operation)
+}
+
+impl Transport for PortalUploader {
+    fn path(&self) -> String {
+        self.base_url.to_string()
+    }
+
+    fn get(&mut self, _key: &str, _trace_id: &str) -> Result<Box<dyn Read>> {
+        Err(write_only_error("get"))
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.start_upload(key, trace_id, false)
+    }
+
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.start_upload(key, trace_id, true)
+    }
+
+    fn list(&mut self, _prefix: &str, _trace_id: &str) -> Result<Vec<String>> {
+        Err(write_only_error("list"))
+    }
+
+    fn delete(&mut self, _key: &str, _trace_id: &str) -> Result<()> {
+        Err(write_only_error("delete"))
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        match self.head(key, trace_id, "exists") {
+            Ok(_) => Ok(true),
+            Err(e) if error_http_status(&e) == Some(404) => Ok(false),
+            Err(e) => Err(e).context(format!("failed to check whether {} exists", key)),
+        }
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        let response = self
+            .head(key, trace_id, "etag")
+            .context(format!("failed to get headers of {}", key))?;
+        response
+            .header("ETag")
+            .map(String::from)
+            .context(format!("no ETag in response for {}", key))
+    }
+}
+
+/// The state of an upload session as reported by the portal server.
+#[derive(Debug, PartialEq)]
+enum UploadStatus {
+    Complete,
+    /// The server has the first `received` bytes of the object.
+    Incomplete {
+        received: usize,
+    },
+}
+
+impl UploadStatus {
+    fn from_response(response: Response) -> Result<Self> {
+        match response.status() {
+            200 | 201 => Ok(UploadStatus::Complete),
+            308 => match response.header("Range") {
+                // No Range header means the server has nothing yet
+                None => Ok(UploadStatus::Incomplete { received: 0 }),
+                Some(range) => {
+                    let end = range
+                        .strip_prefix("bytes=0-")
+                        .context(format!("Range header {} missing bytes prefix", range))?
+                        .parse::<usize>()
+                        .context(format!(
+                            "end in Range header {} is not a valid usize",
+                            range
+                        ))?;
+                    Ok(UploadStatus::Incomplete { received: end + 1 })
+                }
+            },
+            status => Err(anyhow!(
+                "unexpected HTTP status {} from upload session",
+                status
+            )),
+        }
+    }
+}
+
+/// PortalUploadWriter buffers content written to it and uploads it to a
+/// portal server upload session in chunks. Content is only dropped from the
+/// buffer once the server acknowledges it, so that a failed chunk can be
+/// resumed from wherever the server got to.
+struct PortalUploadWriter {
+    session_url: Url,
+    upload_chunk_size: usize,
+    /// Offset in the object of the first byte in the buffer
+    position: usize,
+    buffer: Vec<u8>,
+    attempts_without_progress: u32,
+    complete: bool,
+    token: String,
+    agent: RetryingAgent,
+    logger: Logger,
+}
+
+impl PortalUploadWriter {
+    fn put(&self, content_range: &str, body: &[u8]) -> Result<UploadStatus> {
+        let request = self
+            .agent
+            .prepare_request(RequestParameters {
+                url: self.session_url.clone(),
+                method: Method::Put,
+                token_provider: Some(&mut StaticOauthTokenProvider::from(self.token.clone())),
+            })?
+            .set("Content-Range", content_range);
+        let response = self.agent.send_bytes(&self.logger, &request, body)?;
+        UploadStatus::from_response(response)
+    }
+
+    /// Uploads the next chunk of the buffer. If last is true and the rest of
+    /// the buffer fits in the chunk, the chunk completes the object.
+    fn upload_chunk(&mut self, last: bool) -> Result<()> {
+        let length = self.buffer.len().min(self.upload_chunk_size);
+        let completes = last && length == self.buffer.len();
+        let total = if completes {
+            (self.position + length).to_string()
+        } else {
+            "*".to_owned()
+        };
+        let content_range = if length == 0 {
+            format!("bytes */{}", total)
+        } else {
+            format!(
+                "bytes {}-{}/{}",
+                self.position,
+                self.position + length - 1,
+                total
+            )
+        };
+        debug!(
+            self.logger, "uploading object chunk";
+            "content_range" => &content_range,
+        );
+
+        let status = match self.put(&content_range, &self.buffer[..length]) {
+            Ok(status) => status,
+            Err(error) => {
+                self.attempts_without_progress += 1;
+                if self.attempts_without_progress >= MAX_ATTEMPTS_WITHOUT_PROGRESS {
+                    return Err(error.context(format!(
+                        "upload to {} made no progress in {} attempts",
+                        self.session_url, MAX_ATTEMPTS_WITHOUT_PROGRESS
+                    )));
+                }
+                warn!(
+                    self.logger, "failed to upload chunk, resuming upload: {:?}", error;
+                    "content_range" => &content_range,
+                );
+                self.put("bytes */*", &[])
+                    .context("failed to query status of upload session")
+                    .context(error)?
+            }
+        };
+
+        match status {
+            UploadStatus::Complete if completes => {
+                self.position += length;
+                self.buffer.clear();
+                self.complete = true;
+                Ok(())
+            }
+            UploadStatus::Complete => Err(anyhow!(
+                "portal server reported upload complete with content remaining"
+            )),
+            UploadStatus::Incomplete { received } => {
+                if received < self.position || received > self.position + length {
+                    return Err(anyhow!(
+                        "portal server reported receiving {} bytes, but {} bytes were sent",
+                        received,
+                        self.position + length
+                    ));
+                }
+                if received > self.position {
+                    self.attempts_without_progress = 0;
+                }
+                self.buffer.drain(..received - self.position);
+                self.position = received;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Write for PortalUploadWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= self.upload_chunk_size {
+            self.upload_chunk(false)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, Error::AnyhowError(e)))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Content is uploaded in whole chunks, and users of a TransportWriter
+        // call complete_upload when they are finished anyway.
+        Ok(())
+    }
+}
+
+impl TransportWriter for PortalUploadWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        while !self.complete {
+            self.upload_chunk(true)?;
+        }
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        debug!(
+            self.logger, "canceling upload";
+            "upload_session_url" => self.session_url.to_string(),
+        );
+        let request = self.agent.prepare_request(RequestParameters {
+            url: self.session_url.clone(),
+            method: Method::Delete,
+            token_provider: Some(&mut StaticOauthTokenProvider::from(self.token.clone())),
+        })?;
+        self.agent
+            .call(&self.logger, &request)
+            .context("failed to cancel upload to portal server")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::setup_test_logging;
+    use mockito::mock;
+
+    fn mockito_uploader(path: &str, upload_chunk_size: usize) -> PortalUploader {
+        PortalUploader::new_with_token_provider(
+            Url::parse(&format!("{}{}", mockito::server_url(), path)).unwrap(),
+            Box::new(StaticOauthTokenProvider::from("fake-token".to_owned())),
+            upload_chunk_size,
+            &setup_test_logging(),
+        )
+    }
+
+    fn mock_start(path: &str, session: &str) -> mockito::Mock {
+        mock("POST", path)
+            .match_header("Authorization", "Bearer fake-token")
+            .with_status(201)
+            .with_header("Location", session)
+            .expect(1)
+            .create()
+    }
+
+    fn mock_chunk(
+        session: &str,
+        content_range: &str,
+        body: &str,
+        status: usize,
+        range: Option<&str>,
+    ) -> mockito::Mock {
+        let mock = mock("PUT", session)
+            .match_header("Authorization", "Bearer fake-token")
+            .match_header("Content-Range", content_range)
+            .match_body(body)
+            .with_status(status);
+        match range {
+            Some(range) => mock.with_header("Range", range),
+            None => mock,
+        }
+        .expect(1)
+        .create()
+    }
+
+    #[test]
+    fn reject_insecure_url() {
+        let logger = setup_test_logging();
+        let authentication = PortalAuthentication::IdentityToken {
+            audience: "portal".to_owned(),
+        };
+        PortalUploader::new(
+            Url::parse("http://example.com/").unwrap(),
+            authentication,
+            None,
+            None,
+            None,
+            &logger,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn chunked_upload() {
+        let mut uploader = mockito_uploader("/portal-chunked", 4);
+        let session = "/portal-chunked-session";
+        let mocks = vec![
+            mock_start("/portal-chunked/sum/part", session),
+            mock_chunk(session, "bytes 0-3/*", "0123", 308, Some("bytes=0-3")),
+            // The server only takes part of this chunk, so the rest is sent
+            // again with the next one.
+            mock_chunk(session, "bytes 4-7/*", "4567", 308, Some("bytes=0-5")),
+            mock_chunk(session, "bytes 6-9/*", "6789", 308, Some("bytes=0-9")),
+            mock_chunk(session, "bytes 10-11/12", "ab", 201, None),
+        ];
+
+        let mut writer = uploader.put("sum/part", "trace-id").unwrap();
+        writer.write_all(b"0123456789ab").unwrap();
+        writer.complete_upload().unwrap();
+
+        for mock in mocks {
+            mock.assert();
+        }
+    }
+
+    #[test]
+    fn resume_failed_chunk() {
+        let mut uploader = mockito_uploader("/portal-resume", 4);
+        let session = "/portal-resume-session";
+        let mocks = vec![
+            mock_start("/portal-resume/sum/part", session),
+            mock_chunk(session, "bytes 0-3/*", "0123", 308, Some("bytes=0-3")),
+            // The chunk fails, but the server got some of it
+            mock_chunk(session, "bytes 4-5/6", "45", 400, None),
+            mock_chunk(session, "bytes */*", "", 308, Some("bytes=0-4")),
+            mock_chunk(session, "bytes 5-5/6", "5", 200, None),
+        ];
+
+        let mut writer = uploader.put("sum/part", "trace-id").unwrap();
+        writer.write_all(b"012345").unwrap();
+        writer.complete_upload().unwrap();
+
+        for mock in mocks {
+            mock.assert();
+        }
+    }
+
+    #[test]
+    fn give_up_without_progress() {
+        let mut uploader = mockito_uploader("/portal-no-progress", 4);
+        let session = "/portal-no-progress-session";
+        let mocked_start = mock_start("/portal-no-progress/key", session);
+        let mocked_chunk = mock("PUT", session)
+            .match_header("Content-Range", "bytes 0-1/2")
+            .with_status(400)
+            .expect(MAX_ATTEMPTS_WITHOUT_PROGRESS as usize)
+            .create();
+        let mocked_status = mock("PUT", session)
+            .match_header("Content-Range", "bytes */*")
+            .with_status(308)
+            .expect(MAX_ATTEMPTS_WITHOUT_PROGRESS as usize - 1)
+            .create();
+
+        let mut writer = uploader.put("key", "trace-id").unwrap();
+        writer.write_all(b"01").unwrap();
+        writer.complete_upload().unwrap_err();
+
+        mocked_start.assert();
+        mocked_chunk.assert();
+        mocked_status.assert();
+    }
+
+    #[test]
+    fn empty_object_and_cancel() {
+        let mut uploader = mockito_uploader("/portal-empty", 4);
+        let session = "/portal-empty-session";
+        let mocks = vec![
+            mock_start("/portal-empty/empty", session),
+            mock_chunk(session, "bytes */0", "", 200, None),
+        ];
+        let mut writer = uploader.put("empty", "trace-id").unwrap();
+        writer.complete_upload().unwrap();
+        for mock in mocks {
+            mock.assert();
+        }
+
+        let session = "/portal-canceled-session";
+        let mocked_start = mock_start("/portal-empty/canceled", session);
+        let mocked_delete = mock("DELETE", session)
+            .match_header("Authorization", "Bearer fake-token")
+            .with_status(204)
+            .expect(1)
+            .create();
+        let mut writer = uploader.put("canceled", "trace-id").unwrap();
+        writer.cancel_upload().unwrap();
+        mocked_start.assert();
+        mocked_delete.assert();
+    }
+
+    #[test]
+    fn put_if_absent() {
+        let mut uploader = mockito_uploader("/portal-exclusive", 4);
+        let mocked_start = mock("POST", "/portal-exclusive/key")
+            .match_header("If-None-Match", "*")
+            .with_status(412)
+            .expect(1)
+            .create();
+
+        let error = uploader.put_if_absent("key", "trace-id").err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<TransportError>(),
+            Some(TransportError::AlreadyExists(_))
+        ));
+        mocked_start.assert();
+    }
+
+    #[test]
+    fn reads_fail() {
+        let mut uploader = mockito_uploader("/portal-reads", 4);
+        assert!(uploader.get("key", "trace-id").is_err());
+        assert!(uploader.list("", "trace-id").is_err());
+        assert!(uploader.delete("key", "trace-id").is_err());
+
+        let mocked_head = mock("HEAD", "/portal-reads/missing")
+            .with_status(404)
+            .expect(1)
+            .create();
+        assert!(!uploader.exists("missing", "trace-id").unwrap());
+        mocked_head.assert();
+    }
+}
@@ -1,35 +1,71 @@
 use crate::aws_credentials;
 use crate::{
-    aws_credentials::{basic_runtime, retry_request},
+    aws_credentials::retry_request_with_parameters,
     config::S3Path,
     logging::event,
+    retries::{Retrier, RetryParameters},
+    runtime::{shared_runtime, PrefetchingReader},
     transport::{Transport, TransportWriter},
-    Error,
+    Error, TransportError,
 };
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
 use derivative::Derivative;
+use futures::{stream, Stream, StreamExt};
 use http::{HeaderMap, StatusCode};
 use hyper_rustls::HttpsConnector;
-use rusoto_core::{request::BufferedHttpResponse, ByteStream, Region, RusotoError};
+use rusoto_core::{
+    credential::ProvideAwsCredentials,
+    param::{Params, ServiceParams},
+    request::{BufferedHttpResponse, HttpDispatchError},
+    signature::SignedRequest,
+    ByteStream, Client, DispatchSignedRequest, Region, RusotoError, RusotoResult,
+};
 use rusoto_s3::{
-    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
-    CompletedPart, CreateMultipartUploadRequest, GetObjectRequest, S3Client, UploadPartRequest, S3,
+    AbortMultipartUploadRequest, CompleteMultipartUploadError, CompleteMultipartUploadOutput,
+    CompleteMultipartUploadRequest, CompletedMultipartUpload, CompletedPart,
+    CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectError, GetObjectRequest,
+    HeadObjectError, HeadObjectRequest, ListObjectsV2Request, S3Client, UploadPartRequest, S3,
 };
-use slog::{debug, info, o, Logger};
+use slog::{debug, info, o, warn, Logger};
 use std::{
-    io::{Read, Write},
+    collections::VecDeque,
+    io::{self, Read, Write},
     mem,
-    pin::Pin,
     time::Duration,
 };
-use tokio::{
-    io::{AsyncRead, AsyncReadExt},
-    runtime::Runtime,
-};
+use tokio::{runtime::Runtime, task::JoinHandle};
+use xml::reader::{EventReader, XmlEvent};
 
 /// ClientProvider allows mocking out a client for testing.
-type ClientProvider = Box<dyn Fn(&Region, aws_credentials::Provider) -> Result<S3Client>>;
+type ClientProvider = Box<dyn Fn(&Region, aws_credentials::Provider) -> Result<S3ApiClient>>;
+
+/// An S3Client along with the rusoto_core::Client and Region it sends requests
+/// through. rusoto_s3 can't set every header that S3 supports, so requests
+/// that need one are built as a SignedRequest and dispatched through `client`,
+/// which signs them with the same credentials as `s3`.
+#[derive(Clone)]
+struct S3ApiClient {
+    s3: S3Client,
+    client: Client,
+    region: Region,
+}
+
+impl S3ApiClient {
+    /// Creates an S3ApiClient, like S3Client::new_with.
+    fn new_with<P, D>(request_dispatcher: D, credentials_provider: P, region: Region) -> Self
+    where
+        P: ProvideAwsCredentials + Send + Sync + 'static,
+        D: DispatchSignedRequest + Send + Sync + 'static,
+    {
+        let client = Client::new_with(credentials_provider, request_dispatcher);
+        S3ApiClient {
+            s3: S3Client::new_with_client(client.clone(), region.clone()),
+            client,
+            region,
+        }
+    }
+}
 
 /// Implementation of Transport that reads and writes objects from Amazon S3.
 #[derive(Derivative)]
@@ -38,19 +74,47 @@ pub struct S3Transport {
     path: S3Path,
     #[derivative(Debug = "ignore")]
     credentials_provider: aws_credentials::Provider,
-    // client_provider allows injection of mock S3Client for testing purposes
+    // client_provider allows injection of mock S3ApiClient for testing purposes
     #[derivative(Debug = "ignore")]
     client_provider: ClientProvider,
+    // region is the region that S3 clients are constructed for, which may be
+    // a custom region pointing at an S3-compatible endpoint.
+    region: Region,
+    retry_parameters: RetryParameters,
+    upload_part_size: usize,
+    max_parallel_upload_parts: usize,
     logger: Logger,
 }
 
+/// Minimum size of a part in a multipart upload, except for the last one.
+/// https://docs.aws.amazon.com/AmazonS3/latest/dev/qfacts.html
+const MINIMUM_UPLOAD_PART_SIZE: usize = 5_242_880;
+
+/// How long a MultipartUploadWriter that is dropped without completing or
+/// canceling its upload waits for its single attempt to abort the upload.
+const ABANDONED_UPLOAD_ABORT_TIMEOUT: Duration = Duration::from_secs(10);
+
 impl S3Transport {
+    /// Creates an S3Transport for the provided path. If `endpoint` is set,
+    /// requests are sent to that URL (e.g. "http://localhost:9000" for a local
+    /// MinIO server) rather than to the AWS endpoint for the path's region,
+    /// which is still used when signing requests. Rusoto always uses
+    /// path-style addressing (i.e. `{endpoint}/{bucket}/{key}`), which is what
+    /// S3-compatible services like MinIO and localstack expect.
     pub fn new(
         path: S3Path,
+        endpoint: Option<String>,
         credentials_provider: aws_credentials::Provider,
         parent_logger: &Logger,
     ) -> Self {
-        S3Transport::new_with_client(
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom {
+                name: path.region.name().to_owned(),
+                endpoint,
+            },
+            None => path.region.clone(),
+        };
+        let mut transport = S3Transport::new_with_client(
             path,
             credentials_provider,
             Box::new(
@@ -73,7 +137,7 @@ impl S3Transport {
                     let connector = HttpsConnector::with_native_roots();
                     let http_client = rusoto_core::HttpClient::from_builder(builder, connector);
 
-                    Ok(S3Client::new_with(
+                    Ok(S3ApiClient::new_with(
                         http_client,
                         credentials_provider,
                         region.clone(),
@@ -81,7 +145,34 @@ impl S3Transport {
                 },
             ),
             parent_logger,
-        )
+        );
+        transport.region = region;
+        transport
+    }
+
+    /// Creates an S3Transport that accesses the bucket as the IAM role
+    /// identified by `role_arn`, which is assumed via STS AssumeRole using
+    /// credentials from `base_credentials_provider`. This allows writing to
+    /// buckets owned by other AWS accounts that grant access to that role.
+    /// `endpoint` is interpreted as in `S3Transport::new`.
+    pub fn new_with_assumed_role(
+        path: S3Path,
+        endpoint: Option<String>,
+        role_arn: &str,
+        base_credentials_provider: aws_credentials::Provider,
+        parent_logger: &Logger,
+    ) -> Result<Self> {
+        let credentials_provider = aws_credentials::Provider::new_assume_role(
+            role_arn,
+            base_credentials_provider,
+            path.region.clone(),
+        )?;
+        Ok(S3Transport::new(
+            path,
+            endpoint,
+            credentials_provider,
+            parent_logger,
+        ))
     }
 
     fn new_with_client(
@@ -95,12 +186,81 @@ impl S3Transport {
             event::IDENTITY => credentials_provider.to_string(),
         ));
         S3Transport {
+            region: path.region.clone(),
             path: path.ensure_directory_prefix(),
             credentials_provider,
             client_provider,
+            retry_parameters: RetryParameters::default(),
+            upload_part_size: MINIMUM_UPLOAD_PART_SIZE,
+            max_parallel_upload_parts: 1,
             logger,
         }
     }
+
+    /// Configures how S3 API requests that fail with transient errors (e.g.
+    /// HTTP 503 SlowDown) are retried. If this is never called, requests are
+    /// retried using RetryParameters::default().
+    pub fn set_retry_parameters(&mut self, retry_parameters: RetryParameters) {
+        self.retry_parameters = retry_parameters;
+    }
+
+    /// Configures the size of the parts in which objects are uploaded to S3.
+    /// Larger parts mean fewer UploadPart requests for large objects, at the
+    /// cost of more memory. Defaults to 5 MiB, which is also the smallest part
+    /// size S3 permits.
+    pub fn set_upload_part_size(&mut self, upload_part_size: usize) -> Result<()> {
+        if upload_part_size < MINIMUM_UPLOAD_PART_SIZE {
+            return Err(anyhow!(
+                "upload part size {} is smaller than minimum {}",
+                upload_part_size,
+                MINIMUM_UPLOAD_PART_SIZE
+            ));
+        }
+        self.upload_part_size = upload_part_size;
+        Ok(())
+    }
+
+    /// Configures how many UploadPart requests may be in flight at once for
+    /// each object being uploaded. Defaults to 1, meaning parts are uploaded
+    /// serially. Each in-flight part holds a buffer of at least the upload part
+    /// size, so this also bounds the memory used by each upload.
+    pub fn set_max_parallel_upload_parts(
+        &mut self,
+        max_parallel_upload_parts: usize,
+    ) -> Result<()> {
+        if max_parallel_upload_parts == 0 {
+            return Err(anyhow!("max parallel upload parts must be at least 1"));
+        }
+        self.max_parallel_upload_parts = max_parallel_upload_parts;
+        Ok(())
+    }
+
+    /// Starts a multipart upload of the object with the provided key. If
+    /// `if_none_match` is true, the upload is only completed if no object
+    /// exists with the key.
+    fn multipart_upload_writer(
+        &mut self,
+        key: &str,
+        trace_id: &str,
+        if_none_match: bool,
+    ) -> Result<MultipartUploadWriter> {
+        let logger = self.logger.new(o!(
+            event::STORAGE_KEY => key.to_owned(),
+            event::TRACE_ID => trace_id.to_owned(),
+        ));
+        info!(logger, "put"; "if_none_match" => if_none_match);
+        let mut writer = MultipartUploadWriter::new(
+            self.path.bucket.to_owned(),
+            format!("{}{}", &self.path.key, key),
+            self.upload_part_size,
+            self.max_parallel_upload_parts,
+            (self.client_provider)(&self.region, self.credentials_provider.clone())?,
+            self.retry_parameters,
+            &logger,
+        )?;
+        writer.if_none_match = if_none_match;
+        Ok(writer)
+    }
 }
 
 impl Transport for S3Transport {
@@ -115,70 +275,425 @@ impl Transport for S3Transport {
             event::ACTION => "get s3 object",
         ));
         info!(logger, "get");
-        let runtime = basic_runtime()?;
-        let client = (self.client_provider)(&self.path.region, self.credentials_provider.clone())?;
+        let runtime = shared_runtime()?;
+        let client = (self.client_provider)(&self.region, self.credentials_provider.clone())?;
 
-        let get_output = retry_request(&logger, || {
-            runtime.block_on(client.get_object(GetObjectRequest {
+        let get_output = retry_request_with_parameters(&logger, &self.retry_parameters, || {
+            runtime.block_on(client.s3.get_object(GetObjectRequest {
                 bucket: self.path.bucket.to_owned(),
                 key: [&self.path.key, key].concat(),
                 ..Default::default()
             }))
         })
-        .context("error getting S3 object")?;
+        .map_err(|e| {
+            let not_found = matches!(e, RusotoError::Service(GetObjectError::NoSuchKey(_)));
+            let error = anyhow::Error::new(e).context("error getting S3 object");
+            if not_found {
+                error.context(TransportError::NotFound(key.to_owned()))
+            } else {
+                error
+            }
+        })?;
 
         let body = get_output.body.context("no body in GetObjectResponse")?;
 
-        Ok(Box::new(StreamingBodyReader::new(body, runtime)))
+        // If the download has to be resumed, make sure that we get the rest of
+        // the same version of the object.
+        let resume_request = GetObjectRequest {
+            bucket: self.path.bucket.to_owned(),
+            key: [&self.path.key, key].concat(),
+            if_match: get_output.e_tag,
+            ..Default::default()
+        };
+
+        let download = S3Download::new(
+            body,
+            resume_request,
+            client.s3,
+            self.retry_parameters,
+            logger,
+        );
+        Ok(Box::new(PrefetchingReader::from_stream(
+            download.into_stream(),
+        )?))
     }
 
     fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        Ok(Box::new(
+            self.multipart_upload_writer(key, trace_id, false)?,
+        ))
+    }
+
+    /// The upload is completed with If-None-Match: *, so S3 refuses to create
+    /// the object if one already exists with the key, including one created by
+    /// a concurrent upload that completed first.
+    /// https://docs.aws.amazon.com/AmazonS3/latest/userguide/conditional-writes.html
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        // Fail early rather than uploading an object that can't be created
+        if self.exists(key, trace_id)? {
+            return Err(TransportError::AlreadyExists(key.to_owned()).into());
+        }
+        Ok(Box::new(self.multipart_upload_writer(key, trace_id, true)?))
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        let logger = self.logger.new(o!(
+            event::STORAGE_KEY => prefix.to_owned(),
+            event::TRACE_ID => trace_id.to_owned(),
+            event::ACTION => "list s3 objects",
+        ));
+        info!(logger, "list");
+        let runtime = shared_runtime()?;
+        let client = (self.client_provider)(&self.region, self.credentials_provider.clone())?;
+
+        // ListObjectsV2 returns at most 1,000 keys per request, so we follow
+        // continuation tokens until the listing is no longer truncated.
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_ListObjectsV2.html
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let list_output =
+                retry_request_with_parameters(&logger, &self.retry_parameters, || {
+                    runtime.block_on(client.s3.list_objects_v2(ListObjectsV2Request {
+                        bucket: self.path.bucket.to_owned(),
+                        prefix: Some([&self.path.key, prefix].concat()),
+                        continuation_token: continuation_token.clone(),
+                        ..Default::default()
+                    }))
+                })
+                .context("error listing S3 objects")?;
+
+            for object in list_output.contents.unwrap_or_default() {
+                let key = object.key.context("no key in listed S3 object")?;
+                keys.push(
+                    key.strip_prefix(&self.path.key)
+                        .context(format!("listed key {} outside of S3 path", key))?
+                        .to_owned(),
+                );
+            }
+
+            if list_output.is_truncated != Some(true) {
+                break;
+            }
+            continuation_token = Some(
+                list_output
+                    .next_continuation_token
+                    .context("no continuation token in truncated ListObjectsV2 response")?,
+            );
+        }
+
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
         let logger = self.logger.new(o!(
             event::STORAGE_KEY => key.to_owned(),
             event::TRACE_ID => trace_id.to_owned(),
+            event::ACTION => "delete s3 object",
         ));
-        info!(logger, "put");
-        let writer = MultipartUploadWriter::new(
-            self.path.bucket.to_owned(),
-            format!("{}{}", &self.path.key, key),
-            // Set buffer size to 5 MB, which is the minimum required by Amazon
-            // https://docs.aws.amazon.com/AmazonS3/latest/dev/qfacts.html
-            5_242_880,
-            (self.client_provider)(&self.path.region, self.credentials_provider.clone())?,
-            &logger,
-        )?;
-        Ok(Box::new(writer))
+        info!(logger, "delete");
+        let runtime = shared_runtime()?;
+        let client = (self.client_provider)(&self.region, self.credentials_provider.clone())?;
+
+        // DeleteObject succeeds even if the object does not exist.
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_DeleteObject.html
+        retry_request_with_parameters(&logger, &self.retry_parameters, || {
+            runtime.block_on(client.s3.delete_object(DeleteObjectRequest {
+                bucket: self.path.bucket.to_owned(),
+                key: [&self.path.key, key].concat(),
+                ..Default::default()
+            }))
+        })
+        .context("error deleting S3 object")?;
+
+        Ok(())
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        let logger = self.logger.new(o!(
+            event::STORAGE_KEY => key.to_owned(),
+            event::TRACE_ID => trace_id.to_owned(),
+            event::ACTION => "head s3 object",
+        ));
+        info!(logger, "exists");
+        let runtime = shared_runtime()?;
+        let client = (self.client_provider)(&self.region, self.credentials_provider.clone())?;
+
+        let head_result = retry_request_with_parameters(&logger, &self.retry_parameters, || {
+            runtime.block_on(client.s3.head_object(HeadObjectRequest {
+                bucket: self.path.bucket.to_owned(),
+                key: [&self.path.key, key].concat(),
+                ..Default::default()
+            }))
+        });
+
+        // Responses to HEAD requests have no body, so Rusoto usually can't
+        // tell that a 404 means NoSuchKey and reports RusotoError::Unknown.
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_HeadObject.html
+        match head_result {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+            Err(RusotoError::Unknown(response)) if response.status == StatusCode::NOT_FOUND => {
+                Ok(false)
+            }
+            Err(e) => Err(e).context("error checking existence of S3 object"),
+        }
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        let logger = self.logger.new(o!(
+            event::STORAGE_KEY => key.to_owned(),
+            event::TRACE_ID => trace_id.to_owned(),
+            event::ACTION => "head s3 object",
+        ));
+        info!(logger, "etag");
+        let runtime = shared_runtime()?;
+        let client = (self.client_provider)(&self.region, self.credentials_provider.clone())?;
+
+        let output = retry_request_with_parameters(&logger, &self.retry_parameters, || {
+            runtime.block_on(client.s3.head_object(HeadObjectRequest {
+                bucket: self.path.bucket.to_owned(),
+                key: [&self.path.key, key].concat(),
+                ..Default::default()
+            }))
+        })
+        .context("error getting S3 object metadata")?;
+
+        output.e_tag.context("no ETag in S3 HeadObject response")
+    }
+}
+
+/// Completes a multipart upload like S3::complete_multipart_upload, but with
+/// an If-None-Match: * header so that S3 only creates the object if none
+/// exists with its key. rusoto_s3 has no way to set that header, so we build
+/// and sign the request ourselves.
+/// https://docs.aws.amazon.com/AmazonS3/latest/API/API_CompleteMultipartUpload.html
+async fn complete_multipart_upload_if_none_match(
+    client: &S3ApiClient,
+    input: CompleteMultipartUploadRequest,
+) -> RusotoResult<CompleteMultipartUploadOutput, CompleteMultipartUploadError> {
+    let mut request = SignedRequest::new(
+        "POST",
+        "s3",
+        &client.region,
+        &format!("/{}/{}", input.bucket, input.key),
+    );
+    request.add_header("If-None-Match", "*");
+    let mut params = Params::new();
+    params.put("uploadId", &input.upload_id);
+    request.set_params(params);
+
+    let mut payload = String::from("<CompleteMultipartUpload>");
+    for part in input
+        .multipart_upload
+        .and_then(|upload| upload.parts)
+        .unwrap_or_default()
+    {
+        payload.push_str(&format!(
+            "<Part><ETag>{}</ETag><PartNumber>{}</PartNumber></Part>",
+            part.e_tag
+                .as_deref()
+                .map(escape_xml_text)
+                .unwrap_or_default(),
+            part.part_number.unwrap_or_default(),
+        ));
+    }
+    payload.push_str("</CompleteMultipartUpload>");
+    request.set_payload(Some(payload.into_bytes()));
+
+    let response = client
+        .client
+        .sign_and_dispatch(request)
+        .await?
+        .buffer()
+        .await?;
+    if !response.status.is_success() {
+        return Err(CompleteMultipartUploadError::from_response(response));
     }
+
+    parse_complete_multipart_upload_output(&response.body)
+        .map_err(|e| RusotoError::ParseError(format!("{:?}", e)))
 }
 
-/// StreamingBodyReader is an std::io::Read implementation which reads from the
-/// tokio::io::AsyncRead inside the StreamingBody in a Rusoto API request
-/// response.
-struct StreamingBodyReader {
-    body_reader: Pin<Box<dyn AsyncRead + Send + Sync>>,
-    runtime: Runtime,
+/// Escapes the characters that may not appear literally in XML text.
+fn escape_xml_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
-impl StreamingBodyReader {
-    fn new(body: ByteStream, runtime: Runtime) -> StreamingBodyReader {
-        StreamingBodyReader {
-            body_reader: Box::pin(body.into_async_read()),
-            runtime,
+/// Parses the body of a response to CompleteMultipartUpload. If the body is an
+/// Error document rather than a CompleteMultipartUploadResult, as S3 may send
+/// with a 200 OK status, all the fields of the output are None.
+fn parse_complete_multipart_upload_output(body: &[u8]) -> Result<CompleteMultipartUploadOutput> {
+    let mut output = CompleteMultipartUploadOutput::default();
+    let mut element_path: Vec<String> = Vec::new();
+
+    for event in EventReader::new(body) {
+        match event.context("failed to parse CompleteMultipartUpload response")? {
+            XmlEvent::StartElement { name, .. } => element_path.push(name.local_name),
+            XmlEvent::EndElement { .. } => {
+                element_path.pop();
+            }
+            XmlEvent::Characters(text) => {
+                let path: Vec<&str> = element_path.iter().map(String::as_str).collect();
+                match path.as_slice() {
+                    ["CompleteMultipartUploadResult", "Location"] => output.location = Some(text),
+                    ["CompleteMultipartUploadResult", "Bucket"] => output.bucket = Some(text),
+                    ["CompleteMultipartUploadResult", "Key"] => output.key = Some(text),
+                    ["CompleteMultipartUploadResult", "ETag"] => output.e_tag = Some(text),
+                    _ => {}
+                }
+            }
+            _ => {}
         }
     }
+
+    Ok(output)
+}
+
+/// Returns true if the response is S3's rejection of a conditional write
+/// because an object already exists with the key or another conditional write
+/// of the key is in progress.
+/// https://docs.aws.amazon.com/AmazonS3/latest/userguide/conditional-writes.html
+fn is_conditional_write_conflict(response: &BufferedHttpResponse) -> bool {
+    response.status == StatusCode::PRECONDITION_FAILED
+        || (response.status == StatusCode::CONFLICT
+            && String::from_utf8_lossy(&response.body).contains("ConditionalRequestConflict"))
 }
 
-impl Read for StreamingBodyReader {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-        self.runtime.block_on(self.body_reader.read(buf))
+/// S3Download reads the StreamingBody in a GetObject response on the shared
+/// runtime. S3Transport::get hands the stream of its chunks to a
+/// PrefetchingReader, so that the object is downloaded ahead of the caller's
+/// reads without blocking a thread on each read. If reading from the body
+/// fails, e.g. because the connection was dropped, S3Download issues a new
+/// GetObject request with a Range header to resume reading the object from
+/// where it left off.
+struct S3Download {
+    // None if the last read failed and the download must be resumed
+    body: Option<ByteStream>,
+    // The request used to resume the download, minus the Range header
+    request: GetObjectRequest,
+    // Number of bytes read so far
+    offset: u64,
+    client: S3Client,
+    retry_parameters: RetryParameters,
+    logger: Logger,
+}
+
+impl S3Download {
+    fn new(
+        body: ByteStream,
+        request: GetObjectRequest,
+        client: S3Client,
+        retry_parameters: RetryParameters,
+        logger: Logger,
+    ) -> S3Download {
+        S3Download {
+            body: Some(body),
+            request,
+            offset: 0,
+            client,
+            retry_parameters,
+            logger,
+        }
+    }
+
+    /// Returns a stream of the chunks of the object, which ends early with an
+    /// error if reading the object fails even after retries.
+    fn into_stream(self) -> impl Stream<Item = io::Result<Bytes>> {
+        stream::try_unfold(self, |mut download| async move {
+            Ok(download.next_chunk().await?.map(|chunk| (chunk, download)))
+        })
+    }
+
+    /// Returns the next chunk of the object, or None once all of it has been
+    /// read, retrying failures to read the body or to resume the download.
+    async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let logger = self.logger.new(o!(event::ACTION => "read s3 object"));
+        let retry_parameters = self.retry_parameters;
+        let mut retrier = Retrier::new(&logger, &retry_parameters);
+        loop {
+            let error = match self.next_chunk_once().await {
+                Ok(chunk) => return Ok(chunk),
+                Err(error) => error,
+            };
+            if !retrier
+                .retry(&error, aws_credentials::retryable(&error))
+                .await
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    Error::AnyhowError(
+                        anyhow::Error::new(error).context("error reading S3 object"),
+                    ),
+                ));
+            }
+        }
+    }
+
+    /// Reads the next chunk from the current body, first resuming the download
+    /// if the previous read failed. Failures to read from the body are
+    /// reported as RusotoError::HttpDispatch so that they are retried like any
+    /// other dropped connection.
+    async fn next_chunk_once(&mut self) -> RusotoResult<Option<Bytes>, GetObjectError> {
+        let mut body = match self.body.take() {
+            Some(body) => body,
+            None => {
+                info!(
+                    self.logger, "resuming download of S3 object";
+                    "offset" => self.offset,
+                );
+                let get_output = match self
+                    .client
+                    .get_object(GetObjectRequest {
+                        range: Some(format!("bytes={}-", self.offset)),
+                        ..self.request.clone()
+                    })
+                    .await
+                {
+                    Ok(get_output) => get_output,
+                    // If the connection was dropped after we had read the
+                    // entire object, there is nothing left to read.
+                    // https://docs.aws.amazon.com/AmazonS3/latest/API/API_GetObject.html
+                    Err(RusotoError::Unknown(response))
+                        if response.status == StatusCode::RANGE_NOT_SATISFIABLE =>
+                    {
+                        return Ok(None)
+                    }
+                    Err(e) => return Err(e),
+                };
+                get_output.body.ok_or_else(|| {
+                    RusotoError::ParseError("no body in GetObjectResponse".to_owned())
+                })?
+            }
+        };
+
+        // If the read fails, body is dropped, and the next attempt will resume
+        // the download.
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                self.offset += chunk.len() as u64;
+                self.body = Some(body);
+                Ok(Some(chunk))
+            }
+            Some(Err(e)) => Err(RusotoError::HttpDispatch(HttpDispatchError::new(format!(
+                "failed to read S3 object body at offset {}: {}",
+                self.offset, e
+            )))),
+            None => Ok(None),
+        }
     }
 }
 
 /// MultipartUploadWriter is a TransportWriter implementation that uses AWS S3
 /// multi part uploads to permit streaming of objects into S3. On creation, it
 /// initiates a multipart upload. It maintains a memory buffer into which it
-/// writes the buffers passed by std::io::Write::write, and when there is more
-/// than buffer_capacity bytes in it, performs an UploadPart call. On
+/// copies the buffers passed by std::io::Write::write, and each time the buffer
+/// fills up to minimum_upload_part_size bytes, performs an UploadPart call.
+/// Writes larger than the space left in the buffer are split across parts, so
+/// the buffer never grows beyond the part size. On
 /// TransportWrite::complete_upload, it calls CompleteMultipartUpload to finish
 /// the upload. If any part of the upload fails, it cleans up by calling
 /// AbortMultipartUpload as otherwise we would be billed for partial uploads.
@@ -186,53 +701,86 @@ impl Read for StreamingBodyReader {
 #[derive(Derivative)]
 #[derivative(Debug)]
 struct MultipartUploadWriter {
-    runtime: Runtime,
+    runtime: &'static Runtime,
     #[derivative(Debug = "ignore")]
-    client: S3Client,
+    client: S3ApiClient,
     bucket: String,
     key: String,
     upload_id: String,
+    next_part_number: i64,
+    #[derivative(Debug = "ignore")]
+    in_flight_parts: VecDeque<JoinHandle<Result<UploadedPart>>>,
     completed_parts: Vec<CompletedPart>,
+    // MD5 digests of the content of each of completed_parts
+    #[derivative(Debug = "ignore")]
+    part_digests: Vec<md5::Digest>,
+    // True if S3 reported the MD5 digest of each completed part as its ETag
+    etags_are_digests: bool,
+    // True once the upload has been completed or canceled
+    finished: bool,
+    // True if the upload should only be completed if no object exists with
+    // the key
+    if_none_match: bool,
     minimum_upload_part_size: usize,
+    max_parallel_parts: usize,
     buffer: Vec<u8>,
+    retry_parameters: RetryParameters,
     logger: Logger,
 }
 
+/// A part of a multipart upload that has been successfully uploaded.
+struct UploadedPart {
+    completed_part: CompletedPart,
+    /// MD5 digest of the part's content
+    digest: md5::Digest,
+    /// True if the part's ETag is its MD5 digest, which is not the case for
+    /// encrypted objects
+    etag_is_digest: bool,
+}
+
 impl MultipartUploadWriter {
     /// Creates a new MultipartUploadWriter with the provided parameters. A real
-    /// instance of this will fail if buffer_capacity is less than 5 MB, but we
-    /// allow smaller values for testing purposes. Larger values are also
-    /// acceptable but smaller values prevent excessive memory usage.
+    /// instance of this will fail if minimum_upload_part_size is less than
+    /// 5 MB, but we allow smaller values for testing purposes. Larger values
+    /// are also acceptable but smaller values prevent excessive memory usage.
+    /// Up to max_parallel_parts UploadPart requests may be in flight at once,
+    /// each of which holds on to a buffer of minimum_upload_part_size bytes, so
+    /// the writer uses at most (max_parallel_parts + 1) times that much memory
+    /// for content.
     fn new(
         bucket: String,
         key: String,
         minimum_upload_part_size: usize,
-        client: S3Client,
+        max_parallel_parts: usize,
+        client: S3ApiClient,
+        retry_parameters: RetryParameters,
         parent_logger: &Logger,
     ) -> Result<MultipartUploadWriter> {
-        let runtime = basic_runtime()?;
+        let runtime = shared_runtime()?;
         let logger = parent_logger.new(o!());
 
         // We use the "bucket-owner-full-control" canned ACL to ensure that
         // objects we send to peers will be owned by them.
         // https://docs.aws.amazon.com/AmazonS3/latest/dev/about-object-ownership.html
-        let create_output = retry_request(
-            &logger.new(o!(event::ACTION => "create multipart upload")),
-            || {
-                runtime.block_on(
-                    client.create_multipart_upload(CreateMultipartUploadRequest {
-                        bucket: bucket.to_string(),
-                        key: key.to_string(),
-                        acl: Some("bucket-owner-full-control".to_owned()),
-                        ..Default::default()
-                    }),
-                )
-            },
-        )
-        .context(format!(
-            "error creating multipart upload to s3://{}",
-            bucket
-        ))?;
+        let create_output =
+            retry_request_with_parameters(
+                &logger.new(o!(event::ACTION => "create multipart upload")),
+                &retry_parameters,
+                || {
+                    runtime.block_on(client.s3.create_multipart_upload(
+                        CreateMultipartUploadRequest {
+                            bucket: bucket.to_string(),
+                            key: key.to_string(),
+                            acl: Some("bucket-owner-full-control".to_owned()),
+                            ..Default::default()
+                        },
+                    ))
+                },
+            )
+            .context(format!(
+                "error creating multipart upload to s3://{}",
+                bucket
+            ))?;
 
         Ok(MultipartUploadWriter {
             runtime,
@@ -242,82 +790,167 @@ impl MultipartUploadWriter {
             upload_id: create_output
                 .upload_id
                 .context("no upload ID in CreateMultipartUploadResponse")?,
+            next_part_number: 0,
+            in_flight_parts: VecDeque::new(),
             completed_parts: Vec::new(),
-            // Upload parts must be at least buffer_capacity, but it's fine if
-            // they're bigger, so overprovision the buffer to make it unlikely
-            // that the caller will overflow it.
-            minimum_upload_part_size,
-            buffer: Vec::with_capacity(minimum_upload_part_size * 2),
+            part_digests: Vec::new(),
+            etags_are_digests: true,
+            finished: false,
+            if_none_match: false,
+            minimum_upload_part_size: minimum_upload_part_size.max(1),
+            max_parallel_parts: max_parallel_parts.max(1),
+            buffer: Vec::with_capacity(minimum_upload_part_size),
+            retry_parameters,
             logger,
         })
     }
 
-    /// Upload content in internal buffer, if any, to S3 in an UploadPart call.
+    /// Start uploading content in internal buffer, if any, to S3 in an
+    /// UploadPart call. The upload runs in the background, but if there are
+    /// already max_parallel_parts uploads in flight, this blocks until the
+    /// oldest of them completes.
     fn upload_part(&mut self) -> Result<()> {
         if self.buffer.is_empty() {
             return Ok(());
         }
 
-        let part_number = (self.completed_parts.len() + 1) as i64;
+        self.next_part_number += 1;
+        let part_number = self.next_part_number;
+        debug!(self.logger, "uploading part"; "part_number" => part_number);
 
         // Move internal buffer into request object and replace it with a new,
         // empty buffer. UploadPartRequest assumes ownership of the request body
         // so sadly we have to clone the buffer in order to be able to retry.
         let body = mem::replace(
             &mut self.buffer,
-            Vec::with_capacity(self.minimum_upload_part_size * 2),
+            Vec::with_capacity(self.minimum_upload_part_size),
         );
 
-        let upload_output =
-            retry_request(&self.logger.new(o!(event::ACTION => "upload part")), || {
-                self.runtime
-                    .block_on(self.client.upload_part(UploadPartRequest {
-                        bucket: self.bucket.to_string(),
-                        key: self.key.to_string(),
-                        upload_id: self.upload_id.clone(),
-                        part_number: part_number as i64,
-                        body: Some(body.clone().into()),
-                        ..Default::default()
-                    }))
-            })
-            .context("failed to upload part")
-            .map_err(|e| {
-                // Clean up botched uploads
-                if let Err(cancel) = self.cancel_upload() {
-                    return cancel.context(e);
-                }
-                e
-            })?;
+        let handle = self.runtime.handle().clone();
+        let client = self.client.clone();
+        let bucket = self.bucket.clone();
+        let key = self.key.clone();
+        let upload_id = self.upload_id.clone();
+        let retry_parameters = self.retry_parameters;
+        let logger = self.logger.new(o!(event::ACTION => "upload part"));
 
-        let e_tag = upload_output
-            .e_tag
-            .context("no ETag in UploadPartOutput")
-            .map_err(|e| {
-                if let Err(cancel) = self.cancel_upload() {
-                    return cancel.context(e);
+        // Send the part's MD5 digest in Content-MD5 so that S3 rejects the part
+        // if it is corrupted in transit.
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_UploadPart.html
+        let digest = md5::compute(&body);
+        let content_md5 = base64::encode(digest.0);
+
+        // retry_request blocks the current thread between attempts, so we run
+        // the upload on a thread where blocking is permitted.
+        self.in_flight_parts
+            .push_back(self.runtime.spawn_blocking(move || {
+                let upload_output =
+                    retry_request_with_parameters(&logger, &retry_parameters, || {
+                        handle.block_on(client.s3.upload_part(UploadPartRequest {
+                            bucket: bucket.clone(),
+                            key: key.clone(),
+                            upload_id: upload_id.clone(),
+                            part_number,
+                            body: Some(body.clone().into()),
+                            content_md5: Some(content_md5.clone()),
+                            ..Default::default()
+                        }))
+                    })
+                    .context("failed to upload part")?;
+
+                let e_tag = upload_output.e_tag.context("no ETag in UploadPartOutput")?;
+
+                // The ETag of an object or part is the hex encoding of its MD5
+                // digest unless it is encrypted with SSE-KMS or SSE-C.
+                // https://docs.aws.amazon.com/AmazonS3/latest/API/RESTCommonResponseHeaders.html
+                let etag_is_digest = upload_output.server_side_encryption.as_deref()
+                    != Some("aws:kms")
+                    && upload_output.sse_customer_algorithm.is_none();
+                if etag_is_digest && e_tag.trim_matches('"') != format!("{:x}", digest) {
+                    return Err(anyhow!(
+                        "ETag {} of part {} does not match its MD5 digest {:x}",
+                        e_tag,
+                        part_number,
+                        digest
+                    ));
                 }
-                e
-            })?;
 
-        let completed_part = CompletedPart {
-            e_tag: Some(e_tag),
-            part_number: Some(part_number),
-        };
-        self.completed_parts.push(completed_part);
+                Ok(UploadedPart {
+                    completed_part: CompletedPart {
+                        e_tag: Some(e_tag),
+                        part_number: Some(part_number),
+                    },
+                    digest,
+                    etag_is_digest,
+                })
+            }));
+
+        while self.in_flight_parts.len() >= self.max_parallel_parts {
+            self.wait_for_oldest_part()?;
+        }
+
         Ok(())
     }
+
+    /// Wait for all in-flight part uploads to complete.
+    fn wait_for_all_parts(&mut self) -> Result<()> {
+        while !self.in_flight_parts.is_empty() {
+            self.wait_for_oldest_part()?;
+        }
+        Ok(())
+    }
+
+    /// Wait for the oldest in-flight part upload to complete and record it as
+    /// a completed part. Parts are started in order of part number, so waiting
+    /// on them in the order they were started keeps completed_parts sorted, as
+    /// CompleteMultipartUpload requires.
+    fn wait_for_oldest_part(&mut self) -> Result<()> {
+        let in_flight_part = match self.in_flight_parts.pop_front() {
+            Some(in_flight_part) => in_flight_part,
+            None => return Ok(()),
+        };
+
+        let result = self
+            .runtime
+            .block_on(in_flight_part)
+            .context("failed to join part upload task")
+            .and_then(|result| result);
+
+        match result {
+            Ok(uploaded_part) => {
+                self.completed_parts.push(uploaded_part.completed_part);
+                self.part_digests.push(uploaded_part.digest);
+                self.etags_are_digests &= uploaded_part.etag_is_digest;
+                Ok(())
+            }
+            Err(e) => {
+                // Clean up botched uploads
+                if let Err(cancel) = self.cancel_upload() {
+                    return Err(cancel.context(e));
+                }
+                Err(e)
+            }
+        }
+    }
 }
 
 impl Write for MultipartUploadWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        debug!(self.logger, "uploading part");
-        // Write into memory buffer, and upload to S3 if we have accumulated
-        // enough content.
-        self.buffer.extend_from_slice(buf);
-        if self.buffer.len() >= self.minimum_upload_part_size {
-            self.upload_part().map_err(|e| {
-                std::io::Error::new(std::io::ErrorKind::Other, Error::AnyhowError(e))
-            })?;
+        // Copy into memory buffer no more than will fit in the current part,
+        // uploading each part to S3 as it fills up, so that memory use does not
+        // depend on the size of the caller's writes.
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            let space = self.minimum_upload_part_size - self.buffer.len();
+            let (chunk, rest) = remaining.split_at(space.min(remaining.len()));
+            self.buffer.extend_from_slice(chunk);
+            remaining = rest;
+
+            if self.buffer.len() >= self.minimum_upload_part_size {
+                self.upload_part().map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::Other, Error::AnyhowError(e))
+                })?;
+            }
         }
 
         Ok(buf.len())
@@ -330,28 +963,35 @@ impl Write for MultipartUploadWriter {
 
 impl TransportWriter for MultipartUploadWriter {
     fn complete_upload(&mut self) -> Result<()> {
-        // Write last part, if any
+        // Write last part, if any, and wait for all parts to be uploaded
         self.upload_part()?;
+        self.wait_for_all_parts()?;
 
-        // Ignore output for now, but we might want the e_tag to check the
-        // digest
         let completed_parts = mem::take(&mut self.completed_parts);
-        retry_request(
+        let part_digests = mem::take(&mut self.part_digests);
+        let result = retry_request_with_parameters(
             &self.logger.new(o!(event::ACTION => "complete upload")),
+            &self.retry_parameters,
             || {
-                let output = self
-                    .runtime
-                    .block_on(self.client.complete_multipart_upload(
-                        CompleteMultipartUploadRequest {
-                            bucket: self.bucket.to_string(),
-                            key: self.key.to_string(),
-                            upload_id: self.upload_id.clone(),
-                            multipart_upload: Some(CompletedMultipartUpload {
-                                parts: Some(completed_parts.clone()),
-                            }),
-                            ..Default::default()
-                        },
-                    ))?;
+                let request = CompleteMultipartUploadRequest {
+                    bucket: self.bucket.to_string(),
+                    key: self.key.to_string(),
+                    upload_id: self.upload_id.clone(),
+                    multipart_upload: Some(CompletedMultipartUpload {
+                        parts: Some(completed_parts.clone()),
+                    }),
+                    ..Default::default()
+                };
+                let output = if self.if_none_match {
+                    self.runtime
+                        .block_on(complete_multipart_upload_if_none_match(
+                            &self.client,
+                            request,
+                        ))?
+                } else {
+                    self.runtime
+                        .block_on(self.client.s3.complete_multipart_upload(request))?
+                };
 
                 // Due to an oddity in S3's CompleteMultipartUpload API, some
                 // failed uploads can cause complete_multipart_upload to return
@@ -374,33 +1014,132 @@ impl TransportWriter for MultipartUploadWriter {
 
                 Ok(output)
             },
-        )
-        .context("error completing upload")?;
+        );
+
+        // S3 fails a conditional CompleteMultipartUpload with 412 Precondition
+        // Failed if the object exists, or with 409 ConditionalRequestConflict
+        // if another upload of the same key completed while this one was being
+        // completed. Either way, the object was not created by this upload,
+        // which is abandoned.
+        let output = match result {
+            Err(RusotoError::Unknown(response))
+                if self.if_none_match && is_conditional_write_conflict(&response) =>
+            {
+                let error = anyhow::Error::new(
+                    RusotoError::<CompleteMultipartUploadError>::Unknown(response),
+                )
+                .context(TransportError::AlreadyExists(self.key.clone()));
+                if let Err(cancel) = self.cancel_upload() {
+                    return Err(cancel.context(error));
+                }
+                return Err(error);
+            }
+            result => result.context("error completing upload")?,
+        };
+        self.finished = true;
+
+        // The ETag of an object created by a multipart upload is the hex
+        // encoding of the MD5 digest of the concatenated MD5 digests of its
+        // parts, followed by "-" and the number of parts. Checking it ensures
+        // that the object S3 assembled consists of exactly the parts we sent.
+        if self.etags_are_digests && !part_digests.is_empty() {
+            let concatenated_digests: Vec<u8> =
+                part_digests.iter().flat_map(|digest| digest.0).collect();
+            let expected_e_tag = format!(
+                "{:x}-{}",
+                md5::compute(&concatenated_digests),
+                part_digests.len()
+            );
+            let e_tag = output
+                .e_tag
+                .context("no ETag in CompleteMultipartUploadOutput")?;
+            if e_tag.trim_matches('"') != expected_e_tag {
+                return Err(anyhow!(
+                    "ETag {} of uploaded object does not match expected ETag {}",
+                    e_tag,
+                    expected_e_tag
+                ));
+            }
+        }
 
         Ok(())
     }
 
     fn cancel_upload(&mut self) -> Result<()> {
         debug!(self.logger, "canceling upload");
+        self.finished = true;
+        // Let any part uploads still in flight finish so that they can't
+        // outlive the upload. Their results don't matter since we are about to
+        // abort the upload.
+        for in_flight_part in mem::take(&mut self.in_flight_parts) {
+            let _ = self.runtime.block_on(in_flight_part);
+        }
+
         // There's nothing useful in the output so discard it
-        self.runtime.block_on(
-            self.client
-                .abort_multipart_upload(AbortMultipartUploadRequest {
-                    bucket: self.bucket.to_string(),
-                    key: self.key.to_string(),
-                    upload_id: self.upload_id.clone(),
-                    ..Default::default()
-                }),
-        )?;
+        retry_request_with_parameters(
+            &self.logger.new(o!(event::ACTION => "abort upload")),
+            &self.retry_parameters,
+            || {
+                self.runtime.block_on(self.client.s3.abort_multipart_upload(
+                    AbortMultipartUploadRequest {
+                        bucket: self.bucket.to_string(),
+                        key: self.key.to_string(),
+                        upload_id: self.upload_id.clone(),
+                        ..Default::default()
+                    },
+                ))
+            },
+        )
+        .context("error aborting upload")?;
         Ok(())
     }
 }
 
+impl Drop for MultipartUploadWriter {
+    fn drop(&mut self) {
+        // If the writer is dropped without the upload being completed or
+        // canceled, e.g. because of an error while writing an object, try to
+        // abort the upload so that we aren't billed for the parts already
+        // uploaded. Unlike cancel_upload, we neither wait for parts in flight
+        // nor retry, so that dropping a writer can't block for long. Uploads
+        // that this fails to abort are left for the bucket's lifecycle rule
+        // for incomplete multipart uploads to clean up.
+        if self.finished {
+            return;
+        }
+        for in_flight_part in mem::take(&mut self.in_flight_parts) {
+            in_flight_part.abort();
+        }
+        let abort = self
+            .client
+            .s3
+            .abort_multipart_upload(AbortMultipartUploadRequest {
+                bucket: self.bucket.to_string(),
+                key: self.key.to_string(),
+                upload_id: self.upload_id.clone(),
+                ..Default::default()
+            });
+        // The timeout must be created inside the runtime
+        let abort = async { tokio::time::timeout(ABANDONED_UPLOAD_ABORT_TIMEOUT, abort).await };
+        let error = match self.runtime.block_on(abort) {
+            Ok(Ok(_)) => return,
+            Ok(Err(e)) => format!("{:?}", e),
+            Err(_) => "timed out".to_owned(),
+        };
+        warn!(
+            self.logger, "failed to abort abandoned upload";
+            "error" => error,
+            "upload_id" => &self.upload_id,
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::logging::setup_test_logging;
-    use rusoto_core::{request::HttpDispatchError, signature::SignedRequest};
+    use crate::transport::is_already_exists_error;
+    use mockito::{mock, Matcher};
     use rusoto_mock::{MockRequestDispatcher, MultipleMockRequestDispatcher};
     use rusoto_s3::CreateMultipartUploadError;
     use std::io::Read;
@@ -421,6 +1160,49 @@ mod tests {
     const TEST_BUCKET: &str = "fake-bucket";
     const TEST_KEY: &str = "fake-key";
 
+    /// Returns the ETag S3 would report for a part with the provided content.
+    fn part_etag(content: &[u8]) -> String {
+        format!("\"{:x}\"", md5::compute(content))
+    }
+
+    /// Returns the ETag S3 would report for an object assembled from parts
+    /// with the provided contents.
+    fn upload_etag(parts: &[&[u8]]) -> String {
+        let concatenated_digests: Vec<u8> =
+            parts.iter().flat_map(|part| md5::compute(part).0).collect();
+        format!(
+            "\"{:x}-{}\"",
+            md5::compute(&concatenated_digests),
+            parts.len()
+        )
+    }
+
+    /// Returns a response to CompleteMultipartUpload with the provided ETag.
+    fn complete_multipart_upload_response(e_tag: &str) -> MockRequestDispatcher {
+        MockRequestDispatcher::with_status(200)
+            .with_request_checker(is_complete_multipart_upload_request)
+            .with_body(&format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<CompleteMultipartUploadResult>
+   <Location>string</Location>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <ETag>{}</ETag>
+</CompleteMultipartUploadResult>"#,
+                e_tag
+            ))
+    }
+
+    /// Retry parameters with short intervals so that tests run quickly.
+    fn test_retry_parameters() -> RetryParameters {
+        RetryParameters {
+            initial_interval: Duration::from_millis(10),
+            max_interval: Duration::from_millis(10),
+            max_elapsed_time: Duration::from_secs(10),
+            max_attempts: Some(3),
+        }
+    }
+
     fn is_create_multipart_upload_request(request: &SignedRequest) {
         // https://docs.aws.amazon.com/AmazonS3/latest/API/API_CreateMultipartUpload.html
         assert_eq!(
@@ -452,6 +1234,11 @@ mod tests {
             "expected UploadPart request, found {:?}",
             request
         );
+        assert!(
+            request.headers.contains_key("content-md5"),
+            "expected Content-MD5 header in UploadPart request, found {:?}",
+            request
+        );
     }
 
     fn is_abort_multipart_upload_request(request: &SignedRequest) {
@@ -482,6 +1269,16 @@ mod tests {
         );
     }
 
+    fn is_conditional_complete_multipart_upload_request(request: &SignedRequest) {
+        is_complete_multipart_upload_request(request);
+        assert_eq!(
+            request.headers.get("if-none-match"),
+            Some(&vec![b"*".to_vec()]),
+            "expected If-None-Match: * header, found {:?}",
+            request
+        );
+    }
+
     fn is_get_object_request(request: &SignedRequest) {
         // https://docs.aws.amazon.com/AmazonS3/latest/API/API_GetObject.html
         assert_eq!(
@@ -504,19 +1301,446 @@ mod tests {
         );
     }
 
-    #[test]
+    fn is_list_objects_v2_request(request: &SignedRequest) {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_ListObjectsV2.html
+        assert_eq!(
+            request.method, "GET",
+            "expected ListObjectsV2 request, found {:?}",
+            request
+        );
+        assert_eq!(
+            request.params.get("list-type"),
+            Some(&Some("2".to_owned())),
+            "expected ListObjectsV2 request, found {:?}",
+            request
+        );
+        assert_eq!(
+            request.params.get("prefix"),
+            Some(&Some("some/prefix/2021/".to_owned())),
+            "expected ListObjectsV2 request, found {:?}",
+            request
+        );
+    }
+
+    #[test]
+    fn list_s3_transport() {
+        let logger = setup_test_logging();
+        let s3_path = S3Path {
+            region: Region::UsWest2,
+            bucket: TEST_BUCKET.into(),
+            key: "some/prefix".into(),
+        };
+
+        let mut transport = S3Transport::new_with_client(
+            s3_path,
+            aws_credentials::Provider::new_mock(),
+            Box::new(
+                |region: &Region, credentials_provider: aws_credentials::Provider| {
+                    let requests = vec![
+                        // First page of listing. HTTP 500 will cause a retry.
+                        MockRequestDispatcher::with_status(500)
+                            .with_request_checker(is_list_objects_v2_request),
+                        MockRequestDispatcher::with_status(200)
+                            .with_body(
+                                r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult>
+   <IsTruncated>true</IsTruncated>
+   <Contents><Key>some/prefix/2021/a</Key></Contents>
+   <Contents><Key>some/prefix/2021/b</Key></Contents>
+   <Name>fake-bucket</Name>
+   <Prefix>some/prefix/2021/</Prefix>
+   <NextContinuationToken>fake-token</NextContinuationToken>
+</ListBucketResult>"#,
+                            )
+                            .with_request_checker(is_list_objects_v2_request),
+                        // Second and last page of listing
+                        MockRequestDispatcher::with_status(200)
+                            .with_body(
+                                r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult>
+   <IsTruncated>false</IsTruncated>
+   <Contents><Key>some/prefix/2021/c</Key></Contents>
+   <Name>fake-bucket</Name>
+   <Prefix>some/prefix/2021/</Prefix>
+</ListBucketResult>"#,
+                            )
+                            .with_request_checker(|request: &SignedRequest| {
+                                is_list_objects_v2_request(request);
+                                assert_eq!(
+                                    request.params.get("continuation-token"),
+                                    Some(&Some("fake-token".to_owned()))
+                                );
+                            }),
+                    ];
+                    Ok(S3ApiClient::new_with(
+                        MultipleMockRequestDispatcher::new(requests),
+                        credentials_provider,
+                        region.clone(),
+                    ))
+                },
+            ),
+            &logger,
+        );
+
+        assert_eq!(
+            transport.list("2021/", "trace-id").unwrap(),
+            vec!["2021/a", "2021/b", "2021/c"]
+        );
+    }
+
+    #[test]
+    fn delete_and_exists_s3_transport() {
+        let logger = setup_test_logging();
+        let s3_path = S3Path {
+            region: Region::UsWest2,
+            bucket: TEST_BUCKET.into(),
+            key: "".into(),
+        };
+
+        let requests = vec![
+            // Response to HeadObject for existing object
+            MockRequestDispatcher::with_status(200).with_request_checker(
+                |request: &SignedRequest| {
+                    assert_eq!(request.method, "HEAD");
+                    assert_eq!(request.path, "/fake-bucket/fake-key");
+                },
+            ),
+            // Response to DeleteObject
+            MockRequestDispatcher::with_status(204).with_request_checker(
+                |request: &SignedRequest| {
+                    assert_eq!(request.method, "DELETE");
+                    assert_eq!(request.path, "/fake-bucket/fake-key");
+                },
+            ),
+            // Response to HeadObject for missing object
+            MockRequestDispatcher::with_status(404),
+            // Response to HeadObject that fails
+            MockRequestDispatcher::with_status(401),
+        ];
+        // S3Transport obtains a new client for each call, so we share a single
+        // client across calls to consume the canned responses in order.
+        let client = S3ApiClient::new_with(
+            MultipleMockRequestDispatcher::new(requests),
+            aws_credentials::Provider::new_mock(),
+            Region::UsWest2,
+        );
+        let mut transport = S3Transport::new_with_client(
+            s3_path,
+            aws_credentials::Provider::new_mock(),
+            Box::new(move |_, _| Ok(client.clone())),
+            &logger,
+        );
+
+        assert!(transport.exists(TEST_KEY, "trace-id").unwrap());
+        transport.delete(TEST_KEY, "trace-id").unwrap();
+        assert!(!transport.exists(TEST_KEY, "trace-id").unwrap());
+        transport.exists(TEST_KEY, "trace-id").unwrap_err();
+    }
+
+    #[test]
+    fn etag_s3_transport() {
+        let logger = setup_test_logging();
+        let s3_path = S3Path {
+            region: Region::UsWest2,
+            bucket: TEST_BUCKET.into(),
+            key: "".into(),
+        };
+
+        let requests = vec![
+            MockRequestDispatcher::with_status(200)
+                .with_header("ETag", "\"fake-etag\"")
+                .with_request_checker(|request: &SignedRequest| {
+                    assert_eq!(request.method, "HEAD");
+                    assert_eq!(request.path, "/fake-bucket/fake-key");
+                }),
+            MockRequestDispatcher::with_status(404),
+        ];
+        let client = S3ApiClient::new_with(
+            MultipleMockRequestDispatcher::new(requests),
+            aws_credentials::Provider::new_mock(),
+            Region::UsWest2,
+        );
+        let mut transport = S3Transport::new_with_client(
+            s3_path,
+            aws_credentials::Provider::new_mock(),
+            Box::new(move |_, _| Ok(client.clone())),
+            &logger,
+        );
+
+        assert_eq!(
+            transport.etag(TEST_KEY, "trace-id").unwrap(),
+            "\"fake-etag\""
+        );
+        transport.etag(TEST_KEY, "trace-id").unwrap_err();
+    }
+
+    #[test]
+    fn custom_endpoint() {
+        let logger = setup_test_logging();
+        let mocked_get = mock(
+            "GET",
+            format!("/{}/path/{}", TEST_BUCKET, TEST_KEY).as_str(),
+        )
+        .with_status(200)
+        .with_body("fake-content")
+        .expect(1)
+        .create();
+
+        let mut transport = S3Transport::new(
+            S3Path {
+                region: Region::UsWest2,
+                bucket: TEST_BUCKET.into(),
+                key: "path/".into(),
+            },
+            Some(mockito::server_url()),
+            aws_credentials::Provider::new_mock(),
+            &logger,
+        );
+
+        let mut content = Vec::new();
+        transport
+            .get(TEST_KEY, "trace-id")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"fake-content");
+        mocked_get.assert();
+    }
+
+    #[test]
+    fn assumed_role_credentials() {
+        let logger = setup_test_logging();
+        let mocked_assume_role = mock("POST", "/")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::Regex("Action=AssumeRole".to_owned()),
+                Matcher::Regex(
+                    "RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Fs3-writer".to_owned(),
+                ),
+            ]))
+            .with_status(200)
+            .with_body(
+                r#"<AssumeRoleResponse xmlns="https://sts.amazonaws.com/doc/2011-06-15/">
+  <AssumeRoleResult>
+    <Credentials>
+      <AccessKeyId>s3-writer-access-key</AccessKeyId>
+      <SecretAccessKey>s3-writer-secret-key</SecretAccessKey>
+      <SessionToken>s3-writer-session-token</SessionToken>
+      <Expiration>2100-01-01T00:00:00Z</Expiration>
+    </Credentials>
+    <AssumedRoleUser>
+      <Arn>arn:aws:sts::123456789012:assumed-role/s3-writer/prio-facilitator</Arn>
+      <AssumedRoleId>ARO123EXAMPLE123:prio-facilitator</AssumedRoleId>
+    </AssumedRoleUser>
+  </AssumeRoleResult>
+  <ResponseMetadata>
+    <RequestId>c6104cbe-af31-11e0-8154-cbc7ccf896c7</RequestId>
+  </ResponseMetadata>
+</AssumeRoleResponse>"#,
+            )
+            .expect(1)
+            .create();
+
+        // S3 requests should be signed with the credentials obtained from STS
+        // rather than the base credentials
+        let credentials_provider = aws_credentials::Provider::new_assume_role(
+            "arn:aws:iam::123456789012:role/s3-writer",
+            aws_credentials::Provider::new_mock(),
+            Region::Custom {
+                name: "us-west-2".to_owned(),
+                endpoint: mockito::server_url(),
+            },
+        )
+        .unwrap();
+        let mut transport = S3Transport::new_with_client(
+            S3Path {
+                region: Region::UsWest2,
+                bucket: TEST_BUCKET.into(),
+                key: "".into(),
+            },
+            credentials_provider,
+            Box::new(|region, credentials_provider| {
+                Ok(S3ApiClient::new_with(
+                    MockRequestDispatcher::with_status(200).with_request_checker(
+                        |request: &SignedRequest| {
+                            assert_eq!(request.method, "HEAD");
+                            assert_eq!(request.path, "/fake-bucket/fake-key");
+                            assert_eq!(
+                                request.headers.get("x-amz-security-token"),
+                                Some(&vec![b"s3-writer-session-token".to_vec()])
+                            );
+                            let authorization =
+                                String::from_utf8(request.headers["authorization"][0].clone())
+                                    .unwrap();
+                            assert!(
+                                authorization.contains("Credential=s3-writer-access-key/"),
+                                "unexpected authorization header {}",
+                                authorization
+                            );
+                        },
+                    ),
+                    credentials_provider,
+                    region.clone(),
+                ))
+            }),
+            &logger,
+        );
+        transport.set_retry_parameters(test_retry_parameters());
+
+        // Assumed role credentials are cached across requests
+        assert!(transport.exists(TEST_KEY, "trace-id").unwrap());
+        assert!(transport.exists(TEST_KEY, "trace-id").unwrap());
+        mocked_assume_role.assert();
+    }
+
+    #[test]
+    fn resume_interrupted_download() {
+        let logger = setup_test_logging();
+
+        // A body that yields some content and then fails, as if the connection
+        // were dropped.
+        let interrupted_body = ByteStream::new(futures::stream::iter(vec![
+            Ok(Bytes::from_static(b"fake-")),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "connection reset",
+            )),
+        ]));
+
+        let client = S3Client::new_with(
+            MockRequestDispatcher::with_status(206)
+                .with_body("content")
+                .with_request_checker(|request: &SignedRequest| {
+                    is_get_object_request(request);
+                    assert_eq!(
+                        request.headers.get("range"),
+                        Some(&vec![b"bytes=5-".to_vec()])
+                    );
+                    assert_eq!(
+                        request.headers.get("if-match"),
+                        Some(&vec![b"fake-etag".to_vec()])
+                    );
+                }),
+            aws_credentials::Provider::new_mock(),
+            Region::UsWest2,
+        );
+
+        let download = S3Download::new(
+            interrupted_body,
+            GetObjectRequest {
+                bucket: TEST_BUCKET.to_owned(),
+                key: TEST_KEY.to_owned(),
+                if_match: Some("fake-etag".to_owned()),
+                ..Default::default()
+            },
+            client,
+            test_retry_parameters(),
+            logger,
+        );
+
+        let mut reader = PrefetchingReader::from_stream(download.into_stream()).unwrap();
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"fake-content");
+    }
+
+    #[test]
+    fn get_retries_slow_down() {
+        let logger = setup_test_logging();
+        let s3_path = S3Path {
+            region: Region::UsWest2,
+            bucket: TEST_BUCKET.into(),
+            key: "".into(),
+        };
+
+        let slow_down_response = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Error>
+    <Code>SlowDown</Code>
+    <Message>Please reduce your request rate.</Message>
+</Error>"#;
+
+        let mut transport = S3Transport::new_with_client(
+            s3_path.clone(),
+            aws_credentials::Provider::new_mock(),
+            Box::new(
+                move |region: &Region, credentials_provider: aws_credentials::Provider| {
+                    let requests = vec![
+                        MockRequestDispatcher::with_status(503)
+                            .with_body(slow_down_response)
+                            .with_request_checker(is_get_object_request),
+                        MockRequestDispatcher::with_status(503)
+                            .with_body(slow_down_response)
+                            .with_request_checker(is_get_object_request),
+                        MockRequestDispatcher::with_status(200)
+                            .with_body("fake-content")
+                            .with_request_checker(is_get_object_request),
+                    ];
+                    Ok(S3ApiClient::new_with(
+                        MultipleMockRequestDispatcher::new(requests),
+                        credentials_provider,
+                        region.clone(),
+                    ))
+                },
+            ),
+            &logger,
+        );
+        transport.set_retry_parameters(test_retry_parameters());
+
+        let mut content = Vec::new();
+        transport
+            .get(TEST_KEY, "trace-id")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"fake-content");
+
+        // With one fewer attempt permitted, the request should fail with the
+        // last error. If the transport made another request, the mock
+        // dispatcher would panic because it has no more responses.
+        let mut transport = S3Transport::new_with_client(
+            s3_path,
+            aws_credentials::Provider::new_mock(),
+            Box::new(
+                move |region: &Region, credentials_provider: aws_credentials::Provider| {
+                    let requests = vec![
+                        MockRequestDispatcher::with_status(503)
+                            .with_body(slow_down_response)
+                            .with_request_checker(is_get_object_request),
+                        MockRequestDispatcher::with_status(503)
+                            .with_body(slow_down_response)
+                            .with_request_checker(is_get_object_request),
+                    ];
+                    Ok(S3ApiClient::new_with(
+                        MultipleMockRequestDispatcher::new(requests),
+                        credentials_provider,
+                        region.clone(),
+                    ))
+                },
+            ),
+            &logger,
+        );
+        transport.set_retry_parameters(RetryParameters {
+            max_attempts: Some(2),
+            ..test_retry_parameters()
+        });
+
+        assert!(transport.get(TEST_KEY, "trace-id").is_err());
+    }
+
+    #[test]
     fn multipart_upload_create_fails() {
         let logger = setup_test_logging();
         let err = MultipartUploadWriter::new(
             String::from(TEST_BUCKET),
             String::from(TEST_KEY),
             50,
-            S3Client::new_with(
+            1,
+            S3ApiClient::new_with(
                 MockRequestDispatcher::with_status(401)
                     .with_request_checker(is_create_multipart_upload_request),
                 aws_credentials::Provider::new_mock(),
                 Region::UsWest2,
             ),
+            test_retry_parameters(),
             &logger,
         )
         .expect_err("expected error");
@@ -536,7 +1760,8 @@ mod tests {
             String::from(TEST_BUCKET),
             String::from(TEST_KEY),
             50,
-            S3Client::new_with(
+            1,
+            S3ApiClient::new_with(
                 MockRequestDispatcher::with_status(200)
                     .with_body(
                         r#"<?xml version="1.0" encoding="UTF-8"?>
@@ -549,6 +1774,7 @@ mod tests {
                 aws_credentials::Provider::new_mock(),
                 Region::UsWest2,
             ),
+            test_retry_parameters(),
             &logger,
         )
         .expect_err("expected error");
@@ -578,7 +1804,7 @@ mod tests {
             // Well formed response to UploadPart.
             MockRequestDispatcher::with_status(200)
                 .with_request_checker(is_upload_part_request)
-                .with_header("ETag", "fake-etag"),
+                .with_header("ETag", &part_etag(&[0; 50])),
             // HTTP 200 response to CompleteMultipartUpload that contains an
             // error. Should cause us to retry.
             // https://docs.aws.amazon.com/AmazonS3/latest/API/API_CompleteMultipartUpload.html#API_CompleteMultipartUpload_Examples
@@ -594,28 +1820,20 @@ mod tests {
 </Error>"#,
                 ),
             // Well formed response to CompleteMultipartUpload
-            MockRequestDispatcher::with_status(200)
-                .with_request_checker(is_complete_multipart_upload_request)
-                .with_body(
-                    r#"<?xml version="1.0" encoding="UTF-8"?>
-<CompleteMultipartUploadResult>
-   <Location>string</Location>
-   <Bucket>fake-bucket</Bucket>
-   <Key>fake-key</Key>
-   <ETag>fake-etag</ETag>
-</CompleteMultipartUploadResult>"#,
-                ),
+            complete_multipart_upload_response(&upload_etag(&[&[0; 50]])),
         ];
 
         let mut writer = MultipartUploadWriter::new(
             String::from(TEST_BUCKET),
             String::from(TEST_KEY),
             50,
-            S3Client::new_with(
+            1,
+            S3ApiClient::new_with(
                 MultipleMockRequestDispatcher::new(requests),
                 aws_credentials::Provider::new_mock(),
                 Region::UsWest2,
             ),
+            test_retry_parameters(),
             &logger,
         )
         .unwrap();
@@ -633,6 +1851,7 @@ mod tests {
             String::from(TEST_BUCKET),
             String::from(TEST_KEY),
             50,
+            1,
             {
                 let requests = vec![
                     // Response to CreateMultipartUpload
@@ -670,56 +1889,39 @@ mod tests {
                     // Well formed response to UploadPart.
                     MockRequestDispatcher::with_status(200)
                         .with_request_checker(is_upload_part_request)
-                        .with_header("ETag", "fake-etag"),
+                        .with_header("ETag", &part_etag(&[0; 50])),
                     // Well formed response to UploadPart
                     MockRequestDispatcher::with_status(200)
                         .with_request_checker(is_upload_part_request)
-                        .with_header("ETag", "fake-etag"),
+                        .with_header("ETag", &part_etag(&[0; 25])),
                     // Well formed response to CompleteMultipartUpload
-                    MockRequestDispatcher::with_status(200)
-                        .with_request_checker(is_complete_multipart_upload_request)
-                        .with_body(
-                            r#"<?xml version="1.0" encoding="UTF-8"?>
-<CompleteMultipartUploadResult>
-   <Location>string</Location>
-   <Bucket>fake-bucket</Bucket>
-   <Key>fake-key</Key>
-   <ETag>fake-etag</ETag>
-</CompleteMultipartUploadResult>"#,
-                        ),
+                    complete_multipart_upload_response(&upload_etag(&[&[0; 50], &[0; 25]])),
                     // Well formed response to CompleteMultipartUpload
-                    MockRequestDispatcher::with_status(200)
-                        .with_request_checker(is_complete_multipart_upload_request)
-                        .with_body(
-                            r#"<?xml version="1.0" encoding="UTF-8"?>
-<CompleteMultipartUploadResult>
-   <Location>string</Location>
-   <Bucket>fake-bucket</Bucket>
-   <Key>fake-key</Key>
-   <ETag>fake-etag</ETag>
-</CompleteMultipartUploadResult>"#,
-                        ),
+                    // Well formed response to CompleteMultipartUpload. There
+                    // are no parts, so the ETag is not checked.
+                    complete_multipart_upload_response("fake-etag"),
                     // Failure response to CompleteMultipartUpload.
                     MockRequestDispatcher::with_status(400)
                         .with_body("first 400")
                         .with_request_checker(is_complete_multipart_upload_request),
                 ];
-                S3Client::new_with(
+                S3ApiClient::new_with(
                     MultipleMockRequestDispatcher::new(requests),
                     aws_credentials::Provider::new_mock(),
                     Region::UsWest2,
                 )
             },
+            test_retry_parameters(),
             &logger,
         )
         .expect("failed to create multipart upload writer");
 
         // First write will fail due to HTTP 401
-        writer.write_all(&[0; 51]).unwrap_err();
+        writer.write_all(&[0; 50]).unwrap_err();
         // Second write will fail because response is missing ETag
-        writer.write_all(&[0; 51]).unwrap_err();
+        writer.write_all(&[0; 50]).unwrap_err();
         // Third write will work
-        writer.write_all(&[0; 51]).unwrap();
+        writer.write_all(&[0; 50]).unwrap();
         // This write will put some content in the buffer, but not enough to
         // cause an UploadPart
         writer.write_all(&[0; 25]).unwrap();
@@ -732,6 +1934,348 @@ mod tests {
         writer.complete_upload().unwrap_err();
     }
 
+    #[test]
+    fn multipart_upload_etag_mismatch() {
+        let logger = setup_test_logging();
+        let mut writer = MultipartUploadWriter::new(
+            String::from(TEST_BUCKET),
+            String::from(TEST_KEY),
+            50,
+            1,
+            S3ApiClient::new_with(
+                MultipleMockRequestDispatcher::new(vec![
+                    MockRequestDispatcher::with_status(200)
+                        .with_body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+                        )
+                        .with_request_checker(is_create_multipart_upload_request),
+                    // Response to UploadPart whose ETag doesn't match the part
+                    MockRequestDispatcher::with_status(200)
+                        .with_request_checker(is_upload_part_request)
+                        .with_header("ETag", &part_etag(&[1; 50])),
+                    // Response to AbortMultipartUpload, expected because of
+                    // previous UploadPart failure
+                    MockRequestDispatcher::with_status(204)
+                        .with_request_checker(is_abort_multipart_upload_request),
+                    // Well formed response to UploadPart
+                    MockRequestDispatcher::with_status(200)
+                        .with_request_checker(is_upload_part_request)
+                        .with_header("ETag", &part_etag(&[0; 50])),
+                    // Response to CompleteMultipartUpload whose ETag doesn't
+                    // match the uploaded parts
+                    complete_multipart_upload_response(&upload_etag(&[&[1; 50]])),
+                ]),
+                aws_credentials::Provider::new_mock(),
+                Region::UsWest2,
+            ),
+            test_retry_parameters(),
+            &logger,
+        )
+        .expect("failed to create multipart upload writer");
+
+        writer.write_all(&[0; 50]).unwrap_err();
+        writer.write_all(&[0; 50]).unwrap();
+        writer.complete_upload().unwrap_err();
+    }
+
+    #[test]
+    fn multipart_upload_aborted_on_drop() {
+        let logger = setup_test_logging();
+        let writer = MultipartUploadWriter::new(
+            String::from(TEST_BUCKET),
+            String::from(TEST_KEY),
+            50,
+            1,
+            S3ApiClient::new_with(
+                MultipleMockRequestDispatcher::new(vec![
+                    MockRequestDispatcher::with_status(200)
+                        .with_body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+                        )
+                        .with_request_checker(is_create_multipart_upload_request),
+                    // Response to AbortMultipartUpload, expected because the
+                    // writer is dropped without completing the upload
+                    MockRequestDispatcher::with_status(204)
+                        .with_request_checker(is_abort_multipart_upload_request),
+                ]),
+                aws_credentials::Provider::new_mock(),
+                Region::UsWest2,
+            ),
+            test_retry_parameters(),
+            &logger,
+        )
+        .expect("failed to create multipart upload writer");
+
+        // The mock dispatcher panics if it runs out of responses, so this
+        // checks that exactly one AbortMultipartUpload request is sent.
+        drop(writer);
+    }
+
+    #[test]
+    fn failed_abort_on_drop_not_retried() {
+        let logger = setup_test_logging();
+        let writer = MultipartUploadWriter::new(
+            String::from(TEST_BUCKET),
+            String::from(TEST_KEY),
+            50,
+            1,
+            S3ApiClient::new_with(
+                MultipleMockRequestDispatcher::new(vec![
+                    MockRequestDispatcher::with_status(200)
+                        .with_body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+                        )
+                        .with_request_checker(is_create_multipart_upload_request),
+                    // A retryable failure, which cancel_upload would retry
+                    MockRequestDispatcher::with_status(500)
+                        .with_request_checker(is_abort_multipart_upload_request),
+                ]),
+                aws_credentials::Provider::new_mock(),
+                Region::UsWest2,
+            ),
+            test_retry_parameters(),
+            &logger,
+        )
+        .expect("failed to create multipart upload writer");
+
+        // A second AbortMultipartUpload request would make the mock dispatcher
+        // panic.
+        drop(writer);
+    }
+
+    #[test]
+    fn put_if_absent_s3_transport() {
+        let logger = setup_test_logging();
+        let s3_path = S3Path {
+            region: Region::UsWest2,
+            bucket: TEST_BUCKET.into(),
+            key: "".into(),
+        };
+
+        let create_multipart_upload_response = || {
+            MockRequestDispatcher::with_status(200)
+                .with_body(
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+                )
+                .with_request_checker(is_create_multipart_upload_request)
+        };
+        let upload_part_response = || {
+            MockRequestDispatcher::with_status(200)
+                .with_request_checker(is_upload_part_request)
+                .with_header("ETag", &part_etag(b"content"))
+        };
+        let requests = vec![
+            // HeadObject for object that does not exist yet
+            MockRequestDispatcher::with_status(404),
+            create_multipart_upload_response(),
+            upload_part_response(),
+            MockRequestDispatcher::with_status(200)
+                .with_request_checker(is_conditional_complete_multipart_upload_request)
+                .with_body(&format!(
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<CompleteMultipartUploadResult>
+   <Location>string</Location>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <ETag>{}</ETag>
+</CompleteMultipartUploadResult>"#,
+                    upload_etag(&[b"content"]).replace('"', "&quot;")
+                )),
+            // A concurrent upload created the object after the HeadObject
+            MockRequestDispatcher::with_status(404),
+            create_multipart_upload_response(),
+            upload_part_response(),
+            MockRequestDispatcher::with_status(412)
+                .with_request_checker(is_conditional_complete_multipart_upload_request)
+                .with_body(
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<Error><Code>PreconditionFailed</Code></Error>"#,
+                ),
+            MockRequestDispatcher::with_status(204)
+                .with_request_checker(is_abort_multipart_upload_request),
+            // A concurrent upload was completing while this one completed
+            MockRequestDispatcher::with_status(404),
+            create_multipart_upload_response(),
+            upload_part_response(),
+            MockRequestDispatcher::with_status(409)
+                .with_request_checker(is_conditional_complete_multipart_upload_request)
+                .with_body(
+                    r#"<?xml version="1.0" encoding="UTF-8"?>
+<Error><Code>ConditionalRequestConflict</Code></Error>"#,
+                ),
+            MockRequestDispatcher::with_status(204)
+                .with_request_checker(is_abort_multipart_upload_request),
+            // HeadObject for object that already exists
+            MockRequestDispatcher::with_status(200),
+        ];
+        let client = S3ApiClient::new_with(
+            MultipleMockRequestDispatcher::new(requests),
+            aws_credentials::Provider::new_mock(),
+            Region::UsWest2,
+        );
+        let mut transport = S3Transport::new_with_client(
+            s3_path,
+            aws_credentials::Provider::new_mock(),
+            Box::new(move |_, _| Ok(client.clone())),
+            &logger,
+        );
+        transport.set_retry_parameters(test_retry_parameters());
+
+        let mut writer = transport.put_if_absent(TEST_KEY, "trace-id").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.complete_upload().unwrap();
+
+        for _ in 0..2 {
+            let mut writer = transport.put_if_absent(TEST_KEY, "trace-id").unwrap();
+            writer.write_all(b"content").unwrap();
+            let error = writer.complete_upload().unwrap_err();
+            assert!(is_already_exists_error(&error), "{:?}", error);
+        }
+
+        let error = transport.put_if_absent(TEST_KEY, "trace-id").err().unwrap();
+        assert!(is_already_exists_error(&error), "{:?}", error);
+    }
+
+    #[test]
+    fn multipart_upload_parallel_parts() {
+        let logger = setup_test_logging();
+        let upload_part_response = || {
+            MockRequestDispatcher::with_status(200)
+                .with_request_checker(is_upload_part_request)
+                .with_header("ETag", &part_etag(&[0; 50]))
+        };
+        let mut writer = MultipartUploadWriter::new(
+            String::from(TEST_BUCKET),
+            String::from(TEST_KEY),
+            50,
+            3,
+            S3ApiClient::new_with(
+                MultipleMockRequestDispatcher::new(vec![
+                    MockRequestDispatcher::with_status(200)
+                        .with_body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+                        )
+                        .with_request_checker(is_create_multipart_upload_request),
+                    upload_part_response(),
+                    upload_part_response(),
+                    upload_part_response(),
+                    upload_part_response(),
+                    complete_multipart_upload_response(&upload_etag(&[
+                        &[0; 50], &[0; 50], &[0; 50], &[0; 50],
+                    ])),
+                ]),
+                aws_credentials::Provider::new_mock(),
+                Region::UsWest2,
+            ),
+            test_retry_parameters(),
+            &logger,
+        )
+        .expect("failed to create multipart upload writer");
+
+        for _ in 0..4 {
+            writer.write_all(&[0; 50]).unwrap();
+            assert!(writer.in_flight_parts.len() < 3);
+        }
+
+        // Parts must be recorded in order regardless of the order in which
+        // the uploads finish.
+        writer.wait_for_all_parts().unwrap();
+        let part_numbers: Vec<Option<i64>> = writer
+            .completed_parts
+            .iter()
+            .map(|part| part.part_number)
+            .collect();
+        assert_eq!(part_numbers, vec![Some(1), Some(2), Some(3), Some(4)]);
+
+        writer.complete_upload().unwrap();
+    }
+
+    #[test]
+    fn multipart_upload_splits_large_writes() {
+        let logger = setup_test_logging();
+        let mut writer = MultipartUploadWriter::new(
+            String::from(TEST_BUCKET),
+            String::from(TEST_KEY),
+            50,
+            1,
+            S3ApiClient::new_with(
+                MultipleMockRequestDispatcher::new(vec![
+                    MockRequestDispatcher::with_status(200)
+                        .with_body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+                        )
+                        .with_request_checker(is_create_multipart_upload_request),
+                    // The writer checks each part's ETag against its content,
+                    // so these responses also check the size of each part.
+                    MockRequestDispatcher::with_status(200)
+                        .with_request_checker(is_upload_part_request)
+                        .with_header("ETag", &part_etag(&[0; 50])),
+                    MockRequestDispatcher::with_status(200)
+                        .with_request_checker(is_upload_part_request)
+                        .with_header("ETag", &part_etag(&[0; 50])),
+                    MockRequestDispatcher::with_status(200)
+                        .with_request_checker(is_upload_part_request)
+                        .with_header("ETag", &part_etag(&[0; 50])),
+                    MockRequestDispatcher::with_status(200)
+                        .with_request_checker(is_upload_part_request)
+                        .with_header("ETag", &part_etag(&[0; 5])),
+                    complete_multipart_upload_response(&upload_etag(&[
+                        &[0; 50], &[0; 50], &[0; 50], &[0; 5],
+                    ])),
+                ]),
+                aws_credentials::Provider::new_mock(),
+                Region::UsWest2,
+            ),
+            test_retry_parameters(),
+            &logger,
+        )
+        .expect("failed to create multipart upload writer");
+
+        // A single write much larger than the part size is split into parts
+        // without growing the buffer.
+        assert_eq!(writer.write(&[0; 120]).unwrap(), 120);
+        assert_eq!(writer.buffer.len(), 20);
+        assert!(writer.buffer.capacity() <= 50);
+
+        // A write that overflows the buffer fills up the current part and
+        // leaves the rest in the buffer.
+        writer.write_all(&[0; 35]).unwrap();
+        assert_eq!(writer.buffer.len(), 5);
+        assert!(writer.buffer.capacity() <= 50);
+
+        writer.complete_upload().unwrap();
+    }
+
     #[test]
     fn roundtrip_s3_transport() {
         let logger = setup_test_logging();
@@ -743,7 +2287,7 @@ mod tests {
 
         let client_provider = Box::new(
             |region: &Region, credentials_provider: aws_credentials::Provider| {
-                Ok(S3Client::new_with(
+                Ok(S3ApiClient::new_with(
                     // Failed GetObject request
                     MockRequestDispatcher::with_status(404)
                         .with_request_checker(is_get_object_request),
@@ -767,7 +2311,7 @@ mod tests {
             aws_credentials::Provider::new_mock(),
             Box::new(
                 |region: &Region, credentials_provider: aws_credentials::Provider| {
-                    Ok(S3Client::new_with(
+                    Ok(S3ApiClient::new_with(
                         // Successful GetObject request
                         MockRequestDispatcher::with_status(200)
                             .with_request_checker(is_get_object_request)
@@ -807,25 +2351,15 @@ mod tests {
                         // Well formed response to UploadPart
                         MockRequestDispatcher::with_status(200)
                             .with_request_checker(is_upload_part_request)
-                            .with_header("ETag", "fake-etag"),
+                            .with_header("ETag", &part_etag(b"fake-content")),
                         // Well formed response to CompleteMultipartUpload
-                        MockRequestDispatcher::with_status(200)
-                            .with_request_checker(is_complete_multipart_upload_request)
-                            .with_body(
-                                r#"<?xml version="1.0" encoding="UTF-8"?>
-<CompleteMultipartUploadResult>
-   <Location>string</Location>
-   <Bucket>fake-bucket</Bucket>
-   <Key>fake-key</Key>
-   <ETag>fake-etag</ETag>
-</CompleteMultipartUploadResult>"#,
-                            ),
+                        complete_multipart_upload_response(&upload_etag(&[b"fake-content"])),
                         // Response to AbortMultipartUpload, expected because of
                         // cancel_upload call
                         MockRequestDispatcher::with_status(204)
                             .with_request_checker(is_abort_multipart_upload_request),
                     ];
-                    Ok(S3Client::new_with(
+                    Ok(S3ApiClient::new_with(
                         MultipleMockRequestDispatcher::new(requests),
                         credentials_provider,
                         region.clone(),
@@ -840,4 +2374,64 @@ mod tests {
         writer.complete_upload().unwrap();
         writer.cancel_upload().unwrap();
     }
+
+    #[test]
+    fn transport_upload_part_settings() {
+        let logger = setup_test_logging();
+        let s3_path = S3Path {
+            region: Region::UsWest2,
+            bucket: TEST_BUCKET.into(),
+            key: "".into(),
+        };
+        let part_size = MINIMUM_UPLOAD_PART_SIZE + 1;
+
+        let mut transport = S3Transport::new_with_client(
+            s3_path,
+            aws_credentials::Provider::new_mock(),
+            Box::new(
+                move |region: &Region, credentials_provider: aws_credentials::Provider| {
+                    let part = vec![0; part_size];
+                    // The writer checks each part's ETag against its content,
+                    // so these responses also check the size of each part.
+                    let upload_part_response = || {
+                        MockRequestDispatcher::with_status(200)
+                            .with_request_checker(is_upload_part_request)
+                            .with_header("ETag", &part_etag(&part))
+                    };
+                    let requests = vec![
+                        MockRequestDispatcher::with_status(200)
+                            .with_body(
+                                r#"<?xml version="1.0" encoding="UTF-8"?>
+<InitiateMultipartUploadResult>
+   <Bucket>fake-bucket</Bucket>
+   <Key>fake-key</Key>
+   <UploadId>upload-id</UploadId>
+</InitiateMultipartUploadResult>"#,
+                            )
+                            .with_request_checker(is_create_multipart_upload_request),
+                        upload_part_response(),
+                        upload_part_response(),
+                        complete_multipart_upload_response(&upload_etag(&[&part, &part])),
+                    ];
+                    Ok(S3ApiClient::new_with(
+                        MultipleMockRequestDispatcher::new(requests),
+                        credentials_provider,
+                        region.clone(),
+                    ))
+                },
+            ),
+            &logger,
+        );
+
+        transport
+            .set_upload_part_size(MINIMUM_UPLOAD_PART_SIZE - 1)
+            .unwrap_err();
+        transport.set_max_parallel_upload_parts(0).unwrap_err();
+        transport.set_upload_part_size(part_size).unwrap();
+        transport.set_max_parallel_upload_parts(2).unwrap();
+
+        let mut writer = transport.put(TEST_KEY, "trace-id").unwrap();
+        writer.write_all(&vec![0; part_size * 2]).unwrap();
+        writer.complete_upload().unwrap();
+    }
 }
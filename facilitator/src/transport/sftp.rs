@@ -0,0 +1,756 @@
+use crate::{
+    config::SftpPath,
+    logging::event,
+    transport::{Transport, TransportWriter},
+};
+use anyhow::{anyhow, Context, Result};
+use derivative::Derivative;
+use slog::{info, o, warn, Logger};
+use ssh2::{CheckResult, ErrorCode, File, FileStat, KnownHostFileKind, RenameFlags, Session, Sftp};
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use uuid::Uuid;
+
+/// Prefix of the names of temporary files that objects are written to before
+/// they are renamed into place. Files with this prefix are not listed.
+const TEMP_FILE_PREFIX: &str = ".facilitator-upload-";
+
+/// SFTP status code for a missing file (SSH_FX_NO_SUCH_FILE).
+/// https://datatracker.ietf.org/doc/html/draft-ietf-secsh-filexfer-02#section-7
+const SFTP_NO_SUCH_FILE: i32 = 2;
+
+/// Timeout for blocking operations on an SSH session.
+const SESSION_TIMEOUT_MILLIS: u32 = 60_000;
+
+/// Credentials used to connect to an SFTP server.
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct SftpCredentials {
+    /// PEM encoded private key used to authenticate to the server.
+    #[derivative(Debug = "ignore")]
+    pub private_key: String,
+    /// Passphrase with which the private key is encrypted, if any.
+    #[derivative(Debug = "ignore")]
+    pub passphrase: Option<String>,
+    /// OpenSSH known_hosts file against which the server's host key is
+    /// verified.
+    pub known_hosts_file: PathBuf,
+}
+
+/// The SFTP operations that SftpTransport performs, which lets tests substitute
+/// a fake server for ssh2::Sftp. Paths are relative to the SFTP user's home
+/// directory unless they are absolute.
+trait SftpSession {
+    fn stat(&self, path: &Path) -> Result<FileStat, ssh2::Error>;
+    fn mkdir(&self, path: &Path, mode: i32) -> Result<(), ssh2::Error>;
+    fn readdir(&self, path: &Path) -> Result<Vec<(PathBuf, FileStat)>, ssh2::Error>;
+    fn open(&self, path: &Path) -> Result<Box<dyn Read>, ssh2::Error>;
+    fn create(&self, path: &Path) -> Result<Box<dyn SftpFile>, ssh2::Error>;
+    fn rename(&self, src: &Path, dst: &Path, flags: Option<RenameFlags>)
+        -> Result<(), ssh2::Error>;
+    fn unlink(&self, path: &Path) -> Result<(), ssh2::Error>;
+}
+
+/// A file opened for writing on an SFTP server.
+trait SftpFile: Write {
+    fn close(&mut self) -> Result<(), ssh2::Error>;
+}
+
+impl SftpSession for Sftp {
+    fn stat(&self, path: &Path) -> Result<FileStat, ssh2::Error> {
+        Sftp::stat(self, path)
+    }
+
+    fn mkdir(&self, path: &Path, mode: i32) -> Result<(), ssh2::Error> {
+        Sftp::mkdir(self, path, mode)
+    }
+
+    fn readdir(&self, path: &Path) -> Result<Vec<(PathBuf, FileStat)>, ssh2::Error> {
+        Sftp::readdir(self, path)
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Read>, ssh2::Error> {
+        Ok(Box::new(Sftp::open(self, path)?))
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn SftpFile>, ssh2::Error> {
+        Ok(Box::new(Sftp::create(self, path)?))
+    }
+
+    fn rename(
+        &self,
+        src: &Path,
+        dst: &Path,
+        flags: Option<RenameFlags>,
+    ) -> Result<(), ssh2::Error> {
+        Sftp::rename(self, src, dst, flags)
+    }
+
+    fn unlink(&self, path: &Path) -> Result<(), ssh2::Error> {
+        Sftp::unlink(self, path)
+    }
+}
+
+impl SftpFile for File {
+    fn close(&mut self) -> Result<(), ssh2::Error> {
+        File::close(self)
+    }
+}
+
+/// SessionProvider allows substituting a fake SFTP server for testing.
+type SessionProvider = Box<dyn Fn(&SftpPath, &SftpCredentials) -> Result<Arc<dyn SftpSession>>>;
+
+/// A transport implementation backed by a directory on an SFTP server. Keys
+/// are interpreted as paths relative to that directory. The connection to the
+/// server is established on first use and reused until a request fails. The
+/// server's host key must be listed in the known hosts file provided in the
+/// credentials. Objects are only ever replaced atomically, so on servers that
+/// speak SFTP version 3 (e.g. OpenSSH), which can't rename over an existing
+/// file, putting an object that already exists fails.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct SftpTransport {
+    path: SftpPath,
+    credentials: SftpCredentials,
+    #[derivative(Debug = "ignore")]
+    session_provider: SessionProvider,
+    #[derivative(Debug = "ignore")]
+    sftp: Option<Arc<dyn SftpSession>>,
+    #[derivative(Debug = "ignore")]
+    logger: Logger,
+}
+
+impl SftpTransport {
+    /// Creates an SftpTransport for the provided path, authenticating to the
+    /// server with the provided credentials.
+    pub fn new(path: SftpPath, credentials: SftpCredentials, parent_logger: &Logger) -> Self {
+        SftpTransport::new_with_session_provider(
+            path,
+            credentials,
+            Box::new(|path, credentials| Ok(Arc::new(connect(path, credentials)?))),
+            parent_logger,
+        )
+    }
+
+    fn new_with_session_provider(
+        path: SftpPath,
+        credentials: SftpCredentials,
+        session_provider: SessionProvider,
+        parent_logger: &Logger,
+    ) -> Self {
+        let path = path.ensure_directory_prefix();
+        let logger = parent_logger.new(o!(
+            event::STORAGE_PATH => path.to_string(),
+            event::IDENTITY => path.user.clone(),
+        ));
+        SftpTransport {
+            path,
+            credentials,
+            session_provider,
+            sftp: None,
+            logger,
+        }
+    }
+
+    /// Runs `f` with an SFTP session, connecting to the server if necessary.
+    /// If `f` fails, the session is discarded so that the next request uses a
+    /// fresh connection.
+    fn with_sftp<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Arc<dyn SftpSession>, &Path) -> Result<T>,
+    {
+        let sftp = match &self.sftp {
+            Some(sftp) => sftp.clone(),
+            None => {
+                let sftp = (self.session_provider)(&self.path, &self.credentials)?;
+                self.sftp = Some(sftp.clone());
+                sftp
+            }
+        };
+
+        let result = f(&sftp, Path::new(&self.path.key));
+        if result.is_err() {
+            self.sftp = None;
+        }
+        result
+    }
+}
+
+/// Connects to the SFTP server at `path`, verifying its host key against the
+/// known hosts file and authenticating with the private key in `credentials`.
+fn connect(path: &SftpPath, credentials: &SftpCredentials) -> Result<Sftp> {
+    let tcp_stream = TcpStream::connect((path.host.as_str(), path.port))
+        .with_context(|| format!("failed to connect to {}", path.host))?;
+    let mut session = Session::new().context("failed to create SSH session")?;
+    session.set_tcp_stream(tcp_stream);
+    session.set_timeout(SESSION_TIMEOUT_MILLIS);
+    session.handshake().context("SSH handshake failed")?;
+
+    let (host_key, _) = session
+        .host_key()
+        .context("SSH server did not provide a host key")?;
+    let mut known_hosts = session
+        .known_hosts()
+        .context("failed to initialize known hosts")?;
+    known_hosts
+        .read_file(&credentials.known_hosts_file, KnownHostFileKind::OpenSSH)
+        .with_context(|| {
+            format!(
+                "failed to read known hosts file {}",
+                credentials.known_hosts_file.display()
+            )
+        })?;
+    match known_hosts.check_port(&path.host, path.port, host_key) {
+        CheckResult::Match => {}
+        CheckResult::Mismatch => {
+            return Err(anyhow!(
+                "host key for {} does not match known hosts",
+                path.host
+            ))
+        }
+        CheckResult::NotFound => return Err(anyhow!("no known host key for {}", path.host)),
+        CheckResult::Failure => return Err(anyhow!("failed to check host key for {}", path.host)),
+    }
+
+    session
+        .userauth_pubkey_memory(
+            &path.user,
+            None,
+            &credentials.private_key,
+            credentials.passphrase.as_deref(),
+        )
+        .with_context(|| format!("failed to authenticate as {}", path.user))?;
+    if !session.authenticated() {
+        return Err(anyhow!("failed to authenticate as {}", path.user));
+    }
+
+    session.sftp().context("failed to start SFTP subsystem")
+}
+
+fn is_no_such_file(error: &ssh2::Error) -> bool {
+    error.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE)
+}
+
+/// Creates the provided directory and any missing parents.
+fn create_dir_all(sftp: &dyn SftpSession, directory: &Path) -> Result<()> {
+    if directory.as_os_str().is_empty() {
+        return Ok(());
+    }
+    match sftp.stat(directory) {
+        Ok(stat) if stat.is_dir() => return Ok(()),
+        Ok(_) => return Err(anyhow!("{} is not a directory", directory.display())),
+        Err(e) if is_no_such_file(&e) => {}
+        Err(e) => return Err(e).context(format!("failed to stat {}", directory.display())),
+    }
+    if let Some(parent) = directory.parent() {
+        create_dir_all(sftp, parent)?;
+    }
+    sftp.mkdir(directory, 0o755)
+        .with_context(|| format!("failed to create directory {}", directory.display()))
+}
+
+/// Appends the keys of all files under `directory`/`relative_directory` that
+/// might begin with `prefix` to `keys`.
+fn list_files(
+    sftp: &dyn SftpSession,
+    directory: &Path,
+    relative_directory: &str,
+    prefix: &str,
+    keys: &mut Vec<String>,
+) -> Result<()> {
+    let entries = match sftp.readdir(&directory.join(relative_directory)) {
+        Ok(entries) => entries,
+        Err(e) if is_no_such_file(&e) => return Ok(()),
+        Err(e) => return Err(e).context("failed to read directory"),
+    };
+
+    for (path, stat) in entries {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => continue,
+        };
+        if name.starts_with(TEMP_FILE_PREFIX) {
+            continue;
+        }
+        let key = format!("{}{}", relative_directory, name);
+        if stat.is_dir() {
+            let subdirectory = format!("{}/", key);
+            if subdirectory.starts_with(prefix) || prefix.starts_with(&subdirectory) {
+                list_files(sftp, directory, &subdirectory, prefix, keys)?;
+            }
+        } else if stat.is_file() && key.starts_with(prefix) {
+            keys.push(key);
+        }
+    }
+    Ok(())
+}
+
+impl Transport for SftpTransport {
+    fn path(&self) -> String {
+        self.path.to_string()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "get");
+        self.with_sftp(|sftp, directory| {
+            sftp.open(&directory.join(key))
+                .with_context(|| format!("failed to open {} over SFTP", key))
+        })
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "put");
+        self.with_sftp(|sftp, directory| {
+            let path = directory.join(key);
+            let parent = path.parent().unwrap_or_else(|| Path::new(""));
+            create_dir_all(sftp.as_ref(), parent)?;
+
+            // Like LocalFileTransport, write to a temporary file that is
+            // renamed into place once the upload is complete.
+            let temp_path = parent.join(format!("{}{}", TEMP_FILE_PREFIX, Uuid::new_v4()));
+            let file = sftp
+                .create(&temp_path)
+                .with_context(|| format!("failed to create {}", temp_path.display()))?;
+
+            Ok(Box::new(SftpWriter {
+                sftp: sftp.clone(),
+                file: Some(file),
+                temp_path,
+                path,
+                logger,
+            }) as Box<dyn TransportWriter>)
+        })
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => prefix.to_owned(),
+        ));
+        info!(logger, "list");
+        self.with_sftp(|sftp, directory| {
+            let mut keys = Vec::new();
+            list_files(sftp.as_ref(), directory, "", prefix, &mut keys)?;
+            keys.sort();
+            Ok(keys)
+        })
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "delete");
+        self.with_sftp(|sftp, directory| match sftp.unlink(&directory.join(key)) {
+            Err(e) if !is_no_such_file(&e) => {
+                Err(e).context(format!("failed to delete {} over SFTP", key))
+            }
+            _ => Ok(()),
+        })
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "exists");
+        self.with_sftp(|sftp, directory| match sftp.stat(&directory.join(key)) {
+            Ok(stat) => Ok(stat.is_file()),
+            Err(e) if is_no_such_file(&e) => Ok(false),
+            Err(e) => Err(e).context(format!("failed to stat {} over SFTP", key)),
+        })
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        let logger = self.logger.new(o!(
+            event::TRACE_ID => trace_id.to_owned(),
+            event::STORAGE_KEY => key.to_owned(),
+        ));
+        info!(logger, "etag");
+        self.with_sftp(|sftp, directory| {
+            let stat = sftp
+                .stat(&directory.join(key))
+                .with_context(|| format!("failed to stat {} over SFTP", key))?;
+            // As with LocalFileTransport, derive an ETag from the modification
+            // time and size, which change whenever a file is renamed into place.
+            Ok(format!(
+                "{:x}-{:x}",
+                stat.mtime.unwrap_or_default(),
+                stat.size.unwrap_or_default()
+            ))
+        })
+    }
+}
+
+/// Writes content into a temporary file on the SFTP server, which is renamed
+/// to the destination path when the upload is completed or deleted if the
+/// upload is canceled or the rename fails, so that readers never see a
+/// partially written object and an existing object is never lost.
+struct SftpWriter {
+    sftp: Arc<dyn SftpSession>,
+    // None once the upload has been completed or canceled
+    file: Option<Box<dyn SftpFile>>,
+    temp_path: PathBuf,
+    path: PathBuf,
+    logger: Logger,
+}
+
+impl SftpWriter {
+    fn remove_temp_file(&mut self) -> Result<()> {
+        match self.sftp.unlink(&self.temp_path) {
+            Err(e) if !is_no_such_file(&e) => Err(e).context(format!(
+                "failed to delete temporary file {}",
+                self.temp_path.display()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for SftpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.file {
+            Some(file) => file.write(buf),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "upload was already completed or canceled",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TransportWriter for SftpWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        if let Err(e) = file.close() {
+            self.remove_temp_file()?;
+            return Err(e).context("failed to close file over SFTP");
+        }
+
+        // Servers speaking SFTP version 3 ignore the rename flags and refuse
+        // to rename over an existing file. We could delete the destination
+        // and then rename, but then a failure between the two would lose an
+        // object that was already committed, so we give up instead.
+        let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+        if let Err(e) = self.sftp.rename(&self.temp_path, &self.path, flags) {
+            self.remove_temp_file()?;
+            return Err(e).context(format!(
+                "failed to rename {} to {} (SFTP servers that don't support \
+                 atomically replacing files can't overwrite objects)",
+                self.temp_path.display(),
+                self.path.display()
+            ));
+        }
+        Ok(())
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        if let Some(mut file) = self.file.take() {
+            // The temporary file is removed regardless of whether it closes
+            // cleanly.
+            let _ = file.close();
+            self.remove_temp_file()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SftpWriter {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            if let Err(e) = self.cancel_upload() {
+                warn!(self.logger, "failed to cancel abandoned upload: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::setup_test_logging;
+    use std::{fs, os::unix::fs::MetadataExt};
+    use tempfile::TempDir;
+
+    /// SFTP status code for a generic failure (SSH_FX_FAILURE).
+    const SFTP_FAILURE: i32 = 4;
+
+    /// A fake SFTP server that serves a local directory, standing in for the
+    /// SFTP user's home directory. Like servers that speak SFTP version 3, it
+    /// refuses to rename a file over an existing one.
+    struct FakeSftpServer {
+        root: PathBuf,
+    }
+
+    fn sftp_error(error: io::Error) -> ssh2::Error {
+        if error.kind() == io::ErrorKind::NotFound {
+            ssh2::Error::new(ErrorCode::SFTP(SFTP_NO_SUCH_FILE), "no such file")
+        } else {
+            ssh2::Error::new(ErrorCode::SFTP(SFTP_FAILURE), "failure")
+        }
+    }
+
+    fn file_stat(metadata: fs::Metadata) -> FileStat {
+        FileStat {
+            size: Some(metadata.size()),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            perm: Some(metadata.mode()),
+            atime: Some(metadata.atime() as u64),
+            mtime: Some(metadata.mtime() as u64),
+        }
+    }
+
+    impl SftpSession for FakeSftpServer {
+        fn stat(&self, path: &Path) -> Result<FileStat, ssh2::Error> {
+            fs::metadata(self.root.join(path))
+                .map(file_stat)
+                .map_err(sftp_error)
+        }
+
+        fn mkdir(&self, path: &Path, _mode: i32) -> Result<(), ssh2::Error> {
+            fs::create_dir(self.root.join(path)).map_err(sftp_error)
+        }
+
+        fn readdir(&self, path: &Path) -> Result<Vec<(PathBuf, FileStat)>, ssh2::Error> {
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(self.root.join(path)).map_err(sftp_error)? {
+                let entry = entry.map_err(sftp_error)?;
+                let metadata = entry.metadata().map_err(sftp_error)?;
+                entries.push((path.join(entry.file_name()), file_stat(metadata)));
+            }
+            Ok(entries)
+        }
+
+        fn open(&self, path: &Path) -> Result<Box<dyn Read>, ssh2::Error> {
+            Ok(Box::new(
+                fs::File::open(self.root.join(path)).map_err(sftp_error)?,
+            ))
+        }
+
+        fn create(&self, path: &Path) -> Result<Box<dyn SftpFile>, ssh2::Error> {
+            Ok(Box::new(
+                fs::File::create(self.root.join(path)).map_err(sftp_error)?,
+            ))
+        }
+
+        fn rename(
+            &self,
+            src: &Path,
+            dst: &Path,
+            _flags: Option<RenameFlags>,
+        ) -> Result<(), ssh2::Error> {
+            if self.root.join(dst).exists() {
+                return Err(ssh2::Error::new(ErrorCode::SFTP(SFTP_FAILURE), "failure"));
+            }
+            fs::rename(self.root.join(src), self.root.join(dst)).map_err(sftp_error)
+        }
+
+        fn unlink(&self, path: &Path) -> Result<(), ssh2::Error> {
+            fs::remove_file(self.root.join(path)).map_err(sftp_error)
+        }
+    }
+
+    impl SftpFile for fs::File {
+        fn close(&mut self) -> Result<(), ssh2::Error> {
+            self.sync_all().map_err(sftp_error)
+        }
+    }
+
+    /// Returns an SftpTransport for the directory "directory" on a fake SFTP
+    /// server serving `home`.
+    fn fake_sftp_transport(home: &TempDir) -> SftpTransport {
+        let root = home.path().to_path_buf();
+        SftpTransport::new_with_session_provider(
+            "sftp://user@sftp.example.com/directory".parse().unwrap(),
+            SftpCredentials {
+                private_key: String::new(),
+                passphrase: None,
+                known_hosts_file: PathBuf::from("/nonexistent/known_hosts"),
+            },
+            Box::new(move |_, _| {
+                Ok(Arc::new(FakeSftpServer { root: root.clone() }) as Arc<dyn SftpSession>)
+            }),
+            &setup_test_logging(),
+        )
+    }
+
+    /// Returns the names of the files in `directory` on the local filesystem.
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn list_recurses_into_directories_and_skips_temp_files() {
+        let home = TempDir::new().unwrap();
+        let directory = home.path().join("directory");
+        fs::create_dir_all(directory.join("2021/05")).unwrap();
+        fs::create_dir_all(directory.join("other")).unwrap();
+        for file in &[
+            "a",
+            "2021/b",
+            "2021/05/c",
+            "2021/.facilitator-upload-in-progress",
+            "other/d",
+        ] {
+            fs::write(directory.join(file), file).unwrap();
+        }
+        let mut transport = fake_sftp_transport(&home);
+
+        assert_eq!(
+            transport.list("2021/", "trace-id").unwrap(),
+            vec!["2021/05/c", "2021/b"]
+        );
+        assert_eq!(
+            transport.list("", "trace-id").unwrap(),
+            vec!["2021/05/c", "2021/b", "a", "other/d"]
+        );
+        assert_eq!(
+            transport.list("2021/05/c", "trace-id").unwrap(),
+            vec!["2021/05/c"]
+        );
+        assert!(transport.list("missing/", "trace-id").unwrap().is_empty());
+    }
+
+    #[test]
+    fn complete_upload_renames_temp_file_into_place() {
+        let home = TempDir::new().unwrap();
+        let mut transport = fake_sftp_transport(&home);
+
+        let mut writer = transport.put("batch/key", "trace-id").unwrap();
+        writer.write_all(b"content").unwrap();
+        // Until the upload is completed, the object is invisible
+        let batch_directory = home.path().join("directory/batch");
+        assert_eq!(file_names(&batch_directory).len(), 1);
+        assert!(file_names(&batch_directory)[0].starts_with(TEMP_FILE_PREFIX));
+        assert!(!transport.exists("batch/key", "trace-id").unwrap());
+        assert!(transport.list("", "trace-id").unwrap().is_empty());
+
+        writer.complete_upload().unwrap();
+        assert_eq!(file_names(&batch_directory), vec!["key"]);
+        assert_eq!(transport.list("", "trace-id").unwrap(), vec!["batch/key"]);
+        let mut content = Vec::new();
+        transport
+            .get("batch/key", "trace-id")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"content");
+    }
+
+    #[test]
+    fn cancel_upload_removes_temp_file() {
+        let home = TempDir::new().unwrap();
+        let mut transport = fake_sftp_transport(&home);
+        let directory = home.path().join("directory");
+
+        let mut writer = transport.put("key", "trace-id").unwrap();
+        writer.write_all(b"content").unwrap();
+        writer.cancel_upload().unwrap();
+        assert!(file_names(&directory).is_empty());
+        assert!(!transport.exists("key", "trace-id").unwrap());
+
+        // Dropping an upload that was never completed also cancels it
+        let mut writer = transport.put("key", "trace-id").unwrap();
+        writer.write_all(b"content").unwrap();
+        drop(writer);
+        assert!(file_names(&directory).is_empty());
+    }
+
+    #[test]
+    fn failed_replace_keeps_existing_object() {
+        let home = TempDir::new().unwrap();
+        let mut transport = fake_sftp_transport(&home);
+
+        let mut writer = transport.put("key", "trace-id").unwrap();
+        writer.write_all(b"original").unwrap();
+        writer.complete_upload().unwrap();
+
+        // The fake server can't rename over the existing object, so the
+        // upload fails, leaving the original object and no temporary file.
+        let mut writer = transport.put("key", "trace-id").unwrap();
+        writer.write_all(b"replacement").unwrap();
+        writer.complete_upload().unwrap_err();
+
+        assert_eq!(file_names(&home.path().join("directory")), vec!["key"]);
+        let mut content = Vec::new();
+        transport
+            .get("key", "trace-id")
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, b"original");
+    }
+
+    #[test]
+    fn get_exists_etag_and_delete() {
+        let home = TempDir::new().unwrap();
+        let directory = home.path().join("directory");
+        fs::create_dir_all(directory.join("subdirectory")).unwrap();
+        fs::write(directory.join("key"), b"content").unwrap();
+        let mut transport = fake_sftp_transport(&home);
+
+        assert!(transport.exists("key", "trace-id").unwrap());
+        assert!(!transport.exists("missing", "trace-id").unwrap());
+        // Directories are not objects
+        assert!(!transport.exists("subdirectory", "trace-id").unwrap());
+        assert!(transport.get("missing", "trace-id").is_err());
+
+        let etag = transport.etag("key", "trace-id").unwrap();
+        assert!(etag.ends_with("-7"), "{}", etag);
+        assert!(transport.etag("missing", "trace-id").is_err());
+
+        transport.delete("key", "trace-id").unwrap();
+        assert!(!transport.exists("key", "trace-id").unwrap());
+        // Deleting an object that does not exist succeeds
+        transport.delete("key", "trace-id").unwrap();
+    }
+
+    #[test]
+    fn connection_failure_is_an_error() {
+        let logger = setup_test_logging();
+        let mut transport = SftpTransport::new(
+            "sftp://user@127.0.0.1:1/directory".parse().unwrap(),
+            SftpCredentials {
+                private_key: String::new(),
+                passphrase: None,
+                known_hosts_file: PathBuf::from("/nonexistent/known_hosts"),
+            },
+            &logger,
+        );
+
+        assert_eq!(transport.path(), "sftp://user@127.0.0.1:1/directory/");
+        assert!(transport.exists("key", "trace-id").is_err());
+        assert!(transport.get("key", "trace-id").is_err());
+        assert!(transport.sftp.is_none());
+    }
+}
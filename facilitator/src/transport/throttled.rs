@@ -0,0 +1,319 @@
+use crate::transport::{Transport, TransportWriter};
+use anyhow::{anyhow, Result};
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Limits on the rate at which a ThrottledTransport makes requests to and
+/// transfers content to or from its inner transport. Unset limits are not
+/// enforced.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThrottleParameters {
+    /// Maximum number of requests (e.g. get or list) per second.
+    pub requests_per_second: Option<f64>,
+    /// Maximum number of bytes read or written per second.
+    pub bytes_per_second: Option<f64>,
+}
+
+/// A transport that wraps another transport, delaying requests and reads or
+/// writes of object content so that they don't exceed the configured rates,
+/// e.g. to stay under a storage service's request rate limits. Rates are
+/// enforced with token buckets that allow bursts of up to one second's worth
+/// of requests or bytes. Clones of a ThrottledTransport, and the readers and
+/// writers it returns, share the same limits.
+#[derive(Clone, Debug)]
+pub struct ThrottledTransport<T: Transport> {
+    inner: T,
+    requests: Option<Limiter>,
+    bytes: Option<Limiter>,
+}
+
+impl<T: Transport> ThrottledTransport<T> {
+    /// Creates a ThrottledTransport that limits requests to the provided
+    /// transport according to `parameters`. Returns an error if any limit is
+    /// not a positive number.
+    pub fn new(inner: T, parameters: ThrottleParameters) -> Result<Self> {
+        Ok(ThrottledTransport {
+            inner,
+            requests: parameters
+                .requests_per_second
+                .map(Limiter::new)
+                .transpose()?,
+            bytes: parameters.bytes_per_second.map(Limiter::new).transpose()?,
+        })
+    }
+
+    fn throttle_request(&self) {
+        if let Some(requests) = &self.requests {
+            requests.acquire(1);
+        }
+    }
+
+    fn throttled_writer(&self, writer: Box<dyn TransportWriter>) -> Box<dyn TransportWriter> {
+        match &self.bytes {
+            Some(bytes) => Box::new(ThrottledWriter {
+                writer,
+                limiter: bytes.clone(),
+            }),
+            None => writer,
+        }
+    }
+}
+
+impl<T: Transport> Transport for ThrottledTransport<T> {
+    fn path(&self) -> String {
+        self.inner.path()
+    }
+
+    fn get(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn Read>> {
+        self.throttle_request();
+        let reader = self.inner.get(key, trace_id)?;
+        match &self.bytes {
+            Some(bytes) => Ok(Box::new(ThrottledReader {
+                reader,
+                limiter: bytes.clone(),
+            })),
+            None => Ok(reader),
+        }
+    }
+
+    fn put(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.throttle_request();
+        let writer = self.inner.put(key, trace_id)?;
+        Ok(self.throttled_writer(writer))
+    }
+
+    fn put_if_absent(&mut self, key: &str, trace_id: &str) -> Result<Box<dyn TransportWriter>> {
+        self.throttle_request();
+        let writer = self.inner.put_if_absent(key, trace_id)?;
+        Ok(self.throttled_writer(writer))
+    }
+
+    fn list(&mut self, prefix: &str, trace_id: &str) -> Result<Vec<String>> {
+        self.throttle_request();
+        self.inner.list(prefix, trace_id)
+    }
+
+    fn delete(&mut self, key: &str, trace_id: &str) -> Result<()> {
+        self.throttle_request();
+        self.inner.delete(key, trace_id)
+    }
+
+    fn exists(&mut self, key: &str, trace_id: &str) -> Result<bool> {
+        self.throttle_request();
+        self.inner.exists(key, trace_id)
+    }
+
+    fn etag(&mut self, key: &str, trace_id: &str) -> Result<String> {
+        self.throttle_request();
+        self.inner.etag(key, trace_id)
+    }
+}
+
+/// A token bucket that refills at `rate` tokens per second, up to `capacity`
+/// tokens. Tokens may be taken from the bucket even if it doesn't hold enough,
+/// in which case its balance goes negative and the taker must wait until it
+/// would have refilled.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            capacity: rate,
+            tokens: rate,
+            updated: now,
+        }
+    }
+
+    /// Takes `amount` tokens from the bucket at time `now` and returns how
+    /// long the taker must wait before proceeding.
+    fn take(&mut self, amount: f64, now: Instant) -> Duration {
+        if now > self.updated {
+            let elapsed = now.duration_since(self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+            self.updated = now;
+        }
+        self.tokens -= amount;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+/// A token bucket shared between a ThrottledTransport and its clones, readers
+/// and writers.
+#[derive(Clone, Debug)]
+struct Limiter(Arc<Mutex<TokenBucket>>);
+
+impl Limiter {
+    fn new(rate: f64) -> Result<Self> {
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(anyhow!("throttle rate must be positive, not {}", rate));
+        }
+        Ok(Limiter(Arc::new(Mutex::new(TokenBucket::new(
+            rate,
+            Instant::now(),
+        )))))
+    }
+
+    /// Takes `amount` tokens, sleeping if the rate has been exceeded.
+    fn acquire(&self, amount: u64) {
+        // Don't hold the lock while sleeping so that other users of the
+        // limiter can take their place in line.
+        let wait = self.0.lock().unwrap().take(amount as f64, Instant::now());
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+struct ThrottledReader {
+    reader: Box<dyn Read>,
+    limiter: Limiter,
+}
+
+impl Read for ThrottledReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.limiter.acquire(len as u64);
+        Ok(len)
+    }
+}
+
+struct ThrottledWriter {
+    writer: Box<dyn TransportWriter>,
+    limiter: Limiter,
+}
+
+impl Write for ThrottledWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.limiter.acquire(len as u64);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl TransportWriter for ThrottledWriter {
+    fn complete_upload(&mut self) -> Result<()> {
+        self.writer.complete_upload()
+    }
+
+    fn cancel_upload(&mut self) -> Result<()> {
+        self.writer.cancel_upload()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    #[test]
+    fn token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, start);
+
+        // A full bucket allows a burst of up to `rate` tokens
+        for _ in 0..10 {
+            assert_eq!(bucket.take(1.0, start), Duration::from_secs(0));
+        }
+        assert_eq!(bucket.take(1.0, start), Duration::from_millis(100));
+        assert_eq!(bucket.take(1.0, start), Duration::from_millis(200));
+
+        // After a second, the debt is paid off and eight tokens are available
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(8.0, later), Duration::from_secs(0));
+        assert_eq!(bucket.take(5.0, later), Duration::from_millis(500));
+
+        // The bucket never holds more than `capacity` tokens
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.take(10.0, much_later), Duration::from_secs(0));
+        assert_eq!(bucket.take(10.0, much_later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        for &rate in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(ThrottledTransport::new(
+                MemoryTransport::new(),
+                ThrottleParameters {
+                    requests_per_second: Some(rate),
+                    bytes_per_second: None,
+                },
+            )
+            .is_err());
+            assert!(ThrottledTransport::new(
+                MemoryTransport::new(),
+                ThrottleParameters {
+                    requests_per_second: None,
+                    bytes_per_second: Some(rate),
+                },
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn throttle_requests() {
+        let mut transport = ThrottledTransport::new(
+            MemoryTransport::new(),
+            ThrottleParameters {
+                requests_per_second: Some(50.0),
+                bytes_per_second: None,
+            },
+        )
+        .unwrap();
+
+        // The first 50 requests are a burst, and the next 10 take 200 ms
+        let start = Instant::now();
+        for _ in 0..60 {
+            transport.exists("key", "").unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[test]
+    fn throttle_bandwidth() {
+        let mut transport = ThrottledTransport::new(
+            MemoryTransport::new(),
+            ThrottleParameters {
+                requests_per_second: None,
+                bytes_per_second: Some(100_000.0),
+            },
+        )
+        .unwrap();
+        let content = vec![1; 60_000];
+
+        let start = Instant::now();
+        let mut writer = transport.put("key", "").unwrap();
+        writer.write_all(&content).unwrap();
+        writer.complete_upload().unwrap();
+
+        // The upload used up most of the burst, so reading the object back
+        // must wait for the bucket to refill
+        let mut content_again = Vec::new();
+        transport
+            .get("key", "")
+            .unwrap()
+            .read_to_end(&mut content_again)
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        assert_eq!(content_again, content);
+    }
+}
@@ -1,10 +1,15 @@
+use anyhow::anyhow;
 use chrono::NaiveDateTime;
 use facilitator::{
-    aggregation::BatchAggregator,
+    aggregation::{BatchAggregator, RejectedPacket, RejectionReason},
     batch::{Batch, BatchReader},
-    idl::{InvalidPacket, Packet, SumPart},
+    export::AggregateResult,
+    field::EncodedField,
+    hpke::{Ciphersuite, HpkePrivateKey},
+    idl::{InvalidPacket, SumPart},
     intake::BatchIntaker,
     logging::setup_test_logging,
+    prio_crypto::PrioBackend,
     sample::{SampleGenerator, SampleOutput},
     test_utils::{
         default_facilitator_packet_encryption_public_key, default_facilitator_signing_private_key,
@@ -14,27 +19,63 @@ use facilitator::{
         DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY, DEFAULT_PHA_ECIES_PRIVATE_KEY,
     },
     transport::{
-        LocalFileTransport, SignableTransport, VerifiableAndDecryptableTransport,
+        LocalFileTransport, SignableTransport, Transport, VerifiableAndDecryptableTransport,
         VerifiableTransport,
     },
-    Error,
 };
-use prio::{encrypt::PrivateKey, util::reconstruct_shares};
+use prio::{
+    encrypt::PrivateKey,
+    field::{Field32, Field64},
+    util::reconstruct_shares,
+};
 use slog::info;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    str::FromStr,
+};
 use tempfile::TempDir;
 use uuid::Uuid;
 
 #[test]
 fn end_to_end() {
-    end_to_end_test(None, None)
+    end_to_end_test::<Field32>(None, None, None, PrioBackend::LibprioRs)
+}
+
+#[test]
+fn end_to_end_field64() {
+    end_to_end_test::<Field64>(None, None, None, PrioBackend::LibprioRs)
+}
+
+#[test]
+fn end_to_end_hpke() {
+    end_to_end_test::<Field32>(
+        None,
+        None,
+        Some("x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm"),
+        PrioBackend::LibprioRs,
+    )
+}
+
+#[test]
+fn end_to_end_reference_prio_backend() {
+    // The PHA uses libprio-rs while the facilitator uses the reference
+    // backend, so the sums are only correct if the two agree on the
+    // verification message and validity of every packet.
+    end_to_end_test::<Field32>(Some(5), None, None, PrioBackend::Reference);
+    end_to_end_test::<Field64>(
+        None,
+        None,
+        Some("x25519-hkdf-sha256/hkdf-sha256/aes-128-gcm"),
+        PrioBackend::Reference,
+    )
 }
 
 #[test]
 fn inconsistent_ingestion_batches() {
     // Have sample generation drop every third and every fourth packet from the
     // PHA and facilitator ingestion batches, respectively.
-    end_to_end_test(Some(3), Some(4))
+    end_to_end_test::<Field32>(Some(3), Some(4), None, PrioBackend::LibprioRs)
 }
 
 /// This test verifies that aggregations fail as expected if some subset of the
@@ -64,7 +105,7 @@ fn aggregation_including_invalid_batch() {
             transport: Box::new(LocalFileTransport::new(
                 pha_tempdir.path().join("ingestion"),
             )),
-            batch_signing_key: default_ingestor_private_key(),
+            batch_signer: Box::new(default_ingestor_private_key()),
         },
         packet_encryption_public_key: default_pha_packet_encryption_public_key(),
         drop_nth_packet: None,
@@ -75,7 +116,7 @@ fn aggregation_including_invalid_batch() {
             transport: Box::new(LocalFileTransport::new(
                 facilitator_tempdir.path().join("ingestion"),
             )),
-            batch_signing_key: default_ingestor_private_key(),
+            batch_signer: Box::new(default_ingestor_private_key()),
         },
         packet_encryption_public_key: default_facilitator_packet_encryption_public_key(),
         drop_nth_packet: None,
@@ -134,6 +175,7 @@ fn aggregation_including_invalid_batch() {
             PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
             PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
         ],
+        hpke_packet_decryption_keys: vec![],
     };
 
     // Facilitator reads ingestion batches from this transport
@@ -148,6 +190,7 @@ fn aggregation_including_invalid_batch() {
             PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
             PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
         ],
+        hpke_packet_decryption_keys: vec![],
     };
 
     // PHA uses this transport to send correctly signed validation batches to
@@ -156,7 +199,7 @@ fn aggregation_including_invalid_batch() {
         transport: Box::new(LocalFileTransport::new(
             facilitator_tempdir.path().join("peer-validation"),
         )),
-        batch_signing_key: default_pha_signing_private_key(),
+        batch_signer: Box::new(default_pha_signing_private_key()),
     };
 
     // PHA uses this transport to send incorrectly signed validation batches to
@@ -166,7 +209,7 @@ fn aggregation_including_invalid_batch() {
             facilitator_tempdir.path().join("peer-validation"),
         )),
         // Intentionally the wrong key
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
     };
 
     // Facilitator uses this transport to send correctly signed validation
@@ -175,7 +218,7 @@ fn aggregation_including_invalid_batch() {
         transport: Box::new(LocalFileTransport::new(
             pha_tempdir.path().join("peer-validation"),
         )),
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
     };
 
     // PHA uses this transport to send correctly signed validation batches to
@@ -184,7 +227,7 @@ fn aggregation_including_invalid_batch() {
         transport: Box::new(LocalFileTransport::new(
             pha_tempdir.path().join("own-validation"),
         )),
-        batch_signing_key: default_pha_signing_private_key(),
+        batch_signer: Box::new(default_pha_signing_private_key()),
     };
 
     // Facilitator uses this transport to send correctly signed validation
@@ -193,7 +236,7 @@ fn aggregation_including_invalid_batch() {
         transport: Box::new(LocalFileTransport::new(
             facilitator_tempdir.path().join("own-validation"),
         )),
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
     };
 
     // Perform the intake over the batches, on the PHA and then facilitator,
@@ -239,9 +282,11 @@ fn aggregation_including_invalid_batch() {
             facilitator_batch_intaker.set_use_bogus_packet_file_digest(true);
         }
 
-        pha_batch_intaker.generate_validation_share(|_| {}).unwrap();
+        pha_batch_intaker
+            .generate_validation_share(|_| Ok(()))
+            .unwrap();
         facilitator_batch_intaker
-            .generate_validation_share(|_| {})
+            .generate_validation_share(|_| Ok(()))
             .unwrap();
     }
 
@@ -288,13 +333,13 @@ fn aggregation_including_invalid_batch() {
         transport: Box::new(LocalFileTransport::new(
             facilitator_tempdir.path().join("peer-validation"),
         )),
-        batch_signing_public_keys: pha_pub_keys,
+        batch_signing_public_keys: pha_pub_keys.clone(),
     };
 
     // PHA uses this transport to send sum parts
     let mut pha_aggregation_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-        batch_signing_key: default_pha_signing_private_key(),
+        batch_signer: Box::new(default_pha_signing_private_key()),
     };
 
     // Facilitator uses this transport to send sum parts
@@ -302,7 +347,7 @@ fn aggregation_including_invalid_batch() {
         transport: Box::new(LocalFileTransport::new(
             facilitator_tempdir.path().to_path_buf(),
         )),
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
     };
 
     // Perform the aggregation on PHA and facilitator
@@ -321,7 +366,7 @@ fn aggregation_including_invalid_batch() {
         &logger,
     )
     .unwrap()
-    .generate_sum_part(&batch_uuids_and_dates, |_| {})
+    .generate_sum_part(&batch_uuids_and_dates, |_| Ok(()))
     .unwrap_err();
     // Ideally we would be able to match on a variant in an error enum to check
     // what the failure was but for now check the error description
@@ -342,14 +387,78 @@ fn aggregation_including_invalid_batch() {
         &logger,
     )
     .unwrap()
-    .generate_sum_part(&batch_uuids_and_dates, |_| {})
+    .generate_sum_part(&batch_uuids_and_dates, |_| Ok(()))
     .unwrap_err();
     assert!(err
         .to_string()
         .contains("key identifier default-facilitator-signing-key not present in key map"));
+
+    // A batch whose validation batches never arrived fails aggregation unless
+    // enough of the other batches are complete, in which case it is excluded
+    // from the sum part.
+    let missing_batch_uuid = Uuid::new_v4();
+    let batches_with_missing = vec![
+        batch_uuids_and_dates[0],
+        (missing_batch_uuid, date),
+        batch_uuids_and_dates[1],
+    ];
+    for (min_complete_batches_percent, succeeds) in &[(100.0, false), (70.0, false), (60.0, true)] {
+        let mut aggregator = BatchAggregator::new(
+            "None",
+            instance_name,
+            aggregation_name,
+            &start_date,
+            &end_date,
+            true,  // is_first
+            false, // permissive
+            &mut pha_ingest_transport,
+            &mut pha_own_validation_transport,
+            &mut pha_peer_validation_transport,
+            &mut pha_aggregation_transport,
+            &logger,
+        )
+        .unwrap();
+        aggregator.set_min_complete_batches_percent(*min_complete_batches_percent);
+        let result = aggregator.generate_sum_part(&batches_with_missing, |_| Ok(()));
+        assert_eq!(result.is_ok(), *succeeds, "{:?}", result);
+    }
+
+    let mut pha_aggregation_batch_reader: BatchReader<'_, SumPart, InvalidPacket> =
+        BatchReader::new(
+            Batch::new_sum(
+                instance_name,
+                aggregation_name,
+                &start_date,
+                &end_date,
+                true,
+            ),
+            &mut *pha_aggregation_transport.transport,
+            false,
+            "trace-id",
+            &logger,
+        );
+    let sum_part = pha_aggregation_batch_reader.header(&pha_pub_keys).unwrap();
+    assert_eq!(
+        sum_part.batch_uuids,
+        vec![batch_uuids_and_dates[0].0, batch_uuids_and_dates[1].0]
+    );
+    assert_eq!(sum_part.excluded_batch_uuids, vec![missing_batch_uuid]);
+    assert!(sum_part.batch_uuids_digests_match());
+    assert!(sum_part.batch_uuids_digest.is_some());
+    assert!(sum_part.facilitator_version.is_some());
 }
 
-fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usize>) {
+/// Runs intake and aggregation of two batches end to end. If
+/// facilitator_hpke_ciphersuite is Some, the facilitator's shares are
+/// encrypted with HPKE in that ciphersuite rather than with ECIES. The
+/// facilitator's intake and aggregation use facilitator_prio_backend, while
+/// the PHA's use libprio-rs.
+fn end_to_end_test<F: EncodedField>(
+    drop_nth_pha: Option<usize>,
+    drop_nth_facilitator: Option<usize>,
+    facilitator_hpke_ciphersuite: Option<&str>,
+    facilitator_prio_backend: PrioBackend,
+) {
     let logger = setup_test_logging();
     let pha_tempdir = TempDir::new().unwrap();
     let pha_copy_tempdir = TempDir::new().unwrap();
@@ -374,7 +483,7 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
     let mut pha_output = SampleOutput {
         transport: SignableTransport {
             transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-            batch_signing_key: default_ingestor_private_key(),
+            batch_signer: Box::new(default_ingestor_private_key()),
         },
         packet_encryption_public_key: default_pha_packet_encryption_public_key(),
 
@@ -386,7 +495,7 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
             transport: Box::new(LocalFileTransport::new(
                 facilitator_tempdir.path().to_path_buf(),
             )),
-            batch_signing_key: default_ingestor_private_key(),
+            batch_signer: Box::new(default_ingestor_private_key()),
         },
         packet_encryption_public_key: default_facilitator_packet_encryption_public_key(),
         drop_nth_packet: drop_nth_facilitator,
@@ -404,13 +513,24 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         &mut facilitator_output,
         &logger,
     );
+    let facilitator_hpke_key = facilitator_hpke_ciphersuite.map(|ciphersuite| {
+        HpkePrivateKey::generate(Ciphersuite::from_str(ciphersuite).unwrap()).unwrap()
+    });
+    if let Some(key) = &facilitator_hpke_key {
+        sample_generator.set_facilitator_hpke_public_key(key.ciphersuite(), key.public_key());
+    }
 
     let batch_1_reference_sum = sample_generator
-        .generate_ingestion_sample("trace-id", &batch_1_uuid, &date, first_batch_packet_count)
+        .generate_ingestion_sample_in_field::<F>(
+            "trace-id",
+            &batch_1_uuid,
+            &date,
+            first_batch_packet_count,
+        )
         .unwrap();
 
     let batch_2_reference_sum = sample_generator
-        .generate_ingestion_sample("trace-id", &batch_2_uuid, &date, 14)
+        .generate_ingestion_sample_in_field::<F>("trace-id", &batch_2_uuid, &date, 14)
         .unwrap();
 
     let mut ingestor_pub_keys = HashMap::new();
@@ -427,6 +547,7 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
             PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
             PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
         ],
+        hpke_packet_decryption_keys: vec![],
     };
 
     let mut facilitator_ingest_transport = VerifiableAndDecryptableTransport {
@@ -440,32 +561,33 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
             PrivateKey::from_base64(DEFAULT_PHA_ECIES_PRIVATE_KEY).unwrap(),
             PrivateKey::from_base64(DEFAULT_FACILITATOR_ECIES_PRIVATE_KEY).unwrap(),
         ],
+        hpke_packet_decryption_keys: facilitator_hpke_key.into_iter().collect(),
     };
 
     let mut pha_peer_validate_signable_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-        batch_signing_key: default_pha_signing_private_key(),
+        batch_signer: Box::new(default_pha_signing_private_key()),
     };
 
     let mut facilitator_peer_validate_signable_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(
             facilitator_tempdir.path().to_path_buf(),
         )),
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
     };
 
     let mut pha_own_validate_signable_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(
             pha_copy_tempdir.path().to_path_buf(),
         )),
-        batch_signing_key: default_pha_signing_private_key(),
+        batch_signer: Box::new(default_pha_signing_private_key()),
     };
 
     let mut facilitator_own_validate_signable_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(
             facilitator_copy_tempdir.path().to_path_buf(),
         )),
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
     };
 
     let mut intake_callback_count = 0;
@@ -484,7 +606,10 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
     .unwrap();
     batch_intaker.set_callback_cadence(2);
     batch_intaker
-        .generate_validation_share(|_| intake_callback_count += 1)
+        .generate_validation_share(|_| {
+            intake_callback_count += 1;
+            Ok(())
+        })
         .unwrap();
 
     assert_eq!(
@@ -505,10 +630,10 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         &logger,
     )
     .unwrap()
-    .generate_validation_share(|_| {})
+    .generate_validation_share(|_| Ok(()))
     .unwrap();
 
-    BatchIntaker::new(
+    let mut facilitator_batch_intaker = BatchIntaker::new(
         "None",
         &aggregation_name,
         &batch_1_uuid,
@@ -520,11 +645,13 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         false,
         &logger,
     )
-    .unwrap()
-    .generate_validation_share(|_| {})
     .unwrap();
+    facilitator_batch_intaker.set_prio_backend(facilitator_prio_backend);
+    facilitator_batch_intaker
+        .generate_validation_share(|_| Ok(()))
+        .unwrap();
 
-    BatchIntaker::new(
+    let mut facilitator_batch_intaker = BatchIntaker::new(
         "None",
         &aggregation_name,
         &batch_2_uuid,
@@ -536,9 +663,11 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         false,
         &logger,
     )
-    .unwrap()
-    .generate_validation_share(|_| {})
     .unwrap();
+    facilitator_batch_intaker.set_prio_backend(facilitator_prio_backend);
+    facilitator_batch_intaker
+        .generate_validation_share(|_| Ok(()))
+        .unwrap();
 
     let batch_ids_and_dates = vec![(batch_1_uuid, date), (batch_2_uuid, date)];
 
@@ -567,7 +696,7 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
 
     let mut pha_aggregation_transport = SignableTransport {
         transport: Box::new(LocalFileTransport::new(pha_tempdir.path().to_path_buf())),
-        batch_signing_key: default_pha_signing_private_key(),
+        batch_signer: Box::new(default_pha_signing_private_key()),
     };
 
     let mut aggregation_callback_count = 0;
@@ -586,7 +715,10 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         &logger,
     )
     .unwrap()
-    .generate_sum_part(&batch_ids_and_dates, |_| aggregation_callback_count += 1)
+    .generate_sum_part(&batch_ids_and_dates, |_| {
+        aggregation_callback_count += 1;
+        Ok(())
+    })
     .unwrap();
 
     assert_eq!(aggregation_callback_count, 2);
@@ -595,11 +727,14 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         transport: Box::new(LocalFileTransport::new(
             facilitator_tempdir.path().to_path_buf(),
         )),
-        batch_signing_key: default_facilitator_signing_private_key(),
+        batch_signer: Box::new(default_facilitator_signing_private_key()),
     };
 
-    let mut aggregation_callback_count = 0;
-    BatchAggregator::new(
+    // The facilitator's first attempt at aggregation is abandoned after the
+    // first batch, and the second resumes from the checkpoint written then.
+    let checkpoint_tempdir = TempDir::new().unwrap();
+    let mut checkpoint_transport = LocalFileTransport::new(checkpoint_tempdir.path().to_path_buf());
+    let mut facilitator_aggregator = BatchAggregator::new(
         "None",
         instance_name,
         &aggregation_name,
@@ -613,11 +748,84 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         &mut facilitator_aggregation_transport,
         &logger,
     )
-    .unwrap()
-    .generate_sum_part(&batch_ids_and_dates, |_| aggregation_callback_count += 1)
     .unwrap();
+    facilitator_aggregator.set_prio_backend(facilitator_prio_backend);
+    facilitator_aggregator.set_checkpointing(&mut checkpoint_transport, 1, true);
+    facilitator_aggregator
+        .generate_sum_part(&batch_ids_and_dates, |_| Err(anyhow!("shutting down")))
+        .unwrap_err();
+    drop(facilitator_aggregator);
 
-    assert_eq!(aggregation_callback_count, 2);
+    let mut aggregation_callback_count = 0;
+    let rejected_packets_tempdir = TempDir::new().unwrap();
+    let mut rejected_packets_transport =
+        LocalFileTransport::new(rejected_packets_tempdir.path().to_path_buf());
+    let mut facilitator_aggregator = BatchAggregator::new(
+        "None",
+        instance_name,
+        &aggregation_name,
+        &start_date,
+        &end_date,
+        false,
+        false,
+        &mut facilitator_ingest_transport,
+        &mut facilitator_validate_verifiable_transport,
+        &mut pha_validate_verifiable_transport,
+        &mut facilitator_aggregation_transport,
+        &logger,
+    )
+    .unwrap();
+    facilitator_aggregator.set_prio_backend(facilitator_prio_backend);
+    facilitator_aggregator.set_checkpointing(&mut checkpoint_transport, 1, true);
+    facilitator_aggregator.set_rejected_packets_report(&mut rejected_packets_transport);
+    facilitator_aggregator
+        .generate_sum_part(&batch_ids_and_dates, |_| {
+            aggregation_callback_count += 1;
+            Ok(())
+        })
+        .unwrap();
+    drop(facilitator_aggregator);
+
+    assert_eq!(aggregation_callback_count, 1);
+    // The checkpoint is removed once the sum part is written
+    assert!(checkpoint_transport.list("", "None").unwrap().is_empty());
+
+    // Packets rejected before the checkpoint are reported along with those
+    // rejected after resuming. Packets dropped from both data share
+    // processors' ingestion batches are never seen, so are not reported.
+    let report_keys = rejected_packets_transport.list("", "None").unwrap();
+    assert_eq!(report_keys.len(), 1);
+    let mut report = String::new();
+    rejected_packets_transport
+        .get(&report_keys[0], "None")
+        .unwrap()
+        .read_to_string(&mut report)
+        .unwrap();
+    let rejected_packets: Vec<RejectedPacket> = report
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let mut expected_rejected_packets = Vec::new();
+    for (batch_uuid, reference_sum) in &[
+        (batch_1_uuid, &batch_1_reference_sum),
+        (batch_2_uuid, &batch_2_reference_sum),
+    ] {
+        for packet_uuid in reference_sum
+            .pha_dropped_packets
+            .iter()
+            .filter(|uuid| !reference_sum.facilitator_dropped_packets.contains(uuid))
+        {
+            expected_rejected_packets.push(RejectedPacket {
+                packet_uuid: *packet_uuid,
+                batch_uuid: *batch_uuid,
+                reason: RejectionReason::MissingPeerValidationPacket,
+            });
+        }
+    }
+    assert_eq!(rejected_packets.len(), expected_rejected_packets.len());
+    for rejected_packet in &rejected_packets {
+        assert!(expected_rejected_packets.contains(rejected_packet));
+    }
 
     let mut pha_aggregation_batch_reader: BatchReader<'_, SumPart, InvalidPacket> =
         BatchReader::new(
@@ -638,7 +846,7 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         pha_sum_part.total_individual_clients,
         batch_1_reference_sum.contributions as i64 + batch_2_reference_sum.contributions as i64
     );
-    let pha_sum_fields = pha_sum_part.sum().unwrap();
+    let pha_sum_fields = pha_sum_part.sum::<F>().unwrap();
 
     let mut facilitator_aggregation_batch_reader: BatchReader<'_, SumPart, InvalidPacket> =
         BatchReader::new(
@@ -662,7 +870,7 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         facilitator_sum_part.total_individual_clients,
         batch_1_reference_sum.contributions as i64 + batch_2_reference_sum.contributions as i64
     );
-    let facilitator_sum_fields = facilitator_sum_part.sum().unwrap();
+    let facilitator_sum_fields = facilitator_sum_part.sum::<F>().unwrap();
 
     let reconstructed = reconstruct_shares(&facilitator_sum_fields, &pha_sum_fields).unwrap();
 
@@ -675,6 +883,12 @@ fn end_to_end_test(drop_nth_pha: Option<usize>, drop_nth_facilitator: Option<usi
         pha_sum_fields, facilitator_sum_fields, reconstructed, reference_sum
     );
 
+    let exported = AggregateResult::from_sum_parts(&pha_sum_part, &facilitator_sum_part).unwrap();
+    assert_eq!(
+        exported.sums,
+        reference_sum.iter().map(|f| f.encode()).collect::<Vec<_>>()
+    );
+
     assert_eq!(
         facilitator_sum_part.total_individual_clients, pha_sum_part.total_individual_clients,
         "facilitator sum part total individual clients does not match the pha sum part total individual clients\n\
@@ -713,15 +927,20 @@ fn check_invalid_packets(
         for dropped in peer_dropped_packets_2 {
             dropped_packets.insert(dropped);
         }
-        let mut invalid_packet_reader = batch_reader.packet_file_reader(sum_part_header).unwrap();
-        loop {
-            match InvalidPacket::read(&mut invalid_packet_reader) {
+        for packet in batch_reader.packet_file_reader(sum_part_header).unwrap() {
+            match packet {
                 Ok(packet) => assert!(dropped_packets.contains(&packet.uuid)),
-                Err(Error::EofError) => break,
                 Err(err) => panic!("error reading invalid packet {}", err),
             }
         }
     } else {
-        assert!(batch_reader.packet_file_reader(sum_part_header).is_err());
+        // With no invalid packets, the packet file is empty
+        assert_eq!(
+            batch_reader
+                .packet_file_reader(sum_part_header)
+                .unwrap()
+                .count(),
+            0
+        );
     }
 }
@@ -387,6 +387,23 @@ resource "kubernetes_deployment" "intake_batch" {
             container_port = 8080
             protocol       = "TCP"
           }
+          # The metrics endpoint also serves health and readiness checks. The
+          # readiness check contacts the task queue and the bucket we own, so
+          # it is run infrequently and allowed plenty of time.
+          liveness_probe {
+            http_get {
+              path = "/healthz"
+              port = 8080
+            }
+          }
+          readiness_probe {
+            http_get {
+              path = "/readyz"
+              port = 8080
+            }
+            period_seconds  = 60
+            timeout_seconds = 30
+          }
           resources {
             # Batch intake is single threaded, and we never expect to see
             # batches larger than 3-400 MB, so set the limits such that we can
@@ -499,6 +516,23 @@ resource "kubernetes_deployment" "aggregate" {
             container_port = 8080
             protocol       = "TCP"
           }
+          # The metrics endpoint also serves health and readiness checks. The
+          # readiness check contacts the task queue and the bucket we own, so
+          # it is run infrequently and allowed plenty of time.
+          liveness_probe {
+            http_get {
+              path = "/healthz"
+              port = 8080
+            }
+          }
+          readiness_probe {
+            http_get {
+              path = "/readyz"
+              port = 8080
+            }
+            period_seconds  = 60
+            timeout_seconds = 30
+          }
           resources {
             # As in the intake-batch case, aggregate jobs are single threaded
             # and need to fit whole ingestion batches into memory.
@@ -516,8 +516,9 @@ func enqueueAggregationTask(
 	for _, batchPath := range readyBatches {
 		batchCount++
 		batches = append(batches, task.Batch{
-			ID:   batchPath.ID,
-			Time: wftime.Timestamp(batchPath.Time),
+			ID:          batchPath.ID,
+			Time:        wftime.Timestamp(batchPath.Time),
+			Traceparent: task.BatchTraceparent(aggregationID, batchPath.ID),
 		})
 
 		// All batches should have the same aggregation ID?
@@ -528,6 +529,7 @@ func enqueueAggregationTask(
 
 	aggregationTask := task.Aggregation{
 		TraceID:          uuid.New(),
+		Traceparent:      task.NewTraceparent(),
 		AggregationID:    aggregationID,
 		AggregationStart: wftime.Timestamp(aggregationWindow.Begin),
 		AggregationEnd:   wftime.Timestamp(aggregationWindow.End),
@@ -581,6 +583,7 @@ func enqueueIntakeTasks(
 			BatchID:       batch.ID,
 			Date:          wftime.Timestamp(batch.Time),
 			TraceID:       uuid.New(),
+			Traceparent:   task.BatchTraceparent(batch.AggregationID, batch.ID),
 		}
 
 		if _, ok := taskMarkers[intakeTask.Marker()]; ok {
@@ -72,6 +72,7 @@ func TestScheduleIntakeTasks(t *testing.T) {
 			taskMarkerExists: false,
 			expectedIntakeTask: &task.IntakeBatch{
 				TraceID:       expectedUuid,
+				Traceparent:   task.BatchTraceparent("kittens-seen", "b8a5579a-f984-460a-a42d-2813cbf57771"),
 				AggregationID: "kittens-seen",
 				BatchID:       "b8a5579a-f984-460a-a42d-2813cbf57771",
 				Date:          wftime.Timestamp(batchTime),
@@ -194,8 +195,9 @@ func TestScheduleAggregationTasks(t *testing.T) {
 		AggregationEnd:   wftime.Timestamp(aggregationEnd),
 		Batches: []task.Batch{
 			task.Batch{
-				ID:   "b8a5579a-f984-460a-a42d-2813cbf57771",
-				Time: wftime.Timestamp(batchTime),
+				ID:          "b8a5579a-f984-460a-a42d-2813cbf57771",
+				Time:        wftime.Timestamp(batchTime),
+				Traceparent: task.BatchTraceparent("kittens-seen", "b8a5579a-f984-460a-a42d-2813cbf57771"),
 			},
 		},
 	}
@@ -324,9 +326,10 @@ func TestScheduleAggregationTasks(t *testing.T) {
 				foundExpectedTask := false
 				for _, enqueuedTask := range aggregateTaskEnqueuer.enqueuedTasks {
 					if aggregationTask, ok := enqueuedTask.(task.Aggregation); ok {
-						// TraceID is a dynamic value assigned at runtime. Don't
-						// use it to match
+						// TraceID and Traceparent are dynamic values assigned
+						// at runtime. Don't use them to match
 						aggregationTask.TraceID = expectedUuid
+						aggregationTask.Traceparent = ""
 
 						if reflect.DeepEqual(aggregationTask, *testCase.expectedAggregationTask) {
 							foundExpectedTask = true
@@ -6,6 +6,9 @@ package task
 
 import (
 	"context"
+	"crypto/rand"
+	"crypto/sha256"
+	"encoding/hex"
 	"encoding/json"
 	"fmt"
 	"sync"
@@ -35,6 +38,9 @@ type Task interface {
 type Aggregation struct {
 	// TraceID is the tracing identifier for the aggregation.
 	TraceID uuid.UUID `json:"trace-id"`
+	// Traceparent is the W3C Trace Context traceparent of the trace that the
+	// aggregation is traced as part of.
+	Traceparent string `json:"traceparent,omitempty"`
 	// AggregationID is the identifier for the aggregation
 	AggregationID string `json:"aggregation-id"`
 	// AggregationStart is the start of the range of time covered by the
@@ -69,11 +75,45 @@ type Batch struct {
 	ID string `json:"id"`
 	// Time is the timestamp on the batch
 	Time wftime.Timestamp `json:"time"`
+	// Traceparent is the W3C Trace Context traceparent of the batch (see
+	// BatchTraceparent), which aggregating the batch is traced as part of.
+	Traceparent string `json:"traceparent,omitempty"`
+}
+
+// BatchTraceparent returns the W3C Trace Context traceparent for everything
+// that happens to a batch. Intake tasks for the batch carry it, so that the
+// intake is traced as part of it, and it is carried alongside the batch in
+// aggregation tasks, so that aggregating the batch is traced as part of it
+// too. Its trace and parent span IDs are derived from the aggregation and
+// batch IDs so that it can be recomputed when scheduling the aggregation.
+// facilitator derives it the same way.
+// https://www.w3.org/TR/trace-context/#traceparent-header
+func BatchTraceparent(aggregationID, batchID string) string {
+	digest := sha256.Sum256([]byte(aggregationID + "/" + batchID))
+	return traceparent(digest[:16], digest[16:24])
+}
+
+// NewTraceparent returns a W3C Trace Context traceparent that starts a new
+// trace, with random trace and parent span IDs.
+func NewTraceparent() string {
+	var ids [24]byte
+	if _, err := rand.Read(ids[:]); err != nil {
+		panic(fmt.Sprintf("failed to generate trace IDs: %s", err))
+	}
+	return traceparent(ids[:16], ids[16:])
+}
+
+// traceparent formats a sampled, version 00 traceparent.
+func traceparent(traceID, parentID []byte) string {
+	return fmt.Sprintf("00-%s-%s-01", hex.EncodeToString(traceID), hex.EncodeToString(parentID))
 }
 
 type IntakeBatch struct {
 	// TraceID is the tracing identifier for the intake batch.
 	TraceID uuid.UUID `json:"trace-id"`
+	// Traceparent is the W3C Trace Context traceparent of the batch (see
+	// BatchTraceparent), which the intake is traced as part of.
+	Traceparent string `json:"traceparent,omitempty"`
 	// AggregationID is the identifier for the aggregation
 	AggregationID string `json:"aggregation-id"`
 	// BatchID is the identifier of the batch. Typically a UUID.
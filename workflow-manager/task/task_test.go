@@ -0,0 +1,32 @@
+package task
+
+import (
+	"strings"
+	"testing"
+)
+
+func TestBatchTraceparent(t *testing.T) {
+	// facilitator must derive the same traceparent for a batch
+	traceparent := BatchTraceparent("kittens-seen", "b8a5579a-f984-460a-a42d-2813cbf57771")
+	expected := "00-2e0831b886ed1ab0efc84bb2ad91a46f-d93bdb2a1f64e301-01"
+	if traceparent != expected {
+		t.Errorf("unexpected traceparent %q, wanted %q", traceparent, expected)
+	}
+
+	if traceparent == BatchTraceparent("kittens-seen", "c0b1f4a4-1b50-4f02-9ab0-5b6cbb0b4a6f") {
+		t.Errorf("batches of the same aggregation have the same traceparent")
+	}
+	if traceparent == BatchTraceparent("puppies-seen", "b8a5579a-f984-460a-a42d-2813cbf57771") {
+		t.Errorf("batches of different aggregations have the same traceparent")
+	}
+}
+
+func TestNewTraceparent(t *testing.T) {
+	traceparent := NewTraceparent()
+	if len(traceparent) != 55 || !strings.HasPrefix(traceparent, "00-") || !strings.HasSuffix(traceparent, "-01") {
+		t.Errorf("malformed traceparent %q", traceparent)
+	}
+	if traceparent == NewTraceparent() {
+		t.Errorf("new traceparents are not random")
+	}
+}